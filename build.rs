@@ -0,0 +1,7 @@
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+
+    // link the kernel into the higher half, see linker.ld
+    println!("cargo:rustc-link-arg=-T{}/linker.ld", manifest_dir);
+    println!("cargo:rerun-if-changed=linker.ld");
+}
@@ -0,0 +1,665 @@
+//! ACPI tables
+//!
+//! The RSDP the firmware left in the BIOS areas (see [`bootinfo::rsdp`]) points at the root
+//! table, the XSDT, or the RSDT on ACPI 1.0 machines, which lists every other table. [`INIT`]
+//! checks the root table and parses the MADT, which says what interrupt controllers there are
+//! and which CPUs they belong to. [`hpet`] and [`mcfg`] read the HPET and MCFG tables when the
+//! [`hpet`](crate::hpet) and [`pci`](crate::pci) drivers ask for them, and other tables can be
+//! found with [`find_table`]. [`INIT`] also reads what [`power`](crate::power) needs out of
+//! the FADT: the PM1 control ports, the reset register, and the `\_S5` sleep type, which is
+//! only in the DSDT's AML. It's found by looking for the bytes that name it, the way most
+//! small kernels do, which works for firmware that has it as a plain package. With the `aml`
+//! feature, the `aml` module evaluates it later, for firmware that works it out, with
+//! [`definition_blocks`] and [`set_s5`].
+//!
+//! Every table is checksummed, and one that doesn't add up is ignored. The tables are in
+//! ordinary memory, so they're read through the physical memory window.
+//!
+//! links:
+//! - <https://wiki.osdev.org/RSDP>
+//! - <https://wiki.osdev.org/XSDT>
+//! - <https://wiki.osdev.org/MADT>
+//! - ACPI spec, MADT: <https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#multiple-apic-description-table-madt>
+//! - HPET table: IA-PC HPET specification 1.0a, 3.2.4
+//! - MCFG: <https://wiki.osdev.org/PCI_Express>
+//! - ACPI spec, FADT: <https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#fixed-acpi-description-table-fadt>
+//! - finding `\_S5`: <https://wiki.osdev.org/Shutdown>
+//!
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+
+use x86_64::PhysAddr;
+
+use crate::bootinfo;
+use crate::init::{InitCall, Stage};
+use crate::mem::phys_to_virt;
+use crate::sync::SpinLock;
+use crate::{ilog, wlog};
+
+/// Local APICs kept from the MADT, the rest are dropped
+pub const MAX_CPUS: usize = 64;
+pub const MAX_IO_APICS: usize = 8;
+pub const MAX_OVERRIDES: usize = 16;
+
+/// Why the ACPI tables couldn't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The firmware didn't leave an RSDP anywhere we looked
+    NoRsdp,
+    /// The RSDP's extended checksum doesn't add up
+    BadRsdp,
+    /// A table's checksum doesn't add up, or it's not the table it should be. Has its address.
+    BadTable(PhysAddr),
+    /// There's no MADT
+    NoMadt,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the rest is ACPI 2.0 and later
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header every table but the RSDP starts with
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// A table read out of physical memory
+///
+/// # Safety
+///
+/// There has to be a `T` at `addr`.
+unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    ptr::read_unaligned(phys_to_virt(addr).as_ptr::<T>())
+}
+
+/// Whether the `len` bytes at `addr` add up to 0, the way every ACPI checksum works
+fn checksum_ok(addr: PhysAddr, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), len) };
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// The header of the table at `addr`, if it's a `signature` table and its checksum adds up
+fn table(addr: PhysAddr, signature: &[u8; 4]) -> Result<SdtHeader, AcpiError> {
+    let header: SdtHeader = unsafe { read(addr) };
+    let len = header.length as usize;
+    if header.signature != *signature || len < size_of::<SdtHeader>() || !checksum_ok(addr, len) {
+        return Err(AcpiError::BadTable(addr));
+    }
+    Ok(header)
+}
+
+/// The table listing all the others
+#[derive(Debug, Clone, Copy)]
+struct Root {
+    addr: PhysAddr,
+    /// XSDT entries are 64 bits, RSDT ones 32
+    xsdt: bool,
+    entries: usize,
+}
+
+impl Root {
+    fn find(rsdp_addr: PhysAddr) -> Result<Root, AcpiError> {
+        let rsdp: Rsdp = unsafe { read(rsdp_addr) };
+        // bootinfo already checked the ACPI 1.0 part
+        let (addr, xsdt) = if rsdp.revision >= 2 {
+            if !checksum_ok(rsdp_addr, size_of::<Rsdp>()) {
+                return Err(AcpiError::BadRsdp);
+            }
+            (PhysAddr::new(rsdp.xsdt_address), true)
+        } else {
+            (PhysAddr::new(rsdp.rsdt_address as u64), false)
+        };
+
+        let header = table(addr, if xsdt { b"XSDT" } else { b"RSDT" })?;
+        let entry_size = if xsdt { 8 } else { 4 };
+        Ok(Root {
+            addr,
+            xsdt,
+            entries: (header.length as usize - size_of::<SdtHeader>()) / entry_size,
+        })
+    }
+
+    /// Address of table `i` in the list
+    fn entry(&self, i: usize) -> PhysAddr {
+        let entries = self.addr + size_of::<SdtHeader>() as u64;
+        unsafe {
+            if self.xsdt {
+                PhysAddr::new(read::<u64>(entries + i as u64 * 8))
+            } else {
+                PhysAddr::new(read::<u32>(entries + i as u64 * 4) as u64)
+            }
+        }
+    }
+
+    /// Every table with `signature`, since there can be more than one SSDT
+    fn tables<'a>(&'a self, signature: &'a [u8; 4]) -> impl Iterator<Item = PhysAddr> + 'a {
+        (0..self.entries)
+            .map(|i| self.entry(i))
+            .filter(|&addr| table(addr, signature).is_ok())
+    }
+
+    fn find_table(&self, signature: &[u8; 4]) -> Option<PhysAddr> {
+        self.tables(signature).next()
+    }
+}
+
+/// A CPU's local APIC
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LocalApic {
+    /// The CPU's ACPI processor ID
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Not enabled means the CPU can't be used, it might be there to be hotplugged
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    /// Where its registers are
+    pub address: PhysAddr,
+    /// The first global system interrupt it handles
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that isn't wired to the global system interrupt with the same number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0 and 1, trigger mode in bits 2 and 3
+    pub flags: u16,
+}
+
+/// What the MADT says about the interrupt controllers
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    /// Where every CPU's local APIC registers are
+    pub local_apic_address: PhysAddr,
+    /// There are 8259 PICs too, which have to be masked to use the APICs
+    pub pic_compatible: bool,
+    local_apics: [LocalApic; MAX_CPUS],
+    local_apic_count: usize,
+    io_apics: [IoApic; MAX_IO_APICS],
+    io_apic_count: usize,
+    overrides: [InterruptOverride; MAX_OVERRIDES],
+    override_count: usize,
+}
+
+/// MADT entry types
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ADDRESS: u8 = 5;
+
+/// The MADT's own fields after the header: the local APIC address and flags
+const MADT_FIELDS: u64 = 8;
+
+impl Madt {
+    fn parse(addr: PhysAddr) -> Madt {
+        let header: SdtHeader = unsafe { read(addr) };
+        let fields = addr + size_of::<SdtHeader>() as u64;
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(unsafe { read::<u32>(fields) } as u64),
+            pic_compatible: unsafe { read::<u32>(fields + 4u64) } & 1 != 0,
+            local_apics: [LocalApic::default(); MAX_CPUS],
+            local_apic_count: 0,
+            io_apics: [IoApic {
+                id: 0,
+                address: PhysAddr::zero(),
+                gsi_base: 0,
+            }; MAX_IO_APICS],
+            io_apic_count: 0,
+            overrides: [InterruptOverride {
+                irq: 0,
+                gsi: 0,
+                flags: 0,
+            }; MAX_OVERRIDES],
+            override_count: 0,
+        };
+
+        // entries are a type byte, a length byte, and then whatever the type has
+        let end = addr + header.length as u64;
+        let mut entry = fields + MADT_FIELDS;
+        while entry + 2u64 <= end {
+            let (kind, len) = unsafe { (read::<u8>(entry), read::<u8>(entry + 1u64)) };
+            if len < 2 || entry + len as u64 > end {
+                wlog!("acpi: MADT entry at {:#x} runs off the end", entry.as_u64());
+                break;
+            }
+            let body = entry + 2u64;
+            unsafe {
+                match kind {
+                    LOCAL_APIC if madt.local_apic_count < MAX_CPUS => {
+                        madt.local_apics[madt.local_apic_count] = LocalApic {
+                            processor_id: read(body),
+                            apic_id: read(body + 1u64),
+                            enabled: read::<u32>(body + 2u64) & 1 != 0,
+                        };
+                        madt.local_apic_count += 1;
+                    }
+                    IO_APIC if madt.io_apic_count < MAX_IO_APICS => {
+                        madt.io_apics[madt.io_apic_count] = IoApic {
+                            id: read(body),
+                            address: PhysAddr::new(read::<u32>(body + 2u64) as u64),
+                            gsi_base: read(body + 6u64),
+                        };
+                        madt.io_apic_count += 1;
+                    }
+                    INTERRUPT_OVERRIDE if madt.override_count < MAX_OVERRIDES => {
+                        madt.overrides[madt.override_count] = InterruptOverride {
+                            irq: read(body + 1u64),
+                            gsi: read(body + 2u64),
+                            flags: read(body + 6u64),
+                        };
+                        madt.override_count += 1;
+                    }
+                    LOCAL_APIC_ADDRESS => {
+                        madt.local_apic_address = PhysAddr::new(read(body + 2u64));
+                    }
+                    _ => {}
+                }
+            }
+            entry += len as u64;
+        }
+        madt
+    }
+
+    pub fn local_apics(&self) -> &[LocalApic] {
+        &self.local_apics[..self.local_apic_count]
+    }
+
+    pub fn io_apics(&self) -> &[IoApic] {
+        &self.io_apics[..self.io_apic_count]
+    }
+
+    pub fn overrides(&self) -> &[InterruptOverride] {
+        &self.overrides[..self.override_count]
+    }
+
+    /// The global system interrupt ISA `irq` comes in on, and its override flags if it has any
+    pub fn isa_gsi(&self, irq: u8) -> (u32, Option<u16>) {
+        match self.overrides().iter().find(|o| o.irq == irq) {
+            Some(o) => (o.gsi, Some(o.flags)),
+            None => (irq as u32, None),
+        }
+    }
+}
+
+/// What the HPET table says about the first HPET
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    /// Where its registers are
+    pub address: PhysAddr,
+    /// Which HPET it is, when there's more than one
+    pub number: u8,
+    /// The shortest period it can do periodic interrupts with without losing any, in ticks
+    pub min_tick: u16,
+}
+
+/// The HPET table as it's laid out in memory
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct HpetTable {
+    header: SdtHeader,
+    block_id: u32,
+    /// The register block, as a generic address structure
+    address_space: u8,
+    bit_width: u8,
+    bit_offset: u8,
+    access_size: u8,
+    address: u64,
+    number: u8,
+    min_tick: u16,
+    page_protection: u8,
+}
+
+/// The generic address structure space for memory
+const SYSTEM_MEMORY: u8 = 0;
+
+/// Where the MCFG says a PCI segment's memory mapped configuration space is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mcfg {
+    /// Where the configuration space of `start_bus` starts, each bus after it is 1 MiB on
+    pub address: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// An entry in the MCFG, after the header and 8 reserved bytes
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct McfgEntry {
+    address: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    reserved: u32,
+}
+
+const MCFG_ENTRIES: u64 = size_of::<SdtHeader>() as u64 + 8;
+
+/// What the FADT and DSDT say about turning the machine off and resetting it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// Port that [`acpi_enable`](Self::acpi_enable) is written to, to switch the chipset into
+    /// ACPI mode. 0 if it's always in ACPI mode.
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+    /// The ISA IRQ the SCI comes in on, which fixed events like the power button raise
+    pub sci_interrupt: u8,
+    /// The PM1 event blocks, status registers in the first half and enable registers in the
+    /// second. 0 for one that isn't there.
+    pub pm1a_event: u16,
+    pub pm1b_event: u16,
+    /// Bytes in each PM1 event block
+    pub pm1_event_len: u8,
+    pub pm1a_control: u16,
+    /// 0 if there's only the one PM1 control block
+    pub pm1b_control: u16,
+    /// SLP_TYPa and SLP_TYPb for S5, soft off, None if the DSDT doesn't have `\_S5`
+    pub s5: Option<(u8, u8)>,
+    /// Where the DSDT is, null if there isn't one
+    pub dsdt: PhysAddr,
+    /// Port to write [`reset_value`](Self::reset_value) to, to reset the machine. None if
+    /// there's no reset register, or it's not in I/O space.
+    pub reset_port: Option<u16>,
+    pub reset_value: u8,
+}
+
+// FADT field offsets
+const FADT_DSDT: u64 = 40;
+const FADT_SCI_INTERRUPT: u64 = 46;
+const FADT_SMI_COMMAND: u64 = 48;
+const FADT_ACPI_ENABLE: u64 = 52;
+const FADT_PM1A_EVENT: u64 = 56;
+const FADT_PM1B_EVENT: u64 = 60;
+const FADT_PM1A_CONTROL: u64 = 64;
+const FADT_PM1B_CONTROL: u64 = 68;
+const FADT_PM1_EVENT_LEN: u64 = 88;
+const FADT_FLAGS: u64 = 112;
+/// The reset register, as a generic address structure
+const FADT_RESET_REGISTER: u64 = 116;
+const FADT_RESET_VALUE: u64 = 128;
+/// The DSDT's 64-bit address, in ACPI 2.0 and later
+const FADT_X_DSDT: u64 = 140;
+
+/// Flag for there being a reset register
+const RESET_REG_SUP: u32 = 1 << 10;
+/// The generic address structure space for I/O ports
+const SYSTEM_IO: u8 = 1;
+
+// AML opcodes
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+const ROOT_CHAR: u8 = b'\\';
+
+impl Fadt {
+    fn parse(addr: PhysAddr) -> Fadt {
+        let header: SdtHeader = unsafe { read(addr) };
+        let len = header.length as u64;
+        // fields past the end of an older, shorter table count as 0
+        let field = |offset: u64, size: u64| -> u64 {
+            if offset + size > len {
+                return 0;
+            }
+            unsafe {
+                match size {
+                    1 => read::<u8>(addr + offset) as u64,
+                    2 => read::<u16>(addr + offset) as u64,
+                    4 => read::<u32>(addr + offset) as u64,
+                    _ => read::<u64>(addr + offset),
+                }
+            }
+        };
+
+        let reset_port = (field(FADT_FLAGS, 4) as u32 & RESET_REG_SUP != 0
+            && field(FADT_RESET_REGISTER, 1) as u8 == SYSTEM_IO)
+            .then(|| field(FADT_RESET_REGISTER + 4, 8) as u16);
+        let dsdt = match field(FADT_X_DSDT, 8) {
+            0 => field(FADT_DSDT, 4),
+            x_dsdt => x_dsdt,
+        };
+        Fadt {
+            smi_command: field(FADT_SMI_COMMAND, 4) as u16,
+            acpi_enable: field(FADT_ACPI_ENABLE, 1) as u8,
+            sci_interrupt: field(FADT_SCI_INTERRUPT, 2) as u8,
+            pm1a_event: field(FADT_PM1A_EVENT, 4) as u16,
+            pm1b_event: field(FADT_PM1B_EVENT, 4) as u16,
+            pm1_event_len: field(FADT_PM1_EVENT_LEN, 1) as u8,
+            pm1a_control: field(FADT_PM1A_CONTROL, 4) as u16,
+            pm1b_control: field(FADT_PM1B_CONTROL, 4) as u16,
+            s5: s5_from_dsdt(PhysAddr::new(dsdt)),
+            dsdt: PhysAddr::new(dsdt),
+            reset_port,
+            reset_value: field(FADT_RESET_VALUE, 1) as u8,
+        }
+    }
+}
+
+/// The AML in the `signature` table at `addr`, after its header, with the table's revision
+fn aml(addr: PhysAddr, signature: &[u8; 4]) -> Option<(u8, &'static [u8])> {
+    if addr.is_null() {
+        return None;
+    }
+    let header = table(addr, signature).ok()?;
+    let aml = unsafe {
+        core::slice::from_raw_parts(
+            phys_to_virt(addr + size_of::<SdtHeader>() as u64).as_ptr::<u8>(),
+            header.length as usize - size_of::<SdtHeader>(),
+        )
+    };
+    Some((header.revision, aml))
+}
+
+/// The S5 sleep types out of the DSDT at `addr`
+fn s5_from_dsdt(addr: PhysAddr) -> Option<(u8, u8)> {
+    find_s5(aml(addr, b"DSDT")?.1)
+}
+
+/// Find `Name (\_S5, Package () { SLP_TYPa, SLP_TYPb, ... })` in `aml`, and take the first
+/// two elements
+fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let named = |at: usize| match at {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => aml[at - 1] == NAME_OP || (aml[at - 1] == ROOT_CHAR && aml[at - 2] == NAME_OP),
+    };
+    let at = aml
+        .windows(4)
+        .enumerate()
+        .find(|&(at, name)| name == b"_S5_" && named(at))?
+        .0;
+    let mut rest = aml.get(at + 4..)?;
+    if *rest.first()? != PACKAGE_OP {
+        return None;
+    }
+    // the package length takes up 1 to 4 bytes, the top two bits of the first say how many
+    // more there are, and the element count comes after it
+    let length_bytes = 1 + (*rest.get(1)? >> 6) as usize;
+    rest = rest.get(1 + length_bytes + 1..)?;
+
+    let mut element = || -> Option<u8> {
+        let (value, len) = match *rest.first()? {
+            ZERO_OP => (0, 1),
+            ONE_OP => (1, 1),
+            BYTE_PREFIX => (*rest.get(1)?, 2),
+            _ => return None,
+        };
+        rest = &rest[len..];
+        Some(value)
+    };
+    let a = element()?;
+    let b = element()?;
+    Some((a, b))
+}
+
+static ROOT: SpinLock<Option<Root>> = SpinLock::new("acpi root", None);
+static MADT: SpinLock<Option<Madt>> = SpinLock::new("madt", None);
+static FADT: SpinLock<Option<Fadt>> = SpinLock::new("fadt", None);
+
+/// Find the table with `signature`, None if there's no such table or ACPI isn't set up
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    (*ROOT.lock())?.find_table(signature)
+}
+
+/// The parsed MADT, None if there isn't one or ACPI isn't set up
+pub fn madt() -> Option<Madt> {
+    *MADT.lock()
+}
+
+/// What was read out of the FADT, None if there isn't one or ACPI isn't set up
+pub fn fadt() -> Option<Fadt> {
+    *FADT.lock()
+}
+
+/// The AML in the DSDT and then in every SSDT, with the revision of the table it's in, which
+/// says how wide its integers are. Empty if there's no DSDT or ACPI isn't set up.
+pub fn definition_blocks() -> Vec<(u8, &'static [u8])> {
+    let root = *ROOT.lock();
+    let (Some(root), Some(fadt)) = (root, fadt()) else {
+        return Vec::new();
+    };
+    let Some(dsdt) = aml(fadt.dsdt, b"DSDT") else {
+        return Vec::new();
+    };
+    let ssdts = root.tables(b"SSDT").filter_map(|addr| aml(addr, b"SSDT"));
+    core::iter::once(dsdt).chain(ssdts).collect()
+}
+
+/// Replace the `\_S5` sleep type that was found in the DSDT's bytes with `s5`, what
+/// evaluating `\_S5` gave
+pub fn set_s5(s5: (u8, u8)) {
+    if let Some(fadt) = FADT.lock().as_mut() {
+        fadt.s5 = Some(s5);
+    }
+}
+
+/// The HPET table, None if there isn't one, it's not memory mapped, or ACPI isn't set up
+pub fn hpet() -> Option<Hpet> {
+    let addr = find_table(b"HPET")?;
+    let header: SdtHeader = unsafe { read(addr) };
+    if (header.length as usize) < size_of::<HpetTable>() {
+        return None;
+    }
+    let table: HpetTable = unsafe { read(addr) };
+    if table.address_space != SYSTEM_MEMORY {
+        return None;
+    }
+    Some(Hpet {
+        address: PhysAddr::new(table.address),
+        number: table.number,
+        min_tick: table.min_tick,
+    })
+}
+
+/// The MCFG entry for PCI segment 0, None if there isn't one or ACPI isn't set up
+pub fn mcfg() -> Option<Mcfg> {
+    let addr = find_table(b"MCFG")?;
+    let header: SdtHeader = unsafe { read(addr) };
+    let end = addr + header.length as u64;
+    let mut entry = addr + MCFG_ENTRIES;
+    while entry + size_of::<McfgEntry>() as u64 <= end {
+        let found: McfgEntry = unsafe { read(entry) };
+        if found.segment == 0 {
+            return Some(Mcfg {
+                address: PhysAddr::new(found.address),
+                segment: found.segment,
+                start_bus: found.start_bus,
+                end_bus: found.end_bus,
+            });
+        }
+        entry += size_of::<McfgEntry>() as u64;
+    }
+    None
+}
+
+fn setup() -> Result<(), AcpiError> {
+    let rsdp = bootinfo::rsdp().ok_or(AcpiError::NoRsdp)?;
+    let root = Root::find(rsdp)?;
+    *ROOT.lock() = Some(root);
+
+    if let Some(fadt) = root.find_table(b"FACP").map(Fadt::parse) {
+        ilog!(
+            "acpi: PM1a control at {:#x}, S5 sleep type {:?}, reset register {:?}",
+            fadt.pm1a_control,
+            fadt.s5,
+            fadt.reset_port
+        );
+        *FADT.lock() = Some(fadt);
+    }
+
+    let madt = root
+        .find_table(b"APIC")
+        .map(Madt::parse)
+        .ok_or(AcpiError::NoMadt)?;
+    ilog!(
+        "acpi: {} tables, {} cpus, {} I/O APICs, local APIC at {:#x}",
+        root.entries,
+        madt.local_apics()
+            .iter()
+            .filter(|apic| apic.enabled)
+            .count(),
+        madt.io_apics().len(),
+        madt.local_apic_address.as_u64()
+    );
+    *MADT.lock() = Some(madt);
+    Ok(())
+}
+
+fn init() {
+    if let Err(error) = setup() {
+        wlog!("acpi: not using ACPI tables: {:?}", error);
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "acpi",
+    stage: Stage::Memory,
+    after: &[],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn finds_s5() {
+        // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero }), after some other name
+        let aml = [
+            0x08, b'_', b'S', b'4', b'_', 0x12, 0x06, 0x04, 0x0a, 0x06, 0x00, 0x00, 0x00, //
+            0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x07, 0x04, 0x0a, 0x05, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(find_s5(&aml), Some((5, 0)));
+        assert_eq!(find_s5(&aml[..13]), None);
+        assert_eq!(find_s5(&aml[..24]), None);
+    }
+}
@@ -0,0 +1,284 @@
+//! Devices in the namespace
+//!
+//! A device is known by its hardware ID, `_HID`, or one of its compatible IDs in `_CID`,
+//! which are strings or EISA IDs like `PNP0A03` squeezed into 32 bits. `_STA` says whether
+//! it's there, and its `_INI` has to have run before anything else of it is used.
+//!
+//! A PCI root bridge's `_PRT` says where each slot's interrupt pins go: straight to a global
+//! system interrupt, or to a link device, whose `_CRS` has the interrupt the firmware picked
+//! for it. A link the firmware left without one doesn't get one picked here, that would take
+//! `_PRS` and `_SRS`.
+//!
+//! links:
+//! - ACPI spec, device identification: <https://uefi.org/specs/ACPI/6.5/06_Device_Configuration.html#device-identification-objects>
+//! - ACPI spec, `_PRT`: <https://uefi.org/specs/ACPI/6.5/06_Device_Configuration.html#prt-pci-routing-table>
+//!
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::interp::{AmlError, Host, Interpreter};
+use super::namespace::{Object, Path, ScopeKind};
+use super::resource::{self, Interrupt};
+use super::value::{Reference, Value};
+
+/// The IDs of PCI and PCI Express root bridges
+pub const PCI_ROOT_BRIDGES: [&str; 2] = ["PNP0A03", "PNP0A08"];
+
+// _STA bits
+const STA_PRESENT: u64 = 1;
+const STA_FUNCTIONING: u64 = 1 << 3;
+
+/// What `_STA` says for a device without one: it's there and working
+const STA_DEFAULT: u64 = 0xf;
+
+/// An EISA ID the way AML has it: three letters in 5 bits each and four hex digits, stored
+/// big endian
+pub fn eisa_id(id: &str) -> Option<u64> {
+    let id = id.as_bytes();
+    if id.len() != 7 || !id[..3].iter().all(u8::is_ascii_uppercase) {
+        return None;
+    }
+    let letters = id[..3]
+        .iter()
+        .fold(0u16, |bits, &c| bits << 5 | (c - b'@') as u16);
+    let product = u16::from_str_radix(core::str::from_utf8(&id[3..]).ok()?, 16).ok()?;
+    let bytes = [
+        (letters >> 8) as u8,
+        letters as u8,
+        (product >> 8) as u8,
+        product as u8,
+    ];
+    Some(u32::from_le_bytes(bytes) as u64)
+}
+
+/// Every device in the namespace, parents before their children
+pub fn devices<H: Host>(aml: &Interpreter<H>) -> Vec<Path> {
+    aml.namespace
+        .iter()
+        .filter(|(_, object)| matches!(object, Object::Scope(ScopeKind::Device)))
+        .map(|(path, _)| path.clone())
+        .collect()
+}
+
+/// What `_STA` says about `device`. One that can't be evaluated isn't there.
+pub fn status<H: Host>(aml: &mut Interpreter<H>, device: &Path) -> u64 {
+    match aml.evaluate(&device.child(*b"_STA"), Vec::new()) {
+        Ok(value) => aml.integer(&value).unwrap_or(0),
+        Err(AmlError::NotFound) => STA_DEFAULT,
+        Err(_) => 0,
+    }
+}
+
+/// Whether `device` has `id` as its `_HID` or in its `_CID`
+pub fn has_id<H: Host>(aml: &mut Interpreter<H>, device: &Path, id: &str) -> bool {
+    [*b"_HID", *b"_CID"].into_iter().any(|name| {
+        let Ok(value) = aml.evaluate(&device.child(name), Vec::new()) else {
+            return false;
+        };
+        // _CID can be a package of them
+        let ids = match value {
+            Value::Package(package) => package.borrow().clone(),
+            value => vec![value],
+        };
+        ids.iter().any(|value| match value {
+            Value::Integer(value) => eisa_id(id) == Some(*value),
+            Value::String(string) => string == id,
+            _ => false,
+        })
+    })
+}
+
+/// Run `\_SB._INI` and then the `_INI` of every device that's there, parents first. A device
+/// that's neither there nor working hides the ones in it. Gives how many ran and how many of
+/// those failed.
+pub fn initialize<H: Host>(aml: &mut Interpreter<H>) -> (usize, usize) {
+    let (mut ran, mut failed) = (0, 0);
+    let mut run = |aml: &mut Interpreter<H>, path: Path| {
+        if !matches!(aml.namespace.get(&path), Some(Object::Method(_))) {
+            return;
+        }
+        ran += 1;
+        if aml.evaluate(&path, Vec::new()).is_err() {
+            failed += 1;
+        }
+    };
+    run(aml, Path::parse("\\_SB._INI").unwrap());
+
+    let mut hidden: Vec<Path> = Vec::new();
+    for device in devices(aml) {
+        if hidden.iter().any(|path| device.0.starts_with(&path.0)) {
+            continue;
+        }
+        let status = status(aml, &device);
+        if status & (STA_PRESENT | STA_FUNCTIONING) == 0 {
+            hidden.push(device);
+            continue;
+        }
+        if status & STA_PRESENT != 0 {
+            run(aml, device.child(*b"_INI"));
+        }
+    }
+    (ran, failed)
+}
+
+/// SLP_TYPa and SLP_TYPb for sleep state `state`, from the `\_Sx` package, None if the
+/// firmware doesn't have it
+pub fn sleep_type<H: Host>(aml: &mut Interpreter<H>, state: u8) -> Option<(u8, u8)> {
+    let path = Path(vec![[b'_', b'S', b'0' + state, b'_']]);
+    let Ok(Value::Package(package)) = aml.evaluate(&path, Vec::new()) else {
+        return None;
+    };
+    let elements = package.borrow().clone();
+    let a = aml.integer(elements.first()?).ok()?;
+    // some old firmware packs both into the one element
+    let b = match elements.get(1) {
+        Some(b) => aml.integer(b).ok()?,
+        None => a >> 8,
+    };
+    Some((a as u8, b as u8))
+}
+
+/// Where a PCI interrupt pin goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// To a link device, with the interrupt in its `_CRS`: an ISA IRQ in PIC mode, a global
+    /// system interrupt once `\_PIC` has said it's APIC mode
+    Link(Interrupt),
+    /// Straight to a global system interrupt, which is level triggered and active low
+    Gsi(u32),
+}
+
+/// Where interrupt pin `pin`, 0 for INTA through 3 for INTD, of PCI device `device` on the
+/// root bus of `bridge` goes. None if its `_PRT` doesn't say.
+pub fn pci_route<H: Host>(
+    aml: &mut Interpreter<H>,
+    bridge: &Path,
+    device: u8,
+    pin: u8,
+) -> Result<Option<Route>, AmlError> {
+    let Value::Package(table) = aml.evaluate(&bridge.child(*b"_PRT"), Vec::new())? else {
+        return Err(AmlError::Type);
+    };
+    let entries = table.borrow().clone();
+    for entry in entries {
+        let Value::Package(entry) = entry else {
+            return Err(AmlError::Type);
+        };
+        let entry = entry.borrow().clone();
+        let [address, entry_pin, source, index] = &entry[..] else {
+            return Err(AmlError::Type);
+        };
+        // the function is always 0xffff, any function
+        if aml.integer(address)? >> 16 != device as u64 || aml.integer(entry_pin)? != pin as u64 {
+            continue;
+        }
+        let link = match source {
+            Value::Reference(reference @ (Reference::Name(..) | Reference::Object(_))) => {
+                aml.reference_path(reference)?
+            }
+            Value::String(path) => Path::parse(path).ok_or(AmlError::NotFound)?,
+            source => {
+                // a source of 0, and the index is the global system interrupt
+                if aml.integer(source)? != 0 {
+                    return Err(AmlError::Type);
+                }
+                return Ok(Some(Route::Gsi(aml.integer(index)? as u32)));
+            }
+        };
+        let crs = aml.evaluate(&link.child(*b"_CRS"), Vec::new())?;
+        let crs = aml.buffer(&crs)?;
+        return Ok(resource::interrupt(&crs)?.map(Route::Link));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::interp::Space;
+    use super::*;
+
+    struct NoHardware;
+
+    impl Host for NoHardware {
+        fn read(&mut self, _: Space, _: u64, _: u8) -> Result<u64, AmlError> {
+            Err(AmlError::Space(0))
+        }
+
+        fn write(&mut self, _: Space, _: u64, _: u8, _: u64) -> Result<(), AmlError> {
+            Err(AmlError::Space(0))
+        }
+
+        fn sleep_ms(&mut self, _ms: u64) {}
+
+        fn stall_us(&mut self, _us: u64) {}
+
+        fn timer(&mut self) -> u64 {
+            0
+        }
+    }
+
+    #[test_case]
+    fn eisa_ids() {
+        assert_eq!(eisa_id("PNP0A03"), Some(0x030a_d041));
+        assert_eq!(eisa_id("PNP0303"), Some(0x0303_d041));
+        assert_eq!(eisa_id("PNP0A0"), None);
+        assert_eq!(eisa_id("pnp0A03"), None);
+    }
+
+    #[test_case]
+    fn routes_pci_interrupts() {
+        static AML: &[u8] = &[
+            // Scope (\_SB) {
+            0x10, 0x4a, 0x05, 0x5c, 0x5f, 0x53, 0x42, 0x5f,
+            //   Device (LNKA) { Name (_HID, EisaId ("PNP0C0F"))
+            0x5b, 0x82, 0x1e, 0x4c, 0x4e, 0x4b, 0x41, 0x08, 0x5f, 0x48, 0x49, 0x44, 0x0c, 0x41,
+            0xd0, 0x0c, 0x0f,
+            //     Name (_CRS, ResourceTemplate () { IRQ (Level, ActiveLow, Shared) { 11 } }) }
+            0x08, 0x5f, 0x43, 0x52, 0x53, 0x11, 0x09, 0x0a, 0x06, 0x23, 0x00, 0x08, 0x18, 0x79,
+            0x00, //   Device (PCI0) { Name (_HID, EisaId ("PNP0A03"))
+            0x5b, 0x82, 0x31, 0x50, 0x43, 0x49, 0x30, 0x08, 0x5f, 0x48, 0x49, 0x44, 0x0c, 0x41,
+            0xd0, 0x0a, 0x03,
+            //     Name (_PRT, Package (0x02) { Package (0x04) { 0x0003FFFF, Zero, LNKA, Zero },
+            0x08, 0x5f, 0x50, 0x52, 0x54, 0x12, 0x1c, 0x02, 0x12, 0x0d, 0x04, 0x0c, 0xff, 0xff,
+            0x03, 0x00, 0x00, 0x4c, 0x4e, 0x4b, 0x41, 0x00,
+            //       Package (0x04) { 0x0004FFFF, One, Zero, 0x11 } }) } }
+            0x12, 0x0b, 0x04, 0x0c, 0xff, 0xff, 0x04, 0x00, 0x01, 0x00, 0x0a, 0x11,
+        ];
+        let mut aml = Interpreter::new(NoHardware, 2);
+        aml.load(AML).unwrap();
+        let bridges: Vec<Path> = devices(&aml)
+            .into_iter()
+            .filter(|device| has_id(&mut aml, device, PCI_ROOT_BRIDGES[0]))
+            .collect();
+        assert_eq!(bridges, [Path::parse("\\_SB.PCI0").unwrap()]);
+
+        let link = Interrupt {
+            number: 11,
+            edge: false,
+            active_low: true,
+            shared: true,
+        };
+        assert_eq!(
+            pci_route(&mut aml, &bridges[0], 3, 0),
+            Ok(Some(Route::Link(link)))
+        );
+        assert_eq!(
+            pci_route(&mut aml, &bridges[0], 4, 1),
+            Ok(Some(Route::Gsi(17)))
+        );
+        assert_eq!(pci_route(&mut aml, &bridges[0], 4, 0), Ok(None));
+    }
+
+    #[test_case]
+    fn finds_sleep_types() {
+        // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero })
+        static AML: &[u8] = &[
+            0x08, 0x5c, 0x5f, 0x53, 0x35, 0x5f, 0x12, 0x07, 0x04, 0x0a, 0x05, 0x00, 0x00, 0x00,
+        ];
+        let mut aml = Interpreter::new(NoHardware, 2);
+        aml.load(AML).unwrap();
+        assert_eq!(sleep_type(&mut aml, 5), Some((5, 0)));
+        assert_eq!(sleep_type(&mut aml, 3), None);
+    }
+}
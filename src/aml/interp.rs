@@ -0,0 +1,1916 @@
+//! The AML interpreter
+//!
+//! AML is bytecode: a table's body is a list of terms, each an opcode and its operands, with
+//! a package length in front of anything with a body of its own. Loading a table runs its
+//! terms at the root, which is what defines the objects, and evaluating a method runs its
+//! body in a frame of its own, with the locals and arguments. Objects a method makes are
+//! gone once it returns.
+//!
+//! Hardware is reached through a [`Host`], for the fields in operation regions and for waits,
+//! so the interpreter itself only works on the bytes it's given.
+//!
+//! links:
+//! - ACPI spec, AML grammar: <https://uefi.org/specs/ACPI/6.5/20_AML_Specification.html>
+//!
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::namespace::{
+    is_lead_char, is_name_char, BufferField, Field, FieldKind, Method, NameString, Namespace,
+    Object, Path, Region, ScopeKind, Seg, Update,
+};
+use super::value::{Reference, Value};
+
+/// Why evaluating something didn't work
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmlError {
+    /// The bytecode ends in the middle of a term
+    Truncated,
+    /// An opcode the interpreter doesn't do, extended ones with 0x5b in the high byte
+    Unsupported(u16),
+    /// A name that isn't in the namespace
+    NotFound,
+    /// Something's already there with the name
+    Exists,
+    /// A value of the wrong type for what it's used for
+    Type,
+    /// An index past the end of a buffer, package, or string
+    Index,
+    DivideByZero,
+    /// Methods called each other more than [`MAX_DEPTH`] deep
+    TooDeep,
+    /// A loop went around more than [`MAX_LOOPS`] times
+    Loop,
+    /// A field in a region of an address space there's no way to get at
+    Space(u8),
+    /// The AML gave up with `Fatal`
+    Fatal,
+}
+
+/// Method calls that can be nested
+pub const MAX_DEPTH: usize = 32;
+/// Times a `While` can loop before it's taken to be stuck, polling hardware that isn't going
+/// to answer
+pub const MAX_LOOPS: usize = 100_000;
+
+// address spaces
+pub const SYSTEM_MEMORY: u8 = 0;
+pub const SYSTEM_IO: u8 = 1;
+pub const PCI_CONFIG: u8 = 2;
+
+/// The interpreter's way to the hardware
+pub trait Host {
+    /// Read `width` bytes, 1, 2, 4, or 8, at `address` in `space`
+    fn read(&mut self, space: Space, address: u64, width: u8) -> Result<u64, AmlError>;
+    fn write(&mut self, space: Space, address: u64, width: u8, value: u64) -> Result<(), AmlError>;
+    fn sleep_ms(&mut self, ms: u64);
+    fn stall_us(&mut self, us: u64);
+    /// A count in 100 ns units, for `Timer`
+    fn timer(&mut self) -> u64;
+}
+
+/// Where a region is, with what's needed to get at it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Space {
+    Memory,
+    Io,
+    Pci { bus: u8, device: u8, function: u8 },
+}
+
+// opcodes
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ALIAS_OP: u8 = 0x06;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const VAR_PACKAGE_OP: u8 = 0x13;
+const METHOD_OP: u8 = 0x14;
+const EXTERNAL_OP: u8 = 0x15;
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+const EXT_PREFIX: u8 = 0x5b;
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_PREFIX: u8 = b'^';
+const LOCAL0_OP: u8 = 0x60;
+const LOCAL7_OP: u8 = 0x67;
+const ARG0_OP: u8 = 0x68;
+const ARG6_OP: u8 = 0x6e;
+const STORE_OP: u8 = 0x70;
+const REF_OF_OP: u8 = 0x71;
+const ADD_OP: u8 = 0x72;
+const CONCAT_OP: u8 = 0x73;
+const SUBTRACT_OP: u8 = 0x74;
+const INCREMENT_OP: u8 = 0x75;
+const DECREMENT_OP: u8 = 0x76;
+const MULTIPLY_OP: u8 = 0x77;
+const DIVIDE_OP: u8 = 0x78;
+const SHIFT_LEFT_OP: u8 = 0x79;
+const SHIFT_RIGHT_OP: u8 = 0x7a;
+const AND_OP: u8 = 0x7b;
+const NAND_OP: u8 = 0x7c;
+const OR_OP: u8 = 0x7d;
+const NOR_OP: u8 = 0x7e;
+const XOR_OP: u8 = 0x7f;
+const NOT_OP: u8 = 0x80;
+const FIND_SET_LEFT_BIT_OP: u8 = 0x81;
+const FIND_SET_RIGHT_BIT_OP: u8 = 0x82;
+const DEREF_OF_OP: u8 = 0x83;
+const CONCAT_RES_OP: u8 = 0x84;
+const MOD_OP: u8 = 0x85;
+const NOTIFY_OP: u8 = 0x86;
+const SIZE_OF_OP: u8 = 0x87;
+const INDEX_OP: u8 = 0x88;
+const MATCH_OP: u8 = 0x89;
+const CREATE_DWORD_FIELD_OP: u8 = 0x8a;
+const CREATE_WORD_FIELD_OP: u8 = 0x8b;
+const CREATE_BYTE_FIELD_OP: u8 = 0x8c;
+const CREATE_BIT_FIELD_OP: u8 = 0x8d;
+const OBJECT_TYPE_OP: u8 = 0x8e;
+const CREATE_QWORD_FIELD_OP: u8 = 0x8f;
+const LAND_OP: u8 = 0x90;
+const LOR_OP: u8 = 0x91;
+const LNOT_OP: u8 = 0x92;
+const LEQUAL_OP: u8 = 0x93;
+const LGREATER_OP: u8 = 0x94;
+const LLESS_OP: u8 = 0x95;
+const TO_BUFFER_OP: u8 = 0x96;
+const TO_DECIMAL_STRING_OP: u8 = 0x97;
+const TO_HEX_STRING_OP: u8 = 0x98;
+const TO_INTEGER_OP: u8 = 0x99;
+const TO_STRING_OP: u8 = 0x9c;
+const COPY_OBJECT_OP: u8 = 0x9d;
+const MID_OP: u8 = 0x9e;
+const CONTINUE_OP: u8 = 0x9f;
+const IF_OP: u8 = 0xa0;
+const ELSE_OP: u8 = 0xa1;
+const WHILE_OP: u8 = 0xa2;
+const NOOP_OP: u8 = 0xa3;
+const RETURN_OP: u8 = 0xa4;
+const BREAK_OP: u8 = 0xa5;
+const BREAKPOINT_OP: u8 = 0xcc;
+const ONES_OP: u8 = 0xff;
+
+// after the extended prefix
+const MUTEX_OP: u8 = 0x01;
+const EVENT_OP: u8 = 0x02;
+const COND_REF_OF_OP: u8 = 0x12;
+const CREATE_FIELD_OP: u8 = 0x13;
+const STALL_OP: u8 = 0x21;
+const SLEEP_OP: u8 = 0x22;
+const ACQUIRE_OP: u8 = 0x23;
+const SIGNAL_OP: u8 = 0x24;
+const WAIT_OP: u8 = 0x25;
+const RESET_OP: u8 = 0x26;
+const RELEASE_OP: u8 = 0x27;
+const FROM_BCD_OP: u8 = 0x28;
+const TO_BCD_OP: u8 = 0x29;
+const REVISION_OP: u8 = 0x30;
+const DEBUG_OP: u8 = 0x31;
+const FATAL_OP: u8 = 0x32;
+const TIMER_OP: u8 = 0x33;
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+const PROCESSOR_OP: u8 = 0x83;
+const POWER_RES_OP: u8 = 0x84;
+const THERMAL_ZONE_OP: u8 = 0x85;
+const INDEX_FIELD_OP: u8 = 0x86;
+const BANK_FIELD_OP: u8 = 0x87;
+
+/// The end tag that closes a resource template
+const END_TAG: u8 = 0x79;
+
+/// Bytecode being run, up to the end of whatever it's in
+struct Code {
+    aml: &'static [u8],
+    pos: usize,
+}
+
+impl Code {
+    fn peek(&self) -> Result<u8, AmlError> {
+        self.aml.get(self.pos).copied().ok_or(AmlError::Truncated)
+    }
+
+    fn byte(&mut self) -> Result<u8, AmlError> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'static [u8], AmlError> {
+        let bytes = self
+            .aml
+            .get(self.pos..self.pos + n)
+            .ok_or(AmlError::Truncated)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn integer(&mut self, n: usize) -> Result<u64, AmlError> {
+        let bytes = self.bytes(n)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | byte as u64))
+    }
+
+    /// A package length as it's written: the top two bits of the first byte say how many
+    /// more bytes there are, and those have the higher bits
+    fn pkg_length_value(&mut self) -> Result<usize, AmlError> {
+        let lead = self.byte()?;
+        let more = (lead >> 6) as usize;
+        if more == 0 {
+            return Ok((lead & 0x3f) as usize);
+        }
+        let mut len = (lead & 0xf) as usize;
+        for i in 0..more {
+            len |= (self.byte()? as usize) << (4 + 8 * i);
+        }
+        Ok(len)
+    }
+
+    /// Where what the package length in front of it covers ends. The length counts itself.
+    fn pkg_end(&mut self) -> Result<usize, AmlError> {
+        let start = self.pos;
+        let end = start + self.pkg_length_value()?;
+        if end > self.aml.len() || end < self.pos {
+            return Err(AmlError::Truncated);
+        }
+        Ok(end)
+    }
+
+    fn seg(&mut self) -> Result<Seg, AmlError> {
+        let bytes = self.bytes(4)?;
+        if !is_lead_char(bytes[0]) || !bytes[1..].iter().all(|&c| is_name_char(c)) {
+            return Err(AmlError::Unsupported(bytes[0] as u16));
+        }
+        Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn name_string(&mut self) -> Result<NameString, AmlError> {
+        let mut name = NameString {
+            root: false,
+            up: 0,
+            segs: Vec::new(),
+        };
+        if self.peek()? == ROOT_CHAR {
+            self.pos += 1;
+            name.root = true;
+        } else {
+            while self.peek()? == PARENT_PREFIX {
+                self.pos += 1;
+                name.up += 1;
+            }
+        }
+        let count = match self.peek()? {
+            ZERO_OP => {
+                self.pos += 1;
+                0
+            }
+            DUAL_NAME_PREFIX => {
+                self.pos += 1;
+                2
+            }
+            MULTI_NAME_PREFIX => {
+                self.pos += 1;
+                self.byte()? as usize
+            }
+            _ => 1,
+        };
+        for _ in 0..count {
+            name.segs.push(self.seg()?);
+        }
+        Ok(name)
+    }
+
+    /// Whether a name starts here
+    fn at_name(&self) -> bool {
+        self.peek().is_ok_and(|c| {
+            is_lead_char(c)
+                || matches!(
+                    c,
+                    ROOT_CHAR | PARENT_PREFIX | DUAL_NAME_PREFIX | MULTI_NAME_PREFIX
+                )
+        })
+    }
+}
+
+/// What a term did to the flow of the method it's in
+enum Flow {
+    Next,
+    Return(Value),
+    Break,
+    Continue,
+}
+
+/// A method being run, or a table being loaded
+struct Frame {
+    scope: Path,
+    locals: [Value; 8],
+    args: [Value; 7],
+    /// Objects the method made, which go once it returns. None while loading, when they stay.
+    made: Option<Vec<Path>>,
+}
+
+/// Where a result goes
+enum Target {
+    None,
+    Local(usize),
+    Arg(usize),
+    Debug,
+    Name(Path),
+    Reference(Reference),
+}
+
+pub struct Interpreter<H: Host> {
+    pub namespace: Namespace,
+    pub host: H,
+    /// Bytes in an integer: 4 for a revision 1 DSDT, 8 from revision 2
+    int_bytes: usize,
+    depth: usize,
+    /// Terms that failed while loading, which get skipped to the end of their scope
+    pub load_errors: usize,
+}
+
+impl<H: Host> Interpreter<H> {
+    /// An interpreter with integers as wide as the DSDT's `revision` says
+    pub fn new(host: H, revision: u8) -> Interpreter<H> {
+        let mut namespace = Namespace::new();
+        let predefined = [
+            (
+                "\\_OSI",
+                Object::Method(Method::Native { args: 1, func: osi }),
+            ),
+            (
+                "\\_OS",
+                Object::Value(Value::String("Microsoft Windows NT".into())),
+            ),
+            ("\\_REV", Object::Value(Value::Integer(2))),
+            ("\\_GL", Object::Mutex),
+        ];
+        for (path, object) in predefined {
+            // the fresh namespace has the root
+            let _ = namespace.insert(Path::parse(path).unwrap(), object);
+        }
+        Interpreter {
+            namespace,
+            host,
+            int_bytes: if revision < 2 { 4 } else { 8 },
+            depth: 0,
+            load_errors: 0,
+        }
+    }
+
+    fn ones(&self) -> u64 {
+        if self.int_bytes == 4 {
+            u32::MAX as u64
+        } else {
+            u64::MAX
+        }
+    }
+
+    fn truncate(&self, value: u64) -> u64 {
+        value & self.ones()
+    }
+
+    fn boolean(&self, value: bool) -> Value {
+        Value::Integer(if value { self.ones() } else { 0 })
+    }
+
+    /// Run the terms of a table's body, adding what they define to the namespace
+    pub fn load(&mut self, aml: &'static [u8]) -> Result<(), AmlError> {
+        let mut frame = Frame {
+            scope: Path::ROOT,
+            locals: Default::default(),
+            args: Default::default(),
+            made: None,
+        };
+        let mut code = Code { aml, pos: 0 };
+        self.body(&mut code, aml.len(), &mut frame).map(|_| ())
+    }
+
+    /// Evaluate the object at `path` with `args`: call it if it's a method, read it if it's a
+    /// field, or just give its value
+    pub fn evaluate(&mut self, path: &Path, args: Vec<Value>) -> Result<Value, AmlError> {
+        match self.namespace.get(path) {
+            Some(Object::Method(_)) => self.call(path, args),
+            Some(_) => self.read_object(path),
+            None => Err(AmlError::NotFound),
+        }
+    }
+
+    /// The integer `value` is, or refers to
+    pub fn integer(&mut self, value: &Value) -> Result<u64, AmlError> {
+        match value {
+            // what a reference refers to, but not on to what that refers to, which can be
+            // itself
+            Value::Reference(reference) => self.deref(reference)?.to_integer(self.int_bytes),
+            value => value.to_integer(self.int_bytes),
+        }
+    }
+
+    /// The bytes `value` is, or refers to
+    pub fn buffer(&mut self, value: &Value) -> Result<Vec<u8>, AmlError> {
+        match value {
+            Value::Reference(reference) => self.deref(reference)?.to_buffer(self.int_bytes),
+            value => value.to_buffer(self.int_bytes),
+        }
+    }
+
+    /// The path a package element or other reference names
+    pub fn reference_path(&self, reference: &Reference) -> Result<Path, AmlError> {
+        match reference {
+            Reference::Object(path) => Ok(path.clone()),
+            Reference::Name(scope, name) => {
+                self.namespace.lookup(name, scope).ok_or(AmlError::NotFound)
+            }
+            _ => Err(AmlError::Type),
+        }
+    }
+
+    fn call(&mut self, path: &Path, args: Vec<Value>) -> Result<Value, AmlError> {
+        let method = match self.namespace.get(path) {
+            Some(Object::Method(method)) => method.clone(),
+            _ => return Err(AmlError::Type),
+        };
+        let body = match method {
+            Method::Native { func, .. } => return func(&args),
+            Method::Aml { body, .. } => body,
+        };
+        if self.depth >= MAX_DEPTH {
+            return Err(AmlError::TooDeep);
+        }
+
+        let mut frame = Frame {
+            scope: path.clone(),
+            locals: Default::default(),
+            args: Default::default(),
+            made: Some(Vec::new()),
+        };
+        for (slot, arg) in frame.args.iter_mut().zip(args) {
+            *slot = arg;
+        }
+        self.depth += 1;
+        let mut code = Code { aml: body, pos: 0 };
+        let result = self.term_list(&mut code, body.len(), &mut frame);
+        self.depth -= 1;
+        for made in frame.made.iter().flatten().rev() {
+            self.namespace.remove(made);
+        }
+        match result? {
+            Flow::Return(value) => Ok(value),
+            _ => Ok(Value::Integer(0)),
+        }
+    }
+
+    /// Run terms up to `end`, which nothing in them can look past, so an `If` at the end of
+    /// a list doesn't take the `Else` after the list for its own
+    fn term_list(
+        &mut self,
+        code: &mut Code,
+        end: usize,
+        frame: &mut Frame,
+    ) -> Result<Flow, AmlError> {
+        let mut inner = Code {
+            aml: &code.aml[..end],
+            pos: code.pos,
+        };
+        let mut flow = Ok(Flow::Next);
+        while inner.pos < end {
+            match self.term(&mut inner, frame) {
+                Ok(Flow::Next) => {}
+                result => {
+                    flow = result;
+                    break;
+                }
+            }
+        }
+        code.pos = inner.pos;
+        flow
+    }
+
+    /// The body of a scope, a device, or something else that holds objects. While loading, a
+    /// term that fails loses the rest of the body but not the table.
+    fn body(&mut self, code: &mut Code, end: usize, frame: &mut Frame) -> Result<Flow, AmlError> {
+        if frame.made.is_some() {
+            return self.term_list(code, end, frame);
+        }
+        if self.term_list(code, end, frame).is_err() {
+            self.load_errors += 1;
+        }
+        code.pos = end;
+        Ok(Flow::Next)
+    }
+
+    /// Add an object called `name` in the frame's scope
+    fn create(
+        &mut self,
+        name: &NameString,
+        object: Object,
+        frame: &mut Frame,
+    ) -> Result<(), AmlError> {
+        let path = name.resolve(&frame.scope).ok_or(AmlError::NotFound)?;
+        self.namespace.insert(path.clone(), object)?;
+        if let Some(made) = &mut frame.made {
+            made.push(path);
+        }
+        Ok(())
+    }
+
+    /// Run the contents of the scope-like object `name`. A `Scope` opens one that's there, the
+    /// others make theirs.
+    fn scope(
+        &mut self,
+        code: &mut Code,
+        end: usize,
+        name: &NameString,
+        kind: ScopeKind,
+        frame: &mut Frame,
+    ) -> Result<Flow, AmlError> {
+        let path = if kind == ScopeKind::Scope {
+            self.namespace
+                .lookup(name, &frame.scope)
+                .ok_or(AmlError::NotFound)?
+        } else {
+            self.create(name, Object::Scope(kind), frame)?;
+            name.resolve(&frame.scope).ok_or(AmlError::NotFound)?
+        };
+        let outer = core::mem::replace(&mut frame.scope, path);
+        let flow = self.body(code, end, frame);
+        frame.scope = outer;
+        flow.map(|_| Flow::Next)
+    }
+
+    fn term(&mut self, code: &mut Code, frame: &mut Frame) -> Result<Flow, AmlError> {
+        let op = code.peek()?;
+        match op {
+            ALIAS_OP => {
+                code.pos += 1;
+                let source = code.name_string()?;
+                let alias = code.name_string()?;
+                let target = self
+                    .namespace
+                    .lookup(&source, &frame.scope)
+                    .ok_or(AmlError::NotFound)?;
+                self.create(&alias, Object::Alias(target), frame)?;
+            }
+            NAME_OP => {
+                code.pos += 1;
+                let name = code.name_string()?;
+                let value = self.term_arg(code, frame)?;
+                self.create(&name, Object::Value(value), frame)?;
+            }
+            SCOPE_OP => {
+                code.pos += 1;
+                let end = code.pkg_end()?;
+                let name = code.name_string()?;
+                return self.scope(code, end, &name, ScopeKind::Scope, frame);
+            }
+            METHOD_OP => {
+                code.pos += 1;
+                let end = code.pkg_end()?;
+                let name = code.name_string()?;
+                let flags = code.byte()?;
+                let body = &code.aml[code.pos..end];
+                code.pos = end;
+                let method = Method::Aml {
+                    args: flags & 7,
+                    body,
+                };
+                self.create(&name, Object::Method(method), frame)?;
+            }
+            EXTERNAL_OP => {
+                code.pos += 1;
+                code.name_string()?;
+                code.bytes(2)?;
+            }
+            CREATE_DWORD_FIELD_OP
+            | CREATE_WORD_FIELD_OP
+            | CREATE_BYTE_FIELD_OP
+            | CREATE_BIT_FIELD_OP
+            | CREATE_QWORD_FIELD_OP => {
+                code.pos += 1;
+                let buffer = self.term_arg(code, frame)?;
+                let index = self.term_arg(code, frame)?;
+                let index = self.integer(&index)? as usize;
+                let (bit_offset, bit_len) = match op {
+                    CREATE_BIT_FIELD_OP => (index, 1),
+                    CREATE_BYTE_FIELD_OP => (index * 8, 8),
+                    CREATE_WORD_FIELD_OP => (index * 8, 16),
+                    CREATE_DWORD_FIELD_OP => (index * 8, 32),
+                    _ => (index * 8, 64),
+                };
+                let name = code.name_string()?;
+                self.create_buffer_field(buffer, bit_offset, bit_len, &name, frame)?;
+            }
+            IF_OP => return self.if_else(code, frame),
+            ELSE_OP => return Err(AmlError::Unsupported(ELSE_OP as u16)),
+            WHILE_OP => return self.while_loop(code, frame),
+            NOOP_OP | BREAKPOINT_OP => code.pos += 1,
+            RETURN_OP => {
+                code.pos += 1;
+                let value = self.term_arg(code, frame)?;
+                return Ok(Flow::Return(value));
+            }
+            BREAK_OP => {
+                code.pos += 1;
+                return Ok(Flow::Break);
+            }
+            CONTINUE_OP => {
+                code.pos += 1;
+                return Ok(Flow::Continue);
+            }
+            NOTIFY_OP => {
+                // nothing listens for notifications
+                code.pos += 1;
+                self.target(code, frame)?;
+                self.term_arg(code, frame)?;
+            }
+            EXT_PREFIX => return self.ext_term(code, frame),
+            _ => {
+                self.term_arg(code, frame)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn ext_term(&mut self, code: &mut Code, frame: &mut Frame) -> Result<Flow, AmlError> {
+        let op = *code.aml.get(code.pos + 1).ok_or(AmlError::Truncated)?;
+        let ext = |op: u8| (EXT_PREFIX as u16) << 8 | op as u16;
+        match op {
+            MUTEX_OP => {
+                code.pos += 2;
+                let name = code.name_string()?;
+                code.byte()?;
+                self.create(&name, Object::Mutex, frame)?;
+            }
+            EVENT_OP => {
+                code.pos += 2;
+                let name = code.name_string()?;
+                self.create(&name, Object::Event, frame)?;
+            }
+            CREATE_FIELD_OP => {
+                code.pos += 2;
+                let buffer = self.term_arg(code, frame)?;
+                let bit_offset = self.term_arg(code, frame)?;
+                let bit_offset = self.integer(&bit_offset)? as usize;
+                let bit_len = self.term_arg(code, frame)?;
+                let bit_len = self.integer(&bit_len)? as usize;
+                let name = code.name_string()?;
+                self.create_buffer_field(buffer, bit_offset, bit_len, &name, frame)?;
+            }
+            STALL_OP | SLEEP_OP => {
+                code.pos += 2;
+                let time = self.term_arg(code, frame)?;
+                let time = self.integer(&time)?;
+                if op == STALL_OP {
+                    self.host.stall_us(time);
+                } else {
+                    self.host.sleep_ms(time);
+                }
+            }
+            SIGNAL_OP | RESET_OP | RELEASE_OP => {
+                // there's only ever the one thread in the interpreter
+                code.pos += 2;
+                self.target(code, frame)?;
+            }
+            FATAL_OP => return Err(AmlError::Fatal),
+            OP_REGION_OP => {
+                code.pos += 2;
+                let name = code.name_string()?;
+                let space = code.byte()?;
+                let offset = self.term_arg(code, frame)?;
+                let offset = self.integer(&offset)?;
+                let len = self.term_arg(code, frame)?;
+                let len = self.integer(&len)?;
+                let region = Region {
+                    space,
+                    offset,
+                    len,
+                    pci: None,
+                };
+                self.create(&name, Object::Region(region), frame)?;
+            }
+            FIELD_OP | INDEX_FIELD_OP | BANK_FIELD_OP => {
+                code.pos += 2;
+                let end = code.pkg_end()?;
+                let first = code.name_string()?;
+                let first = self
+                    .namespace
+                    .lookup(&first, &frame.scope)
+                    .ok_or(AmlError::NotFound)?;
+                let kind = match op {
+                    FIELD_OP => FieldKind::Region(first),
+                    INDEX_FIELD_OP => {
+                        let data = code.name_string()?;
+                        let data = self
+                            .namespace
+                            .lookup(&data, &frame.scope)
+                            .ok_or(AmlError::NotFound)?;
+                        FieldKind::Index { index: first, data }
+                    }
+                    _ => {
+                        let bank = code.name_string()?;
+                        let bank = self
+                            .namespace
+                            .lookup(&bank, &frame.scope)
+                            .ok_or(AmlError::NotFound)?;
+                        let value = self.term_arg(code, frame)?;
+                        let value = self.integer(&value)?;
+                        FieldKind::Bank {
+                            region: first,
+                            bank,
+                            value,
+                        }
+                    }
+                };
+                let flags = code.byte()?;
+                self.field_list(code, end, kind, flags, frame)?;
+            }
+            DEVICE_OP | PROCESSOR_OP | POWER_RES_OP | THERMAL_ZONE_OP => {
+                code.pos += 2;
+                let end = code.pkg_end()?;
+                let name = code.name_string()?;
+                let kind = match op {
+                    DEVICE_OP => ScopeKind::Device,
+                    PROCESSOR_OP => {
+                        // the processor ID, and the address and length of its P_BLK
+                        code.bytes(6)?;
+                        ScopeKind::Processor
+                    }
+                    POWER_RES_OP => {
+                        // the system level and resource order
+                        code.bytes(3)?;
+                        ScopeKind::PowerResource
+                    }
+                    _ => ScopeKind::ThermalZone,
+                };
+                return self.scope(code, end, &name, kind, frame);
+            }
+            _ => {
+                if matches!(
+                    op,
+                    COND_REF_OF_OP
+                        | ACQUIRE_OP
+                        | WAIT_OP
+                        | FROM_BCD_OP
+                        | TO_BCD_OP
+                        | REVISION_OP
+                        | TIMER_OP
+                ) {
+                    self.term_arg(code, frame)?;
+                } else {
+                    return Err(AmlError::Unsupported(ext(op)));
+                }
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn if_else(&mut self, code: &mut Code, frame: &mut Frame) -> Result<Flow, AmlError> {
+        code.pos += 1;
+        let end = code.pkg_end()?;
+        let predicate = self.term_arg(code, frame)?;
+        let taken = self.integer(&predicate)? != 0;
+        let flow = if taken {
+            self.term_list(code, end, frame)?
+        } else {
+            Flow::Next
+        };
+        code.pos = end;
+        if code.peek().ok() == Some(ELSE_OP) {
+            code.pos += 1;
+            let end = code.pkg_end()?;
+            if !taken {
+                let flow = self.term_list(code, end, frame)?;
+                code.pos = end;
+                return Ok(flow);
+            }
+            code.pos = end;
+        }
+        Ok(flow)
+    }
+
+    fn while_loop(&mut self, code: &mut Code, frame: &mut Frame) -> Result<Flow, AmlError> {
+        code.pos += 1;
+        let end = code.pkg_end()?;
+        let start = code.pos;
+        for _ in 0..MAX_LOOPS {
+            code.pos = start;
+            let predicate = self.term_arg(code, frame)?;
+            if self.integer(&predicate)? == 0 {
+                code.pos = end;
+                return Ok(Flow::Next);
+            }
+            match self.term_list(code, end, frame)? {
+                Flow::Next | Flow::Continue => {}
+                Flow::Break => {
+                    code.pos = end;
+                    return Ok(Flow::Next);
+                }
+                flow => return Ok(flow),
+            }
+        }
+        Err(AmlError::Loop)
+    }
+
+    fn field_list(
+        &mut self,
+        code: &mut Code,
+        end: usize,
+        kind: FieldKind,
+        flags: u8,
+        frame: &mut Frame,
+    ) -> Result<(), AmlError> {
+        let mut access_type = flags & 0xf;
+        let update = match (flags >> 5) & 3 {
+            1 => Update::WriteAsOnes,
+            2 => Update::WriteAsZeros,
+            _ => Update::Preserve,
+        };
+        let mut bit_offset = 0;
+        while code.pos < end {
+            match code.peek()? {
+                // reserved
+                0x00 => {
+                    code.pos += 1;
+                    bit_offset += code.pkg_length_value()? as u64;
+                }
+                // access type and attributes
+                0x01 => {
+                    code.pos += 1;
+                    access_type = code.byte()? & 0xf;
+                    code.byte()?;
+                }
+                // extended access, which adds a length for the attributes
+                0x03 => {
+                    code.pos += 1;
+                    access_type = code.byte()? & 0xf;
+                    code.bytes(2)?;
+                }
+                0x02 => return Err(AmlError::Unsupported(0x02)),
+                _ => {
+                    let seg = code.seg()?;
+                    let bit_len = code.pkg_length_value()? as u64;
+                    let field = Field {
+                        kind: kind.clone(),
+                        bit_offset,
+                        bit_len,
+                        access: access_width(access_type, bit_offset, bit_len),
+                        update,
+                    };
+                    let name = NameString {
+                        root: false,
+                        up: 0,
+                        segs: vec![seg],
+                    };
+                    self.create(&name, Object::Field(field), frame)?;
+                    bit_offset += bit_len;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn create_buffer_field(
+        &mut self,
+        buffer: Value,
+        bit_offset: usize,
+        bit_len: usize,
+        name: &NameString,
+        frame: &mut Frame,
+    ) -> Result<(), AmlError> {
+        let buffer = match buffer {
+            Value::Buffer(buffer) => buffer,
+            Value::Reference(reference) => match self.deref(&reference)? {
+                Value::Buffer(buffer) => buffer,
+                _ => return Err(AmlError::Type),
+            },
+            _ => return Err(AmlError::Type),
+        };
+        if bit_offset + bit_len > buffer.borrow().len() * 8 {
+            return Err(AmlError::Index);
+        }
+        let field = BufferField {
+            buffer,
+            bit_offset,
+            bit_len,
+        };
+        self.create(name, Object::BufferField(field), frame)
+    }
+
+    /// Evaluate an operand
+    fn term_arg(&mut self, code: &mut Code, frame: &mut Frame) -> Result<Value, AmlError> {
+        let op = code.peek()?;
+        if code.at_name() {
+            let name = code.name_string()?;
+            let path = self
+                .namespace
+                .lookup(&name, &frame.scope)
+                .ok_or(AmlError::NotFound)?;
+            if let Some(Object::Method(method)) = self.namespace.get(&path) {
+                let count = method.args();
+                let mut args = Vec::new();
+                for _ in 0..count {
+                    args.push(self.term_arg(code, frame)?);
+                }
+                return self.call(&path, args);
+            }
+            return self.read_object(&path);
+        }
+
+        code.pos += 1;
+        let value = match op {
+            ZERO_OP => Value::Integer(0),
+            ONE_OP => Value::Integer(1),
+            ONES_OP => Value::Integer(self.ones()),
+            BYTE_PREFIX => Value::Integer(code.integer(1)?),
+            WORD_PREFIX => Value::Integer(code.integer(2)?),
+            DWORD_PREFIX => Value::Integer(code.integer(4)?),
+            QWORD_PREFIX => Value::Integer(self.truncate(code.integer(8)?)),
+            STRING_PREFIX => {
+                let rest = &code.aml[code.pos..];
+                let len = rest
+                    .iter()
+                    .position(|&c| c == 0)
+                    .ok_or(AmlError::Truncated)?;
+                code.pos += len + 1;
+                Value::String(rest[..len].iter().map(|&c| c as char).collect())
+            }
+            BUFFER_OP => {
+                let end = code.pkg_end()?;
+                let size = self.term_arg(code, frame)?;
+                let size = self.integer(&size)? as usize;
+                let init = code.aml.get(code.pos..end).ok_or(AmlError::Truncated)?;
+                code.pos = end;
+                let mut buffer = vec![0; size.max(init.len())];
+                buffer[..init.len()].copy_from_slice(init);
+                Value::buffer(buffer)
+            }
+            PACKAGE_OP | VAR_PACKAGE_OP => {
+                let end = code.pkg_end()?;
+                let count = if op == PACKAGE_OP {
+                    code.byte()? as usize
+                } else {
+                    let count = self.term_arg(code, frame)?;
+                    self.integer(&count)? as usize
+                };
+                let mut elements = Vec::new();
+                while code.pos < end {
+                    let element = if code.at_name() {
+                        let name = code.name_string()?;
+                        Value::Reference(Reference::Name(frame.scope.clone(), name))
+                    } else {
+                        self.term_arg(code, frame)?
+                    };
+                    elements.push(element);
+                }
+                if elements.len() < count {
+                    elements.resize(count, Value::Uninitialized);
+                }
+                Value::package(elements)
+            }
+            LOCAL0_OP..=LOCAL7_OP => frame.locals[(op - LOCAL0_OP) as usize].clone(),
+            ARG0_OP..=ARG6_OP => frame.args[(op - ARG0_OP) as usize].clone(),
+            STORE_OP | COPY_OBJECT_OP => {
+                let value = self.term_arg(code, frame)?;
+                let target = self.target(code, frame)?;
+                self.store(value.clone(), &target, frame, op == COPY_OBJECT_OP)?;
+                value
+            }
+            REF_OF_OP => match self.target(code, frame)? {
+                Target::Name(path) => Value::Reference(Reference::Object(path)),
+                Target::Reference(reference) => Value::Reference(reference),
+                _ => return Err(AmlError::Type),
+            },
+            ADD_OP | SUBTRACT_OP | MULTIPLY_OP | SHIFT_LEFT_OP | SHIFT_RIGHT_OP | AND_OP
+            | NAND_OP | OR_OP | NOR_OP | XOR_OP | MOD_OP => {
+                let a = self.term_arg(code, frame)?;
+                let a = self.integer(&a)?;
+                let b = self.term_arg(code, frame)?;
+                let b = self.integer(&b)?;
+                let result = match op {
+                    ADD_OP => a.wrapping_add(b),
+                    SUBTRACT_OP => a.wrapping_sub(b),
+                    MULTIPLY_OP => a.wrapping_mul(b),
+                    SHIFT_LEFT_OP => a.checked_shl(b as u32).unwrap_or(0),
+                    SHIFT_RIGHT_OP => a.checked_shr(b as u32).unwrap_or(0),
+                    AND_OP => a & b,
+                    NAND_OP => !(a & b),
+                    OR_OP => a | b,
+                    NOR_OP => !(a | b),
+                    XOR_OP => a ^ b,
+                    _ => a.checked_rem(b).ok_or(AmlError::DivideByZero)?,
+                };
+                let result = Value::Integer(self.truncate(result));
+                let target = self.target(code, frame)?;
+                self.store(result.clone(), &target, frame, false)?;
+                result
+            }
+            DIVIDE_OP => {
+                let a = self.term_arg(code, frame)?;
+                let a = self.integer(&a)?;
+                let b = self.term_arg(code, frame)?;
+                let b = self.integer(&b)?;
+                if b == 0 {
+                    return Err(AmlError::DivideByZero);
+                }
+                let remainder = self.target(code, frame)?;
+                self.store(Value::Integer(a % b), &remainder, frame, false)?;
+                let quotient = self.target(code, frame)?;
+                self.store(Value::Integer(a / b), &quotient, frame, false)?;
+                Value::Integer(a / b)
+            }
+            NOT_OP | FIND_SET_LEFT_BIT_OP | FIND_SET_RIGHT_BIT_OP => {
+                let a = self.term_arg(code, frame)?;
+                let a = self.integer(&a)?;
+                let result = match op {
+                    NOT_OP => self.truncate(!a),
+                    FIND_SET_LEFT_BIT_OP => match a {
+                        0 => 0,
+                        a => 64 - a.leading_zeros() as u64,
+                    },
+                    _ => match a {
+                        0 => 0,
+                        a => a.trailing_zeros() as u64 + 1,
+                    },
+                };
+                let result = Value::Integer(result);
+                let target = self.target(code, frame)?;
+                self.store(result.clone(), &target, frame, false)?;
+                result
+            }
+            INCREMENT_OP | DECREMENT_OP => {
+                let target = self.target(code, frame)?;
+                let value = self.read_target(&target, frame)?;
+                let value = self.integer(&value)?;
+                let value = if op == INCREMENT_OP {
+                    value.wrapping_add(1)
+                } else {
+                    value.wrapping_sub(1)
+                };
+                let value = Value::Integer(self.truncate(value));
+                self.store(value.clone(), &target, frame, false)?;
+                value
+            }
+            DEREF_OF_OP => {
+                let value = self.term_arg(code, frame)?;
+                match value {
+                    Value::Reference(reference) => self.deref(&reference)?,
+                    Value::String(path) => {
+                        let path = Path::parse(&path).ok_or(AmlError::NotFound)?;
+                        self.read_object(&path)?
+                    }
+                    _ => return Err(AmlError::Type),
+                }
+            }
+            CONCAT_OP | CONCAT_RES_OP => {
+                let a = self.term_arg(code, frame)?;
+                let b = self.term_arg(code, frame)?;
+                let result = if op == CONCAT_RES_OP {
+                    let mut a = self.buffer(&a)?;
+                    let b = self.buffer(&b)?;
+                    // the first template's end tag goes, the second's stays
+                    if a.len() >= 2 && a[a.len() - 2] == END_TAG {
+                        a.truncate(a.len() - 2);
+                    }
+                    a.extend_from_slice(&b);
+                    Value::buffer(a)
+                } else {
+                    match a {
+                        Value::String(a) => {
+                            let b = match b {
+                                Value::String(b) => b,
+                                Value::Integer(b) => format!("{:x}", b),
+                                _ => String::from_utf8_lossy(&self.buffer(&b)?).into(),
+                            };
+                            Value::String(a + &b)
+                        }
+                        a => {
+                            let mut a = self.buffer(&a)?;
+                            a.extend_from_slice(&self.buffer(&b)?);
+                            Value::buffer(a)
+                        }
+                    }
+                };
+                let target = self.target(code, frame)?;
+                self.store(result.clone(), &target, frame, false)?;
+                result
+            }
+            SIZE_OF_OP => {
+                let target = self.target(code, frame)?;
+                let value = self.read_target(&target, frame)?;
+                let size = match &value {
+                    Value::String(string) => string.len(),
+                    Value::Buffer(buffer) => buffer.borrow().len(),
+                    Value::Package(package) => package.borrow().len(),
+                    _ => return Err(AmlError::Type),
+                };
+                Value::Integer(size as u64)
+            }
+            INDEX_OP => {
+                let source = self.term_arg(code, frame)?;
+                let source = match source {
+                    Value::Reference(reference) => self.deref(&reference)?,
+                    source => source,
+                };
+                let index = self.term_arg(code, frame)?;
+                let index = self.integer(&index)? as usize;
+                let reference = match source {
+                    Value::Package(package) if index < package.borrow().len() => {
+                        Reference::Element(package, index)
+                    }
+                    Value::Buffer(buffer) if index < buffer.borrow().len() => {
+                        Reference::Byte(buffer, index)
+                    }
+                    Value::Package(_) | Value::Buffer(_) => return Err(AmlError::Index),
+                    _ => return Err(AmlError::Type),
+                };
+                let value = Value::Reference(reference);
+                let target = self.target(code, frame)?;
+                self.store(value.clone(), &target, frame, true)?;
+                value
+            }
+            MATCH_OP => {
+                let package = self.term_arg(code, frame)?;
+                let package = match package {
+                    Value::Package(package) => package,
+                    _ => return Err(AmlError::Type),
+                };
+                let op1 = code.byte()?;
+                let a = self.term_arg(code, frame)?;
+                let a = self.integer(&a)?;
+                let op2 = code.byte()?;
+                let b = self.term_arg(code, frame)?;
+                let b = self.integer(&b)?;
+                let start = self.term_arg(code, frame)?;
+                let start = self.integer(&start)? as usize;
+                let elements = package.borrow().clone();
+                let mut found = self.ones();
+                for (i, element) in elements.iter().enumerate().skip(start) {
+                    // elements that aren't integers never match
+                    let Ok(element) = self.integer(element) else {
+                        continue;
+                    };
+                    if match_op(op1, element, a) && match_op(op2, element, b) {
+                        found = i as u64;
+                        break;
+                    }
+                }
+                Value::Integer(found)
+            }
+            OBJECT_TYPE_OP => {
+                let code_of = match self.target(code, frame)? {
+                    Target::Name(path) => self.namespace.get(&path).map_or(0, Object::type_code),
+                    Target::Debug => 16,
+                    target => self.read_target(&target, frame)?.type_code(),
+                };
+                Value::Integer(code_of)
+            }
+            LAND_OP | LOR_OP => {
+                let a = self.term_arg(code, frame)?;
+                let a = self.integer(&a)? != 0;
+                let b = self.term_arg(code, frame)?;
+                let b = self.integer(&b)? != 0;
+                self.boolean(if op == LAND_OP { a && b } else { a || b })
+            }
+            LNOT_OP => {
+                let a = self.term_arg(code, frame)?;
+                let a = self.integer(&a)?;
+                self.boolean(a == 0)
+            }
+            LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+                let a = self.term_arg(code, frame)?;
+                let b = self.term_arg(code, frame)?;
+                let ordering = match a {
+                    Value::Integer(a) => a.cmp(&self.integer(&b)?),
+                    Value::String(ref a) => {
+                        let b = self.buffer(&b)?;
+                        let b = b.strip_suffix(&[0]).unwrap_or(&b);
+                        a.as_bytes().cmp(b)
+                    }
+                    a => self.buffer(&a)?.cmp(&self.buffer(&b)?),
+                };
+                self.boolean(match op {
+                    LEQUAL_OP => ordering.is_eq(),
+                    LGREATER_OP => ordering.is_gt(),
+                    _ => ordering.is_lt(),
+                })
+            }
+            TO_BUFFER_OP | TO_INTEGER_OP | TO_HEX_STRING_OP | TO_DECIMAL_STRING_OP => {
+                let value = self.term_arg(code, frame)?;
+                let result = match op {
+                    TO_BUFFER_OP => Value::buffer(self.buffer(&value)?),
+                    TO_INTEGER_OP => match value {
+                        Value::String(ref string) if !string.starts_with("0x") => Value::Integer(
+                            string
+                                .trim()
+                                .bytes()
+                                .take_while(u8::is_ascii_digit)
+                                .fold(0u64, |n, d| n.wrapping_mul(10) + (d - b'0') as u64),
+                        ),
+                        ref value => Value::Integer(self.integer(value)?),
+                    },
+                    _ => {
+                        let decimal = op == TO_DECIMAL_STRING_OP;
+                        let string = match value {
+                            Value::Integer(n) if decimal => format!("{}", n),
+                            Value::Integer(n) => format!("0x{:X}", n),
+                            Value::String(string) => string,
+                            value => {
+                                let bytes = self.buffer(&value)?;
+                                let parts: Vec<String> = bytes
+                                    .iter()
+                                    .map(|b| {
+                                        if decimal {
+                                            format!("{}", b)
+                                        } else {
+                                            format!("0x{:02X}", b)
+                                        }
+                                    })
+                                    .collect();
+                                parts.join(",")
+                            }
+                        };
+                        Value::String(string)
+                    }
+                };
+                let target = self.target(code, frame)?;
+                self.store(result.clone(), &target, frame, false)?;
+                result
+            }
+            TO_STRING_OP => {
+                let value = self.term_arg(code, frame)?;
+                let bytes = self.buffer(&value)?;
+                let len = self.term_arg(code, frame)?;
+                let len = self.integer(&len)? as usize;
+                let string = bytes
+                    .iter()
+                    .take(len)
+                    .take_while(|&&b| b != 0)
+                    .map(|&b| b as char)
+                    .collect();
+                let result = Value::String(string);
+                let target = self.target(code, frame)?;
+                self.store(result.clone(), &target, frame, false)?;
+                result
+            }
+            MID_OP => {
+                let value = self.term_arg(code, frame)?;
+                let start = self.term_arg(code, frame)?;
+                let start = self.integer(&start)? as usize;
+                let len = self.term_arg(code, frame)?;
+                let len = self.integer(&len)? as usize;
+                let result = match value {
+                    Value::String(string) => {
+                        Value::String(string.chars().skip(start).take(len).collect())
+                    }
+                    value => {
+                        let bytes = self.buffer(&value)?;
+                        let start = start.min(bytes.len());
+                        let end = start.saturating_add(len).min(bytes.len());
+                        Value::buffer(bytes[start..end].to_vec())
+                    }
+                };
+                let target = self.target(code, frame)?;
+                self.store(result.clone(), &target, frame, false)?;
+                result
+            }
+            EXT_PREFIX => self.ext_term_arg(code, frame)?,
+            op => return Err(AmlError::Unsupported(op as u16)),
+        };
+        Ok(value)
+    }
+
+    fn ext_term_arg(&mut self, code: &mut Code, frame: &mut Frame) -> Result<Value, AmlError> {
+        let op = code.byte()?;
+        let value = match op {
+            REVISION_OP => Value::Integer(1),
+            TIMER_OP => Value::Integer(self.host.timer()),
+            COND_REF_OF_OP => {
+                // the name can be missing, that's what it's there to find out
+                let found = if code.at_name() {
+                    let name = code.name_string()?;
+                    self.namespace
+                        .lookup(&name, &frame.scope)
+                        .map(|path| Value::Reference(Reference::Object(path)))
+                } else {
+                    match self.target(code, frame)? {
+                        Target::Reference(reference) => Some(Value::Reference(reference)),
+                        target => Some(self.read_target(&target, frame)?),
+                    }
+                };
+                let target = self.target(code, frame)?;
+                match found {
+                    Some(reference) => {
+                        self.store(reference, &target, frame, true)?;
+                        self.boolean(true)
+                    }
+                    None => self.boolean(false),
+                }
+            }
+            ACQUIRE_OP | WAIT_OP => {
+                // there's only ever the one thread in the interpreter, so it never waits
+                if op == ACQUIRE_OP {
+                    self.target(code, frame)?;
+                    code.bytes(2)?;
+                } else {
+                    self.target(code, frame)?;
+                    self.term_arg(code, frame)?;
+                }
+                self.boolean(false)
+            }
+            FROM_BCD_OP | TO_BCD_OP => {
+                let a = self.term_arg(code, frame)?;
+                let mut a = self.integer(&a)?;
+                let (mut result, mut digit) = (0u64, 0);
+                while a != 0 && digit < 16 {
+                    if op == FROM_BCD_OP {
+                        result += (a & 0xf) * 10u64.pow(digit);
+                        a >>= 4;
+                    } else {
+                        result |= (a % 10) << (4 * digit);
+                        a /= 10;
+                    }
+                    digit += 1;
+                }
+                let result = Value::Integer(self.truncate(result));
+                let target = self.target(code, frame)?;
+                self.store(result.clone(), &target, frame, false)?;
+                result
+            }
+            DEBUG_OP => return Err(AmlError::Type),
+            op => return Err(AmlError::Unsupported((EXT_PREFIX as u16) << 8 | op as u16)),
+        };
+        Ok(value)
+    }
+
+    /// Parse where a result goes
+    fn target(&mut self, code: &mut Code, frame: &mut Frame) -> Result<Target, AmlError> {
+        if code.at_name() {
+            let name = code.name_string()?;
+            let path = self
+                .namespace
+                .lookup(&name, &frame.scope)
+                .ok_or(AmlError::NotFound)?;
+            return Ok(Target::Name(path));
+        }
+        let op = code.peek()?;
+        match op {
+            ZERO_OP => {
+                code.pos += 1;
+                Ok(Target::None)
+            }
+            LOCAL0_OP..=LOCAL7_OP => {
+                code.pos += 1;
+                Ok(Target::Local((op - LOCAL0_OP) as usize))
+            }
+            ARG0_OP..=ARG6_OP => {
+                code.pos += 1;
+                Ok(Target::Arg((op - ARG0_OP) as usize))
+            }
+            EXT_PREFIX if code.aml.get(code.pos + 1) == Some(&DEBUG_OP) => {
+                code.pos += 2;
+                Ok(Target::Debug)
+            }
+            _ => match self.term_arg(code, frame)? {
+                Value::Reference(Reference::Object(path)) => Ok(Target::Name(path)),
+                Value::Reference(reference) => Ok(Target::Reference(reference)),
+                _ => Err(AmlError::Type),
+            },
+        }
+    }
+
+    fn read_target(&mut self, target: &Target, frame: &Frame) -> Result<Value, AmlError> {
+        let value = match target {
+            Target::Local(i) => frame.locals[*i].clone(),
+            Target::Arg(i) => frame.args[*i].clone(),
+            Target::Name(path) => return self.read_object(path),
+            Target::Reference(reference) => return self.deref(reference),
+            Target::None | Target::Debug => return Err(AmlError::Type),
+        };
+        match value {
+            Value::Reference(reference) => self.deref(&reference),
+            value => Ok(value),
+        }
+    }
+
+    /// Store `value` in `target`. A copy replaces what's there, a store converts to its type.
+    fn store(
+        &mut self,
+        value: Value,
+        target: &Target,
+        frame: &mut Frame,
+        copy: bool,
+    ) -> Result<(), AmlError> {
+        let value = value.deep_copy();
+        match target {
+            Target::None | Target::Debug => Ok(()),
+            Target::Local(i) => {
+                frame.locals[*i] = value;
+                Ok(())
+            }
+            Target::Arg(i) => {
+                // an argument passed with RefOf is stored through
+                if let (Value::Reference(reference), false) = (&frame.args[*i], copy) {
+                    let reference = reference.clone();
+                    return self.store_reference(&reference, value);
+                }
+                frame.args[*i] = value;
+                Ok(())
+            }
+            Target::Name(path) => {
+                let data = matches!(self.namespace.get(path), Some(Object::Value(_)));
+                if copy && data {
+                    self.set(path, value);
+                    Ok(())
+                } else {
+                    self.write_object(path, value)
+                }
+            }
+            Target::Reference(reference) => self.store_reference(reference, value),
+        }
+    }
+
+    fn store_reference(&mut self, reference: &Reference, value: Value) -> Result<(), AmlError> {
+        match reference {
+            Reference::Object(_) | Reference::Name(..) => {
+                let path = self.reference_path(reference)?;
+                self.write_object(&path, value)
+            }
+            Reference::Element(package, index) => {
+                let mut package = package.borrow_mut();
+                *package.get_mut(*index).ok_or(AmlError::Index)? = value;
+                Ok(())
+            }
+            Reference::Byte(buffer, index) => {
+                let byte = self.integer(&value)? as u8;
+                *buffer.borrow_mut().get_mut(*index).ok_or(AmlError::Index)? = byte;
+                Ok(())
+            }
+        }
+    }
+
+    /// What a reference refers to
+    fn deref(&mut self, reference: &Reference) -> Result<Value, AmlError> {
+        match reference {
+            Reference::Object(_) | Reference::Name(..) => {
+                let path = self.reference_path(reference)?;
+                self.read_object(&path)
+            }
+            Reference::Element(package, index) => {
+                let value = package.borrow().get(*index).cloned();
+                value.ok_or(AmlError::Index)
+            }
+            Reference::Byte(buffer, index) => {
+                let byte = buffer.borrow().get(*index).copied();
+                byte.map(|byte| Value::Integer(byte as u64))
+                    .ok_or(AmlError::Index)
+            }
+        }
+    }
+
+    /// The value of the object at `path`. Fields are read, and anything that isn't data is
+    /// referred to.
+    fn read_object(&mut self, path: &Path) -> Result<Value, AmlError> {
+        match self.namespace.get(path).ok_or(AmlError::NotFound)? {
+            Object::Value(value) => Ok(value.clone()),
+            Object::Field(field) => {
+                let field = field.clone();
+                self.read_field(&field)
+            }
+            Object::BufferField(field) => {
+                let buffer = field.buffer.borrow();
+                let mut bytes = vec![0; field.bit_len.div_ceil(8)];
+                for bit in 0..field.bit_len {
+                    let from = field.bit_offset + bit;
+                    if buffer[from / 8] & 1 << (from % 8) != 0 {
+                        bytes[bit / 8] |= 1 << (bit % 8);
+                    }
+                }
+                Ok(self.bits_value(bytes, field.bit_len))
+            }
+            _ => Ok(Value::Reference(Reference::Object(path.clone()))),
+        }
+    }
+
+    /// Replace the data at `path`
+    fn set(&mut self, path: &Path, value: Value) {
+        if let Some(object) = self.namespace.get_mut(path) {
+            *object = Object::Value(value);
+        }
+    }
+
+    /// Store `value` in the object at `path`, converted to the type of what's there
+    fn write_object(&mut self, path: &Path, value: Value) -> Result<(), AmlError> {
+        let value = match value {
+            Value::Reference(reference @ (Reference::Element(..) | Reference::Byte(..))) => {
+                self.deref(&reference)?
+            }
+            value => value,
+        };
+        let object = self.namespace.get(path).ok_or(AmlError::NotFound)?.clone();
+        match object {
+            Object::Value(Value::Integer(_)) => {
+                let value = Value::Integer(self.integer(&value)?);
+                self.set(path, value);
+            }
+            Object::Value(Value::Buffer(buffer)) => {
+                // the buffer keeps its length
+                let bytes = self.buffer(&value)?;
+                let mut buffer = buffer.borrow_mut();
+                let len = buffer.len();
+                buffer.fill(0);
+                let n = len.min(bytes.len());
+                buffer[..n].copy_from_slice(&bytes[..n]);
+            }
+            Object::Value(Value::String(_)) => {
+                let string = match value {
+                    Value::String(string) => string,
+                    Value::Integer(n) => format!("{:X}", n),
+                    value => String::from_utf8_lossy(&self.buffer(&value)?).into(),
+                };
+                self.set(path, Value::String(string));
+            }
+            Object::Value(_) => self.set(path, value),
+            Object::Field(field) => {
+                let bytes = self.buffer(&value)?;
+                self.write_field(&field, &bytes)?;
+            }
+            Object::BufferField(field) => {
+                let bytes = self.buffer(&value)?;
+                let mut buffer = field.buffer.borrow_mut();
+                for bit in 0..field.bit_len {
+                    let set = bytes
+                        .get(bit / 8)
+                        .is_some_and(|byte| byte & 1 << (bit % 8) != 0);
+                    let to = field.bit_offset + bit;
+                    if set {
+                        buffer[to / 8] |= 1 << (to % 8);
+                    } else {
+                        buffer[to / 8] &= !(1 << (to % 8));
+                    }
+                }
+            }
+            _ => return Err(AmlError::Type),
+        }
+        Ok(())
+    }
+
+    /// An integer if the bits fit in one, a buffer if they don't
+    fn bits_value(&self, bytes: Vec<u8>, bit_len: usize) -> Value {
+        if bit_len <= self.int_bytes * 8 {
+            let value = bytes
+                .iter()
+                .rev()
+                .fold(0, |value, &byte| value << 8 | byte as u64);
+            Value::Integer(value)
+        } else {
+            Value::buffer(bytes)
+        }
+    }
+
+    fn read_field(&mut self, field: &Field) -> Result<Value, AmlError> {
+        let width = field.access as u64;
+        let mut bytes = vec![0; (field.bit_len as usize).div_ceil(8)];
+        let first = field.bit_offset / (width * 8);
+        let last = (field.bit_offset + field.bit_len.max(1) - 1) / (width * 8);
+        for unit in first..=last {
+            let value = self.access(field, unit * width, None)?;
+            for bit in 0..width * 8 {
+                let at = unit * width * 8 + bit;
+                if at < field.bit_offset || at >= field.bit_offset + field.bit_len {
+                    continue;
+                }
+                if value & 1 << bit != 0 {
+                    let to = (at - field.bit_offset) as usize;
+                    bytes[to / 8] |= 1 << (to % 8);
+                }
+            }
+        }
+        Ok(self.bits_value(bytes, field.bit_len as usize))
+    }
+
+    fn write_field(&mut self, field: &Field, bytes: &[u8]) -> Result<(), AmlError> {
+        let width = field.access as u64;
+        let first = field.bit_offset / (width * 8);
+        let last = (field.bit_offset + field.bit_len.max(1) - 1) / (width * 8);
+        let unit_mask = if width == 8 {
+            u64::MAX
+        } else {
+            (1 << (width * 8)) - 1
+        };
+        for unit in first..=last {
+            let start = unit * width * 8;
+            let covered =
+                start >= field.bit_offset && start + width * 8 <= field.bit_offset + field.bit_len;
+            let mut value = match field.update {
+                _ if covered => 0,
+                Update::Preserve => self.access(field, unit * width, None)?,
+                Update::WriteAsOnes => unit_mask,
+                Update::WriteAsZeros => 0,
+            };
+            for bit in 0..width * 8 {
+                let at = start + bit;
+                if at < field.bit_offset || at >= field.bit_offset + field.bit_len {
+                    continue;
+                }
+                let from = (at - field.bit_offset) as usize;
+                if bytes
+                    .get(from / 8)
+                    .is_some_and(|byte| byte & 1 << (from % 8) != 0)
+                {
+                    value |= 1 << bit;
+                } else {
+                    value &= !(1 << bit);
+                }
+            }
+            self.access(field, unit * width, Some(value))?;
+        }
+        Ok(())
+    }
+
+    /// Read, or write when there's a value, the access at `offset` bytes into the field's
+    /// region
+    fn access(&mut self, field: &Field, offset: u64, write: Option<u64>) -> Result<u64, AmlError> {
+        let width = field.access;
+        let region = match &field.kind {
+            FieldKind::Region(region) => region.clone(),
+            FieldKind::Bank {
+                region,
+                bank,
+                value,
+            } => {
+                self.write_object(bank, Value::Integer(*value))?;
+                region.clone()
+            }
+            FieldKind::Index { index, data } => {
+                let (index, data) = (index.clone(), data.clone());
+                self.write_object(&index, Value::Integer(offset))?;
+                return match write {
+                    Some(value) => {
+                        self.write_object(&data, Value::Integer(value))?;
+                        Ok(0)
+                    }
+                    None => {
+                        let value = self.read_object(&data)?;
+                        self.integer(&value)
+                    }
+                };
+            }
+        };
+
+        let Some(Object::Region(found)) = self.namespace.get(&region) else {
+            return Err(AmlError::Type);
+        };
+        let found = found.clone();
+        let space = match found.space {
+            SYSTEM_MEMORY => Space::Memory,
+            SYSTEM_IO => Space::Io,
+            PCI_CONFIG => {
+                let (bus, device, function) = match found.pci {
+                    Some(pci) => pci,
+                    None => {
+                        let pci = self.pci_address(&region)?;
+                        if let Some(Object::Region(region)) = self.namespace.get_mut(&region) {
+                            region.pci = Some(pci);
+                        }
+                        pci
+                    }
+                };
+                Space::Pci {
+                    bus,
+                    device,
+                    function,
+                }
+            }
+            space => return Err(AmlError::Space(space)),
+        };
+        let address = found.offset + offset;
+        match write {
+            Some(value) => {
+                self.host.write(space, address, width, value)?;
+                Ok(0)
+            }
+            None => self.host.read(space, address, width),
+        }
+    }
+
+    /// The PCI function a configuration space region at `region` is the space of: the
+    /// device it's in has the device and function in `_ADR`, and the bus is the `_BBN` of the
+    /// nearest scope above that has one
+    fn pci_address(&mut self, region: &Path) -> Result<(u8, u8, u8), AmlError> {
+        let device = region.parent().ok_or(AmlError::NotFound)?;
+        let address = match self.evaluate(&device.child(*b"_ADR"), Vec::new()) {
+            Ok(value) => self.integer(&value)?,
+            Err(AmlError::NotFound) => 0,
+            Err(error) => return Err(error),
+        };
+        let mut scope = Some(device);
+        let mut bus = 0;
+        while let Some(path) = scope {
+            if let Ok(value) = self.evaluate(&path.child(*b"_BBN"), Vec::new()) {
+                bus = self.integer(&value)?;
+                break;
+            }
+            scope = path.parent();
+        }
+        Ok((bus as u8, (address >> 16) as u8, address as u8))
+    }
+}
+
+/// Bytes per access for an access type. AnyAcc gets the narrowest access that covers the
+/// field in one go.
+fn access_width(access_type: u8, bit_offset: u64, bit_len: u64) -> u8 {
+    match access_type {
+        1 => 1,
+        2 => 2,
+        3 => 4,
+        4 => 8,
+        // BufferAcc is for the serial buses, which don't get here
+        0 => [1, 2, 4]
+            .into_iter()
+            .find(|&width| {
+                let bits = width as u64 * 8;
+                bit_offset / bits == (bit_offset + bit_len.max(1) - 1) / bits
+            })
+            .unwrap_or(1),
+        _ => 1,
+    }
+}
+
+/// Whether `element` passes a `Match` comparison with `operand`
+fn match_op(op: u8, element: u64, operand: u64) -> bool {
+    match op {
+        1 => element == operand,
+        2 => element <= operand,
+        3 => element < operand,
+        4 => element >= operand,
+        5 => element > operand,
+        // MTR, always true
+        _ => true,
+    }
+}
+
+/// What `\_OSI` says yes to: the Windows versions, like every other OS does, since that's
+/// what firmware is tested against, and the features any OS has
+fn osi(args: &[Value]) -> Result<Value, AmlError> {
+    const SUPPORTED: &[&str] = &[
+        "Windows 2000",
+        "Windows 2001",
+        "Windows 2001 SP1",
+        "Windows 2001.1",
+        "Windows 2001 SP2",
+        "Windows 2001.1 SP1",
+        "Windows 2006",
+        "Windows 2006.1",
+        "Windows 2006 SP1",
+        "Windows 2006 SP2",
+        "Windows 2009",
+        "Windows 2012",
+        "Windows 2013",
+        "Windows 2015",
+        "Module Device",
+        "Processor Device",
+        "3.0 Thermal Model",
+        "3.0 _SCP Extensions",
+        "Processor Aggregator Device",
+        "Extended Address Space Descriptor",
+    ];
+    let Some(Value::String(interface)) = args.first() else {
+        return Err(AmlError::Type);
+    };
+    let supported = SUPPORTED.contains(&interface.as_str());
+    Ok(Value::Integer(if supported { u64::MAX } else { 0 }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// I/O ports 0x400 to 0x407, and nothing else
+    struct Ports([u8; 8]);
+
+    impl Host for Ports {
+        fn read(&mut self, space: Space, address: u64, width: u8) -> Result<u64, AmlError> {
+            match (space, address.checked_sub(0x400)) {
+                (Space::Io, Some(port)) if port + width as u64 <= 8 => {
+                    let bytes = &self.0[port as usize..][..width as usize];
+                    Ok(bytes
+                        .iter()
+                        .rev()
+                        .fold(0, |value, &byte| value << 8 | byte as u64))
+                }
+                _ => Err(AmlError::Space(SYSTEM_IO)),
+            }
+        }
+
+        fn write(
+            &mut self,
+            space: Space,
+            address: u64,
+            width: u8,
+            value: u64,
+        ) -> Result<(), AmlError> {
+            match (space, address.checked_sub(0x400)) {
+                (Space::Io, Some(port)) if port + width as u64 <= 8 => {
+                    let bytes = &mut self.0[port as usize..][..width as usize];
+                    bytes.copy_from_slice(&value.to_le_bytes()[..width as usize]);
+                    Ok(())
+                }
+                _ => Err(AmlError::Space(SYSTEM_IO)),
+            }
+        }
+
+        fn sleep_ms(&mut self, _ms: u64) {}
+
+        fn stall_us(&mut self, _us: u64) {}
+
+        fn timer(&mut self) -> u64 {
+            0
+        }
+    }
+
+    fn call(aml: &mut Interpreter<Ports>, path: &str, args: Vec<Value>) -> Value {
+        aml.evaluate(&Path::parse(path).unwrap(), args).unwrap()
+    }
+
+    #[test_case]
+    fn runs_methods() {
+        static AML: &[u8] = &[
+            // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero })
+            0x08, 0x5c, 0x5f, 0x53, 0x35, 0x5f, 0x12, 0x07, 0x04, 0x0a, 0x05, 0x00, 0x00, 0x00,
+            // Method (SUM, 1) { Local0 = Zero; Local1 = Zero
+            0x14, 0x19, 0x53, 0x55, 0x4d, 0x5f, 0x01, 0x70, 0x00, 0x60, 0x70, 0x00, 0x61,
+            //   While (Local1 < Arg0) { Local1++; Local0 += Local1 }
+            0xa2, 0x0a, 0x95, 0x61, 0x68, 0x75, 0x61, 0x72, 0x60, 0x61, 0x60,
+            //   Return (Local0) }
+            0xa4, 0x60,
+        ];
+        let mut aml = Interpreter::new(Ports([0; 8]), 2);
+        aml.load(AML).unwrap();
+        assert_eq!(aml.load_errors, 0);
+        let sum = call(&mut aml, "\\SUM", vec![Value::Integer(4)]);
+        assert_eq!(aml.integer(&sum), Ok(10));
+        let Value::Package(s5) = call(&mut aml, "\\_S5", Vec::new()) else {
+            panic!("\\_S5 isn't a package");
+        };
+        assert_eq!(s5.borrow().len(), 4);
+    }
+
+    #[test_case]
+    fn reads_and_writes_fields() {
+        static AML: &[u8] = &[
+            // OperationRegion (PMIO, SystemIO, 0x0400, 0x08)
+            0x5b, 0x80, 0x50, 0x4d, 0x49, 0x4f, 0x01, 0x0b, 0x00, 0x04, 0x0a, 0x08,
+            // Field (PMIO, ByteAcc, NoLock, Preserve) { Offset (0x01), CTL, 4, EN, 1 }
+            0x5b, 0x81, 0x12, 0x50, 0x4d, 0x49, 0x4f, 0x01, 0x00, 0x08, 0x43, 0x54, 0x4c, 0x5f,
+            0x04, 0x45, 0x4e, 0x5f, 0x5f, 0x01,
+            // Method (SET, 1) { If (Arg0) { EN = One } Else { EN = Zero } Return (CTL) }
+            0x14, 0x1c, 0x53, 0x45, 0x54, 0x5f, 0x01, 0xa0, 0x08, 0x68, 0x70, 0x01, 0x45, 0x4e,
+            0x5f, 0x5f, 0xa1, 0x07, 0x70, 0x00, 0x45, 0x4e, 0x5f, 0x5f, 0xa4, 0x43, 0x54, 0x4c,
+            0x5f,
+        ];
+        let mut aml = Interpreter::new(Ports([0, 0xa3, 0, 0, 0, 0, 0, 0]), 2);
+        aml.load(AML).unwrap();
+        let ctl = call(&mut aml, "\\SET", vec![Value::Integer(1)]);
+        assert_eq!(aml.integer(&ctl), Ok(3));
+        // the bits around EN stay as they were
+        assert_eq!(aml.host.0[1], 0xb3);
+        call(&mut aml, "\\SET", vec![Value::Integer(0)]);
+        assert_eq!(aml.host.0[1], 0xa3);
+    }
+
+    #[test_case]
+    fn buffer_fields_and_temporary_names() {
+        static AML: &[u8] = &[
+            // Method (BUF) { Name (RBUF, Buffer (0x04) { 0x01, 0x02, 0x03, 0x04 })
+            0x14, 0x2a, 0x42, 0x55, 0x46, 0x5f, 0x00, 0x08, 0x52, 0x42, 0x55, 0x46, 0x11, 0x07,
+            0x0a, 0x04, 0x01, 0x02, 0x03, 0x04, //   CreateWordField (RBUF, One, WRD)
+            0x8b, 0x52, 0x42, 0x55, 0x46, 0x01, 0x57, 0x52, 0x44, 0x5f,
+            //   WRD = 0xbeef; Return (RBUF) }
+            0x70, 0x0b, 0xef, 0xbe, 0x57, 0x52, 0x44, 0x5f, 0xa4, 0x52, 0x42, 0x55, 0x46,
+        ];
+        let mut aml = Interpreter::new(Ports([0; 8]), 2);
+        aml.load(AML).unwrap();
+        let Value::Buffer(buffer) = call(&mut aml, "\\BUF", Vec::new()) else {
+            panic!("BUF didn't give a buffer");
+        };
+        assert_eq!(&buffer.borrow()[..], &[0x01, 0xef, 0xbe, 0x04]);
+        // RBUF only lasted as long as the call
+        assert!(aml
+            .namespace
+            .get(&Path::parse("\\BUF.RBUF").unwrap())
+            .is_none());
+    }
+}
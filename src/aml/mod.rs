@@ -0,0 +1,312 @@
+//! ACPI Machine Language
+//!
+//! Some of what the firmware knows is only in its AML, the bytecode in the DSDT and SSDTs, and
+//! on real machines it's often worked out when it's asked for rather than written down. With
+//! the `aml` feature, [`INIT`] loads every table into a namespace with a small
+//! [`interp`]reter, runs the `_INI` methods, tells the firmware with `\_PIC` whether the I/O
+//! APIC is in use, and evaluates `\_S5` for [`acpi::set_s5`]. [`pci_irq`] then goes through
+//! the root bridge's `_PRT`, and the `_CRS` of the link devices it names, for which interrupt a
+//! PCI device's pin comes in on, and sets the I/O APIC up for it.
+//!
+//! Without the feature, `\_S5` is only found by its bytes, and PCI devices go by the interrupt
+//! line the firmware wrote into their configuration space, which is right on QEMU but not
+//! everywhere.
+//!
+//! The interpreter's values share buffers and packages with `Rc`, so they stay behind a lock
+//! and only plain data comes out.
+//!
+//! links:
+//! - <https://wiki.osdev.org/AML>
+//! - ACPI spec, ACPI namespace: <https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#acpi-namespace>
+//!
+
+pub mod device;
+pub mod interp;
+pub mod namespace;
+pub mod resource;
+pub mod value;
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr;
+
+use x86_64::PhysAddr;
+
+use self::device::{Route, PCI_ROOT_BRIDGES};
+use self::interp::{AmlError, Host, Interpreter, Space, SYSTEM_IO, SYSTEM_MEMORY};
+use self::namespace::Path;
+use self::value::Value;
+use crate::arch::io::Port;
+use crate::init::{InitCall, Stage};
+use crate::mem::paging::{self, PAGE_SIZE};
+use crate::pic::IRQ_COUNT;
+use crate::sync::Mutex;
+use crate::{acpi, apic, bootinfo, ilog, mem, pci, time, timer, wlog};
+
+/// How the interpreter gets at the machine
+#[derive(Default)]
+struct Machine {
+    /// Pages of device memory that regions are in, mapped the first time they're used
+    mapped: BTreeMap<u64, u64>,
+}
+
+impl Machine {
+    /// Where `width` bytes at physical `address` can be reached. RAM, which ACPI's own
+    /// memory is in, is in the physical memory window, anything else is a device's.
+    fn memory(&mut self, address: u64, width: u8) -> Result<u64, AmlError> {
+        let page = address & !(PAGE_SIZE - 1);
+        if address - page + width as u64 > PAGE_SIZE {
+            return Err(AmlError::Space(SYSTEM_MEMORY));
+        }
+        let in_map = bootinfo::memory_map().is_some_and(|map| {
+            map.iter().any(|region| {
+                (region.range.start_addr()..region.range.end_addr()).contains(&address)
+            })
+        });
+        if in_map {
+            return Ok(mem::phys_to_virt(PhysAddr::new(address)).as_u64());
+        }
+        if let Some(virt) = self.mapped.get(&page) {
+            return Ok(virt + (address - page));
+        }
+        // not in the memory map, so not RAM
+        let virt = unsafe { paging::map_mmio(PhysAddr::new(page), PAGE_SIZE) }
+            .map_err(|_| AmlError::Space(SYSTEM_MEMORY))?
+            .as_u64();
+        self.mapped.insert(page, virt);
+        Ok(virt + (address - page))
+    }
+}
+
+/// The configuration space function and dword offset for `address`, if it's in the first 256
+/// bytes and doesn't cross a dword
+fn pci_dword(space: Space, address: u64, width: u8) -> Option<(pci::Address, u8)> {
+    let Space::Pci {
+        bus,
+        device,
+        function,
+    } = space
+    else {
+        return None;
+    };
+    if address + width as u64 > 256 || (address & 3) + width as u64 > 4 {
+        return None;
+    }
+    let function = pci::Address {
+        bus,
+        device,
+        function,
+    };
+    Some((function, address as u8))
+}
+
+fn width_mask(width: u8) -> u64 {
+    match width {
+        8 => u64::MAX,
+        width => (1 << (width * 8)) - 1,
+    }
+}
+
+impl Host for Machine {
+    fn read(&mut self, space: Space, address: u64, width: u8) -> Result<u64, AmlError> {
+        match space {
+            Space::Io => {
+                let port = address as u16;
+                // AML says which ports, and it's the firmware's to know they're fine to read
+                unsafe {
+                    match width {
+                        1 => Ok(Port::<u8>::new(port).read() as u64),
+                        2 => Ok(Port::<u16>::new(port).read() as u64),
+                        4 => Ok(Port::<u32>::new(port).read() as u64),
+                        _ => Err(AmlError::Space(SYSTEM_IO)),
+                    }
+                }
+            }
+            Space::Memory => {
+                let at = self.memory(address, width)?;
+                unsafe {
+                    Ok(match width {
+                        1 => ptr::read_volatile(at as *const u8) as u64,
+                        2 => ptr::read_volatile(at as *const u16) as u64,
+                        4 => ptr::read_volatile(at as *const u32) as u64,
+                        _ => ptr::read_volatile(at as *const u64),
+                    })
+                }
+            }
+            Space::Pci { .. } => {
+                let (function, offset) =
+                    pci_dword(space, address, width).ok_or(AmlError::Space(interp::PCI_CONFIG))?;
+                let dword = function.read(offset & !3) >> ((offset & 3) * 8);
+                Ok(dword as u64 & width_mask(width))
+            }
+        }
+    }
+
+    fn write(&mut self, space: Space, address: u64, width: u8, value: u64) -> Result<(), AmlError> {
+        match space {
+            Space::Io => {
+                let port = address as u16;
+                unsafe {
+                    match width {
+                        1 => Port::<u8>::new(port).write(value as u8),
+                        2 => Port::<u16>::new(port).write(value as u16),
+                        4 => Port::<u32>::new(port).write(value as u32),
+                        _ => return Err(AmlError::Space(SYSTEM_IO)),
+                    }
+                }
+            }
+            Space::Memory => {
+                let at = self.memory(address, width)?;
+                unsafe {
+                    match width {
+                        1 => ptr::write_volatile(at as *mut u8, value as u8),
+                        2 => ptr::write_volatile(at as *mut u16, value as u16),
+                        4 => ptr::write_volatile(at as *mut u32, value as u32),
+                        _ => ptr::write_volatile(at as *mut u64, value),
+                    }
+                }
+            }
+            Space::Pci { .. } => {
+                let (function, offset) =
+                    pci_dword(space, address, width).ok_or(AmlError::Space(interp::PCI_CONFIG))?;
+                let shift = (offset & 3) * 8;
+                let mask = (width_mask(width) as u32) << shift;
+                let dword = function.read(offset & !3) & !mask;
+                function.write(offset & !3, dword | ((value as u32) << shift & mask));
+            }
+        }
+        Ok(())
+    }
+
+    fn sleep_ms(&mut self, ms: u64) {
+        // the boot is the only time AML runs, and waits there are short
+        let mut left = ms;
+        while left > 0 {
+            let step = left.min(50);
+            timer::wait_ms(step);
+            left -= step;
+        }
+    }
+
+    fn stall_us(&mut self, us: u64) {
+        let end = time::nanos() + us * 1000;
+        while time::nanos() < end {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn timer(&mut self) -> u64 {
+        time::nanos() / 100
+    }
+}
+
+struct Aml(Interpreter<Machine>);
+
+// the Rcs in the namespace are only ever reached through the Mutex below
+unsafe impl Send for Aml {}
+
+/// The namespace, None until [`INIT`] has loaded it
+static AML: Mutex<Option<Aml>> = Mutex::new("aml", None);
+
+/// The PCI root bridge that has bus `bus`
+fn root_bridge(aml: &mut Interpreter<Machine>, bus: u8) -> Option<Path> {
+    device::devices(aml).into_iter().find(|bridge| {
+        if !PCI_ROOT_BRIDGES
+            .iter()
+            .any(|id| device::has_id(aml, bridge, id))
+        {
+            return false;
+        }
+        // a bridge without a _BBN has bus 0
+        let number = aml
+            .evaluate(&bridge.child(*b"_BBN"), Vec::new())
+            .and_then(|value| aml.integer(&value))
+            .unwrap_or(0);
+        number == bus as u64
+    })
+}
+
+/// The IRQ `device`'s interrupt pin comes in on, from the `_PRT` of the root bridge it's
+/// on, with the I/O APIC set up for it. None if the firmware doesn't say, for a device behind
+/// another bridge, and before [`INIT`].
+pub fn pci_irq(device: &pci::Device) -> Option<u8> {
+    let pin = device.interrupt_pin();
+    if pin == 0 {
+        return None;
+    }
+    let route = {
+        let mut aml = AML.lock();
+        let aml = &mut aml.as_mut()?.0;
+        let bridge = root_bridge(aml, device.address.bus)?;
+        match device::pci_route(aml, &bridge, device.address.device, pin - 1) {
+            Ok(route) => route?,
+            Err(error) => {
+                wlog!("aml: no _PRT entry for {}: {:?}", device.address, error);
+                return None;
+            }
+        }
+    };
+
+    let (gsi, level, active_low) = match route {
+        Route::Link(interrupt) => (interrupt.number, !interrupt.edge, interrupt.active_low),
+        Route::Gsi(gsi) => (gsi, true, true),
+    };
+    if gsi < IRQ_COUNT as u32 {
+        // the legacy IRQs are their own GSIs, the MADT only moves the ISA devices' ones
+        let irq = gsi as u8;
+        apic::set_trigger(irq, level, active_low);
+        return Some(irq);
+    }
+    // one past the legacy IRQs takes the place of the line the firmware gave it
+    let line = device.interrupt_line();
+    (line < IRQ_COUNT && apic::route_gsi(line, gsi, level, active_low)).then_some(line)
+}
+
+fn init() {
+    let blocks = acpi::definition_blocks();
+    let Some(&(revision, _)) = blocks.first() else {
+        wlog!("aml: no DSDT to load");
+        return;
+    };
+
+    let mut aml = Interpreter::new(Machine::default(), revision);
+    for (_, block) in &blocks {
+        if let Err(error) = aml.load(block) {
+            wlog!("aml: a table stopped loading: {:?}", error);
+        }
+    }
+    let (ran, failed) = device::initialize(&mut aml);
+
+    // the firmware has the interrupts routed for the PICs until it's told otherwise
+    let pic = Path::parse("\\_PIC").unwrap();
+    let mode = Value::Integer(apic::enabled() as u64);
+    match aml.evaluate(&pic, vec![mode]) {
+        Ok(_) | Err(AmlError::NotFound) => {}
+        Err(error) => wlog!("aml: \\_PIC failed: {:?}", error),
+    }
+    let s5 = device::sleep_type(&mut aml, 5);
+    if let Some(s5) = s5 {
+        acpi::set_s5(s5);
+    }
+
+    ilog!(
+        "aml: {} tables, {} objects, {} terms that didn't load, {} _INI methods ({} failed), \
+         S5 sleep type {:?}",
+        blocks.len(),
+        aml.namespace.iter().count(),
+        aml.load_errors,
+        ran,
+        failed,
+        s5
+    );
+    *AML.lock() = Some(Aml(aml));
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "aml",
+    stage: Stage::Interrupts,
+    // \_PIC says whether the I/O APIC took over, and Sleep needs the timer
+    after: &["acpi", "apic", "timer"],
+    func: init,
+};
@@ -0,0 +1,334 @@
+//! The ACPI namespace
+//!
+//! Every object the tables define has an absolute [`Path`] of 4 character segments, like
+//! `\_SB_.PCI0._PRT`. AML mostly names things relative to the scope it's in, with a
+//! [`NameString`], and a name of a single segment is looked for in every scope up to the root
+//! until it's found. [`Namespace`] keeps the objects by path, which keeps a scope's children
+//! next to each other.
+//!
+
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+
+use super::interp::AmlError;
+use super::value::Value;
+
+/// One segment of a name, padded out with `_`
+pub type Seg = [u8; 4];
+
+/// Where an object is, from the root
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Path(pub Vec<Seg>);
+
+impl Path {
+    pub const ROOT: Path = Path(Vec::new());
+
+    /// Parse an absolute path the way ASL writes it, e.g. `\_SB.PCI0`, with short segments
+    /// padded out
+    pub fn parse(path: &str) -> Option<Path> {
+        let path = path.strip_prefix('\\')?;
+        if path.is_empty() {
+            return Some(Path::ROOT);
+        }
+        let mut segs = Vec::new();
+        for part in path.split('.') {
+            if part.is_empty() || part.len() > 4 || !part.bytes().all(is_name_char) {
+                return None;
+            }
+            let mut seg = [b'_'; 4];
+            seg[..part.len()].copy_from_slice(part.as_bytes());
+            segs.push(seg);
+        }
+        Some(Path(segs))
+    }
+
+    pub fn child(&self, seg: Seg) -> Path {
+        let mut path = self.clone();
+        path.0.push(seg);
+        path
+    }
+
+    /// The scope it's in, None for the root
+    pub fn parent(&self) -> Option<Path> {
+        let (_, parent) = self.0.split_last()?;
+        Some(Path(parent.to_vec()))
+    }
+
+    pub fn last(&self) -> Option<Seg> {
+        self.0.last().copied()
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\\")?;
+        for (i, seg) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            for &c in seg {
+                write!(f, "{}", c as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a name can be made of: the first character can't be a digit
+pub fn is_lead_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c == b'_'
+}
+
+pub fn is_name_char(c: u8) -> bool {
+    is_lead_char(c) || c.is_ascii_digit()
+}
+
+/// A name as AML writes it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameString {
+    /// Starts at the root, with `\`
+    pub root: bool,
+    /// Scopes to go up first, one for each `^`
+    pub up: usize,
+    pub segs: Vec<Seg>,
+}
+
+impl NameString {
+    /// The path it names from `scope`, without the search rules. None if it goes up past the
+    /// root.
+    pub fn resolve(&self, scope: &Path) -> Option<Path> {
+        let mut path = if self.root {
+            Path::ROOT
+        } else {
+            let keep = scope.0.len().checked_sub(self.up)?;
+            Path(scope.0[..keep].to_vec())
+        };
+        path.0.extend_from_slice(&self.segs);
+        Some(path)
+    }
+
+    /// Whether it's a single segment with no prefix, the kind that's searched for
+    fn searched(&self) -> bool {
+        !self.root && self.up == 0 && self.segs.len() == 1
+    }
+}
+
+/// What a scope-like object is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeKind {
+    Scope,
+    Device,
+    Processor,
+    PowerResource,
+    ThermalZone,
+}
+
+#[derive(Clone)]
+pub enum Method {
+    /// Bytecode, borrowed from the table it's in
+    Aml { args: u8, body: &'static [u8] },
+    /// The interpreter's own, like `\_OSI`
+    Native {
+        args: u8,
+        func: fn(&[Value]) -> Result<Value, AmlError>,
+    },
+}
+
+impl Method {
+    pub fn args(&self) -> u8 {
+        match *self {
+            Method::Aml { args, .. } | Method::Native { args, .. } => args,
+        }
+    }
+}
+
+/// An operation region: a range of memory, I/O ports, or PCI configuration space that fields
+/// are laid over
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub space: u8,
+    pub offset: u64,
+    pub len: u64,
+    /// Bus, device, and function of a PCI configuration space one, worked out on first use
+    pub pci: Option<(u8, u8, u8)>,
+}
+
+/// Where a field's bits are read from and written to
+#[derive(Debug, Clone)]
+pub enum FieldKind {
+    Region(Path),
+    /// Through an index field and a data field: the field's offset is written to the one, and
+    /// its bits go through the other
+    Index {
+        index: Path,
+        data: Path,
+    },
+    /// A region with banks, `bank` is set to `value` before each access
+    Bank {
+        region: Path,
+        bank: Path,
+        value: u64,
+    },
+}
+
+/// What happens to the bits in an access that aren't the field's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Update {
+    Preserve,
+    WriteAsOnes,
+    WriteAsZeros,
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub kind: FieldKind,
+    pub bit_offset: u64,
+    pub bit_len: u64,
+    /// Bytes per access
+    pub access: u8,
+    pub update: Update,
+}
+
+/// Bits of a buffer, made with `CreateField` or one of its fixed size versions
+#[derive(Debug, Clone)]
+pub struct BufferField {
+    pub buffer: Rc<RefCell<Vec<u8>>>,
+    pub bit_offset: usize,
+    pub bit_len: usize,
+}
+
+#[derive(Clone)]
+pub enum Object {
+    Scope(ScopeKind),
+    /// Data given a name with `Name`
+    Value(Value),
+    Method(Method),
+    Region(Region),
+    Field(Field),
+    BufferField(BufferField),
+    Mutex,
+    Event,
+    /// Another name for the object at the path
+    Alias(Path),
+}
+
+impl Object {
+    /// The number `ObjectType` gives for it
+    pub fn type_code(&self) -> u64 {
+        match self {
+            Object::Value(value) => value.type_code(),
+            Object::Field(_) => 5,
+            Object::Scope(ScopeKind::Device) => 6,
+            Object::Event => 7,
+            Object::Method(_) => 8,
+            Object::Mutex => 9,
+            Object::Region(_) => 10,
+            Object::Scope(ScopeKind::PowerResource) => 11,
+            Object::Scope(ScopeKind::Processor) => 12,
+            Object::Scope(ScopeKind::ThermalZone) => 13,
+            Object::BufferField(_) => 14,
+            Object::Scope(ScopeKind::Scope) | Object::Alias(_) => 0,
+        }
+    }
+}
+
+/// Every object the tables have defined
+pub struct Namespace {
+    objects: BTreeMap<Path, Object>,
+}
+
+impl Namespace {
+    /// The root and the scopes every namespace starts with
+    pub fn new() -> Namespace {
+        let mut objects = BTreeMap::new();
+        objects.insert(Path::ROOT, Object::Scope(ScopeKind::Scope));
+        for seg in [b"_GPE", b"_PR_", b"_SB_", b"_SI_", b"_TZ_"] {
+            objects.insert(Path::ROOT.child(*seg), Object::Scope(ScopeKind::Scope));
+        }
+        Namespace { objects }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&Object> {
+        self.objects.get(path)
+    }
+
+    pub fn get_mut(&mut self, path: &Path) -> Option<&mut Object> {
+        self.objects.get_mut(path)
+    }
+
+    /// Add `object` at `path`, which has to be in a scope that's there and not be taken
+    pub fn insert(&mut self, path: Path, object: Object) -> Result<(), AmlError> {
+        let parent = path.parent().ok_or(AmlError::Exists)?;
+        if !self.objects.contains_key(&parent) {
+            return Err(AmlError::NotFound);
+        }
+        if self.objects.contains_key(&path) {
+            return Err(AmlError::Exists);
+        }
+        self.objects.insert(path, object);
+        Ok(())
+    }
+
+    /// Take the object at `path` out, and everything in its scope
+    pub fn remove(&mut self, path: &Path) {
+        let inside: Vec<Path> = self
+            .objects
+            .range(path.clone()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.0.starts_with(&path.0))
+            .cloned()
+            .collect();
+        for key in inside {
+            self.objects.remove(&key);
+        }
+    }
+
+    /// Find the object `name` refers to from `scope`, following aliases. A single segment is
+    /// looked for in `scope` and then each scope above it.
+    pub fn lookup(&self, name: &NameString, scope: &Path) -> Option<Path> {
+        let mut path = if name.searched() {
+            let mut scope = scope.clone();
+            loop {
+                let path = scope.child(name.segs[0]);
+                if self.objects.contains_key(&path) {
+                    break path;
+                }
+                scope = scope.parent()?;
+            }
+        } else {
+            let path = name.resolve(scope)?;
+            self.objects.contains_key(&path).then_some(path)?
+        };
+        // an alias of an alias is still only a few hops
+        for _ in 0..8 {
+            match self.objects.get(&path) {
+                Some(Object::Alias(target)) => path = target.clone(),
+                _ => return Some(path),
+            }
+        }
+        None
+    }
+
+    /// The objects right inside `scope`
+    pub fn children<'a>(&'a self, scope: &'a Path) -> impl Iterator<Item = &'a Path> + 'a {
+        self.objects
+            .range(scope.clone()..)
+            .map(|(key, _)| key)
+            .take_while(move |key| key.0.starts_with(&scope.0))
+            .filter(move |key| key.0.len() == scope.0.len() + 1)
+    }
+
+    /// Every object, parents before their children
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &Object)> {
+        self.objects.iter()
+    }
+}
+
+impl Default for Namespace {
+    fn default() -> Namespace {
+        Namespace::new()
+    }
+}
@@ -0,0 +1,159 @@
+//! Resource templates
+//!
+//! `_CRS` and the other resource methods give back a buffer of descriptors, each a small
+//! one, with its type and length in one byte, or a large one, with a type byte and a 16-bit
+//! length. The buffer ends with the end tag. Only the interrupt descriptors are looked into
+//! for what they say, since working out where interrupts go is all they're used for so far.
+//!
+//! links:
+//! - ACPI spec, resource data types: <https://uefi.org/specs/ACPI/6.5/06_Device_Configuration.html#resource-data-types-for-acpi>
+//!
+
+use alloc::vec::Vec;
+
+use super::interp::AmlError;
+
+// small descriptor types
+const IRQ: u8 = 0x04;
+const IO: u8 = 0x08;
+const END_TAG: u8 = 0x0f;
+
+// large descriptor types
+const MEMORY32_FIXED: u8 = 0x06;
+const EXTENDED_IRQ: u8 = 0x09;
+
+/// An interrupt a device uses, or can be set to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupt {
+    /// An ISA IRQ from an IRQ descriptor, a global system interrupt from an extended one
+    pub number: u32,
+    /// Edge triggered rather than level triggered
+    pub edge: bool,
+    pub active_low: bool,
+    pub shared: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Interrupt(Interrupt),
+    Io {
+        min: u16,
+        max: u16,
+        len: u8,
+    },
+    Memory {
+        base: u32,
+        len: u32,
+    },
+    /// A descriptor of another type, with its type byte
+    Other(u8),
+}
+
+/// Every descriptor in `buffer`, up to the end tag
+pub fn parse(buffer: &[u8]) -> Result<Vec<Resource>, AmlError> {
+    let mut resources = Vec::new();
+    let mut rest = buffer;
+    while let Some(&tag) = rest.first() {
+        let large = tag & 0x80 != 0;
+        let (kind, body, len) = if large {
+            let len = rest.get(1..3).ok_or(AmlError::Truncated)?;
+            (tag & 0x7f, 3, u16::from_le_bytes([len[0], len[1]]) as usize)
+        } else {
+            ((tag >> 3) & 0xf, 1, (tag & 7) as usize)
+        };
+        let data = rest.get(body..body + len).ok_or(AmlError::Truncated)?;
+        rest = &rest[body + len..];
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+
+        match (large, kind) {
+            (false, END_TAG) => break,
+            (false, IRQ) if len >= 2 => {
+                // without the flags byte it's edge triggered and active high
+                let flags = data.get(2).copied().unwrap_or(1);
+                let mask = u16_at(0);
+                resources.extend((0..16).filter(|irq| mask & 1 << irq != 0).map(|irq| {
+                    Resource::Interrupt(Interrupt {
+                        number: irq,
+                        edge: flags & 1 != 0,
+                        active_low: flags & 1 << 3 != 0,
+                        shared: flags & 1 << 4 != 0,
+                    })
+                }));
+            }
+            (false, IO) if len >= 7 => resources.push(Resource::Io {
+                min: u16_at(1),
+                max: u16_at(3),
+                len: data[6],
+            }),
+            (true, MEMORY32_FIXED) if len >= 9 => resources.push(Resource::Memory {
+                base: u32_at(1),
+                len: u32_at(5),
+            }),
+            (true, EXTENDED_IRQ) if len >= 2 => {
+                let (flags, count) = (data[0], data[1] as usize);
+                if len < 2 + count * 4 {
+                    return Err(AmlError::Truncated);
+                }
+                resources.extend((0..count).map(|i| {
+                    Resource::Interrupt(Interrupt {
+                        number: u32_at(2 + i * 4),
+                        edge: flags & 1 << 1 != 0,
+                        active_low: flags & 1 << 2 != 0,
+                        shared: flags & 1 << 3 != 0,
+                    })
+                }));
+            }
+            _ => resources.push(Resource::Other(tag)),
+        }
+    }
+    Ok(resources)
+}
+
+/// The first interrupt in `buffer`
+pub fn interrupt(buffer: &[u8]) -> Result<Option<Interrupt>, AmlError> {
+    Ok(parse(buffer)?
+        .into_iter()
+        .find_map(|resource| match resource {
+            Resource::Interrupt(interrupt) => Some(interrupt),
+            _ => None,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test_case]
+    fn parses_templates() {
+        let crs = [
+            // Interrupt (ResourceConsumer, Edge, ActiveHigh, Exclusive) { 4 }
+            0x89, 0x06, 0x00, 0x03, 0x01, 0x04, 0x00, 0x00, 0x00,
+            // IO (Decode16, 0x03F8, 0x03F8, 0x01, 0x08)
+            0x47, 0x01, 0xf8, 0x03, 0xf8, 0x03, 0x01, 0x08, //
+            0x79, 0x00,
+        ];
+        let com1 = Interrupt {
+            number: 4,
+            edge: true,
+            active_low: false,
+            shared: false,
+        };
+        assert_eq!(
+            parse(&crs),
+            Ok(vec![
+                Resource::Interrupt(com1),
+                Resource::Io {
+                    min: 0x3f8,
+                    max: 0x3f8,
+                    len: 8
+                }
+            ])
+        );
+        assert_eq!(interrupt(&crs[9..]), Ok(None));
+        assert_eq!(parse(&crs[..5]), Err(AmlError::Truncated));
+    }
+}
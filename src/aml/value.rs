@@ -0,0 +1,121 @@
+//! What AML computes with
+//!
+//! Integers, strings, buffers, and packages, and references to named objects and to the
+//! elements of packages and buffers. Buffers and packages are shared, so a buffer field made
+//! over a buffer, or a reference to an element, sees it change.
+//!
+
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use super::interp::AmlError;
+use super::namespace::{NameString, Path};
+
+#[derive(Debug, Clone)]
+pub enum Reference {
+    /// A named object
+    Object(Path),
+    /// A name in a package, looked up from the scope the package was made in when it's used
+    Name(Path, NameString),
+    /// An element of a package
+    Element(Rc<RefCell<Vec<Value>>>, usize),
+    /// A byte of a buffer
+    Byte(Rc<RefCell<Vec<u8>>>, usize),
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum Value {
+    #[default]
+    Uninitialized,
+    Integer(u64),
+    String(String),
+    Buffer(Rc<RefCell<Vec<u8>>>),
+    Package(Rc<RefCell<Vec<Value>>>),
+    Reference(Reference),
+}
+
+impl Value {
+    pub fn buffer(bytes: Vec<u8>) -> Value {
+        Value::Buffer(Rc::new(RefCell::new(bytes)))
+    }
+
+    pub fn package(elements: Vec<Value>) -> Value {
+        Value::Package(Rc::new(RefCell::new(elements)))
+    }
+
+    /// The number `ObjectType` gives for it
+    pub fn type_code(&self) -> u64 {
+        match self {
+            Value::Uninitialized => 0,
+            Value::Integer(_) => 1,
+            Value::String(_) => 2,
+            Value::Buffer(_) => 3,
+            Value::Package(_) => 4,
+            Value::Reference(_) => 0,
+        }
+    }
+
+    /// A copy that doesn't share its buffer or package with this one, which is what storing a
+    /// value makes
+    pub fn deep_copy(&self) -> Value {
+        match self {
+            Value::Buffer(buffer) => Value::buffer(buffer.borrow().clone()),
+            Value::Package(package) => {
+                Value::package(package.borrow().iter().map(Value::deep_copy).collect())
+            }
+            value => value.clone(),
+        }
+    }
+
+    /// The value as an integer of `bytes` bytes: a buffer's first bytes, little endian, or a
+    /// string of hex digits
+    pub fn to_integer(&self, bytes: usize) -> Result<u64, AmlError> {
+        let value = match self {
+            Value::Integer(value) => *value,
+            Value::Buffer(buffer) => {
+                let buffer = buffer.borrow();
+                buffer
+                    .iter()
+                    .take(bytes)
+                    .rev()
+                    .fold(0, |value, &byte| value << 8 | byte as u64)
+            }
+            Value::String(string) => {
+                let digits = string.trim_start();
+                let digits = digits
+                    .strip_prefix("0x")
+                    .or_else(|| digits.strip_prefix("0X"))
+                    .unwrap_or(digits);
+                let end = digits
+                    .find(|c: char| !c.is_ascii_hexdigit())
+                    .unwrap_or(digits.len());
+                // anything past what fits is dropped, like the digits that aren't hex
+                let end = end.min(bytes * 2);
+                u64::from_str_radix(&digits[..end], 16).unwrap_or(0)
+            }
+            _ => return Err(AmlError::Type),
+        };
+        Ok(if bytes < 8 {
+            value & ((1 << (bytes * 8)) - 1)
+        } else {
+            value
+        })
+    }
+
+    /// The value as a buffer: an integer's `bytes` bytes, little endian, or a string's bytes
+    /// and the NUL after them
+    pub fn to_buffer(&self, bytes: usize) -> Result<Vec<u8>, AmlError> {
+        match self {
+            Value::Integer(value) => Ok(value.to_le_bytes()[..bytes].to_vec()),
+            Value::Buffer(buffer) => Ok(buffer.borrow().clone()),
+            Value::String(string) => {
+                let mut buffer = string.as_bytes().to_vec();
+                buffer.push(0);
+                Ok(buffer)
+            }
+            _ => Err(AmlError::Type),
+        }
+    }
+}
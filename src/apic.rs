@@ -0,0 +1,462 @@
+//! Local APIC and I/O APIC
+//!
+//! When the MADT lists them, [`INIT`] moves interrupt delivery off the 8259 PICs: the PICs
+//! are masked, each legacy IRQ is routed through the I/O APIC to the vector it had on the PIC,
+//! and IRQ 0 becomes the local APIC timer rather than the PIT. [`pic`] keeps the same interface
+//! either way, and once [`enabled`] is true it masks and acknowledges IRQs through here.
+//!
+//! The local APIC timer counts down at the bus clock divided by 16, which isn't known, so it's
+//! measured with [`timer::wait_ms`] first. `noapic` on the command line keeps the PICs.
+//!
+//! links:
+//! - <https://wiki.osdev.org/APIC>
+//! - <https://wiki.osdev.org/IOAPIC>
+//! - <https://wiki.osdev.org/APIC_Timer>
+//! - Intel SDM vol. 3A, chapter 11
+//!
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use crate::acpi::{self, Madt, MAX_IO_APICS};
+use crate::arch::msr;
+use crate::cpu::{self, Feature};
+use crate::init::{InitCall, Stage};
+use crate::mem::paging::{self, MapError, PAGE_SIZE};
+use crate::pic::{self, IRQ_BASE, IRQ_COUNT};
+use crate::sync::SpinLock;
+use crate::{cmdline, ilog, timer, wlog};
+
+/// Where the local APIC sends interrupts it can't deliver properly. They don't get an EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The PIT's IRQ, which the local APIC timer takes over
+const TIMER_IRQ: u8 = 0;
+
+// local APIC registers, as offsets from its base
+const ID: u64 = 0x20;
+const EOI: u64 = 0xb0;
+const SPURIOUS: u64 = 0xf0;
+const ICR_LOW: u64 = 0x300;
+const ICR_HIGH: u64 = 0x310;
+const LVT_TIMER: u64 = 0x320;
+const TIMER_INITIAL: u64 = 0x380;
+const TIMER_CURRENT: u64 = 0x390;
+const TIMER_DIVIDE: u64 = 0x3e0;
+
+/// Spurious interrupt register: the APIC is on
+const APIC_ENABLE: u32 = 1 << 8;
+/// Local vector table entries: masked
+const LVT_MASKED: u32 = 1 << 16;
+/// LVT timer: start over when the count runs out
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide configuration for the bus clock divided by 16
+const DIVIDE_16: u32 = 0b0011;
+
+// interprocessor interrupt command bits
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+
+/// How long to let the timer count for when calibrating, in ms
+const CALIBRATE_MS: u64 = 10;
+
+// I/O APIC registers: an index is written to IOREGSEL, then the register is at IOWIN
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOAPICVER: u32 = 0x01;
+/// Redirection entries are two registers each, starting here
+const IOREDTBL: u32 = 0x10;
+
+// redirection entry bits
+/// Delivery mode NMI, which ignores the vector and has to be edge triggered
+const REDIRECT_NMI: u32 = 0b100 << 8;
+const REDIRECT_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECT_LEVEL: u32 = 1 << 15;
+const REDIRECT_MASKED: u32 = 1 << 16;
+
+/// Why the APICs couldn't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    /// Registers couldn't be mapped
+    Map(MapError),
+    /// The MADT doesn't list an I/O APIC the legacy IRQs can be routed through
+    NoIoApic,
+}
+
+impl From<MapError> for ApicError {
+    fn from(error: MapError) -> ApicError {
+        ApicError::Map(error)
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Where the local APIC's registers are mapped
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+/// How fast the local APIC timer counts, in Hz
+static TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Whether interrupts come through the APICs rather than the PICs
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn read(reg: u64) -> u32 {
+    let addr = LOCAL_APIC.load(Ordering::Relaxed) + reg;
+    unsafe { ptr::read_volatile(addr as *const u32) }
+}
+
+fn write(reg: u64, value: u32) {
+    let addr = LOCAL_APIC.load(Ordering::Relaxed) + reg;
+    unsafe { ptr::write_volatile(addr as *mut u32, value) }
+}
+
+/// This CPU's local APIC ID
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+/// Tell the local APIC the current interrupt has been handled
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+/// Send an interprocessor interrupt to the CPU with local APIC `apic_id`, and wait for it to
+/// be accepted
+fn send_ipi(apic_id: u8, command: u32) {
+    write(ICR_HIGH, (apic_id as u32) << 24);
+    write(ICR_LOW, command);
+    while read(ICR_LOW) & ICR_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Reset the CPU with local APIC `apic_id`, it waits for a startup IPI after
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_INIT | ICR_ASSERT);
+}
+
+/// Start the CPU with local APIC `apic_id` in real mode at `page` * 4 KiB, after an INIT
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | page as u32);
+}
+
+/// Switch on this CPU's local APIC, on an application processor. The boot CPU's is switched on
+/// by [`INIT`].
+pub fn enable_ap() {
+    write(SPURIOUS, APIC_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+#[derive(Clone, Copy)]
+struct IoApic {
+    /// Where its registers are mapped
+    base: u64,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+            ptr::read_volatile((self.base + IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+            ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+        }
+    }
+}
+
+/// Where a legacy IRQ comes in
+#[derive(Clone, Copy)]
+struct Route {
+    /// Index into [`IoApics::apics`]
+    apic: usize,
+    /// Redirection entry in that I/O APIC
+    entry: u32,
+}
+
+struct IoApics {
+    apics: [Option<IoApic>; MAX_IO_APICS],
+    routes: [Option<Route>; IRQ_COUNT as usize],
+}
+
+impl IoApics {
+    /// The I/O APIC that has `gsi`, with its index in [`apics`](Self::apics)
+    fn find(&self, gsi: u32) -> Option<(usize, IoApic)> {
+        self.apics
+            .iter()
+            .enumerate()
+            .filter_map(|(i, apic)| Some((i, (*apic)?)))
+            .find(|(_, apic)| gsi >= apic.gsi_base && gsi < apic.gsi_base + apic.entries)
+    }
+
+    /// Set the mask bit in `irq`'s redirection entry, if it has one
+    fn set_masked(&self, irq: u8, masked: bool) {
+        let Some(route) = self.routes[irq as usize] else {
+            return;
+        };
+        let Some(apic) = self.apics[route.apic] else {
+            return;
+        };
+        let reg = IOREDTBL + route.entry * 2;
+        let low = apic.read(reg);
+        let low = if masked {
+            low | REDIRECT_MASKED
+        } else {
+            low & !REDIRECT_MASKED
+        };
+        apic.write(reg, low);
+    }
+}
+
+static IO_APICS: SpinLock<IoApics> = SpinLock::new(
+    "io apic",
+    IoApics {
+        apics: [None; MAX_IO_APICS],
+        routes: [None; IRQ_COUNT as usize],
+    },
+);
+
+/// Mask or unmask `irq`, IRQ 0 being the local APIC timer
+pub fn set_masked(irq: u8, masked: bool) {
+    if irq == TIMER_IRQ {
+        let lvt = read(LVT_TIMER);
+        write(
+            LVT_TIMER,
+            if masked {
+                lvt | LVT_MASKED
+            } else {
+                lvt & !LVT_MASKED
+            },
+        );
+        return;
+    }
+    interrupts::without_interrupts(|| IO_APICS.lock().set_masked(irq, masked));
+}
+
+/// Deliver legacy IRQ `irq` to this CPU as an NMI instead of its vector, for the PIT's once
+/// the local APIC timer has taken over from it. False if it doesn't come through an I/O APIC.
+pub fn route_nmi(irq: u8) -> bool {
+    interrupts::without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        let Some(route) = io_apics.routes.get(irq as usize).copied().flatten() else {
+            return false;
+        };
+        let Some(apic) = io_apics.apics[route.apic] else {
+            return false;
+        };
+        let reg = IOREDTBL + route.entry * 2;
+        apic.write(reg + 1, (id() as u32) << 24);
+        // edge triggered, active high, unmasked
+        apic.write(reg, REDIRECT_NMI);
+        true
+    })
+}
+
+/// Make `irq` level triggered and active low, the way ACPI wires the SCI when the MADT has no
+/// override saying otherwise. Nothing happens if it doesn't come through an I/O APIC.
+pub fn set_level_low(irq: u8) {
+    set_trigger(irq, true, true);
+}
+
+/// Set whether `irq` is level or edge triggered and active low or high. Nothing happens if it
+/// doesn't come through an I/O APIC.
+pub fn set_trigger(irq: u8, level: bool, active_low: bool) {
+    interrupts::without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        let Some(route) = io_apics.routes.get(irq as usize).copied().flatten() else {
+            return;
+        };
+        let Some(apic) = io_apics.apics[route.apic] else {
+            return;
+        };
+        let reg = IOREDTBL + route.entry * 2;
+        apic.write(reg, trigger_bits(apic.read(reg), level, active_low));
+    })
+}
+
+/// `low` with the trigger mode and polarity bits set for `level` and `active_low`
+fn trigger_bits(low: u32, level: bool, active_low: bool) -> u32 {
+    let mut low = low & !(REDIRECT_LEVEL | REDIRECT_ACTIVE_LOW);
+    if level {
+        low |= REDIRECT_LEVEL;
+    }
+    if active_low {
+        low |= REDIRECT_ACTIVE_LOW;
+    }
+    low
+}
+
+/// Bring global system interrupt `gsi` in as `irq`, triggered the way `level` and
+/// `active_low` say, instead of what came in as `irq` before, which is masked. It stays masked
+/// or unmasked the way `irq` was. False if no I/O APIC has `gsi`.
+pub fn route_gsi(irq: u8, gsi: u32, level: bool, active_low: bool) -> bool {
+    if irq >= IRQ_COUNT {
+        return false;
+    }
+    interrupts::without_interrupts(|| {
+        let mut io_apics = IO_APICS.lock();
+        let Some((index, apic)) = io_apics.find(gsi) else {
+            return false;
+        };
+        let mut masked = REDIRECT_MASKED;
+        if let Some(old) = io_apics.routes[irq as usize] {
+            if let Some(old_apic) = io_apics.apics[old.apic] {
+                let reg = IOREDTBL + old.entry * 2;
+                let low = old_apic.read(reg);
+                masked = low & REDIRECT_MASKED;
+                old_apic.write(reg, low | REDIRECT_MASKED);
+            }
+        }
+        let entry = gsi - apic.gsi_base;
+        let low = trigger_bits(masked | (IRQ_BASE + irq) as u32, level, active_low);
+        apic.write(IOREDTBL + entry * 2 + 1, (id() as u32) << 24);
+        apic.write(IOREDTBL + entry * 2, low);
+        io_apics.routes[irq as usize] = Some(Route { apic: index, entry });
+        true
+    })
+}
+
+/// Make the local APIC timer fire `hz` times a second, as close as it can get. Returns the
+/// length of a tick in ns.
+pub fn set_timer_frequency(hz: u32) -> u64 {
+    let timer_hz = TIMER_HZ.load(Ordering::Relaxed);
+    let count = (timer_hz / hz.max(1) as u64).clamp(1, u32::MAX as u64);
+    write(TIMER_INITIAL, count as u32);
+    count * 1_000_000_000 / timer_hz
+}
+
+/// Count how fast the timer runs against [`timer::wait_ms`]
+fn calibrate_timer() -> u64 {
+    write(TIMER_DIVIDE, DIVIDE_16);
+    write(LVT_TIMER, LVT_MASKED);
+    write(TIMER_INITIAL, u32::MAX);
+    timer::wait_ms(CALIBRATE_MS);
+    let counted = u32::MAX - read(TIMER_CURRENT);
+    write(TIMER_INITIAL, 0);
+    counted as u64 * 1000 / CALIBRATE_MS
+}
+
+extern "x86-interrupt" fn spurious(frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(&frame);
+    crate::interrupts::record_spurious();
+}
+
+/// Point the spurious interrupt vector in `idt` at a handler that only counts it
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    idt[SPURIOUS_VECTOR].set_handler_fn(spurious);
+}
+
+/// The redirection entry bits for `irq`'s polarity and trigger mode, from its MPS INTI flags
+fn redirect_flags(flags: Option<u16>) -> u32 {
+    let Some(flags) = flags else {
+        // ISA interrupts are active high and edge triggered
+        return 0;
+    };
+    let mut bits = 0;
+    if flags & 0b11 == 0b11 {
+        bits |= REDIRECT_ACTIVE_LOW;
+    }
+    if (flags >> 2) & 0b11 == 0b11 {
+        bits |= REDIRECT_LEVEL;
+    }
+    bits
+}
+
+fn setup(madt: &Madt) -> Result<(), ApicError> {
+    let base = unsafe { paging::map_mmio(madt.local_apic_address, PAGE_SIZE)? };
+    LOCAL_APIC.store(base.as_u64(), Ordering::Relaxed);
+
+    let mut io_apics = IO_APICS.lock();
+    for (slot, apic) in io_apics.apics.iter_mut().zip(madt.io_apics()) {
+        let base = unsafe { paging::map_mmio(apic.address, PAGE_SIZE)? };
+        let mut io_apic = IoApic {
+            base: base.as_u64(),
+            gsi_base: apic.gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(IOAPICVER) >> 16) & 0xff) + 1;
+        *slot = Some(io_apic);
+    }
+
+    let destination = (id() as u32) << 24;
+    // the PIT gets an entry too, masked, for route_nmi
+    for irq in 0..IRQ_COUNT {
+        let (gsi, flags) = madt.isa_gsi(irq);
+        let Some((index, apic)) = io_apics.find(gsi) else {
+            continue;
+        };
+
+        let entry = gsi - apic.gsi_base;
+        let low = REDIRECT_MASKED | redirect_flags(flags) | (IRQ_BASE + irq) as u32;
+        apic.write(IOREDTBL + entry * 2 + 1, destination);
+        apic.write(IOREDTBL + entry * 2, low);
+        io_apics.routes[irq as usize] = Some(Route { apic: index, entry });
+    }
+    if io_apics.routes[1..].iter().all(Option::is_none) {
+        return Err(ApicError::NoIoApic);
+    }
+    drop(io_apics);
+
+    write(SPURIOUS, APIC_ENABLE | SPURIOUS_VECTOR as u32);
+    let timer_hz = calibrate_timer();
+    TIMER_HZ.store(timer_hz, Ordering::Relaxed);
+    write(LVT_TIMER, LVT_MASKED | TIMER_PERIODIC | IRQ_BASE as u32);
+
+    pic::mask_all();
+    ENABLED.store(true, Ordering::Relaxed);
+    // anything that was already set up on the PICs carries on
+    for irq in 0..IRQ_COUNT {
+        if pic::has_handler(irq) {
+            set_masked(irq, false);
+        }
+    }
+
+    ilog!(
+        "apic: local APIC {} with a {} MHz timer, {} I/O APICs",
+        id(),
+        timer_hz / 1_000_000,
+        madt.io_apics().len()
+    );
+    Ok(())
+}
+
+fn init() {
+    match cmdline::get_bool("noapic") {
+        Ok(Some(true)) => return,
+        Ok(_) => {}
+        Err(error) => wlog!("apic: {}", error),
+    }
+    if !cpu::has(Feature::Apic) {
+        ilog!("apic: no local APIC, using the PICs");
+        return;
+    }
+    if !msr::apic_base().enabled {
+        ilog!("apic: the local APIC is disabled, using the PICs");
+        return;
+    }
+    let Some(madt) = acpi::madt() else {
+        ilog!("apic: no MADT, using the PICs");
+        return;
+    };
+
+    if let Err(error) = interrupts::without_interrupts(|| setup(&madt)) {
+        wlog!("apic: {:?}, using the PICs", error);
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "apic",
+    stage: Stage::Interrupts,
+    after: &["pic"],
+    func: init,
+};
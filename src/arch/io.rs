@@ -0,0 +1,116 @@
+//! I/O ports and memory-mapped registers
+//!
+//! A [`Port`] or [`Mmio`] is one device register with the type of its accesses built in, so
+//! drivers can name their registers once as constants instead of passing port numbers and
+//! sizes around. Making one is what's unsafe: once it exists, reading and writing it isn't,
+//! the same way the `x86_64` crate's `Port` makes sense only for the device it was made for.
+//!
+//! ```ignore
+//! const MISC_OUTPUT: Port<u8> = unsafe { Port::new(0x3cc) };
+//! let color = MISC_OUTPUT.read() & 1 != 0;
+//! ```
+//!
+
+use core::marker::PhantomData;
+
+use x86_64::structures::port::{PortRead, PortWrite};
+use x86_64::VirtAddr;
+
+/// The POST code port, which nothing reads, so writing it just takes a bus cycle
+const DELAY_PORT: u16 = 0x80;
+
+/// An I/O port accessed `T` at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port<T> {
+    port: u16,
+    _size: PhantomData<T>,
+}
+
+impl<T> Port<T> {
+    /// # Safety
+    ///
+    /// Accessing `port` as a `T` has to be fine whenever the port gets used, reads and writes
+    /// can have side effects on the device.
+    pub const unsafe fn new(port: u16) -> Port<T> {
+        Port {
+            port,
+            _size: PhantomData,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl<T: PortRead> Port<T> {
+    pub fn read(&self) -> T {
+        unsafe { T::read_from_port(self.port) }
+    }
+}
+
+impl<T: PortWrite> Port<T> {
+    pub fn write(&self, value: T) {
+        unsafe { T::write_to_port(self.port, value) }
+    }
+
+    /// Write `value`, then give slow devices like the legacy PICs time to react before the next
+    /// access
+    pub fn write_delayed(&self, value: T) {
+        self.write(value);
+        unsafe { u8::write_to_port(DELAY_PORT, 0) };
+    }
+}
+
+impl<T: PortRead + PortWrite> Port<T> {
+    /// Read the port, and write back what `f` makes of it
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
+
+/// A memory-mapped device register, accessed with volatile reads and writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mmio<T> {
+    addr: *mut T,
+}
+
+// the register is the device's, not memory owned by whichever CPU made the pointer
+unsafe impl<T> Send for Mmio<T> {}
+unsafe impl<T> Sync for Mmio<T> {}
+
+impl<T: Copy> Mmio<T> {
+    /// # Safety
+    ///
+    /// `addr` has to be mapped to the register, uncached, for as long as this is used, and
+    /// aligned for `T`.
+    pub unsafe fn new(addr: VirtAddr) -> Mmio<T> {
+        Mmio {
+            addr: addr.as_mut_ptr(),
+        }
+    }
+
+    /// The register `offset` bytes further on, with the same type
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`new`](Mmio::new), for the register at the new address.
+    pub unsafe fn offset(&self, offset: usize) -> Mmio<T> {
+        Mmio {
+            addr: self.addr.byte_add(offset),
+        }
+    }
+
+    pub fn read(&self) -> T {
+        unsafe { self.addr.read_volatile() }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { self.addr.write_volatile(value) }
+    }
+
+    /// Read the register, and write back what `f` makes of it
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
@@ -0,0 +1,5 @@
+//! Wrappers for talking to x86 hardware
+//!
+
+pub mod io;
+pub mod msr;
@@ -0,0 +1,145 @@
+//! Model specific registers
+//!
+//! [`read`] and [`write`] take any MSR by number, and the registers the kernel uses have
+//! accessors of their own so callers get typed values and don't need to know the numbers.
+//! Writes are unsafe: most of these registers change what the CPU does on the next syscall,
+//! interrupt, or memory access.
+//!
+//! links:
+//! - Intel SDM vol. 4, "Model-Specific Registers"
+//!
+
+use x86_64::registers::model_specific::{EferFlags, Msr, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::gdt::SegmentSelector;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const APIC_BASE: u32 = 0x1b;
+pub const EFER: u32 = 0xc000_0080;
+pub const STAR: u32 = 0xc000_0081;
+pub const LSTAR: u32 = 0xc000_0082;
+pub const SFMASK: u32 = 0xc000_0084;
+pub const FS_BASE: u32 = 0xc000_0100;
+pub const GS_BASE: u32 = 0xc000_0101;
+pub const KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// Read MSR `msr`
+///
+/// # Safety
+///
+/// The CPU has to have it, reading one that doesn't exist is a general protection fault.
+pub unsafe fn read(msr: u32) -> u64 {
+    Msr::new(msr).read()
+}
+
+/// Write `value` to MSR `msr`
+///
+/// # Safety
+///
+/// The CPU has to have it, and whatever the write changes can't break the kernel.
+pub unsafe fn write(msr: u32, value: u64) {
+    Msr::new(msr).write(value)
+}
+
+pub fn efer() -> EferFlags {
+    EferFlags::from_bits_truncate(unsafe { read(EFER) })
+}
+
+/// Change the flags in EFER
+///
+/// # Safety
+///
+/// Turning off long mode or no-execute with the kernel running on them brings it down.
+pub unsafe fn update_efer(f: impl FnOnce(&mut EferFlags)) {
+    let mut flags = efer();
+    f(&mut flags);
+    write(EFER, flags.bits());
+}
+
+/// What the APIC_BASE MSR says about this CPU's local APIC
+#[derive(Debug, Clone, Copy)]
+pub struct ApicBase {
+    /// Where its registers are
+    pub addr: PhysAddr,
+    /// The global enable bit, a disabled APIC can't be turned on without a reset
+    pub enabled: bool,
+    /// Whether this is the boot CPU
+    pub boot_cpu: bool,
+}
+
+/// Read APIC_BASE, which only exists on CPUs with a local APIC
+pub fn apic_base() -> ApicBase {
+    let value = unsafe { read(APIC_BASE) };
+    ApicBase {
+        addr: PhysAddr::new(value & 0x000f_ffff_ffff_f000),
+        enabled: value & (1 << 11) != 0,
+        boot_cpu: value & (1 << 8) != 0,
+    }
+}
+
+/// Set the segments `syscall` and `sysret` load. Fails if they're not laid out the way
+/// `sysret` expects, see [`gdt`](crate::gdt).
+///
+/// # Safety
+///
+/// The selectors have to be for the right kinds of segments in the loaded GDT.
+pub unsafe fn set_star(
+    user_code: SegmentSelector,
+    user_data: SegmentSelector,
+    kernel_code: SegmentSelector,
+    kernel_data: SegmentSelector,
+) -> Result<(), &'static str> {
+    Star::write(user_code, user_data, kernel_code, kernel_data)
+        .map_err(|_| "the selectors aren't laid out for syscall and sysret")
+}
+
+/// Set where `syscall` jumps to
+///
+/// # Safety
+///
+/// `entry` has to be code that can take a syscall.
+pub unsafe fn set_lstar(entry: VirtAddr) {
+    write(LSTAR, entry.as_u64());
+}
+
+/// Set the flags `syscall` clears
+///
+/// # Safety
+///
+/// The entry code has to cope with whatever the user leaves set.
+pub unsafe fn set_sfmask(flags: RFlags) {
+    write(SFMASK, flags.bits());
+}
+
+pub fn fs_base() -> VirtAddr {
+    VirtAddr::new_truncate(unsafe { read(FS_BASE) })
+}
+
+/// # Safety
+///
+/// Nothing in the kernel can be relying on FS.
+pub unsafe fn set_fs_base(base: VirtAddr) {
+    write(FS_BASE, base.as_u64());
+}
+
+pub fn gs_base() -> VirtAddr {
+    VirtAddr::new_truncate(unsafe { read(GS_BASE) })
+}
+
+/// # Safety
+///
+/// [`percpu`](crate::percpu) finds this CPU's block through GS, it has to point at one.
+pub unsafe fn set_gs_base(base: VirtAddr) {
+    write(GS_BASE, base.as_u64());
+}
+
+pub fn kernel_gs_base() -> VirtAddr {
+    VirtAddr::new_truncate(unsafe { read(KERNEL_GS_BASE) })
+}
+
+/// # Safety
+///
+/// The value is what GS becomes after the next `swapgs`.
+pub unsafe fn set_kernel_gs_base(base: VirtAddr) {
+    write(KERNEL_GS_BASE, base.as_u64());
+}
@@ -0,0 +1,172 @@
+//! Block devices
+//!
+//! Disks and anything else that's read and written a sector at a time implement
+//! [`BlockDevice`], and register with [`register`] once they're found. Filesystems look them up
+//! by name with [`get`], so they work the same on any of them.
+//!
+//! Transfers are whole sectors: a buffer's length has to be a multiple of the device's
+//! [`sector_size`](BlockDevice::sector_size), and [`check`] is there for drivers to turn
+//! anything else away before touching the hardware.
+//!
+
+use crate::debug::fault;
+use crate::sync::SpinLock;
+
+/// Devices that can be registered at once
+const MAX_DEVICES: usize = 8;
+
+/// Why a transfer failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// It goes past the last sector
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors
+    BadLength,
+    /// The device can't be written
+    ReadOnly,
+    /// The device reported an error
+    Io,
+    /// The device didn't answer in time
+    Timeout,
+}
+
+/// Something made of fixed size sectors
+pub trait BlockDevice: Sync {
+    /// Short name to refer to the device by, like `ata0`
+    fn name(&self) -> &'static str;
+
+    /// Bytes in a sector, 512 for most disks
+    fn sector_size(&self) -> usize {
+        512
+    }
+
+    fn sector_count(&self) -> u64;
+
+    /// Read the sectors starting at `sector` into `buf`
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf` to the sectors starting at `sector`
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Wait until everything written is on the device, for ones that cache writes
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// Size in bytes
+    fn size(&self) -> u64 {
+        self.sector_count() * self.sector_size() as u64
+    }
+}
+
+/// Check that a transfer of `len` bytes from `sector` fits on `device`. Returns how many
+/// sectors it is. It's also where the [`fault`] points for block devices are.
+pub fn check(device: &dyn BlockDevice, sector: u64, len: usize) -> Result<u64, BlockError> {
+    fault::delay(&fault::BLOCK_DELAY);
+    if fault::BLOCK.fire() {
+        return Err(BlockError::Io);
+    }
+    if !len.is_multiple_of(device.sector_size()) {
+        return Err(BlockError::BadLength);
+    }
+    let count = (len / device.sector_size()) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+static DEVICES: SpinLock<[Option<&'static dyn BlockDevice>; MAX_DEVICES]> =
+    SpinLock::new("block devices", [None; MAX_DEVICES]);
+
+/// Why a device couldn't be registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// There's already a device with that name
+    Exists,
+    /// All `MAX_DEVICES` slots are taken
+    Full,
+}
+
+/// Make `device` available to [`get`]
+pub fn register(device: &'static dyn BlockDevice) -> Result<(), RegisterError> {
+    let mut devices = DEVICES.lock();
+    if devices
+        .iter()
+        .flatten()
+        .any(|registered| registered.name() == device.name())
+    {
+        return Err(RegisterError::Exists);
+    }
+
+    let slot = devices
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(RegisterError::Full)?;
+    *slot = Some(device);
+    Ok(())
+}
+
+/// The device called `name`
+pub fn get(name: &str) -> Option<&'static dyn BlockDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .flatten()
+        .find(|device| device.name() == name)
+        .copied()
+}
+
+/// Call `f` with every registered device, in the order they were registered
+pub fn for_each(mut f: impl FnMut(&'static dyn BlockDevice)) {
+    // a copy, so `f` can do I/O without the registry locked
+    let devices = *DEVICES.lock();
+    for &device in devices.iter().flatten() {
+        f(device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Four sectors that read as their sector number and can't be written
+    struct Numbers;
+
+    impl BlockDevice for Numbers {
+        fn name(&self) -> &'static str {
+            "test-numbers"
+        }
+
+        fn sector_count(&self) -> u64 {
+            4
+        }
+
+        fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+            check(self, sector, buf.len())?;
+            for (i, chunk) in buf.chunks_mut(self.sector_size()).enumerate() {
+                chunk.fill(sector as u8 + i as u8);
+            }
+            Ok(())
+        }
+
+        fn write(&self, _sector: u64, _buf: &[u8]) -> Result<(), BlockError> {
+            Err(BlockError::ReadOnly)
+        }
+    }
+
+    #[test_case]
+    fn registered_device_reads() {
+        static NUMBERS: Numbers = Numbers;
+        register(&NUMBERS).unwrap();
+        assert_eq!(register(&NUMBERS), Err(RegisterError::Exists));
+
+        let device = get("test-numbers").unwrap();
+        assert_eq!(device.size(), 2048);
+        let mut buf = [0; 1024];
+        device.read(2, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[1023]), (2, 3));
+        assert_eq!(device.read(3, &mut buf), Err(BlockError::OutOfRange));
+        assert_eq!(device.read(0, &mut buf[..100]), Err(BlockError::BadLength));
+    }
+}
@@ -0,0 +1,111 @@
+//! What the bootloader tells us
+//!
+//! `kernel_main` hands the bootloader's [`BootInfo`] to [`init`] before anything else runs, and
+//! it's read through the accessors here from then on. It's checked first, since everything
+//! that reaches physical memory relies on the bootloader having honored our layout.
+//!
+//! bootloader 0.9 doesn't pass along a framebuffer or the ACPI RSDP, so those come from
+//! elsewhere: [`framebuffer`] is whatever [`gfx`] found, and [`rsdp`] searches the BIOS areas
+//! the ACPI spec says it's in.
+//!
+//! links:
+//! - RSDP: <https://wiki.osdev.org/RSDP>
+//!
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::BootInfo;
+use x86_64::PhysAddr;
+
+use crate::gfx::{self, FramebufferInfo};
+use crate::mem::{self, PHYS_OFFSET};
+use crate::sync::SpinLock;
+
+/// Why the boot info can't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoError {
+    /// Physical memory isn't mapped at [`PHYS_OFFSET`], but here
+    PhysicalMemoryOffset(u64),
+    /// There's no usable memory in the memory map
+    NoUsableMemory,
+    /// The memory map's regions overlap or are out of order, at this index
+    BadMemoryMap(usize),
+}
+
+static BOOT_INFO: SpinLock<Option<&'static BootInfo>> = SpinLock::new("boot info", None);
+
+/// Check `boot_info` and keep it for the rest of the kernel
+pub fn init(boot_info: &'static BootInfo) -> Result<(), BootInfoError> {
+    if boot_info.physical_memory_offset != PHYS_OFFSET {
+        return Err(BootInfoError::PhysicalMemoryOffset(
+            boot_info.physical_memory_offset,
+        ));
+    }
+
+    let map = &boot_info.memory_map;
+    for (i, pair) in map.windows(2).enumerate() {
+        if pair[1].range.start_addr() < pair[0].range.end_addr() {
+            return Err(BootInfoError::BadMemoryMap(i + 1));
+        }
+    }
+    if !map
+        .iter()
+        .any(|region| region.region_type == MemoryRegionType::Usable)
+    {
+        return Err(BootInfoError::NoUsableMemory);
+    }
+
+    *BOOT_INFO.lock() = Some(boot_info);
+    Ok(())
+}
+
+/// The bootloader's memory map, None before [`init`]
+pub fn memory_map() -> Option<&'static MemoryMap> {
+    BOOT_INFO.lock().map(|boot_info| &boot_info.memory_map)
+}
+
+/// Where all of physical memory is mapped, None before [`init`]. Always [`PHYS_OFFSET`].
+pub fn physical_memory_offset() -> Option<u64> {
+    BOOT_INFO
+        .lock()
+        .map(|boot_info| boot_info.physical_memory_offset)
+}
+
+/// The framebuffer we're drawing to, if there is one
+pub fn framebuffer() -> Option<FramebufferInfo> {
+    gfx::framebuffer_info()
+}
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Bytes the ACPI 1.0 checksum covers
+const RSDP_V1_SIZE: usize = 20;
+
+/// Where the BIOS data area keeps the extended BIOS data area's segment
+const EBDA_SEGMENT_PTR: u64 = 0x40e;
+/// The main BIOS area the RSDP can be in
+const BIOS_AREA: (u64, u64) = (0xe_0000, 0x10_0000);
+
+/// Find an RSDP between `start` and `end`, it's always 16 byte aligned
+fn find_rsdp(start: u64, end: u64) -> Option<PhysAddr> {
+    (start..end).step_by(16).map(PhysAddr::new).find(|&addr| {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(mem::phys_to_virt(addr).as_ptr::<u8>(), RSDP_V1_SIZE)
+        };
+        bytes.starts_with(RSDP_SIGNATURE)
+            && bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+    })
+}
+
+/// Physical address of the ACPI RSDP, found by searching the first KiB of the extended BIOS data
+/// area and then the main BIOS area. It's searched for again on every call.
+pub fn rsdp() -> Option<PhysAddr> {
+    let segment =
+        unsafe { *mem::phys_to_virt(PhysAddr::new(EBDA_SEGMENT_PTR)).as_ptr::<u16>() } as u64;
+    let ebda = segment << 4;
+    // nothing sensible is below the end of the BIOS data area
+    let in_ebda = if ebda >= 0x500 {
+        find_rsdp(ebda, ebda + 1024)
+    } else {
+        None
+    };
+    in_ebda.or_else(|| find_rsdp(BIOS_AREA.0, BIOS_AREA.1))
+}
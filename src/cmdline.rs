@@ -0,0 +1,189 @@
+//! Kernel command line
+//!
+//! bootloader 0.9 doesn't pass a command line, so for now it's baked in at build time from the
+//! `ZENIX_CMDLINE` environment variable:
+//!
+//! ```shell
+//! $ ZENIX_CMDLINE="loglevel=debug console=serial" cargo run
+//! ```
+//!
+//! The line is split on whitespace into `key=value` pairs or bare `flag`s. Values can be
+//! double-quoted to include spaces. If a key shows up more than once, the last one wins.
+//!
+//! | option                | does                                                       |
+//! |-----------------------|------------------------------------------------------------|
+//! | `loglevel=LEVEL`      | lowest [log level](crate::log) printed, `info` by default  |
+//! | `heap=KIB`            | size of the [heap](crate::config::heap_size)               |
+//! | `hz=HZ`               | [timer](crate::config::timer_hz) interrupts a second       |
+//! | `timeslice=MS`        | a thread's [time slice](crate::config::time_slice_ms)      |
+//! | `logtime`             | start log lines with the [RTC](crate::rtc)'s time of day   |
+//! | `console=SINK,...`    | the only [sinks](crate::console::sink) printing goes to    |
+//! | `novga`               | keep printing off the VGA text screen                      |
+//! | `noapic`              | keep using the 8259 [PICs](crate::pic)                     |
+//! | `clock=CLOCK`         | [timer](crate::timer) hardware, `pit` or `hpet`            |
+//! | `nostatus`            | no [status line](crate::statusbar)                         |
+//! | `statusrows=ROWS`     | [console rows](crate::config::status_rows) it keeps        |
+//! | `video=WxH[xBPP]`     | [graphics mode](crate::gfx) to switch to                   |
+//! | `font=PATH`           | a PSF [font](crate::gfx::console) from the initrd          |
+//! | `quiet`               | boot output only to [klog](crate::klog) until the shell    |
+//! | `splash[=PATH]`       | a [splash](crate::gfx::splash) image up until the shell    |
+//! | `ramdisk=MIB`         | a blank [RAM disk](crate::drivers::ramdisk) that size      |
+//! | `ip=ADDRESS`          | the kernel's [IPv4 address](crate::net), none by default   |
+//! | `syslog=HOST[:PORT]`  | a [syslog](crate::net::syslog) collector to send lines to  |
+//! | `panic=ACTION[,SECS]` | what a [panic](crate::power::after_panic) ends with        |
+//! | `watchdog=SECONDS`    | when the [watchdog](crate::watchdog) warns, 0 for never    |
+//! | `gdb`                 | wait for [gdb](crate::debug::gdbstub) on COM2 at boot      |
+//! | `kdb`                 | stop in the [debugger](crate::debug::kdb) before the shell |
+//!
+//! The numbers are [tunables](crate::config), with defaults cargo features can change.
+//!
+//! Options are read with [`get`] and [`has`], or turned into typed values with [`parse`] and
+//! [`get_bool`].
+//!
+
+use core::fmt;
+use core::str::FromStr;
+
+use lazy_static::lazy_static;
+
+/// Maximum number of arguments kept, anything past this is dropped
+const MAX_ARGS: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+struct Arg {
+    key: &'static str,
+    value: &'static str,
+}
+
+impl Arg {
+    const EMPTY: Arg = Arg { key: "", value: "" };
+}
+
+struct Cmdline {
+    raw: &'static str,
+    args: [Arg; MAX_ARGS],
+    len: usize,
+}
+
+impl Cmdline {
+    fn parse(raw: &'static str) -> Cmdline {
+        let mut cmdline = Cmdline {
+            raw,
+            args: [Arg::EMPTY; MAX_ARGS],
+            len: 0,
+        };
+
+        let mut rest = raw.trim_start();
+        while !rest.is_empty() && cmdline.len < MAX_ARGS {
+            // find the end of this argument, whitespace between quotes doesn't count
+            let mut in_quotes = false;
+            let end = rest
+                .char_indices()
+                .find(|&(_, c)| {
+                    if c == '"' {
+                        in_quotes = !in_quotes;
+                    }
+                    !in_quotes && c.is_whitespace()
+                })
+                .map_or(rest.len(), |(i, _)| i);
+
+            let (arg, tail) = rest.split_at(end);
+            rest = tail.trim_start();
+
+            let (key, value) = arg.split_once('=').unwrap_or((arg, ""));
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+
+            cmdline.args[cmdline.len] = Arg { key, value };
+            cmdline.len += 1;
+        }
+
+        cmdline
+    }
+
+    fn args(&self) -> &[Arg] {
+        &self.args[..self.len]
+    }
+
+    /// The last argument for `key`
+    fn find(&self, key: &str) -> Option<&Arg> {
+        self.args().iter().rev().find(|arg| arg.key == key)
+    }
+}
+
+lazy_static! {
+    static ref CMDLINE: Cmdline = Cmdline::parse(option_env!("ZENIX_CMDLINE").unwrap_or(""));
+}
+
+/// The command line as it was passed in
+pub fn raw() -> &'static str {
+    CMDLINE.raw
+}
+
+/// Look up the value given for `key`. Bare flags have an empty value.
+pub fn get(key: &str) -> Option<&'static str> {
+    CMDLINE.find(key).map(|arg| arg.value)
+}
+
+/// Check whether `key` was given at all, with or without a value
+pub fn has(key: &str) -> bool {
+    get(key).is_some()
+}
+
+/// An option's value that couldn't be turned into the type it needs to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidValue {
+    pub key: &'static str,
+    pub value: &'static str,
+}
+
+impl fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid value for {}: {:?}", self.key, self.value)
+    }
+}
+
+/// Parse the value given for `key`, None if it wasn't given
+pub fn parse<T: FromStr>(key: &str) -> Result<Option<T>, InvalidValue> {
+    let Some(arg) = CMDLINE.find(key) else {
+        return Ok(None);
+    };
+    arg.value.parse().map(Some).map_err(|_| InvalidValue {
+        key: arg.key,
+        value: arg.value,
+    })
+}
+
+/// Read `key` as a switch. A bare flag is on, and so are `on`, `yes`, `true`, and `1`. `off`,
+/// `no`, `false`, and `0` are off.
+pub fn get_bool(key: &str) -> Result<Option<bool>, InvalidValue> {
+    let Some(arg) = CMDLINE.find(key) else {
+        return Ok(None);
+    };
+    match arg.value {
+        "" | "on" | "yes" | "true" | "1" => Ok(Some(true)),
+        "off" | "no" | "false" | "0" => Ok(Some(false)),
+        value => Err(InvalidValue {
+            key: arg.key,
+            value,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn splits_arguments() {
+        let cmdline = Cmdline::parse("  quiet panic=halt splash=\"/a b.bmp\" panic=reboot,5 ");
+        let find = |key| cmdline.find(key).map(|arg| arg.value);
+        assert_eq!(cmdline.args().len(), 4);
+        assert_eq!(find("quiet"), Some(""));
+        assert_eq!(find("splash"), Some("/a b.bmp"));
+        assert_eq!(find("panic"), Some("reboot,5"));
+        assert_eq!(find("gdb"), None);
+    }
+}
@@ -0,0 +1,177 @@
+//! Kernel tunables
+//!
+//! The numbers other modules are built around that there's a reason to change, kept in one
+//! place. Each has a default here, which a cargo feature can change at build time, and which
+//! the [command line](crate::cmdline) can override at boot:
+//!
+//! | tunable                     | default  | feature                    | command line       |
+//! |-----------------------------|----------|----------------------------|--------------------|
+//! | [heap](crate::mem::heap)    | 1 MiB    | `large_heap`: 16 MiB       | `heap=KIB`         |
+//! | [timer](crate::timer) rate  | 1000 Hz  | `low_hz`: 100 Hz           | `hz=HZ`            |
+//! | [time slice](crate::sched)  | 10 ms    |                            | `timeslice=MS`     |
+//! | [log level](crate::log)     | info     | `debug_log`: debug         | `loglevel=LEVEL`   |
+//! | [status](crate::statusbar)  | 1 row    | `no_status_line`: 0 rows   | `statusrows=ROWS`  |
+//!
+//! `nostatus` is the same as `statusrows=0`. [`INIT`] reads the command line once, early, and
+//! a value that doesn't parse or is out of range is warned about and the default kept. Until
+//! then, and in modules that run before it, the functions here return the defaults.
+//!
+
+use core::fmt::Display;
+use core::ops::RangeInclusive;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use crate::cmdline::{self, InvalidValue};
+use crate::init::{InitCall, Stage};
+use crate::log::LogLevel;
+use crate::wlog;
+
+/// Bytes of heap mapped at boot
+pub const HEAP_SIZE: u64 = if cfg!(feature = "large_heap") {
+    16 * 1024 * 1024
+} else {
+    1024 * 1024
+};
+const HEAP_KIB: RangeInclusive<u64> = 256..=1024 * 1024;
+
+/// How often the timer interrupt fires
+pub const TIMER_HZ: u32 = if cfg!(feature = "low_hz") { 100 } else { 1000 };
+/// The PIT can't go slower than 19 Hz
+const TIMER_RANGE: RangeInclusive<u32> = 19..=10_000;
+
+/// How long a thread gets before it's preempted
+pub const TIME_SLICE_MS: u64 = 10;
+const TIME_SLICE_RANGE: RangeInclusive<u64> = 1..=1000;
+
+/// The lowest log level printed
+pub const LOG_LEVEL: LogLevel = if cfg!(feature = "debug_log") {
+    LogLevel::Debug
+} else {
+    LogLevel::Info
+};
+
+/// Rows at the bottom of each VGA console kept out of the text, the status line is the last
+pub const STATUS_ROWS: usize = if cfg!(feature = "no_status_line") {
+    0
+} else {
+    1
+};
+const STATUS_RANGE: RangeInclusive<usize> = 0..=4;
+
+static HEAP: AtomicU64 = AtomicU64::new(HEAP_SIZE);
+static TIMER: AtomicU32 = AtomicU32::new(TIMER_HZ);
+static TIME_SLICE: AtomicU64 = AtomicU64::new(TIME_SLICE_MS);
+static LEVEL: AtomicU8 = AtomicU8::new(LOG_LEVEL as u8);
+static STATUS: AtomicUsize = AtomicUsize::new(STATUS_ROWS);
+
+pub fn heap_size() -> u64 {
+    HEAP.load(Ordering::Relaxed)
+}
+
+pub fn timer_hz() -> u32 {
+    TIMER.load(Ordering::Relaxed)
+}
+
+pub fn time_slice_ms() -> u64 {
+    TIME_SLICE.load(Ordering::Relaxed)
+}
+
+pub fn log_level() -> LogLevel {
+    LogLevel::ALL[LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+pub fn status_rows() -> usize {
+    STATUS.load(Ordering::Relaxed)
+}
+
+/// `value`, what the command line had for `key`, if it's there and in `range`, or `default`
+fn checked<T: PartialOrd + Display + Copy>(
+    key: &str,
+    value: Result<Option<T>, InvalidValue>,
+    range: RangeInclusive<T>,
+    default: T,
+) -> T {
+    match value {
+        Ok(None) => default,
+        Ok(Some(value)) if range.contains(&value) => value,
+        Ok(Some(value)) => {
+            wlog!(
+                "config: {}={} isn't in {}..={}, keeping {}",
+                key,
+                value,
+                range.start(),
+                range.end(),
+                default
+            );
+            default
+        }
+        Err(error) => {
+            wlog!("config: {}, keeping {}", error, default);
+            default
+        }
+    }
+}
+
+fn number<T: FromStr + PartialOrd + Display + Copy>(
+    key: &str,
+    range: RangeInclusive<T>,
+    default: T,
+) -> T {
+    checked(key, cmdline::parse(key), range, default)
+}
+
+fn init() {
+    let heap_kib = number("heap", HEAP_KIB, HEAP_SIZE / 1024);
+    HEAP.store(heap_kib * 1024, Ordering::Relaxed);
+    TIMER.store(number("hz", TIMER_RANGE, TIMER_HZ), Ordering::Relaxed);
+    TIME_SLICE.store(
+        number("timeslice", TIME_SLICE_RANGE, TIME_SLICE_MS),
+        Ordering::Relaxed,
+    );
+
+    if let Some(name) = cmdline::get("loglevel") {
+        match LogLevel::from_name(name) {
+            Some(level) => LEVEL.store(level as u8, Ordering::Relaxed),
+            None => wlog!(
+                "config: unknown loglevel {}, keeping {}",
+                name,
+                LOG_LEVEL.name()
+            ),
+        }
+    }
+
+    let rows = match cmdline::get_bool("nostatus") {
+        Ok(Some(true)) => 0,
+        Ok(_) => number("statusrows", STATUS_RANGE, STATUS_ROWS),
+        Err(error) => {
+            wlog!("config: {}", error);
+            STATUS_ROWS
+        }
+    };
+    STATUS.store(rows, Ordering::Relaxed);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "config",
+    stage: Stage::Early,
+    // the warnings need somewhere to go
+    after: &["vga", "fbcon"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn out_of_range_keeps_default() {
+        assert_eq!(checked("hz", Ok(None), TIMER_RANGE, TIMER_HZ), TIMER_HZ);
+        assert_eq!(checked("hz", Ok(Some(250)), TIMER_RANGE, TIMER_HZ), 250);
+        assert_eq!(checked("hz", Ok(Some(5)), TIMER_RANGE, TIMER_HZ), TIMER_HZ);
+        assert!(TIMER_RANGE.contains(&TIMER_HZ));
+        assert!(HEAP_KIB.contains(&(HEAP_SIZE / 1024)));
+        assert!(TIME_SLICE_RANGE.contains(&TIME_SLICE_MS));
+        assert!(STATUS_RANGE.contains(&STATUS_ROWS));
+    }
+}
@@ -0,0 +1,214 @@
+//! ANSI/VT100 escape sequence parsing
+//!
+//! Only CSI sequences (`ESC [ params final`) are recognized, which covers colors, cursor
+//! movement, and erasing. Any other sequence starting with ESC is dropped.
+//!
+//! links:
+//! - <https://en.wikipedia.org/wiki/ANSI_escape_code>
+//! - <https://vt100.net/emu/dec_ansi_parser>
+//!
+
+use super::Color;
+
+const MAX_PARAMS: usize = 8;
+
+/// A complete control sequence
+#[derive(Debug, Clone, Copy)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    /// The final byte, which says what to do with the parameters
+    pub action: char,
+}
+
+impl Csi {
+    const EMPTY: Csi = Csi {
+        params: [0; MAX_PARAMS],
+        len: 0,
+        action: '\0',
+    };
+
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+
+    /// Parameter `i`, with missing or zero values replaced by `default`
+    pub fn param(&self, i: usize, default: u16) -> u16 {
+        match self.params().get(i) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+}
+
+/// Something the console has to act on
+#[derive(Debug, Clone, Copy)]
+pub enum Output {
+    Char(char),
+    Csi(Csi),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// Splits a stream of characters into printable characters and control sequences
+pub struct Parser {
+    state: State,
+    csi: Csi,
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::new()
+    }
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            csi: Csi::EMPTY,
+        }
+    }
+
+    /// Feed in the next character. Returns whatever it completed, if anything.
+    pub fn feed(&mut self, c: char) -> Option<Output> {
+        match self.state {
+            State::Ground => {
+                if c == '\x1b' {
+                    self.state = State::Escape;
+                    None
+                } else {
+                    Some(Output::Char(c))
+                }
+            }
+            State::Escape => {
+                if c == '[' {
+                    self.state = State::Csi;
+                    self.csi = Csi::EMPTY;
+                } else {
+                    self.state = State::Ground;
+                }
+                None
+            }
+            State::Csi => match c {
+                '0'..='9' => {
+                    if self.csi.len == 0 {
+                        self.csi.len = 1;
+                    }
+                    let param = &mut self.csi.params[self.csi.len - 1];
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add(c as u16 - '0' as u16);
+                    None
+                }
+                ';' => {
+                    if self.csi.len == 0 {
+                        self.csi.len = 1;
+                    }
+                    // extra parameters are dropped, the last slot keeps getting overwritten
+                    if self.csi.len < MAX_PARAMS {
+                        self.csi.len += 1;
+                    }
+                    self.csi.params[self.csi.len - 1] = 0;
+                    None
+                }
+                // private markers and intermediates, none of what's supported uses them
+                '<'..='?' | ' '..='/' => None,
+                '@'..='~' => {
+                    self.state = State::Ground;
+                    self.csi.action = c;
+                    Some(Output::Csi(self.csi))
+                }
+                // anything else cancels the sequence
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// ANSI color numbers (black, red, green, yellow, blue, magenta, cyan, white) in VGA order
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+
+/// Apply an SGR (`ESC [ ... m`) sequence to the current colors
+pub fn apply_sgr(csi: &Csi, fg: &mut Color, bg: &mut Color, default_fg: Color, default_bg: Color) {
+    // no parameters at all means reset
+    if csi.params().is_empty() {
+        *fg = default_fg;
+        *bg = default_bg;
+        return;
+    }
+
+    for &param in csi.params() {
+        match param {
+            0 => {
+                *fg = default_fg;
+                *bg = default_bg;
+            }
+            // bold shows up as the bright variant, like on the linux console
+            1 => *fg = fg.bright(),
+            22 => *fg = fg.dim(),
+            30..=37 => *fg = ANSI_COLORS[(param - 30) as usize],
+            39 => *fg = default_fg,
+            40..=47 => *bg = ANSI_COLORS[(param - 40) as usize],
+            49 => *bg = default_bg,
+            90..=97 => *fg = ANSI_COLORS[(param - 90) as usize].bright(),
+            100..=107 => *bg = ANSI_COLORS[(param - 100) as usize].bright(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_str(parser: &mut Parser, s: &str) -> Option<Output> {
+        s.chars().fold(None, |_, c| parser.feed(c))
+    }
+
+    #[test_case]
+    fn plain_characters_pass_through() {
+        let mut parser = Parser::new();
+        assert!(matches!(parser.feed('a'), Some(Output::Char('a'))));
+    }
+
+    #[test_case]
+    fn csi_parameters() {
+        let mut parser = Parser::new();
+        let Some(Output::Csi(csi)) = feed_str(&mut parser, "\x1b[1;;31m") else {
+            panic!("no CSI sequence");
+        };
+        assert_eq!(csi.action, 'm');
+        assert_eq!(csi.params(), &[1, 0, 31]);
+        assert_eq!(csi.param(1, 7), 7);
+    }
+
+    #[test_case]
+    fn sgr_colors() {
+        let mut parser = Parser::new();
+        let Some(Output::Csi(csi)) = feed_str(&mut parser, "\x1b[31;104m") else {
+            panic!("no CSI sequence");
+        };
+        let (mut fg, mut bg) = (Color::White, Color::Black);
+        apply_sgr(&csi, &mut fg, &mut bg, Color::White, Color::Black);
+        assert_eq!(fg, Color::Red);
+        assert_eq!(bg, Color::LightBlue);
+    }
+}
@@ -0,0 +1,93 @@
+//! Unicode to code page 437
+//!
+//! Both the VGA text buffer and the framebuffer font are indexed by CP437 byte, so text is
+//! put through [`encode`] on its way to the screen, and [`decode`] goes back for fonts that
+//! aren't in CP437 order. Besides the characters CP437 actually has,
+//! a few common ones it doesn't, like curly quotes and dashes, get their closest ASCII
+//! stand-in, and anything else is left to the caller, which shows [`REPLACEMENT`].
+//!
+//! links:
+//! - <https://en.wikipedia.org/wiki/Code_page_437>
+//!
+
+/// A filled square, for characters without a glyph
+pub const REPLACEMENT: u8 = 0xfe;
+
+/// The glyphs at 0x00 to 0x1f, in place of the control characters. 0x00 is blank.
+const LOW: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', //
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// The glyphs at 0x80 to 0xff
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// The CP437 byte that shows `c`, None if there isn't one. Printable ASCII maps to itself,
+/// control characters don't map at all.
+pub fn encode(c: char) -> Option<u8> {
+    match c {
+        ' '..='~' => return Some(c as u8),
+        '\u{0}'..='\u{7f}' => return None,
+        '⌂' => return Some(0x7f),
+        // look-alikes that have their own code points: beta, micro, and ohm
+        '\u{3b2}' => return Some(0xe1),
+        '\u{3bc}' => return Some(0xe6),
+        '\u{2126}' => return Some(0xea),
+        '∅' => return Some(0xed),
+        '‘' | '’' | '′' => return Some(b'\''),
+        '“' | '”' | '″' => return Some(b'"'),
+        '‐' | '‑' | '–' | '—' | '−' => return Some(b'-'),
+        _ => {}
+    }
+    if let Some(index) = HIGH.iter().position(|&glyph| glyph == c) {
+        return Some(0x80 + index as u8);
+    }
+    LOW.iter()
+        .skip(1)
+        .position(|&glyph| glyph == c)
+        .map(|index| 1 + index as u8)
+}
+
+/// The character CP437 byte `byte` shows, a space for the blank glyph at 0
+pub fn decode(byte: u8) -> char {
+    match byte {
+        0 => ' ',
+        0x01..=0x1f => LOW[byte as usize],
+        0x7f => '⌂',
+        0x80..=0xff => HIGH[byte as usize - 0x80],
+        _ => byte as char,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn encodes_characters() {
+        assert_eq!(encode('A'), Some(b'A'));
+        assert_eq!(encode('\n'), None);
+        assert_eq!(encode('°'), Some(0xf8));
+        assert_eq!(encode('┌'), Some(0xda));
+        assert_eq!(encode('☺'), Some(0x01));
+        assert_eq!(encode('’'), Some(b'\''));
+        assert_eq!(encode('\u{a0}'), Some(0xff));
+        assert_eq!(encode('😀'), None);
+    }
+
+    #[test_case]
+    fn decodes_every_byte_back() {
+        for byte in 1..=255u8 {
+            assert_eq!(encode(decode(byte)), Some(byte));
+        }
+    }
+}
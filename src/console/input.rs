@@ -0,0 +1,106 @@
+//! Console input
+//!
+//! The [`keyboard`](crate::keyboard) and the [`serial`](crate::serial) port both put what's
+//! typed on them into one queue, as the bytes a VT100 terminal would send, so whoever reads it
+//! doesn't care which one it came from. That's what makes the shell usable headless, with
+//! `console=serial` on the command line sending the output the same way.
+//!
+//! The interrupt handlers are the producers. They all run on the boot CPU with interrupts off,
+//! so they never push at the same time and the queue doesn't need a lock. There's meant to be
+//! one consumer at a time, either polling with [`try_read`], blocking in [`read`], or awaiting
+//! [`next_byte`] in a [`task`](crate::task).
+//!
+
+use core::future::Future;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use core::task::Poll;
+
+use crate::sync::WaitQueue;
+use crate::task::WakerSlot;
+
+const QUEUE_SIZE: usize = 256;
+
+/// Bytes typed but not read yet. `head` is only moved by the reader and `tail` only by the
+/// interrupt handlers, so neither needs a lock.
+struct Queue {
+    buf: [AtomicU8; QUEUE_SIZE],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+impl Queue {
+    fn push(&self, byte: u8) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % QUEUE_SIZE;
+        if next == self.head.load(Ordering::Acquire) {
+            // full, drop it
+            return;
+        }
+        self.buf[tail].store(byte, Ordering::Relaxed);
+        self.tail.store(next, Ordering::Release);
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = self.buf[head].load(Ordering::Relaxed);
+        self.head.store((head + 1) % QUEUE_SIZE, Ordering::Release);
+        Some(byte)
+    }
+}
+
+static QUEUE: Queue = Queue {
+    buf: [const { AtomicU8::new(0) }; QUEUE_SIZE],
+    head: AtomicUsize::new(0),
+    tail: AtomicUsize::new(0),
+};
+
+/// The task waiting in [`next_byte`]
+static WAKER: WakerSlot = WakerSlot::new("input waker");
+/// Threads waiting in [`read`]
+static READERS: WaitQueue = WaitQueue::new("input readers");
+
+/// Add bytes to the queue, from an input device's interrupt handler. Bytes that don't fit are
+/// dropped.
+pub fn push(bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    for &byte in bytes {
+        QUEUE.push(byte);
+    }
+    WAKER.wake();
+    READERS.wake_one();
+}
+
+/// The next byte typed, if there is one
+pub fn try_read() -> Option<u8> {
+    QUEUE.pop()
+}
+
+/// Block until the next byte is typed, needs interrupts on
+pub fn read() -> u8 {
+    let mut byte = None;
+    READERS.wait_until(|| {
+        byte = try_read();
+        byte.is_some()
+    });
+    byte.unwrap()
+}
+
+/// Wait for the next byte typed, from a task
+pub fn next_byte() -> impl Future<Output = u8> {
+    core::future::poll_fn(|context| {
+        if let Some(byte) = try_read() {
+            return Poll::Ready(byte);
+        }
+        WAKER.register(context.waker());
+        // it might have come in before the waker was there
+        match try_read() {
+            Some(byte) => Poll::Ready(byte),
+            None => Poll::Pending,
+        }
+    })
+}
@@ -0,0 +1,161 @@
+//! Console output
+//!
+//! Every display that can show text implements [`Console`]. The active one is the framebuffer
+//! console when the bootloader left us in a graphics mode, and the VGA text buffer otherwise.
+//!
+//! `print!` and `println!` write to every enabled [`sink`], which are the active console and
+//! the serial port unless they've been changed.
+//!
+//! Printing is safe from interrupt handlers: the console and sink locks are only ever held
+//! with interrupts off, so a handler can't interrupt their holder and spin on them forever.
+//!
+
+use core::fmt;
+
+use x86_64::instructions::interrupts;
+
+use crate::{gfx, vga};
+
+pub mod ansi;
+pub mod cp437;
+pub mod input;
+pub mod readline;
+pub mod sink;
+pub mod tui;
+
+pub use readline::readline;
+
+/// The 16 colors of the VGA text mode palette, which every console uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Color {
+    Black = 0,
+    Blue = 1,
+    Green = 2,
+    Cyan = 3,
+    Red = 4,
+    Magenta = 5,
+    Brown = 6,
+    LightGray = 7,
+    DarkGray = 8,
+    LightBlue = 9,
+    LightGreen = 10,
+    LightCyan = 11,
+    LightRed = 12,
+    Pink = 13,
+    Yellow = 14,
+    White = 15,
+}
+
+impl Color {
+    const ALL: [Color; 16] = [
+        Color::Black,
+        Color::Blue,
+        Color::Green,
+        Color::Cyan,
+        Color::Red,
+        Color::Magenta,
+        Color::Brown,
+        Color::LightGray,
+        Color::DarkGray,
+        Color::LightBlue,
+        Color::LightGreen,
+        Color::LightCyan,
+        Color::LightRed,
+        Color::Pink,
+        Color::Yellow,
+        Color::White,
+    ];
+
+    /// The color with this index in the palette, only the low 4 bits count
+    pub fn from_index(index: u8) -> Color {
+        Color::ALL[index as usize & 0xf]
+    }
+
+    /// The high intensity version of this color
+    pub fn bright(self) -> Color {
+        Color::ALL[self as usize | 8]
+    }
+
+    /// The low intensity version of this color
+    pub fn dim(self) -> Color {
+        Color::ALL[self as usize & 7]
+    }
+}
+
+/// A text display
+pub trait Console: fmt::Write + Send {
+    /// Size in character cells, as (columns, rows)
+    fn size(&self) -> (usize, usize);
+
+    /// Blank the whole screen and move the cursor to the top left
+    fn clear(&mut self);
+
+    /// The colors text is written in, foreground and background
+    fn color(&self) -> (Color, Color);
+
+    /// Set the colors used for text written from now on
+    fn set_color(&mut self, foreground: Color, background: Color);
+}
+
+/// Run `f` on the active console, with interrupts off
+pub fn with_console<R>(f: impl FnOnce(&mut dyn Console) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        if let Some(console) = gfx::console::CONSOLE.lock().as_mut() {
+            return f(console);
+        }
+
+        f(&mut *vga::WRITER.lock())
+    })
+}
+
+/// Set the colors the active console writes text in from now on
+pub fn set_color(foreground: Color, background: Color) {
+    with_console(|console| console.set_color(foreground, background));
+}
+
+/// Run `f` with the active console writing in the given colors, and put the old ones back after
+pub fn with_color<R>(foreground: Color, background: Color, f: impl FnOnce() -> R) -> R {
+    let (old_foreground, old_background) = with_console(|console| console.color());
+    set_color(foreground, background);
+    let result = f();
+    set_color(old_foreground, old_background);
+    result
+}
+
+/// Write text to the console and the other sinks
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+/// Write a line of text to the console and the other sinks
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// `print!` in a foreground color, on the console's current background
+///
+/// ```ignore
+/// print_colored!(Color::LightGreen, "[ok] {}\n", name);
+/// ```
+#[macro_export]
+macro_rules! print_colored {
+    ($color:expr, $($arg:tt)*) => ($crate::console::_print_colored($color, format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    interrupts::without_interrupts(|| sink::write_fmt(args));
+}
+
+#[doc(hidden)]
+pub fn _print_colored(color: Color, args: fmt::Arguments) {
+    // all at once, so nothing printed from an interrupt comes out in this color
+    interrupts::without_interrupts(|| {
+        let (_, background) = with_console(|console| console.color());
+        with_color(color, background, || _print(args));
+    });
+}
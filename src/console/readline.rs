@@ -0,0 +1,368 @@
+//! Line editing
+//!
+//! An [`Editor`] reads a line at a time for a prompt, keeping its own history and completing
+//! words with the function it was made with. The [`shell`](crate::shell) has one. Anything else
+//! that wants a line from the user can call [`readline`], which shares one editor and its
+//! history, without completion.
+//!
+//! Keys come in from the [console input](super::input), where the serial port and the
+//! [`keyboard`](crate::keyboard) both put the bytes a VT100-style terminal sends: printable characters, control characters, and escape sequences
+//! for the cursor keys. Supported:
+//!
+//! - left/right, home/end (also Ctrl+A/Ctrl+E), and backspace/delete edit the line
+//! - up/down go through the last [`HISTORY_LEN`] lines
+//! - tab completes the word before the cursor with the editor's [`Completer`], or lists the
+//!   choices when there's more than one
+//! - Ctrl+C drops the line
+//!
+//! Redrawing only uses backspace to move left and rewrites characters to move right, so it
+//! works the same on the serial terminal and on consoles that don't know escape sequences.
+//!
+
+use alloc::string::String;
+
+use super::input;
+use crate::sync::Mutex;
+use crate::{print, println, task};
+
+pub const MAX_LINE: usize = 128;
+/// Lines kept in the history
+pub const HISTORY_LEN: usize = 16;
+
+const ESC: u8 = 0x1b;
+const CTRL_A: u8 = 0x01;
+const CTRL_C: u8 = 0x03;
+const CTRL_E: u8 = 0x05;
+const TAB: u8 = 0x09;
+
+/// Finds completions for a word: `complete(line, word, candidate)` calls `candidate` with
+/// each string that could replace `word`, which is the end of `line` after the last space
+pub type Completer = fn(line: &str, word: &str, candidate: &mut dyn FnMut(&str));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(u8),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Tab,
+    Cancel,
+}
+
+/// Wait for the next byte typed, running tasks meanwhile
+fn receive() -> u8 {
+    loop {
+        if let Some(byte) = input::try_read() {
+            return byte;
+        }
+        task::idle();
+    }
+}
+
+/// Wait for the next key, None for ones that aren't handled
+fn read_key() -> Option<Key> {
+    match receive() {
+        b'\r' | b'\n' => Some(Key::Enter),
+        // backspace and delete, which is what most terminals send for backspace
+        0x08 | 0x7f => Some(Key::Backspace),
+        CTRL_A => Some(Key::Home),
+        CTRL_C => Some(Key::Cancel),
+        CTRL_E => Some(Key::End),
+        TAB => Some(Key::Tab),
+        ESC => read_escape(),
+        byte @ 0x20..=0x7e => Some(Key::Char(byte)),
+        _ => None,
+    }
+}
+
+/// Decode the rest of an escape sequence, `ESC [ A` or `ESC O A` style for the cursor keys,
+/// or `ESC [ 3 ~` style for the editing keys
+fn read_escape() -> Option<Key> {
+    if !matches!(receive(), b'[' | b'O') {
+        return None;
+    }
+
+    let mut number = 0;
+    loop {
+        match receive() {
+            b'A' => return Some(Key::Up),
+            b'B' => return Some(Key::Down),
+            b'C' => return Some(Key::Right),
+            b'D' => return Some(Key::Left),
+            b'H' => return Some(Key::Home),
+            b'F' => return Some(Key::End),
+            digit @ b'0'..=b'9' => number = number * 10 + (digit - b'0') as u32,
+            b'~' => {
+                return match number {
+                    1 | 7 => Some(Key::Home),
+                    3 => Some(Key::Delete),
+                    4 | 8 => Some(Key::End),
+                    _ => None,
+                }
+            }
+            // parameters we don't care about, e.g. modifiers
+            b';' => {}
+            _ => return None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    buf: [u8; MAX_LINE],
+    len: usize,
+}
+
+impl Line {
+    const EMPTY: Line = Line {
+        buf: [0; MAX_LINE],
+        len: 0,
+    };
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+pub struct Editor {
+    line: Line,
+    cursor: usize,
+    history: [Line; HISTORY_LEN],
+    /// Lines in the history so far, up to `HISTORY_LEN`
+    history_count: usize,
+    /// Where the next line goes in `history`
+    history_next: usize,
+    /// How far back up/down has gone, 0 is the line being edited
+    browsing: usize,
+    /// The line being edited, kept while browsing the history
+    draft: Line,
+    complete: Completer,
+}
+
+impl Editor {
+    pub const fn new(complete: Completer) -> Editor {
+        Editor {
+            line: Line::EMPTY,
+            cursor: 0,
+            history: [Line::EMPTY; HISTORY_LEN],
+            history_count: 0,
+            history_next: 0,
+            browsing: 0,
+            draft: Line::EMPTY,
+            complete,
+        }
+    }
+
+    /// Write bytes from the line, only printable ASCII gets in so they're always valid
+    fn echo(bytes: &[u8]) {
+        print!("{}", core::str::from_utf8(bytes).unwrap());
+    }
+
+    fn move_left(&mut self, n: usize) {
+        for _ in 0..n {
+            print!("\x08");
+        }
+        self.cursor -= n;
+    }
+
+    fn move_right(&mut self, n: usize) {
+        Self::echo(&self.line.buf[self.cursor..self.cursor + n]);
+        self.cursor += n;
+    }
+
+    /// Redraw from the cursor to the end of the line, blanking `cleared` more cells for
+    /// characters that were there before, then go back to the cursor
+    fn redraw_tail(&mut self, cleared: usize) {
+        Self::echo(&self.line.buf[self.cursor..self.line.len]);
+        for _ in 0..cleared {
+            print!(" ");
+        }
+        for _ in 0..self.line.len - self.cursor + cleared {
+            print!("\x08");
+        }
+    }
+
+    fn insert(&mut self, byte: u8) {
+        if self.line.len == MAX_LINE {
+            return;
+        }
+        self.line
+            .buf
+            .copy_within(self.cursor..self.line.len, self.cursor + 1);
+        self.line.buf[self.cursor] = byte;
+        self.line.len += 1;
+
+        self.move_right(1);
+        self.redraw_tail(0);
+    }
+
+    /// Remove the character under the cursor
+    fn delete(&mut self) {
+        if self.cursor == self.line.len {
+            return;
+        }
+        self.line
+            .buf
+            .copy_within(self.cursor + 1..self.line.len, self.cursor);
+        self.line.len -= 1;
+        self.redraw_tail(1);
+    }
+
+    /// Replace the whole line with `line`, leaving the cursor at its end
+    fn replace(&mut self, line: Line) {
+        let old_len = self.line.len;
+        self.move_left(self.cursor);
+        self.line = line;
+        self.redraw_tail(old_len.saturating_sub(line.len));
+        self.move_right(line.len);
+    }
+
+    /// The history entry `back` lines ago, 1 is the newest
+    fn history_entry(&self, back: usize) -> Line {
+        self.history[(self.history_next + HISTORY_LEN - back) % HISTORY_LEN]
+    }
+
+    fn history_up(&mut self) {
+        if self.browsing == self.history_count {
+            return;
+        }
+        if self.browsing == 0 {
+            self.draft = self.line;
+        }
+        self.browsing += 1;
+        self.replace(self.history_entry(self.browsing));
+    }
+
+    fn history_down(&mut self) {
+        match self.browsing {
+            0 => {}
+            1 => {
+                self.browsing = 0;
+                self.replace(self.draft);
+            }
+            _ => {
+                self.browsing -= 1;
+                self.replace(self.history_entry(self.browsing));
+            }
+        }
+    }
+
+    fn remember(&mut self) {
+        let line = self.line.as_bytes();
+        let repeat = self.history_count > 0 && self.history_entry(1).as_bytes() == line;
+        if line.iter().all(u8::is_ascii_whitespace) || repeat {
+            return;
+        }
+
+        self.history[self.history_next] = self.line;
+        self.history_next = (self.history_next + 1) % HISTORY_LEN;
+        self.history_count = (self.history_count + 1).min(HISTORY_LEN);
+    }
+
+    /// Complete the word before the cursor, which has to be at the end of the line
+    fn tab(&mut self, prompt: &str) {
+        if self.cursor != self.line.len {
+            return;
+        }
+
+        let line = core::str::from_utf8(self.line.as_bytes()).unwrap();
+        let word = line.rsplit(' ').next().unwrap_or("");
+
+        // the longest prefix all of the candidates share
+        let mut common = Line::EMPTY;
+        let mut count = 0;
+        (self.complete)(line, word, &mut |candidate| {
+            let candidate = &candidate.as_bytes()[..candidate.len().min(MAX_LINE)];
+            if count == 0 {
+                common.buf[..candidate.len()].copy_from_slice(candidate);
+                common.len = candidate.len();
+            } else {
+                common.len = common
+                    .as_bytes()
+                    .iter()
+                    .zip(candidate)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+            }
+            count += 1;
+        });
+
+        let typed = word.len();
+        if count == 0 || common.len < typed {
+            return;
+        }
+
+        if count == 1 || common.len > typed {
+            for &byte in &common.as_bytes()[typed..] {
+                self.insert(byte);
+            }
+            // a directory is completed with its slash, and the name after it still to come
+            if count == 1 && !common.as_bytes().ends_with(b"/") {
+                self.insert(b' ');
+            }
+            return;
+        }
+
+        // nothing more in common, so show the choices and start the line over below them
+        println!();
+        (self.complete)(line, word, &mut |candidate| print!("{}  ", candidate));
+        println!();
+        print!("{}", prompt);
+        self.cursor = 0;
+        self.move_right(self.line.len);
+    }
+
+    /// Print `prompt` and read a line, None if it was cancelled with Ctrl+C
+    pub fn read_line(&mut self, prompt: &str) -> Option<&str> {
+        print!("{}", prompt);
+        self.line.len = 0;
+        self.cursor = 0;
+        self.browsing = 0;
+
+        loop {
+            let Some(key) = read_key() else {
+                continue;
+            };
+
+            match key {
+                Key::Char(byte) => self.insert(byte),
+                Key::Enter => break,
+                Key::Backspace if self.cursor > 0 => {
+                    self.move_left(1);
+                    self.delete();
+                }
+                Key::Delete => self.delete(),
+                Key::Left if self.cursor > 0 => self.move_left(1),
+                Key::Right if self.cursor < self.line.len => self.move_right(1),
+                Key::Home => self.move_left(self.cursor),
+                Key::End => self.move_right(self.line.len - self.cursor),
+                Key::Up => self.history_up(),
+                Key::Down => self.history_down(),
+                Key::Tab => self.tab(prompt),
+                Key::Cancel => {
+                    println!("^C");
+                    return None;
+                }
+                _ => {}
+            }
+        }
+
+        println!();
+        self.remember();
+        Some(core::str::from_utf8(self.line.as_bytes()).unwrap())
+    }
+}
+
+/// The editor for [`readline`]
+static EDITOR: Mutex<Editor> = Mutex::new("readline", Editor::new(|_, _, _| {}));
+
+/// Print `prompt` and read a line, None if it was cancelled with Ctrl+C
+pub fn readline(prompt: &str) -> Option<String> {
+    EDITOR.lock().read_line(prompt).map(String::from)
+}
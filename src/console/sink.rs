@@ -0,0 +1,254 @@
+//! Where printed text goes
+//!
+//! `print!` hands its text to every enabled [`LogSink`] in the registry. The console, the
+//! serial port, and the [`klog`](crate::klog) buffer are registered from the start, more can
+//! be added with [`register`], and any of them can be switched off and on by name with
+//! [`set_enabled`].
+//!
+//! `console=serial` on the command line sends printing only to the serial port, or any other
+//! comma separated list of sinks. The klog buffer is always kept. `novga` switches off the
+//! console sink when the console is the VGA text screen.
+//!
+//! `quiet`, or a [`splash`](crate::gfx::splash) screen, keeps the boot to the klog buffer:
+//! every other sink is held off until [`boot_done`] puts them back, just before the shell
+//! starts.
+//!
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::init::{InitCall, Stage};
+use crate::klog::KlogSink;
+use crate::serial::SERIAL1;
+use crate::sync::SpinLock;
+use crate::{cmdline, gfx, wlog};
+
+/// Sinks that can be registered at once
+const MAX_SINKS: usize = 8;
+
+/// Something that can take printed text
+pub trait LogSink: Sync {
+    /// Short name to refer to the sink by, e.g. from the shell
+    fn name(&self) -> &'static str;
+
+    fn write_str(&self, s: &str);
+}
+
+/// The active console, see [`with_console`](super::with_console)
+struct ConsoleSink;
+
+impl LogSink for ConsoleSink {
+    fn name(&self) -> &'static str {
+        "console"
+    }
+
+    fn write_str(&self, s: &str) {
+        super::with_console(|console| console.write_str(s).unwrap());
+    }
+}
+
+/// COM1
+struct SerialSink;
+
+impl LogSink for SerialSink {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn write_str(&self, s: &str) {
+        use core::fmt::Write;
+        SERIAL1.lock().write_str(s).unwrap();
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    sink: &'static dyn LogSink,
+    enabled: bool,
+    /// Off for a quiet boot, and back on once it's done
+    held: bool,
+}
+
+type Sinks = [Option<Entry>; MAX_SINKS];
+
+/// Only locked with interrupts off, through [`with_sinks`], since interrupt handlers print
+static SINKS: SpinLock<Sinks> = SpinLock::new("sinks", {
+    let mut sinks = [None; MAX_SINKS];
+    sinks[0] = Some(Entry {
+        sink: &ConsoleSink,
+        enabled: true,
+        held: false,
+    });
+    sinks[1] = Some(Entry {
+        sink: &SerialSink,
+        enabled: true,
+        held: false,
+    });
+    sinks[2] = Some(Entry {
+        sink: &KlogSink,
+        enabled: true,
+        held: false,
+    });
+    sinks
+});
+
+/// Set from the start of a quiet boot until [`boot_done`]
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+fn with_sinks<R>(f: impl FnOnce(&mut Sinks) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut SINKS.lock()))
+}
+
+/// Why a sink couldn't be registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// There's already a sink with that name
+    Exists,
+    /// All `MAX_SINKS` slots are taken
+    Full,
+}
+
+/// Start sending printed text to `sink` too, once the boot's done if it's a quiet one
+pub fn register(sink: &'static dyn LogSink) -> Result<(), RegisterError> {
+    with_sinks(|sinks| {
+        if sinks
+            .iter()
+            .flatten()
+            .any(|entry| entry.sink.name() == sink.name())
+        {
+            return Err(RegisterError::Exists);
+        }
+
+        let slot = sinks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegisterError::Full)?;
+        let held = QUIET.load(Ordering::Relaxed);
+        *slot = Some(Entry {
+            sink,
+            enabled: !held,
+            held,
+        });
+        Ok(())
+    })
+}
+
+/// Switch the sink called `name` on or off, false if there's no such sink
+pub fn set_enabled(name: &str, enabled: bool) -> bool {
+    with_sinks(|sinks| {
+        match sinks
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.sink.name() == name)
+        {
+            Some(entry) => {
+                entry.enabled = enabled;
+                entry.held = false;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Put back the sinks a quiet boot held off. Called before the shell starts, and on a panic
+/// so it isn't only in the klog buffer.
+pub fn boot_done() {
+    if !QUIET.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    with_sinks(|sinks| {
+        for entry in sinks.iter_mut().flatten() {
+            if entry.held {
+                entry.enabled = true;
+                entry.held = false;
+            }
+        }
+    });
+}
+
+/// Call `f` with the name of each registered sink and whether it's enabled
+pub fn for_each(mut f: impl FnMut(&'static str, bool)) {
+    let sinks = with_sinks(|sinks| *sinks);
+    for entry in sinks.iter().flatten() {
+        f(entry.sink.name(), entry.enabled);
+    }
+}
+
+/// Lets `write_fmt` format straight into a sink
+struct Adapter(&'static dyn LogSink);
+
+impl fmt::Write for Adapter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+/// Write to every enabled sink
+pub fn write_fmt(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    // a copy, so a sink can print (or panic) without the registry locked
+    let sinks = with_sinks(|sinks| *sinks);
+    for entry in sinks.iter().flatten().filter(|entry| entry.enabled) {
+        Adapter(entry.sink).write_fmt(args).unwrap();
+    }
+}
+
+/// Apply `console=`, `novga`, and `quiet` or `splash` from the command line
+fn init() {
+    if let Some(wanted) = cmdline::get("console") {
+        for name in wanted.split(',') {
+            let found = with_sinks(|sinks| {
+                sinks
+                    .iter()
+                    .flatten()
+                    .any(|entry| entry.sink.name() == name)
+            });
+            if !found {
+                wlog!("console: no sink called {}", name);
+            }
+        }
+        for_each(|name, _| {
+            set_enabled(name, name == "klog" || wanted.split(',').any(|w| w == name));
+        });
+    }
+
+    match cmdline::get_bool("novga") {
+        Ok(Some(true)) if gfx::console::CONSOLE.lock().is_none() => {
+            set_enabled("console", false);
+        }
+        Ok(_) => {}
+        Err(error) => wlog!("console: {}", error),
+    }
+
+    let quiet = match cmdline::get_bool("quiet") {
+        Ok(quiet) => quiet.unwrap_or(false),
+        Err(error) => {
+            wlog!("console: {}", error);
+            false
+        }
+    };
+    if quiet || cmdline::has("splash") {
+        QUIET.store(true, Ordering::Relaxed);
+        with_sinks(|sinks| {
+            for entry in sinks.iter_mut().flatten() {
+                if entry.enabled && entry.sink.name() != "klog" {
+                    entry.enabled = false;
+                    entry.held = true;
+                }
+            }
+        });
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "sinks",
+    stage: Stage::Early,
+    // warnings about bad options go out before they take effect
+    after: &["log"],
+    func: init,
+};
@@ -0,0 +1,384 @@
+//! Boxes and lines on the text console
+//!
+//! Everything here draws on a [`Console`] by moving the cursor with an ANSI cursor position
+//! sequence and writing box drawing characters, which [`cp437`](super::cp437) turns into the
+//! glyphs both consoles have. Rectangles are in character cells, and are clipped to the
+//! console. The cursor is left wherever the drawing ended, and nothing scrolls, since a
+//! console only wraps when the character after the last column is written.
+//!
+//! ```ignore
+//! console::with_console(|console| {
+//!     let split = Split::Columns(30);
+//!     let [left, right] = tui::draw_panes(console, screen, Style::Double, split, [None; 2]);
+//!     tui::write_at(console, left.x, left.y, "idle");
+//! });
+//! ```
+//!
+
+use core::fmt;
+
+use super::Console;
+use crate::gfx::Rect;
+
+/// Which characters a box is drawn with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    /// `┌─┐`
+    Single,
+    /// `╔═╗`
+    Double,
+    /// Solid blocks, `█`
+    Block,
+}
+
+/// The pieces of a box in one [`Style`]
+struct Glyphs {
+    horizontal: char,
+    vertical: char,
+    top_left: char,
+    top_right: char,
+    bottom_left: char,
+    bottom_right: char,
+    /// Where a vertical divider meets the top edge, and the bottom one
+    tee_down: char,
+    tee_up: char,
+    /// Where a horizontal divider meets the left edge, and the right one
+    tee_right: char,
+    tee_left: char,
+}
+
+impl Style {
+    fn glyphs(self) -> Glyphs {
+        match self {
+            Style::Single => Glyphs {
+                horizontal: '─',
+                vertical: '│',
+                top_left: '┌',
+                top_right: '┐',
+                bottom_left: '└',
+                bottom_right: '┘',
+                tee_down: '┬',
+                tee_up: '┴',
+                tee_right: '├',
+                tee_left: '┤',
+            },
+            Style::Double => Glyphs {
+                horizontal: '═',
+                vertical: '║',
+                top_left: '╔',
+                top_right: '╗',
+                bottom_left: '╚',
+                bottom_right: '╝',
+                tee_down: '╦',
+                tee_up: '╩',
+                tee_right: '╠',
+                tee_left: '╣',
+            },
+            Style::Block => Glyphs {
+                horizontal: '█',
+                vertical: '█',
+                top_left: '█',
+                top_right: '█',
+                bottom_left: '█',
+                bottom_right: '█',
+                tee_down: '█',
+                tee_up: '█',
+                tee_right: '█',
+                tee_left: '█',
+            },
+        }
+    }
+}
+
+/// Where a [`draw_panes`] box is divided
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Split {
+    /// Side by side, with the divider this many cells from the left edge
+    Columns(usize),
+    /// One above the other, with the divider this many cells from the top edge
+    Rows(usize),
+}
+
+/// `rect` clipped to the console, if any of it is on it
+fn clip(console: &dyn Console, rect: Rect) -> Option<Rect> {
+    let (cols, rows) = console.size();
+    rect.clip(cols, rows)
+}
+
+/// Move the cursor to column `x`, row `y`, counted from 0
+pub fn move_to(console: &mut dyn Console, x: usize, y: usize) {
+    let _ = write!(console, "\x1b[{};{}H", y + 1, x + 1);
+}
+
+/// Write `text` starting at column `x`, row `y`, cut off at the right edge of the console
+pub fn write_at(console: &mut dyn Console, x: usize, y: usize, text: &str) {
+    let (cols, rows) = console.size();
+    if x >= cols || y >= rows {
+        return;
+    }
+    move_to(console, x, y);
+    for c in text.chars().take(cols - x) {
+        let _ = console.write_char(c);
+    }
+}
+
+fn repeat(console: &mut dyn Console, c: char, count: usize) {
+    for _ in 0..count {
+        let _ = console.write_char(c);
+    }
+}
+
+/// A horizontal line `len` cells long, starting at (`x`, `y`) and going right
+pub fn hline(console: &mut dyn Console, x: usize, y: usize, len: usize, style: Style) {
+    let Some(line) = clip(console, Rect::new(x, y, len, 1)) else {
+        return;
+    };
+    move_to(console, line.x, line.y);
+    repeat(console, style.glyphs().horizontal, line.width);
+}
+
+/// A vertical line `len` cells long, starting at (`x`, `y`) and going down
+pub fn vline(console: &mut dyn Console, x: usize, y: usize, len: usize, style: Style) {
+    let Some(line) = clip(console, Rect::new(x, y, 1, len)) else {
+        return;
+    };
+    let vertical = style.glyphs().vertical;
+    for y in line.y..line.y + line.height {
+        move_to(console, line.x, y);
+        let _ = console.write_char(vertical);
+    }
+}
+
+/// Blank `rect` in the console's current colors
+pub fn fill(console: &mut dyn Console, rect: Rect) {
+    let Some(rect) = clip(console, rect) else {
+        return;
+    };
+    for y in rect.y..rect.y + rect.height {
+        move_to(console, rect.x, y);
+        repeat(console, ' ', rect.width);
+    }
+}
+
+/// The part of `rect` inside its border
+pub fn inner(rect: Rect) -> Rect {
+    Rect::new(
+        rect.x + 1,
+        rect.y + 1,
+        rect.width.saturating_sub(2),
+        rect.height.saturating_sub(2),
+    )
+}
+
+/// Write `title` into a top edge `width` cells wide starting at (`x`, `y`), with a space on
+/// either side and at least a corner and a line left past that
+fn draw_title(console: &mut dyn Console, x: usize, y: usize, width: usize, title: Option<&str>) {
+    let Some(title) = title.filter(|title| !title.is_empty()) else {
+        return;
+    };
+    let room = width.saturating_sub(6);
+    if room == 0 {
+        return;
+    }
+    move_to(console, x + 2, y);
+    let _ = console.write_char(' ');
+    for c in title.chars().take(room) {
+        let _ = console.write_char(c);
+    }
+    let _ = console.write_char(' ');
+}
+
+/// Draw a border around the edge of `rect`, with `title` in the top edge if there's room for
+/// it. What's inside isn't touched, [`fill`] the [`inner`] part first to blank it.
+pub fn draw_box(console: &mut dyn Console, rect: Rect, style: Style, title: Option<&str>) {
+    let Some(rect) = clip(console, rect) else {
+        return;
+    };
+    if rect.width < 2 || rect.height < 2 {
+        return;
+    }
+    let glyphs = style.glyphs();
+
+    move_to(console, rect.x, rect.y);
+    let _ = console.write_char(glyphs.top_left);
+    repeat(console, glyphs.horizontal, rect.width - 2);
+    let _ = console.write_char(glyphs.top_right);
+    vline(console, rect.x, rect.y + 1, rect.height - 2, style);
+    vline(
+        console,
+        rect.x + rect.width - 1,
+        rect.y + 1,
+        rect.height - 2,
+        style,
+    );
+    move_to(console, rect.x, rect.y + rect.height - 1);
+    let _ = console.write_char(glyphs.bottom_left);
+    repeat(console, glyphs.horizontal, rect.width - 2);
+    let _ = console.write_char(glyphs.bottom_right);
+
+    draw_title(console, rect.x, rect.y, rect.width, title);
+}
+
+/// Where the divider of `rect` split at `split` goes, kept off the edges
+fn divider(rect: Rect, split: Split) -> usize {
+    match split {
+        Split::Columns(at) => rect.x + at.clamp(1, rect.width.saturating_sub(2).max(1)),
+        Split::Rows(at) => rect.y + at.clamp(1, rect.height.saturating_sub(2).max(1)),
+    }
+}
+
+/// Where the two panes of `rect` split at `split` are, inside the border and divider
+pub fn panes(rect: Rect, split: Split) -> [Rect; 2] {
+    let inside = inner(rect);
+    let divider = divider(rect, split);
+    match split {
+        Split::Columns(_) => [
+            Rect::new(inside.x, inside.y, divider - inside.x, inside.height),
+            Rect::new(
+                divider + 1,
+                inside.y,
+                (inside.x + inside.width).saturating_sub(divider + 1),
+                inside.height,
+            ),
+        ],
+        Split::Rows(_) => [
+            Rect::new(inside.x, inside.y, inside.width, divider - inside.y),
+            Rect::new(
+                inside.x,
+                divider + 1,
+                inside.width,
+                (inside.y + inside.height).saturating_sub(divider + 1),
+            ),
+        ],
+    }
+}
+
+/// Draw a box around `rect` split in two at `split`, with a title on each pane. Returns where
+/// the panes are, like [`panes`].
+pub fn draw_panes(
+    console: &mut dyn Console,
+    rect: Rect,
+    style: Style,
+    split: Split,
+    titles: [Option<&str>; 2],
+) -> [Rect; 2] {
+    let Some(rect) = clip(console, rect) else {
+        return [Rect::new(0, 0, 0, 0); 2];
+    };
+    let panes = panes(rect, split);
+    if rect.width < 3 || rect.height < 3 {
+        draw_box(console, rect, style, titles[0]);
+        return panes;
+    }
+    let glyphs = style.glyphs();
+    let divider = divider(rect, split);
+
+    draw_box(console, rect, style, None);
+    match split {
+        Split::Columns(_) => {
+            move_to(console, divider, rect.y);
+            let _ = console.write_char(glyphs.tee_down);
+            vline(console, divider, rect.y + 1, rect.height - 2, style);
+            move_to(console, divider, rect.y + rect.height - 1);
+            let _ = console.write_char(glyphs.tee_up);
+            draw_title(console, rect.x, rect.y, divider - rect.x + 1, titles[0]);
+            draw_title(
+                console,
+                divider,
+                rect.y,
+                rect.x + rect.width - divider,
+                titles[1],
+            );
+        }
+        Split::Rows(_) => {
+            move_to(console, rect.x, divider);
+            let _ = console.write_char(glyphs.tee_right);
+            repeat(console, glyphs.horizontal, rect.width - 2);
+            let _ = console.write_char(glyphs.tee_left);
+            draw_title(console, rect.x, rect.y, rect.width, titles[0]);
+            draw_title(console, rect.x, divider, rect.width, titles[1]);
+        }
+    }
+    panes
+}
+
+/// Writes text into a rectangle, from its top left corner
+///
+/// Lines that are too long wrap, and whatever doesn't fit below the last row is dropped.
+/// Control characters other than newlines are left out, so nothing in the text can move the
+/// cursor out of the rectangle.
+pub struct TextArea<'a> {
+    console: &'a mut dyn Console,
+    rect: Rect,
+    col: usize,
+    row: usize,
+}
+
+impl<'a> TextArea<'a> {
+    pub fn new(console: &'a mut dyn Console, rect: Rect) -> TextArea<'a> {
+        let rect = clip(console, rect).unwrap_or(Rect::new(0, 0, 0, 0));
+        TextArea {
+            console,
+            rect,
+            col: 0,
+            row: 0,
+        }
+    }
+
+    /// The row the next character goes on, counted from the top of the rectangle
+    pub fn row(&self) -> usize {
+        self.row
+    }
+
+    /// Whether everything written from now on is dropped
+    pub fn is_full(&self) -> bool {
+        self.row >= self.rect.height
+    }
+}
+
+impl fmt::Write for TextArea<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if c == '\n' {
+                self.row += 1;
+                self.col = 0;
+                continue;
+            }
+            if c.is_control() {
+                continue;
+            }
+            if self.col == self.rect.width {
+                self.row += 1;
+                self.col = 0;
+            }
+            if self.is_full() {
+                break;
+            }
+            if self.col == 0 {
+                move_to(self.console, self.rect.x, self.rect.y + self.row);
+            }
+            self.console.write_char(c)?;
+            self.col += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn panes_split_the_inside() {
+        let rect = Rect::new(0, 0, 80, 25);
+        assert_eq!(inner(rect), Rect::new(1, 1, 78, 23));
+        assert_eq!(
+            panes(rect, Split::Columns(30)),
+            [Rect::new(1, 1, 29, 23), Rect::new(31, 1, 48, 23)]
+        );
+        assert_eq!(
+            panes(rect, Split::Rows(10)),
+            [Rect::new(1, 1, 78, 9), Rect::new(1, 11, 78, 13)]
+        );
+    }
+}
@@ -0,0 +1,377 @@
+//! CPU identification and feature detection
+//!
+//! CPUID is read once at boot by [`INIT`], which logs a summary line like
+//!
+//! ```text
+//! cpu: GenuineIntel family 0x6 model 0x55 stepping 4: fpu tsc msr pae apic ... nx lm
+//! ```
+//!
+//! From then on [`has`] says whether the CPU has a [`Feature`], so code that needs one can
+//! check for it rather than fault on a CPU without it. Before [`INIT`] runs nothing is detected
+//! and [`has`] is always false.
+//!
+//! links:
+//! - <https://wiki.osdev.org/CPUID>
+//! - Intel SDM vol. 2A, CPUID
+//!
+
+use core::arch::x86_64::{__cpuid, __cpuid_count, CpuidResult};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::ilog;
+use crate::init::{InitCall, Stage};
+use crate::sync::SpinLock;
+
+/// Something the CPU may or may not have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Feature {
+    Fpu,
+    Tsc,
+    Msr,
+    Pae,
+    Apic,
+    Pge,
+    Cmov,
+    Pat,
+    Clflush,
+    Mmx,
+    Fxsr,
+    Sse,
+    Sse2,
+    Htt,
+    Sse3,
+    Ssse3,
+    Cx16,
+    Sse4_1,
+    Sse4_2,
+    X2apic,
+    Popcnt,
+    TscDeadline,
+    Aes,
+    Xsave,
+    Avx,
+    Rdrand,
+    /// We're running in a virtual machine
+    Hypervisor,
+    Fsgsbase,
+    Avx2,
+    Smep,
+    Rdseed,
+    Smap,
+    Syscall,
+    /// No-execute pages
+    Nx,
+    /// 1 GiB pages
+    Page1Gb,
+    Rdtscp,
+    LongMode,
+    /// The TSC runs at the same rate in every power state
+    InvariantTsc,
+}
+
+/// The registers CPUID reports features in
+#[derive(Clone, Copy)]
+enum Reg {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 38] = [
+        Feature::Fpu,
+        Feature::Tsc,
+        Feature::Msr,
+        Feature::Pae,
+        Feature::Apic,
+        Feature::Pge,
+        Feature::Cmov,
+        Feature::Pat,
+        Feature::Clflush,
+        Feature::Mmx,
+        Feature::Fxsr,
+        Feature::Sse,
+        Feature::Sse2,
+        Feature::Htt,
+        Feature::Sse3,
+        Feature::Ssse3,
+        Feature::Cx16,
+        Feature::Sse4_1,
+        Feature::Sse4_2,
+        Feature::X2apic,
+        Feature::Popcnt,
+        Feature::TscDeadline,
+        Feature::Aes,
+        Feature::Xsave,
+        Feature::Avx,
+        Feature::Rdrand,
+        Feature::Hypervisor,
+        Feature::Fsgsbase,
+        Feature::Avx2,
+        Feature::Smep,
+        Feature::Rdseed,
+        Feature::Smap,
+        Feature::Syscall,
+        Feature::Nx,
+        Feature::Page1Gb,
+        Feature::Rdtscp,
+        Feature::LongMode,
+        Feature::InvariantTsc,
+    ];
+
+    /// The name Linux uses for it in /proc/cpuinfo
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Fpu => "fpu",
+            Feature::Tsc => "tsc",
+            Feature::Msr => "msr",
+            Feature::Pae => "pae",
+            Feature::Apic => "apic",
+            Feature::Pge => "pge",
+            Feature::Cmov => "cmov",
+            Feature::Pat => "pat",
+            Feature::Clflush => "clflush",
+            Feature::Mmx => "mmx",
+            Feature::Fxsr => "fxsr",
+            Feature::Sse => "sse",
+            Feature::Sse2 => "sse2",
+            Feature::Htt => "htt",
+            Feature::Sse3 => "sse3",
+            Feature::Ssse3 => "ssse3",
+            Feature::Cx16 => "cx16",
+            Feature::Sse4_1 => "sse4_1",
+            Feature::Sse4_2 => "sse4_2",
+            Feature::X2apic => "x2apic",
+            Feature::Popcnt => "popcnt",
+            Feature::TscDeadline => "tsc_deadline",
+            Feature::Aes => "aes",
+            Feature::Xsave => "xsave",
+            Feature::Avx => "avx",
+            Feature::Rdrand => "rdrand",
+            Feature::Hypervisor => "hypervisor",
+            Feature::Fsgsbase => "fsgsbase",
+            Feature::Avx2 => "avx2",
+            Feature::Smep => "smep",
+            Feature::Rdseed => "rdseed",
+            Feature::Smap => "smap",
+            Feature::Syscall => "syscall",
+            Feature::Nx => "nx",
+            Feature::Page1Gb => "pdpe1gb",
+            Feature::Rdtscp => "rdtscp",
+            Feature::LongMode => "lm",
+            Feature::InvariantTsc => "constant_tsc",
+        }
+    }
+
+    /// Where CPUID reports it: the leaf, the register, and the bit
+    fn location(self) -> (u32, Reg, u32) {
+        match self {
+            Feature::Fpu => (1, Reg::Edx, 0),
+            Feature::Tsc => (1, Reg::Edx, 4),
+            Feature::Msr => (1, Reg::Edx, 5),
+            Feature::Pae => (1, Reg::Edx, 6),
+            Feature::Apic => (1, Reg::Edx, 9),
+            Feature::Pge => (1, Reg::Edx, 13),
+            Feature::Cmov => (1, Reg::Edx, 15),
+            Feature::Pat => (1, Reg::Edx, 16),
+            Feature::Clflush => (1, Reg::Edx, 19),
+            Feature::Mmx => (1, Reg::Edx, 23),
+            Feature::Fxsr => (1, Reg::Edx, 24),
+            Feature::Sse => (1, Reg::Edx, 25),
+            Feature::Sse2 => (1, Reg::Edx, 26),
+            Feature::Htt => (1, Reg::Edx, 28),
+            Feature::Sse3 => (1, Reg::Ecx, 0),
+            Feature::Ssse3 => (1, Reg::Ecx, 9),
+            Feature::Cx16 => (1, Reg::Ecx, 13),
+            Feature::Sse4_1 => (1, Reg::Ecx, 19),
+            Feature::Sse4_2 => (1, Reg::Ecx, 20),
+            Feature::X2apic => (1, Reg::Ecx, 21),
+            Feature::Popcnt => (1, Reg::Ecx, 23),
+            Feature::TscDeadline => (1, Reg::Ecx, 24),
+            Feature::Aes => (1, Reg::Ecx, 25),
+            Feature::Xsave => (1, Reg::Ecx, 26),
+            Feature::Avx => (1, Reg::Ecx, 28),
+            Feature::Rdrand => (1, Reg::Ecx, 30),
+            Feature::Hypervisor => (1, Reg::Ecx, 31),
+            Feature::Fsgsbase => (7, Reg::Ebx, 0),
+            Feature::Avx2 => (7, Reg::Ebx, 5),
+            Feature::Smep => (7, Reg::Ebx, 7),
+            Feature::Rdseed => (7, Reg::Ebx, 18),
+            Feature::Smap => (7, Reg::Ebx, 20),
+            Feature::Syscall => (0x8000_0001, Reg::Edx, 11),
+            Feature::Nx => (0x8000_0001, Reg::Edx, 20),
+            Feature::Page1Gb => (0x8000_0001, Reg::Edx, 26),
+            Feature::Rdtscp => (0x8000_0001, Reg::Edx, 27),
+            Feature::LongMode => (0x8000_0001, Reg::Edx, 29),
+            Feature::InvariantTsc => (0x8000_0007, Reg::Edx, 8),
+        }
+    }
+}
+
+/// A set of features, one bit per [`Feature`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features(u64);
+
+impl Features {
+    pub fn contains(self, feature: Feature) -> bool {
+        self.0 & (1 << feature as u8) != 0
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Feature> {
+        Feature::ALL
+            .into_iter()
+            .filter(move |&feature| self.contains(feature))
+    }
+}
+
+/// The names of the features, separated by spaces
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, feature) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(feature.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// What CPUID says about the CPU
+#[derive(Debug, Clone, Copy)]
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    /// NUL padded, all zero if there's no brand string
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    /// The initial APIC ID of the CPU that ran [`INIT`], the boot CPU
+    pub apic_id: u32,
+    pub features: Features,
+}
+
+impl CpuInfo {
+    /// Like `GenuineIntel` or `AuthenticAMD`
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("?")
+    }
+
+    /// The marketing name, if the CPU has one
+    pub fn brand(&self) -> Option<&str> {
+        let end = self.brand.iter().position(|&b| b == 0).unwrap_or(48);
+        match core::str::from_utf8(&self.brand[..end]).map(str::trim) {
+            Ok("") | Err(_) => None,
+            Ok(brand) => Some(brand),
+        }
+    }
+}
+
+fn cpuid(leaf: u32) -> CpuidResult {
+    // leaf 7 has subleaves, the features are in 0 and the others ignore ecx
+    __cpuid_count(leaf, 0)
+}
+
+/// Whether `leaf` is one this CPU has
+fn has_leaf(leaf: u32) -> bool {
+    let range = leaf & 0x8000_0000;
+    __cpuid(range).eax >= leaf
+}
+
+fn detect() -> CpuInfo {
+    let mut vendor = [0; 12];
+    let leaf = cpuid(0);
+    for (i, reg) in [leaf.ebx, leaf.edx, leaf.ecx].into_iter().enumerate() {
+        vendor[i * 4..i * 4 + 4].copy_from_slice(&reg.to_le_bytes());
+    }
+
+    let mut brand = [0; 48];
+    if has_leaf(0x8000_0004) {
+        for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+            let leaf = cpuid(leaf);
+            for (j, reg) in [leaf.eax, leaf.ebx, leaf.ecx, leaf.edx]
+                .into_iter()
+                .enumerate()
+            {
+                let at = i * 16 + j * 4;
+                brand[at..at + 4].copy_from_slice(&reg.to_le_bytes());
+            }
+        }
+    }
+
+    let leaf = cpuid(1);
+    let mut family = (leaf.eax >> 8) & 0xf;
+    let mut model = (leaf.eax >> 4) & 0xf;
+    // the extended fields only count for some families
+    if family == 0xf {
+        family += (leaf.eax >> 20) & 0xff;
+    }
+    if family == 0x6 || family >= 0xf {
+        model += ((leaf.eax >> 16) & 0xf) << 4;
+    }
+
+    let mut features = 0;
+    for feature in Feature::ALL {
+        let (leaf, reg, bit) = feature.location();
+        if !has_leaf(leaf) {
+            continue;
+        }
+        let result = cpuid(leaf);
+        let value = match reg {
+            Reg::Ebx => result.ebx,
+            Reg::Ecx => result.ecx,
+            Reg::Edx => result.edx,
+        };
+        if value & (1 << bit) != 0 {
+            features |= 1 << feature as u8;
+        }
+    }
+
+    CpuInfo {
+        vendor,
+        brand,
+        family,
+        model,
+        stepping: leaf.eax & 0xf,
+        apic_id: leaf.ebx >> 24,
+        features: Features(features),
+    }
+}
+
+/// The detected features, kept apart from [`INFO`] so [`has`] doesn't take a lock
+static FEATURES: AtomicU64 = AtomicU64::new(0);
+
+static INFO: SpinLock<Option<CpuInfo>> = SpinLock::new("cpu info", None);
+
+/// Whether the CPU has `feature`, always false before [`INIT`]
+pub fn has(feature: Feature) -> bool {
+    Features(FEATURES.load(Ordering::Relaxed)).contains(feature)
+}
+
+/// Everything that was detected, None before [`INIT`]
+pub fn info() -> Option<CpuInfo> {
+    *INFO.lock()
+}
+
+fn init() {
+    let info = detect();
+    ilog!(
+        "cpu: {} family {:#x} model {:#x} stepping {}: {}",
+        info.vendor(),
+        info.family,
+        info.model,
+        info.stepping,
+        info.features
+    );
+    FEATURES.store(info.features.0, Ordering::Relaxed);
+    *INFO.lock() = Some(info);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "cpu",
+    stage: Stage::Early,
+    after: &["log"],
+    func: init,
+};
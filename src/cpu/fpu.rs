@@ -0,0 +1,217 @@
+//! Floating point and SIMD registers
+//!
+//! The kernel is built soft-float, so it never touches the x87, SSE, or AVX registers itself,
+//! and the interrupt handlers leave them as they are. User programs can use them, which
+//! [`INIT`] allows: CR0.EM is cleared so the instructions don't fault, CR0.MP and CR0.NE are
+//! set, CR4.OSFXSR and CR4.OSXMMEXCPT say the kernel saves SSE state and handles its
+//! exceptions, and with XSAVE, CR4.OSXSAVE is set and XCR0 covers AVX too when the CPU has it.
+//!
+//! Every user thread has an [`FpuState`] of its own. The [`sched`](crate::sched) saves the
+//! registers into it when switching away from the thread and restores them when switching
+//! back, on every switch rather than lazily on the first use after it. Kernel threads don't
+//! have one and run with whatever was left in the registers. State is saved with XSAVE when
+//! the CPU has it, into an area as big as CPUID says the enabled components need, and with
+//! FXSAVE into 512 bytes otherwise.
+//!
+//! The application processors get the CR4 bits from the [`smp`](crate::smp) trampoline, but
+//! not the rest. They don't run threads.
+//!
+//! links:
+//! - <https://wiki.osdev.org/SSE>
+//! - Intel SDM vol. 1, 13 "Managing State Using the XSAVE Feature Set"
+//!
+
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use core::arch::asm;
+use core::arch::x86_64::__cpuid_count;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+use super::{has, Feature};
+use crate::ilog;
+use crate::init::{InitCall, Stage};
+
+/// What FXSAVE writes, the legacy area at the start of an XSAVE area too
+const FXSAVE_SIZE: usize = 512;
+/// XSAVE needs 64, FXSAVE 16
+const ALIGN: usize = 64;
+
+/// The x87 control word and MXCSR a program starts with: every exception masked
+const INITIAL_FCW: u16 = 0x037f;
+const INITIAL_MXCSR: u32 = 0x1f80;
+const MXCSR_OFFSET: usize = 24;
+
+/// Set when state is saved with XSAVE
+static XSAVE: AtomicBool = AtomicBool::new(false);
+/// Bytes a saved state takes
+static SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+
+/// The floating point and SIMD registers of a thread that isn't running
+pub struct FpuState {
+    area: NonNull<u8>,
+}
+
+// only the thread it belongs to, or the scheduler on its behalf, uses it
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    fn layout() -> Layout {
+        Layout::from_size_align(SIZE.load(Ordering::Relaxed), ALIGN).unwrap()
+    }
+
+    fn zeroed() -> FpuState {
+        let layout = FpuState::layout();
+        let area = unsafe { alloc_zeroed(layout) };
+        FpuState {
+            area: NonNull::new(area).unwrap_or_else(|| handle_alloc_error(layout)),
+        }
+    }
+
+    /// What a new program starts with: everything zero and every exception masked
+    pub fn new() -> FpuState {
+        let state = FpuState::zeroed();
+        // with XSAVE, the header being zero means every component is in its initial state,
+        // which is the same apart from MXCSR
+        unsafe {
+            state.area.cast::<u16>().write(INITIAL_FCW);
+            state
+                .area
+                .add(MXCSR_OFFSET)
+                .cast::<u32>()
+                .write(INITIAL_MXCSR);
+        }
+        state
+    }
+
+    /// The registers as they are now, for a thread that starts where this one is
+    pub fn current() -> FpuState {
+        let mut state = FpuState::zeroed();
+        state.save();
+        state
+    }
+
+    /// Save the registers here
+    pub fn save(&mut self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if XSAVE.load(Ordering::Relaxed) {
+                // every component XCR0 has on
+                asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX,
+                    options(nostack, preserves_flags));
+            } else {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Load the registers from here
+    pub fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if XSAVE.load(Ordering::Relaxed) {
+                asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX,
+                    options(nostack, preserves_flags));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// The saved state, as the CPU laid it out
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.area.as_ptr(), FpuState::layout().size()) }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> FpuState {
+        FpuState::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area.as_ptr(), FpuState::layout()) };
+    }
+}
+
+fn init() {
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+
+    let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+    if has(Feature::Xsave) {
+        if has(Feature::Avx) {
+            components |= XCr0Flags::AVX;
+        }
+        unsafe {
+            Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+            XCr0::write(components);
+        }
+        // the size for what XCR0 has on
+        let size = __cpuid_count(0xd, 0).ebx as usize;
+        SIZE.store(size.max(FXSAVE_SIZE), Ordering::Relaxed);
+        XSAVE.store(true, Ordering::Relaxed);
+    }
+    unsafe { asm!("fninit", options(nomem, nostack)) };
+
+    let xsave = XSAVE.load(Ordering::Relaxed);
+    ilog!(
+        "cpu: fpu state saved with {}, {} bytes{}",
+        if xsave { "xsave" } else { "fxsave" },
+        SIZE.load(Ordering::Relaxed),
+        if components.contains(XCr0Flags::AVX) {
+            " with avx"
+        } else {
+            ""
+        }
+    );
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "fpu",
+    stage: Stage::Early,
+    after: &["cpu"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::instructions::interrupts;
+
+    /// Offset of XMM0 in the legacy area
+    const XMM0_OFFSET: usize = 160;
+
+    fn xmm0() -> u64 {
+        let value: u64;
+        unsafe { asm!("movq {}, xmm0", out(reg) value, options(nomem, nostack)) };
+        value
+    }
+
+    #[test_case]
+    fn saves_and_restores() {
+        // nothing else may load its registers in between
+        interrupts::without_interrupts(|| {
+            let value = 0x1234_5678_9abc_def0u64;
+            unsafe { asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack)) };
+            let saved = FpuState::current();
+            let bytes = &saved.as_bytes()[XMM0_OFFSET..XMM0_OFFSET + 8];
+            assert_eq!(bytes, &value.to_le_bytes());
+
+            let fresh = FpuState::new();
+            fresh.restore();
+            assert_eq!(xmm0(), 0);
+            saved.restore();
+            assert_eq!(xmm0(), value);
+        });
+    }
+}
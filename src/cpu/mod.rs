@@ -0,0 +1,8 @@
+//! The processor we're running on
+//!
+
+pub mod features;
+pub mod fpu;
+pub mod protection;
+
+pub use features::{has, Feature};
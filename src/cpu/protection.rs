@@ -0,0 +1,76 @@
+//! Memory protection features
+//!
+//! [`INIT`] turns on whichever of these the CPU has, and logs which ones it got:
+//!
+//! - NX (EFER.NXE): pages marked no-execute can't run code, see the flags
+//!   [`paging`](crate::mem::paging) gives the kernel image
+//! - WP (CR0.WP): read-only pages are read-only for the kernel too
+//! - SMEP (CR4.SMEP): the kernel can't run code in user pages
+//! - SMAP (CR4.SMAP): the kernel can't touch user pages by accident, only inside
+//!   [`with_user_access`]
+//!
+//! The application processors get the same settings from the [`smp`](crate::smp) trampoline,
+//! which copies the boot CPU's CR4 and sets NXE and WP itself.
+//!
+//! links:
+//! - Intel SDM vol. 3A, 4.6 "Access Rights"
+//!
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::EferFlags;
+
+use super::{has, Feature};
+use crate::arch::msr;
+use crate::ilog;
+use crate::init::{InitCall, Stage};
+
+/// Set once SMAP is on, `stac` and `clac` don't exist without it
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// Run `f` with user pages reachable from the kernel, for copying to or from user memory
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = SMAP.load(Ordering::Relaxed);
+    if smap {
+        unsafe { asm!("stac", options(nomem, nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nomem, nostack)) };
+    }
+    result
+}
+
+fn init() {
+    let nx = has(Feature::Nx);
+    if nx {
+        unsafe { msr::update_efer(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    }
+    unsafe { Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT)) };
+    let smep = has(Feature::Smep);
+    if smep {
+        unsafe { Cr4::update(|cr4| cr4.insert(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION)) };
+    }
+    let smap = has(Feature::Smap);
+    if smap {
+        unsafe { Cr4::update(|cr4| cr4.insert(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION)) };
+        SMAP.store(true, Ordering::Relaxed);
+    }
+
+    let on = |enabled, name| if enabled { name } else { "" };
+    ilog!(
+        "cpu: protection on:{} wp{}{}",
+        on(nx, " nx"),
+        on(smep, " smep"),
+        on(smap, " smap")
+    );
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "protection",
+    stage: Stage::Early,
+    after: &["cpu"],
+    func: init,
+};
@@ -0,0 +1,108 @@
+//! Kernel assertions with more to say than `assert!`
+//!
+//! [`kassert!`](crate::kassert) and [`kassert_eq!`](crate::kassert_eq) work like their `core`
+//! counterparts, but a failure prints the expression, the values involved, where it happened,
+//! the current task, and a backtrace from the failing call before panicking.
+//! [`debug_kassert!`](crate::debug_kassert) and
+//! [`debug_kassert_eq!`](crate::debug_kassert_eq) are only checked in debug builds.
+//!
+
+use core::fmt;
+use core::panic::Location;
+
+use super::backtrace;
+use crate::println;
+
+/// Report a failed assertion and panic
+///
+/// Used by the `kassert` macros, `values` is the left and right side for the `_eq` variants.
+#[doc(hidden)]
+#[cold]
+#[track_caller]
+pub fn failed(
+    expr: &str,
+    values: Option<(&dyn fmt::Debug, &dyn fmt::Debug)>,
+    message: Option<fmt::Arguments>,
+) -> ! {
+    let location = Location::caller();
+
+    println!("assertion failed: {}", expr);
+    if let Some((left, right)) = values {
+        println!("  left:  {:?}", left);
+        println!("  right: {:?}", right);
+    }
+    if let Some(message) = message {
+        println!("  {}", message);
+    }
+    println!("  at {}", location);
+    println!("  task: none, there's no scheduler yet");
+    backtrace::print();
+
+    panic!("kassert failed: {}", expr);
+}
+
+/// Assert that a condition holds, reporting the details and a backtrace if it doesn't
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::debug::assert::failed(stringify!($cond), None, None);
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::debug::assert::failed(stringify!($cond), None, Some(format_args!($($arg)+)));
+        }
+    };
+}
+
+/// Assert that two values are equal, reporting both and a backtrace if they aren't
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    $crate::debug::assert::failed(
+                        concat!(stringify!($left), " == ", stringify!($right)),
+                        Some((&*left, &*right)),
+                        None,
+                    );
+                }
+            }
+        }
+    };
+    ($left:expr, $right:expr, $($arg:tt)+) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    $crate::debug::assert::failed(
+                        concat!(stringify!($left), " == ", stringify!($right)),
+                        Some((&*left, &*right)),
+                        Some(format_args!($($arg)+)),
+                    );
+                }
+            }
+        }
+    };
+}
+
+/// [`kassert!`](crate::kassert), only checked in debug builds
+#[macro_export]
+macro_rules! debug_kassert {
+    ($($arg:tt)*) => {
+        if cfg!(debug_assertions) {
+            $crate::kassert!($($arg)*);
+        }
+    };
+}
+
+/// [`kassert_eq!`](crate::kassert_eq), only checked in debug builds
+#[macro_export]
+macro_rules! debug_kassert_eq {
+    ($($arg:tt)*) => {
+        if cfg!(debug_assertions) {
+            $crate::kassert_eq!($($arg)*);
+        }
+    };
+}
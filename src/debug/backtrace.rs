@@ -0,0 +1,104 @@
+//! Stack backtraces
+//!
+//! Everything is built with frame pointers (see `frame-pointer` in the target spec), so each
+//! function's prologue pushes the caller's RBP and points RBP at it. That makes the stack a
+//! linked list: `[rbp]` is the previous frame and `[rbp + 8]` is the return address into the
+//! caller.
+//!
+//! The walk stops at the first return address outside of the kernel's `.text`. That's the
+//! call from the bootloader into the kernel, or a corrupted frame, and in both cases the
+//! saved RBP next to it can't be trusted to point at a mapped stack.
+//!
+//! links:
+//! - <https://eli.thegreenplace.net/2011/09/06/stack-frame-layout-on-x86-64>
+//!
+
+use core::arch::asm;
+
+use super::symbols;
+use crate::println;
+
+/// Frames printed at most, in case the chain loops
+pub const MAX_FRAMES: usize = 64;
+
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+}
+
+/// Where the kernel is linked, offsets in backtraces are from here. Matches `linker.ld`.
+pub const KERNEL_BASE: u64 = 0xffff_ffff_8000_0000;
+
+/// Whether `addr` is in the kernel's code
+pub fn is_kernel_text(addr: u64) -> bool {
+    let (start, end) = unsafe {
+        (
+            &__text_start as *const u8 as u64,
+            &__text_end as *const u8 as u64,
+        )
+    };
+    (start..end).contains(&addr)
+}
+
+/// The current frame pointer
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// Call `f` with each return address on the stack, starting from the frame at `rbp`
+///
+/// # Safety
+///
+/// `rbp` has to point at a valid frame, e.g. from [`frame_pointer`] or a saved register.
+pub unsafe fn walk_from(mut rbp: u64, mut f: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || !rbp.is_multiple_of(8) {
+            break;
+        }
+
+        let frame = rbp as *const u64;
+        let return_addr = frame.add(1).read();
+        if !is_kernel_text(return_addr) {
+            break;
+        }
+        f(return_addr);
+
+        // stacks grow down, so the caller's frame has to be further up
+        let next = frame.read();
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// Call `f` with each return address on the current stack, innermost first
+pub fn walk(f: impl FnMut(u64)) {
+    unsafe { walk_from(frame_pointer(), f) }
+}
+
+/// Print a backtrace of the current stack
+pub fn print() {
+    println!("backtrace:");
+    let mut depth = 0;
+    walk(|addr| {
+        match symbols::lookup(addr) {
+            Some(symbol) => println!(
+                "  #{:<2} {:#018x} {}+{:#x}",
+                depth, addr, symbol.name, symbol.offset
+            ),
+            None => println!(
+                "  #{:<2} {:#018x} (kernel+{:#x})",
+                depth,
+                addr,
+                addr - KERNEL_BASE
+            ),
+        }
+        depth += 1;
+    });
+}
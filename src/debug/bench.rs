@@ -0,0 +1,206 @@
+//! Microbenchmarks
+//!
+//! A [`Bench`] is a `static` marked `#[test_case]`, so benchmarks run under the same test
+//! framework as tests (see `tests/bench.rs`). Each one is run a few times to warm up, then
+//! timed with the TSC for [`SAMPLES`] runs. The results are printed on the serial port as one
+//! line per benchmark, for scripts to pick out of the test output:
+//!
+//! ```text
+//! bench name=console_scroll samples=64 min=81234 median=83002 avg=83410 max=91022 ns=27667
+//! ```
+//!
+//! `min`, `median`, `avg`, and `max` are in cycles, `ns` is the median converted with the TSC
+//! frequency [`time`](crate::time) measured.
+//!
+//! [`KERNEL`] has benchmarks of the kernel's hot paths that make sense on a running system:
+//! console output, frame and heap allocation, and switching threads. The shell's `bench`
+//! command runs them with [`run_kernel`], which prints a table in nanoseconds instead.
+//!
+
+use alloc::vec::Vec;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::console;
+use crate::mem::frame;
+use crate::time::cycles_to_ns;
+use crate::{println, sched, serial_println, Testable};
+
+/// Timed runs per benchmark
+pub const SAMPLES: usize = 64;
+/// Untimed runs first, to fill caches and take any first-use setup out of the numbers
+const WARMUP: usize = 4;
+
+/// A benchmark, `run` is timed as a whole
+pub struct Bench {
+    pub name: &'static str,
+    pub run: fn(),
+    /// Called before the warmup and after the last sample, untimed
+    pub setup: fn(),
+    pub teardown: fn(),
+}
+
+impl Bench {
+    pub const fn new(name: &'static str, run: fn()) -> Bench {
+        Bench {
+            name,
+            run,
+            setup: || {},
+            teardown: || {},
+        }
+    }
+
+    /// The same benchmark with `setup` and `teardown` around it
+    pub const fn around(self, setup: fn(), teardown: fn()) -> Bench {
+        Bench {
+            setup,
+            teardown,
+            ..self
+        }
+    }
+
+    /// Warm up, time [`SAMPLES`] runs, and summarize them
+    pub fn measure(&self) -> Stats {
+        (self.setup)();
+        for _ in 0..WARMUP {
+            (self.run)();
+        }
+
+        let mut samples = [0; SAMPLES];
+        for sample in samples.iter_mut() {
+            let start = unsafe { _rdtsc() };
+            (self.run)();
+            *sample = unsafe { _rdtsc() } - start;
+        }
+        (self.teardown)();
+        Stats::from_samples(&mut samples)
+    }
+}
+
+/// A summary of timed runs, in TSC cycles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub min: u64,
+    pub median: u64,
+    pub avg: u64,
+    pub max: u64,
+}
+
+impl Stats {
+    /// Summarize `samples`, which get sorted
+    fn from_samples(samples: &mut [u64]) -> Stats {
+        samples.sort_unstable();
+        Stats {
+            min: samples[0],
+            median: samples[samples.len() / 2],
+            avg: samples.iter().sum::<u64>() / samples.len() as u64,
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+impl Testable for Bench {
+    fn run(&self) {
+        let stats = self.measure();
+        serial_println!(
+            "bench name={} samples={} min={} median={} avg={} max={} ns={}",
+            self.name,
+            SAMPLES,
+            stats.min,
+            stats.median,
+            stats.avg,
+            stats.max,
+            cycles_to_ns(stats.median)
+        );
+    }
+}
+
+/// A line of text to write, 64 characters with the newline
+const LINE: &str = "zenix bench: the quick brown fox jumps over the lazy dog 012345\n";
+
+/// Set while [`partner`] should keep yielding back
+static PARTNER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// A thread that hands the CPU straight back, so a yield is a switch there and back
+fn partner() {
+    while PARTNER_RUNNING.load(Ordering::Relaxed) {
+        sched::yield_now();
+    }
+}
+
+fn start_partner() {
+    PARTNER_RUNNING.store(true, Ordering::Relaxed);
+    sched::spawn("bench partner", partner);
+}
+
+fn stop_partner() {
+    PARTNER_RUNNING.store(false, Ordering::Relaxed);
+    // once more, so it sees it and exits
+    sched::yield_now();
+}
+
+/// Benchmarks of the kernel's hot paths, for [`run_kernel`]
+pub static KERNEL: &[Bench] = &[
+    // the active console only, it's what's slow
+    Bench::new("console_write", || {
+        console::with_console(|console| {
+            let _ = console.write_str(LINE);
+        })
+    }),
+    Bench::new("frame_alloc", || {
+        if let Some(frame) = frame::allocate_frame() {
+            unsafe { frame::deallocate_frame(frame) };
+        }
+    }),
+    Bench::new("heap_alloc_64", || {
+        drop(core::hint::black_box(Vec::<u8>::with_capacity(64)));
+    }),
+    Bench::new("heap_alloc_4k", || {
+        drop(core::hint::black_box(Vec::<u8>::with_capacity(4096)));
+    }),
+    // two switches, to the partner thread and back
+    Bench::new("context_switch", sched::yield_now).around(start_partner, stop_partner),
+];
+
+/// Run the [`KERNEL`] benchmarks whose names contain `filter`, or all of them, and print how
+/// long they took
+pub fn run_kernel(filter: Option<&str>) {
+    println!(
+        "{:<16} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "name", "samples", "min ns", "avg ns", "max ns", "median ns"
+    );
+    for bench in KERNEL {
+        if filter.is_some_and(|filter| !bench.name.contains(filter)) {
+            continue;
+        }
+        let stats = bench.measure();
+        println!(
+            "{:<16} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            bench.name,
+            SAMPLES,
+            cycles_to_ns(stats.min),
+            cycles_to_ns(stats.avg),
+            cycles_to_ns(stats.max),
+            cycles_to_ns(stats.median)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn summarizes_samples() {
+        let mut samples = [5, 1, 9, 3, 7];
+        assert_eq!(
+            Stats::from_samples(&mut samples),
+            Stats {
+                min: 1,
+                median: 5,
+                avg: 5,
+                max: 9,
+            }
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! Fault injection
+//!
+//! Error paths that only run when hardware misbehaves or memory runs out rarely run at all.
+//! Each [`FaultPoint`] is a place in the kernel that can be made to fail on purpose, every Nth
+//! time it's passed, so those paths get exercised. A wait passes its point every time it
+//! checks the time.
+//!
+//! | point         | where                                  | what happens                     |
+//! |---------------|----------------------------------------|----------------------------------|
+//! | `alloc`       | the [heap](crate::mem::heap)           | the allocation fails             |
+//! | `block`       | [`block::check`](crate::block::check)  | the transfer fails with `Io`     |
+//! | `block-delay` | the same                               | it waits [`DELAY_MS`] first      |
+//! | `net`         | every frame sent and received          | it's dropped                     |
+//! | `net-delay`   | every frame received                   | it waits [`DELAY_MS`] first      |
+//! | `timeout`     | the [`ata`](crate::drivers::ata) waits | it times out early               |
+//!
+//! They're all off until `fault=POINT:N,...` on the command line, or the shell's `fault`
+//! command, sets how often they fire. Most allocations can't fail without a panic, so `alloc`
+//! mostly shows which ones can.
+//!
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::init::{InitCall, Stage};
+use crate::time::{Duration, Instant};
+use crate::{cmdline, wlog};
+
+/// How long the delay points wait
+pub const DELAY_MS: u64 = 50;
+
+/// A place that can be made to fail
+pub struct FaultPoint {
+    pub name: &'static str,
+    /// Fires every this many passes, 0 for never
+    every: AtomicU64,
+    passes: AtomicU64,
+    fired: AtomicU64,
+}
+
+impl FaultPoint {
+    const fn new(name: &'static str) -> FaultPoint {
+        FaultPoint {
+            name,
+            every: AtomicU64::new(0),
+            passes: AtomicU64::new(0),
+            fired: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a pass, true if this is one that should fail
+    pub fn fire(&self) -> bool {
+        let every = self.every.load(Ordering::Relaxed);
+        if every == 0 {
+            return false;
+        }
+        let passes = self.passes.fetch_add(1, Ordering::Relaxed) + 1;
+        if !passes.is_multiple_of(every) {
+            return false;
+        }
+        self.fired.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Make it fire every `every` passes from now, 0 to turn it off
+    pub fn set_every(&self, every: u64) {
+        self.passes.store(0, Ordering::Relaxed);
+        self.every.store(every, Ordering::Relaxed);
+    }
+
+    pub fn every(&self) -> u64 {
+        self.every.load(Ordering::Relaxed)
+    }
+
+    /// Times it's fired since boot
+    pub fn fired(&self) -> u64 {
+        self.fired.load(Ordering::Relaxed)
+    }
+}
+
+pub static ALLOC: FaultPoint = FaultPoint::new("alloc");
+pub static BLOCK: FaultPoint = FaultPoint::new("block");
+pub static BLOCK_DELAY: FaultPoint = FaultPoint::new("block-delay");
+pub static NET: FaultPoint = FaultPoint::new("net");
+pub static NET_DELAY: FaultPoint = FaultPoint::new("net-delay");
+pub static TIMEOUT: FaultPoint = FaultPoint::new("timeout");
+
+pub static POINTS: &[&FaultPoint] = &[&ALLOC, &BLOCK, &BLOCK_DELAY, &NET, &NET_DELAY, &TIMEOUT];
+
+/// The point called `name`
+pub fn get(name: &str) -> Option<&'static FaultPoint> {
+    POINTS.iter().copied().find(|point| point.name == name)
+}
+
+/// Wait [`DELAY_MS`] if `point` fires. It spins, so it works with interrupts off.
+pub fn delay(point: &FaultPoint) {
+    if point.fire() {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(DELAY_MS) {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Set the points in `spec`, `POINT:N` separated by commas
+pub fn configure(spec: &str) -> Result<(), &str> {
+    for part in spec.split(',') {
+        let (name, every) = part.split_once(':').ok_or(part)?;
+        let point = get(name).ok_or(part)?;
+        point.set_every(every.parse().map_err(|_| part)?);
+    }
+    Ok(())
+}
+
+fn init() {
+    if let Some(spec) = cmdline::get("fault") {
+        if let Err(part) = configure(spec) {
+            wlog!("fault: can't make sense of {} in fault=", part);
+        }
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "fault",
+    stage: Stage::Early,
+    after: &["config"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn fires_every_nth_pass() {
+        let point = FaultPoint::new("test");
+        assert!(!point.fire());
+        point.set_every(3);
+        let fired = (0..9).filter(|_| point.fire()).count();
+        assert_eq!((fired, point.fired()), (3, 3));
+        assert!(configure("net:x").is_err());
+        assert!(configure("nothing:1").is_err());
+    }
+}
@@ -0,0 +1,532 @@
+//! GDB remote stub
+//!
+//! With `gdb` on the command line, the breakpoint and debug exceptions stop in this stub
+//! instead, which talks the GDB remote serial protocol on COM2. [`INIT`] stops once at boot so
+//! there's a chance to attach and set breakpoints. Under QEMU, with
+//! `-serial stdio -serial tcp::1234,server,nowait`, that's `target remote :1234` in gdb.
+//!
+//! The stub answers `?`, `g`, `G`, `m`, `M`, `c`, `s`, `D`, and `k`, plus the few queries gdb
+//! needs to get going, and gives the empty reply that means "unsupported" to everything else.
+//! Breakpoints are set by gdb writing `int3` over the instruction with `M`, which works on the
+//! kernel's read-only text because writes are done with CR0.WP off. Stepping sets the trap
+//! flag, which gets a debug exception after one instruction.
+//!
+//! A panic stops in the stub too, as SIGABRT, with the registers the panic handler captured.
+//! Only the stack registers are real, and continuing tells gdb the kernel's gone.
+//!
+//! The port is polled with interrupts off the whole time, and nothing is locked, so gdb can't
+//! interrupt a running kernel. Stop it with a breakpoint instead.
+//!
+//! links:
+//! - protocol: <https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html>
+//! - register order: gdb's `features/i386/64bit-core.xml`
+//!
+
+use core::arch::global_asm;
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::{interrupts, segmentation::Segment as _};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::segmentation::CS;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::VirtAddr;
+
+use super::regs::Registers;
+use crate::cpu::protection;
+use crate::init::{InitCall, Stage};
+use crate::serial::SerialPort;
+use crate::{cmdline, ilog, mem, watchdog};
+
+const COM2: u16 = 0x2f8;
+
+/// Longest packet taken or sent, data and all, which `qSupported` says in hex
+const MAX_PACKET: usize = 0x1000;
+const PAGE_SIZE: u64 = 4096;
+
+const DEBUG_VECTOR: u64 = 1;
+const TRAP_FLAG: u64 = 1 << 8;
+
+// signals for stop replies
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+
+/// Set once COM2 is ready, by [`INIT`]
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// What a trap saved, as the entry stub pushed it
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// the stub reads cs at 16 above the vector
+const _: () = assert!(offset_of!(TrapFrame, cs) - offset_of!(TrapFrame, vector) == 16);
+const _: () = assert!(size_of::<TrapFrame>() == 21 * 8);
+
+global_asm!(
+    ".global gdb_debug_entry",
+    "gdb_debug_entry:",
+    "    pushq $1",
+    "    jmp gdb_trap_common",
+    ".global gdb_breakpoint_entry",
+    "gdb_breakpoint_entry:",
+    "    pushq $3",
+    "    jmp gdb_trap_common",
+    "gdb_trap_common:",
+    // from user mode, GS has to be the kernel's again
+    "    testb $3, 16(%rsp)",
+    "    jz 1f",
+    "    swapgs",
+    "1:",
+    "    pushq %rax",
+    "    pushq %rbx",
+    "    pushq %rcx",
+    "    pushq %rdx",
+    "    pushq %rsi",
+    "    pushq %rdi",
+    "    pushq %rbp",
+    "    pushq %r8",
+    "    pushq %r9",
+    "    pushq %r10",
+    "    pushq %r11",
+    "    pushq %r12",
+    "    pushq %r13",
+    "    pushq %r14",
+    "    pushq %r15",
+    "    cld",
+    "    movq %rsp, %rdi",
+    // 21 quadwords on a stack that was 16-byte aligned before the CPU pushed its 5
+    "    subq $8, %rsp",
+    "    callq {trap}",
+    "    addq $8, %rsp",
+    "    popq %r15",
+    "    popq %r14",
+    "    popq %r13",
+    "    popq %r12",
+    "    popq %r11",
+    "    popq %r10",
+    "    popq %r9",
+    "    popq %r8",
+    "    popq %rbp",
+    "    popq %rdi",
+    "    popq %rsi",
+    "    popq %rdx",
+    "    popq %rcx",
+    "    popq %rbx",
+    "    popq %rax",
+    "    testb $3, 16(%rsp)",
+    "    jz 2f",
+    "    swapgs",
+    "2:",
+    "    addq $8, %rsp",
+    "    iretq",
+    trap = sym trap,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn gdb_debug_entry();
+    fn gdb_breakpoint_entry();
+}
+
+/// Point the debug and breakpoint vectors in `idt` at the stub, if `gdb` is on the command line
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    if !cmdline::has("gdb") {
+        return;
+    }
+    unsafe {
+        idt.debug
+            .set_handler_addr(VirtAddr::new(gdb_debug_entry as *const () as u64));
+        idt.breakpoint
+            .set_handler_addr(VirtAddr::new(gdb_breakpoint_entry as *const () as u64));
+    }
+}
+
+extern "C" fn trap(frame: &mut TrapFrame) {
+    crate::interrupts::record(frame.vector as u8);
+    if frame.vector == DEBUG_VECTOR {
+        frame.rflags &= !TRAP_FLAG;
+    }
+    if ENABLED.load(Ordering::Relaxed) {
+        // the kernel's stopped for as long as gdb wants
+        watchdog::without_tick_check(|| serve(frame, SIGTRAP, true));
+    }
+}
+
+/// Stop in the stub for a panic, if it's on. Returns once gdb is done looking.
+pub fn stop_for_panic(regs: &Registers) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut frame = TrapFrame {
+        rip: regs.rip,
+        rsp: regs.rsp,
+        rbp: regs.rbp,
+        rflags: regs.rflags,
+        cs: CS::get_reg().0 as u64,
+        ..TrapFrame::default()
+    };
+    serve(&mut frame, SIGABRT, false);
+}
+
+/// A packet being put together
+struct Reply {
+    buf: [u8; MAX_PACKET],
+    len: usize,
+}
+
+impl Reply {
+    fn new() -> Reply {
+        Reply {
+            buf: [0; MAX_PACKET],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(MAX_PACKET - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        self.push(&[DIGITS[byte as usize >> 4], DIGITS[byte as usize & 0xf]]);
+    }
+
+    /// `value`'s low `bytes` bytes, in target order, which is little-endian
+    fn push_register(&mut self, value: u64, bytes: usize) {
+        for byte in &value.to_le_bytes()[..bytes] {
+            self.push_hex(*byte);
+        }
+    }
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// A big-endian hex number, like addresses and lengths are sent as
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, &digit| {
+        Some(value << 4 | hex_digit(digit)? as u64)
+    })
+}
+
+/// Pairs of hex digits, written into `out` as bytes
+fn parse_bytes(digits: &[u8], out: &mut [u8]) -> Option<()> {
+    if digits.len() != out.len() * 2 {
+        return None;
+    }
+    for (byte, pair) in out.iter_mut().zip(digits.chunks(2)) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(())
+}
+
+/// The general registers, in the order gdb has them for x86-64
+fn registers(frame: &mut TrapFrame) -> [&mut u64; 17] {
+    [
+        &mut frame.rax,
+        &mut frame.rbx,
+        &mut frame.rcx,
+        &mut frame.rdx,
+        &mut frame.rsi,
+        &mut frame.rdi,
+        &mut frame.rbp,
+        &mut frame.rsp,
+        &mut frame.r8,
+        &mut frame.r9,
+        &mut frame.r10,
+        &mut frame.r11,
+        &mut frame.r12,
+        &mut frame.r13,
+        &mut frame.r14,
+        &mut frame.r15,
+        &mut frame.rip,
+    ]
+}
+
+/// `g`: the general registers, then eflags and the segment registers, which are 32 bits
+fn read_registers(frame: &mut TrapFrame, reply: &mut Reply) {
+    for register in registers(frame) {
+        reply.push_register(*register, 8);
+    }
+    reply.push_register(frame.rflags, 4);
+    for segment in [frame.cs, frame.ss, 0, 0, 0, 0] {
+        reply.push_register(segment, 4);
+    }
+}
+
+/// `G`: the same layout as `g`. Segment registers can't be changed, and anything after eflags
+/// is ignored.
+fn write_registers(frame: &mut TrapFrame, digits: &[u8]) -> Option<()> {
+    let mut digits = digits.chunks(16);
+    for register in registers(frame) {
+        let mut bytes = [0; 8];
+        parse_bytes(digits.next()?, &mut bytes)?;
+        *register = u64::from_le_bytes(bytes);
+    }
+    let mut rflags = [0; 4];
+    parse_bytes(digits.next()?.get(..8)?, &mut rflags)?;
+    frame.rflags = u32::from_le_bytes(rflags) as u64;
+    Some(())
+}
+
+/// Whether the `len` bytes at `addr` are all mapped
+fn mapped(addr: u64, len: u64) -> bool {
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < end {
+        let present = VirtAddr::try_new(page)
+            .ok()
+            .and_then(mem::virt_to_phys)
+            .is_some();
+        if !present {
+            return false;
+        }
+        page += PAGE_SIZE;
+    }
+    true
+}
+
+/// `addr,len`
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let comma = args.iter().position(|&b| b == b',')?;
+    Some((parse_hex(&args[..comma])?, parse_hex(&args[comma + 1..])?))
+}
+
+/// `m addr,len`
+fn read_memory(args: &[u8], reply: &mut Reply) -> Option<()> {
+    let (addr, len) = parse_range(args)?;
+    // two digits a byte
+    let len = len.min(MAX_PACKET as u64 / 2);
+    if !mapped(addr, len) {
+        return None;
+    }
+    protection::with_user_access(|| {
+        for addr in addr..addr + len {
+            reply.push_hex(unsafe { (addr as *const u8).read_volatile() });
+        }
+    });
+    Some(())
+}
+
+/// `M addr,len:bytes`
+fn write_memory(args: &[u8]) -> Option<()> {
+    let colon = args.iter().position(|&b| b == b':')?;
+    let (addr, len) = parse_range(&args[..colon])?;
+    let mut bytes = [0; MAX_PACKET / 2];
+    let bytes = bytes.get_mut(..len as usize)?;
+    parse_bytes(&args[colon + 1..], bytes)?;
+    if !mapped(addr, len) {
+        return None;
+    }
+
+    // read-only pages too, that's where breakpoints go
+    let cr0 = Cr0::read();
+    unsafe { Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT) };
+    protection::with_user_access(|| {
+        for (i, &byte) in bytes.iter().enumerate() {
+            unsafe { ((addr + i as u64) as *mut u8).write_volatile(byte) };
+        }
+    });
+    unsafe { Cr0::write(cr0) };
+    Some(())
+}
+
+/// Wait for a packet with a good checksum, acknowledging it, and return its data
+fn receive_packet<'a>(port: &mut SerialPort, buf: &'a mut [u8; MAX_PACKET]) -> &'a [u8] {
+    loop {
+        while port.receive() != b'$' {}
+
+        let mut len = 0;
+        let mut sum = 0u8;
+        loop {
+            let byte = port.receive();
+            if byte == b'#' {
+                break;
+            }
+            sum = sum.wrapping_add(byte);
+            if len < MAX_PACKET {
+                buf[len] = byte;
+                len += 1;
+            }
+        }
+        let checksum = [port.receive(), port.receive()];
+        if parse_hex(&checksum) == Some(sum as u64) {
+            port.send(b'+');
+            return &buf[..len];
+        }
+        port.send(b'-');
+    }
+}
+
+/// Send `data` until gdb acknowledges it
+fn send_packet(port: &mut SerialPort, data: &[u8]) {
+    let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    let mut checksum = Reply::new();
+    checksum.push_hex(sum);
+    loop {
+        port.send(b'$');
+        for &byte in data {
+            port.send(byte);
+        }
+        port.send(b'#');
+        port.send(checksum.buf[0]);
+        port.send(checksum.buf[1]);
+        if port.receive() == b'+' {
+            return;
+        }
+    }
+}
+
+/// Report a stop with `signal` and take commands until gdb resumes, or detaches. Resuming
+/// isn't possible when it's not `resumable`, and tells gdb the kernel's gone instead.
+fn serve(frame: &mut TrapFrame, signal: u8, resumable: bool) {
+    interrupts::disable();
+    let mut port = SerialPort::new(COM2);
+    let mut stop = Reply::new();
+    stop.push(b"S");
+    stop.push_hex(signal);
+    send_packet(&mut port, &stop.buf[..stop.len]);
+
+    let mut buf = [0; MAX_PACKET];
+    loop {
+        let packet = receive_packet(&mut port, &mut buf);
+        let (command, args) = match packet.split_first() {
+            Some((&command, args)) => (command, args),
+            None => (0, packet),
+        };
+        let mut reply = Reply::new();
+        let ok = |done: Option<()>| -> &'static [u8] {
+            match done {
+                Some(()) => b"OK",
+                None => b"E01",
+            }
+        };
+        match command {
+            b'?' => reply.push(&stop.buf[..stop.len]),
+            b'g' => read_registers(frame, &mut reply),
+            b'G' => reply.push(ok(write_registers(frame, args))),
+            b'm' => {
+                if read_memory(args, &mut reply).is_none() {
+                    reply.len = 0;
+                    reply.push(b"E14");
+                }
+            }
+            b'M' => reply.push(ok(write_memory(args))),
+            b'c' | b's' if !resumable => {
+                let mut exited = Reply::new();
+                exited.push(b"X");
+                exited.push_hex(signal);
+                send_packet(&mut port, &exited.buf[..exited.len]);
+                return;
+            }
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    frame.rip = addr;
+                }
+                if command == b's' {
+                    frame.rflags |= TRAP_FLAG;
+                } else {
+                    frame.rflags &= !TRAP_FLAG;
+                }
+                // the reply is the stop it ends in
+                return;
+            }
+            b'D' => {
+                send_packet(&mut port, b"OK");
+                frame.rflags &= !TRAP_FLAG;
+                return;
+            }
+            b'k' => {
+                frame.rflags &= !TRAP_FLAG;
+                return;
+            }
+            b'H' => reply.push(b"OK"),
+            b'q' if args.starts_with(b"Supported") => {
+                reply.push(b"PacketSize=1000");
+            }
+            b'q' if args.starts_with(b"Attached") => reply.push(b"1"),
+            b'q' if args.starts_with(b"C") => reply.push(b"QC1"),
+            _ => {}
+        }
+        send_packet(&mut port, &reply.buf[..reply.len]);
+    }
+}
+
+fn init() {
+    if !cmdline::has("gdb") {
+        return;
+    }
+    SerialPort::new(COM2).init();
+    ENABLED.store(true, Ordering::Relaxed);
+    ilog!("gdb: waiting on COM2, attach with target remote");
+    interrupts::int3();
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "gdbstub",
+    stage: Stage::Interrupts,
+    after: &["idt"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn registers_round_trip() {
+        let mut frame = TrapFrame {
+            rax: 0x1122_3344_5566_7788,
+            rip: 0xffff_8000_0000_1000,
+            rflags: 0x202,
+            ..TrapFrame::default()
+        };
+        let mut reply = Reply::new();
+        read_registers(&mut frame, &mut reply);
+        assert_eq!(reply.len, 17 * 16 + 7 * 8);
+        assert_eq!(&reply.buf[..16], b"8877665544332211");
+
+        let mut written = TrapFrame::default();
+        assert_eq!(
+            write_registers(&mut written, &reply.buf[..reply.len]),
+            Some(())
+        );
+        assert_eq!(
+            (written.rax, written.rip, written.rflags),
+            (frame.rax, frame.rip, frame.rflags)
+        );
+        assert_eq!(parse_range(b"ffff8000,10"), Some((0xffff_8000, 0x10)));
+    }
+}
@@ -0,0 +1,170 @@
+//! Kernel debugger monitor
+//!
+//! A small command prompt on the serial port for poking at a kernel that's stuck, without a
+//! host debugger. It polls for input, so it works with interrupts off and from inside the
+//! panic handler, which enters it when `kdb` is on the command line.
+//!
+//! Memory is only read after checking the page tables, so a bad address gives an error
+//! instead of a page fault. MSRs can't be checked ahead of time, and reading one the CPU
+//! doesn't have faults.
+//!
+
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use super::{backtrace, regs, symbols};
+use crate::arch::msr;
+use crate::serial::SERIAL1;
+use crate::{mem, power, serial_print, serial_println};
+
+const MAX_LINE: usize = 128;
+/// Largest dump `x` does in one go
+const MAX_DUMP: u64 = 4096;
+const PAGE_SIZE: u64 = 4096;
+
+const HELP: &str = "\
+commands:
+  help                 show this
+  bt                   backtrace of the monitor's own stack
+  regs                 the monitor's registers
+  x <addr> [len]       hexdump len bytes (default 64) at addr
+  rdmsr <msr>          read a model specific register
+  tasks                list tasks
+  c, continue          leave the monitor
+  reboot               restart the machine
+  shutdown             power off
+numbers are decimal, or hex with 0x";
+
+/// Read a line into `buf`, with echo and backspace. Returns the part that was filled in.
+fn read_line(buf: &mut [u8; MAX_LINE]) -> &str {
+    let mut len = 0;
+    loop {
+        // with interrupts off, so the serial interrupt doesn't take it first
+        let byte = interrupts::without_interrupts(|| SERIAL1.lock().receive());
+        match byte {
+            b'\r' | b'\n' => {
+                serial_println!();
+                break;
+            }
+            // backspace and delete, which is what most terminals send for backspace
+            0x08 | 0x7f => {
+                if len > 0 {
+                    len -= 1;
+                    serial_print!("\x08 \x08");
+                }
+            }
+            0x20..=0x7e if len < MAX_LINE => {
+                buf[len] = byte;
+                len += 1;
+                serial_print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+
+    // only printable ASCII gets in
+    core::str::from_utf8(&buf[..len]).unwrap()
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn print_backtrace() {
+    let mut depth = 0;
+    backtrace::walk(|addr| {
+        let resolved = symbols::resolve(addr);
+        match resolved.symbol {
+            Some(_) => serial_println!("  #{:<2} {:#018x} {}", depth, addr, resolved),
+            None => serial_println!("  #{:<2} {:#018x}", depth, addr),
+        }
+        depth += 1;
+    });
+}
+
+fn hexdump(start: u64, len: u64) {
+    let Some(end) = start.checked_add(len.min(MAX_DUMP)) else {
+        serial_println!("range wraps around");
+        return;
+    };
+
+    let mut line = start & !0xf;
+    while line < end {
+        serial_print!("{:016x}:", line);
+        for addr in line..line + 16 {
+            if addr < start || addr >= end {
+                serial_print!("   ");
+                continue;
+            }
+            if addr == line || addr % PAGE_SIZE == 0 {
+                let mapped = VirtAddr::try_new(addr)
+                    .ok()
+                    .and_then(mem::virt_to_phys)
+                    .is_some();
+                if !mapped {
+                    serial_println!();
+                    serial_println!("{:#x} isn't mapped", addr);
+                    return;
+                }
+            }
+
+            let byte = unsafe { (addr as *const u8).read_volatile() };
+            serial_print!(" {:02x}", byte);
+        }
+        serial_println!();
+        line += 16;
+    }
+}
+
+/// Run one command, returns false when it's time to leave
+fn run(line: &str) -> bool {
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else {
+        return true;
+    };
+
+    let mut arg = || words.next().and_then(parse_number);
+    match command {
+        "help" | "?" => serial_println!("{}", HELP),
+        "bt" => print_backtrace(),
+        "regs" => serial_println!("{}", regs::capture()),
+        "x" => match arg() {
+            Some(addr) => {
+                let len = arg().unwrap_or(64);
+                hexdump(addr, len);
+            }
+            None => serial_println!("usage: x <addr> [len]"),
+        },
+        "rdmsr" => match arg().and_then(|msr| u32::try_from(msr).ok()) {
+            Some(msr) => {
+                let value = unsafe { msr::read(msr) };
+                serial_println!("{:#x} = {:#018x}", msr, value);
+            }
+            None => serial_println!("usage: rdmsr <msr>"),
+        },
+        "tasks" => serial_println!("no tasks, there's no scheduler yet"),
+        "c" | "continue" => return false,
+        "reboot" => power::reboot(),
+        "shutdown" => power::shutdown(),
+        _ => serial_println!("unknown command {}, try help", command),
+    }
+    true
+}
+
+/// Take commands on the serial port until told to continue
+pub fn enter(reason: &str) {
+    serial_println!();
+    serial_println!("kdb: {}", reason);
+    serial_println!("type help for commands");
+
+    let mut buf = [0; MAX_LINE];
+    loop {
+        serial_print!("kdb> ");
+        if !run(read_line(&mut buf)) {
+            break;
+        }
+    }
+}
@@ -0,0 +1,13 @@
+//! Debugging aids for when things go wrong
+//!
+
+pub mod assert;
+pub mod backtrace;
+pub mod bench;
+pub mod fault;
+pub mod gdbstub;
+pub mod kdb;
+pub mod panic_screen;
+pub mod profile;
+pub mod regs;
+pub mod symbols;
@@ -0,0 +1,240 @@
+//! The screen a panic leaves behind
+//!
+//! Instead of a few more lines at the bottom of whatever was on the console, a panic clears it
+//! and [`show`]s what happened in one place, red on light gray (the white VGA text mode can do
+//! without making it blink):
+//!
+//! ```text
+//! ╔═ kernel panic ═══════════════════════╗
+//! ║message, location, uptime, registers  ║
+//! ╠══════════════════════════════════════╣
+//! ║┌─ backtrace ─────┬─ before the panic ┐║
+//! ║│                 │                   │║
+//! ║└─────────────────┴───────────────────┘║
+//! ╚══════════════════════════════════════╝
+//!  press R to reboot
+//! ```
+//!
+//! The log pane has the last lines of the [`klog`] from before the panic, so the panic's own
+//! report, which goes out the other sinks as usual, isn't in it twice. When the kernel would
+//! halt after the panic, it [`wait_for_reboot`]s instead, polling the keyboard and the serial
+//! port since interrupts stay off, and the bottom row says so.
+//!
+//! It's only drawn when the `console` sink is enabled, with `console=serial` there's no one
+//! looking at the screen.
+//!
+
+use core::fmt::{self, Write as _};
+use core::panic::PanicInfo;
+
+use super::regs::Registers;
+use super::{backtrace, symbols};
+use crate::console::tui::{self, Split, Style, TextArea};
+use crate::console::{self, input, sink, Color, Console};
+use crate::gfx::Rect;
+use crate::power::{self, PanicAction};
+use crate::serial::SERIAL1;
+use crate::{keyboard, klog, timer};
+
+const FOREGROUND: Color = Color::Red;
+const BACKGROUND: Color = Color::LightGray;
+
+/// Rows the message, location, uptime, and registers take
+const SUMMARY_ROWS: usize = 7;
+
+/// Log lines kept for the log pane at most, and bytes of each line
+const MAX_LOG_LINES: usize = 32;
+const LOG_LINE_BYTES: usize = 128;
+
+/// Whether the screen will be shown, which is when the console sink is enabled
+pub fn wanted() -> bool {
+    let mut enabled = false;
+    sink::for_each(|name, on| enabled |= name == "console" && on);
+    enabled
+}
+
+/// The last lines of the klog written before `end`, newest last
+struct LogTail {
+    lines: [[u8; LOG_LINE_BYTES]; MAX_LOG_LINES],
+    lens: [usize; MAX_LOG_LINES],
+    /// Lines seen in all, the newest is at `(count - 1) % MAX_LOG_LINES`
+    count: usize,
+}
+
+impl LogTail {
+    fn read(end: u64) -> LogTail {
+        let mut tail = LogTail {
+            lines: [[0; LOG_LINE_BYTES]; MAX_LOG_LINES],
+            lens: [0; MAX_LOG_LINES],
+            count: 0,
+        };
+        klog::for_each_line_before(end, |line| {
+            let line = line.trim_end_matches('\n');
+            // cut on a character boundary
+            let mut len = line.len().min(LOG_LINE_BYTES);
+            while !line.is_char_boundary(len) {
+                len -= 1;
+            }
+            let slot = tail.count % MAX_LOG_LINES;
+            tail.lines[slot][..len].copy_from_slice(&line.as_bytes()[..len]);
+            tail.lens[slot] = len;
+            tail.count += 1;
+        });
+        tail
+    }
+
+    /// The last `n` lines, oldest first
+    fn last(&self, n: usize) -> impl Iterator<Item = &str> {
+        let n = n.min(self.count).min(MAX_LOG_LINES);
+        (self.count - n..self.count).map(|i| {
+            let slot = i % MAX_LOG_LINES;
+            core::str::from_utf8(&self.lines[slot][..self.lens[slot]]).unwrap_or("")
+        })
+    }
+}
+
+fn draw_summary(console: &mut dyn Console, rect: Rect, info: &PanicInfo, regs: &Registers) {
+    let uptime_ms = timer::uptime_ms();
+    let mut area = TextArea::new(console, rect);
+    let _ = writeln!(area, "{}", info.message());
+    match info.location() {
+        Some(location) => {
+            let _ = write!(area, "at {}, ", location);
+        }
+        None => {
+            let _ = write!(area, "somewhere, ");
+        }
+    }
+    let _ = writeln!(
+        area,
+        "{}.{:03} s after boot",
+        uptime_ms / 1000,
+        uptime_ms % 1000
+    );
+    let _ = writeln!(area);
+    let _ = write!(area, "{}", regs);
+}
+
+fn draw_backtrace(console: &mut dyn Console, rect: Rect) {
+    let mut area = TextArea::new(console, rect);
+    let mut depth = 0;
+    backtrace::walk(|addr| {
+        // one frame a line, cut off rather than wrapped
+        let mut line = Line::new(rect.width);
+        let _ = write!(line, "#{:<2} {}", depth, symbols::resolve(addr));
+        let _ = writeln!(area, "{}", line.as_str());
+        depth += 1;
+    });
+}
+
+fn draw_log(console: &mut dyn Console, rect: Rect, log_end: u64) {
+    let tail = LogTail::read(log_end);
+    let mut area = TextArea::new(console, rect);
+    for line in tail.last(rect.height) {
+        for c in line.chars().take(rect.width) {
+            let _ = area.write_char(c);
+        }
+        let _ = area.write_char('\n');
+    }
+}
+
+/// A line formatted into a buffer, cut off at a number of characters
+struct Line {
+    buf: [u8; LOG_LINE_BYTES],
+    len: usize,
+    chars: usize,
+    max_chars: usize,
+}
+
+impl Line {
+    fn new(max_chars: usize) -> Line {
+        Line {
+            buf: [0; LOG_LINE_BYTES],
+            len: 0,
+            chars: 0,
+            max_chars,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.chars == self.max_chars || self.len + c.len_utf8() > LOG_LINE_BYTES {
+                break;
+            }
+            c.encode_utf8(&mut self.buf[self.len..]);
+            self.len += c.len_utf8();
+            self.chars += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Clear the console and draw the panic screen on it. `log_end` is the [`klog::position`] from
+/// before the panic was reported.
+pub fn show(info: &PanicInfo, regs: &Registers, log_end: u64) {
+    let action = power::panic_action();
+    console::with_console(|console| {
+        console.set_color(FOREGROUND, BACKGROUND);
+        console.clear();
+        let (cols, rows) = console.size();
+        // the last row is for the hint
+        let screen = Rect::new(0, 0, cols, rows.saturating_sub(1));
+
+        let [summary, lower] = tui::draw_panes(
+            console,
+            screen,
+            Style::Double,
+            Split::Rows(SUMMARY_ROWS + 1),
+            [Some("kernel panic"), None],
+        );
+        draw_summary(console, summary, info, regs);
+
+        let [frames, log] = tui::draw_panes(
+            console,
+            lower,
+            Style::Single,
+            Split::Columns(lower.width / 2),
+            [Some("backtrace"), Some("before the panic")],
+        );
+        draw_backtrace(console, frames);
+        draw_log(console, log, log_end);
+
+        let hint = match action {
+            PanicAction::Halt => "press R to reboot",
+            PanicAction::Reboot(_) => "rebooting",
+            PanicAction::Shutdown => "powering off",
+        };
+        tui::write_at(console, 1, rows.saturating_sub(1), hint);
+    });
+}
+
+/// Poll the keyboard and the serial port until R is pressed, then reboot
+pub fn wait_for_reboot() -> ! {
+    loop {
+        keyboard::poll();
+        let byte = input::try_read().or_else(|| SERIAL1.lock().try_receive());
+        if let Some(b'r' | b'R') = byte {
+            power::reboot();
+        }
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn lines_are_cut_off() {
+        let mut line = Line::new(8);
+        let symbol = "zenix::main+0x10";
+        let _ = write!(line, "#{:<2} {}", 3, symbol);
+        assert_eq!(line.as_str(), "#3  zeni");
+    }
+}
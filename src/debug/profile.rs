@@ -0,0 +1,164 @@
+//! Sampling profiler
+//!
+//! While it's [`start`]ed, every [timer](crate::timer) tick records where the boot CPU was,
+//! the interrupted instruction and a few of the return addresses above it, into a ring of the
+//! last [`MAX_SAMPLES`]. [`profile`] turns them into a flat profile: for each function, the
+//! samples that were in it, and the samples it was on the stack for, which includes the ones
+//! in what it called. The shell's `profile` command prints that, symbolized.
+//!
+//! The return addresses come from walking the frame pointers from the tick: the IRQ stub's
+//! frame is the interrupted code's, so past the interrupted instruction the walk goes on
+//! through its callers. A tick from user mode only has the instruction, and the walk
+//! doesn't follow user frames. Samples are only as often as the timer, so a function needs
+//! a good number of them before the share it's shown with means much.
+//!
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use super::{backtrace, symbols};
+use crate::sync::SpinLock;
+use crate::user::USER_END;
+
+/// Samples kept, older ones are written over
+pub const MAX_SAMPLES: usize = 2048;
+/// Addresses kept of each sample, the interrupted one first
+pub const DEPTH: usize = 4;
+
+type Sample = [u64; DEPTH];
+
+struct Ring {
+    samples: [Sample; MAX_SAMPLES],
+    /// Where the next one goes
+    next: usize,
+    /// Samples taken since the start, which can be more than are kept
+    taken: u64,
+}
+
+/// Only locked with interrupts off, the timer interrupt takes samples
+static RING: SpinLock<Ring> = SpinLock::new(
+    "profile",
+    Ring {
+        samples: [[0; DEPTH]; MAX_SAMPLES],
+        next: 0,
+        taken: 0,
+    },
+);
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Throw away the samples so far and take new ones on every tick
+pub fn start() {
+    interrupts::without_interrupts(|| {
+        let mut ring = RING.lock();
+        ring.next = 0;
+        ring.taken = 0;
+    });
+    RUNNING.store(true, Ordering::Relaxed);
+}
+
+/// Stop taking samples, keeping the ones there are
+pub fn stop() {
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Take a sample at `rip`, where the timer interrupt came in. Called by the timer on every
+/// tick, with interrupts off.
+pub fn sample(rip: u64) {
+    if !is_running() {
+        return;
+    }
+    let mut sample = [0; DEPTH];
+    sample[0] = rip;
+    let mut depth = 1;
+    // everything before `rip` is the interrupt handler's own frames
+    let mut found = false;
+    backtrace::walk(|addr| {
+        if !found {
+            found = addr == rip;
+        } else if depth < DEPTH {
+            sample[depth] = addr;
+            depth += 1;
+        }
+    });
+
+    let mut ring = RING.lock();
+    let next = ring.next;
+    ring.samples[next] = sample;
+    ring.next = (next + 1) % MAX_SAMPLES;
+    ring.taken += 1;
+}
+
+/// Samples in one function
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    /// Where the function starts, or the address itself when there's no symbol for it. User
+    /// code is all one entry, at 0.
+    pub addr: u64,
+    /// Samples in the function itself
+    pub own: u64,
+    /// Samples with the function anywhere in the kept addresses
+    pub total: u64,
+}
+
+/// What the samples add up to, [`Entry`]s by most samples of their own first, and how many
+/// samples there were. Only the last [`MAX_SAMPLES`] are counted.
+pub fn profile() -> (Vec<Entry>, u64) {
+    /// Samples copied out at once, kept small since it's on the stack
+    const CHUNK: usize = 64;
+    const _: () = assert!(MAX_SAMPLES.is_multiple_of(CHUNK));
+
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut chunk = [[0; DEPTH]; CHUNK];
+    let mut kept = 0;
+    for start in (0..MAX_SAMPLES).step_by(CHUNK) {
+        interrupts::without_interrupts(|| {
+            let ring = RING.lock();
+            kept = ring.taken.min(MAX_SAMPLES as u64) as usize;
+            chunk.copy_from_slice(&ring.samples[start..start + CHUNK]);
+        });
+        let len = kept.saturating_sub(start).min(CHUNK);
+        for sample in &chunk[..len] {
+            let mut seen = [0; DEPTH];
+            for (depth, &addr) in sample.iter().enumerate() {
+                if addr == 0 {
+                    break;
+                }
+                let function = if addr < USER_END {
+                    0
+                } else {
+                    symbols::lookup(addr).map_or(addr, |symbol| symbol.addr)
+                };
+                // recursion counts once
+                if seen[..depth].contains(&function) {
+                    continue;
+                }
+                seen[depth] = function;
+
+                let entry = match entries.iter_mut().find(|entry| entry.addr == function) {
+                    Some(entry) => entry,
+                    None => {
+                        entries.push(Entry {
+                            addr: function,
+                            own: 0,
+                            total: 0,
+                        });
+                        entries.last_mut().unwrap()
+                    }
+                };
+                entry.total += 1;
+                if depth == 0 {
+                    entry.own += 1;
+                }
+            }
+        }
+    }
+    entries.sort_by_key(|entry| core::cmp::Reverse((entry.own, entry.total)));
+    (entries, kept as u64)
+}
@@ -0,0 +1,74 @@
+//! Register snapshots
+//!
+//! There's no exception frame to read them from in the panic path, so [`capture`] reads them
+//! where it's inlined: `rip` is the capture site itself, and the stack registers are those of
+//! the function calling it.
+//!
+
+use core::arch::asm;
+use core::fmt;
+
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags;
+
+/// General state of the CPU at the point [`capture`] was called
+#[derive(Debug, Clone, Copy)]
+pub struct Registers {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+/// Read the registers at the call site
+#[inline(always)]
+pub fn capture() -> Registers {
+    let (rip, rsp, rbp): (u64, u64, u64);
+    unsafe {
+        asm!(
+            "lea {}, [rip]",
+            "mov {}, rsp",
+            "mov {}, rbp",
+            out(reg) rip,
+            out(reg) rsp,
+            out(reg) rbp,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+
+    Registers {
+        rip,
+        rsp,
+        rbp,
+        rflags: rflags::read_raw(),
+        cr0: Cr0::read_raw(),
+        cr2: Cr2::read_raw(),
+        cr3: Cr3::read().0.start_address().as_u64(),
+        cr4: Cr4::read_raw(),
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "rip {:#018x} rsp {:#018x} rbp {:#018x}",
+            self.rip, self.rsp, self.rbp
+        )?;
+        writeln!(
+            f,
+            "rflags {:#010x} ({:?})",
+            self.rflags,
+            rflags::RFlags::from_bits_truncate(self.rflags)
+        )?;
+        write!(
+            f,
+            "cr0 {:#010x} cr2 {:#018x} cr3 {:#018x} cr4 {:#010x}",
+            self.cr0, self.cr2, self.cr3, self.cr4
+        )
+    }
+}
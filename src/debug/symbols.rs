@@ -0,0 +1,137 @@
+//! Kernel symbol table
+//!
+//! The symbols only exist once the kernel is linked, so the table can't be generated by the
+//! build itself. Instead the kernel reserves a zeroed `.ksyms` section, and `tools/ksyms.py`
+//! fills it in on the linked ELF before it's booted (`tools/runner.sh` does this for
+//! `cargo run` and `cargo test`). A kernel that skipped that step has an empty table, and
+//! lookups find nothing.
+//!
+//! See `tools/ksyms.py` for the layout. [`lookup`] finds the function an address is in, and
+//! [`resolve`] is for printing one, as `zenix::mem::frame::allocate+0x42`.
+//!
+
+use core::fmt;
+use core::hint::black_box;
+
+/// Space reserved for the table
+const KSYMS_SIZE: usize = 512 * 1024;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 16;
+
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+/// A function `addr` falls in
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    /// Where the function starts
+    pub addr: u64,
+    /// How far into the function `addr` is
+    pub offset: u64,
+}
+
+struct Entry {
+    addr: u64,
+    size: u64,
+    name: u32,
+}
+
+struct Table {
+    entries: &'static [u8],
+    names: &'static [u8],
+}
+
+impl Table {
+    fn get() -> Option<Table> {
+        // the compiler only ever sees zeros in here, don't let it assume that's what gets read
+        let table: &'static [u8] = black_box(&KSYMS);
+        if &table[..4] != MAGIC {
+            return None;
+        }
+
+        let count = u32::from_le_bytes(table[4..8].try_into().unwrap()) as usize;
+        let names_start = HEADER_SIZE + count * ENTRY_SIZE;
+        Some(Table {
+            entries: table.get(HEADER_SIZE..names_start)?,
+            names: &table[names_start..],
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    fn entry(&self, i: usize) -> Entry {
+        let raw = &self.entries[i * ENTRY_SIZE..(i + 1) * ENTRY_SIZE];
+        Entry {
+            addr: u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+            size: u32::from_le_bytes(raw[8..12].try_into().unwrap()) as u64,
+            name: u32::from_le_bytes(raw[12..16].try_into().unwrap()),
+        }
+    }
+
+    fn name(&self, offset: u32) -> &'static str {
+        let start = (offset as usize).min(self.names.len());
+        let rest = &self.names[start..];
+        let end = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        core::str::from_utf8(&rest[..end]).unwrap_or("<bad symbol name>")
+    }
+}
+
+/// Find the function containing `addr`
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    let table = Table::get()?;
+
+    // the last entry starting at or before addr
+    let (mut low, mut high) = (0, table.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        if table.entry(mid).addr <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let entry = table.entry(low.checked_sub(1)?);
+
+    // symbols without a size are taken to run up to the next one
+    let offset = addr - entry.addr;
+    if entry.size != 0 && offset >= entry.size {
+        return None;
+    }
+
+    Some(Symbol {
+        name: table.name(entry.name),
+        addr: entry.addr,
+        offset,
+    })
+}
+
+/// An address to print, with its symbol if it has one
+#[derive(Debug, Clone, Copy)]
+pub struct Resolved {
+    pub addr: u64,
+    pub symbol: Option<Symbol>,
+}
+
+/// `name+offset`, or just the address without a symbol
+impl fmt::Display for Resolved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.symbol {
+            Some(symbol) => write!(f, "{}+{:#x}", symbol.name, symbol.offset),
+            None => write!(f, "{:#x}", self.addr),
+        }
+    }
+}
+
+/// Look up `addr` for printing
+pub fn resolve(addr: u64) -> Resolved {
+    Resolved {
+        addr,
+        symbol: lookup(addr),
+    }
+}
@@ -0,0 +1,393 @@
+//! ATA disks, in PIO mode
+//!
+//! [`INIT`] sends IDENTIFY to the master and slave drive on both legacy IDE channels, at the
+//! ports every PC and QEMU's PIIX controllers have them on, and registers each ATA disk it
+//! finds as a [`BlockDevice`]: `ata0` and `ata1` on the primary channel, `ata2` and `ata3` on
+//! the secondary one. ATAPI drives, like CD-ROMs, answer with a signature instead and are
+//! skipped.
+//!
+//! Every transfer is polled a sector at a time through the data port, with the drive's
+//! interrupt switched off, so it's slow but needs nothing else. Disks that can do 48-bit LBA
+//! are addressed that way, the others with 28-bit LBA, which reaches 128 GiB. Writes are
+//! followed by a cache flush, so they're on the disk when [`write`](BlockDevice::write)
+//! returns.
+//!
+//! links:
+//! - <https://wiki.osdev.org/ATA_PIO_Mode>
+//! - ATA/ATAPI-8 command set (ACS): IDENTIFY DEVICE, READ SECTORS (EXT), WRITE SECTORS (EXT)
+//!
+
+use alloc::boxed::Box;
+use core::fmt;
+
+use crate::arch::io::Port;
+use crate::block::{self, BlockDevice, BlockError};
+use crate::debug::fault;
+use crate::init::{InitCall, Stage};
+use crate::sync::Mutex;
+use crate::time::{Duration, Instant};
+use crate::{ilog, wlog};
+
+const SECTOR_SIZE: usize = 512;
+
+// task file registers, as offsets from a channel's I/O base
+const DATA: u16 = 0;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+// status bits
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+/// What a channel with nothing on it reads as
+const FLOATING: u8 = 0xff;
+
+/// Device control: the drive doesn't raise its interrupt
+const CONTROL_NO_INTERRUPT: u8 = 1 << 1;
+
+/// Drive/head: LBA addressing, plus the bits that are always set
+const DRIVE_LBA: u8 = 0xe0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const IDENTIFY: u8 = 0xec;
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_EXT: u8 = 0x24;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_EXT: u8 = 0x34;
+const FLUSH_CACHE: u8 = 0xe7;
+const FLUSH_CACHE_EXT: u8 = 0xea;
+
+// IDENTIFY words
+const ID_MODEL: usize = 27;
+const ID_MODEL_WORDS: usize = 20;
+const ID_SECTORS_28: usize = 60;
+const ID_COMMANDS: usize = 83;
+const ID_SECTORS_48: usize = 100;
+/// Command set word: 48-bit LBA is supported
+const COMMANDS_LBA48: u16 = 1 << 10;
+
+/// The most sectors one command can move, with a count of 0 meaning this many
+const MAX_SECTORS_28: u64 = 256;
+const MAX_SECTORS_48: u64 = 65536;
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// How long a drive gets to get ready for each step of a command
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The legacy IDE channels: I/O base and control port
+const CHANNELS: [(u16, u16); 2] = [(0x1f0, 0x3f6), (0x170, 0x376)];
+const NAMES: [&str; 4] = ["ata0", "ata1", "ata2", "ata3"];
+
+/// One IDE channel, shared by its two drives
+struct Channel {
+    base: u16,
+    control: u16,
+}
+
+impl Channel {
+    fn port(&self, offset: u16) -> Port<u8> {
+        // the channel's task file, which only this driver touches
+        unsafe { Port::new(self.base + offset) }
+    }
+
+    fn data(&self) -> Port<u16> {
+        unsafe { Port::new(self.base + DATA) }
+    }
+
+    /// The alternate status register, which reads like status without acknowledging anything
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control) }.read()
+    }
+
+    fn select(&self, slave: bool, lba_top: u8) {
+        let drive = DRIVE_LBA | if slave { DRIVE_SLAVE } else { 0 } | (lba_top & 0xf);
+        self.port(DRIVE).write(drive);
+        // the drive takes 400 ns to put its status up, each read is about 100
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    /// Wait for the drive not to be busy, returning its status
+    fn wait_idle(&self) -> Result<u8, BlockError> {
+        let start = Instant::now();
+        loop {
+            let status = self.alt_status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+            if start.elapsed() > TIMEOUT || fault::TIMEOUT.fire() {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Wait for the drive to have a sector of data for us or want one from us
+    fn wait_data(&self) -> Result<(), BlockError> {
+        let start = Instant::now();
+        loop {
+            let status = self.wait_idle()?;
+            if status & (STATUS_ERROR | STATUS_FAULT) != 0 {
+                return Err(BlockError::Io);
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+            if start.elapsed() > TIMEOUT || fault::TIMEOUT.fire() {
+                return Err(BlockError::Timeout);
+            }
+        }
+    }
+
+    /// Set up a transfer of `count` sectors at `lba` and send `command`
+    fn command(&self, slave: bool, lba48: bool, lba: u64, count: u64, command: u8) {
+        if lba48 {
+            self.select(slave, 0);
+            // the high bytes go first, through the same registers
+            self.port(SECTOR_COUNT).write((count >> 8) as u8);
+            self.port(LBA_LOW).write((lba >> 24) as u8);
+            self.port(LBA_MID).write((lba >> 32) as u8);
+            self.port(LBA_HIGH).write((lba >> 40) as u8);
+        } else {
+            self.select(slave, (lba >> 24) as u8);
+        }
+        self.port(SECTOR_COUNT).write(count as u8);
+        self.port(LBA_LOW).write(lba as u8);
+        self.port(LBA_MID).write((lba >> 8) as u8);
+        self.port(LBA_HIGH).write((lba >> 16) as u8);
+        self.port(COMMAND).write(command);
+    }
+
+    /// Send IDENTIFY, None if there's no ATA disk there
+    fn identify(&self, slave: bool) -> Option<[u16; 256]> {
+        unsafe { Port::<u8>::new(self.control) }.write(CONTROL_NO_INTERRUPT);
+        self.select(slave, 0);
+        if self.alt_status() == FLOATING {
+            return None;
+        }
+        self.command(slave, false, 0, 0, IDENTIFY);
+        // no drive at all reads as 0
+        if self.port(STATUS).read() == 0 {
+            return None;
+        }
+        self.wait_idle().ok()?;
+        // ATAPI and SATA drives put their signature here instead of doing IDENTIFY
+        if self.port(LBA_MID).read() != 0 || self.port(LBA_HIGH).read() != 0 {
+            return None;
+        }
+        self.wait_data().ok()?;
+
+        let mut words = [0; 256];
+        for word in words.iter_mut() {
+            *word = self.data().read();
+        }
+        Some(words)
+    }
+}
+
+/// An ATA disk
+struct Disk {
+    name: &'static str,
+    channel: &'static Mutex<Channel>,
+    slave: bool,
+    lba48: bool,
+    sectors: u64,
+    model: [u8; ID_MODEL_WORDS * 2],
+}
+
+impl Disk {
+    fn model(&self) -> &str {
+        core::str::from_utf8(&self.model).unwrap_or("?").trim()
+    }
+
+    /// Move the sectors starting at `sector` in chunks of as many as one command can do,
+    /// calling `transfer` for each sector once the drive's ready for it
+    fn transfer(
+        &self,
+        sector: u64,
+        len: usize,
+        commands: (u8, u8),
+        mut transfer: impl FnMut(&Channel, usize),
+    ) -> Result<(), BlockError> {
+        let count = block::check(self, sector, len)?;
+        let (max, command) = if self.lba48 {
+            (MAX_SECTORS_48, commands.1)
+        } else {
+            (MAX_SECTORS_28, commands.0)
+        };
+
+        let channel = self.channel.lock();
+        let mut done = 0;
+        while done < count {
+            let chunk = (count - done).min(max);
+            channel.wait_idle()?;
+            // a count of 0 is the most sectors
+            channel.command(self.slave, self.lba48, sector + done, chunk % max, command);
+            for i in 0..chunk {
+                channel.wait_data()?;
+                transfer(&channel, (done + i) as usize * SECTOR_SIZE);
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Disk {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.transfer(
+            sector,
+            buf.len(),
+            (READ_SECTORS, READ_SECTORS_EXT),
+            |channel, at| {
+                for word in buf[at..at + SECTOR_SIZE].chunks_exact_mut(2) {
+                    word.copy_from_slice(&channel.data().read().to_le_bytes());
+                }
+            },
+        )
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.transfer(
+            sector,
+            buf.len(),
+            (WRITE_SECTORS, WRITE_SECTORS_EXT),
+            |channel, at| {
+                for word in buf[at..at + SECTOR_SIZE].chunks_exact(2) {
+                    channel.data().write(u16::from_le_bytes([word[0], word[1]]));
+                }
+            },
+        )?;
+        self.flush()
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let channel = self.channel.lock();
+        channel.wait_idle()?;
+        channel.select(self.slave, 0);
+        let command = if self.lba48 {
+            FLUSH_CACHE_EXT
+        } else {
+            FLUSH_CACHE
+        };
+        channel.port(COMMAND).write(command);
+        let status = channel.wait_idle()?;
+        if status & (STATUS_ERROR | STATUS_FAULT) != 0 {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Disk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}, {} MiB, {}-bit LBA",
+            self.name,
+            self.model(),
+            self.sectors * SECTOR_SIZE as u64 / (1024 * 1024),
+            if self.lba48 { 48 } else { 28 }
+        )
+    }
+}
+
+static PRIMARY: Mutex<Channel> = Mutex::new(
+    "ata primary",
+    Channel {
+        base: CHANNELS[0].0,
+        control: CHANNELS[0].1,
+    },
+);
+static SECONDARY: Mutex<Channel> = Mutex::new(
+    "ata secondary",
+    Channel {
+        base: CHANNELS[1].0,
+        control: CHANNELS[1].1,
+    },
+);
+
+fn probe(channel: &'static Mutex<Channel>, slave: bool, name: &'static str) -> Option<Disk> {
+    let id = channel.lock().identify(slave)?;
+
+    let lba48 = id[ID_COMMANDS] & COMMANDS_LBA48 != 0;
+    let sectors = if lba48 {
+        (0..4).fold(0, |sectors, i| {
+            sectors | (id[ID_SECTORS_48 + i] as u64) << (i * 16)
+        })
+    } else {
+        id[ID_SECTORS_28] as u64 | (id[ID_SECTORS_28 + 1] as u64) << 16
+    };
+    // the model string has the bytes of each word swapped
+    let mut model = [0; ID_MODEL_WORDS * 2];
+    for (i, word) in id[ID_MODEL..ID_MODEL + ID_MODEL_WORDS].iter().enumerate() {
+        model[i * 2..i * 2 + 2].copy_from_slice(&word.to_be_bytes());
+    }
+
+    Some(Disk {
+        name,
+        channel,
+        slave,
+        // small disks can still take 48-bit commands, but there's no need
+        lba48: lba48 && sectors > LBA28_LIMIT,
+        sectors,
+        model,
+    })
+}
+
+fn init() {
+    let drives = [
+        (&PRIMARY, false),
+        (&PRIMARY, true),
+        (&SECONDARY, false),
+        (&SECONDARY, true),
+    ];
+    for ((channel, slave), name) in drives.into_iter().zip(NAMES) {
+        let Some(disk) = probe(channel, slave, name) else {
+            continue;
+        };
+        ilog!("ata: {}", disk);
+        if let Err(error) = block::register(Box::leak(Box::new(disk))) {
+            wlog!("ata: couldn't register {}: {:?}", name, error);
+        }
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "ata",
+    stage: Stage::Drivers,
+    // its timeouts are kept with the TSC
+    after: &["time"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn boot_disk_has_boot_signature() {
+        // the image QEMU boots from is the primary master
+        let Some(disk) = block::get("ata0") else {
+            return;
+        };
+        let mut sector = [0; SECTOR_SIZE];
+        disk.read(0, &mut sector).unwrap();
+        assert_eq!(sector[510..], [0x55, 0xaa]);
+    }
+}
@@ -0,0 +1,9 @@
+//! Device drivers
+//!
+//! Drivers for devices that plug into one of the kernel's interfaces, like the
+//! [`block`](crate::block) layer, rather than being part of the platform itself.
+//!
+
+pub mod ata;
+pub mod ramdisk;
+pub mod virtio;
@@ -0,0 +1,145 @@
+//! RAM disks
+//!
+//! A [`Ramdisk`] is a [`BlockDevice`] kept in a heap buffer, for trying filesystem code out
+//! before there's a disk it can run on. [`INIT`] registers up to two of them:
+//!
+//! - `rd0`, a copy of a disk image built into the kernel with the `ramdisk_image` feature, from
+//!   the file `ZENIX_RAMDISK` names at build time. bootloader 0.9 can't load modules, so that's
+//!   the way to get one in.
+//! - a blank one of `ramdisk=MIB` megabytes from the command line, called `rd1` if there's an
+//!   image and `rd0` if there isn't
+//!
+//! ```shell
+//! $ ZENIX_RAMDISK=$PWD/fat.img cargo run --features ramdisk_image
+//! ```
+//!
+//! Both are writable, and everything written is gone at the next boot.
+//!
+
+use alloc::boxed::Box;
+use alloc::vec;
+use core::fmt;
+
+use crate::block::{self, BlockDevice, BlockError};
+use crate::init::{InitCall, Stage};
+use crate::sync::Mutex;
+use crate::{cmdline, ilog, wlog};
+
+const SECTOR_SIZE: usize = 512;
+const NAMES: [&str; 2] = ["rd0", "rd1"];
+
+#[cfg(feature = "ramdisk_image")]
+static IMAGE: &[u8] = include_bytes!(env!("ZENIX_RAMDISK"));
+
+/// A block device in memory
+pub struct Ramdisk {
+    name: &'static str,
+    data: Mutex<Box<[u8]>>,
+    sectors: u64,
+}
+
+impl Ramdisk {
+    /// A disk holding `data`, padded with zeroes to a whole sector
+    pub fn new(name: &'static str, data: &[u8]) -> Ramdisk {
+        let mut buffer = vec![0; data.len().next_multiple_of(SECTOR_SIZE)];
+        buffer[..data.len()].copy_from_slice(data);
+        Ramdisk::from_buffer(name, buffer.into_boxed_slice())
+    }
+
+    /// A disk of `size` bytes, rounded up to a whole sector, all zeroes
+    pub fn zeroed(name: &'static str, size: usize) -> Ramdisk {
+        let buffer = vec![0; size.next_multiple_of(SECTOR_SIZE)];
+        Ramdisk::from_buffer(name, buffer.into_boxed_slice())
+    }
+
+    fn from_buffer(name: &'static str, buffer: Box<[u8]>) -> Ramdisk {
+        Ramdisk {
+            name,
+            sectors: (buffer.len() / SECTOR_SIZE) as u64,
+            data: Mutex::new("ramdisk", buffer),
+        }
+    }
+}
+
+impl BlockDevice for Ramdisk {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check(self, sector, buf.len())?;
+        let start = sector as usize * SECTOR_SIZE;
+        buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check(self, sector, buf.len())?;
+        let start = sector as usize * SECTOR_SIZE;
+        self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl fmt::Display for Ramdisk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} KiB in memory",
+            self.name,
+            self.sectors * SECTOR_SIZE as u64 / 1024
+        )
+    }
+}
+
+fn add(disk: Ramdisk) {
+    ilog!("ramdisk: {}", disk);
+    let name = disk.name;
+    if let Err(error) = block::register(Box::leak(Box::new(disk))) {
+        wlog!("ramdisk: couldn't register {}: {:?}", name, error);
+    }
+}
+
+fn init() {
+    let mut names = NAMES.into_iter();
+    #[cfg(feature = "ramdisk_image")]
+    add(Ramdisk::new(names.next().unwrap(), IMAGE));
+
+    match cmdline::parse::<usize>("ramdisk") {
+        Ok(Some(mib)) => add(Ramdisk::zeroed(names.next().unwrap(), mib * 1024 * 1024)),
+        Ok(None) => {}
+        Err(error) => wlog!("ramdisk: {}", error),
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "ramdisk",
+    stage: Stage::Drivers,
+    after: &[],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn write_then_read() {
+        let disk = Ramdisk::new("test", &[0xaa; 700]);
+        assert_eq!(disk.sector_count(), 2);
+
+        let mut sector = [0; SECTOR_SIZE];
+        disk.read(1, &mut sector).unwrap();
+        assert_eq!(sector[..700 - SECTOR_SIZE], [0xaa; 700 - SECTOR_SIZE]);
+        assert_eq!(sector[700 - SECTOR_SIZE..], [0; 2 * SECTOR_SIZE - 700]);
+
+        disk.write(0, &[0x55; SECTOR_SIZE]).unwrap();
+        disk.read(0, &mut sector).unwrap();
+        assert_eq!(sector, [0x55; SECTOR_SIZE]);
+        assert_eq!(disk.read(2, &mut sector), Err(BlockError::OutOfRange));
+    }
+}
@@ -0,0 +1,282 @@
+//! Virtio block devices
+//!
+//! [`INIT`] sets up every virtio-blk device on the PCI bus and registers it as a
+//! [`BlockDevice`], named `vda`, `vdb`, and so on in the order they're found. Each has one
+//! queue, and a request on it is a header saying what to do and where, the data, and a status
+//! byte the device writes when it's done. The data is whatever buffer the caller passed, split
+//! where its pages aren't contiguous in physical memory, so nothing gets copied.
+//!
+//! One request per disk is in flight at a time. The thread that made it sleeps on
+//! [`COMPLETED`] until the device puts it on the used ring, and the device's interrupt wakes
+//! it.
+//!
+//! links:
+//! - virtio 1.1, 5.2 "Block Device": <https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html>
+//!
+
+use alloc::boxed::Box;
+use core::fmt;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{Buffer, Transport, VirtioError, Virtqueue, VENDOR_ID};
+use crate::block::{self, BlockDevice, BlockError};
+use crate::init::{InitCall, Stage};
+use crate::mem::frame::{self, FRAME_SIZE};
+use crate::mem::{phys_to_virt, virt_to_phys};
+use crate::sync::{Mutex, WaitQueue};
+use crate::{ilog, pci, wlog};
+
+/// The transitional device's ID, the modern one is 0x1042
+const DEVICE_ID: u16 = 0x1001;
+
+const SECTOR_SIZE: usize = 512;
+
+// feature bits
+const FEATURE_READ_ONLY: u32 = 1 << 5;
+const FEATURE_FLUSH: u32 = 1 << 9;
+
+/// Device configuration: capacity, in 512-byte sectors
+const CONFIG_CAPACITY: u16 = 0;
+
+// request types
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+/// Status the device writes back when a request worked
+const STATUS_OK: u8 = 0;
+
+/// Where the status byte goes in a disk's request frame, after the header
+const STATUS_OFFSET: u64 = 16;
+
+const MAX_DISKS: usize = 8;
+const NAMES: [&str; MAX_DISKS] = ["vda", "vdb", "vdc", "vdd", "vde", "vdf", "vdg", "vdh"];
+
+/// Woken when any disk finishes a request
+static COMPLETED: WaitQueue = WaitQueue::new("virtio-blk");
+
+/// What a request does and where, read by the device
+#[repr(C)]
+struct Header {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// A virtio-blk device
+struct Disk {
+    name: &'static str,
+    transport: Transport,
+    queue: Mutex<Virtqueue>,
+    /// Holds the header and the status byte, for the request in flight
+    request: PhysAddr,
+    sectors: u64,
+    features: u32,
+}
+
+impl Disk {
+    fn read_only(&self) -> bool {
+        self.features & FEATURE_READ_ONLY != 0
+    }
+
+    /// Send a request, with `data` between its header and status, and wait for it
+    fn request(&self, kind: u32, sector: u64, data: &[Buffer]) -> Result<(), BlockError> {
+        let mut queue = self.queue.lock();
+        let header = phys_to_virt(self.request).as_mut_ptr::<Header>();
+        let status = phys_to_virt(self.request + STATUS_OFFSET).as_mut_ptr::<u8>();
+        unsafe {
+            header.write_volatile(Header {
+                kind,
+                reserved: 0,
+                sector,
+            });
+            status.write_volatile(0xff);
+        }
+
+        let mut buffers = [Buffer {
+            addr: self.request,
+            len: size_of::<Header>() as u32,
+            device_writes: false,
+        }; MAX_SEGMENTS + 2];
+        buffers[1..=data.len()].copy_from_slice(data);
+        buffers[data.len() + 1] = Buffer {
+            addr: self.request + STATUS_OFFSET,
+            len: 1,
+            device_writes: true,
+        };
+        let buffers = &buffers[..data.len() + 2];
+        queue.submit(buffers).ok_or(BlockError::Io)?;
+        self.transport.notify(0);
+
+        COMPLETED.wait_until(|| queue.has_used());
+        queue.pop_used();
+        match unsafe { status.read_volatile() } {
+            STATUS_OK => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    /// Move the sectors starting at `sector` to or from the buffer at `addr`, in requests of
+    /// as many pages as the queue has room for
+    fn transfer(
+        &self,
+        kind: u32,
+        sector: u64,
+        addr: VirtAddr,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        block::check(self, sector, len)?;
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(MAX_REQUEST);
+            let mut segments = [Buffer {
+                addr: PhysAddr::zero(),
+                len: 0,
+                device_writes: kind == REQUEST_IN,
+            }; MAX_SEGMENTS];
+            let mut count = 0;
+            let mut at = 0;
+            while at < chunk {
+                let virt = addr + (done + at) as u64;
+                let to_page_end = (FRAME_SIZE - virt.as_u64() % FRAME_SIZE) as usize;
+                let len = to_page_end.min(chunk - at);
+                let phys = virt_to_phys(virt).ok_or(BlockError::Io)?;
+                segments[count].addr = phys;
+                segments[count].len = len as u32;
+                count += 1;
+                at += len;
+            }
+            self.request(
+                kind,
+                sector + (done / SECTOR_SIZE) as u64,
+                &segments[..count],
+            )?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+/// The most bytes sent in one request, and the most pages those can be on
+const MAX_REQUEST: usize = 32 * FRAME_SIZE as usize;
+const MAX_SEGMENTS: usize = MAX_REQUEST / FRAME_SIZE as usize + 1;
+
+impl BlockDevice for Disk {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let addr = VirtAddr::from_ptr(buf.as_mut_ptr());
+        self.transfer(REQUEST_IN, sector, addr, buf.len())
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        self.transfer(
+            REQUEST_OUT,
+            sector,
+            VirtAddr::from_ptr(buf.as_ptr()),
+            buf.len(),
+        )
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        // without the feature, writes go straight through
+        if self.features & FEATURE_FLUSH == 0 {
+            return Ok(());
+        }
+        self.request(REQUEST_FLUSH, 0, &[])
+    }
+}
+
+impl fmt::Display for Disk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: at i/o {:#x}, {} MiB{}",
+            self.name,
+            self.transport.base,
+            self.sectors * SECTOR_SIZE as u64 / (1024 * 1024),
+            if self.read_only() { ", read only" } else { "" }
+        )
+    }
+}
+
+fn setup(device: &pci::Device, name: &'static str) -> Result<Disk, VirtioError> {
+    let transport = Transport::new(device)?;
+    let features = transport.negotiate(FEATURE_READ_ONLY | FEATURE_FLUSH);
+    let queue = transport.queue(0).and_then(|queue| {
+        if (queue.size() as usize) < MAX_SEGMENTS + 2 {
+            return Err(VirtioError::NoQueue(0));
+        }
+        let request = frame::allocate_contiguous(1).ok_or(VirtioError::NoMemory)?;
+        Ok((queue, request))
+    });
+    let (queue, request) = match queue {
+        Ok(queue) => queue,
+        Err(error) => {
+            transport.fail();
+            return Err(error);
+        }
+    };
+    if let Err(error) = super::set_handler(device, &transport, || COMPLETED.wake_all()) {
+        transport.fail();
+        return Err(error);
+    }
+    let sectors = transport.config_u64(CONFIG_CAPACITY);
+    transport.ready();
+
+    Ok(Disk {
+        name,
+        transport,
+        queue: Mutex::new("virtio-blk queue", queue),
+        request: request.start_address(),
+        sectors,
+        features,
+    })
+}
+
+fn init() {
+    let mut count = 0;
+    for device in pci::devices() {
+        if device.vendor_id != VENDOR_ID || device.device_id != DEVICE_ID {
+            continue;
+        }
+        if count == MAX_DISKS {
+            wlog!("virtio-blk: only {} disks are supported", MAX_DISKS);
+            break;
+        }
+        let name = NAMES[count];
+        let disk = match setup(&device, name) {
+            Ok(disk) => disk,
+            Err(error) => {
+                wlog!(
+                    "virtio-blk: couldn't set up {}: {:?}",
+                    device.address,
+                    error
+                );
+                continue;
+            }
+        };
+        ilog!("virtio-blk: {}", disk);
+        count += 1;
+        if let Err(error) = block::register(Box::leak(Box::new(disk))) {
+            wlog!("virtio-blk: couldn't register {}: {:?}", name, error);
+        }
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "virtio-blk",
+    stage: Stage::Drivers,
+    after: &["pci", "pic"],
+    func: init,
+};
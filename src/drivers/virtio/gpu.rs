@@ -0,0 +1,488 @@
+//! Virtio GPU
+//!
+//! [`INIT`] sets up the first virtio-gpu device on the PCI bus, asks it how big its display
+//! is, and moves the [framebuffer console](crate::gfx::console) over to it, at that size or
+//! the one `video=` asks for. From then on [`gfx::set_mode`] changes modes through here
+//! instead of the [`bochs`](crate::gfx::bochs) interface.
+//!
+//! The screen is a 2D resource on the host, backed by physically contiguous frames the kernel
+//! draws into directly. Nothing reaches the display until it's been told: the framebuffer
+//! calls [`present`] with what changed each time it's flushed, which copies that rectangle
+//! over to the resource and then flushes it to the scanout. A mode change makes a new
+//! resource, points the scanout at it, and only then drops the old one.
+//!
+//! Commands go on the control queue one at a time, the request and the response sharing a
+//! frame. The driver spins until the device is done with each one instead of waiting for its
+//! interrupt, since the console flushes with interrupts off.
+//!
+//! links:
+//! - virtio 1.1, 5.7 "GPU Device": <https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html>
+//!
+
+use core::ptr;
+
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use super::modern::{Transport, FEATURE_VERSION_1};
+use super::{Buffer, VirtioError, Virtqueue, VENDOR_ID};
+use crate::gfx::bochs::ModeError;
+use crate::gfx::{self, FramebufferInfo, PixelFormat, Rect};
+use crate::init::{InitCall, Stage};
+use crate::mem::frame::{self, FRAME_SIZE};
+use crate::mem::phys_to_virt;
+use crate::sync::SpinLock;
+use crate::time::{Duration, Instant};
+use crate::{cmdline, ilog, pci, wlog};
+
+/// virtio-gpu only has a modern interface, so there's no transitional ID
+const DEVICE_ID: u16 = 0x1050;
+
+/// Device configuration: how many scanouts there are
+const CONFIG_SCANOUTS: u64 = 8;
+
+// commands
+const GET_DISPLAY_INFO: u32 = 0x0100;
+const RESOURCE_CREATE_2D: u32 = 0x0101;
+const RESOURCE_UNREF: u32 = 0x0102;
+const SET_SCANOUT: u32 = 0x0103;
+const RESOURCE_FLUSH: u32 = 0x0104;
+const TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const RESOURCE_DETACH_BACKING: u32 = 0x0107;
+
+// responses
+const OK_NODATA: u32 = 0x1100;
+const OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// Blue, green, red, and a byte that's ignored, which is [`PixelFormat::Bgrx8888`]
+const FORMAT_B8G8R8X8: u32 = 2;
+const BYTES_PER_PIXEL: usize = 4;
+
+/// Displays [`GET_DISPLAY_INFO`] reports on
+const MAX_SCANOUTS: usize = 16;
+/// The only scanout the console goes on
+const SCANOUT: u32 = 0;
+
+/// What the display is when the device doesn't say
+const DEFAULT_MODE: (usize, usize) = (1024, 768);
+
+/// Where the response goes in the command frame, after the request
+const RESPONSE_OFFSET: u64 = 2048;
+
+/// How long the device gets to finish a command
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Starts every request and response
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Header {
+    kind: u32,
+    flags: u32,
+    fence: u64,
+    context: u32,
+    padding: u32,
+}
+
+impl Header {
+    const fn new(kind: u32) -> Header {
+        Header {
+            kind,
+            flags: 0,
+            fence: 0,
+            context: 0,
+            padding: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpuRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl From<Rect> for GpuRect {
+    fn from(rect: Rect) -> GpuRect {
+        GpuRect {
+            x: rect.x as u32,
+            y: rect.y as u32,
+            width: rect.width as u32,
+            height: rect.height as u32,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayMode {
+    rect: GpuRect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayInfo {
+    header: Header,
+    modes: [DisplayMode; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+struct ResourceCreate2d {
+    header: Header,
+    resource: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Gives a resource backing pages, here always one run of them
+#[repr(C)]
+struct AttachBacking {
+    header: Header,
+    resource: u32,
+    entries: u32,
+    addr: u64,
+    len: u32,
+    padding: u32,
+}
+
+/// Also what [`RESOURCE_DETACH_BACKING`] takes
+#[repr(C)]
+struct ResourceUnref {
+    header: Header,
+    resource: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct SetScanout {
+    header: Header,
+    rect: GpuRect,
+    scanout: u32,
+    resource: u32,
+}
+
+#[repr(C)]
+struct TransferToHost2d {
+    header: Header,
+    rect: GpuRect,
+    /// Where the rectangle starts in the backing
+    offset: u64,
+    resource: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct ResourceFlush {
+    header: Header,
+    rect: GpuRect,
+    resource: u32,
+    padding: u32,
+}
+
+/// The resource on the scanout
+struct Screen {
+    resource: u32,
+    width: usize,
+    height: usize,
+    backing: PhysFrame,
+    frames: u64,
+}
+
+struct Gpu {
+    transport: Transport,
+    control: Virtqueue,
+    /// Holds the request and the response of the command in flight
+    command: PhysAddr,
+    screen: Option<Screen>,
+}
+
+impl Gpu {
+    /// Send `request` and wait for the response, of type `R`, which comes back if the device
+    /// answered with `expected`. Otherwise it's the response type it did answer with, or 0 if
+    /// it never answered.
+    fn command<T, R: Copy>(&mut self, request: T, expected: u32) -> Result<R, u32> {
+        const _: () = assert!(size_of::<DisplayInfo>() as u64 <= FRAME_SIZE - RESPONSE_OFFSET);
+        let response = self.command + RESPONSE_OFFSET;
+        unsafe {
+            phys_to_virt(self.command)
+                .as_mut_ptr::<T>()
+                .write_volatile(request);
+            phys_to_virt(response)
+                .as_mut_ptr::<Header>()
+                .write_volatile(Header::new(0));
+        }
+        let buffers = [
+            Buffer {
+                addr: self.command,
+                len: size_of::<T>() as u32,
+                device_writes: false,
+            },
+            Buffer {
+                addr: response,
+                len: size_of::<R>().max(size_of::<Header>()) as u32,
+                device_writes: true,
+            },
+        ];
+        self.control.submit(&buffers).ok_or(0u32)?;
+        self.transport.notify(0);
+
+        let start = Instant::now();
+        while !self.control.has_used() {
+            if start.elapsed() > TIMEOUT {
+                // the descriptors stay the device's, so the queue's no use from here on
+                return Err(0);
+            }
+            core::hint::spin_loop();
+        }
+        self.control.pop_used();
+        let kind = unsafe { ptr::read_volatile(phys_to_virt(response).as_ptr::<Header>()) }.kind;
+        if kind != expected {
+            return Err(kind);
+        }
+        Ok(unsafe { ptr::read_volatile(phys_to_virt(response).as_ptr::<R>()) })
+    }
+
+    /// Send a command that only gets a header back
+    fn simple<T>(&mut self, request: T) -> Result<(), u32> {
+        self.command::<T, Header>(request, OK_NODATA).map(|_| ())
+    }
+
+    /// The size of the scanout the console goes on, if the device says it's enabled
+    fn display_size(&mut self) -> Option<(usize, usize)> {
+        let info: DisplayInfo = self
+            .command(Header::new(GET_DISPLAY_INFO), OK_DISPLAY_INFO)
+            .ok()?;
+        let mode = info.modes[SCANOUT as usize];
+        (mode.enabled != 0 && mode.rect.width != 0 && mode.rect.height != 0)
+            .then_some((mode.rect.width as usize, mode.rect.height as usize))
+    }
+
+    /// Make a `width` by `height` resource with fresh backing and put it on the scanout, then
+    /// drop the one that was there
+    fn set_mode(&mut self, width: usize, height: usize) -> Result<FramebufferInfo, ModeError> {
+        let frames = ((width * height * BYTES_PER_PIXEL) as u64).div_ceil(FRAME_SIZE);
+        let backing = frame::allocate_contiguous(frames).ok_or(ModeError::Failed)?;
+        // resources 1 and 2 take turns, so the old one's still around while the new is set up
+        let resource = match &self.screen {
+            Some(screen) if screen.resource == 1 => 2,
+            _ => 1,
+        };
+        let screen = Screen {
+            resource,
+            width,
+            height,
+            backing,
+            frames,
+        };
+
+        let rect = GpuRect::from(Rect::new(0, 0, width, height));
+        let result = self
+            .simple(ResourceCreate2d {
+                header: Header::new(RESOURCE_CREATE_2D),
+                resource,
+                format: FORMAT_B8G8R8X8,
+                width: width as u32,
+                height: height as u32,
+            })
+            .and_then(|()| {
+                self.simple(AttachBacking {
+                    header: Header::new(RESOURCE_ATTACH_BACKING),
+                    resource,
+                    entries: 1,
+                    addr: backing.start_address().as_u64(),
+                    len: (width * height * BYTES_PER_PIXEL) as u32,
+                    padding: 0,
+                })
+            })
+            .and_then(|()| {
+                self.simple(SetScanout {
+                    header: Header::new(SET_SCANOUT),
+                    rect,
+                    scanout: SCANOUT,
+                    resource,
+                })
+            });
+        if let Err(response) = result {
+            wlog!(
+                "virtio-gpu: {}x{} didn't work, the device said {:#x}",
+                width,
+                height,
+                response
+            );
+            self.release(screen);
+            return Err(ModeError::Failed);
+        }
+
+        if let Some(old) = self.screen.replace(screen) {
+            self.release(old);
+        }
+        let phys_addr = backing.start_address();
+        Ok(FramebufferInfo {
+            phys_addr,
+            virt_addr: phys_to_virt(phys_addr),
+            width,
+            height,
+            pitch: width * BYTES_PER_PIXEL,
+            bpp: BYTES_PER_PIXEL * 8,
+            format: PixelFormat::Bgrx8888,
+            present: Some(present),
+        })
+    }
+
+    /// Get rid of `screen`'s resource and give back its backing. A resource that was never
+    /// made just gets errors back.
+    fn release(&mut self, screen: Screen) {
+        for kind in [RESOURCE_DETACH_BACKING, RESOURCE_UNREF] {
+            let _ = self.simple(ResourceUnref {
+                header: Header::new(kind),
+                resource: screen.resource,
+                padding: 0,
+            });
+        }
+        for i in 0..screen.frames {
+            // the device is done with it once the backing is detached
+            unsafe { frame::deallocate_frame(screen.backing + i) };
+        }
+    }
+
+    /// Copy `rect` of the backing to the resource and show it
+    fn present(&mut self, rect: Rect) -> Result<(), u32> {
+        let Some(screen) = &self.screen else {
+            return Ok(());
+        };
+        let Some(rect) = rect.clip(screen.width, screen.height) else {
+            return Ok(());
+        };
+        let resource = screen.resource;
+        let offset = (rect.y * screen.width + rect.x) * BYTES_PER_PIXEL;
+        self.simple(TransferToHost2d {
+            header: Header::new(TRANSFER_TO_HOST_2D),
+            rect: rect.into(),
+            offset: offset as u64,
+            resource,
+            padding: 0,
+        })?;
+        self.simple(ResourceFlush {
+            header: Header::new(RESOURCE_FLUSH),
+            rect: rect.into(),
+            resource,
+            padding: 0,
+        })
+    }
+}
+
+/// Only locked with interrupts off, the console flushes from interrupt handlers
+static GPU: SpinLock<Option<Gpu>> = SpinLock::new("virtio-gpu", None);
+
+/// Whether there's a virtio-gpu to set modes on
+pub fn is_present() -> bool {
+    interrupts::without_interrupts(|| GPU.lock().is_some())
+}
+
+/// Show a `width` by `height` framebuffer, returning where it is. The depth has to be 32 bpp.
+pub fn set_mode(width: usize, height: usize, bpp: usize) -> Result<FramebufferInfo, ModeError> {
+    if width == 0 || height == 0 || bpp != BYTES_PER_PIXEL * 8 {
+        return Err(ModeError::Unsupported);
+    }
+    interrupts::without_interrupts(|| {
+        GPU.lock()
+            .as_mut()
+            .ok_or(ModeError::NoDevice)?
+            .set_mode(width, height)
+    })
+}
+
+/// The framebuffer's [`present`](FramebufferInfo::present) hook
+fn present(rect: Rect) {
+    interrupts::without_interrupts(|| {
+        if let Some(gpu) = GPU.lock().as_mut() {
+            // there's nowhere to report it, the console is what would show it
+            let _ = gpu.present(rect);
+        }
+    });
+}
+
+fn setup(device: &pci::Device) -> Result<Gpu, VirtioError> {
+    let mut transport = Transport::new(device)?;
+    let queue = transport
+        .negotiate(FEATURE_VERSION_1)
+        .and_then(|_| transport.queue(0))
+        .and_then(|queue| {
+            let command = frame::allocate_frame().ok_or(VirtioError::NoMemory)?;
+            Ok((queue, command))
+        });
+    let (mut control, command) = match queue {
+        Ok(queue) => queue,
+        Err(error) => {
+            transport.fail();
+            return Err(error);
+        }
+    };
+    control.disable_interrupts();
+    transport.ready();
+
+    Ok(Gpu {
+        transport,
+        control,
+        command: command.start_address(),
+        screen: None,
+    })
+}
+
+fn init() {
+    let Some(device) = pci::find(VENDOR_ID, DEVICE_ID) else {
+        return;
+    };
+    let mut gpu = match setup(&device) {
+        Ok(gpu) => gpu,
+        Err(error) => {
+            wlog!(
+                "virtio-gpu: couldn't set up {}: {:?}",
+                device.address,
+                error
+            );
+            return;
+        }
+    };
+    let scanouts = gpu.transport.config_u32(CONFIG_SCANOUTS);
+    let display = gpu.display_size();
+    interrupts::without_interrupts(|| *GPU.lock() = Some(gpu));
+
+    let (width, height, bpp) = cmdline::get("video")
+        .and_then(gfx::parse_mode)
+        .unwrap_or_else(|| {
+            let (width, height) = display.unwrap_or(DEFAULT_MODE);
+            (width, height, BYTES_PER_PIXEL * 8)
+        });
+    if let Err(error) = gfx::set_mode(width, height, bpp) {
+        wlog!(
+            "virtio-gpu: can't show {}x{}x{}: {:?}",
+            width,
+            height,
+            bpp,
+            error
+        );
+        return;
+    }
+    ilog!(
+        "virtio-gpu: {}, {} scanouts, console at {}x{}",
+        device.address,
+        scanouts,
+        width,
+        height
+    );
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "virtio-gpu",
+    stage: Stage::Drivers,
+    after: &["pci"],
+    func: init,
+};
@@ -0,0 +1,436 @@
+//! Virtio devices on PCI
+//!
+//! Most of QEMU's virtio devices are transitional, so besides the modern capability-based
+//! interface they have the legacy one: every register in the I/O space behind BAR 0. That's
+//! the one [`Transport`] uses, it needs no MMIO mappings or capability walking. Devices newer
+//! than virtio 1.0, like the GPU, only have the modern one, which [`modern::Transport`] finds
+//! through their vendor capabilities.
+//!
+//! The driver and the device talk through [`Virtqueue`]s in memory they share. The driver
+//! puts a chain of buffers in the available ring and notifies the device, which fills in the
+//! ones it's meant to write and puts the chain on the used ring when it's done, then raises
+//! its interrupt. Devices can share an interrupt line, so there's one handler for all of them
+//! that asks each device whether it was the one, see [`set_handler`].
+//!
+//! links:
+//! - <https://wiki.osdev.org/Virtio>
+//! - virtio 1.1, 2.6 "Split Virtqueues" and 4.1.4.8 "Legacy Interfaces: A Note on PCI Device
+//!   Layout": <https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html>
+//!
+
+pub mod blk;
+pub mod gpu;
+pub mod modern;
+pub mod net;
+
+use core::ptr;
+use core::sync::atomic::{fence, AtomicU16, AtomicUsize, Ordering};
+
+use x86_64::PhysAddr;
+
+use crate::arch::io::Port;
+use crate::mem::frame::{self, FRAME_SIZE};
+use crate::mem::paging::MapError;
+use crate::mem::phys_to_virt;
+use crate::pci::{self, Bar};
+use crate::pic;
+
+/// Red Hat's PCI vendor ID, which every virtio device has
+pub const VENDOR_ID: u16 = 0x1af4;
+
+// legacy registers, as offsets into the I/O BAR
+const DEVICE_FEATURES: u16 = 0x00;
+const DRIVER_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0c;
+const QUEUE_SELECT: u16 = 0x0e;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const ISR_STATUS: u16 = 0x13;
+/// Where the device's own configuration starts, without MSI-X
+const DEVICE_CONFIG: u16 = 0x14;
+
+// device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FAILED: u8 = 1 << 7;
+
+/// ISR status: the used ring has something new
+const ISR_QUEUE: u8 = 1 << 0;
+
+// descriptor flags
+const DESC_NEXT: u16 = 1 << 0;
+const DESC_WRITE: u16 = 1 << 1;
+
+/// Available ring flag: don't interrupt when something's put on the used ring
+const AVAILABLE_NO_INTERRUPT: u16 = 1 << 0;
+
+/// The legacy interface wants the rings aligned to this
+const QUEUE_ALIGN: u64 = 4096;
+
+/// Devices that can have interrupt handlers, across every kind
+const MAX_DEVICES: usize = 16;
+
+/// Each device's I/O base, 0 for a free slot, and the handler for it as a `fn()` pointer
+static HANDLERS: [(AtomicU16, AtomicUsize); MAX_DEVICES] =
+    [const { (AtomicU16::new(0), AtomicUsize::new(0)) }; MAX_DEVICES];
+
+/// Why a virtio device couldn't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// BAR 0 isn't an I/O BAR, so there's no legacy interface
+    NoLegacyInterface,
+    /// The device doesn't have the queue
+    NoQueue(u16),
+    /// Its interrupt isn't routed to a legacy IRQ, or there's no room for another handler
+    NoInterrupt,
+    /// There wasn't enough contiguous memory for a queue
+    NoMemory,
+    /// A modern device is missing one of the capabilities that say where its registers are
+    NoCapability(u8),
+    /// The device didn't take the features the driver asked for
+    Features,
+    /// Its registers couldn't be mapped
+    Map(MapError),
+}
+
+impl From<MapError> for VirtioError {
+    fn from(error: MapError) -> VirtioError {
+        VirtioError::Map(error)
+    }
+}
+
+/// A device's legacy registers
+pub struct Transport {
+    base: u16,
+}
+
+impl Transport {
+    /// Reset `device` and tell it a driver's found it
+    pub fn new(device: &pci::Device) -> Result<Transport, VirtioError> {
+        let Some(Bar::Io { port, .. }) = device.bars[0] else {
+            return Err(VirtioError::NoLegacyInterface);
+        };
+        device.enable();
+        let transport = Transport { base: port };
+        transport.status().write(0);
+        transport.status().write(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(transport)
+    }
+
+    fn port<T>(&self, offset: u16) -> Port<T> {
+        // the device's own registers, which only its driver touches
+        unsafe { Port::new(self.base + offset) }
+    }
+
+    fn status(&self) -> Port<u8> {
+        self.port(DEVICE_STATUS)
+    }
+
+    /// Accept the features in `wanted` that the device has, returning those
+    pub fn negotiate(&self, wanted: u32) -> u32 {
+        let features = self.port::<u32>(DEVICE_FEATURES).read() & wanted;
+        self.port::<u32>(DRIVER_FEATURES).write(features);
+        features
+    }
+
+    /// Tell the device the driver's set up, its queues can be used from now on
+    pub fn ready(&self) {
+        self.status().modify(|status| status | STATUS_DRIVER_OK);
+    }
+
+    /// Tell the device the driver gave up on it
+    pub fn fail(&self) {
+        self.status().modify(|status| status | STATUS_FAILED);
+    }
+
+    /// Whether the device raised its interrupt for a used ring. Reading it lowers the interrupt.
+    pub fn take_interrupt(&self) -> bool {
+        self.port::<u8>(ISR_STATUS).read() & ISR_QUEUE != 0
+    }
+
+    /// Tell the device there's something new in queue `index`'s available ring
+    pub fn notify(&self, index: u16) {
+        self.port::<u16>(QUEUE_NOTIFY).write(index);
+    }
+
+    /// Read the byte `offset` bytes into the device's configuration
+    pub fn config_u8(&self, offset: u16) -> u8 {
+        self.port::<u8>(DEVICE_CONFIG + offset).read()
+    }
+
+    /// Read the 32-bit field `offset` bytes into the device's configuration
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        self.port::<u32>(DEVICE_CONFIG + offset).read()
+    }
+
+    /// Read the 64-bit field `offset` bytes into the device's configuration, as two halves
+    pub fn config_u64(&self, offset: u16) -> u64 {
+        self.config_u32(offset) as u64 | (self.config_u32(offset + 4) as u64) << 32
+    }
+
+    /// Set up queue `index`
+    pub fn queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        self.port::<u16>(QUEUE_SELECT).write(index);
+        let size = self.port::<u16>(QUEUE_SIZE).read();
+        if size == 0 {
+            return Err(VirtioError::NoQueue(index));
+        }
+
+        let queue = Virtqueue::new(size)?;
+        self.port::<u32>(QUEUE_ADDRESS)
+            .write((queue.phys.as_u64() / QUEUE_ALIGN) as u32);
+        Ok(queue)
+    }
+}
+
+/// Every virtio device's interrupt
+fn interrupt() {
+    for (base, handler) in &HANDLERS {
+        let (base, handler) = (
+            base.load(Ordering::Relaxed),
+            handler.load(Ordering::Acquire),
+        );
+        // every device's ISR is read, since they can share the line
+        if handler != 0 && (Transport { base }).take_interrupt() {
+            // only ever stored from a `fn()`
+            let handler: fn() = unsafe { core::mem::transmute(handler) };
+            handler();
+        }
+    }
+}
+
+/// Call `handler` whenever the device behind `transport`, which is `device` on the PCI bus,
+/// raises its interrupt. It runs in the interrupt handler.
+pub fn set_handler(
+    device: &pci::Device,
+    transport: &Transport,
+    handler: fn(),
+) -> Result<(), VirtioError> {
+    let irq = device.irq();
+    if irq >= pic::IRQ_COUNT {
+        return Err(VirtioError::NoInterrupt);
+    }
+    let slot = HANDLERS
+        .iter()
+        .find(|(base, _)| {
+            base.compare_exchange(0, transport.base, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        })
+        .ok_or(VirtioError::NoInterrupt)?;
+    // the base has to be there before the interrupt can see the handler
+    slot.1.store(handler as usize, Ordering::Release);
+    pic::set_handler(irq, interrupt);
+    Ok(())
+}
+
+/// Where the parts of a legacy queue with `size` entries go, from its start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Layout {
+    available: u64,
+    used: u64,
+    size: u64,
+}
+
+impl Layout {
+    fn new(size: u16) -> Layout {
+        let size = size as u64;
+        let available = 16 * size;
+        // flags, index, the ring, and the used event
+        let used = (available + 2 * (3 + size)).next_multiple_of(QUEUE_ALIGN);
+        // flags, index, and the ring of id and length pairs, and the available event
+        let end = used + 6 + 8 * size;
+        Layout {
+            available,
+            used,
+            size: end.next_multiple_of(QUEUE_ALIGN),
+        }
+    }
+}
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct Ring {
+    flags: u16,
+    index: u16,
+}
+
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/// Part of a request: `len` bytes of physical memory at `addr`, which the device writes if
+/// `device_writes`, and only reads otherwise
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    pub device_writes: bool,
+}
+
+/// A queue shared with the device
+pub struct Virtqueue {
+    size: u16,
+    /// Where the descriptors start, the rest follows as [`Layout`] says
+    phys: PhysAddr,
+    layout: Layout,
+    descriptors: *mut Descriptor,
+    /// Followed by `size` descriptor indexes
+    available: *mut Ring,
+    /// Followed by `size` [`UsedElement`]s
+    used: *mut Ring,
+    /// The first free descriptor, the others are chained from it by `next`
+    free: u16,
+    free_count: u16,
+    /// How far into the used ring the driver's got
+    last_used: u16,
+}
+
+// the rings are only touched through the queue, by whoever holds it
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    /// A queue of `size` entries in zeroed, physically contiguous memory, laid out the way the
+    /// legacy interface wants so either transport can use it
+    fn new(size: u16) -> Result<Virtqueue, VirtioError> {
+        let layout = Layout::new(size);
+        let frames = layout.size.div_ceil(FRAME_SIZE);
+        let first = frame::allocate_contiguous(frames).ok_or(VirtioError::NoMemory)?;
+        let phys = first.start_address();
+        let base = phys_to_virt(phys).as_mut_ptr::<u8>();
+        unsafe { base.write_bytes(0, (frames * FRAME_SIZE) as usize) };
+
+        let queue = Virtqueue {
+            size,
+            phys,
+            layout,
+            descriptors: base.cast(),
+            available: unsafe { base.add(layout.available as usize) }.cast(),
+            used: unsafe { base.add(layout.used as usize) }.cast(),
+            free: 0,
+            free_count: size,
+            last_used: 0,
+        };
+        for i in 0..size {
+            unsafe { (*queue.descriptors.add(i as usize)).next = i + 1 };
+        }
+        Ok(queue)
+    }
+
+    /// Physical addresses of the descriptors, the available ring, and the used ring
+    fn addresses(&self) -> (PhysAddr, PhysAddr, PhysAddr) {
+        (
+            self.phys,
+            self.phys + self.layout.available,
+            self.phys + self.layout.used,
+        )
+    }
+
+    /// Ask the device not to interrupt for this queue, for a driver that polls [`has_used`]
+    /// instead. It's only a hint, the device can still send one.
+    ///
+    /// [`has_used`]: Virtqueue::has_used
+    pub fn disable_interrupts(&mut self) {
+        unsafe { ptr::write_volatile(&mut (*self.available).flags, AVAILABLE_NO_INTERRUPT) };
+    }
+
+    /// Entries in the queue, also the most buffers a request can have
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Put a request made of `buffers` on the available ring, returning the ID it comes back
+    /// with. None if there aren't enough free descriptors for it. The device doesn't see it
+    /// until it's notified.
+    pub fn submit(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+
+        let head = self.free;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let descriptor = unsafe { &mut *self.descriptors.add(index as usize) };
+            let next = descriptor.next;
+            descriptor.addr = buffer.addr.as_u64();
+            descriptor.len = buffer.len;
+            descriptor.flags = if buffer.device_writes { DESC_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                descriptor.flags |= DESC_NEXT;
+                index = next;
+            } else {
+                self.free = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        unsafe {
+            let ring = self.available.add(1).cast::<u16>();
+            let at = ptr::read_volatile(&(*self.available).index);
+            ptr::write_volatile(ring.add((at % self.size) as usize), head);
+            // the device mustn't see the new index before the entry it covers
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(&mut (*self.available).index, at.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// Whether the device has put something on the used ring the driver hasn't taken yet
+    pub fn has_used(&self) -> bool {
+        unsafe { ptr::read_volatile(&(*self.used).index) != self.last_used }
+    }
+
+    /// Take the next request off the used ring, with how many bytes the device wrote into it,
+    /// and free its descriptors
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        // the entry is written before the index
+        fence(Ordering::SeqCst);
+        let element = unsafe {
+            let ring = self.used.add(1).cast::<UsedElement>();
+            ptr::read_volatile(ring.add((self.last_used % self.size) as usize))
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let head = element.id as u16;
+        let mut index = head;
+        loop {
+            let descriptor = unsafe { &mut *self.descriptors.add(index as usize) };
+            self.free_count += 1;
+            if descriptor.flags & DESC_NEXT == 0 {
+                descriptor.next = self.free;
+                break;
+            }
+            index = descriptor.next;
+        }
+        self.free = head;
+        Some((head, element.len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn legacy_layout() {
+        // QEMU's usual queue size, worked out by hand from the spec
+        let layout = Layout::new(128);
+        assert_eq!(layout.available, 2048);
+        assert_eq!(layout.used, 4096);
+        assert_eq!(layout.size, 8192);
+    }
+}
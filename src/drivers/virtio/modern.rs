@@ -0,0 +1,204 @@
+//! The modern virtio PCI interface
+//!
+//! A modern device's registers are in its memory BARs, and vendor specific capabilities say
+//! which BAR and where: the common configuration, with the feature bits, status and queue
+//! registers, the notification area, and the device's own configuration. [`Transport::new`]
+//! maps each of them. Features are 64 bits here, and the driver has to accept
+//! [`FEATURE_VERSION_1`] or the device takes it for a legacy driver.
+//!
+//! links:
+//! - virtio 1.1, 4.1.4 "Virtio Structure PCI Capabilities": <https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html>
+//!
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{VirtioError, Virtqueue};
+use crate::arch::io::Mmio;
+use crate::mem::paging;
+use crate::pci::{self, Bar};
+
+/// The device follows virtio 1.0 or later, not the legacy interface
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// PCI capability ID of the ones that describe the registers
+const CAPABILITY_VENDOR: u8 = 0x09;
+
+// what each capability describes
+const CONFIG_COMMON: u8 = 1;
+const CONFIG_NOTIFY: u8 = 2;
+const CONFIG_DEVICE: u8 = 4;
+
+// common configuration registers
+const DEVICE_FEATURE_SELECT: u64 = 0x00;
+const DEVICE_FEATURE: u64 = 0x04;
+const DRIVER_FEATURE_SELECT: u64 = 0x08;
+const DRIVER_FEATURE: u64 = 0x0c;
+const DEVICE_STATUS: u64 = 0x14;
+const QUEUE_SELECT: u64 = 0x16;
+const QUEUE_SIZE: u64 = 0x18;
+const QUEUE_ENABLE: u64 = 0x1c;
+const QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const QUEUE_DESC: u64 = 0x20;
+const QUEUE_DRIVER: u64 = 0x28;
+const QUEUE_DEVICE: u64 = 0x30;
+
+/// Status bit: the driver is happy with the features, which the device clears if it isn't
+const STATUS_FEATURES_OK: u8 = 1 << 3;
+
+/// Queues a device can have set up
+const MAX_QUEUES: usize = 4;
+
+/// A modern device's registers
+pub struct Transport {
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_multiplier: u32,
+    /// Each queue's offset into the notification area, in units of the multiplier
+    notify_offsets: [u16; MAX_QUEUES],
+    device: Option<VirtAddr>,
+}
+
+impl Transport {
+    /// Map the registers of `device`, reset it, and tell it a driver's found it
+    pub fn new(device: &pci::Device) -> Result<Transport, VirtioError> {
+        let mut common = None;
+        let mut notify = None;
+        let mut config = None;
+        for (at, id) in device.capabilities() {
+            if id != CAPABILITY_VENDOR {
+                continue;
+            }
+            let address = device.address;
+            let (kind, bar) = (address.read_u8(at + 3), address.read_u8(at + 4));
+            let Some(Some(Bar::Memory { address: base, .. })) = device.bars.get(bar as usize)
+            else {
+                continue;
+            };
+            let (offset, length) = (address.read(at + 8), address.read(at + 12));
+            let slot = match kind {
+                CONFIG_COMMON => &mut common,
+                CONFIG_NOTIFY => &mut notify,
+                CONFIG_DEVICE => &mut config,
+                _ => continue,
+            };
+            // the first of each kind is the one to use
+            if slot.is_none() {
+                let phys = PhysAddr::new(base + offset as u64);
+                let virt = unsafe { paging::map_mmio(phys, length as u64)? };
+                let multiplier = if kind == CONFIG_NOTIFY {
+                    address.read(at + 16)
+                } else {
+                    0
+                };
+                *slot = Some((virt, multiplier));
+            }
+        }
+        let (common, _) = common.ok_or(VirtioError::NoCapability(CONFIG_COMMON))?;
+        let (notify, notify_multiplier) = notify.ok_or(VirtioError::NoCapability(CONFIG_NOTIFY))?;
+
+        device.enable();
+        let transport = Transport {
+            common,
+            notify,
+            notify_multiplier,
+            notify_offsets: [0; MAX_QUEUES],
+            device: config.map(|(virt, _)| virt),
+        };
+        let status = transport.status();
+        status.write(0);
+        // the reset is done once it reads back 0
+        while status.read() != 0 {
+            core::hint::spin_loop();
+        }
+        status.write(super::STATUS_ACKNOWLEDGE | super::STATUS_DRIVER);
+        Ok(transport)
+    }
+
+    fn register<T: Copy>(&self, offset: u64) -> Mmio<T> {
+        // inside the common configuration, which new mapped
+        unsafe { Mmio::new(self.common + offset) }
+    }
+
+    fn status(&self) -> Mmio<u8> {
+        self.register(DEVICE_STATUS)
+    }
+
+    /// Accept the features in `wanted` that the device has, which has to include
+    /// [`FEATURE_VERSION_1`], returning those
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, VirtioError> {
+        let mut features = 0;
+        for half in 0..2 {
+            self.register::<u32>(DEVICE_FEATURE_SELECT).write(half);
+            features |= (self.register::<u32>(DEVICE_FEATURE).read() as u64) << (32 * half);
+        }
+        features &= wanted;
+        if features & FEATURE_VERSION_1 == 0 {
+            return Err(VirtioError::Features);
+        }
+        for half in 0..2 {
+            self.register::<u32>(DRIVER_FEATURE_SELECT).write(half);
+            self.register::<u32>(DRIVER_FEATURE)
+                .write((features >> (32 * half)) as u32);
+        }
+
+        self.status().modify(|status| status | STATUS_FEATURES_OK);
+        if self.status().read() & STATUS_FEATURES_OK == 0 {
+            return Err(VirtioError::Features);
+        }
+        Ok(features)
+    }
+
+    /// Tell the device the driver's set up, its queues can be used from now on
+    pub fn ready(&self) {
+        self.status()
+            .modify(|status| status | super::STATUS_DRIVER_OK);
+    }
+
+    /// Tell the device the driver gave up on it
+    pub fn fail(&self) {
+        self.status().modify(|status| status | super::STATUS_FAILED);
+    }
+
+    /// Tell the device there's something new in queue `index`'s available ring
+    pub fn notify(&self, index: u16) {
+        let offset = self.notify_offsets[index as usize] as u64 * self.notify_multiplier as u64;
+        // set up by queue, inside the notification area
+        unsafe { Mmio::<u16>::new(self.notify + offset) }.write(index);
+    }
+
+    /// Read the 32-bit field `offset` bytes into the device's configuration, 0 if it has none
+    pub fn config_u32(&self, offset: u64) -> u32 {
+        match self.device {
+            Some(config) => unsafe { Mmio::<u32>::new(config + offset) }.read(),
+            None => 0,
+        }
+    }
+
+    /// Set up queue `index`
+    pub fn queue(&mut self, index: u16) -> Result<Virtqueue, VirtioError> {
+        if index as usize >= MAX_QUEUES {
+            return Err(VirtioError::NoQueue(index));
+        }
+        self.register::<u16>(QUEUE_SELECT).write(index);
+        let size = self.register::<u16>(QUEUE_SIZE).read();
+        if size == 0 {
+            return Err(VirtioError::NoQueue(index));
+        }
+
+        let queue = Virtqueue::new(size)?;
+        let (descriptors, available, used) = queue.addresses();
+        for (offset, addr) in [
+            (QUEUE_DESC, descriptors),
+            (QUEUE_DRIVER, available),
+            (QUEUE_DEVICE, used),
+        ] {
+            // 64-bit fields can be written as two halves, the low one first
+            self.register::<u32>(offset).write(addr.as_u64() as u32);
+            self.register::<u32>(offset + 4)
+                .write((addr.as_u64() >> 32) as u32);
+        }
+        self.notify_offsets[index as usize] = self.register::<u16>(QUEUE_NOTIFY_OFF).read();
+        self.register::<u16>(QUEUE_ENABLE).write(1);
+        Ok(queue)
+    }
+}
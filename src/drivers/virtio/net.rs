@@ -0,0 +1,282 @@
+//! Virtio network cards
+//!
+//! [`INIT`] sets up every virtio-net device on the PCI bus and registers it as a
+//! [`NetDevice`], named `eth0`, `eth1`, and so on in the order they're found. A card has a
+//! receive queue and a transmit queue, and every frame on either comes after a small header
+//! that's only used for offloads, which aren't turned on, so it's left zero.
+//!
+//! The receive queue is kept full of page-sized buffers. When frames come in, the interrupt
+//! tells [`net`](crate::net) there's something to receive, and [`NetDevice::receive`] copies
+//! the next one out and gives its buffer straight back to the card. Sending copies the frame
+//! into one of [`TX_BUFFERS`] buffers, and those are taken back off the used ring the next
+//! time something's sent.
+//!
+//! links:
+//! - virtio 1.1, 5.1 "Network Device": <https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html>
+//!
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+
+use super::{Buffer, Transport, VirtioError, Virtqueue, VENDOR_ID};
+use crate::init::{InitCall, Stage};
+use crate::mem::frame;
+use crate::mem::phys_to_virt;
+use crate::net::{self, MacAddress, NetDevice, NetError, MAX_FRAME};
+use crate::sync::SpinLock;
+use crate::{ilog, pci, wlog};
+
+/// The transitional device's ID, the modern one is 0x1041
+const DEVICE_ID: u16 = 0x1000;
+
+/// Feature bit: the device's configuration has its MAC address
+const FEATURE_MAC: u32 = 1 << 5;
+
+/// Device configuration: the MAC address
+const CONFIG_MAC: u16 = 0;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+/// The legacy header, without the merged buffer count that'd need another feature
+const HEADER_LEN: usize = 10;
+/// Where a frame goes in its buffer page, after the header. Without `VIRTIO_F_ANY_LAYOUT` a
+/// legacy device wants the header in a descriptor of its own.
+const FRAME_OFFSET: u64 = 16;
+
+/// Receive buffers kept on the queue, one page each
+const RX_BUFFERS: usize = 32;
+/// Frames that can be waiting to be sent
+pub const TX_BUFFERS: usize = 16;
+
+const MAX_CARDS: usize = 4;
+const NAMES: [&str; MAX_CARDS] = ["eth0", "eth1", "eth2", "eth3"];
+
+/// A queue and the page behind each request on it, by the ID the request has
+struct Queue {
+    queue: Virtqueue,
+    pages: Vec<Option<PhysAddr>>,
+}
+
+impl Queue {
+    fn new(queue: Virtqueue) -> Queue {
+        let pages = vec![None; queue.size() as usize];
+        Queue { queue, pages }
+    }
+
+    /// Put the page at `page` on the queue, as a header and `len` bytes of frame after it
+    fn submit(&mut self, page: PhysAddr, len: usize, device_writes: bool) -> bool {
+        let buffers = [
+            Buffer {
+                addr: page,
+                len: HEADER_LEN as u32,
+                device_writes,
+            },
+            Buffer {
+                addr: page + FRAME_OFFSET,
+                len: len as u32,
+                device_writes,
+            },
+        ];
+        match self.queue.submit(&buffers) {
+            Some(id) => {
+                self.pages[id as usize] = Some(page);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The next page the device is done with, and how many bytes it wrote to it
+    fn pop_used(&mut self) -> Option<(PhysAddr, usize)> {
+        let (id, len) = self.queue.pop_used()?;
+        let page = self.pages[id as usize].take()?;
+        Some((page, len as usize))
+    }
+}
+
+struct Transmit {
+    queue: Queue,
+    /// Pages not on the queue, with room for all of them so nothing's allocated when sending
+    free: Vec<PhysAddr>,
+}
+
+/// A virtio-net device. Both queues are only locked with interrupts off, so frames can be
+/// sent from interrupt handlers.
+struct Card {
+    name: &'static str,
+    transport: Transport,
+    mac: MacAddress,
+    receive: SpinLock<Queue>,
+    transmit: SpinLock<Transmit>,
+}
+
+impl NetDevice for Card {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME || frame.len() < 14 {
+            return Err(NetError::BadLength);
+        }
+        interrupts::without_interrupts(|| {
+            let mut transmit = self.transmit.lock();
+            while let Some((page, _)) = transmit.queue.pop_used() {
+                transmit.free.push(page);
+            }
+            let page = transmit.free.pop().ok_or(NetError::Busy)?;
+
+            let base = phys_to_virt(page).as_mut_ptr::<u8>();
+            unsafe {
+                base.write_bytes(0, HEADER_LEN);
+                let to = base.add(FRAME_OFFSET as usize);
+                to.copy_from_nonoverlapping(frame.as_ptr(), frame.len());
+            }
+            if !transmit.queue.submit(page, frame.len(), false) {
+                transmit.free.push(page);
+                return Err(NetError::Busy);
+            }
+            self.transport.notify(TRANSMIT_QUEUE);
+            Ok(())
+        })
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> Option<usize> {
+        interrupts::without_interrupts(|| {
+            let mut receive = self.receive.lock();
+            let (page, written) = receive.pop_used()?;
+            let len = written.saturating_sub(HEADER_LEN).min(MAX_FRAME);
+            let copied = len.min(buf.len());
+            let from = phys_to_virt(page + FRAME_OFFSET).as_ptr::<u8>();
+            unsafe { buf.as_mut_ptr().copy_from_nonoverlapping(from, copied) };
+
+            // there was room for it before
+            receive.submit(page, MAX_FRAME, true);
+            self.transport.notify(RECEIVE_QUEUE);
+            Some(len)
+        })
+    }
+
+    fn has_frames(&self) -> bool {
+        interrupts::without_interrupts(|| self.receive.lock().queue.has_used())
+    }
+}
+
+impl fmt::Display for Card {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: at i/o {:#x}, mac {}",
+            self.name, self.transport.base, self.mac
+        )
+    }
+}
+
+/// `count` pages, or None if there isn't that much memory
+fn allocate_pages(count: usize) -> Option<Vec<PhysAddr>> {
+    (0..count)
+        .map(|_| frame::allocate_contiguous(1).map(|frame| frame.start_address()))
+        .collect()
+}
+
+/// Set up both queues, with the receive one full of buffers
+fn queues(transport: &Transport) -> Result<(Queue, Transmit), VirtioError> {
+    let mut receive = Queue::new(transport.queue(RECEIVE_QUEUE)?);
+    let transmit = Queue::new(transport.queue(TRANSMIT_QUEUE)?);
+    // two descriptors a frame
+    let rx_buffers = RX_BUFFERS.min(receive.queue.size() as usize / 2);
+    let tx_buffers = TX_BUFFERS.min(transmit.queue.size() as usize / 2);
+
+    for page in allocate_pages(rx_buffers).ok_or(VirtioError::NoMemory)? {
+        receive.submit(page, MAX_FRAME, true);
+    }
+    let free = allocate_pages(tx_buffers).ok_or(VirtioError::NoMemory)?;
+    Ok((
+        receive,
+        Transmit {
+            queue: transmit,
+            free,
+        },
+    ))
+}
+
+fn setup(device: &pci::Device, index: usize) -> Result<Card, VirtioError> {
+    let transport = Transport::new(device)?;
+    let features = transport.negotiate(FEATURE_MAC);
+    let queues = queues(&transport)
+        .and_then(|queues| super::set_handler(device, &transport, net::received).map(|_| queues));
+    let (receive, transmit) = match queues {
+        Ok(queues) => queues,
+        Err(error) => {
+            transport.fail();
+            return Err(error);
+        }
+    };
+
+    let mut mac = MacAddress::default();
+    if features & FEATURE_MAC != 0 {
+        for (i, byte) in mac.0.iter_mut().enumerate() {
+            *byte = transport.config_u8(CONFIG_MAC + i as u16);
+        }
+    } else {
+        // made up, with the locally administered bit set
+        mac = MacAddress([0x02, 0, 0, 0, 0, index as u8]);
+    }
+    transport.ready();
+    transport.notify(RECEIVE_QUEUE);
+
+    Ok(Card {
+        name: NAMES[index],
+        transport,
+        mac,
+        receive: SpinLock::new("virtio-net receive", receive),
+        transmit: SpinLock::new("virtio-net transmit", transmit),
+    })
+}
+
+fn init() {
+    let mut count = 0;
+    for device in pci::devices() {
+        if device.vendor_id != VENDOR_ID || device.device_id != DEVICE_ID {
+            continue;
+        }
+        if count == MAX_CARDS {
+            wlog!("virtio-net: only {} cards are supported", MAX_CARDS);
+            break;
+        }
+        let card = match setup(&device, count) {
+            Ok(card) => card,
+            Err(error) => {
+                wlog!(
+                    "virtio-net: couldn't set up {}: {:?}",
+                    device.address,
+                    error
+                );
+                continue;
+            }
+        };
+        ilog!("virtio-net: {}", card);
+        count += 1;
+        let card = Box::leak(Box::new(card));
+        if let Err(error) = net::register(card) {
+            wlog!("virtio-net: couldn't register {}: {:?}", card.name, error);
+        }
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "virtio-net",
+    stage: Stage::Drivers,
+    after: &["pci", "pic"],
+    func: init,
+};
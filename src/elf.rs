@@ -0,0 +1,266 @@
+//! ELF64 executables
+//!
+//! [`parse`] checks the file header and program headers of a static x86_64 executable, and
+//! [`load`] maps its `PT_LOAD` segments into the lower half of a process's
+//! [`AddressSpace`] for [`user`](crate::user) mode:
+//! every page user-accessible, writable only if the segment is, and executable only if the
+//! segment is. Bytes past the end of a segment's data in the file, like its `.bss`, are zero,
+//! and pages that are only that get their frames when they're first touched.
+//!
+//! Everything else in the file is ignored, there's no dynamic linking or relocation, so a
+//! program has to be linked to run where it says.
+//!
+//! links:
+//! - <https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html>
+//! - <https://refspecs.linuxfoundation.org/elf/gabi4+/ch5.pheader.html>
+//!
+
+use alloc::vec::Vec;
+
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::mem::paging::{AddressSpace, MapError, PAGE_SIZE};
+use crate::mem::{frame, phys_to_virt};
+use crate::user::USER_END;
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// What's wrong with an executable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// It doesn't start with the ELF magic
+    BadMagic,
+    /// It's not little-endian ELF64 for x86_64
+    Unsupported,
+    /// It's not an executable, like an object file or a shared library
+    NotExecutable,
+    /// A header or segment runs past the end of the file
+    Truncated,
+    /// A segment or the entry point is outside user space, below [`USER_END`]
+    BadAddress,
+    /// A segment couldn't be mapped
+    Map(MapError),
+}
+
+impl From<MapError> for ElfError {
+    fn from(error: MapError) -> ElfError {
+        ElfError::Map(error)
+    }
+}
+
+/// A program header
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+impl Segment {
+    pub fn is_load(&self) -> bool {
+        self.kind == PT_LOAD
+    }
+
+    /// The flags its pages get
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// A parsed executable
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    pub entry: VirtAddr,
+    segments: Vec<Segment>,
+}
+
+impl Elf<'_> {
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// Check that `bytes` is an executable this kernel can load, and read its program headers
+pub fn parse(bytes: &[u8]) -> Result<Elf<'_>, ElfError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if bytes[..4] != MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    if bytes[4] != CLASS_64 || bytes[5] != DATA_LITTLE_ENDIAN || u16_at(bytes, 18) != MACHINE_X86_64
+    {
+        return Err(ElfError::Unsupported);
+    }
+    if u16_at(bytes, 16) != TYPE_EXECUTABLE {
+        return Err(ElfError::NotExecutable);
+    }
+
+    let entry = u64_at(bytes, 24);
+    let table = u64_at(bytes, 32) as usize;
+    let entry_size = u16_at(bytes, 54) as usize;
+    let count = u16_at(bytes, 56) as usize;
+    if count > 0 && entry_size < PROGRAM_HEADER_SIZE {
+        return Err(ElfError::Unsupported);
+    }
+    let table_end = count
+        .checked_mul(entry_size)
+        .and_then(|size| size.checked_add(table))
+        .ok_or(ElfError::Truncated)?;
+    if table_end > bytes.len() {
+        return Err(ElfError::Truncated);
+    }
+
+    let mut segments = Vec::with_capacity(count);
+    for i in 0..count {
+        let header = &bytes[table + i * entry_size..];
+        let segment = Segment {
+            kind: u32_at(header, 0),
+            flags: u32_at(header, 4),
+            offset: u64_at(header, 8),
+            vaddr: u64_at(header, 16),
+            file_size: u64_at(header, 32),
+            mem_size: u64_at(header, 40),
+        };
+        if segment.is_load() {
+            check(bytes, &segment)?;
+        }
+        segments.push(segment);
+    }
+    if entry == 0 || entry >= USER_END {
+        return Err(ElfError::BadAddress);
+    }
+
+    Ok(Elf {
+        bytes,
+        entry: VirtAddr::new(entry),
+        segments,
+    })
+}
+
+fn check(bytes: &[u8], segment: &Segment) -> Result<(), ElfError> {
+    let file_end = segment
+        .offset
+        .checked_add(segment.file_size)
+        .ok_or(ElfError::Truncated)?;
+    if file_end > bytes.len() as u64 || segment.file_size > segment.mem_size {
+        return Err(ElfError::Truncated);
+    }
+    match segment.vaddr.checked_add(segment.mem_size) {
+        // leaves page 0 unmapped, so null pointers fault
+        Some(end) if segment.vaddr >= PAGE_SIZE && end <= USER_END => Ok(()),
+        _ => Err(ElfError::BadAddress),
+    }
+}
+
+/// Map the loadable segments of `elf` into `space` and fill them in. Pages with none of the
+/// file in them, like most of the `.bss`, are mapped lazily. The pages mapped belong to
+/// `space`, also when loading fails partway.
+pub fn load(elf: &Elf, space: &mut AddressSpace) -> Result<VirtAddr, ElfError> {
+    for segment in elf.segments.iter().filter(|segment| segment.is_load()) {
+        let data = &elf.bytes[segment.offset as usize..][..segment.file_size as usize];
+        let start = VirtAddr::new(segment.vaddr).align_down(PAGE_SIZE);
+        let end = VirtAddr::new(segment.vaddr + segment.mem_size).align_up(PAGE_SIZE);
+
+        let mut page = start;
+        while page < end {
+            // the part of the segment's data that lands in this page
+            let from = page.as_u64().max(segment.vaddr);
+            let to = (page.as_u64() + PAGE_SIZE).min(segment.vaddr + segment.file_size);
+            if from >= to {
+                // only zeroes, so it can wait for the program to touch it
+                space.map_lazy(page, page + PAGE_SIZE, segment.page_flags());
+                page += PAGE_SIZE;
+                continue;
+            }
+
+            let frame = frame::allocate_frame().ok_or(MapError::NoFrames)?;
+            let window = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+            unsafe {
+                window.write_bytes(0, PAGE_SIZE as usize);
+                if let Err(error) = space.map_to(page, frame.start_address(), segment.page_flags())
+                {
+                    frame::deallocate_frame(frame);
+                    return Err(error.into());
+                }
+            }
+
+            let data = &data[(from - segment.vaddr) as usize..(to - segment.vaddr) as usize];
+            // the page may not be writable through its user mapping, so it's filled in through
+            // the physical memory window
+            unsafe {
+                window
+                    .add((from - page.as_u64()) as usize)
+                    .copy_from_nonoverlapping(data.as_ptr(), data.len());
+            }
+            page += PAGE_SIZE;
+        }
+    }
+    Ok(elf.entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static HELLO: &[u8] = include_bytes!("user/hello.elf");
+
+    #[test_case]
+    fn parses_hello() {
+        let elf = parse(HELLO).unwrap();
+        assert_eq!(elf.entry.as_u64(), 0x40_1000);
+        let load: Vec<&Segment> = elf.segments().iter().filter(|s| s.is_load()).collect();
+        assert_eq!(load.len(), 4);
+        // .text is executable but not writable, .data the other way around
+        assert!(!load[1].page_flags().contains(PageTableFlags::NO_EXECUTE));
+        assert!(!load[1].page_flags().contains(PageTableFlags::WRITABLE));
+        assert!(load[3].page_flags().contains(PageTableFlags::NO_EXECUTE));
+        assert!(load[3].page_flags().contains(PageTableFlags::WRITABLE));
+    }
+
+    #[test_case]
+    fn rejects_bad_files() {
+        assert_eq!(parse(&HELLO[..32]).err(), Some(ElfError::Truncated));
+        let mut bytes = Vec::from(HELLO);
+        bytes[0] = 0;
+        assert_eq!(parse(&bytes).err(), Some(ElfError::BadMagic));
+        bytes[0] = 0x7f;
+        // a relocatable object
+        bytes[16] = 1;
+        assert_eq!(parse(&bytes).err(), Some(ElfError::NotExecutable));
+    }
+}
@@ -0,0 +1,143 @@
+//! Formatting helpers for bytes
+//!
+//! [`HexSlice`] formats a byte slice as hex bytes separated by spaces, for putting a short
+//! buffer in a log line. [`Hexdump`] formats one the way `hexdump -C` does, 16 bytes a line
+//! with the offset in front and the printable ones as ASCII at the end, and
+//! [`hexdump!`](crate::hexdump) prints one:
+//!
+//! ```ignore
+//! hexdump!(&frame[..len]);
+//! // a raw pointer and length, labelled with the addresses instead of offsets
+//! unsafe { hexdump!(table as *const u8, 64) };
+//! ```
+//!
+
+use core::fmt;
+
+/// Bytes on each line of a [`Hexdump`]
+pub const BYTES_PER_LINE: usize = 16;
+
+/// Formats bytes as hex, like `de ad be ef`
+#[derive(Clone, Copy)]
+pub struct HexSlice<'a>(pub &'a [u8]);
+
+impl fmt::Display for HexSlice<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for HexSlice<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}]", self)
+    }
+}
+
+/// Formats bytes as lines of offset, hex and ASCII
+#[derive(Clone, Copy)]
+pub struct Hexdump<'a> {
+    bytes: &'a [u8],
+    /// What the first line is labelled with
+    base: u64,
+}
+
+impl<'a> Hexdump<'a> {
+    /// A dump of `bytes`, labelled with offsets from 0
+    pub fn new(bytes: &'a [u8]) -> Hexdump<'a> {
+        Hexdump { bytes, base: 0 }
+    }
+
+    /// A dump of `len` bytes at `ptr`, labelled with their addresses
+    ///
+    /// # Safety
+    /// All `len` bytes have to be mapped and readable for as long as the dump is around.
+    pub unsafe fn from_raw(ptr: *const u8, len: usize) -> Hexdump<'a> {
+        Hexdump {
+            bytes: unsafe { core::slice::from_raw_parts(ptr, len) },
+            base: ptr as u64,
+        }
+    }
+
+    /// Label the lines starting from `base` instead
+    pub fn at(self, base: u64) -> Hexdump<'a> {
+        Hexdump { base, ..self }
+    }
+}
+
+impl fmt::Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let end = self.base + self.bytes.len() as u64;
+        for (i, line) in self.bytes.chunks(BYTES_PER_LINE).enumerate() {
+            let offset = self.base + (i * BYTES_PER_LINE) as u64;
+            // addresses get all 16 digits so they line up, small offsets only need 8
+            if end > u32::MAX as u64 {
+                write!(f, "{:016x} ", offset)?;
+            } else {
+                write!(f, "{:08x} ", offset)?;
+            }
+
+            for column in 0..BYTES_PER_LINE {
+                if column == BYTES_PER_LINE / 2 {
+                    f.write_str(" ")?;
+                }
+                match line.get(column) {
+                    Some(byte) => write!(f, " {:02x}", byte)?,
+                    None => f.write_str("   ")?,
+                }
+            }
+
+            f.write_str("  |")?;
+            for &byte in line {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                write!(f, "{}", c)?;
+            }
+            f.write_str("|\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Print a [`Hexdump`] of a slice, or of a raw pointer and length, which needs `unsafe`
+#[macro_export]
+macro_rules! hexdump {
+    ($bytes:expr $(,)?) => {
+        $crate::print!(
+            "{}",
+            $crate::fmt::Hexdump::new(::core::convert::AsRef::<[u8]>::as_ref(&$bytes))
+        )
+    };
+    ($ptr:expr, $len:expr $(,)?) => {
+        $crate::print!(
+            "{}",
+            $crate::fmt::Hexdump::from_raw($ptr as *const u8, $len)
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn formats_dumps() {
+        let bytes = *b"zenix\0\x01\xffhexdump test";
+        assert_eq!(format!("{}", HexSlice(&bytes[..4])), "7a 65 6e 69");
+        assert_eq!(format!("{:?}", HexSlice(&[])), "[]");
+        assert_eq!(
+            format!("{}", Hexdump::new(&bytes).at(0x1000)),
+            "00001000  7a 65 6e 69 78 00 01 ff  68 65 78 64 75 6d 70 20  |zenix...hexdump |\n\
+             00001010  74 65 73 74                                       |test|\n"
+        );
+    }
+}
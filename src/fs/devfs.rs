@@ -0,0 +1,277 @@
+//! Device files
+//!
+//! A filesystem with nothing stored in it, mounted at `/dev` by [`INIT`]. Each file stands for
+//! a device or a bit of kernel state and makes up what's read from it on the spot, so the
+//! shell and user programs can get at those the same way they get at any other file:
+//!
+//! | file         | reads                                        | writes                 |
+//! |--------------|----------------------------------------------|------------------------|
+//! | `null`       | nothing                                      | are thrown away        |
+//! | `zero`       | zeroes                                       | are thrown away        |
+//! | `random`     | [random](crate::rand) bytes                  | are thrown away        |
+//! | `console`    | not supported                                | are printed            |
+//! | `serial`     | not supported                                | go out the serial port |
+//! | `kmsg`       | the [kernel message buffer](crate::klog)     | not supported          |
+//! | `uptime`     | seconds since boot                           | not supported          |
+//! | `meminfo`    | physical memory and heap usage               | not supported          |
+//! | `interrupts` | [interrupt counts](crate::interrupts::stats) | not supported          |
+//!
+//! Every registered [block device](crate::block) is there as well, under its own name, and
+//! reads and writes go straight to its sectors.
+//!
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use super::vfs::{self, DirEntry, FileSystem, Inode, Kind, Metadata, VfsError};
+use crate::block::{self, BlockDevice};
+use crate::init::{InitCall, Stage};
+use crate::mem::{self, frame::FRAME_SIZE};
+use crate::{interrupts, klog, print, rand, serial_print, timer, wlog};
+
+const MOUNT_POINT: &str = "/dev";
+
+#[derive(Clone, Copy)]
+enum Node {
+    Null,
+    Zero,
+    Random,
+    /// Written to an output, and can't be read
+    Output(fn(&str)),
+    /// Text made up each time it's read
+    Text(fn(&mut String)),
+    Block(&'static dyn BlockDevice),
+}
+
+static NODES: &[(&str, Node)] = &[
+    ("null", Node::Null),
+    ("zero", Node::Zero),
+    ("random", Node::Random),
+    ("console", Node::Output(|text| print!("{}", text))),
+    ("serial", Node::Output(|text| serial_print!("{}", text))),
+    ("kmsg", Node::Text(kmsg)),
+    ("uptime", Node::Text(uptime)),
+    ("meminfo", Node::Text(meminfo)),
+    (
+        "interrupts",
+        Node::Text(|text| {
+            let _ = write!(text, "{}", interrupts::stats());
+        }),
+    ),
+];
+
+fn kmsg(text: &mut String) {
+    klog::for_each_line(|line| text.push_str(line));
+}
+
+fn uptime(text: &mut String) {
+    let ms = timer::uptime_ms();
+    let _ = writeln!(text, "{}.{:03}", ms / 1000, ms % 1000);
+}
+
+fn meminfo(text: &mut String) {
+    if let Some(frames) = mem::frame::stats() {
+        let _ = writeln!(
+            text,
+            "frames:      {} KiB",
+            frames.total * FRAME_SIZE / 1024
+        );
+        let _ = writeln!(text, "frames used: {} KiB", frames.used * FRAME_SIZE / 1024);
+    }
+    let heap = mem::heap::stats();
+    let _ = writeln!(text, "heap:        {} KiB", heap.size / 1024);
+    let _ = writeln!(text, "heap used:   {} KiB", heap.used / 1024);
+    let _ = writeln!(text, "allocations: {}", heap.allocations);
+}
+
+/// Copy what's in `bytes` from `offset` into `buf`
+fn read_slice(bytes: &[u8], offset: u64, buf: &mut [u8]) -> usize {
+    let start = (offset as usize).min(bytes.len());
+    let len = buf.len().min(bytes.len() - start);
+    buf[..len].copy_from_slice(&bytes[start..start + len]);
+    len
+}
+
+/// The sectors of `device` that bytes `offset..offset + len` are in, and where those bytes
+/// start in the first one. Transfers are clipped to the end of the device.
+fn sectors(device: &dyn BlockDevice, offset: u64, len: usize) -> (u64, usize, usize) {
+    let sector_size = device.sector_size() as u64;
+    let len = (len as u64).min(device.size().saturating_sub(offset));
+    if len == 0 {
+        return (0, 0, 0);
+    }
+    let first = offset / sector_size;
+    let end = (offset + len).div_ceil(sector_size);
+    (
+        first,
+        (offset % sector_size) as usize,
+        ((end - first) * sector_size) as usize,
+    )
+}
+
+impl Node {
+    fn kind(&self) -> Kind {
+        match self {
+            Node::Text(_) => Kind::File,
+            _ => Kind::Device,
+        }
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Metadata {
+        let size = match self {
+            Node::Block(device) => device.size(),
+            _ => 0,
+        };
+        Metadata {
+            kind: self.kind(),
+            size,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        match *self {
+            Node::Null => Ok(0),
+            Node::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+            Node::Random => {
+                rand::fill(buf);
+                Ok(buf.len())
+            }
+            Node::Output(_) => Err(VfsError::NotSupported),
+            Node::Text(generate) => {
+                let mut text = String::new();
+                generate(&mut text);
+                Ok(read_slice(text.as_bytes(), offset, buf))
+            }
+            Node::Block(device) => {
+                let (first, skip, span) = sectors(device, offset, buf.len());
+                if span == 0 {
+                    return Ok(0);
+                }
+                let mut sectors = vec![0; span];
+                device.read(first, &mut sectors)?;
+                Ok(read_slice(&sectors[skip..], 0, buf))
+            }
+        }
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        match *self {
+            Node::Null | Node::Zero | Node::Random => Ok(buf.len()),
+            Node::Output(output) => {
+                output(&String::from_utf8_lossy(buf));
+                Ok(buf.len())
+            }
+            Node::Text(_) => Err(VfsError::NotSupported),
+            Node::Block(device) => {
+                let (first, skip, span) = sectors(device, offset, buf.len());
+                if span == 0 {
+                    return Ok(0);
+                }
+                // whatever else is in the first and last sector has to be kept
+                let mut sectors = vec![0; span];
+                device.read(first, &mut sectors)?;
+                let len = buf.len().min(span - skip);
+                sectors[skip..skip + len].copy_from_slice(&buf[..len]);
+                device.write(first, &sectors)?;
+                Ok(len)
+            }
+        }
+    }
+}
+
+/// Call `f` with every file's name and node, the fixed ones then the block devices
+fn for_each_node(mut f: impl FnMut(&str, Node)) {
+    for &(name, node) in NODES {
+        f(name, node);
+    }
+    block::for_each(|device| f(device.name(), Node::Block(device)));
+}
+
+/// The one directory
+struct Root;
+
+impl Inode for Root {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: Kind::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let mut found = None;
+        for_each_node(|node_name, node| {
+            if node_name == name && found.is_none() {
+                found = Some(node);
+            }
+        });
+        match found {
+            Some(node) => Ok(Arc::new(node)),
+            None => Err(VfsError::NotFound),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let mut entries = Vec::new();
+        for_each_node(|name, node| {
+            entries.push(DirEntry {
+                name: String::from(name),
+                kind: node.kind(),
+            })
+        });
+        Ok(entries)
+    }
+}
+
+struct DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+}
+
+fn init() {
+    if let Err(error) = vfs::mount(MOUNT_POINT, Arc::new(DevFs)) {
+        wlog!("devfs: couldn't mount {}: {:?}", MOUNT_POINT, error);
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "devfs",
+    stage: Stage::Drivers,
+    after: &[],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn reads_through_vfs() {
+        let mut file = vfs::open("/dev/zero").unwrap();
+        let mut buf = [0xff; 16];
+        assert_eq!(file.read(&mut buf).unwrap(), 16);
+        assert_eq!(buf, [0; 16]);
+
+        let uptime = vfs::open("/dev/uptime").unwrap().read_to_end().unwrap();
+        assert!(uptime.ends_with(b"\n"));
+        assert!(vfs::read_dir("/dev")
+            .unwrap()
+            .iter()
+            .any(|entry| entry.name == "null"));
+    }
+}
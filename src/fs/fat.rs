@@ -0,0 +1,709 @@
+//! FAT32, read only
+//!
+//! [`INIT`] looks for a FAT32 filesystem on every block device, either over the whole device
+//! or in one of the four primary partitions of an MBR, and mounts the first one it finds, in
+//! the [`vfs`] at `/mnt/disk`. Others can be mounted with [`mount_at`]. [`open`] looks a path up on it without going through the VFS,
+//! with `/` between directories, and returns a [`File`] to read it through:
+//!
+//! ```ignore
+//! let config = fat::open("/boot/config.txt")?.read_to_end()?;
+//! ```
+//!
+//! Names match without regard to case, like they do on DOS and Windows. Long file names are
+//! read and preferred, but the 8.3 short name of an entry works too. There's no caching, every
+//! lookup reads the directories on the way again, and nothing is ever written.
+//!
+//! links:
+//! - <https://wiki.osdev.org/FAT>
+//! - Microsoft's "FAT: General Overview of On-Disk Format"
+//!
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::vfs::{self, FileSystem, Inode, Kind, Metadata, VfsError};
+use crate::block::{self, BlockDevice, BlockError};
+use crate::init::{InitCall, Stage};
+use crate::sync::SpinLock;
+use crate::{ilog, wlog};
+
+const SECTOR_SIZE: usize = 512;
+/// Where the [`vfs`] gets the mounted volume
+const MOUNT_POINT: &str = "/mnt/disk";
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+// MBR partition table
+const PARTITION_TABLE: usize = 446;
+const PARTITION_ENTRY_SIZE: usize = 16;
+const PARTITION_FAT32: u8 = 0x0b;
+const PARTITION_FAT32_LBA: u8 = 0x0c;
+
+// FAT entries, only the low 28 bits of which count
+const CLUSTER_MASK: u32 = 0x0fff_ffff;
+/// This and above end a cluster chain
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+/// The first cluster in the data region
+const FIRST_CLUSTER: u32 = 2;
+
+const ENTRY_SIZE: usize = 32;
+// first byte of a directory entry
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+/// Stands in for a name that really starts with 0xe5
+const ENTRY_E5: u8 = 0x05;
+
+// attributes
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// All of read only, hidden, system, and volume ID, which marks a long name entry
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+// case of a short name, from Windows NT
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXTENSION: u8 = 0x10;
+
+/// Long name entry: the last part of the name, which comes first
+const LONG_LAST: u8 = 0x40;
+/// UTF-16 characters in a long name entry, and where they are in it
+const LONG_CHARS: usize = 13;
+const LONG_CHAR_OFFSETS: [usize; LONG_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Enough entries for a name of 255 characters
+const LONG_MAX_ENTRIES: usize = 20;
+
+/// Why a file couldn't be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    /// The device couldn't be read
+    Block(BlockError),
+    /// There's no FAT32 filesystem on the device
+    NotFat,
+    /// Something on disk doesn't add up, like a cluster chain that ends early
+    Corrupt,
+    /// No filesystem has been mounted
+    NotMounted,
+    /// There's nothing at the path
+    NotFound,
+    /// Part of the path that should be a directory isn't
+    NotADirectory,
+    /// A directory was opened as a file
+    IsADirectory,
+}
+
+impl From<BlockError> for FatError {
+    fn from(error: BlockError) -> FatError {
+        FatError::Block(error)
+    }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// Whether `boot` is the boot sector of a FAT32 filesystem this driver can read
+fn is_fat32(boot: &[u8]) -> bool {
+    boot[510..] == BOOT_SIGNATURE
+        && u16_at(boot, 11) as usize == SECTOR_SIZE
+        && boot[13].is_power_of_two()
+        && boot[16] != 0
+        // FAT12 and FAT16 have a fixed size root directory and a 16-bit FAT size
+        && u16_at(boot, 17) == 0
+        && u16_at(boot, 22) == 0
+        && u32_at(boot, 36) != 0
+}
+
+/// The checksum of a short name that its long name entries carry
+fn checksum(short: &[u8]) -> u8 {
+    short[..11]
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// The 8.3 name in a directory entry as `NAME.EXT`, lower case where its flags say
+fn short_name(raw: &[u8]) -> String {
+    let case = raw[12];
+    let mut name = String::new();
+    for (i, &byte) in raw[..11].iter().enumerate() {
+        if i == 8 && raw[8] != b' ' {
+            name.push('.');
+        }
+        let byte = match byte {
+            b' ' => continue,
+            ENTRY_E5 if i == 0 => ENTRY_DELETED,
+            byte => byte,
+        };
+        let lower = case & if i < 8 { LOWER_BASE } else { LOWER_EXTENSION } != 0;
+        if lower {
+            name.push(byte.to_ascii_lowercase() as char);
+        } else {
+            name.push(byte as char);
+        }
+    }
+    name
+}
+
+/// A long name being put together from its entries, which come last part first, right before
+/// the short entry they belong to
+struct LongName {
+    chars: [u16; LONG_CHARS * LONG_MAX_ENTRIES],
+    /// 0 when there's no name in progress
+    len: usize,
+    /// The sequence number of the entry that should come next, 0 once it's all there
+    next: u8,
+    checksum: u8,
+}
+
+impl LongName {
+    fn new() -> LongName {
+        LongName {
+            chars: [0; LONG_CHARS * LONG_MAX_ENTRIES],
+            len: 0,
+            next: 0,
+            checksum: 0,
+        }
+    }
+
+    fn add(&mut self, raw: &[u8]) {
+        let sequence = raw[0] & !LONG_LAST;
+        if raw[0] & LONG_LAST != 0 && (1..=LONG_MAX_ENTRIES as u8).contains(&sequence) {
+            self.len = sequence as usize * LONG_CHARS;
+            self.checksum = raw[13];
+        } else if self.len == 0
+            || sequence == 0
+            || sequence != self.next
+            || raw[13] != self.checksum
+        {
+            // an orphan, left over from something that didn't know about long names
+            self.len = 0;
+            return;
+        }
+
+        let at = (sequence as usize - 1) * LONG_CHARS;
+        for (i, &offset) in LONG_CHAR_OFFSETS.iter().enumerate() {
+            self.chars[at + i] = u16_at(raw, offset);
+        }
+        self.next = sequence - 1;
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// The name, if it's all there and belongs to the short entry with `checksum`
+    fn take(&mut self, checksum: u8) -> Option<String> {
+        let len = core::mem::take(&mut self.len);
+        if len == 0 || self.next != 0 || self.checksum != checksum {
+            return None;
+        }
+        // the name ends at a 0, unless it fills the last entry
+        let chars = self.chars[..len].iter().copied().take_while(|&c| c != 0);
+        Some(
+            char::decode_utf16(chars)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        )
+    }
+}
+
+/// A file or directory
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    /// The long name if there is one, or the short one
+    pub name: String,
+    pub short_name: String,
+    pub attributes: u8,
+    /// Where its data starts, 0 for an empty file
+    pub cluster: u32,
+    /// In bytes, 0 for a directory
+    pub size: u32,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.short_name.eq_ignore_ascii_case(name)
+    }
+}
+
+/// A FAT32 filesystem on a block device
+pub struct Volume {
+    device: &'static dyn BlockDevice,
+    /// The sector its boot sector is in, on the device
+    start: u64,
+    sectors_per_cluster: u64,
+    /// The first sector of the first FAT, from `start`
+    fat_start: u64,
+    /// The first sector of [`FIRST_CLUSTER`], from `start`
+    data_start: u64,
+    /// One past the last cluster
+    cluster_end: u32,
+    root: u32,
+    label: [u8; 11],
+}
+
+impl Volume {
+    /// Find the filesystem on `device`, on the whole of it or in a partition
+    pub fn mount(device: &'static dyn BlockDevice) -> Result<Volume, FatError> {
+        if device.sector_size() != SECTOR_SIZE {
+            return Err(FatError::NotFat);
+        }
+        let mut sector = [0; SECTOR_SIZE];
+        device.read(0, &mut sector)?;
+        if is_fat32(&sector) {
+            return Volume::new(device, 0, &sector);
+        }
+        if sector[510..] != BOOT_SIGNATURE {
+            return Err(FatError::NotFat);
+        }
+
+        let table = sector;
+        for entry in table[PARTITION_TABLE..510].chunks_exact(PARTITION_ENTRY_SIZE) {
+            if !matches!(entry[4], PARTITION_FAT32 | PARTITION_FAT32_LBA) {
+                continue;
+            }
+            let start = u32_at(entry, 8) as u64;
+            device.read(start, &mut sector)?;
+            if is_fat32(&sector) {
+                return Volume::new(device, start, &sector);
+            }
+        }
+        Err(FatError::NotFat)
+    }
+
+    fn new(device: &'static dyn BlockDevice, start: u64, boot: &[u8]) -> Result<Volume, FatError> {
+        let sectors_per_cluster = boot[13] as u64;
+        let fat_start = u16_at(boot, 14) as u64;
+        let data_start = fat_start + boot[16] as u64 * u32_at(boot, 36) as u64;
+        let total = u32_at(boot, 32) as u64;
+        if total <= data_start || start + total > device.sector_count() {
+            return Err(FatError::Corrupt);
+        }
+        let clusters = (total - data_start) / sectors_per_cluster;
+
+        let mut label = [0; 11];
+        label.copy_from_slice(&boot[71..82]);
+        Ok(Volume {
+            device,
+            start,
+            sectors_per_cluster,
+            fat_start,
+            data_start,
+            cluster_end: FIRST_CLUSTER + clusters as u32,
+            root: u32_at(boot, 44),
+            label,
+        })
+    }
+
+    /// Bytes in a cluster
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    /// The name it was formatted with
+    pub fn label(&self) -> &str {
+        core::str::from_utf8(&self.label).unwrap_or("?").trim_end()
+    }
+
+    fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> Result<(), FatError> {
+        let sector = self.start
+            + self.data_start
+            + (cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster;
+        Ok(self.device.read(sector, buf)?)
+    }
+
+    /// The cluster after `cluster` in its chain, None at the end
+    fn next(&self, cluster: u32) -> Result<Option<u32>, FatError> {
+        let offset = cluster as usize * 4;
+        let mut sector = [0; SECTOR_SIZE];
+        self.device.read(
+            self.start + self.fat_start + (offset / SECTOR_SIZE) as u64,
+            &mut sector,
+        )?;
+        match u32_at(&sector, offset % SECTOR_SIZE) & CLUSTER_MASK {
+            END_OF_CHAIN.. => Ok(None),
+            // free and bad clusters included
+            next if next < FIRST_CLUSTER || next >= self.cluster_end => Err(FatError::Corrupt),
+            next => Ok(Some(next)),
+        }
+    }
+
+    fn root_entry(&self) -> DirEntry {
+        DirEntry {
+            name: String::from("/"),
+            short_name: String::from("/"),
+            attributes: ATTR_DIRECTORY,
+            cluster: self.root,
+            size: 0,
+        }
+    }
+
+    /// Everything in the directory starting at `cluster`, `.` and `..` included
+    pub fn read_dir(&self, cluster: u32) -> Result<Vec<DirEntry>, FatError> {
+        let mut entries = Vec::new();
+        let mut long_name = LongName::new();
+        let mut buffer = vec![0; self.cluster_size()];
+        let mut cluster = Some(cluster);
+        let mut visited = 0;
+        while let Some(current) = cluster {
+            // a chain going round in circles would otherwise never end
+            visited += 1;
+            if visited > self.cluster_end || current < FIRST_CLUSTER {
+                return Err(FatError::Corrupt);
+            }
+            self.read_cluster(current, &mut buffer)?;
+
+            for raw in buffer.chunks_exact(ENTRY_SIZE) {
+                match raw[0] {
+                    ENTRY_END => return Ok(entries),
+                    ENTRY_DELETED => {
+                        long_name.clear();
+                        continue;
+                    }
+                    _ => {}
+                }
+                let attributes = raw[11];
+                if attributes == ATTR_LONG_NAME {
+                    long_name.add(raw);
+                    continue;
+                }
+                if attributes & ATTR_VOLUME_ID != 0 {
+                    long_name.clear();
+                    continue;
+                }
+
+                let short_name = short_name(raw);
+                entries.push(DirEntry {
+                    name: long_name
+                        .take(checksum(raw))
+                        .unwrap_or_else(|| short_name.clone()),
+                    short_name,
+                    attributes,
+                    cluster: (u16_at(raw, 20) as u32) << 16 | u16_at(raw, 26) as u32,
+                    size: u32_at(raw, 28),
+                });
+            }
+            cluster = self.next(current)?;
+        }
+        Ok(entries)
+    }
+
+    /// Look up `path`, from the root directory
+    pub fn lookup(&self, path: &str) -> Result<DirEntry, FatError> {
+        let mut entry = self.root_entry();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if !entry.is_dir() {
+                return Err(FatError::NotADirectory);
+            }
+            entry = self
+                .read_dir(entry.cluster)?
+                .into_iter()
+                .find(|entry| entry.matches(name))
+                .ok_or(FatError::NotFound)?;
+            // `..` in a directory under the root points at cluster 0
+            if entry.is_dir() && entry.cluster == 0 {
+                entry.cluster = self.root;
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Open the file at `path` for reading
+    pub fn open(&self, path: &str) -> Result<File<'_>, FatError> {
+        let entry = self.lookup(path)?;
+        if entry.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        Ok(File::new(self, &entry))
+    }
+}
+
+impl fmt::Display for Volume {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at sector {}: FAT32 {:?}, {} MiB, {} KiB clusters",
+            self.device.name(),
+            self.start,
+            self.label(),
+            (self.cluster_end - FIRST_CLUSTER) as u64 * self.cluster_size() as u64 / (1024 * 1024),
+            self.cluster_size() / 1024
+        )
+    }
+}
+
+/// A file open for reading
+pub struct File<'a> {
+    volume: &'a Volume,
+    first: u32,
+    size: u32,
+    position: u32,
+    /// The cluster `position` is in
+    cluster: Option<u32>,
+    /// Holds the cluster in `loaded`
+    buffer: Vec<u8>,
+    loaded: Option<u32>,
+}
+
+impl<'a> File<'a> {
+    fn new(volume: &'a Volume, entry: &DirEntry) -> File<'a> {
+        File {
+            volume,
+            first: entry.cluster,
+            size: entry.size,
+            position: 0,
+            cluster: Some(entry.cluster),
+            buffer: Vec::new(),
+            loaded: None,
+        }
+    }
+
+    /// In bytes
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Read from where the last read stopped into `buf`. Returns how many bytes that was,
+    /// which is less than `buf` holds only at the end of the file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FatError> {
+        let cluster_size = self.volume.cluster_size();
+        let mut done = 0;
+        while done < buf.len() && self.position < self.size {
+            let cluster = self.cluster.ok_or(FatError::Corrupt)?;
+            if self.loaded != Some(cluster) {
+                self.buffer.resize(cluster_size, 0);
+                if !(FIRST_CLUSTER..self.volume.cluster_end).contains(&cluster) {
+                    return Err(FatError::Corrupt);
+                }
+                self.volume.read_cluster(cluster, &mut self.buffer)?;
+                self.loaded = Some(cluster);
+            }
+
+            let offset = self.position as usize % cluster_size;
+            let len = (cluster_size - offset)
+                .min(buf.len() - done)
+                .min((self.size - self.position) as usize);
+            buf[done..done + len].copy_from_slice(&self.buffer[offset..offset + len]);
+            done += len;
+            self.position += len as u32;
+            if (self.position as usize).is_multiple_of(cluster_size) && self.position < self.size {
+                self.cluster = self.volume.next(cluster)?;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Move to `position`, or the end if that's past it. The chain is followed from the start
+    /// to get there.
+    pub fn seek(&mut self, position: u32) -> Result<(), FatError> {
+        self.position = position.min(self.size);
+        let mut cluster = Some(self.first);
+        for _ in 0..self.position as usize / self.volume.cluster_size() {
+            match cluster {
+                Some(current) => cluster = self.volume.next(current)?,
+                None => break,
+            }
+        }
+        self.cluster = cluster;
+        Ok(())
+    }
+
+    /// Read the rest of the file
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>, FatError> {
+        let mut bytes = vec![0; (self.size - self.position) as usize];
+        let len = self.read(&mut bytes)?;
+        bytes.truncate(len);
+        Ok(bytes)
+    }
+}
+
+static VOLUME: SpinLock<Option<&'static Volume>> = SpinLock::new("fat volume", None);
+
+/// The mounted filesystem
+pub fn volume() -> Option<&'static Volume> {
+    *VOLUME.lock()
+}
+
+/// Open the file at `path` on the mounted filesystem
+pub fn open(path: &str) -> Result<File<'static>, FatError> {
+    volume().ok_or(FatError::NotMounted)?.open(path)
+}
+
+impl From<FatError> for VfsError {
+    fn from(error: FatError) -> VfsError {
+        match error {
+            FatError::Block(error) => VfsError::Io(error),
+            FatError::NotFat | FatError::Corrupt => VfsError::Corrupt,
+            FatError::NotMounted | FatError::NotFound => VfsError::NotFound,
+            FatError::NotADirectory => VfsError::NotADirectory,
+            FatError::IsADirectory => VfsError::IsADirectory,
+        }
+    }
+}
+
+/// A file or directory on a mounted volume, for the [`vfs`]
+struct FatInode {
+    volume: &'static Volume,
+    entry: DirEntry,
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: if self.entry.is_dir() {
+                Kind::Directory
+            } else {
+                Kind::File
+            },
+            size: self.entry.size as u64,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        if !self.entry.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        let mut entry = self
+            .volume
+            .read_dir(self.entry.cluster)?
+            .into_iter()
+            .find(|entry| entry.matches(name))
+            .ok_or(VfsError::NotFound)?;
+        if entry.is_dir() && entry.cluster == 0 {
+            entry.cluster = self.volume.root;
+        }
+        Ok(Arc::new(FatInode {
+            volume: self.volume,
+            entry,
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<vfs::DirEntry>, VfsError> {
+        if !self.entry.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        let entries = self.volume.read_dir(self.entry.cluster)?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .map(|entry| vfs::DirEntry {
+                kind: if entry.is_dir() {
+                    Kind::Directory
+                } else {
+                    Kind::File
+                },
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        if self.entry.is_dir() {
+            return Err(VfsError::IsADirectory);
+        }
+        let mut file = File::new(self.volume, &self.entry);
+        file.seek(offset.min(u32::MAX as u64) as u32)?;
+        Ok(file.read(buf)?)
+    }
+}
+
+/// A mounted volume, for the [`vfs`]
+struct FatFs(&'static Volume);
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "fat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(FatInode {
+            volume: self.0,
+            entry: self.0.root_entry(),
+        })
+    }
+}
+
+/// Mount the FAT32 volume on `device` in the [`vfs`] at `path`. The volume is never freed,
+/// even once it's unmounted.
+pub fn mount_at(device: &'static dyn BlockDevice, path: &str) -> Result<(), VfsError> {
+    let volume = Volume::mount(device)?;
+    let volume = Box::leak(Box::new(volume));
+    vfs::mount(path, Arc::new(FatFs(volume)))
+}
+
+fn init() {
+    block::for_each(|device| {
+        if volume().is_some() {
+            return;
+        }
+        let Ok(volume) = Volume::mount(device) else {
+            return;
+        };
+        ilog!("fat: mounted {} at {}", volume, MOUNT_POINT);
+        let volume = Box::leak(Box::new(volume));
+        *VOLUME.lock() = Some(volume);
+        if let Err(error) = vfs::mount(MOUNT_POINT, Arc::new(FatFs(volume))) {
+            wlog!("fat: couldn't mount {}: {:?}", MOUNT_POINT, error);
+        }
+    });
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "fat",
+    stage: Stage::Drivers,
+    after: &["ata", "virtio-blk", "ramdisk"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn long_entry(sequence: u8, checksum: u8, part: &str) -> [u8; ENTRY_SIZE] {
+        let mut raw = [0xff; ENTRY_SIZE];
+        raw[0] = sequence;
+        raw[11] = ATTR_LONG_NAME;
+        raw[13] = checksum;
+        let mut chars = part.encode_utf16().chain([0]);
+        for offset in LONG_CHAR_OFFSETS {
+            if let Some(c) = chars.next() {
+                raw[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        raw
+    }
+
+    #[test_case]
+    fn short_names() {
+        let mut raw = [0; ENTRY_SIZE];
+        raw[..11].copy_from_slice(b"README  TXT");
+        assert_eq!(short_name(&raw), "README.TXT");
+        raw[12] = LOWER_BASE;
+        assert_eq!(short_name(&raw), "readme.TXT");
+        raw[..11].copy_from_slice(b"BOOT       ");
+        assert_eq!(short_name(&raw), "boot");
+    }
+
+    #[test_case]
+    fn long_names() {
+        let short = *b"CONFIG~1TXT";
+        let sum = checksum(&short);
+        let mut name = LongName::new();
+        name.add(&long_entry(LONG_LAST | 2, sum, "txt"));
+        name.add(&long_entry(1, sum, "config-file.t"));
+        assert_eq!(name.take(sum).as_deref(), Some("config-file.txt"));
+
+        // the short entry was renamed by something that left the long name behind
+        name.add(&long_entry(LONG_LAST | 1, sum, "old.txt"));
+        assert_eq!(name.take(sum.wrapping_add(1)), None);
+    }
+}
@@ -0,0 +1,370 @@
+//! The initial RAM filesystem
+//!
+//! A ustar archive built into the kernel with the `initrd` feature, from the file `ZENIX_INITRD`
+//! names at build time, since bootloader 0.9 can't load one next to the kernel:
+//!
+//! ```shell
+//! $ tar --format=ustar -cf initrd.tar -C root bin etc
+//! $ ZENIX_INITRD=$PWD/initrd.tar cargo run --features initrd
+//! ```
+//!
+//! [`INIT`] indexes the archive's headers, and [`get`] looks a file up by its path in it, with
+//! or without a leading `/` or `./`. The data is never copied, files are slices of the archive
+//! in the kernel image, which makes them read only. [`user::run`](crate::user::run) looks for
+//! programs not built into the kernel in `/bin`.
+//!
+//! The archive is also mounted at `/` in the [`vfs`]. Directories don't need entries of their
+//! own there, one is there if anything in the archive is under it.
+//!
+//! links:
+//! - <https://wiki.osdev.org/USTAR>
+//! - POSIX pax, "ustar Interchange Format": <https://pubs.opengroup.org/onlinepubs/9699919799/utilities/pax.html>
+//!
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::vfs::{self, FileSystem, Inode, Metadata, VfsError};
+use crate::init::{InitCall, Stage};
+use crate::sync::SpinLock;
+use crate::{ilog, wlog};
+
+const BLOCK_SIZE: usize = 512;
+
+// header fields, as (offset, length)
+const NAME: (usize, usize) = (0, 100);
+const MODE: (usize, usize) = (100, 8);
+const SIZE: (usize, usize) = (124, 12);
+const CHECKSUM: (usize, usize) = (148, 8);
+const TYPE: usize = 156;
+const MAGIC: (usize, usize) = (257, 5);
+const PREFIX: (usize, usize) = (345, 155);
+
+// entry types
+const TYPE_FILE: u8 = b'0';
+/// What pre-POSIX tar wrote for a file
+const TYPE_FILE_OLD: u8 = 0;
+const TYPE_DIRECTORY: u8 = b'5';
+
+#[cfg(feature = "initrd")]
+static IMAGE: &[u8] = include_bytes!(env!("ZENIX_INITRD"));
+#[cfg(not(feature = "initrd"))]
+static IMAGE: &[u8] = &[];
+
+/// What's wrong with an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitrdError {
+    /// The header at this offset isn't a ustar header
+    BadHeader(usize),
+    /// The header at this offset has a checksum that doesn't match
+    BadChecksum(usize),
+    /// An entry's data runs past the end of the archive
+    Truncated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+    /// Links, devices, and the rest, which are listed but have no data
+    Other,
+}
+
+/// A file or directory in the archive
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Its path, without a leading `/` or `./`, or a trailing `/` for directories
+    pub path: String,
+    pub kind: Kind,
+    pub mode: u32,
+    pub data: &'static [u8],
+}
+
+/// The entries in an archive
+pub struct Archive {
+    entries: Vec<Entry>,
+}
+
+/// A NUL-terminated header field
+fn field(header: &[u8], (offset, len): (usize, usize)) -> &[u8] {
+    let field = &header[offset..offset + len];
+    let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+    &field[..end]
+}
+
+/// An octal number field, which can be padded with spaces or NULs
+fn octal(header: &[u8], location: (usize, usize)) -> Option<u64> {
+    let digits = field(header, location);
+    let digits = core::str::from_utf8(digits).ok()?.trim_matches(' ');
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// Strip what tar can put around a path, so `./bin/hello` and `/bin/hello` are both `bin/hello`
+fn normalize(path: &str) -> &str {
+    let path = path.trim_end_matches('/');
+    let path = path.strip_prefix("./").unwrap_or(path);
+    path.trim_start_matches('/')
+}
+
+impl Archive {
+    /// Index `bytes`, reading up to the first empty block or the end
+    pub fn parse(bytes: &'static [u8]) -> Result<Archive, InitrdError> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + BLOCK_SIZE <= bytes.len() {
+            let header = &bytes[offset..offset + BLOCK_SIZE];
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            if field(header, MAGIC) != b"ustar" {
+                return Err(InitrdError::BadHeader(offset));
+            }
+            // the checksum is the sum of the header's bytes, its own field counted as spaces
+            let (checksum_at, checksum_len) = CHECKSUM;
+            let sum = header[..checksum_at]
+                .iter()
+                .chain(&header[checksum_at + checksum_len..])
+                .map(|&b| b as u64)
+                .sum::<u64>()
+                + b' ' as u64 * checksum_len as u64;
+            if octal(header, CHECKSUM) != Some(sum) {
+                return Err(InitrdError::BadChecksum(offset));
+            }
+
+            let size = octal(header, SIZE).ok_or(InitrdError::BadHeader(offset))? as usize;
+            let start = offset + BLOCK_SIZE;
+            let data = bytes
+                .get(start..start + size)
+                .ok_or(InitrdError::Truncated)?;
+            let kind = match header[TYPE] {
+                TYPE_FILE | TYPE_FILE_OLD => Kind::File,
+                TYPE_DIRECTORY => Kind::Directory,
+                _ => Kind::Other,
+            };
+
+            // long paths are split between the prefix and the name
+            let mut path = String::from_utf8_lossy(field(header, PREFIX)).into_owned();
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(&String::from_utf8_lossy(field(header, NAME)));
+            entries.push(Entry {
+                path: String::from(normalize(&path)),
+                kind,
+                mode: octal(header, MODE).unwrap_or(0) as u32,
+                data: if kind == Kind::File { data } else { &[] },
+            });
+            offset = start + size.next_multiple_of(BLOCK_SIZE);
+        }
+        Ok(Archive { entries })
+    }
+
+    /// The entry at `path`
+    pub fn get(&self, path: &str) -> Option<&Entry> {
+        let path = normalize(path);
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+}
+
+/// A file or directory in the archive, for the [`vfs`]
+struct InitrdInode {
+    archive: &'static Archive,
+    /// Empty for the root
+    path: String,
+    /// None for the root and directories without entries
+    entry: Option<&'static Entry>,
+}
+
+impl InitrdInode {
+    fn is_dir(&self) -> bool {
+        self.entry.is_none_or(|entry| entry.kind == Kind::Directory)
+    }
+}
+
+impl Inode for InitrdInode {
+    fn metadata(&self) -> Metadata {
+        let kind = match self.entry.map(|entry| entry.kind) {
+            None | Some(Kind::Directory) => vfs::Kind::Directory,
+            Some(Kind::File) => vfs::Kind::File,
+            Some(Kind::Other) => vfs::Kind::Device,
+        };
+        Metadata {
+            kind,
+            size: self.entry.map_or(0, |entry| entry.data.len() as u64),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        if !self.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        let path = if self.path.is_empty() {
+            String::from(name)
+        } else {
+            alloc::format!("{}/{}", self.path, name)
+        };
+        let entry = self.archive.get(&path);
+        let implied = || {
+            self.archive.entries.iter().any(|entry| {
+                entry.path.len() > path.len()
+                    && entry.path.starts_with(path.as_str())
+                    && entry.path.as_bytes()[path.len()] == b'/'
+            })
+        };
+        if entry.is_none() && !implied() {
+            return Err(VfsError::NotFound);
+        }
+        Ok(Arc::new(InitrdInode {
+            archive: self.archive,
+            path,
+            entry,
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<vfs::DirEntry>, VfsError> {
+        if !self.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        let mut entries: Vec<vfs::DirEntry> = Vec::new();
+        for entry in &self.archive.entries {
+            // the name under this directory of the entry or the directory it's in
+            let Some(rest) = (if self.path.is_empty() {
+                Some(entry.path.as_str())
+            } else {
+                entry
+                    .path
+                    .strip_prefix(self.path.as_str())
+                    .and_then(|rest| rest.strip_prefix('/'))
+            }) else {
+                continue;
+            };
+            let (name, kind) = match rest.split_once('/') {
+                Some((name, _)) => (name, vfs::Kind::Directory),
+                None if entry.kind == Kind::Directory => (rest, vfs::Kind::Directory),
+                None if entry.kind == Kind::File => (rest, vfs::Kind::File),
+                None => (rest, vfs::Kind::Device),
+            };
+            if !name.is_empty() && !entries.iter().any(|entry| entry.name == name) {
+                entries.push(vfs::DirEntry {
+                    name: String::from(name),
+                    kind,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let data = match self.entry {
+            Some(entry) if entry.kind != Kind::Directory => entry.data,
+            _ => return Err(VfsError::IsADirectory),
+        };
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+}
+
+/// The archive, for the [`vfs`]
+struct InitrdFs(&'static Archive);
+
+impl FileSystem for InitrdFs {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(InitrdInode {
+            archive: self.0,
+            path: String::new(),
+            entry: None,
+        })
+    }
+}
+
+static ARCHIVE: SpinLock<Option<&'static Archive>> = SpinLock::new("initrd", None);
+
+/// The built-in archive, None if there isn't one or it couldn't be read
+pub fn archive() -> Option<&'static Archive> {
+    *ARCHIVE.lock()
+}
+
+/// The contents of the file at `path` in the built-in archive
+pub fn get(path: &str) -> Option<&'static [u8]> {
+    let entry = archive()?.get(path)?;
+    (entry.kind == Kind::File).then_some(entry.data)
+}
+
+fn init() {
+    if IMAGE.is_empty() {
+        return;
+    }
+    match Archive::parse(IMAGE) {
+        Ok(archive) => {
+            ilog!(
+                "initrd: {} entries, {} KiB",
+                archive.entries().len(),
+                IMAGE.len() / 1024
+            );
+            let archive = Box::leak(Box::new(archive));
+            *ARCHIVE.lock() = Some(archive);
+            if let Err(error) = vfs::mount("/", Arc::new(InitrdFs(archive))) {
+                wlog!("initrd: couldn't mount it: {:?}", error);
+            }
+        }
+        Err(error) => wlog!("initrd: couldn't read the archive: {:?}", error),
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "initrd",
+    stage: Stage::Drivers,
+    after: &[],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ustar header for a file of `size` bytes at `path`
+    fn header(path: &str, kind: u8, size: usize) -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+        header[..path.len()].copy_from_slice(path.as_bytes());
+        header[MODE.0..MODE.0 + 7].copy_from_slice(b"0000644");
+        let size = alloc::format!("{:011o}", size);
+        header[SIZE.0..SIZE.0 + 11].copy_from_slice(size.as_bytes());
+        header[TYPE] = kind;
+        header[MAGIC.0..MAGIC.0 + 6].copy_from_slice(b"ustar\0");
+        header[CHECKSUM.0..CHECKSUM.0 + 8].fill(b' ');
+        let sum: u32 = header.iter().map(|&b| b as u32).sum();
+        let sum = alloc::format!("{:06o}\0", sum);
+        header[CHECKSUM.0..CHECKSUM.0 + 7].copy_from_slice(sum.as_bytes());
+        header
+    }
+
+    #[test_case]
+    fn finds_files() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&header("./etc/", TYPE_DIRECTORY, 0));
+        bytes.extend_from_slice(&header("./etc/motd", TYPE_FILE, 6));
+        let mut data = [0; BLOCK_SIZE];
+        data[..6].copy_from_slice(b"hello\n");
+        bytes.extend_from_slice(&data);
+        bytes.extend_from_slice(&[0; 2 * BLOCK_SIZE]);
+        let bytes = Box::leak(bytes.into_boxed_slice());
+
+        let archive = Archive::parse(bytes).unwrap();
+        assert_eq!(archive.entries().len(), 2);
+        assert_eq!(archive.get("etc").unwrap().kind, Kind::Directory);
+        assert_eq!(archive.get("/etc/motd").unwrap().data, b"hello\n");
+        assert!(archive.get("etc/issue").is_none());
+    }
+}
@@ -0,0 +1,12 @@
+//! Filesystems
+//!
+//! Drivers for the formats the kernel can read files from. The on-disk ones sit on top of the
+//! [`block`](crate::block) layer, so any disk the kernel has a driver for works with them, and
+//! the [`initrd`] is read straight out of the kernel image. They're put together into one tree
+//! of paths by the [`vfs`].
+//!
+
+pub mod devfs;
+pub mod fat;
+pub mod initrd;
+pub mod vfs;
@@ -0,0 +1,367 @@
+//! Virtual filesystem
+//!
+//! Every filesystem, whatever it's stored in, is a [`FileSystem`] whose files and directories
+//! are [`Inode`]s, and gets [`mount`]ed somewhere in one tree of paths. [`open`] finds the
+//! mount a path is under, the one with the longest matching prefix, and looks the rest of the
+//! path up one name at a time from that filesystem's root:
+//!
+//! ```ignore
+//! let motd = vfs::open("/mnt/disk/etc/motd")?.read_to_end()?;
+//! ```
+//!
+//! Paths are absolute. `.` is skipped and `..` goes up a level, which never leaves `/`, and
+//! it's worked out on the path before anything is looked up, so it can cross out of a mount.
+//! Mount points don't need a directory to be mounted on: a directory that only has mounts
+//! under it, like `/mnt` when nothing's at `/`, shows up empty apart from those.
+//!
+//! A [`File`] is an inode and a position. Inodes are reference counted, so a file stays
+//! readable after its filesystem is [`unmount`]ed.
+//!
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::block::BlockError;
+use crate::sync::SpinLock;
+
+/// Why a filesystem operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    /// There's nothing at the path
+    NotFound,
+    /// The path isn't absolute
+    RelativePath,
+    /// Part of the path that should be a directory isn't
+    NotADirectory,
+    /// A directory was read or written like a file
+    IsADirectory,
+    /// The filesystem or file can't be written
+    ReadOnly,
+    /// The file can't do that, like reading from one that's only for output
+    NotSupported,
+    /// There's already something mounted there
+    AlreadyMounted,
+    /// The disk underneath failed
+    Io(BlockError),
+    /// What's on the disk doesn't add up
+    Corrupt,
+    /// The file is bigger than [`File::read_to_end`] reads, or never ends
+    TooBig,
+}
+
+/// Most bytes [`File::read_to_end`] reads, files like `/dev/zero` would fill the heap
+pub const MAX_READ_TO_END: usize = 64 * 1024;
+
+impl From<BlockError> for VfsError {
+    fn from(error: BlockError) -> VfsError {
+        VfsError::Io(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+    /// Something that makes up its contents when it's read, like the files in `/dev`
+    Device,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: Kind,
+    /// In bytes, 0 for directories and devices
+    pub size: u64,
+}
+
+/// A name in a directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: Kind,
+}
+
+/// A file or directory in a filesystem. Everything but [`metadata`](Inode::metadata) is for
+/// one or the other, and fails on the wrong kind by default.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// The inode called `name` in this directory
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// Everything in this directory, without `.` and `..`
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// Read from `offset` into `buf`, returning how many bytes that was, 0 at the end
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    /// Write `buf` at `offset`, returning how many bytes that was
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+}
+
+/// Something that can be mounted
+pub trait FileSystem: Send + Sync {
+    /// What kind it is, like `fat`
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+struct Mount {
+    /// Normalized, `/` for the root
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: SpinLock<Vec<Mount>> = SpinLock::new("mounts", Vec::new());
+
+/// The names in `path` with `.` and `..` worked out
+fn components(path: &str) -> Result<Vec<&str>, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::RelativePath);
+    }
+    let mut components = Vec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    Ok(components)
+}
+
+/// `components` as a path, the way mount points are kept
+fn join(components: &[&str]) -> String {
+    let mut path = String::new();
+    for name in components {
+        path.push('/');
+        path.push_str(name);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+/// `path` with `.`, `..`, and repeated slashes worked out, the way [`mount`] keeps it
+pub fn normalize(path: &str) -> Result<String, VfsError> {
+    Ok(join(&components(path)?))
+}
+
+/// The names in a mount point, none for `/`
+fn names(mount: &str) -> Vec<&str> {
+    mount.split('/').filter(|name| !name.is_empty()).collect()
+}
+
+/// Whether the mount point at `mount` is `components` or above it
+fn is_prefix(mount: &str, components: &[&str]) -> bool {
+    components.starts_with(&names(mount))
+}
+
+/// Whether there's a mount point below `components`
+fn has_mount_under(mounts: &[Mount], components: &[&str]) -> bool {
+    mounts.iter().any(|mount| {
+        let names = names(&mount.path);
+        names.len() > components.len() && names.starts_with(components)
+    })
+}
+
+/// Mount `fs` at `path`
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), VfsError> {
+    let path = join(&components(path)?);
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(VfsError::AlreadyMounted);
+    }
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+/// Take away whatever is mounted at `path`
+pub fn unmount(path: &str) -> Result<(), VfsError> {
+    let path = join(&components(path)?);
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == path)
+        .ok_or(VfsError::NotFound)?;
+    mounts.remove(index);
+    Ok(())
+}
+
+/// Call `f` with the path and filesystem of every mount, in the order they were mounted
+pub fn for_each_mount(mut f: impl FnMut(&str, &dyn FileSystem)) {
+    // a copy, so `f` can use the filesystems without the table locked
+    let mounts: Vec<(String, Arc<dyn FileSystem>)> = MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| (mount.path.clone(), mount.fs.clone()))
+        .collect();
+    for (path, fs) in mounts {
+        f(&path, &*fs);
+    }
+}
+
+/// A directory that's only there because there are mounts under it
+struct MountDir;
+
+impl Inode for MountDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: Kind::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Ok(Vec::new())
+    }
+}
+
+/// The inode at `components`
+fn resolve(components: &[&str]) -> Result<Arc<dyn Inode>, VfsError> {
+    let (found, has_mounts_under) = {
+        let mounts = MOUNTS.lock();
+        let found = mounts
+            .iter()
+            .filter(|mount| is_prefix(&mount.path, components))
+            .max_by_key(|mount| names(&mount.path).len())
+            .map(|mount| (mount.fs.clone(), names(&mount.path).len()));
+        (found, has_mount_under(&mounts, components))
+    };
+
+    let walked = match found {
+        Some((fs, depth)) => components[depth..]
+            .iter()
+            .try_fold(fs.root(), |inode, name| inode.lookup(name)),
+        None => Err(VfsError::NotFound),
+    };
+    match walked {
+        Err(VfsError::NotFound) if has_mounts_under => Ok(Arc::new(MountDir)),
+        walked => walked,
+    }
+}
+
+/// The inode at `path`
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, VfsError> {
+    resolve(&components(path)?)
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, VfsError> {
+    Ok(lookup(path)?.metadata())
+}
+
+/// Everything in the directory at `path`, with the mount points right under it
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, VfsError> {
+    let components = components(path)?;
+    let mut entries = resolve(&components)?.read_dir()?;
+    for mount in MOUNTS.lock().iter() {
+        let names = names(&mount.path);
+        if names.len() != components.len() + 1 || !names.starts_with(&components) {
+            continue;
+        }
+        let name = names[components.len()];
+        if !entries.iter().any(|entry| entry.name == name) {
+            entries.push(DirEntry {
+                name: String::from(name),
+                kind: Kind::Directory,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Open the file at `path`
+pub fn open(path: &str) -> Result<File, VfsError> {
+    let inode = lookup(path)?;
+    if inode.metadata().kind == Kind::Directory {
+        return Err(VfsError::IsADirectory);
+    }
+    Ok(File { inode, position: 0 })
+}
+
+/// An open file. A clone is the same file with a position of its own.
+#[derive(Clone)]
+pub struct File {
+    inode: Arc<dyn Inode>,
+    position: u64,
+}
+
+impl File {
+    pub fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+
+    /// Where the next read or write starts
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn seek(&mut self, position: u64) {
+        self.position = position;
+    }
+
+    /// Read from the position into `buf` and move past it. Returns how many bytes that was,
+    /// 0 at the end.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let len = self.inode.read_at(self.position, buf)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    /// Write `buf` at the position and move past it
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
+        let len = self.inode.write_at(self.position, buf)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    /// Read from the position to the end, up to [`MAX_READ_TO_END`] bytes
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>, VfsError> {
+        let mut bytes = Vec::new();
+        let mut chunk = [0; 512];
+        loop {
+            let len = self.read(&mut chunk)?;
+            if len == 0 {
+                return Ok(bytes);
+            }
+            if bytes.len() + len > MAX_READ_TO_END {
+                return Err(VfsError::TooBig);
+            }
+            bytes.extend_from_slice(&chunk[..len]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn normalizes_paths() {
+        assert_eq!(components("/a/./b//c/..").unwrap(), ["a", "b"]);
+        assert_eq!(components("/../..").unwrap(), [] as [&str; 0]);
+        assert_eq!(components("a/b"), Err(VfsError::RelativePath));
+        assert_eq!(join(&["mnt", "disk"]), "/mnt/disk");
+        assert_eq!(join(&[]), "/");
+        assert_eq!(normalize("/mnt//disk/../disk/").unwrap(), "/mnt/disk");
+        assert!(is_prefix("/mnt", &["mnt", "disk"]));
+        assert!(is_prefix("/", &["mnt"]));
+        assert!(!is_prefix("/mnt/disk", &["mnt"]));
+    }
+}
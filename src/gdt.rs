@@ -0,0 +1,136 @@
+//! Global descriptor table and task state segment
+//!
+//! Segmentation is mostly switched off in long mode, but the CPU still wants a GDT with a code
+//! segment for CS, and a TSS descriptor. The TSS is what holds the interrupt stack table: the
+//! known-good stacks the CPU can switch to when an exception comes in, so a handler still
+//! works when the stack it interrupted is gone. It also has the stack the CPU moves to when an
+//! interrupt comes in from user mode, which the [`sched`](crate::sched) points at the running
+//! thread's own with [`set_kernel_stack`].
+//!
+//! The user segments come after the TSS, data first, since that's the order `sysret` expects
+//! to find them in, see [`syscall`](crate::syscall). Every CPU's GDT has the same layout, so
+//! the [`selectors`] are the same everywhere.
+//!
+//! links:
+//! - reference post: <https://os.phil-opp.com/double-fault-exceptions/>
+//! - <https://wiki.osdev.org/Global_Descriptor_Table>
+//! - <https://wiki.osdev.org/Task_State_Segment>
+//!
+
+use alloc::boxed::Box;
+
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+use crate::init::{InitCall, Stage};
+use crate::mem::stack;
+use crate::wlog;
+
+/// Interrupt stack table slot of the stack double faults run on
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+pub const IST_STACK_SIZE: usize = 4096 * 5;
+
+pub struct Selectors {
+    pub code: SegmentSelector,
+    pub data: SegmentSelector,
+    pub tss: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+}
+
+/// The boot CPU's TSS, filled in by [`init`] and only changed by [`set_kernel_stack`] after
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let tss: *const TaskStateSegment = &raw const TSS;
+        let selectors = append_segments(&mut gdt, unsafe { &*tss });
+        (gdt, selectors)
+    };
+}
+
+fn append_segments(gdt: &mut GlobalDescriptorTable, tss: &'static TaskStateSegment) -> Selectors {
+    Selectors {
+        code: gdt.append(Descriptor::kernel_code_segment()),
+        data: gdt.append(Descriptor::kernel_data_segment()),
+        tss: gdt.append(Descriptor::tss_segment(tss)),
+        user_data: gdt.append(Descriptor::user_data_segment()),
+        user_code: gdt.append(Descriptor::user_code_segment()),
+    }
+}
+
+/// The segment selectors, the same on every CPU
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.code);
+        SS::set_reg(selectors.data);
+        DS::set_reg(selectors.data);
+        ES::set_reg(selectors.data);
+        load_tss(selectors.tss);
+    }
+}
+
+/// Have interrupts from user mode on the boot CPU switch to the stack ending at `top`.
+/// Threads only run on the boot CPU, so the others don't need it.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { (&raw mut TSS.privilege_stack_table[0]).write_volatile(top) };
+}
+
+/// Move the boot CPU's double faults to a stack with a guard page, the one they start out on
+/// is in the kernel image with nothing unmapped under it
+fn init_stacks() {
+    match stack::allocate("double fault", IST_STACK_SIZE) {
+        Ok(stack) => unsafe {
+            (&raw mut TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize])
+                .write_volatile(stack.leak())
+        },
+        Err(error) => wlog!("gdt: no guarded double fault stack: {:?}", error),
+    }
+}
+
+fn init() {
+    static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+    // stacks grow down, so the CPU wants the end
+    let stack_end = VirtAddr::from_ptr(&raw const STACK) + IST_STACK_SIZE as u64;
+    unsafe { TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end };
+
+    let (gdt, selectors) = &*GDT;
+    load(gdt, selectors);
+}
+
+/// Give an application processor a GDT and TSS of its own, with double faults running on
+/// `double_fault_stack`. A TSS is marked busy once it's loaded, so CPUs can't share one.
+pub fn init_ap(double_fault_stack: VirtAddr) {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack;
+    let tss = Box::leak(Box::new(tss));
+
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let selectors = append_segments(gdt, tss);
+    load(gdt, &selectors);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "gdt",
+    stage: Stage::Early,
+    after: &[],
+    func: init,
+};
+
+pub const STACK_INIT: InitCall = InitCall {
+    name: "gdt stacks",
+    stage: Stage::Memory,
+    after: &["paging"],
+    func: init_stacks,
+};
@@ -0,0 +1,127 @@
+//! Bochs/QEMU VBE display interface ("dispi")
+//!
+//! QEMU's standard VGA (and Bochs, VirtualBox's default adapter) can be switched into a linear
+//! framebuffer mode of any size through a handful of registers behind an index/data port pair.
+//! The framebuffer itself is PCI BAR 0 of the card.
+//!
+//! links:
+//! - registers: <https://wiki.osdev.org/Bochs_VBE_Extensions>
+//! - QEMU source: <https://gitlab.com/qemu-project/qemu/-/blob/master/include/hw/display/bochs-vbe.h>
+//!
+
+use x86_64::structures::port::{PortRead as _, PortWrite as _};
+use x86_64::PhysAddr;
+
+use super::{FramebufferInfo, PixelFormat};
+use crate::{mem, pci};
+
+const DISPI_INDEX: u16 = 0x1ce;
+const DISPI_DATA: u16 = 0x1cf;
+
+const REG_ID: u16 = 0;
+const REG_XRES: u16 = 1;
+const REG_YRES: u16 = 2;
+const REG_BPP: u16 = 3;
+const REG_ENABLE: u16 = 4;
+const REG_VIRT_WIDTH: u16 = 6;
+const REG_X_OFFSET: u16 = 8;
+const REG_Y_OFFSET: u16 = 9;
+
+/// Oldest and newest interface versions, anything in between understands what's used here
+const ID_MIN: u16 = 0xb0c0;
+const ID_MAX: u16 = 0xb0c5;
+
+const ENABLED: u16 = 0x01;
+/// Makes the resolution registers read back the maximums instead
+const GET_CAPS: u16 = 0x02;
+const LFB_ENABLED: u16 = 0x40;
+
+const PCI_VENDOR_ID: u16 = 0x1234;
+const PCI_DEVICE_ID: u16 = 0x1111;
+
+/// Why a mode couldn't be set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeError {
+    /// There's no Bochs compatible display adapter
+    NoDevice,
+    /// The adapter can't do this size or depth, or we can't draw at that depth
+    Unsupported,
+    /// The adapter didn't take the mode, or there wasn't the memory for it
+    Failed,
+}
+
+fn read(reg: u16) -> u16 {
+    unsafe {
+        u16::write_to_port(DISPI_INDEX, reg);
+        u16::read_from_port(DISPI_DATA)
+    }
+}
+
+fn write(reg: u16, value: u16) {
+    unsafe {
+        u16::write_to_port(DISPI_INDEX, reg);
+        u16::write_to_port(DISPI_DATA, value);
+    }
+}
+
+/// Whether the dispi interface is there at all
+pub fn detect() -> bool {
+    (ID_MIN..=ID_MAX).contains(&read(REG_ID))
+}
+
+/// Largest resolution and depth the adapter supports, as (width, height, bpp)
+fn max_mode() -> (usize, usize, usize) {
+    let enable = read(REG_ENABLE);
+    write(REG_ENABLE, enable | GET_CAPS);
+    let max = (
+        read(REG_XRES) as usize,
+        read(REG_YRES) as usize,
+        read(REG_BPP) as usize,
+    );
+    write(REG_ENABLE, enable);
+    max
+}
+
+/// Physical address of the linear framebuffer, from the adapter's BAR 0
+fn lfb_address() -> Option<PhysAddr> {
+    let bar = pci::find(PCI_VENDOR_ID, PCI_DEVICE_ID)?.bar(0) & !0xf;
+    (bar != 0).then(|| PhysAddr::new(bar as u64))
+}
+
+/// Switch to a `width` by `height` linear framebuffer mode at `bpp` bits per pixel
+pub fn set_mode(width: usize, height: usize, bpp: usize) -> Result<FramebufferInfo, ModeError> {
+    if !detect() {
+        return Err(ModeError::NoDevice);
+    }
+    let phys_addr = lfb_address().ok_or(ModeError::NoDevice)?;
+
+    let format = PixelFormat::from_bpp(bpp).ok_or(ModeError::Unsupported)?;
+    let (max_width, max_height, max_bpp) = max_mode();
+    if width == 0 || height == 0 || width > max_width || height > max_height || bpp > max_bpp {
+        return Err(ModeError::Unsupported);
+    }
+
+    // the registers only take effect while the interface is disabled
+    write(REG_ENABLE, 0);
+    write(REG_XRES, width as u16);
+    write(REG_YRES, height as u16);
+    write(REG_BPP, bpp as u16);
+    write(REG_VIRT_WIDTH, width as u16);
+    write(REG_X_OFFSET, 0);
+    write(REG_Y_OFFSET, 0);
+    write(REG_ENABLE, ENABLED | LFB_ENABLED);
+
+    let pitch = read(REG_VIRT_WIDTH) as usize * bpp / 8;
+    Ok(FramebufferInfo {
+        phys_addr,
+        // the window covers this as long as the memory map reaches 4 GiB, which QEMU's does
+        // because of the BIOS ROM mapped just below it
+        virt_addr: mem::phys_to_virt(phys_addr),
+        width: read(REG_XRES) as usize,
+        height: read(REG_YRES) as usize,
+        pitch,
+        bpp,
+        format,
+        present: None,
+    })
+}
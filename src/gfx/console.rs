@@ -0,0 +1,513 @@
+//! Text console on the framebuffer
+//!
+//! Keeps a grid of character cells and draws each one with the VGA 8x16 font whenever it
+//! changes. `font=PATH` on the command line picks a PSF font from the initrd instead, which
+//! [`FONT_INIT`] switches to once the initrd's there. Cells hold code page 437 bytes either
+//! way, and a font with a Unicode table has each one looked up in it, once, when it's loaded. Scrolling moves the pixels that are already there instead of drawing every glyph
+//! again. Colors come from the usual 16 color VGA palette, and ANSI escape sequences for colors,
+//! cursor movement, and erasing are understood.
+//!
+//! The cells are the console's [terminal](crate::tty). While another terminal is on the
+//! screen they're [hidden](FramebufferConsole::set_visible): they keep up with what's written,
+//! and the other terminal's cells are drawn with [`draw_other`](FramebufferConsole::draw_other)
+//! instead.
+//!
+
+use alloc::boxed::Box;
+use core::fmt;
+use core::ops::Range;
+
+use x86_64::instructions::interrupts;
+
+use super::font::psf::Psf;
+use super::font::{Font, REPLACEMENT_GLYPH, VGA_8X16};
+use super::framebuffer::{self, Framebuffer};
+use super::{Rect, Rgb};
+use crate::console::ansi::{self, Csi, Output, Parser};
+use crate::console::cp437;
+use crate::console::{Color, Console};
+use crate::fs::initrd;
+use crate::init::{InitCall, Stage};
+use crate::sync::SpinLock;
+use crate::{cmdline, ilog, speaker, wlog};
+
+/// Enough cells for 1920x1200 with an 8x16 font
+const MAX_COLS: usize = 240;
+const MAX_ROWS: usize = 75;
+
+/// Height of the underline cursor in pixels
+const CURSOR_HEIGHT: usize = 2;
+
+const DEFAULT_FG: Color = Color::White;
+const DEFAULT_BG: Color = Color::Black;
+
+/// What each [`Color`] looks like, the standard VGA text mode palette
+const PALETTE: [Rgb; 16] = [
+    Rgb::new(0x00, 0x00, 0x00),
+    Rgb::new(0x00, 0x00, 0xaa),
+    Rgb::new(0x00, 0xaa, 0x00),
+    Rgb::new(0x00, 0xaa, 0xaa),
+    Rgb::new(0xaa, 0x00, 0x00),
+    Rgb::new(0xaa, 0x00, 0xaa),
+    Rgb::new(0xaa, 0x55, 0x00),
+    Rgb::new(0xaa, 0xaa, 0xaa),
+    Rgb::new(0x55, 0x55, 0x55),
+    Rgb::new(0x55, 0x55, 0xff),
+    Rgb::new(0x55, 0xff, 0x55),
+    Rgb::new(0x55, 0xff, 0xff),
+    Rgb::new(0xff, 0x55, 0x55),
+    Rgb::new(0xff, 0x55, 0xff),
+    Rgb::new(0xff, 0xff, 0x55),
+    Rgb::new(0xff, 0xff, 0xff),
+];
+
+fn rgb(color: Color) -> Rgb {
+    PALETTE[color as usize]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    /// Glyph index into the font
+    glyph: u8,
+    fg: Color,
+    bg: Color,
+}
+
+impl Cell {
+    const BLANK: Cell = Cell {
+        glyph: b' ',
+        fg: DEFAULT_FG,
+        bg: DEFAULT_BG,
+    };
+}
+
+pub struct FramebufferConsole {
+    font: &'static Font,
+    /// The font's glyph for each CP437 byte
+    glyphs: [u16; 256],
+    cells: [[Cell; MAX_COLS]; MAX_ROWS],
+    cols: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    fg: Color,
+    bg: Color,
+    default_fg: Color,
+    default_bg: Color,
+    parser: Parser,
+    /// Whether the cells are on the screen, another [terminal](crate::tty)'s can be instead
+    visible: bool,
+}
+
+impl FramebufferConsole {
+    /// A console covering a `width` by `height` pixel screen
+    pub fn new(width: usize, height: usize) -> FramebufferConsole {
+        let (font, glyphs) = match *PSF.lock() {
+            Some(psf) => (&psf.font, glyph_map(psf)),
+            None => (&VGA_8X16, IN_ORDER),
+        };
+        FramebufferConsole {
+            font,
+            glyphs,
+            cells: [[Cell::BLANK; MAX_COLS]; MAX_ROWS],
+            cols: (width / font.width).clamp(1, MAX_COLS),
+            rows: (height / font.height).clamp(1, MAX_ROWS),
+            col: 0,
+            row: 0,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            default_fg: DEFAULT_FG,
+            default_bg: DEFAULT_BG,
+            parser: Parser::new(),
+            visible: true,
+        }
+    }
+
+    /// Draw with `psf` from now on, fitting as many cells on a `width` by `height` pixel screen
+    /// as it leaves room for
+    fn set_font(&mut self, psf: &'static Psf, width: usize, height: usize) {
+        self.font = &psf.font;
+        self.glyphs = glyph_map(psf);
+        self.resize(width, height);
+    }
+
+    /// Take the cells off `fb`, or put them back on it. Everything written still goes into
+    /// the cells while they're off, and nothing's drawn.
+    pub fn set_visible(&mut self, fb: &mut Framebuffer, visible: bool) {
+        self.visible = visible;
+        if !visible {
+            return;
+        }
+        let screen = Rect::new(0, 0, fb.width(), fb.height());
+        fb.fill_rect(screen, rgb(self.bg));
+        self.redraw(fb);
+        self.show_cursor(fb);
+        fb.flush();
+    }
+
+    /// Draw `rows` of a grid of cells that aren't the console's on `fb`, while it's
+    /// [hidden](Self::set_visible), with the whole screen blanked first if `blank`. `cell`
+    /// gives the glyph and colors at a row and column, and the columns go up to `cols`.
+    pub fn draw_other(
+        &self,
+        fb: &mut Framebuffer,
+        blank: bool,
+        rows: Range<usize>,
+        cols: usize,
+        cell: impl Fn(usize, usize) -> (u8, Color, Color),
+    ) {
+        if blank {
+            let screen = Rect::new(0, 0, fb.width(), fb.height());
+            fb.fill_rect(screen, rgb(DEFAULT_BG));
+        }
+        for row in rows.start.min(self.rows)..rows.end.min(self.rows) {
+            for col in 0..cols.min(self.cols) {
+                let (glyph, fg, bg) = cell(row, col);
+                fb.draw_glyph(
+                    col * self.font.width,
+                    row * self.font.height,
+                    self.font,
+                    self.glyphs[glyph as usize] as usize,
+                    rgb(fg),
+                    rgb(bg),
+                );
+            }
+        }
+        fb.flush();
+    }
+
+    /// Fit the console to a `width` by `height` pixel screen
+    ///
+    /// Text that still fits stays where it is. If the cursor's row is gone, everything moves
+    /// up to keep it on the last row.
+    fn resize(&mut self, width: usize, height: usize) {
+        let (old_cols, old_rows) = (self.cols, self.rows);
+        self.cols = (width / self.font.width).clamp(1, MAX_COLS);
+        self.rows = (height / self.font.height).clamp(1, MAX_ROWS);
+
+        // cells outside the old screen can have anything left in them
+        for row in &mut self.cells[..old_rows] {
+            row[old_cols..].fill(Cell::BLANK);
+        }
+        self.cells[old_rows..].fill([Cell::BLANK; MAX_COLS]);
+
+        if self.row >= self.rows {
+            let shift = self.row + 1 - self.rows;
+            self.cells.copy_within(shift..old_rows, 0);
+            self.cells[old_rows - shift..].fill([Cell::BLANK; MAX_COLS]);
+            self.row = self.rows - 1;
+        }
+        self.col = self.col.min(self.cols);
+
+        if let Some(fb) = framebuffer::FRAMEBUFFER.lock().as_mut() {
+            self.redraw(fb);
+            self.show_cursor(fb);
+            fb.flush();
+        }
+    }
+
+    fn blank(&self) -> Cell {
+        Cell {
+            glyph: b' ',
+            fg: self.fg,
+            bg: self.bg,
+        }
+    }
+
+    fn draw_cell(&self, fb: &mut Framebuffer, col: usize, row: usize) {
+        if !self.visible {
+            return;
+        }
+        let cell = self.cells[row][col];
+        fb.draw_glyph(
+            col * self.font.width,
+            row * self.font.height,
+            self.font,
+            self.glyphs[cell.glyph as usize] as usize,
+            rgb(cell.fg),
+            rgb(cell.bg),
+        );
+    }
+
+    fn redraw(&self, fb: &mut Framebuffer) {
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                self.draw_cell(fb, col, row);
+            }
+        }
+    }
+
+    /// Position the cursor is drawn at, which stays on screen after writing the last column
+    fn cursor_cell(&self) -> (usize, usize) {
+        (self.col.min(self.cols - 1), self.row)
+    }
+
+    fn show_cursor(&self, fb: &mut Framebuffer) {
+        if !self.visible {
+            return;
+        }
+        let (col, row) = self.cursor_cell();
+        let cursor = Rect {
+            x: col * self.font.width,
+            y: (row + 1) * self.font.height - CURSOR_HEIGHT,
+            width: self.font.width,
+            height: CURSOR_HEIGHT,
+        };
+        fb.fill_rect(cursor, rgb(self.fg));
+    }
+
+    fn hide_cursor(&self, fb: &mut Framebuffer) {
+        let (col, row) = self.cursor_cell();
+        self.draw_cell(fb, col, row);
+    }
+
+    fn new_line(&mut self, fb: &mut Framebuffer) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+
+        // out of rows, shift everything up and start over on a blank last line. the pixels
+        // move along with the cells, so nothing has to be drawn again
+        self.cells.copy_within(1..self.rows, 0);
+        let blank = self.blank();
+        self.cells[self.rows - 1][..self.cols].fill(blank);
+        if self.visible {
+            fb.scroll_up(self.rows * self.font.height, self.font.height, rgb(self.bg));
+        }
+    }
+
+    fn put_glyph(&mut self, fb: &mut Framebuffer, glyph: u8) {
+        if self.col >= self.cols {
+            self.new_line(fb);
+        }
+
+        self.cells[self.row][self.col] = Cell {
+            glyph,
+            fg: self.fg,
+            bg: self.bg,
+        };
+        self.draw_cell(fb, self.col, self.row);
+        self.col += 1;
+    }
+
+    /// Blank the cells from `start` up to `end`, both counted in cells from the top left
+    fn erase(&mut self, fb: &mut Framebuffer, start: usize, end: usize) {
+        let blank = self.blank();
+        for i in start..end.min(self.cols * self.rows) {
+            let (col, row) = (i % self.cols, i / self.cols);
+            self.cells[row][col] = blank;
+            self.draw_cell(fb, col, row);
+        }
+    }
+
+    fn handle_csi(&mut self, fb: &mut Framebuffer, csi: &Csi) {
+        let n = csi.param(0, 1) as usize;
+        let (col, row) = self.cursor_cell();
+        let here = row * self.cols + col;
+        let line_start = row * self.cols;
+
+        match csi.action {
+            'm' => ansi::apply_sgr(
+                csi,
+                &mut self.fg,
+                &mut self.bg,
+                self.default_fg,
+                self.default_bg,
+            ),
+            'H' | 'f' => {
+                self.row = (csi.param(0, 1) as usize - 1).min(self.rows - 1);
+                self.col = (csi.param(1, 1) as usize - 1).min(self.cols - 1);
+            }
+            'A' => self.row = row.saturating_sub(n),
+            'B' => self.row = (row + n).min(self.rows - 1),
+            'C' => self.col = (col + n).min(self.cols - 1),
+            'D' => self.col = col.saturating_sub(n),
+            'J' => match csi.param(0, 0) {
+                0 => self.erase(fb, here, self.cols * self.rows),
+                1 => self.erase(fb, 0, here + 1),
+                2 => self.erase(fb, 0, self.cols * self.rows),
+                _ => {}
+            },
+            'K' => match csi.param(0, 0) {
+                0 => self.erase(fb, here, line_start + self.cols),
+                1 => self.erase(fb, line_start, here + 1),
+                2 => self.erase(fb, line_start, line_start + self.cols),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn write_char(&mut self, fb: &mut Framebuffer, c: char) {
+        match self.parser.feed(c) {
+            Some(Output::Char('\n')) => self.new_line(fb),
+            Some(Output::Char('\r')) => self.col = 0,
+            Some(Output::Char('\t')) => self.col = ((self.col / 8 + 1) * 8).min(self.cols),
+            Some(Output::Char('\x08')) => self.col = self.col.saturating_sub(1),
+            Some(Output::Char('\x07')) => speaker::bell(),
+            Some(Output::Char(c)) => {
+                self.put_glyph(fb, cp437::encode(c).unwrap_or(cp437::REPLACEMENT))
+            }
+            Some(Output::Csi(csi)) => self.handle_csi(fb, &csi),
+            None => {}
+        }
+    }
+}
+
+impl fmt::Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut fb = framebuffer::FRAMEBUFFER.lock();
+        let Some(fb) = fb.as_mut() else {
+            return Ok(());
+        };
+
+        self.hide_cursor(fb);
+        for c in s.chars() {
+            self.write_char(fb, c);
+        }
+        self.show_cursor(fb);
+        fb.flush();
+        Ok(())
+    }
+}
+
+impl Console for FramebufferConsole {
+    fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    fn clear(&mut self) {
+        let mut fb = framebuffer::FRAMEBUFFER.lock();
+        let Some(fb) = fb.as_mut() else {
+            return;
+        };
+
+        self.erase(fb, 0, self.cols * self.rows);
+        self.col = 0;
+        self.row = 0;
+        self.show_cursor(fb);
+        fb.flush();
+    }
+
+    fn color(&self) -> (Color, Color) {
+        (self.default_fg, self.default_bg)
+    }
+
+    fn set_color(&mut self, foreground: Color, background: Color) {
+        self.fg = foreground;
+        self.bg = background;
+        self.default_fg = foreground;
+        self.default_bg = background;
+    }
+}
+
+/// CP437 bytes are the glyph indexes in the fonts that don't say otherwise
+const IN_ORDER: [u16; 256] = {
+    let mut glyphs = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        glyphs[i] = i as u16;
+        i += 1;
+    }
+    glyphs
+};
+
+/// The glyph in `psf` for each CP437 byte, through its Unicode table if it has one
+fn glyph_map(psf: &Psf) -> [u16; 256] {
+    if !psf.has_unicode() {
+        return IN_ORDER;
+    }
+    let missing = psf
+        .glyph_index('\u{fffd}')
+        .or_else(|| psf.glyph_index('?'))
+        .unwrap_or(REPLACEMENT_GLYPH);
+    let mut glyphs = [0; 256];
+    for (byte, glyph) in glyphs.iter_mut().enumerate() {
+        let index = psf
+            .glyph_index(cp437::decode(byte as u8))
+            .unwrap_or(missing);
+        *glyph = index as u16;
+    }
+    glyphs
+}
+
+/// The font `font=` loaded, for consoles set up after it
+static PSF: SpinLock<Option<&'static Psf>> = SpinLock::new("fbcon font", None);
+
+/// The framebuffer console, `None` until it's set up or if there's no framebuffer
+pub static CONSOLE: SpinLock<Option<FramebufferConsole>> = SpinLock::new("fbcon", None);
+
+pub const INIT: InitCall = InitCall {
+    name: "fbcon",
+    stage: Stage::Early,
+    after: &["gfx"],
+    func: init,
+};
+
+/// Move console output over to the framebuffer, if there is one
+fn init() {
+    let size = framebuffer::FRAMEBUFFER
+        .lock()
+        .as_ref()
+        .map(|fb| (fb.width(), fb.height()));
+
+    if let Some((width, height)) = size {
+        resize(width, height);
+    }
+}
+
+/// Fit the console to a `width` by `height` pixel screen, setting it up if there wasn't one
+pub fn resize(width: usize, height: usize) {
+    let mut console = CONSOLE.lock();
+    match console.as_mut() {
+        Some(console) => console.resize(width, height),
+        None => *console = Some(FramebufferConsole::new(width, height)),
+    }
+}
+
+pub const FONT_INIT: InitCall = InitCall {
+    name: "font",
+    stage: Stage::Drivers,
+    after: &["initrd"],
+    func: load_font,
+};
+
+/// Switch to the font `font=` names in the initrd, keeping the built-in one if it's missing or
+/// isn't a font
+fn load_font() {
+    let Some(path) = cmdline::get("font") else {
+        return;
+    };
+    let Some(file) = initrd::get(path) else {
+        wlog!("fbcon: no font {} in the initrd", path);
+        return;
+    };
+    let psf = match Psf::parse(file) {
+        Ok(psf) => &*Box::leak(Box::new(psf)),
+        Err(error) => {
+            wlog!("fbcon: can't use {}: {:?}", path, error);
+            return;
+        }
+    };
+    *PSF.lock() = Some(psf);
+
+    let size = framebuffer::FRAMEBUFFER
+        .lock()
+        .as_ref()
+        .map(|fb| (fb.width(), fb.height()));
+    if let Some((width, height)) = size {
+        interrupts::without_interrupts(|| {
+            if let Some(console) = CONSOLE.lock().as_mut() {
+                console.set_font(psf, width, height);
+            }
+        });
+    }
+    ilog!(
+        "fbcon: font {}, {}x{}",
+        path,
+        psf.font.width,
+        psf.font.height
+    );
+}
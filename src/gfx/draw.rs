@@ -0,0 +1,175 @@
+//! 2D drawing primitives
+//!
+//! Shapes are given in signed pixel coordinates and may hang off any edge of the screen, only
+//! the visible part gets drawn. Everything ends up as single pixels or horizontal spans on the
+//! [`Framebuffer`], so it goes through the back buffer and dirty tracking like anything else.
+//!
+//! links:
+//! - Bresenham's line algorithm: <https://en.wikipedia.org/wiki/Bresenham%27s_line_algorithm>
+//! - midpoint circle algorithm: <https://en.wikipedia.org/wiki/Midpoint_circle_algorithm>
+//!
+
+use super::framebuffer::Framebuffer;
+use super::{Rect, Rgb};
+
+/// Most vertices [`fill_polygon`] handles, any after that are ignored
+pub const MAX_POLYGON_POINTS: usize = 64;
+
+fn pixel(fb: &mut Framebuffer, x: isize, y: isize, color: Rgb) {
+    if x >= 0 && y >= 0 {
+        fb.put_pixel(x as usize, y as usize, color);
+    }
+}
+
+/// Fill the pixels from `x0` to `x1` on row `y`, in either order
+fn span(fb: &mut Framebuffer, x0: isize, x1: isize, y: isize, color: Rgb) {
+    let (left, right) = (x0.min(x1).max(0), x0.max(x1));
+    if y < 0 || right < left {
+        return;
+    }
+
+    let rect = Rect {
+        x: left as usize,
+        y: y as usize,
+        width: (right - left + 1) as usize,
+        height: 1,
+    };
+    fb.fill_rect(rect, color);
+}
+
+/// Draw a line from (`x0`, `y0`) to (`x1`, `y1`), both ends included
+pub fn line(fb: &mut Framebuffer, x0: isize, y0: isize, x1: isize, y1: isize, color: Rgb) {
+    let dx = (x1 - x0).abs();
+    let dy = -(y1 - y0).abs();
+    let step_x = if x0 < x1 { 1 } else { -1 };
+    let step_y = if y0 < y1 { 1 } else { -1 };
+
+    let (mut x, mut y) = (x0, y0);
+    let mut error = dx + dy;
+    loop {
+        pixel(fb, x, y, color);
+        if x == x1 && y == y1 {
+            break;
+        }
+
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            y += step_y;
+        }
+    }
+}
+
+/// Draw the outline of `rect`, one pixel wide
+pub fn rect(fb: &mut Framebuffer, rect: Rect, color: Rgb) {
+    if rect.width == 0 || rect.height == 0 {
+        return;
+    }
+
+    let (left, top) = (rect.x as isize, rect.y as isize);
+    let right = left + rect.width as isize - 1;
+    let bottom = top + rect.height as isize - 1;
+
+    span(fb, left, right, top, color);
+    span(fb, left, right, bottom, color);
+    for y in top + 1..bottom {
+        pixel(fb, left, y, color);
+        pixel(fb, right, y, color);
+    }
+}
+
+/// Fill `rect`
+pub fn fill_rect(fb: &mut Framebuffer, rect: Rect, color: Rgb) {
+    fb.fill_rect(rect, color);
+}
+
+/// Call `f` with every (x, y) offset on one octant of a circle of `radius`, going from the
+/// top towards the diagonal
+fn circle_octant(radius: isize, mut f: impl FnMut(isize, isize)) {
+    let (mut x, mut y) = (0, radius);
+    let mut error = 1 - radius;
+    while x <= y {
+        f(x, y);
+        x += 1;
+        if error < 0 {
+            error += 2 * x + 1;
+        } else {
+            y -= 1;
+            error += 2 * (x - y) + 1;
+        }
+    }
+}
+
+/// Draw the outline of a circle centered on (`cx`, `cy`)
+pub fn circle(fb: &mut Framebuffer, cx: isize, cy: isize, radius: isize, color: Rgb) {
+    if radius < 0 {
+        return;
+    }
+
+    circle_octant(radius, |x, y| {
+        for (px, py) in [
+            (x, y),
+            (y, x),
+            (-x, y),
+            (-y, x),
+            (x, -y),
+            (y, -x),
+            (-x, -y),
+            (-y, -x),
+        ] {
+            pixel(fb, cx + px, cy + py, color);
+        }
+    });
+}
+
+/// Fill a circle centered on (`cx`, `cy`)
+pub fn fill_circle(fb: &mut Framebuffer, cx: isize, cy: isize, radius: isize, color: Rgb) {
+    if radius < 0 {
+        return;
+    }
+
+    circle_octant(radius, |x, y| {
+        span(fb, cx - x, cx + x, cy + y, color);
+        span(fb, cx - x, cx + x, cy - y, color);
+        span(fb, cx - y, cx + y, cy + x, color);
+        span(fb, cx - y, cx + y, cy - x, color);
+    });
+}
+
+/// Fill the polygon with corners `points`, using the even-odd rule
+///
+/// The last point connects back to the first. Only the first [`MAX_POLYGON_POINTS`] are used.
+pub fn fill_polygon(fb: &mut Framebuffer, points: &[(isize, isize)], color: Rgb) {
+    let points = &points[..points.len().min(MAX_POLYGON_POINTS)];
+    if points.len() < 3 {
+        return;
+    }
+
+    let top = points.iter().map(|p| p.1).min().unwrap().max(0);
+    let bottom = points.iter().map(|p| p.1).max().unwrap();
+    let bottom = bottom.min(fb.height() as isize - 1);
+
+    let mut crossings = [0isize; MAX_POLYGON_POINTS];
+    for y in top..=bottom {
+        // where each edge crosses this row
+        let mut count = 0;
+        for (i, &(x0, y0)) in points.iter().enumerate() {
+            let (x1, y1) = points[(i + 1) % points.len()];
+            // half open, so a vertex shared by two edges is only counted once
+            if (y0 <= y) != (y1 <= y) {
+                crossings[count] = x0 + (y - y0) * (x1 - x0) / (y1 - y0);
+                count += 1;
+            }
+        }
+
+        let crossings = &mut crossings[..count];
+        crossings.sort_unstable();
+        for pair in crossings.chunks_exact(2) {
+            span(fb, pair[0], pair[1], y, color);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Bitmap fonts
+//!
+//! Glyphs are stored one after another as 1bpp bitmaps. Each row is padded out to a whole
+//! number of bytes and the most significant bit is the leftmost pixel, which is the layout
+//! used by both the VGA ROM fonts and PSF files, which [`psf`] can parse.
+//!
+
+pub mod psf;
+mod vga8x16;
+
+/// A fixed-width bitmap font, indexed by code page 437 byte
+pub struct Font {
+    /// Glyph width in pixels
+    pub width: usize,
+    /// Glyph height in pixels
+    pub height: usize,
+    data: &'static [u8],
+}
+
+impl Font {
+    /// Bytes taken up by one row of a glyph
+    pub fn bytes_per_row(&self) -> usize {
+        self.width.div_ceil(8)
+    }
+
+    /// Bytes taken up by a whole glyph
+    pub fn bytes_per_glyph(&self) -> usize {
+        self.bytes_per_row() * self.height
+    }
+
+    /// Number of glyphs in the font
+    pub fn glyph_count(&self) -> usize {
+        self.data.len() / self.bytes_per_glyph()
+    }
+
+    /// The bitmap for `index`, or for the replacement glyph if the font doesn't have one
+    pub fn glyph(&self, index: usize) -> &'static [u8] {
+        let index = if index < self.glyph_count() {
+            index
+        } else if REPLACEMENT_GLYPH < self.glyph_count() {
+            REPLACEMENT_GLYPH
+        } else {
+            0
+        };
+
+        let size = self.bytes_per_glyph();
+        &self.data[index * size..(index + 1) * size]
+    }
+}
+
+/// Glyph used for anything a font can't show, a filled square in code page 437
+pub const REPLACEMENT_GLYPH: usize = 0xfe;
+
+/// The 8x16 font from the VGA BIOS
+pub static VGA_8X16: Font = Font {
+    width: 8,
+    height: 16,
+    data: &vga8x16::DATA,
+};
@@ -0,0 +1,241 @@
+//! PC Screen Font (PSF) parsing
+//!
+//! Both versions store a header, the glyph bitmaps in the same layout as [`Font`], and
+//! optionally a table mapping each glyph to the Unicode characters it can show. PSF1 fonts are
+//! always 8 pixels wide with 256 or 512 glyphs and store the table as UTF-16 code units. PSF2
+//! allows any size and stores it as UTF-8.
+//!
+//! Fonts are parsed in place, so they borrow from wherever the file was loaded.
+//!
+//! links:
+//! - format description: <https://www.win.tue.nl/~aeb/linux/kbd/font-formats-1.html>
+//!
+
+use super::Font;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TAB: u8 = 0x02;
+const PSF1_MODE_SEQ: u8 = 0x04;
+const PSF1_HEADER_SIZE: usize = 4;
+/// Starts a sequence of characters that combine into one glyph
+const PSF1_START_SEQ: u16 = 0xfffe;
+/// Ends the entry for a glyph
+const PSF1_SEPARATOR: u16 = 0xffff;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_HEADER_SIZE: usize = 32;
+const PSF2_START_SEQ: u8 = 0xfe;
+const PSF2_SEPARATOR: u8 = 0xff;
+
+/// Why a font file couldn't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsfError {
+    /// Not a PSF1 or PSF2 file
+    BadMagic,
+    /// The header promises more data than there is
+    Truncated,
+    /// The header describes a font with no glyphs or no pixels
+    Empty,
+    /// The glyphs are padded differently than [`Font`] expects
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    Psf1,
+    Psf2,
+}
+
+/// A parsed PSF font
+pub struct Psf {
+    pub font: Font,
+    version: Version,
+    glyph_count: usize,
+    /// The Unicode table, if the font has one
+    unicode: Option<&'static [u8]>,
+}
+
+impl Psf {
+    /// Parse a PSF1 or PSF2 file
+    pub fn parse(file: &'static [u8]) -> Result<Psf, PsfError> {
+        if file.starts_with(&PSF2_MAGIC) {
+            Psf::parse_psf2(file)
+        } else if file.starts_with(&PSF1_MAGIC) {
+            Psf::parse_psf1(file)
+        } else {
+            Err(PsfError::BadMagic)
+        }
+    }
+
+    fn parse_psf1(file: &'static [u8]) -> Result<Psf, PsfError> {
+        let header = file.get(..PSF1_HEADER_SIZE).ok_or(PsfError::Truncated)?;
+        let mode = header[2];
+        let height = header[3] as usize;
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+
+        Psf::build(
+            file,
+            Version::Psf1,
+            PSF1_HEADER_SIZE,
+            glyph_count,
+            8,
+            height,
+            mode & (PSF1_MODE_HAS_TAB | PSF1_MODE_SEQ) != 0,
+        )
+    }
+
+    fn parse_psf2(file: &'static [u8]) -> Result<Psf, PsfError> {
+        let header = file.get(..PSF2_HEADER_SIZE).ok_or(PsfError::Truncated)?;
+        let field = |i: usize| {
+            let bytes = [
+                header[i * 4],
+                header[i * 4 + 1],
+                header[i * 4 + 2],
+                header[i * 4 + 3],
+            ];
+            u32::from_le_bytes(bytes) as usize
+        };
+
+        // fields after the magic: version, headersize, flags, length, charsize, height, width
+        let header_size = field(2);
+        let flags = field(3) as u32;
+        let glyph_count = field(4);
+        let glyph_size = field(5);
+        let height = field(6);
+        let width = field(7);
+
+        if header_size < PSF2_HEADER_SIZE {
+            return Err(PsfError::Truncated);
+        }
+        // the bitmaps must be laid out the way Font expects
+        if Some(glyph_size) != width.div_ceil(8).checked_mul(height) {
+            return Err(PsfError::Unsupported);
+        }
+
+        Psf::build(
+            file,
+            Version::Psf2,
+            header_size,
+            glyph_count,
+            width,
+            height,
+            flags & PSF2_HAS_UNICODE_TABLE != 0,
+        )
+    }
+
+    fn build(
+        file: &'static [u8],
+        version: Version,
+        offset: usize,
+        glyph_count: usize,
+        width: usize,
+        height: usize,
+        has_unicode: bool,
+    ) -> Result<Psf, PsfError> {
+        if glyph_count == 0 || width == 0 || height == 0 {
+            return Err(PsfError::Empty);
+        }
+
+        // the sizes come straight from the file, so don't trust them not to overflow
+        let size = glyph_count
+            .checked_mul(width.div_ceil(8))
+            .and_then(|size| size.checked_mul(height))
+            .ok_or(PsfError::Truncated)?;
+        let end = offset.checked_add(size).ok_or(PsfError::Truncated)?;
+        let data = file.get(offset..end).ok_or(PsfError::Truncated)?;
+
+        Ok(Psf {
+            font: Font {
+                width,
+                height,
+                data,
+            },
+            version,
+            glyph_count,
+            unicode: has_unicode.then(|| &file[end..]),
+        })
+    }
+
+    /// Whether there's a Unicode table, without one the glyphs are in code page 437 order
+    pub fn has_unicode(&self) -> bool {
+        self.unicode.is_some()
+    }
+
+    /// The glyph that shows `c`
+    ///
+    /// Fonts without a Unicode table are assumed to be in code page order, so only ASCII is
+    /// looked up directly. Characters that only appear as part of a combining sequence aren't
+    /// found.
+    pub fn glyph_index(&self, c: char) -> Option<usize> {
+        let Some(table) = self.unicode else {
+            return (c.is_ascii() && (c as usize) < self.glyph_count).then_some(c as usize);
+        };
+
+        match self.version {
+            Version::Psf1 => psf1_lookup(table, self.glyph_count, c),
+            Version::Psf2 => psf2_lookup(table, self.glyph_count, c),
+        }
+    }
+}
+
+fn psf1_lookup(table: &[u8], glyph_count: usize, c: char) -> Option<usize> {
+    let mut glyph = 0;
+    let mut in_seq = false;
+
+    for unit in table.chunks_exact(2) {
+        if glyph >= glyph_count {
+            break;
+        }
+
+        match u16::from_le_bytes([unit[0], unit[1]]) {
+            PSF1_SEPARATOR => {
+                glyph += 1;
+                in_seq = false;
+            }
+            PSF1_START_SEQ => in_seq = true,
+            // PSF1 only maps the basic multilingual plane
+            unit if !in_seq && unit as u32 == c as u32 => return Some(glyph),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn psf2_lookup(table: &[u8], glyph_count: usize, c: char) -> Option<usize> {
+    let mut encoded = [0; 4];
+    let needle = c.encode_utf8(&mut encoded).as_bytes();
+
+    let mut glyph = 0;
+    let mut i = 0;
+    while i < table.len() && glyph < glyph_count {
+        match table[i] {
+            PSF2_SEPARATOR => {
+                glyph += 1;
+                i += 1;
+            }
+            // sequences run until the end of the entry, which is all that's left for this glyph
+            PSF2_START_SEQ => {
+                while i < table.len() && table[i] != PSF2_SEPARATOR {
+                    i += 1;
+                }
+            }
+            lead => {
+                let len = match lead {
+                    0x00..=0x7f => 1,
+                    0xc0..=0xdf => 2,
+                    0xe0..=0xef => 3,
+                    _ => 4,
+                };
+                if table.get(i..i + len) == Some(needle) {
+                    return Some(glyph);
+                }
+                i += len;
+            }
+        }
+    }
+
+    None
+}
@@ -0,0 +1,356 @@
+//! Linear framebuffer with a pixel API
+//!
+//! Drawing code works in [`Rgb`] and pixel coordinates. The framebuffer takes care of turning
+//! that into bytes at the right offset, based on the pitch, bpp, and pixel format it was
+//! handed. Everything is clipped to the screen, so callers don't have to bounds check.
+//!
+//! How colors turn into bytes is up to the [`PixelFormat`]. Indexed framebuffers get an
+//! RGB 3-3-2 palette loaded into the DAC, so an index is just the top bits of each channel
+//! packed together.
+//!
+//! Video memory is uncached and slow to write, so drawing goes into a back buffer in RAM when
+//! the framebuffer fits in one. The area touched since the last [`Framebuffer::flush`] is kept
+//! as a dirty rectangle, and flushing copies just that part over. Nothing flushes on a timer
+//! yet, whoever draws is expected to flush when they're done. A framebuffer that's already in
+//! RAM and has a [`present`](FramebufferInfo::present) hook is drawn to directly, and flushing
+//! hands the hook the dirty rectangle instead.
+//!
+//! links:
+//! - VGA DAC: <http://www.osdever.net/FreeVGA/vga/colorreg.htm>
+//!
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::lazy_static;
+use x86_64::structures::port::PortWrite as _;
+
+use super::font::{Font, REPLACEMENT_GLYPH};
+use super::{framebuffer_info, FramebufferInfo, PixelFormat, Rect, Rgb};
+use crate::console::cp437;
+use crate::sync::SpinLock;
+
+/// Write index for the DAC palette, the color data follows on the data port
+const DAC_WRITE_INDEX: u16 = 0x3c8;
+const DAC_DATA: u16 = 0x3c9;
+
+/// Enough for 1024x768 at 32bpp, bigger framebuffers are drawn to directly
+const BACK_BUFFER_SIZE: usize = 1024 * 768 * 4;
+
+static mut BACK_BUFFER: [u8; BACK_BUFFER_SIZE] = [0; BACK_BUFFER_SIZE];
+static BACK_BUFFER_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// The back buffer, the first time this is called
+fn take_back_buffer() -> Option<&'static mut [u8]> {
+    if BACK_BUFFER_TAKEN.swap(true, Ordering::AcqRel) {
+        return None;
+    }
+
+    // the flag makes sure this is the only reference that's ever handed out
+    Some(unsafe { &mut *ptr::addr_of_mut!(BACK_BUFFER) })
+}
+
+pub struct Framebuffer {
+    info: FramebufferInfo,
+    buffer: *mut u8,
+    /// RAM to draw into, flushed to `buffer`
+    back: Option<&'static mut [u8]>,
+    /// Whether `back` is big enough for the current mode, otherwise drawing is direct
+    double_buffered: bool,
+    /// Part of `back` that hasn't been flushed yet
+    dirty: Option<Rect>,
+}
+
+// the buffer is only ever reached through the Mutex below
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    /// Wrap the framebuffer described by `info`
+    ///
+    /// # Safety
+    ///
+    /// `info` has to describe a mapped framebuffer that nothing else writes to. `back` is used
+    /// as the back buffer if it's big enough, otherwise drawing goes straight to video memory.
+    pub unsafe fn new(info: FramebufferInfo, back: Option<&'static mut [u8]>) -> Framebuffer {
+        let mut fb = Framebuffer {
+            info,
+            buffer: info.virt_addr.as_mut_ptr(),
+            back,
+            double_buffered: false,
+            dirty: None,
+        };
+        fb.reconfigure(info);
+        fb
+    }
+
+    /// Start drawing to the framebuffer described by `info` instead, keeping the back buffer
+    ///
+    /// # Safety
+    ///
+    /// Same as [`Framebuffer::new`].
+    pub unsafe fn reconfigure(&mut self, info: FramebufferInfo) {
+        if info.format == PixelFormat::Indexed {
+            load_rgb332_palette();
+        }
+
+        self.info = info;
+        self.buffer = info.virt_addr.as_mut_ptr();
+        self.double_buffered = info.present.is_none()
+            && self
+                .back
+                .as_ref()
+                .is_some_and(|back| back.len() >= info.size());
+        self.dirty = None;
+    }
+
+    /// The back buffer, if it's in use
+    fn back(&mut self) -> Option<&mut [u8]> {
+        match self.back.as_mut() {
+            Some(back) if self.double_buffered => Some(back),
+            _ => None,
+        }
+    }
+
+    pub fn info(&self) -> &FramebufferInfo {
+        &self.info
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        self.info.format.bytes_per_pixel()
+    }
+
+    /// Turn a color into the value stored for one pixel
+    fn encode(&self, color: Rgb) -> u32 {
+        self.info.format.encode(color)
+    }
+
+    /// Store an already encoded pixel, the caller has checked the coordinates
+    fn write_encoded(&mut self, x: usize, y: usize, value: u32) {
+        let bpp = self.bytes_per_pixel();
+        let offset = y * self.info.pitch + x * bpp;
+        let bytes = &value.to_le_bytes()[..bpp];
+        match self.back() {
+            Some(back) => back[offset..offset + bpp].copy_from_slice(bytes),
+            None => {
+                for (i, byte) in bytes.iter().enumerate() {
+                    unsafe {
+                        self.buffer.add(offset + i).write_volatile(*byte);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Note that `rect`, already clipped, has to be flushed
+    fn mark_dirty(&mut self, rect: Rect) {
+        if self.double_buffered || self.info.present.is_some() {
+            self.dirty = Some(match self.dirty {
+                Some(dirty) => dirty.union(&rect),
+                None => rect,
+            });
+        }
+    }
+
+    /// Copy everything drawn since the last flush to the screen
+    pub fn flush(&mut self) {
+        let Some(dirty) = self.dirty.take() else {
+            return;
+        };
+        if let Some(present) = self.info.present {
+            present(dirty);
+            return;
+        }
+        let buffer = self.buffer;
+        let bpp = self.bytes_per_pixel();
+        let pitch = self.info.pitch;
+        let Some(back) = self.back() else {
+            return;
+        };
+
+        for y in dirty.y..dirty.y + dirty.height {
+            let offset = y * pitch + dirty.x * bpp;
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    back.as_ptr().add(offset),
+                    buffer.add(offset),
+                    dirty.width * bpp,
+                );
+            }
+        }
+    }
+
+    /// Set a single pixel, anything off screen is ignored
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x < self.width() && y < self.height() {
+            let value = self.encode(color);
+            self.write_encoded(x, y, value);
+            self.mark_dirty(Rect {
+                x,
+                y,
+                width: 1,
+                height: 1,
+            });
+        }
+    }
+
+    /// Fill a rectangle with one color
+    pub fn fill_rect(&mut self, rect: Rect, color: Rgb) {
+        let Some(rect) = rect.clip(self.width(), self.height()) else {
+            return;
+        };
+
+        let value = self.encode(color);
+        for y in rect.y..rect.y + rect.height {
+            for x in rect.x..rect.x + rect.width {
+                self.write_encoded(x, y, value);
+            }
+        }
+        self.mark_dirty(rect);
+    }
+
+    /// Copy a `width` pixel wide image with its top left corner at (`x`, `y`)
+    ///
+    /// `pixels` is in row order, its length decides the height. Partial rows at the end are
+    /// dropped.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, pixels: &[Rgb]) {
+        if width == 0 {
+            return;
+        }
+
+        let dest = Rect {
+            x,
+            y,
+            width,
+            height: pixels.len() / width,
+        };
+        let Some(clipped) = dest.clip(self.width(), self.height()) else {
+            return;
+        };
+
+        for row in 0..clipped.height {
+            for col in 0..clipped.width {
+                let color = pixels[row * width + col];
+                let value = self.encode(color);
+                self.write_encoded(clipped.x + col, clipped.y + row, value);
+            }
+        }
+        self.mark_dirty(clipped);
+    }
+
+    /// Move the top `height` rows of pixels up by `distance`, filling the rows that opens up at
+    /// the bottom with `fill`
+    ///
+    /// This is a plain memory move, which is much cheaper than drawing everything again. It
+    /// works best with a back buffer, since reading video memory back is slow.
+    pub fn scroll_up(&mut self, height: usize, distance: usize, fill: Rgb) {
+        let height = height.min(self.height());
+        let distance = distance.min(height);
+        let pitch = self.info.pitch;
+        let moved = (height - distance) * pitch;
+
+        let buffer = self.buffer;
+        match self.back() {
+            Some(back) => back.copy_within(distance * pitch..distance * pitch + moved, 0),
+            None => unsafe { ptr::copy(buffer.add(distance * pitch), buffer, moved) },
+        }
+        let screen = Rect {
+            x: 0,
+            y: 0,
+            width: self.width(),
+            height,
+        };
+        self.mark_dirty(screen);
+
+        let opened = Rect {
+            x: 0,
+            y: height - distance,
+            width: self.width(),
+            height: distance,
+        };
+        self.fill_rect(opened, fill);
+    }
+}
+
+impl Framebuffer {
+    /// Draw glyph `index` from `font` with its top left corner at (`x`, `y`)
+    pub fn draw_glyph(&mut self, x: usize, y: usize, font: &Font, index: usize, fg: Rgb, bg: Rgb) {
+        let dest = Rect {
+            x,
+            y,
+            width: font.width,
+            height: font.height,
+        };
+        let Some(clipped) = dest.clip(self.width(), self.height()) else {
+            return;
+        };
+
+        let fg = self.encode(fg);
+        let bg = self.encode(bg);
+        let glyph = font.glyph(index);
+        let bytes_per_row = font.bytes_per_row();
+
+        for row in 0..clipped.height {
+            let bits = &glyph[row * bytes_per_row..(row + 1) * bytes_per_row];
+            for col in 0..clipped.width {
+                let set = bits[col / 8] & (0x80 >> (col % 8)) != 0;
+                self.write_encoded(x + col, y + row, if set { fg } else { bg });
+            }
+        }
+        self.mark_dirty(clipped);
+    }
+
+    /// Draw a line of text starting at (`x`, `y`)
+    ///
+    /// Characters are mapped to [`cp437`], anything it doesn't have shows up as the
+    /// replacement glyph.
+    pub fn draw_str(&mut self, x: usize, y: usize, font: &Font, s: &str, fg: Rgb, bg: Rgb) {
+        for (i, c) in s.chars().enumerate() {
+            let index = cp437::encode(c).map_or(REPLACEMENT_GLYPH, usize::from);
+            self.draw_glyph(x + i * font.width, y, font, index, fg, bg);
+        }
+    }
+}
+
+/// Program the DAC so that index `rrrgggbb` shows that color
+fn load_rgb332_palette() {
+    // the DAC takes 6 bits per channel
+    let scale = |value: u8, max: u8| (value as u16 * 63 / max as u16) as u8;
+
+    unsafe {
+        u8::write_to_port(DAC_WRITE_INDEX, 0);
+        for index in 0..=255u8 {
+            u8::write_to_port(DAC_DATA, scale(index >> 5, 7));
+            u8::write_to_port(DAC_DATA, scale((index >> 2) & 0x7, 7));
+            u8::write_to_port(DAC_DATA, scale(index & 0x3, 3));
+        }
+    }
+}
+
+lazy_static! {
+    /// The boot framebuffer, `None` in VGA text mode
+    pub static ref FRAMEBUFFER: SpinLock<Option<Framebuffer>> = SpinLock::new(
+        "framebuffer",
+        framebuffer_info().map(|info| unsafe { Framebuffer::new(info, take_back_buffer()) })
+    );
+}
+
+/// Point [`FRAMEBUFFER`] at a new framebuffer, after a mode change
+///
+/// # Safety
+///
+/// Same as [`Framebuffer::new`].
+pub unsafe fn switch_to(info: FramebufferInfo) {
+    let mut fb = FRAMEBUFFER.lock();
+    match fb.as_mut() {
+        Some(fb) => fb.reconfigure(info),
+        None => *fb = Some(Framebuffer::new(info, take_back_buffer())),
+    }
+}
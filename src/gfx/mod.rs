@@ -0,0 +1,227 @@
+//! Graphics output
+//!
+//! The framebuffer handed over by the bootloader is recorded here at boot. When there is one,
+//! it's preferred over VGA text mode, which the firmware leaves disabled in graphics modes.
+//!
+//! bootloader 0.9 doesn't report a framebuffer in its boot info. The only graphics mode it can
+//! set up is mode 13h (with the `vga_320x200` feature), which always sits at the same physical
+//! address, so that's what gets described here. On QEMU and Bochs a bigger mode can be set
+//! with [`set_mode`] through the [`bochs`] interface, or at boot with `video=`. When there's a
+//! [virtio-gpu](crate::drivers::virtio::gpu) it's used instead, from when its driver's set up:
+//!
+//! ```shell
+//! $ ZENIX_CMDLINE="video=1024x768x32" cargo run
+//! ```
+//!
+//! The depth is optional and defaults to 32 bpp.
+//!
+//! Drawing goes through [`framebuffer::FRAMEBUFFER`], with shapes from [`draw`], and [`console`]
+//! puts text on it.
+//!
+//! links:
+//! - mode 13h: <https://en.wikipedia.org/wiki/Mode_13h>
+//!
+
+pub mod bochs;
+pub mod console;
+pub mod draw;
+pub mod font;
+pub mod framebuffer;
+pub mod pixel;
+pub mod splash;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::drivers::virtio::gpu;
+use crate::init::{InitCall, Stage};
+use crate::sync::SpinLock;
+use crate::{cmdline, println};
+
+pub use pixel::PixelFormat;
+
+#[cfg(feature = "vga_320x200")]
+use crate::mem;
+
+/// A color, 8 bits per channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+}
+
+/// A rectangle in pixels, or character cells for [`tui`](crate::console::tui), (`x`, `y`) is
+/// the top left corner
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The part of this rectangle that fits on a `width` by `height` screen, if any
+    pub fn clip(&self, width: usize, height: usize) -> Option<Rect> {
+        if self.width == 0 || self.height == 0 || self.x >= width || self.y >= height {
+            return None;
+        }
+
+        Some(Rect {
+            x: self.x,
+            y: self.y,
+            width: self.width.min(width - self.x),
+            height: self.height.min(height - self.y),
+        })
+    }
+
+    /// The smallest rectangle covering both this one and `other`
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+/// Where the framebuffer lives and how it's shaped
+#[derive(Debug, Clone, Copy)]
+pub struct FramebufferInfo {
+    /// Physical address of the first pixel
+    pub phys_addr: PhysAddr,
+    /// Same memory, through the physical memory window
+    pub virt_addr: VirtAddr,
+    /// Width in pixels
+    pub width: usize,
+    /// Height in pixels
+    pub height: usize,
+    /// Bytes from the start of one row to the start of the next
+    pub pitch: usize,
+    /// Bits per pixel
+    pub bpp: usize,
+    pub format: PixelFormat,
+    /// For a framebuffer in RAM that the device copies from, like [virtio-gpu]'s, what tells
+    /// it about each rectangle that's changed once it's flushed. There's no back buffer then,
+    /// drawing goes straight into that RAM.
+    ///
+    /// [virtio-gpu]: crate::drivers::virtio::gpu
+    pub present: Option<fn(Rect)>,
+}
+
+impl FramebufferInfo {
+    /// Size of the framebuffer memory in bytes
+    pub fn size(&self) -> usize {
+        self.pitch * self.height
+    }
+}
+
+static FRAMEBUFFER: SpinLock<Option<FramebufferInfo>> = SpinLock::new("framebuffer info", None);
+
+pub const INIT: InitCall = InitCall {
+    name: "gfx",
+    stage: Stage::Early,
+    after: &[],
+    func: init,
+};
+
+/// Record the framebuffer the bootloader left us in, if any, switch modes if the command line
+/// asks for it, and clear the screen
+fn init() {
+    *FRAMEBUFFER.lock() = boot_framebuffer();
+
+    if let Some(video) = cmdline::get("video") {
+        let result = parse_mode(video)
+            .ok_or(bochs::ModeError::Unsupported)
+            .and_then(|(width, height, bpp)| set_mode(width, height, bpp));
+        if let Err(err) = result {
+            println!("gfx: can't set video={}: {:?}", video, err);
+        }
+    }
+
+    clear();
+}
+
+/// Parse a `WIDTHxHEIGHT` or `WIDTHxHEIGHTxBPP` mode
+pub fn parse_mode(mode: &str) -> Option<(usize, usize, usize)> {
+    let mut parts = mode.split('x').map(|part| part.parse().ok());
+    let width = parts.next()??;
+    let height = parts.next()??;
+    let bpp = parts.next().unwrap_or(Some(32))?;
+    parts.next().is_none().then_some((width, height, bpp))
+}
+
+fn clear() {
+    if let Some(fb) = framebuffer::FRAMEBUFFER.lock().as_mut() {
+        let screen = Rect {
+            x: 0,
+            y: 0,
+            width: fb.width(),
+            height: fb.height(),
+        };
+        fb.fill_rect(screen, Rgb::BLACK);
+        fb.flush();
+    }
+}
+
+/// Change the display mode, and move the console over to the new framebuffer
+pub fn set_mode(width: usize, height: usize, bpp: usize) -> Result<(), bochs::ModeError> {
+    let info = if gpu::is_present() {
+        gpu::set_mode(width, height, bpp)?
+    } else {
+        bochs::set_mode(width, height, bpp)?
+    };
+    *FRAMEBUFFER.lock() = Some(info);
+    unsafe {
+        framebuffer::switch_to(info);
+    }
+
+    clear();
+    console::resize(info.width, info.height);
+    Ok(())
+}
+
+#[cfg(feature = "vga_320x200")]
+fn boot_framebuffer() -> Option<FramebufferInfo> {
+    let phys_addr = PhysAddr::new(0xa0000);
+    Some(FramebufferInfo {
+        phys_addr,
+        virt_addr: mem::phys_to_virt(phys_addr),
+        width: 320,
+        height: 200,
+        pitch: 320,
+        bpp: 8,
+        format: PixelFormat::Indexed,
+        present: None,
+    })
+}
+
+#[cfg(not(feature = "vga_320x200"))]
+fn boot_framebuffer() -> Option<FramebufferInfo> {
+    None
+}
+
+/// The current framebuffer, or `None` if we're in VGA text mode
+pub fn framebuffer_info() -> Option<FramebufferInfo> {
+    *FRAMEBUFFER.lock()
+}
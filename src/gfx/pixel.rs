@@ -0,0 +1,87 @@
+//! Pixel formats
+//!
+//! Every format is named after the order its channels sit in memory, so `Bgr888` is a blue
+//! byte, then green, then red. Pixels are handled as a `u32` holding the bytes of one pixel in
+//! little-endian order, which is what [`PixelFormat::encode`] produces and
+//! [`PixelFormat::decode`] takes. Only the low [`PixelFormat::bytes_per_pixel`] bytes are used.
+//!
+
+use super::Rgb;
+
+/// How pixels are laid out in the framebuffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// One byte per pixel, indexing into the VGA DAC palette. The palette is loaded as RGB 3-3-2
+    Indexed,
+    /// Two bytes per pixel, a little-endian `u16` with 5 bits of red at the top, then 6 green
+    /// and 5 blue
+    Rgb565,
+    /// Three bytes per pixel: blue, green, red
+    Bgr888,
+    /// Four bytes per pixel: red, green, blue, alpha
+    Rgba8888,
+    /// Four bytes per pixel: blue, green, red, unused
+    Bgrx8888,
+}
+
+impl PixelFormat {
+    /// The format QEMU, Bochs, and most VBE BIOSes use at a given depth
+    pub fn from_bpp(bpp: usize) -> Option<PixelFormat> {
+        match bpp {
+            8 => Some(PixelFormat::Indexed),
+            16 => Some(PixelFormat::Rgb565),
+            24 => Some(PixelFormat::Bgr888),
+            32 => Some(PixelFormat::Bgrx8888),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Indexed => 1,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Bgr888 => 3,
+            PixelFormat::Rgba8888 | PixelFormat::Bgrx8888 => 4,
+        }
+    }
+
+    /// Turn a color into this format
+    pub fn encode(self, color: Rgb) -> u32 {
+        let Rgb { r, g, b } = color;
+        match self {
+            PixelFormat::Indexed => ((r & 0xe0) | ((g & 0xe0) >> 3) | (b >> 6)) as u32,
+            PixelFormat::Rgb565 => {
+                ((r as u32 >> 3) << 11) | ((g as u32 >> 2) << 5) | (b as u32 >> 3)
+            }
+            PixelFormat::Bgr888 | PixelFormat::Bgrx8888 => u32::from_le_bytes([b, g, r, 0]),
+            PixelFormat::Rgba8888 => u32::from_le_bytes([r, g, b, 0xff]),
+        }
+    }
+
+    /// Turn a pixel in this format back into a color
+    ///
+    /// Channels stored with fewer than 8 bits are scaled up, so white stays white.
+    pub fn decode(self, pixel: u32) -> Rgb {
+        let [b0, b1, b2, _] = pixel.to_le_bytes();
+        match self {
+            PixelFormat::Indexed => Rgb::new(
+                expand(b0 >> 5, 3),
+                expand((b0 >> 2) & 0x7, 3),
+                expand(b0 & 0x3, 2),
+            ),
+            PixelFormat::Rgb565 => Rgb::new(
+                expand((pixel >> 11) as u8 & 0x1f, 5),
+                expand((pixel >> 5) as u8 & 0x3f, 6),
+                expand(pixel as u8 & 0x1f, 5),
+            ),
+            PixelFormat::Bgr888 | PixelFormat::Bgrx8888 => Rgb::new(b2, b1, b0),
+            PixelFormat::Rgba8888 => Rgb::new(b0, b1, b2),
+        }
+    }
+}
+
+/// Scale a `bits` wide channel up to 8 bits
+fn expand(value: u8, bits: u32) -> u8 {
+    let max = (1u16 << bits) - 1;
+    (value as u16 * 255 / max) as u8
+}
@@ -0,0 +1,215 @@
+//! Boot splash
+//!
+//! Decodes uncompressed BMP images and draws them centered on the framebuffer. Only 24 and 32
+//! bpp `BI_RGB` bitmaps are understood, which is what image editors write by default for
+//! "uncompressed, no palette". Rows can be stored either bottom up (the usual) or top down.
+//!
+//! `splash=PATH` on the command line shows the image at `PATH` in the
+//! [initrd](crate::fs::initrd) once the drivers are up, [`DEFAULT_IMAGE`] for a bare `splash`.
+//! The console is hidden behind it, and the boot [kept quiet](crate::console::sink), until
+//! [`finish`] puts the console back before the shell starts.
+//!
+//! links:
+//! - BMP format: <https://en.wikipedia.org/wiki/BMP_file_format>
+//!
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use super::framebuffer::{self, Framebuffer};
+use super::{console, Rect, Rgb};
+use crate::fs::initrd;
+use crate::init::{InitCall, Stage};
+use crate::{cmdline, ilog, wlog};
+
+/// The image a bare `splash` shows
+pub const DEFAULT_IMAGE: &str = "splash.bmp";
+
+const FILE_HEADER_SIZE: usize = 14;
+/// BITMAPINFOHEADER, later header versions only add fields after it
+const INFO_HEADER_SIZE: usize = 40;
+const BI_RGB: u32 = 0;
+
+/// Why an image couldn't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BmpError {
+    /// Doesn't start with `BM`
+    BadMagic,
+    /// The headers point past the end of the file
+    Truncated,
+    /// Compressed, paletted, or some other layout that isn't handled
+    Unsupported,
+}
+
+/// An uncompressed BMP image, borrowed from the file it was parsed from
+pub struct Bmp {
+    pub width: usize,
+    pub height: usize,
+    bytes_per_pixel: usize,
+    /// Bytes per stored row, which are padded to 4 bytes
+    stride: usize,
+    top_down: bool,
+    pixels: &'static [u8],
+}
+
+impl Bmp {
+    pub fn parse(file: &'static [u8]) -> Result<Bmp, BmpError> {
+        if !file.starts_with(b"BM") {
+            return Err(BmpError::BadMagic);
+        }
+        if file.len() < FILE_HEADER_SIZE + INFO_HEADER_SIZE {
+            return Err(BmpError::Truncated);
+        }
+
+        let u16_at = |i: usize| u16::from_le_bytes([file[i], file[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([file[i], file[i + 1], file[i + 2], file[i + 3]]);
+
+        let data_offset = u32_at(10) as usize;
+        let width = u32_at(18) as i32;
+        let height = u32_at(22) as i32;
+        let bpp = u16_at(28);
+        let compression = u32_at(30);
+
+        // 32bpp images often say BI_BITFIELDS with the default masks, but there's no way to tell
+        // without reading them, so only take the plain ones
+        if compression != BI_RGB || !(bpp == 24 || bpp == 32) || width <= 0 || height == 0 {
+            return Err(BmpError::Unsupported);
+        }
+
+        let width = width as usize;
+        let bytes_per_pixel = bpp as usize / 8;
+        let stride = (width * bytes_per_pixel).next_multiple_of(4);
+        let rows = height.unsigned_abs() as usize;
+
+        let size = stride.checked_mul(rows).ok_or(BmpError::Truncated)?;
+        let end = data_offset.checked_add(size).ok_or(BmpError::Truncated)?;
+        let pixels = file.get(data_offset..end).ok_or(BmpError::Truncated)?;
+
+        Ok(Bmp {
+            width,
+            height: rows,
+            bytes_per_pixel,
+            stride,
+            // a negative height means the first row stored is the top one
+            top_down: height < 0,
+            pixels,
+        })
+    }
+
+    /// The color at (`x`, `y`), counting from the top left
+    pub fn pixel(&self, x: usize, y: usize) -> Rgb {
+        let row = if self.top_down {
+            y
+        } else {
+            self.height - 1 - y
+        };
+        let offset = row * self.stride + x * self.bytes_per_pixel;
+        // stored as blue, green, red, and an unused byte at 32bpp
+        let bgr = &self.pixels[offset..offset + 3];
+        Rgb::new(bgr[2], bgr[1], bgr[0])
+    }
+}
+
+/// Clear the screen to `background` and draw `image` in the middle of it
+///
+/// Images bigger than the screen are cropped evenly on both sides.
+pub fn show(fb: &mut Framebuffer, image: &Bmp, background: Rgb) {
+    let screen = Rect {
+        x: 0,
+        y: 0,
+        width: fb.width(),
+        height: fb.height(),
+    };
+    fb.fill_rect(screen, background);
+
+    let (x, crop_x) = center(fb.width(), image.width);
+    let (y, crop_y) = center(fb.height(), image.height);
+    for row in 0..image.height.min(fb.height()) {
+        for col in 0..image.width.min(fb.width()) {
+            let color = image.pixel(crop_x + col, crop_y + row);
+            fb.put_pixel(x + col, y + row, color);
+        }
+    }
+    fb.flush();
+}
+
+/// Where something `size` long starts on a `screen` long axis, and how much of it is cut off
+/// the start to fit
+fn center(screen: usize, size: usize) -> (usize, usize) {
+    if size <= screen {
+        ((screen - size) / 2, 0)
+    } else {
+        (0, (size - screen) / 2)
+    }
+}
+
+/// Set while the splash is up and the console's hidden
+static SHOWN: AtomicBool = AtomicBool::new(false);
+
+pub const INIT: InitCall = InitCall {
+    name: "splash",
+    stage: Stage::Drivers,
+    // after the last mode change and font change, which would draw over it
+    after: &["initrd", "virtio-gpu", "font"],
+    func: init,
+};
+
+/// Show the image `splash=` names, if the command line has it
+fn init() {
+    let Some(path) = cmdline::get("splash") else {
+        return;
+    };
+    let path = if path.is_empty() { DEFAULT_IMAGE } else { path };
+    let Some(file) = initrd::get(path) else {
+        wlog!("splash: no image {} in the initrd", path);
+        return;
+    };
+    let image = match Bmp::parse(file) {
+        Ok(image) => image,
+        Err(error) => {
+            wlog!("splash: can't use {}: {:?}", path, error);
+            return;
+        }
+    };
+
+    let shown = interrupts::without_interrupts(|| {
+        let mut console = console::CONSOLE.lock();
+        let mut fb = framebuffer::FRAMEBUFFER.lock();
+        let Some(fb) = fb.as_mut() else {
+            return false;
+        };
+        if let Some(console) = console.as_mut() {
+            console.set_visible(fb, false);
+        }
+        show(fb, &image, Rgb::BLACK);
+        true
+    });
+    if !shown {
+        wlog!("splash: no framebuffer to show {} on", path);
+        return;
+    }
+    SHOWN.store(true, Ordering::Relaxed);
+    ilog!("splash: {}, {}x{}", path, image.width, image.height);
+}
+
+/// Take the splash down and put the console back on the screen
+pub fn finish() {
+    if !SHOWN.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        // they're only held with interrupts off, so this only fails on a panic that came in
+        // with them locked
+        let (Some(mut console), Some(mut fb)) = (
+            console::CONSOLE.try_lock(),
+            framebuffer::FRAMEBUFFER.try_lock(),
+        ) else {
+            return;
+        };
+        if let (Some(console), Some(fb)) = (console.as_mut(), fb.as_mut()) {
+            console.set_visible(fb, true);
+        }
+    });
+}
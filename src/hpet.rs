@@ -0,0 +1,187 @@
+//! High Precision Event Timer
+//!
+//! The HPET is a counter running at a fixed rate of at least 10 MHz, with a few comparators
+//! that can fire interrupts. Its registers are memory mapped at the address in the ACPI HPET
+//! table, see [`acpi::hpet`].
+//!
+//! It's only used with `clock=hpet` on the command line, for when the PIT is too coarse or
+//! doesn't behave. Then [`INIT`] maps and starts it, and the [`timer`](crate::timer) uses it
+//! in place of the PIT: [`timer::wait_ms`] spins on its counter, which is what the TSC and the
+//! local APIC timer are calibrated against, and if the APICs aren't in use, comparator 0 fires
+//! IRQ 0 in legacy replacement mode, which also disconnects the PIT.
+//!
+//! links:
+//! - <https://wiki.osdev.org/HPET>
+//! - IA-PC HPET specification 1.0a
+//!
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::VirtAddr;
+
+use crate::acpi;
+use crate::arch::io::Mmio;
+use crate::init::{InitCall, Stage};
+use crate::mem::paging::{self, MapError};
+use crate::{cmdline, ilog, wlog};
+
+// registers, as offsets from its base
+const CAPABILITIES: usize = 0x00;
+const CONFIG: usize = 0x10;
+const COUNTER: usize = 0xf0;
+const TIMER0_CONFIG: usize = 0x100;
+const TIMER0_COMPARATOR: usize = 0x108;
+/// The registers up to the last of 32 comparators
+const REGISTERS_SIZE: u64 = 0x400;
+
+/// Capabilities: the counter is 64 bits wide
+const CAP_COUNTER_64: u64 = 1 << 13;
+/// Capabilities: comparators 0 and 1 can take over IRQ 0 and 8
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+
+/// Configuration: the counter runs and interrupts can fire
+const CONFIG_ENABLE: u64 = 1 << 0;
+/// Configuration: comparator 0 fires IRQ 0 and comparator 1 IRQ 8, in place of the PIT and RTC
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+// comparator configuration bits
+const TIMER_INTERRUPT: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+/// The next comparator write sets the period rather than the next time it fires
+const TIMER_SET_PERIOD: u64 = 1 << 6;
+
+/// The spec's upper limit for the counter's period, 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_NS: u64 = 1_000_000;
+
+/// Where the registers are mapped, 0 when it's not in use
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// How long a count takes, in femtoseconds
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+/// What the counter wraps at, minus 1
+static COUNTER_MASK: AtomicU64 = AtomicU64::new(0);
+
+/// Why the HPET couldn't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// There's no HPET table
+    NoTable,
+    /// The registers couldn't be mapped
+    Map(MapError),
+    /// The counter's period is 0 or longer than the spec allows
+    BadPeriod(u64),
+}
+
+impl From<MapError> for HpetError {
+    fn from(error: MapError) -> HpetError {
+        HpetError::Map(error)
+    }
+}
+
+fn register(offset: usize) -> Mmio<u64> {
+    let base = VirtAddr::new(BASE.load(Ordering::Relaxed));
+    // mapped by setup, and every register is 64 bits
+    unsafe { Mmio::new(base).offset(offset) }
+}
+
+/// Whether `clock=hpet` was given and the HPET is running
+pub fn enabled() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// How fast the counter runs, in Hz
+pub fn frequency() -> u64 {
+    1_000_000_000_000_000 / PERIOD_FS.load(Ordering::Relaxed).max(1)
+}
+
+/// The counter, 0 if the HPET isn't [`enabled`]
+pub fn counter() -> u64 {
+    if !enabled() {
+        return 0;
+    }
+    register(COUNTER).read()
+}
+
+/// Spin for `ms` milliseconds on the counter
+pub fn wait_ms(ms: u64) {
+    let mask = COUNTER_MASK.load(Ordering::Relaxed);
+    let counts = ms * 1_000_000_000_000 / PERIOD_FS.load(Ordering::Relaxed).max(1);
+    let start = counter();
+    while counter().wrapping_sub(start) & mask < counts {
+        core::hint::spin_loop();
+    }
+}
+
+/// Make comparator 0 fire IRQ 0 `hz` times a second. Returns the length of a tick in ns, or
+/// None if this HPET can't do that.
+pub fn set_frequency(hz: u32) -> Option<u64> {
+    if !enabled() {
+        return None;
+    }
+    let capabilities = register(CAPABILITIES).read();
+    let timer = register(TIMER0_CONFIG);
+    if capabilities & CAP_LEGACY_ROUTE == 0 || timer.read() & TIMER_PERIODIC_CAP == 0 {
+        return None;
+    }
+
+    let period_fs = PERIOD_FS.load(Ordering::Relaxed);
+    let counts = (frequency() / hz.max(1) as u64).max(1);
+    let config = register(CONFIG);
+    // stopped while the comparator is set up, so it doesn't fire halfway
+    config.modify(|config| config & !CONFIG_ENABLE);
+    timer.modify(|timer| timer | TIMER_INTERRUPT | TIMER_PERIODIC | TIMER_SET_PERIOD);
+    register(TIMER0_COMPARATOR).write(counter() + counts);
+    // the second write sets the period
+    register(TIMER0_COMPARATOR).write(counts);
+    config.modify(|config| config | CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+    Some(counts * period_fs / FS_PER_NS)
+}
+
+fn setup() -> Result<(), HpetError> {
+    let table = acpi::hpet().ok_or(HpetError::NoTable)?;
+    let base = unsafe { paging::map_mmio(table.address, REGISTERS_SIZE)? };
+    let capabilities = unsafe { Mmio::<u64>::new(base) }.read();
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        return Err(HpetError::BadPeriod(period_fs));
+    }
+    let wide = capabilities & CAP_COUNTER_64 != 0;
+
+    PERIOD_FS.store(period_fs, Ordering::Relaxed);
+    COUNTER_MASK.store(
+        if wide { u64::MAX } else { u32::MAX as u64 },
+        Ordering::Relaxed,
+    );
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+    register(CONFIG).modify(|config| config | CONFIG_ENABLE);
+
+    ilog!(
+        "hpet: {} at {:#x}, {} kHz, {} bit counter, {} comparators",
+        table.number,
+        table.address.as_u64(),
+        frequency() / 1000,
+        if wide { 64 } else { 32 },
+        ((capabilities >> 8) & 0x1f) + 1
+    );
+    Ok(())
+}
+
+fn init() {
+    match cmdline::get("clock") {
+        None | Some("pit") => {}
+        Some("hpet") => {
+            if let Err(error) = setup() {
+                wlog!("hpet: not using the HPET: {:?}", error);
+            }
+        }
+        Some(value) => wlog!("hpet: unknown clock {:?}, using the PIT", value),
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "hpet",
+    stage: Stage::Memory,
+    after: &["acpi", "paging"],
+    func: init,
+};
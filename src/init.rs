@@ -0,0 +1,164 @@
+//! Boot-time initialization
+//!
+//! Each subsystem describes how it's brought up with an [`InitCall`]: a name, the [`Stage`] it
+//! belongs to, and the names of the initializers that have to run before it. They're gathered
+//! in [`INIT_CALLS`], and [`run`] goes through the stages in order, running each initializer
+//! once everything it depends on is done.
+//!
+//! There's no calibrated clock this early, so step durations are logged in TSC cycles.
+//!
+
+use core::arch::x86_64::_rdtsc;
+
+#[cfg(feature = "aml")]
+use crate::aml;
+use crate::{
+    acpi, apic, config, console, cpu, debug, drivers, fs, gdt, gfx, hpet, ilog, interrupts,
+    keyboard, log, mem, mouse, net, pci, percpu, pic, power, rand, rtc, sched, serial, smp,
+    statusbar, syscall, time, timer, vga, watchdog,
+};
+
+/// Boot stages, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// Console output and anything needed to report problems
+    Early,
+    /// Physical and virtual memory management
+    Memory,
+    /// Exception and interrupt handling
+    Interrupts,
+    /// Device drivers
+    Drivers,
+    /// Everything that needs the rest of the kernel up
+    Late,
+}
+
+impl Stage {
+    const ALL: [Stage; 5] = [
+        Stage::Early,
+        Stage::Memory,
+        Stage::Interrupts,
+        Stage::Drivers,
+        Stage::Late,
+    ];
+}
+
+/// A subsystem initializer
+pub struct InitCall {
+    pub name: &'static str,
+    pub stage: Stage,
+    /// Initializers that have to be done first, from this stage or an earlier one
+    pub after: &'static [&'static str],
+    pub func: fn(),
+}
+
+/// Every initializer run at boot
+const INIT_CALLS: &[&InitCall] = &[
+    &gdt::INIT,
+    &gfx::INIT,
+    &gfx::console::INIT,
+    &vga::INIT,
+    &config::INIT,
+    &debug::fault::INIT,
+    &log::INIT,
+    &console::sink::INIT,
+    &cpu::features::INIT,
+    &cpu::protection::INIT,
+    &cpu::fpu::INIT,
+    &rand::INIT,
+    &mem::frame::INIT,
+    &smp::RESERVE_INIT,
+    &mem::paging::INIT,
+    &mem::heap::INIT,
+    &percpu::INIT,
+    &acpi::INIT,
+    &hpet::INIT,
+    &time::INIT,
+    &vga::MMIO_INIT,
+    &gdt::STACK_INIT,
+    &interrupts::INIT,
+    &syscall::INIT,
+    &debug::gdbstub::INIT,
+    &pic::INIT,
+    &apic::INIT,
+    &timer::INIT,
+    #[cfg(feature = "aml")]
+    &aml::INIT,
+    &keyboard::INIT,
+    &serial::INIT,
+    &mouse::INIT,
+    &pci::INIT,
+    &drivers::ata::INIT,
+    &drivers::virtio::blk::INIT,
+    &drivers::virtio::gpu::INIT,
+    &drivers::virtio::net::INIT,
+    &drivers::ramdisk::INIT,
+    &fs::fat::INIT,
+    &fs::initrd::INIT,
+    &gfx::console::FONT_INIT,
+    &gfx::splash::INIT,
+    &fs::devfs::INIT,
+    &rtc::INIT,
+    &statusbar::INIT,
+    &sched::INIT,
+    &net::INIT,
+    &net::syslog::INIT,
+    &watchdog::INIT,
+    &power::INIT,
+    &smp::INIT,
+];
+
+fn index_of(name: &str) -> usize {
+    INIT_CALLS
+        .iter()
+        .position(|call| call.name == name)
+        .unwrap_or_else(|| panic!("init: no initializer named {}", name))
+}
+
+/// Bring up every subsystem, stage by stage
+pub fn run() {
+    let mut done = [false; INIT_CALLS.len()];
+
+    for stage in Stage::ALL {
+        loop {
+            let mut progress = false;
+            let mut pending = false;
+
+            for (i, call) in INIT_CALLS.iter().enumerate() {
+                if call.stage != stage || done[i] {
+                    continue;
+                }
+
+                let ready = call.after.iter().all(|dep| {
+                    let dep = INIT_CALLS[index_of(dep)];
+                    assert!(
+                        dep.stage <= stage,
+                        "init: {} ({:?}) can't run after {} ({:?})",
+                        call.name,
+                        call.stage,
+                        dep.name,
+                        dep.stage
+                    );
+                    done[index_of(dep.name)]
+                });
+                if !ready {
+                    pending = true;
+                    continue;
+                }
+
+                let start = unsafe { _rdtsc() };
+                (call.func)();
+                let cycles = unsafe { _rdtsc() } - start;
+                ilog!("init: {} done in {} cycles", call.name, cycles);
+
+                done[i] = true;
+                progress = true;
+            }
+
+            if !pending {
+                break;
+            }
+            assert!(progress, "init: dependency cycle in the {:?} stage", stage);
+        }
+    }
+}
@@ -0,0 +1,615 @@
+//! Interrupt descriptor table and CPU exception handlers
+//!
+//! Vectors 0-31 are CPU exceptions, handled here. The legacy hardware IRQs come after them and
+//! are handled by [`pic`](crate::pic), and the APIC's spurious vector is at the very end.
+//!
+//! Every exception comes in through an entry stub that pushes every general purpose register
+//! and CR2, then calls `exception`, which prints what happened: the exception's name, the
+//! function it happened in (see [`symbols`]), and an [`ExceptionFrame`]'s registers, error code
+//! and top of the stack, in the one layout. A breakpoint returns to where it came from,
+//! everything else is a bug and ends in a panic. The exception is a fault from a
+//! [`user`](crate::user) program, which only kills that program.
+//!
+//! Page faults from the kernel also say what the access was, as far as the error code tells,
+//! and how the faulting address is mapped, level by level. NMIs are the [`watchdog`]'s while
+//! its tick check is on.
+//!
+//! Every handler starts by getting GS back onto this CPU's [`percpu`] block, with a
+//! [`KernelGs`]. Those that can come in while the kernel has the user's GS loaded, NMIs,
+//! machine checks, double faults, and general protection faults, look at the GS base itself.
+//!
+//! Double faults run on their own stack from the TSS (see [`gdt`](crate::gdt)). The usual
+//! reason for one is a kernel stack overflow: the CPU can't push the page fault's frame onto
+//! the stack that just ran out, and a handler on that same stack would fault a third time.
+//! Kernel stacks have guard pages under them (see [`stack`]), and a fault on one is reported
+//! as an overflow of that stack.
+//!
+//! Every CPU counts the interrupts it takes on each vector, exceptions and IRQs alike, and the
+//! spurious ones from the APIC or the PICs separately. [`stats`] adds them up into a
+//! [`Stats`], which the shell's `lsirq` and `/dev/interrupts` print.
+//!
+//! links:
+//! - reference post: <https://os.phil-opp.com/cpu-exceptions/>
+//! - exception list: <https://wiki.osdev.org/Exceptions>
+//!
+
+use core::arch::global_asm;
+use core::fmt;
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::Segment as _;
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::registers::segmentation::{DS, ES, FS, GS};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+use crate::acpi::MAX_CPUS;
+use crate::arch::msr;
+use crate::debug::symbols::{self, Resolved};
+use crate::init::{InitCall, Stage};
+use crate::mem::{paging, stack};
+use crate::percpu::KernelGs;
+use crate::{apic, debug, gdt, percpu, pic, println, process, smp, syscall, user, watchdog, wlog};
+
+pub const VECTORS: usize = 256;
+/// Vectors below this are CPU exceptions
+pub const EXCEPTIONS: u8 = 32;
+
+/// Interrupts taken by CPU and vector
+static COUNTS: [[AtomicU64; VECTORS]; MAX_CPUS] =
+    [const { [const { AtomicU64::new(0) }; VECTORS] }; MAX_CPUS];
+/// Spurious interrupts by CPU, which aren't in [`COUNTS`]
+static SPURIOUS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Count an interrupt on `vector` on this CPU, from its handler
+pub fn record(vector: u8) {
+    COUNTS[percpu::cpu_id()][vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a spurious interrupt on this CPU, from its handler
+pub fn record_spurious() {
+    SPURIOUS[percpu::cpu_id()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Interrupts taken on `vector` since boot, over every CPU
+pub fn count(vector: u8) -> u64 {
+    COUNTS
+        .iter()
+        .map(|counts| counts[vector as usize].load(Ordering::Relaxed))
+        .sum()
+}
+
+/// What one CPU has taken since boot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuStats {
+    pub exceptions: u64,
+    /// Everything that wasn't an exception or spurious
+    pub irqs: u64,
+    pub spurious: u64,
+}
+
+/// A snapshot of the interrupt counters
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// By vector, over every CPU
+    pub vectors: [u64; VECTORS],
+    cpus: [CpuStats; MAX_CPUS],
+    cpu_count: usize,
+}
+
+impl Stats {
+    /// Each online CPU's counts, by CPU number
+    pub fn cpus(&self) -> &[CpuStats] {
+        &self.cpus[..self.cpu_count]
+    }
+
+    /// Every CPU's counts added up
+    pub fn total(&self) -> CpuStats {
+        self.cpus()
+            .iter()
+            .fold(CpuStats::default(), |total, cpu| CpuStats {
+                exceptions: total.exceptions + cpu.exceptions,
+                irqs: total.irqs + cpu.irqs,
+                spurious: total.spurious + cpu.spurious,
+            })
+    }
+}
+
+/// Every online CPU, then every IRQ and any other vector that's been taken
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "cpu  exceptions        irqs  spurious")?;
+        for (cpu, stats) in self.cpus().iter().enumerate() {
+            writeln!(
+                f,
+                "{:>3}  {:>10}  {:>10}  {:>8}",
+                cpu, stats.exceptions, stats.irqs, stats.spurious
+            )?;
+        }
+        writeln!(f, "vector  source              count")?;
+        for (vector, &count) in self.vectors.iter().enumerate() {
+            let vector = vector as u8;
+            let irq = vector.wrapping_sub(pic::IRQ_BASE);
+            if irq < pic::IRQ_COUNT {
+                let handler = if pic::has_handler(irq) {
+                    ""
+                } else {
+                    "  (no handler)"
+                };
+                writeln!(f, "{:>6}  irq {:<12} {:>8}{}", vector, irq, count, handler)?;
+            } else if count != 0 {
+                let source = if vector < EXCEPTIONS {
+                    "exception"
+                } else {
+                    "other"
+                };
+                writeln!(f, "{:>6}  {:<16} {:>8}", vector, source, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Add up the counters
+pub fn stats() -> Stats {
+    let mut stats = Stats {
+        vectors: [0; VECTORS],
+        cpus: [CpuStats::default(); MAX_CPUS],
+        cpu_count: smp::online().min(MAX_CPUS),
+    };
+    for cpu in 0..stats.cpu_count {
+        for (vector, count) in COUNTS[cpu].iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            stats.vectors[vector] += count;
+            if vector < EXCEPTIONS as usize {
+                stats.cpus[cpu].exceptions += count;
+            } else {
+                stats.cpus[cpu].irqs += count;
+            }
+        }
+        stats.cpus[cpu].spurious = SPURIOUS[cpu].load(Ordering::Relaxed);
+    }
+    stats
+}
+
+/// Vectors with a special case in [`exception`]
+const BREAKPOINT: u8 = 3;
+const NMI: u8 = 2;
+const DOUBLE_FAULT: u8 = 8;
+const GENERAL_PROTECTION: u8 = 13;
+const PAGE_FAULT: u8 = 14;
+const MACHINE_CHECK: u8 = 18;
+
+/// Each exception's name, by vector, None for the reserved ones
+const NAMES: [Option<&str>; EXCEPTIONS as usize] = [
+    Some("divide error"),
+    Some("debug"),
+    Some("non-maskable interrupt"),
+    Some("breakpoint"),
+    Some("overflow"),
+    Some("bound range exceeded"),
+    Some("invalid opcode"),
+    Some("device not available"),
+    Some("double fault"),
+    None,
+    Some("invalid TSS"),
+    Some("segment not present"),
+    Some("stack segment fault"),
+    Some("general protection fault"),
+    Some("page fault"),
+    None,
+    Some("x87 floating point"),
+    Some("alignment check"),
+    Some("machine check"),
+    Some("SIMD floating point"),
+    Some("virtualization"),
+    Some("control protection"),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    Some("hypervisor injection"),
+    Some("VMM communication"),
+    Some("security"),
+    None,
+];
+
+/// Vectors the CPU pushes an error code for
+const WITH_CODE: &[u8] = &[8, 10, 11, 12, 13, 14, 17, 21, 29, 30];
+
+/// Quadwords of the faulting stack [`ExceptionFrame`] prints
+const STACK_DUMP: usize = 8;
+
+/// What an exception saved, as the entry stub pushed it
+#[repr(C)]
+pub struct ExceptionFrame {
+    /// Read by the stub before anything can fault again
+    pub cr2: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// 0 for exceptions without one
+    pub code: u64,
+    /// Pushed by the CPU
+    pub cpu: InterruptStackFrame,
+}
+
+// 23 quadwords, see the stub
+const _: () = assert!(size_of::<ExceptionFrame>() == 23 * 8);
+const _: () = assert!(offset_of!(ExceptionFrame, vector) == 16 * 8);
+
+// Every exception vector gets an entry that pushes a 0 in place of the error code if the CPU
+// didn't push one, and then the vector, so the common part always finds the same layout.
+// `exception_entries` has their addresses by vector, 0 for the reserved ones.
+global_asm!(
+    ".macro exception_entry vector, code",
+    "exception_entry_\\vector:",
+    ".if \\code == 0",
+    "    pushq $0",
+    ".endif",
+    "    pushq $\\vector",
+    "    jmp exception_common",
+    ".endm",
+    "exception_entry 0, 0",
+    "exception_entry 1, 0",
+    "exception_entry 2, 0",
+    "exception_entry 3, 0",
+    "exception_entry 4, 0",
+    "exception_entry 5, 0",
+    "exception_entry 6, 0",
+    "exception_entry 7, 0",
+    "exception_entry 8, 1",
+    "exception_entry 10, 1",
+    "exception_entry 11, 1",
+    "exception_entry 12, 1",
+    "exception_entry 13, 1",
+    "exception_entry 14, 1",
+    "exception_entry 16, 0",
+    "exception_entry 17, 1",
+    "exception_entry 18, 0",
+    "exception_entry 19, 0",
+    "exception_entry 20, 0",
+    "exception_entry 21, 1",
+    "exception_entry 28, 0",
+    "exception_entry 29, 1",
+    "exception_entry 30, 1",
+    "exception_common:",
+    "    pushq %rax",
+    "    pushq %rbx",
+    "    pushq %rcx",
+    "    pushq %rdx",
+    "    pushq %rsi",
+    "    pushq %rdi",
+    "    pushq %rbp",
+    "    pushq %r8",
+    "    pushq %r9",
+    "    pushq %r10",
+    "    pushq %r11",
+    "    pushq %r12",
+    "    pushq %r13",
+    "    pushq %r14",
+    "    pushq %r15",
+    "    movq %cr2, %rax",
+    "    pushq %rax",
+    "    cld",
+    "    movq %rsp, %rdi",
+    // 23 quadwords on a stack the CPU aligned to 16 bytes before pushing its frame
+    "    subq $8, %rsp",
+    "    callq {exception}",
+    "    addq $16, %rsp",
+    "    popq %r15",
+    "    popq %r14",
+    "    popq %r13",
+    "    popq %r12",
+    "    popq %r11",
+    "    popq %r10",
+    "    popq %r9",
+    "    popq %r8",
+    "    popq %rbp",
+    "    popq %rdi",
+    "    popq %rsi",
+    "    popq %rdx",
+    "    popq %rcx",
+    "    popq %rbx",
+    "    popq %rax",
+    "    addq $16, %rsp",
+    "    iretq",
+    ".pushsection .rodata.exception_entries, \"a\"",
+    ".balign 8",
+    ".global exception_entries",
+    "exception_entries:",
+    "    .quad exception_entry_0, exception_entry_1, exception_entry_2, exception_entry_3",
+    "    .quad exception_entry_4, exception_entry_5, exception_entry_6, exception_entry_7",
+    "    .quad exception_entry_8, 0, exception_entry_10, exception_entry_11",
+    "    .quad exception_entry_12, exception_entry_13, exception_entry_14, 0",
+    "    .quad exception_entry_16, exception_entry_17, exception_entry_18, exception_entry_19",
+    "    .quad exception_entry_20, exception_entry_21, 0, 0",
+    "    .quad 0, 0, 0, 0",
+    "    .quad exception_entry_28, exception_entry_29, exception_entry_30, 0",
+    ".popsection",
+    exception = sym exception,
+    options(att_syntax)
+);
+
+extern "C" {
+    static exception_entries: [u64; EXCEPTIONS as usize];
+}
+
+/// The name of exception `vector`
+fn name(vector: u8) -> &'static str {
+    NAMES
+        .get(vector as usize)
+        .copied()
+        .flatten()
+        .unwrap_or("reserved")
+}
+
+/// The function the exception happened in
+fn at(frame: &InterruptStackFrame) -> Resolved {
+    symbols::resolve(frame.instruction_pointer.as_u64())
+}
+
+/// Every register, then the top of the stack if it's the kernel's and mapped. The same fixed
+/// layout for every exception, three registers to a line so it fits the VGA screen.
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cpu = &self.cpu;
+        let rsp = cpu.stack_pointer.as_u64();
+        let rows = [
+            [("rax", self.rax), ("rbx", self.rbx), ("rcx", self.rcx)],
+            [("rdx", self.rdx), ("rsi", self.rsi), ("rdi", self.rdi)],
+            [("rbp", self.rbp), ("rsp", rsp), ("r8", self.r8)],
+            [("r9", self.r9), ("r10", self.r10), ("r11", self.r11)],
+            [("r12", self.r12), ("r13", self.r13), ("r14", self.r14)],
+            [
+                ("r15", self.r15),
+                ("rip", cpu.instruction_pointer.as_u64()),
+                ("rfl", cpu.cpu_flags.bits()),
+            ],
+            [
+                ("cr0", Cr0::read_raw()),
+                ("cr2", self.cr2),
+                ("cr3", Cr3::read().0.start_address().as_u64()),
+            ],
+        ];
+        for row in rows {
+            for (i, (name, value)) in row.into_iter().enumerate() {
+                let gap = if i == 0 { "" } else { " " };
+                write!(f, "{}{:<3} {:016x}", gap, name, value)?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "cr4 {:016x} err ", Cr4::read_raw())?;
+        if WITH_CODE.contains(&(self.vector as u8)) {
+            writeln!(f, "{:016x} vec {}", self.code, self.vector)?;
+        } else {
+            writeln!(f, "---------------- vec {}", self.vector)?;
+        }
+        // the CPU leaves the data segment registers alone, so they're still the faulting code's
+        writeln!(
+            f,
+            "cs {:04x} ss {:04x} ds {:04x} es {:04x} fs {:04x} gs {:04x} fsbase {:016x}",
+            cpu.code_segment.0,
+            cpu.stack_segment.0,
+            DS::get_reg().0,
+            ES::get_reg().0,
+            FS::get_reg().0,
+            GS::get_reg().0,
+            msr::fs_base().as_u64()
+        )?;
+
+        let end = rsp.saturating_add(STACK_DUMP as u64 * 8);
+        let readable = rsp.is_multiple_of(8)
+            && rsp >= crate::mem::PHYS_OFFSET
+            && [rsp, end - 1].into_iter().all(|addr| {
+                VirtAddr::try_new(addr).is_ok_and(|virt| {
+                    stack::guard_of(virt).is_none() && crate::mem::virt_to_phys(virt).is_some()
+                })
+            });
+        if !readable {
+            return write!(f, "stack {:016x} can't be read", rsp);
+        }
+        write!(f, "stack {:016x}", rsp)?;
+        let words = unsafe { core::slice::from_raw_parts(rsp as *const u64, STACK_DUMP) };
+        for (i, word) in words.iter().enumerate() {
+            if i % 4 == 0 {
+                write!(f, "\n  +{:02x}", i * 8)?;
+            }
+            write!(f, " {:016x}", word)?;
+        }
+        Ok(())
+    }
+}
+
+/// Report an exception that can't be handled, with what `details` prints between the name
+/// and the registers, and panic
+fn unhandled(frame: &ExceptionFrame, details: impl FnOnce()) -> ! {
+    let vector = frame.vector as u8;
+    println!("EXCEPTION: {} in {}", name(vector), at(&frame.cpu));
+    details();
+    println!("{}", frame);
+    panic!("unhandled exception: {}", name(vector));
+}
+
+/// What a page fault's error code says happened, like "write to a page that isn't present"
+fn page_fault_cause(code: PageFaultErrorCode) -> (&'static str, &'static str) {
+    let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch from"
+    } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write to"
+    } else {
+        "read from"
+    };
+    let reason = if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        "a page table with reserved bits set"
+    } else if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "a page that doesn't allow it"
+    } else {
+        "a page that isn't present"
+    };
+    (access, reason)
+}
+
+fn page_fault(frame: &ExceptionFrame) {
+    let addr = frame.cr2;
+    let code = PageFaultErrorCode::from_bits_truncate(frame.code);
+    let (access, reason) = page_fault_cause(code);
+    let mode = if code.contains(PageFaultErrorCode::USER_MODE) {
+        "user"
+    } else {
+        "kernel"
+    };
+
+    if user::from_user(&frame.cpu) {
+        // a lazy or copy-on-write page, which isn't the program's fault
+        let write = code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+        if VirtAddr::try_new(addr).is_ok_and(|virt| process::handle_page_fault(virt, write)) {
+            return;
+        }
+        wlog!("user: page fault, {} {:#x}, {}", access, addr, reason);
+        user::fault("page fault", &frame.cpu);
+    }
+
+    unhandled(frame, || {
+        println!("  error code {:?}", code);
+        if let Some(name) = VirtAddr::try_new(addr).ok().and_then(stack::guard_of) {
+            println!("  kernel stack overflow in {}", name);
+        }
+        println!("  {} mode {} {:#x}, {}", mode, access, addr, reason);
+        match VirtAddr::try_new(addr) {
+            Ok(virt) => paging::dump_mapping(virt),
+            Err(_) => println!("  the address isn't canonical"),
+        }
+    });
+}
+
+fn double_fault(frame: &ExceptionFrame) -> ! {
+    // a stack overflow faults on the guard page, then again pushing the page fault's frame
+    let overflow = VirtAddr::try_new(frame.cr2)
+        .ok()
+        .and_then(stack::guard_of)
+        .or_else(|| stack::guard_of(frame.cpu.stack_pointer - 8u64));
+    unhandled(frame, || {
+        if let Some(name) = overflow {
+            println!("  kernel stack overflow in {}", name);
+        }
+    });
+}
+
+/// Where every exception goes from its entry stub. Returning goes back to where it came from,
+/// which only breakpoints and page faults a process can be given a page for do.
+extern "C" fn exception(frame: &ExceptionFrame) {
+    let vector = frame.vector as u8;
+    let _gs = match vector {
+        // these can come in while the kernel has the user's GS loaded, before the syscall
+        // entry code's swapgs or on the iretq back
+        NMI | DOUBLE_FAULT | GENERAL_PROTECTION | MACHINE_CHECK => KernelGs::enter_paranoid(),
+        _ => KernelGs::enter(&frame.cpu),
+    };
+    record(vector);
+    match vector {
+        NMI if watchdog::nmi() => return,
+        BREAKPOINT => {
+            println!("EXCEPTION: breakpoint in {}\n{}", at(&frame.cpu), frame);
+            return;
+        }
+        PAGE_FAULT => page_fault(frame),
+        DOUBLE_FAULT => double_fault(frame),
+        // the iretq a syscall returns through, when the user gave it an address that isn't
+        // canonical, faults with the user's GS already loaded
+        GENERAL_PROTECTION if syscall::is_return_to_user(&frame.cpu) => {
+            user::fault(name(vector), &frame.cpu)
+        }
+        _ => {}
+    }
+    if user::from_user(&frame.cpu) {
+        user::fault(name(vector), &frame.cpu);
+    }
+    unhandled(frame, || {});
+}
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        let entry = |vector: u8| VirtAddr::new(unsafe { exception_entries[vector as usize] });
+        unsafe {
+            idt.divide_error.set_handler_addr(entry(0));
+            idt.debug.set_handler_addr(entry(1));
+            idt.non_maskable_interrupt.set_handler_addr(entry(NMI));
+            idt.breakpoint.set_handler_addr(entry(BREAKPOINT));
+            idt.overflow.set_handler_addr(entry(4));
+            idt.bound_range_exceeded.set_handler_addr(entry(5));
+            idt.invalid_opcode.set_handler_addr(entry(6));
+            idt.device_not_available.set_handler_addr(entry(7));
+            idt.double_fault
+                .set_handler_addr(entry(DOUBLE_FAULT))
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.invalid_tss.set_handler_addr(entry(10));
+            idt.segment_not_present.set_handler_addr(entry(11));
+            idt.stack_segment_fault.set_handler_addr(entry(12));
+            idt.general_protection_fault
+                .set_handler_addr(entry(GENERAL_PROTECTION));
+            idt.page_fault.set_handler_addr(entry(PAGE_FAULT));
+            idt.x87_floating_point.set_handler_addr(entry(16));
+            idt.alignment_check.set_handler_addr(entry(17));
+            idt.machine_check.set_handler_addr(entry(MACHINE_CHECK));
+            idt.simd_floating_point.set_handler_addr(entry(19));
+            idt.virtualization.set_handler_addr(entry(20));
+            idt.cp_protection_exception.set_handler_addr(entry(21));
+            idt.hv_injection_exception.set_handler_addr(entry(28));
+            idt.vmm_communication_exception.set_handler_addr(entry(29));
+            idt.security_exception.set_handler_addr(entry(30));
+        }
+        pic::install(&mut idt);
+        apic::install(&mut idt);
+        debug::gdbstub::install(&mut idt);
+        idt
+    };
+}
+
+/// Load the IDT on this CPU, the application processors share the boot CPU's
+pub fn load() {
+    IDT.load();
+}
+
+fn init() {
+    load();
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "idt",
+    stage: Stage::Interrupts,
+    // entries point at whatever code segment is loaded when they're filled in, and the
+    // double fault stack is in the TSS
+    after: &["gdt"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn breakpoint_returns() {
+        let before = stats().vectors[3];
+        x86_64::instructions::interrupts::int3();
+        assert_eq!(stats().vectors[3], before + 1);
+    }
+}
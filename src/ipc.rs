@@ -0,0 +1,287 @@
+//! Message passing between kernel threads and tasks
+//!
+//! [`channel`] makes a bounded queue with any number of [`Sender`]s and one [`Receiver`].
+//! Sending blocks while the queue is full and receiving blocks while it's empty, the same way
+//! waiting on a [`WaitQueue`] does, and each has a `try_` version that doesn't. A [`task`]
+//! awaits [`Receiver::recv_async`] instead of blocking.
+//!
+//! The room for `capacity` messages is allocated up front, so [`Sender::try_send`] doesn't
+//! allocate and can be called from an interrupt handler, like a driver handing over what it
+//! got. The queue's lock is only taken with interrupts off for that reason.
+//!
+//! Once every sender is gone the receiver gets what's left, then [`RecvError`]. Once the
+//! receiver is gone, sending hands the message back in the error.
+//!
+//! ```ignore
+//! let (sender, receiver) = ipc::channel("scancodes", 64);
+//! sched::spawn("decoder", move || {
+//!     while let Ok(scancode) = receiver.recv() {
+//!         // ...
+//!     }
+//! });
+//! ```
+//!
+//! [`task`]: crate::task
+//!
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::task::Poll;
+
+use x86_64::instructions::interrupts;
+
+use crate::sync::{SpinLock, WaitQueue};
+use crate::task::WakerSlot;
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver: bool,
+}
+
+struct Channel<T> {
+    /// Only locked with interrupts off
+    state: SpinLock<State<T>>,
+    capacity: usize,
+    /// Receivers blocked on an empty queue, and senders on a full one
+    not_empty: WaitQueue,
+    not_full: WaitQueue,
+    /// The task in [`Receiver::recv_async`]
+    waker: WakerSlot,
+}
+
+impl<T> Channel<T> {
+    fn with_state<R>(&self, f: impl FnOnce(&mut State<T>) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    fn wake_receiver(&self) {
+        self.not_empty.wake_one();
+        self.waker.wake();
+    }
+}
+
+/// Make a channel that holds up to `capacity` messages, at least 1
+pub fn channel<T: Send>(name: &'static str, capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let capacity = capacity.max(1);
+    let channel = Arc::new(Channel {
+        state: SpinLock::new(
+            name,
+            State {
+                queue: VecDeque::with_capacity(capacity),
+                senders: 1,
+                receiver: true,
+            },
+        ),
+        capacity,
+        not_empty: WaitQueue::new(name),
+        not_full: WaitQueue::new(name),
+        waker: WakerSlot::new(name),
+    });
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+/// Why [`Sender::try_send`] didn't send, with the message it was given
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The queue has `capacity` messages in it already
+    Full(T),
+    /// The receiver is gone
+    Disconnected(T),
+}
+
+/// [`Sender::send`] couldn't send because the receiver is gone, here's the message back
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+/// Why [`Receiver::try_recv`] didn't return a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// Nothing's been sent since the last one was received
+    Empty,
+    /// The queue is empty and every sender is gone
+    Disconnected,
+}
+
+/// The queue is empty and every sender is gone, nothing more can come
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+/// The sending end of a [`channel`], clone it for more senders
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T: Send> Sender<T> {
+    /// Queue `message` if there's room. Doesn't block or allocate.
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        self.channel.with_state(|state| {
+            if !state.receiver {
+                return Err(TrySendError::Disconnected(message));
+            }
+            if state.queue.len() >= self.channel.capacity {
+                return Err(TrySendError::Full(message));
+            }
+            state.queue.push_back(message);
+            Ok(())
+        })?;
+        self.channel.wake_receiver();
+        Ok(())
+    }
+
+    /// Queue `message`, blocking until there's room
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        let mut message = Some(message);
+        let mut result = Ok(());
+        self.channel
+            .not_full
+            .wait_until(|| match self.try_send(message.take().unwrap()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(unsent)) => {
+                    message = Some(unsent);
+                    false
+                }
+                Err(TrySendError::Disconnected(unsent)) => {
+                    result = Err(SendError(unsent));
+                    true
+                }
+            });
+        result
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.channel.with_state(|state| state.senders += 1);
+        Sender {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let last = self.channel.with_state(|state| {
+            state.senders -= 1;
+            state.senders == 0
+        });
+        if last {
+            // so a receiver waiting on an empty queue finds out
+            self.channel.not_empty.wake_all();
+            self.channel.waker.wake();
+        }
+    }
+}
+
+/// The receiving end of a [`channel`]
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T: Send> Receiver<T> {
+    /// Take the oldest message, if there is one
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let message = self
+            .channel
+            .with_state(|state| match state.queue.pop_front() {
+                Some(message) => Ok(message),
+                None if state.senders == 0 => Err(TryRecvError::Disconnected),
+                None => Err(TryRecvError::Empty),
+            })?;
+        self.channel.not_full.wake_one();
+        Ok(message)
+    }
+
+    /// Take the oldest message, blocking until there is one
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut result = Err(RecvError);
+        self.channel.not_empty.wait_until(|| match self.try_recv() {
+            Ok(message) => {
+                result = Ok(message);
+                true
+            }
+            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Disconnected) => true,
+        });
+        result
+    }
+
+    /// Take the oldest message, waiting for one from a [`task`](crate::task)
+    pub fn recv_async(&self) -> impl Future<Output = Result<T, RecvError>> + '_ {
+        core::future::poll_fn(move |context| {
+            // registered before checking, so a message sent in between still wakes it
+            self.channel.waker.register(context.waker());
+            match self.try_recv() {
+                Ok(message) => Poll::Ready(Ok(message)),
+                Err(TryRecvError::Empty) => Poll::Pending,
+                Err(TryRecvError::Disconnected) => Poll::Ready(Err(RecvError)),
+            }
+        })
+    }
+
+    /// Messages waiting to be received
+    pub fn len(&self) -> usize {
+        self.channel.with_state(|state| state.queue.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.with_state(|state| state.receiver = false);
+        // senders waiting on a full queue would wait forever
+        self.channel.not_full.wake_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sched;
+
+    #[test_case]
+    fn bounded_and_disconnects() {
+        let (sender, receiver) = channel("test", 2);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(sender.try_send(1), Ok(()));
+        assert_eq!(sender.try_send(2), Ok(()));
+        assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(receiver.len(), 2);
+
+        let other = sender.clone();
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Ok(1));
+        drop(other);
+        // what's queued still comes out after the senders are gone
+        assert_eq!(receiver.try_recv(), Ok(2));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+
+        let (sender, receiver) = channel("test", 1);
+        drop(receiver);
+        assert_eq!(sender.send(4), Err(SendError(4)));
+    }
+
+    /// A thread blocked on a full queue gets through as the receiver makes room
+    #[test_case]
+    fn blocking_between_threads() {
+        let (sender, receiver) = channel("test", 1);
+        sched::spawn("ipc test", move || {
+            for i in 0..4 {
+                sender.send(i).unwrap();
+            }
+        });
+        for i in 0..4 {
+            assert_eq!(receiver.recv(), Ok(i));
+        }
+        assert_eq!(receiver.recv(), Err(RecvError));
+    }
+}
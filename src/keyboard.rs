@@ -0,0 +1,177 @@
+//! PS/2 keyboard
+//!
+//! The keyboard controller translates whatever the keyboard speaks into scancode set 1, which
+//! is decoded here with a US layout. Keys go into the [console input](crate::console::input)
+//! as the bytes a VT100 terminal would send for them: ASCII for the printable keys, control
+//! characters for Ctrl+letter, and escape sequences for the cursor and editing keys. That way
+//! the keyboard and the serial port look the same to whoever reads them. Shift+PgUp/PgDn and
+//! Alt+F1..F4 are the exception, they scroll the VGA text history and switch [`tty`]s, and
+//! never reach the input.
+//!
+//! links:
+//! - <https://wiki.osdev.org/PS/2_Keyboard>
+//! - scancode set 1: <https://www.win.tue.nl/~aeb/linux/kbd/scancodes-1.html>
+//!
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::structures::port::PortRead as _;
+
+use crate::console::input;
+use crate::init::{InitCall, Stage};
+use crate::{pic, tty};
+
+const KEYBOARD_IRQ: u8 = 1;
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+/// Status bit saying there's a byte to read from `DATA`
+const OUTPUT_FULL: u8 = 1 << 0;
+/// Status bit saying the byte in `DATA` came from the mouse
+const FROM_MOUSE: u8 = 1 << 5;
+
+/// Set on the scancode when a key goes up
+const RELEASED: u8 = 0x80;
+/// Comes before the scancode of the keys that were added after the original keyboard
+const EXTENDED: u8 = 0xe0;
+
+const LEFT_SHIFT: u8 = 0x2a;
+const RIGHT_SHIFT: u8 = 0x36;
+const CTRL: u8 = 0x1d;
+const ALT: u8 = 0x38;
+const CAPS_LOCK: u8 = 0x3a;
+// extended
+const PAGE_UP: u8 = 0x49;
+const PAGE_DOWN: u8 = 0x51;
+const F1: u8 = 0x3b;
+const F4: u8 = 0x3e;
+
+/// What each scancode types, without and with shift. 0 for keys that don't type anything.
+const KEYMAP: [(u8, u8); 0x3a] = {
+    let normal = b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+    let shifted = b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+    let mut map = [(0, 0); 0x3a];
+    let mut i = 0;
+    while i < map.len() {
+        map[i] = (normal[i], shifted[i]);
+        i += 1;
+    }
+    map
+};
+
+/// The escape sequence an extended key sends, by its scancode
+fn extended_sequence(scancode: u8) -> Option<&'static [u8]> {
+    Some(match scancode {
+        0x48 => b"\x1b[A",
+        0x50 => b"\x1b[B",
+        0x4d => b"\x1b[C",
+        0x4b => b"\x1b[D",
+        0x47 => b"\x1b[H",
+        0x4f => b"\x1b[F",
+        0x53 => b"\x1b[3~",
+        PAGE_UP => b"\x1b[5~",
+        PAGE_DOWN => b"\x1b[6~",
+        0x1c => b"\n",
+        0x35 => b"/",
+        _ => return None,
+    })
+}
+
+// decoder state, only touched by the interrupt handler
+static SHIFT: AtomicBool = AtomicBool::new(false);
+static CTRL_HELD: AtomicBool = AtomicBool::new(false);
+static ALT_HELD: AtomicBool = AtomicBool::new(false);
+static CAPS: AtomicBool = AtomicBool::new(false);
+static SAW_EXTENDED: AtomicBool = AtomicBool::new(false);
+
+fn decode(scancode: u8) {
+    if scancode == EXTENDED {
+        SAW_EXTENDED.store(true, Ordering::Relaxed);
+        return;
+    }
+    let extended = SAW_EXTENDED.swap(false, Ordering::Relaxed);
+    let released = scancode & RELEASED != 0;
+    let key = scancode & !RELEASED;
+
+    match key {
+        LEFT_SHIFT | RIGHT_SHIFT if !extended => SHIFT.store(!released, Ordering::Relaxed),
+        // the right ones are the extended versions of the left ones
+        CTRL => CTRL_HELD.store(!released, Ordering::Relaxed),
+        ALT => ALT_HELD.store(!released, Ordering::Relaxed),
+        CAPS_LOCK if !released => {
+            CAPS.fetch_xor(true, Ordering::Relaxed);
+        }
+        _ if released => {}
+        // shift+page up/down scroll the text console's history instead of being typed
+        PAGE_UP | PAGE_DOWN if extended && SHIFT.load(Ordering::Relaxed) => {
+            let half_screen = 12;
+            tty::scroll_view(if key == PAGE_UP {
+                half_screen
+            } else {
+                -half_screen
+            });
+        }
+        F1..=F4 if !extended && ALT_HELD.load(Ordering::Relaxed) => {
+            tty::switch((key - F1) as usize);
+        }
+        _ if extended => {
+            input::push(extended_sequence(key).unwrap_or(&[]));
+        }
+        _ => {
+            let Some(&(normal, shifted)) = KEYMAP.get(key as usize) else {
+                return;
+            };
+            let shift = SHIFT.load(Ordering::Relaxed);
+            let mut byte = if shift { shifted } else { normal };
+            if normal.is_ascii_lowercase() && CAPS.load(Ordering::Relaxed) {
+                byte = if shift { normal } else { shifted };
+            }
+            if byte.is_ascii_alphabetic() && CTRL_HELD.load(Ordering::Relaxed) {
+                byte &= 0x1f;
+            }
+            if byte != 0 {
+                input::push(&[byte]);
+            }
+        }
+    }
+}
+
+/// Decode whatever the controller has, without the interrupt. For when interrupts are off for
+/// good, like after a panic.
+pub fn poll() {
+    unsafe {
+        loop {
+            let status = u8::read_from_port(STATUS);
+            if status & OUTPUT_FULL == 0 {
+                break;
+            }
+            let byte = u8::read_from_port(DATA);
+            if status & FROM_MOUSE == 0 {
+                decode(byte);
+            }
+        }
+    }
+}
+
+fn interrupt() {
+    let scancode = unsafe { u8::read_from_port(DATA) };
+    decode(scancode);
+}
+
+fn init() {
+    // throw away anything left over from the firmware, or the first interrupt never comes
+    interrupts::without_interrupts(|| unsafe {
+        while u8::read_from_port(STATUS) & OUTPUT_FULL != 0 {
+            u8::read_from_port(DATA);
+        }
+    });
+    pic::set_handler(KEYBOARD_IRQ, interrupt);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "keyboard",
+    stage: Stage::Drivers,
+    after: &["pic"],
+    func: init,
+};
@@ -0,0 +1,137 @@
+//! Kernel message buffer
+//!
+//! Everything printed is also kept in a [`SIZE`] byte ring buffer, registered as the `klog`
+//! [`sink`](crate::console::sink), so it's still around after it scrolls off the screen. Once
+//! the buffer is full the oldest text is overwritten.
+//!
+//! [`for_each_line`] goes through what's buffered, oldest first, and [`replay`] writes it all
+//! out somewhere else, such as the serial port after it's been turned back on.
+//! [`for_each_line_before`] stops at an earlier [`position`], to leave out what came after.
+//!
+
+use core::fmt;
+
+use crate::console::sink::LogSink;
+use crate::sync::SpinLock;
+
+pub const SIZE: usize = 64 * 1024;
+/// Longest line handed out by [`for_each_line`], longer ones come out in pieces
+pub const MAX_LINE: usize = 256;
+
+struct Ring {
+    buf: [u8; SIZE],
+    /// Bytes written since boot, the next one goes at `written % SIZE`
+    written: u64,
+}
+
+impl Ring {
+    /// The oldest byte still in the buffer, counting like `written`
+    fn oldest(&self) -> u64 {
+        self.written.saturating_sub(SIZE as u64)
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.buf[(self.written % SIZE as u64) as usize] = byte;
+            self.written += 1;
+        }
+    }
+
+    /// Copy from `pos` up to and including the next newline, but not past `end` or the size of
+    /// `out`. Returns how many bytes were copied.
+    fn copy_line(&self, pos: u64, end: u64, out: &mut [u8]) -> usize {
+        let mut len = 0;
+        while pos + (len as u64) < end && len < out.len() {
+            let byte = self.buf[((pos + len as u64) % SIZE as u64) as usize];
+            out[len] = byte;
+            len += 1;
+            if byte == b'\n' {
+                break;
+            }
+        }
+        len
+    }
+}
+
+static KLOG: SpinLock<Ring> = SpinLock::new(
+    "klog",
+    Ring {
+        buf: [0; SIZE],
+        written: 0,
+    },
+);
+
+pub struct KlogSink;
+
+impl LogSink for KlogSink {
+    fn name(&self) -> &'static str {
+        "klog"
+    }
+
+    fn write_str(&self, s: &str) {
+        KLOG.lock().push(s.as_bytes());
+    }
+}
+
+/// Bytes written since boot, which marks where the buffer is up to for [`for_each_line_before`]
+pub fn position() -> u64 {
+    KLOG.lock().written
+}
+
+/// Call `f` with each buffered line, oldest first, with its newline if it has one
+///
+/// Only what was there when this started is gone through, so `f` can print without chasing
+/// its own output. The buffer isn't locked while `f` runs.
+pub fn for_each_line(f: impl FnMut(&str)) {
+    for_each_line_before(u64::MAX, f);
+}
+
+/// [`for_each_line`], but only for what was written before [`position`] returned `end`
+pub fn for_each_line_before(end: u64, mut f: impl FnMut(&str)) {
+    let (mut pos, end) = {
+        let ring = KLOG.lock();
+        let mut pos = ring.oldest();
+        // after wrapping around, the oldest line is probably missing its start
+        if pos > 0 {
+            let mut skipped = [0; MAX_LINE];
+            pos += ring.copy_line(pos, ring.written, &mut skipped) as u64;
+        }
+        (pos, ring.written.min(end))
+    };
+
+    let mut line = [0; MAX_LINE];
+    while pos < end {
+        let len = {
+            let ring = KLOG.lock();
+            // text printed by `f` may have pushed the rest out already
+            pos = pos.max(ring.oldest());
+            ring.copy_line(pos, end, &mut line)
+        };
+        if len == 0 {
+            break;
+        }
+
+        // a piece of a long line can end in the middle of a character
+        let text = match core::str::from_utf8(&line[..len]) {
+            Ok(text) => text,
+            Err(err) if err.valid_up_to() > 0 => {
+                core::str::from_utf8(&line[..err.valid_up_to()]).unwrap()
+            }
+            // not even one character's worth, skip the byte
+            Err(_) => "\u{fffd}",
+        };
+        f(text);
+        pos += text.len().clamp(1, len) as u64;
+    }
+}
+
+/// Write everything that's buffered to `out`
+pub fn replay(out: &mut dyn fmt::Write) -> fmt::Result {
+    let mut result = Ok(());
+    for_each_line(|line| {
+        if result.is_ok() {
+            result = out.write_str(line);
+        }
+    });
+    result
+}
@@ -0,0 +1,111 @@
+#![no_std]
+#![cfg_attr(test, no_main)]
+#![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+pub mod acpi;
+#[cfg(feature = "aml")]
+pub mod aml;
+pub mod apic;
+pub mod arch;
+pub mod block;
+pub mod bootinfo;
+pub mod cmdline;
+pub mod config;
+pub mod console;
+pub mod cpu;
+pub mod debug;
+pub mod drivers;
+pub mod elf;
+pub mod fmt;
+pub mod fs;
+pub mod gdt;
+pub mod gfx;
+pub mod hpet;
+pub mod init;
+pub mod interrupts;
+pub mod ipc;
+pub mod keyboard;
+pub mod klog;
+pub mod log;
+pub mod mem;
+pub mod mouse;
+pub mod net;
+pub mod pci;
+pub mod percpu;
+pub mod pic;
+pub mod power;
+pub mod process;
+pub mod qemu;
+pub mod rand;
+pub mod rtc;
+pub mod sched;
+pub mod serial;
+pub mod shell;
+pub mod smp;
+pub mod speaker;
+pub mod statusbar;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod time;
+pub mod timer;
+pub mod tty;
+pub mod user;
+pub mod vga;
+pub mod watchdog;
+
+/// Something that can be run as a `#[test_case]`
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    qemu::exit(qemu::ExitCode::Success);
+}
+
+/// Panic handler for test kernels, reports the failure and exits QEMU
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    qemu::exit(qemu::ExitCode::Failed);
+}
+
+#[cfg(test)]
+use bootloader::{entry_point, BootInfo};
+
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
+/// Entry point for `cargo test --lib`
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    bootinfo::init(boot_info).unwrap();
+    init::run();
+    test_main();
+    power::halt()
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info)
+}
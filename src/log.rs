@@ -0,0 +1,146 @@
+//! Kernel logging
+//!
+//! [`dlog!`](crate::dlog), [`ilog!`](crate::ilog), [`wlog!`](crate::wlog), and
+//! [`elog!`](crate::elog) print a line to the console at debug, info, warning, and error
+//! level. Anything below the current level is dropped. The level starts at the
+//! [`config::log_level`], info unless `loglevel=` on the command line says otherwise, and can
+//! be changed at any time with [`set_level`].
+//!
+//! Log lines go wherever `print!` goes. Each level has its own color on the console, so
+//! warnings and errors stand out in the boot output. With `logtime` on the command line they
+//! start with the time of day.
+//!
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::console::{self, Color};
+use crate::init::{InitCall, Stage};
+use crate::{cmdline, config};
+use crate::{rtc, tty};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    pub const ALL: [LogLevel; 4] = [
+        LogLevel::Debug,
+        LogLevel::Info,
+        LogLevel::Warn,
+        LogLevel::Error,
+    ];
+
+    /// The level's name, as it's written on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        LogLevel::ALL.into_iter().find(|level| level.name() == name)
+    }
+
+    fn color(self) -> Color {
+        match self {
+            LogLevel::Debug => Color::DarkGray,
+            LogLevel::Info => Color::LightGray,
+            LogLevel::Warn => Color::Yellow,
+            LogLevel::Error => Color::LightRed,
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(config::LOG_LEVEL as u8);
+/// Set by `logtime` on the command line
+static TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+
+/// The lowest level that's printed
+pub fn level() -> LogLevel {
+    LogLevel::ALL[LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether messages at `level` are printed
+pub fn enabled(level: LogLevel) -> bool {
+    level >= self::level()
+}
+
+/// The time of day in front of every line, see [`rtc`](crate::rtc)
+struct Stamp;
+
+impl fmt::Display for Stamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !TIMESTAMPS.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        match rtc::wall_clock_secs() {
+            Some(secs) => write!(
+                f,
+                "[{:02}:{:02}:{:02}] ",
+                secs / 3600,
+                secs / 60 % 60,
+                secs % 60
+            ),
+            None => write!(f, "[--:--:--] "),
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: LogLevel, args: fmt::Arguments) {
+    tty::write_colored(tty::LOG, level.color(), format_args!("{}{}\n", Stamp, args));
+    if !enabled(level) {
+        return;
+    }
+
+    console::_print_colored(level.color(), format_args!("{}{}\n", Stamp, args));
+}
+
+/// Log a line at debug level
+#[macro_export]
+macro_rules! dlog {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::LogLevel::Debug, format_args!($($arg)*)));
+}
+
+/// Log a line at info level
+#[macro_export]
+macro_rules! ilog {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::LogLevel::Info, format_args!($($arg)*)));
+}
+
+/// Log a line at warning level
+#[macro_export]
+macro_rules! wlog {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::LogLevel::Warn, format_args!($($arg)*)));
+}
+
+/// Log a line at error level
+#[macro_export]
+macro_rules! elog {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::LogLevel::Error, format_args!($($arg)*)));
+}
+
+fn init() {
+    TIMESTAMPS.store(cmdline::has("logtime"), Ordering::Relaxed);
+    set_level(config::log_level());
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "log",
+    stage: Stage::Early,
+    after: &["config"],
+    func: init,
+};
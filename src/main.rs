@@ -1,24 +1,86 @@
 #![no_std]
 #![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zenix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
+use zenix::{bootinfo, cmdline, console, gfx, init, println, shell};
 
-mod vga;
-
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    let regs = zenix::debug::regs::capture();
+    // nothing else gets to run on a kernel that's panicking
+    x86_64::instructions::interrupts::disable();
+    zenix::watchdog::disable();
+    // a panic during a quiet boot is shown like any other
+    console::sink::boot_done();
+    gfx::splash::finish();
+
+    // the report goes everywhere but the screen, which gets the panic screen instead
+    let log_end = zenix::klog::position();
+    let screen = zenix::debug::panic_screen::wanted();
+    if screen {
+        console::sink::set_enabled("console", false);
+    }
     println!("{}", info);
-    loop {}
+    println!("{}", regs);
+    zenix::debug::backtrace::print();
+    zenix::speaker::alert();
+    if screen {
+        zenix::debug::panic_screen::show(info, &regs, log_end);
+    }
+    zenix::debug::gdbstub::stop_for_panic(&regs);
+    if cmdline::has("kdb") {
+        zenix::debug::kdb::enter("panic");
+    }
+    if screen && zenix::power::panic_action() == zenix::power::PanicAction::Halt {
+        zenix::debug::panic_screen::wait_for_reboot();
+    }
+    zenix::power::after_panic()
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zenix::test_panic_handler(info)
 }
 
-/// Entry point
-#[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
-    vga::disable_cursor();
+entry_point!(kernel_main);
+
+/// Entry point, called by the bootloader with the boot info it collected
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    if let Err(error) = bootinfo::init(boot_info) {
+        panic!("bad boot info: {:?}", error);
+    }
+
+    init::run();
+
+    #[cfg(test)]
+    test_main();
 
-    for i in 0..40 {
-        println!("line {}", i);
+    println!(
+        "physical memory mapped at {:#x}, {} memory regions",
+        boot_info.physical_memory_offset,
+        boot_info.memory_map.len()
+    );
+    println!("command line: {}", cmdline::raw());
+    match gfx::framebuffer_info() {
+        Some(fb) => println!(
+            "framebuffer: {}x{} {}bpp at {:#x}",
+            fb.width,
+            fb.height,
+            fb.bpp,
+            fb.phys_addr.as_u64()
+        ),
+        None => println!("framebuffer: none, using vga text mode"),
     }
+    let (cols, rows) = console::with_console(|console| console.size());
+    println!("console: {}x{}", cols, rows);
 
-    loop {}
+    gfx::splash::finish();
+    console::sink::boot_done();
+    shell::run()
 }
@@ -0,0 +1,243 @@
+//! Physical frame allocator
+//!
+//! Frames are handed out from the usable regions of the bootloader's memory map, lowest first.
+//! Freed frames go on a free list that's threaded through the frames themselves, reached
+//! through the physical memory window, and get used again before any new ones.
+//!
+//! A frame can have more than one user, like a page that's mapped copy-on-write into several
+//! address spaces after a fork. Each one past the first is counted with [`share`], and
+//! [`release`] only frees the frame once the last one lets go.
+//!
+
+use alloc::collections::BTreeMap;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::{self, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+use super::phys_to_virt;
+use crate::bootinfo;
+use crate::init::{InitCall, Stage};
+use crate::sync::SpinLock;
+use crate::wlog;
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Frame counts, multiply by [`FRAME_SIZE`] for bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    pub total: u64,
+    pub used: u64,
+}
+
+impl FrameStats {
+    pub fn free(&self) -> u64 {
+        self.total - self.used
+    }
+}
+
+pub struct FrameAllocator {
+    map: &'static MemoryMap,
+    /// Index of the region new frames come from
+    region: usize,
+    /// Next frame in that region that's never been handed out
+    next: u64,
+    /// Most recently freed frame, it holds the address of the one freed before it. 0 when
+    /// the list is empty, frame 0 is never usable.
+    free_list: u64,
+    stats: FrameStats,
+}
+
+impl FrameAllocator {
+    /// An allocator for the usable memory in `map`
+    ///
+    /// # Safety
+    ///
+    /// The memory `map` calls usable has to be unused, nothing else may allocate from it.
+    pub unsafe fn new(map: &'static MemoryMap) -> FrameAllocator {
+        let total = map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+            .map(|region| (region.range.end_addr() - region.range.start_addr()) / FRAME_SIZE)
+            .sum();
+
+        FrameAllocator {
+            map,
+            region: 0,
+            next: 0,
+            free_list: 0,
+            stats: FrameStats { total, used: 0 },
+        }
+    }
+
+    /// A frame that's never been handed out before
+    fn fresh(&mut self) -> Option<PhysFrame> {
+        while let Some(region) = self.map.get(self.region) {
+            let start = region.range.start_addr();
+            let end = region.range.end_addr();
+            if region.region_type == MemoryRegionType::Usable {
+                let addr = self.next.max(start);
+                if addr + FRAME_SIZE <= end {
+                    self.next = addr + FRAME_SIZE;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+                }
+            }
+            self.region += 1;
+        }
+        None
+    }
+
+    pub fn allocate(&mut self) -> Option<PhysFrame> {
+        let frame = match self.free_list {
+            0 => self.fresh()?,
+            addr => {
+                let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+                self.free_list = unsafe { *phys_to_virt(frame.start_address()).as_ptr::<u64>() };
+                frame
+            }
+        };
+        self.stats.used += 1;
+        Some(frame)
+    }
+
+    /// A frame below `limit` for hardware that can't reach higher, if the lowest frame that's
+    /// never been handed out is. That's only likely early on, since frames go lowest first.
+    pub fn allocate_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        let (region, next) = (self.region, self.next);
+        let frame = self.fresh()?;
+        if frame.start_address() + FRAME_SIZE > limit {
+            (self.region, self.next) = (region, next);
+            return None;
+        }
+        self.stats.used += 1;
+        Some(frame)
+    }
+
+    /// `count` frames in a row for devices that need more than a page of physically contiguous
+    /// memory, returning the first. They're frames that have never been handed out, the free
+    /// list isn't in any order. Frames skipped to find a run that long go on the free list.
+    pub fn allocate_contiguous(&mut self, count: u64) -> Option<PhysFrame> {
+        let mut first = self.fresh()?;
+        let mut len = 1;
+        while len < count {
+            let frame = self.fresh();
+            if frame == Some(first + len) {
+                len += 1;
+                continue;
+            }
+            // the region ran out, the run so far is no use
+            for i in 0..len {
+                self.stats.used += 1;
+                unsafe { self.deallocate(first + i) };
+            }
+            (first, len) = (frame?, 1);
+        }
+        self.stats.used += count;
+        Some(first)
+    }
+
+    /// Give `frame` back
+    ///
+    /// # Safety
+    ///
+    /// `frame` has to have come from this allocator and can't be in use anymore.
+    pub unsafe fn deallocate(&mut self, frame: PhysFrame) {
+        *phys_to_virt(frame.start_address()).as_mut_ptr::<u64>() = self.free_list;
+        self.free_list = frame.start_address().as_u64();
+        self.stats.used -= 1;
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+}
+
+unsafe impl paging::FrameAllocator<Size4KiB> for FrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for FrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate(frame);
+    }
+}
+
+/// The kernel's frame allocator, `None` until it's set up
+pub static FRAMES: SpinLock<Option<FrameAllocator>> = SpinLock::new("frames", None);
+
+/// Allocate a frame from the kernel's allocator
+pub fn allocate_frame() -> Option<PhysFrame> {
+    FRAMES.lock().as_mut()?.allocate()
+}
+
+/// Allocate `count` physically contiguous frames from the kernel's allocator, see
+/// [`FrameAllocator::allocate_contiguous`]
+pub fn allocate_contiguous(count: u64) -> Option<PhysFrame> {
+    FRAMES.lock().as_mut()?.allocate_contiguous(count)
+}
+
+/// Return a frame to the kernel's allocator
+///
+/// # Safety
+///
+/// `frame` has to have come from [`allocate_frame`] and can't be in use anymore.
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    if let Some(frames) = FRAMES.lock().as_mut() {
+        frames.deallocate(frame);
+    }
+}
+
+/// Frames with more than one user, and how many users each has past the first
+static SHARED: SpinLock<BTreeMap<PhysFrame, u64>> = SpinLock::new("shared frames", BTreeMap::new());
+
+/// Count another user of `frame`
+pub fn share(frame: PhysFrame) {
+    *SHARED.lock().entry(frame).or_insert(0) += 1;
+}
+
+/// Whether `frame` has more than one user
+pub fn is_shared(frame: PhysFrame) -> bool {
+    SHARED.lock().contains_key(&frame)
+}
+
+/// Let go of `frame`, returning it to the allocator if nothing else is using it
+///
+/// # Safety
+///
+/// The same as for [`deallocate_frame`], for the caller's use of it.
+pub unsafe fn release(frame: PhysFrame) {
+    {
+        let mut shared = SHARED.lock();
+        if let Some(others) = shared.get_mut(&frame) {
+            *others -= 1;
+            if *others == 0 {
+                shared.remove(&frame);
+            }
+            return;
+        }
+    }
+    deallocate_frame(frame);
+}
+
+/// How much physical memory is used and free, None before the allocator is set up
+pub fn stats() -> Option<FrameStats> {
+    FRAMES.lock().as_ref().map(FrameAllocator::stats)
+}
+
+fn init() {
+    let Some(map) = bootinfo::memory_map() else {
+        wlog!("frame: no memory map, physical memory can't be allocated");
+        return;
+    };
+    // the bootloader marks everything it used, the rest is ours
+    *FRAMES.lock() = Some(unsafe { FrameAllocator::new(map) });
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "frame",
+    stage: Stage::Memory,
+    after: &[],
+    func: init,
+};
@@ -0,0 +1,295 @@
+//! Kernel heap
+//!
+//! [`config::heap_size`] bytes at [`HEAP_START`] are mapped at boot and handed out by the global
+//! allocator, so `alloc`'s `Box`, `Vec`, and `String` work anywhere after the memory stage.
+//!
+//! Small allocations are rounded up to one of the [`BLOCK_SIZES`] and come from a free list of
+//! blocks that size, which makes them quick and keeps the heap from fragmenting. Freed blocks
+//! go back on their list and are never merged. Anything bigger than the largest block, and new
+//! blocks when a list is empty, come from a linked list of free regions, first fit.
+//!
+//! Debug builds also [`track`](super::track) every allocation, to find leaks with.
+//!
+//! links:
+//! - <https://os.phil-opp.com/heap-allocation/>
+//! - <https://os.phil-opp.com/allocator-designs/#fixed-size-block-allocator>
+//!
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr::{self, NonNull};
+
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+#[cfg(debug_assertions)]
+use super::track::Tracker;
+use super::{frame, paging};
+use crate::debug::fault;
+use crate::init::{InitCall, Stage};
+use crate::sync::SpinLock;
+use crate::{config, wlog};
+
+/// Where the heap is mapped, see the layout in [`mem`](super)
+pub const HEAP_START: u64 = 0xffff_fd00_0000_0000;
+
+/// Sizes of the fixed-size blocks, each also used as its alignment
+pub const BLOCK_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Heap usage, in bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub size: u64,
+    /// Bytes handed out, rounded up to the block size for small allocations
+    pub used: u64,
+    /// Allocations that haven't been freed
+    pub allocations: u64,
+    /// Allocations since boot by block size, with the ones too big for a block last
+    pub by_size: [u64; BLOCK_SIZES.len() + 1],
+}
+
+/// The block size `layout` fits in, as an index into [`BLOCK_SIZES`]
+fn block_index(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&block| block >= size)
+}
+
+/// A free block, stored in the block itself
+struct Block {
+    next: Option<NonNull<Block>>,
+}
+
+/// A free region, stored at its start
+struct Region {
+    size: usize,
+    next: Option<NonNull<Region>>,
+}
+
+/// First fit allocator over a list of free regions, the fallback for the block lists
+struct RegionList {
+    head: Option<NonNull<Region>>,
+}
+
+impl RegionList {
+    /// Smallest region that can be tracked, anything left over that's smaller is lost
+    const MIN_SIZE: usize = mem::size_of::<Region>();
+
+    /// Add `size` bytes at `addr` to the free regions
+    ///
+    /// # Safety
+    ///
+    /// The memory has to be mapped and unused.
+    unsafe fn add(&mut self, addr: usize, size: usize) {
+        let start = addr.next_multiple_of(mem::align_of::<Region>());
+        let Some(size) = (addr + size).checked_sub(start) else {
+            return;
+        };
+        if size < Self::MIN_SIZE {
+            return;
+        }
+
+        let region = start as *mut Region;
+        region.write(Region {
+            size,
+            next: self.head,
+        });
+        self.head = NonNull::new(region);
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let mut link = &mut self.head;
+        while let Some(region) = *link {
+            let start = region.as_ptr() as usize;
+            let Region { size, next } = unsafe { region.read() };
+
+            let mut alloc_start = start.next_multiple_of(layout.align());
+            // padding in front goes back on the list too, so it has to fit a region
+            if alloc_start != start && alloc_start - start < Self::MIN_SIZE {
+                alloc_start = (start + Self::MIN_SIZE).next_multiple_of(layout.align());
+            }
+            let alloc_end = alloc_start + layout.size();
+            let end = start + size;
+            // and so does what's left after the allocation
+            let fits = alloc_end <= end && (alloc_end == end || end - alloc_end >= Self::MIN_SIZE);
+            if !fits {
+                link = unsafe { &mut (*region.as_ptr()).next };
+                continue;
+            }
+
+            *link = next;
+            unsafe {
+                self.add(start, alloc_start - start);
+                self.add(alloc_end, end - alloc_end);
+            }
+            return alloc_start as *mut u8;
+        }
+        ptr::null_mut()
+    }
+}
+
+/// Heap state, behind the lock in [`Heap`]
+struct Allocator {
+    blocks: [Option<NonNull<Block>>; BLOCK_SIZES.len()],
+    regions: RegionList,
+    stats: HeapStats,
+    #[cfg(debug_assertions)]
+    tracker: Tracker,
+}
+
+// the pointers are all into the heap, which only the allocator touches
+unsafe impl Send for Allocator {}
+
+impl Allocator {
+    /// Layouts for region allocations are padded so their ends can always hold a region
+    fn region_layout(layout: Layout) -> Layout {
+        let align = layout.align().max(mem::align_of::<Region>());
+        let size = layout
+            .size()
+            .max(RegionList::MIN_SIZE)
+            .next_multiple_of(align);
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (ptr, used, class) = match block_index(&layout) {
+            Some(index) => {
+                let size = BLOCK_SIZES[index];
+                let ptr = match self.blocks[index] {
+                    Some(block) => {
+                        self.blocks[index] = unsafe { block.read() }.next;
+                        block.as_ptr() as *mut u8
+                    }
+                    None => {
+                        // a block has room for a region too, they're all at least 8 bytes
+                        let layout = Layout::from_size_align(size, size).unwrap();
+                        self.regions.alloc(Self::region_layout(layout))
+                    }
+                };
+                (ptr, size, index)
+            }
+            None => {
+                let layout = Self::region_layout(layout);
+                (self.regions.alloc(layout), layout.size(), BLOCK_SIZES.len())
+            }
+        };
+
+        if !ptr.is_null() {
+            self.stats.used += used as u64;
+            self.stats.allocations += 1;
+            self.stats.by_size[class] += 1;
+            #[cfg(debug_assertions)]
+            self.tracker.insert(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let used = match block_index(&layout) {
+            Some(index) => {
+                let block = ptr as *mut Block;
+                block.write(Block {
+                    next: self.blocks[index],
+                });
+                self.blocks[index] = NonNull::new(block);
+                BLOCK_SIZES[index]
+            }
+            None => {
+                let layout = Self::region_layout(layout);
+                self.regions.add(ptr as usize, layout.size());
+                layout.size()
+            }
+        };
+        self.stats.used -= used as u64;
+        self.stats.allocations -= 1;
+        #[cfg(debug_assertions)]
+        self.tracker.remove(ptr);
+    }
+}
+
+/// The global allocator, empty until [`INIT`] maps the heap
+pub struct Heap {
+    allocator: SpinLock<Allocator>,
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if fault::ALLOC.fire() {
+            return ptr::null_mut();
+        }
+        self.allocator.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.allocator.lock().dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static HEAP: Heap = Heap {
+    allocator: SpinLock::new(
+        "heap",
+        Allocator {
+            blocks: [None; BLOCK_SIZES.len()],
+            regions: RegionList { head: None },
+            stats: HeapStats {
+                size: 0,
+                used: 0,
+                allocations: 0,
+                by_size: [0; BLOCK_SIZES.len() + 1],
+            },
+            #[cfg(debug_assertions)]
+            tracker: Tracker::new(),
+        },
+    ),
+};
+
+pub fn stats() -> HeapStats {
+    HEAP.allocator.lock().stats
+}
+
+/// Run `f` on the allocation table, with the heap locked, so `f` can't allocate
+#[cfg(debug_assertions)]
+pub(super) fn with_tracker<R>(f: impl FnOnce(&Tracker) -> R) -> R {
+    f(&HEAP.allocator.lock().tracker)
+}
+
+/// Map the heap's pages and give them to the allocator. If memory runs out partway, the heap is
+/// whatever got mapped.
+fn init() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let heap_size = config::heap_size();
+    let mut end = HEAP_START;
+    while end < HEAP_START + heap_size {
+        let Some(frame) = frame::allocate_frame() else {
+            break;
+        };
+        if let Err(error) =
+            unsafe { paging::map_to(VirtAddr::new(end), frame.start_address(), flags) }
+        {
+            wlog!("heap: couldn't map {:#x}: {:?}", end, error);
+            unsafe { frame::deallocate_frame(frame) };
+            break;
+        }
+        end += paging::PAGE_SIZE;
+    }
+
+    if end < HEAP_START + heap_size {
+        wlog!(
+            "heap: only {} KiB of {} KiB mapped",
+            (end - HEAP_START) / 1024,
+            heap_size / 1024
+        );
+    }
+    let mut allocator = HEAP.allocator.lock();
+    let size = (end - HEAP_START) as usize;
+    unsafe { allocator.regions.add(HEAP_START as usize, size) };
+    allocator.stats.size = size as u64;
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "heap",
+    stage: Stage::Memory,
+    after: &["paging", "config"],
+    func: init,
+};
@@ -0,0 +1,154 @@
+//! Memory management
+//!
+//! Virtual address space layout:
+//!
+//! | start                   | contents                                      |
+//! |-------------------------|-----------------------------------------------|
+//! | `0x0000_0000_0000_0000` | user space (lower half)                       |
+//! | `0xffff_8000_0000_0000` | all of physical memory, at [`PHYS_OFFSET`]    |
+//! | `0xffff_fc00_0000_0000` | kernel stacks, see [`stack`]                  |
+//! | `0xffff_fd00_0000_0000` | kernel heap, see [`heap`]                     |
+//! | `0xffff_fe00_0000_0000` | device memory, see [`paging::map_mmio`]       |
+//! | `0xffff_ff00_0000_0000` | boot info, followed by the boot stack         |
+//! | `0xffff_ffff_8000_0000` | kernel image, see `linker.ld`                 |
+//!
+//! The bootloader places the physical memory window, boot info, and stack where
+//! `[package.metadata.bootloader]` in Cargo.toml says to. It also identity maps itself and the
+//! VGA buffer in the lower half, which can't be configured, so [`paging`] clears the lower
+//! half when it takes over the page tables.
+//!
+
+use bootloader::bootinfo::MemoryRegionType;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{OffsetPageTable, PageTable, PageTableFlags, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{bootinfo, println};
+
+pub mod frame;
+pub mod heap;
+pub mod paging;
+pub mod stack;
+#[cfg(debug_assertions)]
+pub mod track;
+
+/// Where all of physical memory is mapped. Must match `physical-memory-offset` in Cargo.toml.
+pub const PHYS_OFFSET: u64 = 0xffff_8000_0000_0000;
+
+/// Get the address a physical address is reachable at through the physical memory window
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + PHYS_OFFSET)
+}
+
+/// Get the physical address `addr` is mapped to by the active page tables, if it's mapped.
+/// This walks the tables without [`paging`]'s lock, so the debugger can use it from anywhere.
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    let (level_4, _) = Cr3::read();
+    // only read through, so the mutable reference OffsetPageTable wants doesn't get used
+    let table = unsafe { &mut *phys_to_virt(level_4.start_address()).as_mut_ptr::<PageTable>() };
+    let mapper = unsafe { OffsetPageTable::new(table, VirtAddr::new(PHYS_OFFSET)) };
+    mapper.translate_addr(addr)
+}
+
+/// Call `f` with the level, index, and entry for `addr` in each of the active page tables, from
+/// the level 4 table down to the first entry that doesn't point at another table. Doesn't take
+/// [`paging`]'s lock either, so a fault handler can use it.
+pub fn walk(addr: VirtAddr, mut f: impl FnMut(u8, usize, &PageTableEntry)) {
+    let (level_4, _) = Cr3::read();
+    let mut table_addr = level_4.start_address();
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    for (level, index) in (1..=4).rev().zip(indexes) {
+        let table = unsafe { &*phys_to_virt(table_addr).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        f(level, usize::from(index), entry);
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        table_addr = entry.addr();
+    }
+}
+
+/// Physical memory by what it's used for, in bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryStats {
+    /// Free for the kernel to use
+    pub usable: u64,
+    /// Holding the kernel, its stack, page tables, and what the bootloader left behind
+    pub kernel: u64,
+    /// Owned by the firmware, ACPI, or broken
+    pub reserved: u64,
+}
+
+impl MemoryStats {
+    pub fn total(&self) -> u64 {
+        self.usable + self.kernel + self.reserved
+    }
+}
+
+/// Summarize the memory map, None if there isn't one yet
+pub fn stats() -> Option<MemoryStats> {
+    let map = bootinfo::memory_map()?;
+
+    let mut stats = MemoryStats::default();
+    for region in map.iter() {
+        let size = region.range.end_addr() - region.range.start_addr();
+        match region.region_type {
+            MemoryRegionType::Usable => stats.usable += size,
+            MemoryRegionType::InUse
+            | MemoryRegionType::Kernel
+            | MemoryRegionType::KernelStack
+            | MemoryRegionType::PageTable
+            | MemoryRegionType::Bootloader
+            | MemoryRegionType::FrameZero
+            | MemoryRegionType::BootInfo
+            | MemoryRegionType::Package => stats.kernel += size,
+            _ => stats.reserved += size,
+        }
+    }
+    Some(stats)
+}
+
+/// Print every region in the bootloader's memory map, with what it's used for
+pub fn dump_memory_map() {
+    let Some(map) = bootinfo::memory_map() else {
+        println!("no memory map");
+        return;
+    };
+
+    println!("{:>18} {:>18} {:>12}  type", "start", "end", "size");
+    for region in map.iter() {
+        let start = region.range.start_addr();
+        let end = region.range.end_addr();
+        println!(
+            "{:#018x} {:#018x} {:>10}Ki  {:?}",
+            start,
+            end,
+            (end - start) / 1024,
+            region.region_type
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The kernel's own statics are mapped all the way down
+    #[test_case]
+    fn walk_reaches_kernel_data() {
+        static MARKER: u8 = 0;
+        let addr = VirtAddr::from_ptr(&MARKER);
+        let mut last = None;
+        walk(addr, |level, _, entry| last = Some((level, entry.flags())));
+        let (level, flags) = last.unwrap();
+        assert!(flags.contains(PageTableFlags::PRESENT));
+        assert!(level == 1 || flags.contains(PageTableFlags::HUGE_PAGE));
+    }
+}
@@ -0,0 +1,720 @@
+//! Kernel page tables
+//!
+//! The level 4 table the bootloader left active is taken over at boot, and from then on every
+//! change to the address space goes through here. Page tables needed along the way come from
+//! the [`frame`](super::frame) allocator.
+//!
+//! The kernel image keeps the mappings the bootloader made for it, but with its code read-only
+//! and everything else no-execute, which [`cpu::protection`](crate::cpu::protection) makes
+//! stick.
+//!
+//! Every process has a level 4 table of its own, an [`AddressSpace`], with its mappings in the
+//! lower half. The upper half is the kernel's and is the same in all of them: its entries are
+//! copied from the kernel's table, and all 256 of them are filled in at boot, so whatever the
+//! kernel maps later shows up in every address space without touching each one.
+//!
+//! Not every page of an address space gets a frame up front. A range mapped with
+//! [`AddressSpace::map_lazy`] gets zeroed frames one page at a time as they're first touched,
+//! and [`AddressSpace::fork`] shares every frame with the copy, marking the writable ones
+//! [`COW`] and read-only in both. Either way the page fault that follows is resolved by
+//! [`AddressSpace::resolve_fault`]: a lazy page gets its frame, and a write to a copy-on-write
+//! page gets a copy of its own, or the frame itself once nothing else shares it.
+//!
+//! Device memory is mapped uncached into the MMIO window with [`map_mmio`] rather than used
+//! through the physical memory window, which the bootloader maps as ordinary cached memory.
+//!
+//! links:
+//! - <https://os.phil-opp.com/paging-implementation/>
+//!
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PageTableIndex,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{frame, phys_to_virt, PHYS_OFFSET};
+use crate::init::{InitCall, Stage};
+use crate::sync::SpinLock;
+use crate::{ilog, println, wlog};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// A page that's shared copy-on-write, writable once it's been copied. One of the bits the
+/// CPU leaves to the OS.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Where device memory gets mapped, see the layout in [`mem`](super)
+pub const MMIO_START: u64 = 0xffff_fe00_0000_0000;
+pub const MMIO_SIZE: u64 = 1 << 39;
+
+/// Why a page couldn't be mapped or unmapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// There's already something mapped there
+    AlreadyMapped,
+    /// Nothing is mapped there
+    NotMapped,
+    /// The address is covered by a huge page, which can't be changed 4 KiB at a time
+    HugePage,
+    /// There wasn't a free frame for a new page table
+    NoFrames,
+    /// The MMIO window is used up
+    NoSpace,
+}
+
+impl From<MapToError<Size4KiB>> for MapError {
+    fn from(error: MapToError<Size4KiB>) -> MapError {
+        match error {
+            MapToError::FrameAllocationFailed => MapError::NoFrames,
+            MapToError::ParentEntryHugePage => MapError::HugePage,
+            MapToError::PageAlreadyMapped(_) => MapError::AlreadyMapped,
+        }
+    }
+}
+
+impl From<FlagUpdateError> for MapError {
+    fn from(error: FlagUpdateError) -> MapError {
+        match error {
+            FlagUpdateError::ParentEntryHugePage => MapError::HugePage,
+            FlagUpdateError::PageNotMapped => MapError::NotMapped,
+        }
+    }
+}
+
+impl From<UnmapError> for MapError {
+    fn from(error: UnmapError) -> MapError {
+        match error {
+            UnmapError::ParentEntryHugePage => MapError::HugePage,
+            UnmapError::PageNotMapped | UnmapError::InvalidFrameAddress(_) => MapError::NotMapped,
+        }
+    }
+}
+
+/// Page tables get their frames from the kernel's frame allocator
+struct Frames;
+
+unsafe impl FrameAllocator<Size4KiB> for Frames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        frame::allocate_frame()
+    }
+}
+
+/// The active page tables, `None` until they're first used
+static PAGE_TABLES: SpinLock<Option<OffsetPageTable<'static>>> = SpinLock::new("page tables", None);
+
+/// The kernel's level 4 table, the one the bootloader left active
+static KERNEL_LEVEL_4: AtomicU64 = AtomicU64::new(0);
+
+/// Next free address in the MMIO window
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// The tables under the level 4 table in `level_4`
+///
+/// # Safety
+///
+/// `level_4` has to be a level 4 table, and nothing else may change the tables while the
+/// result is used.
+unsafe fn tables_at(level_4: PhysFrame) -> OffsetPageTable<'static> {
+    OffsetPageTable::new(table_at(level_4), VirtAddr::new(PHYS_OFFSET))
+}
+
+fn with_tables<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    let mut tables = PAGE_TABLES.lock();
+    let tables = tables.get_or_insert_with(|| {
+        let (level_4, _) = Cr3::read();
+        KERNEL_LEVEL_4.store(level_4.start_address().as_u64(), Ordering::Relaxed);
+        // nothing else touches the tables once they're behind the lock
+        unsafe { tables_at(level_4) }
+    });
+    f(tables)
+}
+
+/// The kernel's level 4 table, which kernel threads run on
+pub fn kernel_level_4() -> PhysFrame {
+    with_tables(|_| ());
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4.load(Ordering::Relaxed)))
+}
+
+/// Load `level_4` into CR3, unless it's there already
+///
+/// # Safety
+///
+/// `level_4` has to be the kernel's table or an [`AddressSpace`]'s that stays alive while
+/// it's loaded.
+pub unsafe fn switch_to(level_4: PhysFrame) {
+    let (active, flags) = Cr3::read();
+    if active != level_4 {
+        Cr3::write(level_4, flags);
+    }
+}
+
+/// Map the page at `virt` to the frame at `phys`
+///
+/// # Safety
+///
+/// Whatever's at `phys` becomes reachable at `virt`, so nothing else may be using it in a way
+/// that conflicts, like another mutable mapping of ordinary memory.
+pub unsafe fn map_to(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<(), MapError> {
+    let page = Page::<Size4KiB>::containing_address(virt);
+    let frame = PhysFrame::containing_address(phys);
+    with_tables(|tables| {
+        tables
+            .map_to_with_table_flags(page, frame, flags, parent_flags(flags), &mut Frames)
+            .map(|flush| flush.flush())
+            .map_err(MapError::from)
+    })
+}
+
+/// Unmap the page at `virt`, returning the frame it was mapped to. The frame isn't freed.
+///
+/// # Safety
+///
+/// Nothing may use the page anymore.
+pub unsafe fn unmap(virt: VirtAddr) -> Result<PhysFrame, MapError> {
+    let page = Page::<Size4KiB>::containing_address(virt);
+    with_tables(|tables| {
+        let (frame, flush) = tables.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+fn translate_in(tables: &OffsetPageTable, virt: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    match tables.translate(virt) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } => Some((frame.start_address() + offset, flags)),
+        TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+    }
+}
+
+/// The physical address `virt` is mapped to in the active tables, and the flags of its page.
+/// In the lower half that's the running process's mapping.
+pub fn translate(virt: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    let (level_4, _) = Cr3::read();
+    // the lock keeps the kernel's half from changing underneath, the process's half only
+    // changes from its own thread
+    with_tables(|_| translate_in(&unsafe { tables_at(level_4) }, virt))
+}
+
+/// The flags the tables above a page get, which have to allow whatever the page allows
+fn parent_flags(flags: PageTableFlags) -> PageTableFlags {
+    let mut parent = flags
+        & (PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE)
+        | PageTableFlags::PRESENT;
+    // it'll be made writable when it's copied
+    if flags.contains(COW) {
+        parent |= PageTableFlags::WRITABLE;
+    }
+    parent
+}
+
+/// Pages that get a zeroed frame when they're first touched
+#[derive(Debug, Clone, Copy)]
+struct Lazy {
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+}
+
+/// A level 4 table for a process: its own lower half, and the kernel's upper half
+///
+/// Everything mapped in the lower half belongs to it, the frames and the tables they're in are
+/// freed when it's dropped. Frames it shares are only freed once nothing else has them.
+pub struct AddressSpace {
+    level_4: PhysFrame,
+    lazy: Vec<Lazy>,
+}
+
+impl AddressSpace {
+    /// An empty lower half with the kernel mapped above it
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let level_4 = frame::allocate_frame().ok_or(MapError::NoFrames)?;
+        let table = unsafe { table_at(level_4) };
+        with_tables(|kernel| *table = kernel.level_4_table().clone());
+        for entry in table.iter_mut().take(256) {
+            entry.set_unused();
+        }
+        Ok(AddressSpace {
+            level_4,
+            lazy: Vec::new(),
+        })
+    }
+
+    /// The level 4 table, for [`switch_to`]
+    pub fn level_4(&self) -> PhysFrame {
+        self.level_4
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4
+    }
+
+    /// Map the page at `virt` to the frame at `phys`. `virt` has to be in the lower half.
+    ///
+    /// # Safety
+    ///
+    /// The same as for [`map_to`], and the frame is freed with the address space.
+    pub unsafe fn map_to(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        assert!(
+            virt.p4_index() < PageTableIndex::new(256),
+            "paging: {:?} is the kernel's",
+            virt
+        );
+        let page = Page::<Size4KiB>::containing_address(virt);
+        let frame = PhysFrame::containing_address(phys);
+        let active = self.is_active();
+        let flush = tables_at(self.level_4).map_to_with_table_flags(
+            page,
+            frame,
+            flags,
+            parent_flags(flags),
+            &mut Frames,
+        )?;
+        // the TLB doesn't hold anything of a space that isn't loaded
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(())
+    }
+
+    /// Unmap the page at `virt`, returning the frame it was mapped to, which isn't freed
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use the page anymore.
+    pub unsafe fn unmap(&mut self, virt: VirtAddr) -> Result<PhysFrame, MapError> {
+        let page = Page::<Size4KiB>::containing_address(virt);
+        let active = self.is_active();
+        let (frame, flush) = tables_at(self.level_4).unmap(page)?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(frame)
+    }
+
+    /// The physical address `virt` is mapped to here, and the flags of its page
+    pub fn translate(&self, virt: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        translate_in(&unsafe { tables_at(self.level_4) }, virt)
+    }
+
+    /// Have the pages from `start` up to `end` mapped with `flags` once they're touched, each
+    /// to a zeroed frame of its own
+    pub fn map_lazy(&mut self, start: VirtAddr, end: VirtAddr, flags: PageTableFlags) {
+        let (start, end) = (start.align_down(PAGE_SIZE), end.align_up(PAGE_SIZE));
+        if let Some(last) = self.lazy.last_mut() {
+            if last.end == start && last.flags == flags {
+                last.end = end;
+                return;
+            }
+        }
+        self.lazy.push(Lazy { start, end, flags });
+    }
+
+    fn flush(&self, page: VirtAddr) {
+        if self.is_active() {
+            tlb::flush(page);
+        }
+    }
+
+    /// Try to resolve a page fault at `virt`, a write if `write` is set, by mapping a lazy page
+    /// or copying a copy-on-write one. False if the fault was a real one, or there wasn't a
+    /// frame to resolve it with.
+    pub fn resolve_fault(&mut self, virt: VirtAddr, write: bool) -> bool {
+        let page = virt.align_down(PAGE_SIZE);
+        if page.p4_index() >= PageTableIndex::new(256) {
+            return false;
+        }
+        match self.translate(page) {
+            Some((phys, flags)) if write && flags.contains(COW) => {
+                let old = PhysFrame::containing_address(phys);
+                let flags = (flags - COW) | PageTableFlags::WRITABLE;
+                unsafe { self.copy_on_write(page, old, flags) }
+            }
+            Some(_) => false,
+            None => {
+                let Some(lazy) = self
+                    .lazy
+                    .iter()
+                    .find(|lazy| (lazy.start..lazy.end).contains(&page))
+                    .copied()
+                else {
+                    return false;
+                };
+                let Some(frame) = frame::allocate_frame() else {
+                    return false;
+                };
+                unsafe {
+                    phys_to_virt(frame.start_address())
+                        .as_mut_ptr::<u8>()
+                        .write_bytes(0, PAGE_SIZE as usize);
+                    if self
+                        .map_to(page, frame.start_address(), lazy.flags)
+                        .is_err()
+                    {
+                        frame::deallocate_frame(frame);
+                        return false;
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    /// Give the copy-on-write `page`, mapped to `old`, a frame of its own with `flags`
+    unsafe fn copy_on_write(
+        &mut self,
+        page: VirtAddr,
+        old: PhysFrame,
+        flags: PageTableFlags,
+    ) -> bool {
+        let Ok(entry) = leaf_entry(self.level_4, page) else {
+            return false;
+        };
+        if frame::is_shared(old) {
+            let Some(new) = frame::allocate_frame() else {
+                return false;
+            };
+            phys_to_virt(new.start_address())
+                .as_mut_ptr::<u8>()
+                .copy_from_nonoverlapping(
+                    phys_to_virt(old.start_address()).as_ptr(),
+                    PAGE_SIZE as usize,
+                );
+            entry.set_frame(new, flags);
+            frame::release(old);
+        } else {
+            // the last one left with it, so it can just have it
+            entry.set_flags(flags);
+        }
+        self.flush(page);
+        true
+    }
+
+    /// A copy of this address space that shares every frame with it. Writable pages become
+    /// [`COW`] in both, so whichever writes to one first gets a copy of its own.
+    pub fn fork(&mut self) -> Result<AddressSpace, MapError> {
+        let mut child = AddressSpace::new()?;
+        child.lazy = self.lazy.clone();
+        let result = unsafe {
+            let parent = table_at(self.level_4);
+            let copy = table_at(child.level_4);
+            parent
+                .iter_mut()
+                .zip(copy.iter_mut())
+                .take(256)
+                .try_for_each(|(entry, copy)| fork_entry(entry, copy, 4))
+        };
+        // the parent's writable pages are read-only now
+        if self.is_active() {
+            tlb::flush_all();
+        }
+        result.map(|()| child)
+    }
+}
+
+/// The level 1 entry for `page` under the level 4 table in `level_4`
+unsafe fn leaf_entry(
+    level_4: PhysFrame,
+    page: VirtAddr,
+) -> Result<&'static mut PageTableEntry, MapError> {
+    let mut table = table_at(level_4);
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = &table[index];
+        if entry.is_unused() {
+            return Err(MapError::NotMapped);
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Err(MapError::HugePage);
+        }
+        table = table_at(PhysFrame::containing_address(entry.addr()));
+    }
+    Ok(&mut table[page.p1_index()])
+}
+
+/// Fill in `copy`, an entry of a fresh table at `level`, to share what `entry` maps. Tables
+/// are copied, frames are shared.
+///
+/// # Safety
+///
+/// `entry` has to be in the lower half of an address space, and `copy` in the same place in
+/// another.
+unsafe fn fork_entry(
+    entry: &mut PageTableEntry,
+    copy: &mut PageTableEntry,
+    level: u8,
+) -> Result<(), MapError> {
+    if entry.is_unused() {
+        return Ok(());
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+    let mut flags = entry.flags();
+    if level == 1 {
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags = (flags - PageTableFlags::WRITABLE) | COW;
+            entry.set_flags(flags);
+        }
+        frame::share(frame);
+        copy.set_frame(frame, flags);
+        return Ok(());
+    }
+    if flags.contains(PageTableFlags::HUGE_PAGE) {
+        // nothing maps them in the lower half
+        return Ok(());
+    }
+    let table = frame::allocate_frame().ok_or(MapError::NoFrames)?;
+    table_at(table).zero();
+    copy.set_frame(table, flags);
+    let (entries, copies) = (table_at(frame), table_at(table));
+    entries
+        .iter_mut()
+        .zip(copies.iter_mut())
+        .try_for_each(|(entry, copy)| fork_entry(entry, copy, level - 1))
+}
+
+/// The page table in `frame`, through the physical memory window
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Free what `entry` of a table at `level` points to: the frame of a page, or a table and
+/// everything under it
+///
+/// # Safety
+///
+/// Nothing may use the mappings anymore.
+unsafe fn free_entry(entry: &mut PageTableEntry, level: u8) {
+    if entry.is_unused() {
+        return;
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+    if level == 1 {
+        frame::release(frame);
+    } else if !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        // nothing maps huge pages in the lower half, one that was would be left alone
+        for entry in table_at(frame).iter_mut() {
+            free_entry(entry, level - 1);
+        }
+        frame::deallocate_frame(frame);
+    }
+    entry.set_unused();
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(
+            !self.is_active(),
+            "paging: address space dropped while loaded"
+        );
+        unsafe {
+            for entry in table_at(self.level_4).iter_mut().take(256) {
+                free_entry(entry, 4);
+            }
+            frame::deallocate_frame(self.level_4);
+        }
+    }
+}
+
+/// Print the entry for `virt` at each level of the active page tables, and where it ends up.
+/// Goes through [`walk`](super::walk), so it doesn't take the lock and works from a fault
+/// handler.
+pub fn dump_mapping(virt: VirtAddr) {
+    super::walk(virt, |level, index, entry| {
+        println!(
+            "  L{}[{:>3}] {:#014x} {:?}",
+            level,
+            index,
+            entry.addr().as_u64(),
+            entry.flags()
+        );
+    });
+    match super::virt_to_phys(virt) {
+        Some(phys) => println!("  {:#x} -> {:#x}", virt.as_u64(), phys.as_u64()),
+        None => println!("  {:#x} isn't mapped", virt.as_u64()),
+    }
+}
+
+/// Map `size` bytes of device memory at `phys` into the MMIO window, uncached
+///
+/// # Safety
+///
+/// `phys` has to be device memory, mapping ordinary memory uncached next to its cached
+/// mapping in the physical memory window makes the two disagree.
+pub unsafe fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapError> {
+    let start = phys.align_down(PAGE_SIZE);
+    let len = (phys + size).align_up(PAGE_SIZE) - start;
+
+    let base = MMIO_NEXT.fetch_add(len, Ordering::Relaxed);
+    if base + len > MMIO_START + MMIO_SIZE {
+        return Err(MapError::NoSpace);
+    }
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    for offset in (0..len).step_by(PAGE_SIZE as usize) {
+        map_to(VirtAddr::new(base + offset), start + offset, flags)?;
+    }
+    Ok(VirtAddr::new(base + (phys - start)))
+}
+
+extern "C" {
+    static __text_start: u8;
+    static __rodata_start: u8;
+    static __data_start: u8;
+    static __kernel_end: u8;
+}
+
+/// Give the pages from `start` up to `end` exactly `flags`
+fn protect(
+    tables: &mut OffsetPageTable,
+    start: *const u8,
+    end: *const u8,
+    flags: PageTableFlags,
+) -> Result<(), MapError> {
+    let mut page = VirtAddr::from_ptr(start).align_down(PAGE_SIZE);
+    let end = VirtAddr::from_ptr(end).align_up(PAGE_SIZE);
+    while page < end {
+        let flush =
+            unsafe { tables.update_flags(Page::<Size4KiB>::containing_address(page), flags)? };
+        // the whole TLB is flushed when this is done
+        flush.ignore();
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Take over the page tables, drop the bootloader's mappings in the lower half, and make the
+/// kernel's code read-only and everything else in the image no-execute
+fn init() {
+    let result = with_tables(|tables| {
+        let level_4 = tables.level_4_table_mut();
+        for entry in level_4.iter_mut().take(256) {
+            entry.set_unused();
+        }
+        // every address space copies these, so they can't change from here on
+        for entry in level_4
+            .iter_mut()
+            .skip(256)
+            .filter(|entry| entry.is_unused())
+        {
+            let level_3 = frame::allocate_frame().ok_or(MapError::NoFrames)?;
+            unsafe { table_at(level_3).zero() };
+            entry.set_frame(level_3, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+
+        let text = PageTableFlags::PRESENT;
+        let rodata = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        let data = rodata | PageTableFlags::WRITABLE;
+        protect(
+            tables,
+            &raw const __text_start,
+            &raw const __rodata_start,
+            text,
+        )?;
+        protect(
+            tables,
+            &raw const __rodata_start,
+            &raw const __data_start,
+            rodata,
+        )?;
+        protect(
+            tables,
+            &raw const __data_start,
+            &raw const __kernel_end,
+            data,
+        )
+    });
+    tlb::flush_all();
+
+    match result {
+        Ok(()) => ilog!("paging: kernel text read-only, data no-execute"),
+        Err(error) => wlog!("paging: couldn't protect the kernel image: {:?}", error),
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "paging",
+    stage: Stage::Memory,
+    after: &["frame"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn byte_at(space: &AddressSpace, virt: VirtAddr) -> (PhysAddr, u8) {
+        let (phys, _) = space.translate(virt).unwrap();
+        (phys, unsafe { *phys_to_virt(phys).as_ptr::<u8>() })
+    }
+
+    #[test_case]
+    fn forks_copy_on_write() {
+        let page = VirtAddr::new(0x40_0000);
+        let flags =
+            PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+        let mut parent = AddressSpace::new().unwrap();
+        let frame = frame::allocate_frame().unwrap();
+        unsafe {
+            *phys_to_virt(frame.start_address()).as_mut_ptr::<u8>() = 42;
+            parent.map_to(page, frame.start_address(), flags).unwrap();
+        }
+
+        let mut child = parent.fork().unwrap();
+        let (phys, _) = child.translate(page).unwrap();
+        assert_eq!(phys, frame.start_address());
+        let (_, shared) = parent.translate(page).unwrap();
+        assert!(shared.contains(COW) && !shared.contains(PageTableFlags::WRITABLE));
+
+        // the child writes first and gets a copy, then the parent has the frame to itself
+        assert!(child.resolve_fault(page, true));
+        assert_eq!(byte_at(&child, page).1, 42);
+        assert_ne!(byte_at(&child, page).0, frame.start_address());
+        assert!(parent.resolve_fault(page, true));
+        assert_eq!(byte_at(&parent, page).0, frame.start_address());
+        let (_, flags) = parent.translate(page).unwrap();
+        assert!(flags.contains(PageTableFlags::WRITABLE) && !flags.contains(COW));
+        // a read-only fault isn't one to resolve
+        assert!(!parent.resolve_fault(page, false));
+    }
+
+    #[test_case]
+    fn lazy_pages_are_zeroed_on_first_touch() {
+        let start = VirtAddr::new(0x80_0000);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut space = AddressSpace::new().unwrap();
+        space.map_lazy(start, start + 2 * PAGE_SIZE, flags);
+        assert!(space.translate(start).is_none());
+        assert!(space.resolve_fault(start + PAGE_SIZE + 8u64, false));
+        assert_eq!(byte_at(&space, start + PAGE_SIZE).1, 0);
+        assert!(space.translate(start).is_none());
+        assert!(!space.resolve_fault(start + 2 * PAGE_SIZE, false));
+    }
+}
@@ -0,0 +1,204 @@
+//! Kernel stacks
+//!
+//! Every stack gets a slot of its own in the stack window, see the layout in [`mem`](super).
+//! The stack is mapped at the top of its slot and the rest of the slot is left unmapped, so a
+//! stack that overflows faults on the guard pages under it right away instead of quietly
+//! writing over whatever comes next. [`guard_of`] tells the fault handlers whose stack it was.
+//!
+//! New stacks are filled with a pattern, so [`Stack::usage`] can tell how deep one has ever
+//! gone from how much of the pattern has been written over.
+//!
+//! The boot stack isn't one of these, but the bootloader leaves the page under it unmapped
+//! too.
+//!
+
+use core::mem;
+
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use super::frame;
+use super::paging::{self, MapError, PAGE_SIZE};
+use crate::sync::SpinLock;
+
+/// Where the stack window starts
+pub const STACKS_START: u64 = 0xffff_fc00_0000_0000;
+/// Address space each stack gets, including its guard pages
+pub const SLOT_SIZE: u64 = 1024 * 1024;
+/// Stacks that can be allocated at once
+pub const MAX_STACKS: usize = 256;
+
+/// What a new stack is filled with
+const PAINT: u64 = 0x57ac_57ac_57ac_57ac;
+
+/// The page under the boot stack, at `kernel-stack-address` in Cargo.toml
+const BOOT_GUARD: u64 = 0xffff_ff00_0001_0000;
+
+/// Why a stack couldn't be allocated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    /// It wouldn't leave room for a guard page in its slot
+    TooBig,
+    /// Every slot is taken
+    NoSlots,
+    /// One of its pages couldn't be mapped
+    Map(MapError),
+}
+
+impl From<MapError> for StackError {
+    fn from(error: MapError) -> StackError {
+        StackError::Map(error)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    name: &'static str,
+    size: u64,
+}
+
+static SLOTS: SpinLock<[Option<Slot>; MAX_STACKS]> = SpinLock::new("stacks", [None; MAX_STACKS]);
+
+/// How much of a stack has been used
+#[derive(Debug, Clone, Copy)]
+pub struct StackUsage {
+    /// Bytes from the top to the deepest it's been
+    pub peak: u64,
+    pub size: u64,
+}
+
+/// A kernel stack, unmapped and freed when it's dropped
+pub struct Stack {
+    slot: usize,
+    size: u64,
+}
+
+impl Stack {
+    /// Where the stack starts, it grows down from here
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new(STACKS_START + (self.slot as u64 + 1) * SLOT_SIZE)
+    }
+
+    /// The lowest address on the stack, the guard pages are right under it
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.size
+    }
+
+    /// How deep the stack has been, from the pattern it was filled with. A word that happens
+    /// to be pushed with the same value makes it look a little shallower than it's been.
+    pub fn usage(&self) -> StackUsage {
+        let words = self.bottom().as_ptr::<u64>();
+        let len = (self.size / 8) as usize;
+        let untouched = (0..len)
+            .take_while(|&i| unsafe { words.add(i).read_volatile() } == PAINT)
+            .count();
+        StackUsage {
+            peak: self.size - untouched as u64 * 8,
+            size: self.size,
+        }
+    }
+
+    /// Keep the stack forever, for the CPUs, which never stop. Returns its top.
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        mem::forget(self);
+        top
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        let mut page = self.bottom();
+        while page < self.top() {
+            // nothing runs on the stack anymore
+            if let Ok(frame) = unsafe { paging::unmap(page) } {
+                unsafe { frame::deallocate_frame(frame) };
+            }
+            page += PAGE_SIZE;
+        }
+        SLOTS.lock()[self.slot] = None;
+    }
+}
+
+/// Allocate a stack of at least `size` bytes, called `name` when it overflows
+pub fn allocate(name: &'static str, size: usize) -> Result<Stack, StackError> {
+    let size = (size as u64).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    if size > SLOT_SIZE - PAGE_SIZE {
+        return Err(StackError::TooBig);
+    }
+
+    let slot = {
+        let mut slots = SLOTS.lock();
+        let slot = slots
+            .iter()
+            .position(Option::is_none)
+            .ok_or(StackError::NoSlots)?;
+        slots[slot] = Some(Slot { name, size });
+        slot
+    };
+
+    // if mapping fails partway, dropping it unmaps what got mapped
+    let stack = Stack { slot, size };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut page = stack.bottom();
+    while page < stack.top() {
+        let frame: PhysFrame = frame::allocate_frame().ok_or(MapError::NoFrames)?;
+        if let Err(error) = unsafe { paging::map_to(page, frame.start_address(), flags) } {
+            unsafe { frame::deallocate_frame(frame) };
+            return Err(error.into());
+        }
+        let words = page.as_mut_ptr::<u64>();
+        for i in 0..(PAGE_SIZE / 8) as usize {
+            unsafe { words.add(i).write_volatile(PAINT) };
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(stack)
+}
+
+/// The name of the stack `addr` is a guard page of, if it's one. Safe to call from a fault
+/// handler, it doesn't wait for any locks.
+pub fn guard_of(addr: VirtAddr) -> Option<&'static str> {
+    let addr = addr.as_u64();
+    if (BOOT_GUARD..BOOT_GUARD + PAGE_SIZE).contains(&addr) {
+        return Some("boot");
+    }
+    if !(STACKS_START..STACKS_START + MAX_STACKS as u64 * SLOT_SIZE).contains(&addr) {
+        return None;
+    }
+
+    let slot = ((addr - STACKS_START) / SLOT_SIZE) as usize;
+    let offset = (addr - STACKS_START) % SLOT_SIZE;
+    match SLOTS.try_lock() {
+        Some(slots) => slots[slot]
+            .filter(|stack| offset < SLOT_SIZE - stack.size)
+            .map(|stack| stack.name),
+        // there's nothing else in the window, so it's some stack's guard
+        None => Some("unknown"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn guard_is_under_stack() {
+        let stack = allocate("test", 8192).unwrap();
+        let bottom = stack.bottom();
+        assert_eq!(guard_of(bottom - 1u64), Some("test"));
+        assert_eq!(guard_of(bottom), None);
+        unsafe { (stack.top() - 8u64).as_mut_ptr::<u64>().write(1) };
+        drop(stack);
+        assert_eq!(guard_of(bottom - 1u64), None);
+    }
+
+    #[test_case]
+    fn usage_counts_from_top() {
+        let stack = allocate("test", 8192).unwrap();
+        assert_eq!(stack.usage().peak, 0);
+        unsafe { (stack.top() - 100u64).as_mut_ptr::<u64>().write(1) };
+        assert_eq!(stack.usage().peak, 104);
+        assert_eq!(stack.usage().size, 8192);
+    }
+}
@@ -0,0 +1,209 @@
+//! Heap allocation tracking, in debug builds
+//!
+//! The [heap](super::heap) records every allocation that hasn't been freed in a table here,
+//! with its size and the first [`DEPTH`] return addresses on the stack when it was made. The
+//! table is a fixed size, since it can't allocate from the heap it's tracking, and allocations
+//! made while it's full are only counted. [`by_site`] groups what's outstanding by the first of
+//! those addresses that's outside the allocator and `alloc`'s own code, which is in the
+//! function that allocated. Something leaking shows up there as a site whose count keeps going up.
+//!
+
+use alloc::vec::Vec;
+
+use crate::debug::{backtrace, symbols};
+
+/// Allocations tracked at once
+pub const MAX_TRACKED: usize = 1024;
+/// Return addresses kept for each allocation
+pub const DEPTH: usize = 5;
+
+/// Functions an allocation's call site can't be in, they're on the way to the allocator
+const ALLOCATOR_PATHS: &[&str] = &[
+    "alloc::",
+    "<alloc::",
+    "core::",
+    "<core::",
+    "__rust",
+    "zenix::mem::heap::",
+    "<zenix::mem::heap::",
+];
+
+#[derive(Clone, Copy)]
+struct Entry {
+    ptr: usize,
+    size: usize,
+    stack: [u64; DEPTH],
+}
+
+/// Open addressing on the pointer, so lookups don't scan the table
+pub(super) struct Tracker {
+    entries: [Option<Entry>; MAX_TRACKED],
+    /// Allocations that didn't fit and aren't tracked
+    untracked: u64,
+}
+
+impl Tracker {
+    pub const fn new() -> Tracker {
+        Tracker {
+            entries: [None; MAX_TRACKED],
+            untracked: 0,
+        }
+    }
+
+    fn home(ptr: usize) -> usize {
+        // blocks are at least 8-byte aligned, so the low bits say nothing
+        (ptr >> 3) % MAX_TRACKED
+    }
+
+    /// Note an allocation of `size` bytes at `ptr`, made from the current stack
+    pub fn insert(&mut self, ptr: *mut u8, size: usize) {
+        let mut stack = [0; DEPTH];
+        let mut depth = 0;
+        backtrace::walk(|addr| {
+            if depth < DEPTH {
+                stack[depth] = addr;
+                depth += 1;
+            }
+        });
+
+        let entry = Entry {
+            ptr: ptr as usize,
+            size,
+            stack,
+        };
+        let home = Self::home(entry.ptr);
+        for i in 0..MAX_TRACKED {
+            let slot = &mut self.entries[(home + i) % MAX_TRACKED];
+            if slot.is_none() {
+                *slot = Some(entry);
+                return;
+            }
+        }
+        self.untracked += 1;
+    }
+
+    /// Forget the allocation at `ptr`
+    pub fn remove(&mut self, ptr: *mut u8) {
+        let ptr = ptr as usize;
+        let home = Self::home(ptr);
+        let Some(mut hole) = (0..MAX_TRACKED)
+            .map(|i| (home + i) % MAX_TRACKED)
+            .take_while(|&i| self.entries[i].is_some())
+            .find(|&i| self.entries[i].is_some_and(|entry| entry.ptr == ptr))
+        else {
+            // one of the untracked ones
+            self.untracked = self.untracked.saturating_sub(1);
+            return;
+        };
+
+        // move later entries of the same run back over the hole, so none of them end up past
+        // an empty slot from their home
+        let mut next = hole;
+        loop {
+            next = (next + 1) % MAX_TRACKED;
+            let Some(entry) = self.entries[next] else {
+                break;
+            };
+            let home = Self::home(entry.ptr);
+            let distance = |from: usize, to: usize| (to + MAX_TRACKED - from) % MAX_TRACKED;
+            if distance(home, hole) < distance(home, next) {
+                self.entries[hole] = Some(entry);
+                hole = next;
+            }
+        }
+        self.entries[hole] = None;
+    }
+}
+
+/// Outstanding allocations from one place
+#[derive(Debug, Clone, Copy)]
+pub struct Site {
+    /// The return address into the function that allocated, 0 if none was found
+    pub addr: u64,
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// The return address an allocation is charged to: the first outside the allocator, or the
+/// outermost kept if there are no symbols to tell
+fn site_of(stack: &[u64; DEPTH]) -> u64 {
+    let outermost = stack.iter().rev().copied().find(|&addr| addr != 0);
+    stack
+        .iter()
+        .copied()
+        .filter(|&addr| addr != 0)
+        .find(|&addr| {
+            symbols::lookup(addr).is_some_and(|symbol| {
+                !ALLOCATOR_PATHS
+                    .iter()
+                    .any(|path| symbol.name.starts_with(path))
+            })
+        })
+        .or(outermost)
+        .unwrap_or(0)
+}
+
+/// Outstanding allocations grouped by where they were made, most bytes first, and how many
+/// more there are that aren't tracked. The table is copied a piece at a time, with the heap
+/// unlocked in between to grow the list, so allocations made meanwhile may or may not be in
+/// it, the list's own included.
+pub fn by_site() -> (Vec<Site>, u64) {
+    /// Entries copied out at once, kept small since it's on the stack
+    const CHUNK: usize = 32;
+    const _: () = assert!(MAX_TRACKED.is_multiple_of(CHUNK));
+
+    let mut sites: Vec<Site> = Vec::new();
+    let mut chunk = [None; CHUNK];
+    let mut untracked = 0;
+    for start in (0..MAX_TRACKED).step_by(CHUNK) {
+        super::heap::with_tracker(|tracker| {
+            chunk.copy_from_slice(&tracker.entries[start..start + CHUNK]);
+            untracked = tracker.untracked;
+        });
+        for entry in chunk.iter().flatten() {
+            let addr = site_of(&entry.stack);
+            match sites.iter_mut().find(|site| site.addr == addr) {
+                Some(site) => {
+                    site.count += 1;
+                    site.bytes += entry.size as u64;
+                }
+                None => sites.push(Site {
+                    addr,
+                    count: 1,
+                    bytes: entry.size as u64,
+                }),
+            }
+        }
+    }
+    sites.sort_by_key(|site| core::cmp::Reverse(site.bytes));
+    (sites, untracked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::SpinLock;
+
+    #[test_case]
+    fn removes_keep_runs_findable() {
+        // too big for the stack
+        static TRACKER: SpinLock<Tracker> = SpinLock::new("test tracker", Tracker::new());
+        let mut tracker = TRACKER.lock();
+        // all with the same home, so they collide
+        let ptrs = [8, 8 + 8 * MAX_TRACKED, 8 + 16 * MAX_TRACKED].map(|ptr| ptr as *mut u8);
+        for ptr in ptrs {
+            tracker.insert(ptr, 16);
+        }
+        tracker.remove(ptrs[0]);
+        tracker.remove(ptrs[2]);
+        assert_eq!(tracker.entries.iter().flatten().count(), 1);
+        assert!(tracker
+            .entries
+            .iter()
+            .flatten()
+            .any(|entry| entry.ptr == ptrs[1] as usize));
+        tracker.remove(ptrs[1]);
+        assert_eq!(tracker.entries.iter().flatten().count(), 0);
+        assert_eq!(tracker.untracked, 0);
+    }
+}
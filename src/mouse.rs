@@ -0,0 +1,283 @@
+//! PS/2 mouse
+//!
+//! The mouse sits on the keyboard controller's second port and interrupts on IRQ 12. Once it's
+//! told to start reporting, it sends a 3-byte packet whenever it moves or a button changes,
+//! which the interrupt handler decodes into a [`MouseEvent`] and queues for [`try_read`] or
+//! [`read`]. Like the keyboard's, the queue has the interrupt handler as its only producer and
+//! is meant for one consumer at a time.
+//!
+//! Nothing draws a pointer on its own. The shell's `mouse` command moves one around the text
+//! screen with [`vga::set_pointer`](crate::vga::set_pointer), to see that it works.
+//!
+//! links:
+//! - <https://wiki.osdev.org/PS/2_Mouse>
+//! - <https://wiki.osdev.org/%228042%22_PS/2_Controller>
+//!
+
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::structures::port::{PortRead as _, PortWrite as _};
+
+use crate::init::{InitCall, Stage};
+use crate::sync::WaitQueue;
+use crate::{pic, wlog};
+
+const MOUSE_IRQ: u8 = 12;
+
+const DATA: u16 = 0x60;
+/// Status when read, commands when written
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+/// Status bit saying there's a byte to read from `DATA`
+const OUTPUT_FULL: u8 = 1 << 0;
+/// Status bit saying the controller hasn't taken the last byte written yet
+const INPUT_FULL: u8 = 1 << 1;
+
+// controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const ENABLE_AUX: u8 = 0xa8;
+/// The next byte written to `DATA` goes to the mouse instead of the keyboard
+const WRITE_AUX: u8 = 0xd4;
+
+// configuration byte
+const AUX_INTERRUPT: u8 = 1 << 1;
+const AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+// mouse commands
+const SET_DEFAULTS: u8 = 0xf6;
+const ENABLE_REPORTING: u8 = 0xf4;
+const ACK: u8 = 0xfa;
+
+// first byte of a packet
+const LEFT: u8 = 1 << 0;
+const RIGHT: u8 = 1 << 1;
+const MIDDLE: u8 = 1 << 2;
+/// Always set in the first byte, which is how packets are kept in step
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+/// How long to wait on the controller before giving up, in status reads
+const TIMEOUT: usize = 100_000;
+
+/// One packet from the mouse
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseEvent {
+    /// Movement right since the last one
+    pub dx: i16,
+    /// Movement down since the last one, the way screen rows go
+    pub dy: i16,
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+impl MouseEvent {
+    /// Decode a packet. Movement that overflowed is dropped, it's garbage anyway.
+    fn decode(packet: [u8; 3]) -> MouseEvent {
+        let [flags, x, y] = packet;
+        let x = if flags & X_SIGN != 0 {
+            x as i16 - 0x100
+        } else {
+            x as i16
+        };
+        let y = if flags & Y_SIGN != 0 {
+            y as i16 - 0x100
+        } else {
+            y as i16
+        };
+        MouseEvent {
+            dx: if flags & X_OVERFLOW != 0 { 0 } else { x },
+            // the mouse counts up as positive
+            dy: if flags & Y_OVERFLOW != 0 { 0 } else { -y },
+            left: flags & LEFT != 0,
+            right: flags & RIGHT != 0,
+            middle: flags & MIDDLE != 0,
+        }
+    }
+
+    /// Packed into a u64, so it fits in a queue slot
+    fn to_bits(self) -> u64 {
+        let buttons = self.left as u64 | (self.right as u64) << 1 | (self.middle as u64) << 2;
+        self.dx as u16 as u64 | (self.dy as u16 as u64) << 16 | buttons << 32
+    }
+
+    fn from_bits(bits: u64) -> MouseEvent {
+        MouseEvent {
+            dx: bits as u16 as i16,
+            dy: (bits >> 16) as u16 as i16,
+            left: bits & 1 << 32 != 0,
+            right: bits & 1 << 33 != 0,
+            middle: bits & 1 << 34 != 0,
+        }
+    }
+}
+
+const QUEUE_SIZE: usize = 64;
+
+/// Events not read yet, kept the same way as the keyboard's bytes: `head` is only moved by the
+/// reader and `tail` only by the interrupt handler.
+struct Queue {
+    buf: [AtomicU64; QUEUE_SIZE],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+impl Queue {
+    fn push(&self, event: MouseEvent) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % QUEUE_SIZE;
+        if next == self.head.load(Ordering::Acquire) {
+            // full, drop it
+            return;
+        }
+        self.buf[tail].store(event.to_bits(), Ordering::Relaxed);
+        self.tail.store(next, Ordering::Release);
+    }
+
+    fn pop(&self) -> Option<MouseEvent> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let bits = self.buf[head].load(Ordering::Relaxed);
+        self.head.store((head + 1) % QUEUE_SIZE, Ordering::Release);
+        Some(MouseEvent::from_bits(bits))
+    }
+}
+
+static QUEUE: Queue = Queue {
+    buf: [const { AtomicU64::new(0) }; QUEUE_SIZE],
+    head: AtomicUsize::new(0),
+    tail: AtomicUsize::new(0),
+};
+
+/// Threads waiting in [`read`]
+static READERS: WaitQueue = WaitQueue::new("mouse readers");
+
+// the packet coming in, only touched by the interrupt handler
+static PACKET: [AtomicU8; 3] = [const { AtomicU8::new(0) }; 3];
+static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+fn interrupt() {
+    let byte = unsafe { u8::read_from_port(DATA) };
+    let received = RECEIVED.load(Ordering::Relaxed);
+    if received == 0 && byte & ALWAYS_ONE == 0 {
+        // out of step, wait for something that can start a packet
+        return;
+    }
+    PACKET[received].store(byte, Ordering::Relaxed);
+    if received < 2 {
+        RECEIVED.store(received + 1, Ordering::Relaxed);
+        return;
+    }
+    RECEIVED.store(0, Ordering::Relaxed);
+
+    let packet = [0, 1, 2].map(|i| PACKET[i].load(Ordering::Relaxed));
+    QUEUE.push(MouseEvent::decode(packet));
+    READERS.wake_one();
+}
+
+/// The next event, if there is one
+pub fn try_read() -> Option<MouseEvent> {
+    QUEUE.pop()
+}
+
+/// Block until the mouse does something, needs interrupts on
+pub fn read() -> MouseEvent {
+    let mut event = None;
+    READERS.wait_until(|| {
+        event = try_read();
+        event.is_some()
+    });
+    event.unwrap()
+}
+
+/// Wait until the controller will take another byte
+fn wait_write() -> bool {
+    (0..TIMEOUT).any(|_| unsafe { u8::read_from_port(STATUS) } & INPUT_FULL == 0)
+}
+
+/// Wait for a byte from the controller
+fn wait_read() -> Option<u8> {
+    (0..TIMEOUT)
+        .any(|_| unsafe { u8::read_from_port(STATUS) } & OUTPUT_FULL != 0)
+        .then(|| unsafe { u8::read_from_port(DATA) })
+}
+
+/// Write `byte` to `port` once the controller's ready for it, false if it never was
+fn write(port: u16, byte: u8) -> bool {
+    let ready = wait_write();
+    if ready {
+        unsafe { u8::write_to_port(port, byte) };
+    }
+    ready
+}
+
+fn command(command: u8) -> bool {
+    write(COMMAND, command)
+}
+
+fn write_data(byte: u8) -> bool {
+    write(DATA, byte)
+}
+
+/// Send `byte` to the mouse, and whether it was acknowledged
+fn send(byte: u8) -> bool {
+    command(WRITE_AUX) && write_data(byte) && wait_read() == Some(ACK)
+}
+
+/// Turn on the second port and its interrupt, and get the mouse reporting
+fn enable() -> bool {
+    if !command(ENABLE_AUX) || !command(READ_CONFIG) {
+        return false;
+    }
+    let Some(config) = wait_read() else {
+        return false;
+    };
+    let config = (config | AUX_INTERRUPT) & !AUX_CLOCK_DISABLED;
+    command(WRITE_CONFIG) && write_data(config) && send(SET_DEFAULTS) && send(ENABLE_REPORTING)
+}
+
+fn init() {
+    // the keyboard's interrupt would take the controller's answers
+    if !interrupts::without_interrupts(enable) {
+        wlog!("mouse: no PS/2 mouse");
+        return;
+    }
+    pic::set_handler(MOUSE_IRQ, interrupt);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "mouse",
+    stage: Stage::Drivers,
+    after: &["pic", "keyboard"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn decodes_packets() {
+        // left held, 5 right and 3 up
+        let event = MouseEvent::decode([ALWAYS_ONE | LEFT, 5, 3]);
+        assert_eq!(
+            (event.dx, event.dy, event.left, event.right),
+            (5, -3, true, false)
+        );
+
+        // 2 left and 1 down
+        let event = MouseEvent::decode([ALWAYS_ONE | X_SIGN | Y_SIGN, 0xfe, 0xff]);
+        assert_eq!((event.dx, event.dy), (-2, 1));
+
+        let event = MouseEvent::decode([ALWAYS_ONE | X_OVERFLOW | MIDDLE, 0x80, 0]);
+        assert_eq!((event.dx, event.middle), (0, true));
+        assert_eq!(MouseEvent::from_bits(event.to_bits()), event);
+    }
+}
@@ -0,0 +1,197 @@
+//! ARP
+//!
+//! Requests for the kernel's [`address`](super::address) are answered with the card's MAC.
+//! Every ARP packet that comes in, and every IPv4 packet, teaches the cache where its sender
+//! is, which is what [`lookup`] goes by. The cache keeps the last [`CACHE_SIZE`] addresses and
+//! entries never expire.
+//!
+//! links:
+//! - RFC 826: <https://www.rfc-editor.org/rfc/rfc826>
+//!
+
+use x86_64::instructions::interrupts;
+
+use super::ethernet::{self, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use super::ipv4::Ipv4Address;
+use super::{MacAddress, NetDevice, NetError};
+use crate::sync::SpinLock;
+
+/// An ARP packet for IPv4 over Ethernet
+const PACKET_LEN: usize = 28;
+
+const HARDWARE_ETHERNET: u16 = 1;
+
+// operations
+const REQUEST: u16 = 1;
+const REPLY: u16 = 2;
+
+pub const CACHE_SIZE: usize = 16;
+
+struct Cache {
+    entries: [Option<(Ipv4Address, MacAddress)>; CACHE_SIZE],
+    /// Where the next new entry goes, over the oldest
+    next: usize,
+}
+
+/// Only locked with interrupts off, since packets can be sent from interrupt handlers
+static CACHE: SpinLock<Cache> = SpinLock::new(
+    "arp cache",
+    Cache {
+        entries: [None; CACHE_SIZE],
+        next: 0,
+    },
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Packet {
+    operation: u16,
+    sender_mac: MacAddress,
+    sender: Ipv4Address,
+    target_mac: MacAddress,
+    target: Ipv4Address,
+}
+
+impl Packet {
+    fn parse(packet: &[u8]) -> Option<Packet> {
+        if packet.len() < PACKET_LEN {
+            return None;
+        }
+        let u16_at = |at: usize| u16::from_be_bytes([packet[at], packet[at + 1]]);
+        if u16_at(0) != HARDWARE_ETHERNET
+            || u16_at(2) != ETHERTYPE_IPV4
+            || packet[4] != 6
+            || packet[5] != 4
+        {
+            return None;
+        }
+        let mac = |at: usize| MacAddress(packet[at..at + 6].try_into().unwrap());
+        let address = |at: usize| Ipv4Address(packet[at..at + 4].try_into().unwrap());
+        Some(Packet {
+            operation: u16_at(6),
+            sender_mac: mac(8),
+            sender: address(14),
+            target_mac: mac(18),
+            target: address(24),
+        })
+    }
+
+    /// Write it into `packet`, returning how long that was
+    fn write(&self, packet: &mut [u8]) -> usize {
+        packet[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        packet[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        packet[4] = 6;
+        packet[5] = 4;
+        packet[6..8].copy_from_slice(&self.operation.to_be_bytes());
+        packet[8..14].copy_from_slice(&self.sender_mac.0);
+        packet[14..18].copy_from_slice(&self.sender.0);
+        packet[18..24].copy_from_slice(&self.target_mac.0);
+        packet[24..28].copy_from_slice(&self.target.0);
+        PACKET_LEN
+    }
+
+    fn send(&self, device: &dyn NetDevice, destination: MacAddress) -> Result<(), NetError> {
+        ethernet::send(device, destination, ETHERTYPE_ARP, |packet| {
+            self.write(packet)
+        })
+    }
+}
+
+/// Remember that `address` is at `mac`
+pub fn learn(address: Ipv4Address, mac: MacAddress) {
+    if address == Ipv4Address::UNSPECIFIED {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let mut cache = CACHE.lock();
+        let existing = cache
+            .entries
+            .iter()
+            .position(|entry| entry.is_some_and(|(known, _)| known == address));
+        let at = existing.unwrap_or(cache.next);
+        if existing.is_none() {
+            cache.next = (cache.next + 1) % CACHE_SIZE;
+        }
+        cache.entries[at] = Some((address, mac));
+    });
+}
+
+/// Where `address` is, if it's in the cache
+pub fn lookup(address: Ipv4Address) -> Option<MacAddress> {
+    if address == Ipv4Address::BROADCAST {
+        return Some(MacAddress::BROADCAST);
+    }
+    interrupts::without_interrupts(|| {
+        CACHE
+            .lock()
+            .entries
+            .iter()
+            .flatten()
+            .find(|(known, _)| *known == address)
+            .map(|&(_, mac)| mac)
+    })
+}
+
+/// Call `f` with every address in the cache and where it is
+pub fn for_each(mut f: impl FnMut(Ipv4Address, MacAddress)) {
+    let entries = interrupts::without_interrupts(|| CACHE.lock().entries);
+    for (address, mac) in entries.into_iter().flatten() {
+        f(address, mac);
+    }
+}
+
+/// Broadcast a request for where `address` is. The answer goes into the cache when it comes.
+pub fn request(device: &dyn NetDevice, address: Ipv4Address) -> Result<(), NetError> {
+    Packet {
+        operation: REQUEST,
+        sender_mac: device.mac(),
+        sender: super::address().unwrap_or(Ipv4Address::UNSPECIFIED),
+        target_mac: MacAddress::default(),
+        target: address,
+    }
+    .send(device, MacAddress::BROADCAST)
+}
+
+/// An ARP packet that came in on `device`
+pub fn handle(device: &dyn NetDevice, packet: &[u8]) {
+    let Some(packet) = Packet::parse(packet) else {
+        return;
+    };
+    learn(packet.sender, packet.sender_mac);
+
+    let Some(address) = super::address() else {
+        return;
+    };
+    if packet.operation == REQUEST && packet.target == address {
+        let reply = Packet {
+            operation: REPLY,
+            sender_mac: device.mac(),
+            sender: address,
+            target_mac: packet.sender_mac,
+            target: packet.sender,
+        };
+        let _ = reply.send(device, packet.sender_mac);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn packet_round_trip() {
+        let packet = Packet {
+            operation: REQUEST,
+            sender_mac: MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+            sender: Ipv4Address([10, 0, 2, 2]),
+            target_mac: MacAddress::default(),
+            target: Ipv4Address([10, 0, 2, 15]),
+        };
+        let mut bytes = [0; PACKET_LEN];
+        assert_eq!(packet.write(&mut bytes), PACKET_LEN);
+        assert_eq!(Packet::parse(&bytes), Some(packet));
+        assert_eq!(Packet::parse(&bytes[..PACKET_LEN - 1]), None);
+
+        learn(packet.sender, packet.sender_mac);
+        assert_eq!(lookup(packet.sender), Some(packet.sender_mac));
+    }
+}
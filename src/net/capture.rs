@@ -0,0 +1,194 @@
+//! Packet capture
+//!
+//! A device that's [tapped](set_tapped) has every frame it sends and receives copied into a
+//! ring of the last [`MAX_CAPTURED`], with the time and the direction, cut off at
+//! [`SNAP_LEN`] bytes. Nothing's copied while no device is tapped. The shell's `capture`
+//! command turns it on and off and shows what's in the ring, either as a hexdump of each
+//! frame or as a pcap file, printed as hex so it comes through the serial port with the rest
+//! of the text. `xxd -r -p` on the lines between the markers turns it back into a file
+//! Wireshark opens. The timestamps are from boot, there's no wall clock to put them on.
+//!
+//! links:
+//! - <https://wiki.wireshark.org/Development/LibpcapFileFormat>
+//!
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use super::{NetDevice, MAX_DEVICES};
+use crate::sync::SpinLock;
+use crate::time;
+
+/// Bytes kept of each frame
+pub const SNAP_LEN: usize = 256;
+/// Frames kept, older ones are written over
+pub const MAX_CAPTURED: usize = 32;
+
+/// pcap's link type for Ethernet
+const LINKTYPE_ETHERNET: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+impl Direction {
+    pub fn name(self) -> &'static str {
+        match self {
+            Direction::Received => "rx",
+            Direction::Sent => "tx",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Captured {
+    pub device: &'static str,
+    pub direction: Direction,
+    /// From [`time::nanos`]
+    pub time_ns: u64,
+    /// How long the frame was, which can be more than was kept
+    pub len: usize,
+    bytes: [u8; SNAP_LEN],
+}
+
+impl Captured {
+    /// What was kept of the frame
+    pub fn data(&self) -> &[u8] {
+        &self.bytes[..self.len.min(SNAP_LEN)]
+    }
+}
+
+struct Ring {
+    frames: [Option<Captured>; MAX_CAPTURED],
+    /// Where the next one goes
+    next: usize,
+}
+
+/// Only locked with interrupts off, frames are sent from interrupt handlers
+static RING: SpinLock<Ring> = SpinLock::new(
+    "capture",
+    Ring {
+        frames: [None; MAX_CAPTURED],
+        next: 0,
+    },
+);
+
+/// Names of the tapped devices
+static TAPPED: SpinLock<[Option<&'static str>; MAX_DEVICES]> =
+    SpinLock::new("capture taps", [None; MAX_DEVICES]);
+/// How many devices are tapped, so frames on the rest don't have to take the lock
+static TAPS: AtomicUsize = AtomicUsize::new(0);
+
+/// Start or stop capturing on `device`
+pub fn set_tapped(device: &'static dyn NetDevice, tapped: bool) {
+    interrupts::without_interrupts(|| {
+        let mut names = TAPPED.lock();
+        let slot = names.iter().position(|&name| name == Some(device.name()));
+        match (slot, tapped) {
+            (None, true) => {
+                if let Some(free) = names.iter_mut().find(|name| name.is_none()) {
+                    *free = Some(device.name());
+                }
+            }
+            (Some(slot), false) => names[slot] = None,
+            _ => {}
+        }
+        TAPS.store(names.iter().flatten().count(), Ordering::Relaxed);
+    });
+}
+
+pub fn is_tapped(device: &dyn NetDevice) -> bool {
+    interrupts::without_interrupts(|| TAPPED.lock().contains(&Some(device.name())))
+}
+
+/// Copy `frame` into the ring if `device` is tapped, from wherever it's sent or received
+pub fn tap(device: &dyn NetDevice, direction: Direction, frame: &[u8]) {
+    if TAPS.load(Ordering::Relaxed) == 0 || !is_tapped(device) {
+        return;
+    }
+    let mut captured = Captured {
+        device: device.name(),
+        direction,
+        time_ns: time::nanos(),
+        len: frame.len(),
+        bytes: [0; SNAP_LEN],
+    };
+    let kept = frame.len().min(SNAP_LEN);
+    captured.bytes[..kept].copy_from_slice(&frame[..kept]);
+    interrupts::without_interrupts(|| {
+        let mut ring = RING.lock();
+        let next = ring.next;
+        ring.frames[next] = Some(captured);
+        ring.next = (next + 1) % MAX_CAPTURED;
+    });
+}
+
+/// What's in the ring, oldest first
+pub fn captured() -> Vec<Captured> {
+    let mut frames = Vec::with_capacity(MAX_CAPTURED);
+    interrupts::without_interrupts(|| {
+        let ring = RING.lock();
+        let (newer, older) = ring.frames.split_at(ring.next);
+        frames.extend(older.iter().chain(newer).flatten());
+    });
+    frames
+}
+
+/// Throw away everything captured
+pub fn clear() {
+    interrupts::without_interrupts(|| {
+        let mut ring = RING.lock();
+        ring.frames = [None; MAX_CAPTURED];
+        ring.next = 0;
+    });
+}
+
+/// The pcap file header, little-endian
+pub fn pcap_header() -> [u8; 24] {
+    let mut header = [0; 24];
+    header[0..4].copy_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    header[4..6].copy_from_slice(&2u16.to_le_bytes());
+    header[6..8].copy_from_slice(&4u16.to_le_bytes());
+    // the time zone and timestamp accuracy are always 0
+    header[16..20].copy_from_slice(&(SNAP_LEN as u32).to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    header
+}
+
+/// The pcap record header for `frame`, which its [`data`](Captured::data) follows
+pub fn pcap_record(frame: &Captured) -> [u8; 16] {
+    let mut header = [0; 16];
+    let seconds = frame.time_ns / 1_000_000_000;
+    let micros = frame.time_ns % 1_000_000_000 / 1000;
+    header[0..4].copy_from_slice(&(seconds as u32).to_le_bytes());
+    header[4..8].copy_from_slice(&(micros as u32).to_le_bytes());
+    header[8..12].copy_from_slice(&(frame.data().len() as u32).to_le_bytes());
+    header[12..16].copy_from_slice(&(frame.len as u32).to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn records_keep_the_snap_length() {
+        let frame = Captured {
+            device: "test",
+            direction: Direction::Sent,
+            time_ns: 2_000_345_000,
+            len: 1000,
+            bytes: [0; SNAP_LEN],
+        };
+        assert_eq!(frame.data().len(), SNAP_LEN);
+        let record = pcap_record(&frame);
+        assert_eq!(record[0..4], 2u32.to_le_bytes());
+        assert_eq!(record[4..8], 345u32.to_le_bytes());
+        assert_eq!(record[8..12], (SNAP_LEN as u32).to_le_bytes());
+        assert_eq!(record[12..16], 1000u32.to_le_bytes());
+    }
+}
@@ -0,0 +1,73 @@
+//! Ethernet frames
+//!
+//! A frame is a 14-byte header, the destination and source addresses and what the payload
+//! is, followed by the payload. Frames shorter than [`MIN_FRAME`] are padded with zeroes when
+//! they're sent, which is what the wire needs and nothing but the card would do otherwise.
+//!
+
+use super::capture::{self, Direction};
+use super::{MacAddress, NetDevice, NetError, MAX_FRAME};
+use crate::debug::fault;
+
+pub const HEADER_LEN: usize = 14;
+/// Shortest frame on the wire, without the checksum
+pub const MIN_FRAME: usize = 60;
+
+// what the payload is
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+}
+
+impl Header {
+    /// Split `frame` into its header and payload
+    pub fn parse(frame: &[u8]) -> Option<(Header, &[u8])> {
+        if frame.len() < HEADER_LEN {
+            return None;
+        }
+        let mac = |at: usize| MacAddress(frame[at..at + 6].try_into().unwrap());
+        let header = Header {
+            destination: mac(0),
+            source: mac(6),
+            ethertype: u16::from_be_bytes([frame[12], frame[13]]),
+        };
+        Some((header, &frame[HEADER_LEN..]))
+    }
+
+    /// Fill in the first [`HEADER_LEN`] bytes of `frame`
+    pub fn write(&self, frame: &mut [u8]) {
+        frame[0..6].copy_from_slice(&self.destination.0);
+        frame[6..12].copy_from_slice(&self.source.0);
+        frame[12..14].copy_from_slice(&self.ethertype.to_be_bytes());
+    }
+}
+
+/// Send a frame to `destination` from `device`. `payload` fills in the payload, which has room
+/// for the most a frame can carry, and returns how long it made it.
+pub fn send(
+    device: &dyn NetDevice,
+    destination: MacAddress,
+    ethertype: u16,
+    payload: impl FnOnce(&mut [u8]) -> usize,
+) -> Result<(), NetError> {
+    let mut frame = [0; MAX_FRAME];
+    Header {
+        destination,
+        source: device.mac(),
+        ethertype,
+    }
+    .write(&mut frame);
+    let len = HEADER_LEN + payload(&mut frame[HEADER_LEN..]);
+    let frame = &frame[..len.max(MIN_FRAME)];
+    capture::tap(device, Direction::Sent, frame);
+    // lost on the way, as far as the sender can tell
+    if fault::NET.fire() {
+        return Ok(());
+    }
+    device.send(frame)
+}
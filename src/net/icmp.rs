@@ -0,0 +1,35 @@
+//! ICMP
+//!
+//! Only echo requests are handled: each is answered with an echo reply carrying the same
+//! identifier, sequence number, and data, which is all `ping` needs.
+//!
+//! links:
+//! - RFC 792: <https://www.rfc-editor.org/rfc/rfc792>
+//!
+
+use super::ipv4::{self, checksum, Header, PROTOCOL_ICMP};
+use super::{MacAddress, NetDevice};
+
+/// Type, code, checksum, and 4 bytes that depend on the type
+const HEADER_LEN: usize = 8;
+
+// types
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+
+/// An ICMP message that came in on `device` from `mac`, in a packet with `header`
+pub fn handle(device: &dyn NetDevice, mac: MacAddress, header: &Header, message: &[u8]) {
+    if message.len() < HEADER_LEN || message[0] != ECHO_REQUEST || checksum(message) != 0 {
+        return;
+    }
+    let _ = ipv4::send(device, mac, header.source, PROTOCOL_ICMP, |reply| {
+        let len = message.len().min(reply.len());
+        reply[..len].copy_from_slice(&message[..len]);
+        reply[0] = ECHO_REPLY;
+        reply[1] = 0;
+        reply[2..4].fill(0);
+        let checksum = checksum(&reply[..len]);
+        reply[2..4].copy_from_slice(&checksum.to_be_bytes());
+        len
+    });
+}
@@ -0,0 +1,259 @@
+//! IPv4
+//!
+//! Packets addressed to the kernel's [`address`](super::address), or broadcast, or to a
+//! loopback address on the [`loopback`](super::loopback) device, are handed to the protocol
+//! they carry. Options are skipped, and fragments are dropped, since nothing the
+//! kernel speaks sends packets that big. Sent packets never have options and are never
+//! fragmented.
+//!
+//! links:
+//! - RFC 791: <https://www.rfc-editor.org/rfc/rfc791>
+//! - the checksum, RFC 1071: <https://www.rfc-editor.org/rfc/rfc1071>
+//!
+
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::ethernet::{self, ETHERTYPE_IPV4};
+use super::{arp, icmp, loopback, udp, MacAddress, NetDevice, NetError};
+
+/// Header without options
+pub const HEADER_LEN: usize = 20;
+
+// protocols
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// Hops a sent packet can take
+const TTL: u8 = 64;
+/// Flags and fragment offset: don't fragment
+const DONT_FRAGMENT: u16 = 1 << 14;
+/// The more fragments flag and the offset, either means it's a fragment
+const FRAGMENT: u16 = 0x3fff;
+
+/// Identification of the next packet sent
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0; 4]);
+    pub const BROADCAST: Ipv4Address = Ipv4Address([0xff; 4]);
+    pub const LOOPBACK: Ipv4Address = Ipv4Address([127, 0, 0, 1]);
+
+    /// Whether it's in `127.0.0.0/8`, which never leaves the machine
+    pub fn is_loopback(self) -> bool {
+        self.0[0] == 127
+    }
+
+    pub fn to_bits(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_bits(bits: u32) -> Ipv4Address {
+        Ipv4Address(bits.to_be_bytes())
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/// A string that isn't four dot-separated numbers up to 255
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseAddressError;
+
+impl FromStr for Ipv4Address {
+    type Err = ParseAddressError;
+
+    fn from_str(s: &str) -> Result<Ipv4Address, ParseAddressError> {
+        let mut address = [0; 4];
+        let mut parts = s.split('.');
+        for byte in &mut address {
+            *byte = parts
+                .next()
+                .and_then(|part| part.parse().ok())
+                .ok_or(ParseAddressError)?;
+        }
+        match parts.next() {
+            Some(_) => Err(ParseAddressError),
+            None => Ok(Ipv4Address(address)),
+        }
+    }
+}
+
+/// The Internet checksum of `data`: the ones' complement of the ones' complement sum of its
+/// 16-bit words. Checking data that has its checksum in it gives 0.
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum(0, data))
+}
+
+/// Add `data`'s 16-bit words to `sum`, for checksums over more than one piece of data. Every
+/// piece but the last has to be an even length.
+pub fn sum(mut sum: u32, data: &[u8]) -> u32 {
+    for pair in data.chunks(2) {
+        let word = match *pair {
+            [high, low] => u16::from_be_bytes([high, low]),
+            [high] => u16::from_be_bytes([high, 0]),
+            _ => unreachable!(),
+        };
+        sum += word as u32;
+    }
+    sum
+}
+
+/// The checksum from a [`sum`]
+pub fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub protocol: u8,
+}
+
+impl Header {
+    /// Split `packet` into its header and payload, None if it's broken or a fragment
+    pub fn parse(packet: &[u8]) -> Option<(Header, &[u8])> {
+        if packet.len() < HEADER_LEN || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (packet[0] & 0xf) as usize * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+        if header_len < HEADER_LEN
+            || total_len < header_len
+            || total_len > packet.len()
+            || fragment & FRAGMENT != 0
+            || checksum(&packet[..header_len]) != 0
+        {
+            return None;
+        }
+        let address = |at: usize| Ipv4Address(packet[at..at + 4].try_into().unwrap());
+        let header = Header {
+            source: address(12),
+            destination: address(16),
+            protocol: packet[9],
+        };
+        // anything past the total length is the frame's padding
+        Some((header, &packet[header_len..total_len]))
+    }
+
+    /// Fill in the first [`HEADER_LEN`] bytes of `packet`, for `payload_len` bytes after them
+    pub fn write(&self, packet: &mut [u8], payload_len: usize) {
+        let total_len = (HEADER_LEN + payload_len) as u16;
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        packet[0] = 0x45;
+        packet[1] = 0;
+        packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+        packet[4..6].copy_from_slice(&id.to_be_bytes());
+        packet[6..8].copy_from_slice(&DONT_FRAGMENT.to_be_bytes());
+        packet[8] = TTL;
+        packet[9] = self.protocol;
+        packet[10..12].fill(0);
+        packet[12..16].copy_from_slice(&self.source.0);
+        packet[16..20].copy_from_slice(&self.destination.0);
+        let checksum = checksum(&packet[..HEADER_LEN]);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+/// The address packets to `destination` are sent from, None if the kernel doesn't have one
+pub fn source_for(destination: Ipv4Address) -> Option<Ipv4Address> {
+    if destination.is_loopback() {
+        return Some(Ipv4Address::LOOPBACK);
+    }
+    super::address()
+}
+
+/// Send a packet to `destination`, which is at `mac` on `device`'s network. `payload` fills
+/// in the payload like it does for [`ethernet::send`].
+pub fn send(
+    device: &dyn NetDevice,
+    mac: MacAddress,
+    destination: Ipv4Address,
+    protocol: u8,
+    payload: impl FnOnce(&mut [u8]) -> usize,
+) -> Result<(), NetError> {
+    let source = source_for(destination).unwrap_or(Ipv4Address::UNSPECIFIED);
+    ethernet::send(device, mac, ETHERTYPE_IPV4, |packet| {
+        let len = payload(&mut packet[HEADER_LEN..]);
+        Header {
+            source,
+            destination,
+            protocol,
+        }
+        .write(packet, len);
+        HEADER_LEN + len
+    })
+}
+
+/// A packet that came in on `device` from `mac`
+pub fn handle(device: &dyn NetDevice, mac: MacAddress, packet: &[u8]) {
+    let Some((header, payload)) = Header::parse(packet) else {
+        return;
+    };
+    if device.name() == loopback::NAME {
+        // only the kernel itself is on the other end
+        if !header.destination.is_loopback() {
+            return;
+        }
+    } else {
+        let Some(address) = super::address() else {
+            return;
+        };
+        if header.destination != address && header.destination != Ipv4Address::BROADCAST {
+            return;
+        }
+        // whoever sent it is going to want an answer
+        arp::learn(header.source, mac);
+    }
+
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::handle(device, mac, &header, payload),
+        PROTOCOL_UDP => udp::handle(&header, payload),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parses_addresses() {
+        let address: Ipv4Address = "10.0.2.15".parse().unwrap();
+        assert_eq!(address, Ipv4Address([10, 0, 2, 15]));
+        assert_eq!(alloc::format!("{}", address), "10.0.2.15");
+        assert!("10.0.2".parse::<Ipv4Address>().is_err());
+        assert!("10.0.2.256".parse::<Ipv4Address>().is_err());
+        assert!("10.0.2.15.1".parse::<Ipv4Address>().is_err());
+    }
+
+    #[test_case]
+    fn header_round_trip() {
+        let header = Header {
+            source: Ipv4Address([192, 168, 0, 1]),
+            destination: Ipv4Address([192, 168, 0, 199]),
+            protocol: PROTOCOL_ICMP,
+        };
+        let mut packet = [0; HEADER_LEN + 4];
+        header.write(&mut packet, 4);
+        assert_eq!(checksum(&packet[..HEADER_LEN]), 0);
+        let (parsed, payload) = Header::parse(&packet).unwrap();
+        assert_eq!((parsed, payload.len()), (header, 4));
+
+        packet[15] ^= 1;
+        assert!(Header::parse(&packet).is_none());
+    }
+}
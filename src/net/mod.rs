@@ -0,0 +1,334 @@
+//! Networking
+//!
+//! Network cards implement [`NetDevice`] and [`register`] once they're found, the same way disks
+//! do with the [`block`](crate::block) layer. A device sends and receives whole Ethernet
+//! frames, from the destination address to the end of the payload, without the preamble or
+//! the checksum the card adds.
+//!
+//! On top of that there's a small stack: [`ethernet`] frames carrying [`arp`] and [`ipv4`],
+//! [`icmp`] echo replies so the kernel can be pinged, and [`udp`] sockets, which [`syslog`]
+//! sends the kernel's output over. The kernel has one IPv4 address, on every card, which is
+//! set with `ip=ADDRESS` on the command line. Without one, nothing is answered. Everything it
+//! sends to is taken to be on the same network, there's no routing, except that `127.x.x.x`
+//! is the [`loopback`] device's. [`INIT`] registers that and starts a thread that handles every
+//! frame that comes in.
+//!
+//! Receiving doesn't block. A driver's interrupt handler calls [`received`] when frames come
+//! in, which wakes whoever is sleeping in [`wait_for_frames`], and they take the frames with
+//! [`NetDevice::receive`]. Sending doesn't block either, so it works from interrupt handlers
+//! too, but it fails with [`NetError::Busy`] while the card is still sending everything it was
+//! given before.
+//!
+//! Both directions go past [`capture`], which keeps copies of the frames on devices that are
+//! tapped.
+//!
+//! With the `smoltcp` feature, one device can be handed to [smoltcp](smol) instead, and the
+//! native stack leaves it alone.
+//!
+
+pub mod arp;
+pub mod capture;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod loopback;
+#[cfg(feature = "smoltcp")]
+pub mod smol;
+pub mod syslog;
+pub mod udp;
+
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use self::ethernet::{ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use self::ipv4::Ipv4Address;
+use crate::debug::fault;
+use crate::init::{InitCall, Stage};
+use crate::sync::{SpinLock, WaitQueue};
+use crate::watchdog::{self, Heartbeat};
+use crate::{cmdline, ilog, sched, timer, wlog};
+
+/// Devices that can be registered at once
+const MAX_DEVICES: usize = 4;
+
+/// Longest frame without the checksum: the 14-byte header and a 1500-byte payload
+pub const MAX_FRAME: usize = 1514;
+
+/// Why something couldn't be sent or received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// It's longer than [`MAX_FRAME`], or shorter than a header
+    BadLength,
+    /// Every transmit buffer is in use
+    Busy,
+    /// There's no network card
+    NoDevice,
+    /// The kernel doesn't have an IPv4 address
+    NoAddress,
+    /// The destination isn't in the [`arp`] cache yet. A request was sent, so trying again
+    /// shortly should work.
+    Unresolved,
+    /// Something's already bound to the port
+    AddressInUse,
+}
+
+/// A hardware address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// Something that sends and receives Ethernet frames
+pub trait NetDevice: Sync {
+    /// Short name to refer to the device by, like `eth0`
+    fn name(&self) -> &'static str;
+
+    fn mac(&self) -> MacAddress;
+
+    /// Queue `frame` to be sent
+    fn send(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Take the next frame that came in, copying as much of it as fits into `buf`. Returns its
+    /// length, None if there wasn't one.
+    fn receive(&self, buf: &mut [u8]) -> Option<usize>;
+
+    /// Whether there's a frame for [`receive`](NetDevice::receive)
+    fn has_frames(&self) -> bool;
+}
+
+/// Only locked with interrupts off, through [`with_devices`], since sending from `print!` can
+/// come from an interrupt handler
+static DEVICES: SpinLock<[Option<&'static dyn NetDevice>; MAX_DEVICES]> =
+    SpinLock::new("net devices", [None; MAX_DEVICES]);
+
+/// Woken by [`received`]
+static RECEIVERS: WaitQueue = WaitQueue::new("net receivers");
+
+/// The kernel's address, as [`Ipv4Address::to_bits`], 0 for none
+static ADDRESS: AtomicU32 = AtomicU32::new(0);
+
+/// Why a device couldn't be registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// There's already a device with that name
+    Exists,
+    /// All `MAX_DEVICES` slots are taken
+    Full,
+}
+
+fn with_devices<R>(f: impl FnOnce(&mut [Option<&'static dyn NetDevice>; MAX_DEVICES]) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut DEVICES.lock()))
+}
+
+/// Make `device` available to [`get`]
+pub fn register(device: &'static dyn NetDevice) -> Result<(), RegisterError> {
+    with_devices(|devices| {
+        if devices
+            .iter()
+            .flatten()
+            .any(|registered| registered.name() == device.name())
+        {
+            return Err(RegisterError::Exists);
+        }
+
+        let slot = devices
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegisterError::Full)?;
+        *slot = Some(device);
+        Ok(())
+    })
+}
+
+/// The device called `name`
+pub fn get(name: &str) -> Option<&'static dyn NetDevice> {
+    with_devices(|devices| {
+        devices
+            .iter()
+            .flatten()
+            .find(|device| device.name() == name)
+            .copied()
+    })
+}
+
+/// The device things are sent from, the first one registered that the native stack has
+pub fn default_device() -> Option<&'static dyn NetDevice> {
+    with_devices(|devices| {
+        devices
+            .iter()
+            .flatten()
+            .find(|device| is_native(**device))
+            .copied()
+    })
+}
+
+/// Whether the native stack sends and receives on `device`, rather than [`smol`]
+#[cfg(feature = "smoltcp")]
+fn is_native(device: &dyn NetDevice) -> bool {
+    !smol::owns(device)
+}
+
+#[cfg(not(feature = "smoltcp"))]
+fn is_native(_device: &dyn NetDevice) -> bool {
+    true
+}
+
+/// Whether [`smol`] is due a poll without any frames
+#[cfg(feature = "smoltcp")]
+fn backend_due() -> bool {
+    smol::take_due()
+}
+
+#[cfg(not(feature = "smoltcp"))]
+fn backend_due() -> bool {
+    false
+}
+
+/// Call `f` with every registered device, in the order they were registered
+pub fn for_each(mut f: impl FnMut(&'static dyn NetDevice)) {
+    // a copy, so `f` can send without the registry locked
+    let devices = with_devices(|devices| *devices);
+    for &device in devices.iter().flatten() {
+        f(device);
+    }
+}
+
+/// The device and hardware address to send to `destination` through. When the address isn't
+/// known yet, an [`arp`] request is sent and this fails with [`NetError::Unresolved`].
+pub fn route(destination: Ipv4Address) -> Result<(&'static dyn NetDevice, MacAddress), NetError> {
+    if destination.is_loopback() {
+        return Ok((&loopback::LOOPBACK, loopback::LOOPBACK.mac()));
+    }
+    let device = default_device().ok_or(NetError::NoDevice)?;
+    match arp::lookup(destination) {
+        Some(mac) => Ok((device, mac)),
+        None => {
+            arp::request(device, destination)?;
+            Err(NetError::Unresolved)
+        }
+    }
+}
+
+/// Tell the receivers some device has frames, from its interrupt handler
+pub fn received() {
+    RECEIVERS.wake_all();
+}
+
+/// Block until some device has frames to receive, or the smoltcp backend is due a poll
+pub fn wait_for_frames() {
+    RECEIVERS.wait_until(|| {
+        let mut any = backend_due();
+        for_each(|device| any |= device.has_frames());
+        any
+    });
+}
+
+/// The kernel's IPv4 address, if it has one
+pub fn address() -> Option<Ipv4Address> {
+    match ADDRESS.load(Ordering::Relaxed) {
+        0 => None,
+        bits => Some(Ipv4Address::from_bits(bits)),
+    }
+}
+
+pub fn set_address(address: Option<Ipv4Address>) {
+    ADDRESS.store(address.map_or(0, Ipv4Address::to_bits), Ordering::Relaxed);
+}
+
+/// A frame that came in on `device`
+fn handle(device: &dyn NetDevice, frame: &[u8]) {
+    let Some((header, payload)) = ethernet::Header::parse(frame) else {
+        return;
+    };
+    if header.destination != device.mac() && header.destination != MacAddress::BROADCAST {
+        return;
+    }
+    match header.ethertype {
+        ETHERTYPE_ARP => arp::handle(device, payload),
+        ETHERTYPE_IPV4 => ipv4::handle(device, header.source, payload),
+        _ => {}
+    }
+}
+
+/// Handle frames from every device as they come in, forever
+fn receive_thread() {
+    static HEARTBEAT: Heartbeat = Heartbeat::new("net");
+    let _ = watchdog::watch(&HEARTBEAT);
+
+    let mut frame = [0; MAX_FRAME];
+    loop {
+        // there may not be anything for a long time
+        HEARTBEAT.pause();
+        wait_for_frames();
+        HEARTBEAT.touch();
+        for_each(|device| {
+            if !is_native(device) {
+                return;
+            }
+            while let Some(len) = device.receive(&mut frame) {
+                let frame = &frame[..len.min(MAX_FRAME)];
+                capture::tap(device, capture::Direction::Received, frame);
+                if fault::NET.fire() {
+                    continue;
+                }
+                if fault::NET_DELAY.fire() {
+                    timer::sleep_ms(fault::DELAY_MS);
+                }
+                handle(device, frame);
+            }
+        });
+        #[cfg(feature = "smoltcp")]
+        smol::poll();
+    }
+}
+
+fn init() {
+    match cmdline::parse::<Ipv4Address>("ip") {
+        Ok(address) => set_address(address),
+        Err(error) => wlog!("net: {}", error),
+    }
+    // after the cards, so it's never the default device when there's one
+    if let Err(error) = register(&loopback::LOOPBACK) {
+        wlog!("net: couldn't register {}: {:?}", loopback::NAME, error);
+    }
+    if let Some(address) = address() {
+        ilog!("net: address {}", address);
+    }
+    #[cfg(feature = "smoltcp")]
+    smol::init();
+    sched::spawn("net", receive_thread);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "net",
+    stage: Stage::Late,
+    // the cards register in the drivers stage
+    after: &["sched"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn mac_address_display() {
+        let mac = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x0a]);
+        assert_eq!(alloc::format!("{}", mac), "52:54:00:12:34:0a");
+    }
+}
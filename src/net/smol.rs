@@ -0,0 +1,229 @@
+//! The smoltcp backend
+//!
+//! With the `smoltcp` feature, `smoltcp=DEV` on the command line hands device `DEV` to
+//! [smoltcp](https://docs.rs/smoltcp) instead of the native stack, for TCP and the rest of what
+//! the native stack doesn't do. [`Phy`] is the `phy::Device` smoltcp sees, over the
+//! [`NetDevice`], and an [`Interface`] on it has the kernel's address. The net thread polls it
+//! whenever the device has frames, and every [`POLL_MS`] for smoltcp's own timers, like
+//! retransmits. Its sockets are added to and used through [`with`].
+//!
+//! The native stack doesn't send on or receive from the device while smoltcp has it. Its
+//! frames still go past [`capture`] and the [`net`](fault::NET) fault points like the native
+//! stack's.
+//!
+//! links:
+//! - <https://docs.rs/smoltcp/0.14.0/smoltcp/>
+//!
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use smoltcp::iface::{Config, Interface, SocketSet};
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpCidr, Ipv4Address as SmolAddress};
+use x86_64::instructions::interrupts;
+
+use super::capture::{self, Direction};
+use super::{ethernet, NetDevice, MAX_FRAME};
+use crate::debug::fault;
+use crate::sync::SpinLock;
+use crate::{cmdline, ilog, rand, time, timer, wlog};
+
+/// How often the interface is polled when no frames come in
+pub const POLL_MS: u64 = 10;
+
+/// A [`NetDevice`] as smoltcp sees it
+pub struct Phy {
+    device: &'static dyn NetDevice,
+    rx: [u8; MAX_FRAME],
+    tx: [u8; MAX_FRAME],
+}
+
+impl Phy {
+    pub fn new(device: &'static dyn NetDevice) -> Phy {
+        Phy {
+            device,
+            rx: [0; MAX_FRAME],
+            tx: [0; MAX_FRAME],
+        }
+    }
+}
+
+/// A frame that came in, in [`Phy`]'s receive buffer
+pub struct RxToken<'a> {
+    frame: &'a [u8],
+}
+
+impl phy::RxToken for RxToken<'_> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(self.frame)
+    }
+}
+
+/// Room for a frame to send, in [`Phy`]'s send buffer
+pub struct TxToken<'a> {
+    device: &'static dyn NetDevice,
+    buf: &'a mut [u8; MAX_FRAME],
+}
+
+impl phy::TxToken for TxToken<'_> {
+    /// `len` is at most the [`MAX_FRAME`] the capabilities say
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let result = f(&mut self.buf[..len]);
+        let sent = len.max(ethernet::MIN_FRAME);
+        self.buf[len..sent].fill(0);
+        let frame = &self.buf[..sent];
+        capture::tap(self.device, Direction::Sent, frame);
+        // a frame that can't be sent is lost on the way, smoltcp sends again what needs it
+        if !fault::NET.fire() {
+            let _ = self.device.send(frame);
+        }
+        result
+    }
+}
+
+impl phy::Device for Phy {
+    type RxToken<'a> = RxToken<'a>;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken<'_>, TxToken<'_>)> {
+        let len = loop {
+            let len = self.device.receive(&mut self.rx)?.min(MAX_FRAME);
+            capture::tap(self.device, Direction::Received, &self.rx[..len]);
+            if fault::NET.fire() {
+                continue;
+            }
+            // the backend's locked, so it can't sleep like the native stack does
+            fault::delay(&fault::NET_DELAY);
+            break len;
+        };
+        Some((
+            RxToken {
+                frame: &self.rx[..len],
+            },
+            TxToken {
+                device: self.device,
+                buf: &mut self.tx,
+            },
+        ))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken {
+            device: self.device,
+            buf: &mut self.tx,
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ethernet;
+        // on Ethernet smoltcp counts the header in
+        capabilities.max_transmission_unit = MAX_FRAME;
+        capabilities
+    }
+}
+
+struct Backend {
+    phy: Phy,
+    iface: Interface,
+    sockets: SocketSet<'static>,
+}
+
+/// Only ever locked from threads
+static BACKEND: SpinLock<Option<Backend>> = SpinLock::new("smoltcp", None);
+/// The device smoltcp has. Only locked with interrupts off, the native stack checks it when
+/// sending from interrupt handlers.
+static DEVICE: SpinLock<Option<&'static dyn NetDevice>> = SpinLock::new("smoltcp device", None);
+/// Set by the timer when it's time to poll again
+static DUE: AtomicBool = AtomicBool::new(false);
+
+fn now() -> Instant {
+    Instant::from_micros((time::nanos() / 1000) as i64)
+}
+
+/// Whether `device` is smoltcp's
+pub fn owns(device: &dyn NetDevice) -> bool {
+    interrupts::without_interrupts(|| {
+        DEVICE
+            .lock()
+            .is_some_and(|owned| owned.name() == device.name())
+    })
+}
+
+/// True once each time the interface should be polled without frames having come in
+pub fn take_due() -> bool {
+    DUE.swap(false, Ordering::Relaxed)
+}
+
+/// Let smoltcp handle the frames that came in and send what it has to, from the net thread
+pub fn poll() {
+    let mut backend = BACKEND.lock();
+    if let Some(Backend {
+        phy,
+        iface,
+        sockets,
+    }) = backend.as_mut()
+    {
+        iface.poll(now(), phy, sockets);
+    }
+}
+
+/// Run `f` on the interface and its sockets, None without the backend. The net thread polls
+/// the sockets added to the set from then on, and once after `f` for what it did.
+pub fn with<R>(f: impl FnOnce(&mut Interface, &mut SocketSet<'static>) -> R) -> Option<R> {
+    let result = BACKEND
+        .lock()
+        .as_mut()
+        .map(|backend| f(&mut backend.iface, &mut backend.sockets));
+    DUE.store(true, Ordering::Relaxed);
+    super::received();
+    result
+}
+
+/// From the timer interrupt
+fn tick() {
+    DUE.store(true, Ordering::Relaxed);
+    super::received();
+}
+
+/// Hand the device `smoltcp=` names to smoltcp, from [`net`](super)'s init
+pub(super) fn init() {
+    let Some(name) = cmdline::get("smoltcp") else {
+        return;
+    };
+    let Some(device) = super::get(name) else {
+        wlog!("smoltcp: no device called {}", name);
+        return;
+    };
+
+    let mut config = Config::new(HardwareAddress::Ethernet(EthernetAddress(device.mac().0)));
+    config.random_seed = rand::u64();
+    let mut phy = Phy::new(device);
+    let mut iface = Interface::new(config, &mut phy, now());
+    if let Some(address) = super::address() {
+        // everything's on the same network, like it is for the native stack
+        let [a, b, c, d] = address.0;
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(SmolAddress::new(a, b, c, d).into(), 0));
+        });
+    }
+    *BACKEND.lock() = Some(Backend {
+        phy,
+        iface,
+        sockets: SocketSet::new(Vec::new()),
+    });
+    interrupts::without_interrupts(|| *DEVICE.lock() = Some(device));
+
+    if let Err(error) = timer::every(POLL_MS, tick) {
+        wlog!("smoltcp: no timer to poll with: {:?}", error);
+    }
+    ilog!("smoltcp: has {}", name);
+}
@@ -0,0 +1,138 @@
+//! Remote syslog
+//!
+//! With `syslog=HOST[:PORT]` on the command line, [`INIT`] registers a `syslog`
+//! [sink](crate::console::sink) that sends every line printed, the same lines the
+//! [`klog`](crate::klog) buffer gets, to a syslog collector as a [`udp`](super::udp)
+//! datagram. The port is 514 if it's left out. Lines go out in the BSD syslog format, as
+//! `kern.info` from `zenix`, and the collector puts its own time on them.
+//!
+//! Nothing is retried. A line that can't be sent, because the collector hasn't answered ARP
+//! yet or the card is busy, is dropped, so the first few lines after boot usually are.
+//!
+//! links:
+//! - RFC 3164: <https://www.rfc-editor.org/rfc/rfc3164>
+//!
+
+use alloc::boxed::Box;
+
+use super::ipv4::Ipv4Address;
+use super::{arp, udp};
+use crate::console::sink::{self, LogSink};
+use crate::init::{InitCall, Stage};
+use crate::klog::MAX_LINE;
+use crate::sync::SpinLock;
+use crate::{cmdline, ilog, wlog};
+
+pub const DEFAULT_PORT: u16 = 514;
+/// Where lines are sent from
+const SOURCE_PORT: u16 = 514;
+/// Facility kern (0) times 8, plus severity info (6)
+const PREFIX: &[u8] = b"<6>zenix: ";
+
+struct Line {
+    buf: [u8; MAX_LINE],
+    len: usize,
+}
+
+/// Sends lines to one collector. Only written to with interrupts off, from `print!`.
+struct SyslogSink {
+    host: Ipv4Address,
+    port: u16,
+    line: SpinLock<Line>,
+}
+
+impl SyslogSink {
+    fn send(&self, line: &[u8]) {
+        let mut datagram = [0; PREFIX.len() + MAX_LINE];
+        datagram[..PREFIX.len()].copy_from_slice(PREFIX);
+        datagram[PREFIX.len()..PREFIX.len() + line.len()].copy_from_slice(line);
+        let _ = udp::send(
+            SOURCE_PORT,
+            self.host,
+            self.port,
+            &datagram[..PREFIX.len() + line.len()],
+        );
+    }
+}
+
+impl LogSink for SyslogSink {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    fn write_str(&self, s: &str) {
+        let mut line = self.line.lock();
+        for &byte in s.as_bytes() {
+            if byte != b'\n' {
+                let len = line.len;
+                line.buf[len] = byte;
+                line.len += 1;
+            }
+            // a line that's too long goes in pieces
+            if byte == b'\n' || line.len == MAX_LINE {
+                self.send(&line.buf[..line.len]);
+                line.len = 0;
+            }
+        }
+    }
+}
+
+/// Split `HOST[:PORT]`
+fn parse_destination(value: &str) -> Option<(Ipv4Address, u16)> {
+    let (host, port) = match value.split_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (value, DEFAULT_PORT),
+    };
+    Some((host.parse().ok()?, port))
+}
+
+fn init() {
+    let Some(value) = cmdline::get("syslog") else {
+        return;
+    };
+    let Some((host, port)) = parse_destination(value) else {
+        wlog!("syslog: invalid value for syslog: {:?}", value);
+        return;
+    };
+    // so the collector's MAC is known by the time there's something to send
+    if let Some(device) = super::default_device() {
+        let _ = arp::request(device, host);
+    }
+
+    let sink = Box::leak(Box::new(SyslogSink {
+        host,
+        port,
+        line: SpinLock::new(
+            "syslog line",
+            Line {
+                buf: [0; MAX_LINE],
+                len: 0,
+            },
+        ),
+    }));
+    match sink::register(sink) {
+        Ok(()) => ilog!("syslog: sending to {}:{}", host, port),
+        Err(error) => wlog!("syslog: couldn't register the sink: {:?}", error),
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "syslog",
+    stage: Stage::Late,
+    after: &["net"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parses_destinations() {
+        let host = Ipv4Address([10, 0, 2, 2]);
+        assert_eq!(parse_destination("10.0.2.2"), Some((host, DEFAULT_PORT)));
+        assert_eq!(parse_destination("10.0.2.2:5140"), Some((host, 5140)));
+        assert_eq!(parse_destination("10.0.2.2:x"), None);
+        assert_eq!(parse_destination("collector"), None);
+    }
+}
@@ -0,0 +1,259 @@
+//! UDP
+//!
+//! A [`UdpSocket`] is bound to a port, and datagrams that come in for that port queue up on it
+//! until they're received, up to [`MAX_QUEUED`] of them. Ones for a port nothing's bound to
+//! are dropped. [`send`] sends a datagram without a socket, which is all that's needed for
+//! one that doesn't expect an answer, and doesn't block or allocate, so it works from an
+//! interrupt handler.
+//!
+//! links:
+//! - RFC 768: <https://www.rfc-editor.org/rfc/rfc768>
+//!
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::ipv4::{self, Ipv4Address, PROTOCOL_UDP};
+use super::NetError;
+use crate::sync::{SpinLock, WaitQueue};
+
+pub const HEADER_LEN: usize = 8;
+/// Most data a datagram can carry without being fragmented
+pub const MAX_DATA: usize =
+    super::MAX_FRAME - super::ethernet::HEADER_LEN - ipv4::HEADER_LEN - HEADER_LEN;
+/// Datagrams kept on a socket before newer ones are dropped
+pub const MAX_QUEUED: usize = 32;
+
+/// Where sockets bound to port 0 get their ports from, the dynamic range
+const FIRST_DYNAMIC_PORT: u16 = 49152;
+/// Where the search for a free dynamic port starts, only changed with [`SOCKETS`] locked
+static NEXT_DYNAMIC_PORT: AtomicU16 = AtomicU16::new(FIRST_DYNAMIC_PORT);
+
+struct Datagram {
+    source: Ipv4Address,
+    port: u16,
+    data: Vec<u8>,
+}
+
+struct Bound {
+    port: u16,
+    queue: VecDeque<Datagram>,
+}
+
+static SOCKETS: SpinLock<Vec<Bound>> = SpinLock::new("udp sockets", Vec::new());
+/// Woken whenever a datagram is queued on any socket
+static ARRIVED: WaitQueue = WaitQueue::new("udp");
+
+/// The checksum over the pseudo-header and `datagram`, which has its checksum field in it
+fn checksum(source: Ipv4Address, destination: Ipv4Address, datagram: &[u8]) -> u16 {
+    let mut sum = ipv4::sum(0, &source.0);
+    sum = ipv4::sum(sum, &destination.0);
+    sum = ipv4::sum(sum, &[0, PROTOCOL_UDP]);
+    sum = ipv4::sum(sum, &(datagram.len() as u16).to_be_bytes());
+    ipv4::fold(ipv4::sum(sum, datagram))
+}
+
+/// Write a datagram from `port` on `source` to `destination_port` on `destination` into
+/// `buf`, returning how long it is
+fn write(
+    buf: &mut [u8],
+    source: Ipv4Address,
+    port: u16,
+    destination: Ipv4Address,
+    destination_port: u16,
+    data: &[u8],
+) -> usize {
+    let len = HEADER_LEN + data.len();
+    buf[0..2].copy_from_slice(&port.to_be_bytes());
+    buf[2..4].copy_from_slice(&destination_port.to_be_bytes());
+    buf[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+    buf[6..8].fill(0);
+    buf[HEADER_LEN..len].copy_from_slice(data);
+    // 0 means there's no checksum, so one that comes out as 0 is sent as all ones
+    let checksum = match checksum(source, destination, &buf[..len]) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    buf[6..8].copy_from_slice(&checksum.to_be_bytes());
+    len
+}
+
+/// Send `data` from `port` to `destination_port` on `destination`, through the device
+/// [`route`](super::route) picks
+pub fn send(
+    port: u16,
+    destination: Ipv4Address,
+    destination_port: u16,
+    data: &[u8],
+) -> Result<(), NetError> {
+    if data.len() > MAX_DATA {
+        return Err(NetError::BadLength);
+    }
+    let source = ipv4::source_for(destination).ok_or(NetError::NoAddress)?;
+    let (device, mac) = super::route(destination)?;
+    ipv4::send(device, mac, destination, PROTOCOL_UDP, |buf| {
+        write(buf, source, port, destination, destination_port, data)
+    })
+}
+
+/// A datagram that came in, in a packet with `header`
+pub fn handle(header: &ipv4::Header, datagram: &[u8]) {
+    if datagram.len() < HEADER_LEN {
+        return;
+    }
+    let source_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    let has_checksum = datagram[6..8] != [0, 0];
+    if len < HEADER_LEN || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    if has_checksum && checksum(header.source, header.destination, datagram) != 0 {
+        return;
+    }
+
+    let mut sockets = SOCKETS.lock();
+    let Some(socket) = sockets.iter_mut().find(|socket| socket.port == port) else {
+        return;
+    };
+    if socket.queue.len() < MAX_QUEUED {
+        socket.queue.push_back(Datagram {
+            source: header.source,
+            port: source_port,
+            data: datagram[HEADER_LEN..].to_vec(),
+        });
+        drop(sockets);
+        ARRIVED.wake_all();
+    }
+}
+
+/// A bound port. Dropping it unbinds the port and throws away whatever's queued.
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Bind `port`, or a free one from the dynamic range for 0
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let taken = |port| sockets.iter().any(|socket: &Bound| socket.port == port);
+        let port = if port != 0 {
+            if taken(port) {
+                return Err(NetError::AddressInUse);
+            }
+            port
+        } else {
+            // the next one after the last handed out that's free, wrapping around
+            let count = u16::MAX - FIRST_DYNAMIC_PORT + 1;
+            let start = NEXT_DYNAMIC_PORT.load(Ordering::Relaxed) - FIRST_DYNAMIC_PORT;
+            let port = (0..count)
+                .map(|i| FIRST_DYNAMIC_PORT + (start + i) % count)
+                .find(|&port| !taken(port))
+                .ok_or(NetError::AddressInUse)?;
+            NEXT_DYNAMIC_PORT.store(
+                port.checked_add(1).unwrap_or(FIRST_DYNAMIC_PORT),
+                Ordering::Relaxed,
+            );
+            port
+        };
+        sockets.push(Bound {
+            port,
+            queue: VecDeque::new(),
+        });
+        Ok(UdpSocket { port })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Send `data` to `port` on `destination`
+    pub fn send_to(
+        &self,
+        data: &[u8],
+        destination: Ipv4Address,
+        port: u16,
+    ) -> Result<(), NetError> {
+        send(self.port, destination, port, data)
+    }
+
+    /// Take the next datagram, copying as much as fits into `buf`. Returns its length and who
+    /// sent it, None if nothing's come in.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> Option<(usize, Ipv4Address, u16)> {
+        let datagram = SOCKETS
+            .lock()
+            .iter_mut()
+            .find(|socket| socket.port == self.port)?
+            .queue
+            .pop_front()?;
+        let len = datagram.data.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram.data[..len]);
+        Some((datagram.data.len(), datagram.source, datagram.port))
+    }
+
+    /// Block until a datagram comes in, then take it like [`try_recv_from`](Self::try_recv_from)
+    pub fn recv_from(&self, buf: &mut [u8]) -> (usize, Ipv4Address, u16) {
+        let mut received = None;
+        ARRIVED.wait_until(|| {
+            received = self.try_recv_from(buf);
+            received.is_some()
+        });
+        received.unwrap()
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().retain(|socket| socket.port != self.port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn datagram_checksum() {
+        let source = Ipv4Address([10, 0, 2, 15]);
+        let destination = Ipv4Address([10, 0, 2, 2]);
+        let mut buf = [0; 64];
+        let len = write(&mut buf, source, 514, destination, 514, b"hello");
+        assert_eq!(len, HEADER_LEN + 5);
+        assert_eq!(checksum(source, destination, &buf[..len]), 0);
+    }
+
+    #[test_case]
+    fn sockets_queue_datagrams() {
+        let socket = UdpSocket::bind(0).unwrap();
+        assert!(socket.port() >= FIRST_DYNAMIC_PORT);
+        assert_eq!(
+            UdpSocket::bind(socket.port()).err(),
+            Some(NetError::AddressInUse)
+        );
+
+        let source = Ipv4Address([10, 0, 2, 2]);
+        let destination = Ipv4Address([10, 0, 2, 15]);
+        let mut datagram = [0; 64];
+        let len = write(
+            &mut datagram,
+            source,
+            7,
+            destination,
+            socket.port(),
+            b"ping",
+        );
+        let header = ipv4::Header {
+            source,
+            destination,
+            protocol: PROTOCOL_UDP,
+        };
+        handle(&header, &datagram[..len]);
+
+        let mut buf = [0; 16];
+        assert_eq!(socket.try_recv_from(&mut buf), Some((4, source, 7)));
+        assert_eq!(&buf[..4], b"ping");
+        assert_eq!(socket.try_recv_from(&mut buf), None);
+    }
+}
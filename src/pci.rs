@@ -0,0 +1,524 @@
+//! PCI configuration space
+//!
+//! Devices are found by trying every bus, device, and function. Slow, but there aren't that
+//! many functions and nothing has to know the topology. Configuration space is read through
+//! configuration access mechanism #1, the `0xcf8`/`0xcfc` port pair, until [`INIT`] finds the
+//! MCFG and maps PCI Express' memory mapped configuration space (ECAM), which every access
+//! goes through after that.
+//!
+//! [`INIT`] also scans the buses once, sizes every function's BARs, and logs what it found,
+//! one `lspci` line per function. Drivers look their hardware up in that list with [`find`]
+//! or [`find_by_class`]. Before [`INIT`] those scan the buses instead.
+//!
+//! links:
+//! - <https://wiki.osdev.org/PCI>
+//! - <https://wiki.osdev.org/PCI_Express>
+//! - vendor and class IDs: <https://pci-ids.ucw.cz/>
+//!
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use crate::arch::io::{Mmio, Port};
+use crate::init::{InitCall, Stage};
+use crate::mem::paging;
+use crate::sync::SpinLock;
+use crate::{acpi, ilog, wlog};
+
+const CONFIG_ADDRESS: Port<u32> = unsafe { Port::new(0xcf8) };
+const CONFIG_DATA: Port<u32> = unsafe { Port::new(0xcfc) };
+
+/// Vendor ID that reads back when there's no function at an address
+const NO_VENDOR: u16 = 0xffff;
+
+// configuration space offsets
+const VENDOR_ID: u8 = 0x00;
+const COMMAND: u8 = 0x04;
+const CLASS: u8 = 0x08;
+const HEADER_TYPE: u8 = 0x0e;
+const BAR0: u8 = 0x10;
+const CAPABILITIES: u8 = 0x34;
+const INTERRUPT_LINE: u8 = 0x3c;
+const INTERRUPT_PIN: u8 = 0x3d;
+
+/// Status register, the top half of the command one: there's a capability list
+const STATUS_CAPABILITIES: u32 = 1 << (16 + 4);
+
+/// Header type bit saying function 0 has siblings
+const MULTI_FUNCTION: u8 = 0x80;
+/// Header types, below the multi-function bit
+const HEADER_DEVICE: u8 = 0x00;
+const HEADER_BRIDGE: u8 = 0x01;
+
+/// Command register: the function answers I/O and memory accesses to its BARs
+const COMMAND_DECODE: u32 = 0b11;
+/// Command register: the function can do DMA
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+
+// BAR type bits
+const BAR_IO: u32 = 1 << 0;
+const BAR_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// Configuration space a bus takes up in ECAM
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+/// Where ECAM is mapped, for bus [`ECAM_BUSES`]' first bus. 0 until [`INIT`] maps it.
+static ECAM: AtomicU64 = AtomicU64::new(0);
+/// The first bus in ECAM, in the low byte, and the last one, in the next
+static ECAM_BUSES: AtomicU64 = AtomicU64::new(0);
+
+/// The port pair has to be used in pairs
+static PORTS: SpinLock<()> = SpinLock::new("pci ports", ());
+
+/// Every function, as [`INIT`] found them
+static DEVICES: SpinLock<Vec<Device>> = SpinLock::new("pci devices", Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    /// The register in ECAM, if this bus is in it
+    fn ecam(&self, offset: u8) -> Option<Mmio<u32>> {
+        let base = ECAM.load(Ordering::Relaxed);
+        let buses = ECAM_BUSES.load(Ordering::Relaxed);
+        let (start, end) = (buses as u8, (buses >> 8) as u8);
+        if base == 0 || !(start..=end).contains(&self.bus) {
+            return None;
+        }
+        let offset = (self.bus - start) as u64 * ECAM_BUS_SIZE
+            + ((self.device as u64) << 15)
+            + ((self.function as u64) << 12)
+            + (offset as u64 & 0xfc);
+        // INIT mapped every listed bus
+        Some(unsafe { Mmio::new(VirtAddr::new(base + offset)) })
+    }
+
+    fn port_address(&self, offset: u8) -> u32 {
+        0x8000_0000
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset as u32 & 0xfc)
+    }
+
+    pub fn read(&self, offset: u8) -> u32 {
+        if let Some(register) = self.ecam(offset) {
+            return register.read();
+        }
+        interrupts::without_interrupts(|| {
+            let _ports = PORTS.lock();
+            CONFIG_ADDRESS.write(self.port_address(offset));
+            CONFIG_DATA.read()
+        })
+    }
+
+    pub fn write(&self, offset: u8, value: u32) {
+        if let Some(register) = self.ecam(offset) {
+            register.write(value);
+            return;
+        }
+        interrupts::without_interrupts(|| {
+            let _ports = PORTS.lock();
+            CONFIG_ADDRESS.write(self.port_address(offset));
+            CONFIG_DATA.write(value);
+        })
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read(offset) >> ((offset & 3) * 8)) as u8
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// What a base address register points at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        /// It can be mapped write-combining, reads have no side effects
+        prefetchable: bool,
+        /// It takes up two BARs, the next one has the high half of the address
+        wide: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Bar::Memory {
+                address,
+                size,
+                prefetchable,
+                wide,
+            } => {
+                write!(f, "memory at {:#x}, {:#x} bytes", address, size)?;
+                if wide {
+                    write!(f, ", 64-bit")?;
+                }
+                if prefetchable {
+                    write!(f, ", prefetchable")?;
+                }
+                Ok(())
+            }
+            Bar::Io { port, size } => write!(f, "I/O ports at {:#x}, {} ports", port, size),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    /// Without the multi-function bit
+    pub header_type: u8,
+    /// The BARs that are in use, a 64-bit one takes two and the second stays None
+    pub bars: [Option<Bar>; 6],
+}
+
+impl Device {
+    fn probe(address: Address) -> Option<Device> {
+        let ids = address.read(VENDOR_ID);
+        if ids as u16 == NO_VENDOR {
+            return None;
+        }
+
+        let class = address.read(CLASS);
+        let header_type = address.read_u8(HEADER_TYPE) & !MULTI_FUNCTION;
+        let bar_count = match header_type {
+            HEADER_DEVICE => 6,
+            HEADER_BRIDGE => 2,
+            _ => 0,
+        };
+        Some(Device {
+            address,
+            vendor_id: ids as u16,
+            device_id: (ids >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            header_type,
+            bars: size_bars(address, bar_count),
+        })
+    }
+
+    /// Raw value of base address register `n`, which includes the type bits
+    pub fn bar(&self, n: u8) -> u32 {
+        self.address.read(BAR0 + n * 4)
+    }
+
+    /// The legacy IRQ the firmware wired its interrupt pin to
+    pub fn interrupt_line(&self) -> u8 {
+        self.address.read_u8(INTERRUPT_LINE)
+    }
+
+    /// The interrupt pin it uses, 1 for INTA through 4 for INTD, or 0 for none
+    pub fn interrupt_pin(&self) -> u8 {
+        self.address.read_u8(INTERRUPT_PIN)
+    }
+
+    /// The IRQ its interrupt comes in on. With the `aml` feature that's what the firmware's
+    /// `_PRT` says, when it says, otherwise it's the [`interrupt_line`](Self::interrupt_line).
+    pub fn irq(&self) -> u8 {
+        #[cfg(feature = "aml")]
+        if let Some(irq) = crate::aml::pci_irq(self) {
+            return irq;
+        }
+        self.interrupt_line()
+    }
+
+    /// Where each of its capabilities starts in configuration space, with its ID
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        let first = if self.address.read(COMMAND) & STATUS_CAPABILITIES != 0 {
+            self.address.read_u8(CAPABILITIES) & !0b11
+        } else {
+            0
+        };
+        // the list is in the 192 bytes after the header, so a broken one still ends
+        let mut next = first;
+        (0..48).map_while(move |_| {
+            if next == 0 {
+                return None;
+            }
+            let at = next;
+            next = self.address.read_u8(at + 1) & !0b11;
+            Some((at, self.address.read_u8(at)))
+        })
+    }
+
+    /// Let it answer accesses to its BARs and do DMA, which firmware doesn't always set up
+    pub fn enable(&self) {
+        let command = self.address.read(COMMAND) & 0xffff;
+        self.address
+            .write(COMMAND, command | COMMAND_DECODE | COMMAND_BUS_MASTER);
+    }
+}
+
+/// The `lspci` line for it
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {:04x}:{:04x}",
+            self.address,
+            class_name(self.class, self.subclass),
+            self.vendor_id,
+            self.device_id
+        )?;
+        match vendor_name(self.vendor_id) {
+            Some(vendor) => write!(f, " {}", vendor),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Find out what the first `count` BARs at `address` point at and how big they are, by
+/// writing all ones to each and seeing which bits stick
+fn size_bars(address: Address, count: u8) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    interrupts::without_interrupts(|| {
+        // nothing can be decoded while a BAR holds all ones
+        let command = address.read(COMMAND) & 0xffff;
+        address.write(COMMAND, command & !COMMAND_DECODE);
+
+        let probe = |n: u8| {
+            let offset = BAR0 + n * 4;
+            let value = address.read(offset);
+            address.write(offset, u32::MAX);
+            let mask = address.read(offset);
+            address.write(offset, value);
+            (value, mask)
+        };
+        let mut n = 0;
+        while n < count {
+            let (value, mask) = probe(n);
+            let slot = n as usize;
+            n += 1;
+            if mask == 0 {
+                continue;
+            }
+            if value & BAR_IO != 0 {
+                bars[slot] = Some(Bar::Io {
+                    port: (value & !0b11) as u16,
+                    size: (!(mask & !0b11) as u16).wrapping_add(1),
+                });
+                continue;
+            }
+
+            let wide = value & BAR_64 != 0 && n < count;
+            let (mut address, mut mask) = ((value & !0xf) as u64, (mask & !0xf) as u64);
+            if wide {
+                let (high, high_mask) = probe(n);
+                n += 1;
+                address |= (high as u64) << 32;
+                mask |= (high_mask as u64) << 32;
+            } else {
+                mask |= 0xffff_ffff << 32;
+            }
+            bars[slot] = Some(Bar::Memory {
+                address,
+                size: (!mask).wrapping_add(1),
+                prefetchable: value & BAR_PREFETCHABLE != 0,
+                wide,
+            });
+        }
+
+        address.write(COMMAND, command);
+    });
+    bars
+}
+
+/// Call `f` with every function on every bus
+pub fn scan(mut f: impl FnMut(Device)) {
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let address = Address {
+                bus,
+                device,
+                function: 0,
+            };
+            let Some(first) = Device::probe(address) else {
+                continue;
+            };
+            f(first);
+
+            if address.read_u8(HEADER_TYPE) & MULTI_FUNCTION == 0 {
+                continue;
+            }
+            for function in 1..8 {
+                if let Some(other) = Device::probe(Address {
+                    function,
+                    ..address
+                }) {
+                    f(other);
+                }
+            }
+        }
+    }
+}
+
+/// Call `f` with every function [`INIT`] found, or scan for them if it hasn't run yet. Doesn't
+/// allocate, so drivers that start before the heap can use it.
+fn for_each(mut f: impl FnMut(Device)) {
+    let devices = DEVICES.lock();
+    if devices.is_empty() {
+        drop(devices);
+        scan(f);
+        return;
+    }
+    for &device in devices.iter() {
+        f(device);
+    }
+}
+
+/// Every function, in bus order
+pub fn devices() -> Vec<Device> {
+    let mut devices = Vec::new();
+    for_each(|device| devices.push(device));
+    devices
+}
+
+/// The first function with these IDs
+pub fn find(vendor_id: u16, device_id: u16) -> Option<Device> {
+    let mut found = None;
+    for_each(|device| {
+        if found.is_none() && device.vendor_id == vendor_id && device.device_id == device_id {
+            found = Some(device);
+        }
+    });
+    found
+}
+
+/// Every function of this class and subclass, in bus order
+pub fn find_by_class(class: u8, subclass: u8) -> Vec<Device> {
+    let mut found = Vec::new();
+    for_each(|device| {
+        if device.class == class && device.subclass == subclass {
+            found.push(device);
+        }
+    });
+    found
+}
+
+/// Name of a vendor that's likely to show up in a VM
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    Some(match vendor_id {
+        0x1022 => "AMD",
+        0x10de => "NVIDIA",
+        0x10ec => "Realtek",
+        0x1234 => "QEMU/Bochs",
+        0x15ad => "VMware",
+        0x1af4 => "Red Hat (virtio)",
+        0x1b36 => "Red Hat (QEMU)",
+        0x8086 => "Intel",
+        0x80ee => "VirtualBox",
+        _ => return None,
+    })
+}
+
+/// Name of a device class, more specific when the subclass is a common one
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, 0x00) => "VGA controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x09, _) => "input controller",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus controller",
+        (0x0c, _) => "serial bus controller",
+        _ => "unknown device",
+    }
+}
+
+/// Map the configuration space the MCFG lists, so it's used from now on
+fn map_ecam() {
+    let Some(mcfg) = acpi::mcfg() else {
+        return;
+    };
+    let buses = (mcfg.end_bus - mcfg.start_bus) as u64 + 1;
+    match unsafe { paging::map_mmio(mcfg.address, buses * ECAM_BUS_SIZE) } {
+        Ok(base) => {
+            ECAM_BUSES.store(
+                mcfg.start_bus as u64 | (mcfg.end_bus as u64) << 8,
+                Ordering::Relaxed,
+            );
+            ECAM.store(base.as_u64(), Ordering::Relaxed);
+            ilog!(
+                "pci: ECAM at {:#x} for buses {}-{}",
+                mcfg.address.as_u64(),
+                mcfg.start_bus,
+                mcfg.end_bus
+            );
+        }
+        Err(error) => wlog!("pci: couldn't map ECAM, using ports: {:?}", error),
+    }
+}
+
+fn init() {
+    map_ecam();
+
+    let mut devices = Vec::new();
+    scan(|device| devices.push(device));
+    for device in &devices {
+        ilog!("pci: {}", device);
+    }
+    *DEVICES.lock() = devices;
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "pci",
+    stage: Stage::Drivers,
+    after: &[],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn finds_host_bridge() {
+        let bridges = find_by_class(0x06, 0x00);
+        assert!(!bridges.is_empty());
+        let bridge = bridges[0];
+        assert_eq!(
+            find(bridge.vendor_id, bridge.device_id).map(|d| d.address),
+            Some(bridge.address)
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! Per-CPU data
+//!
+//! Each CPU has a block of its own, and GS points at it, so code can get at this CPU's data
+//! without locks. The block starts with a header ([`cpu_id`], [`apic_id`]) and is followed by
+//! a copy of every variable declared with [`cpu_local!`](crate::cpu_local):
+//!
+//! ```ignore
+//! cpu_local! {
+//!     /// Interrupts handled on this CPU
+//!     static HANDLED: Cell<u64> = Cell::new(0);
+//! }
+//!
+//! HANDLED.with(|handled| handled.set(handled.get() + 1));
+//! ```
+//!
+//! The variables are really kept in the `.percpu` section, which is only a template: [`new_block`]
+//! copies it into each CPU's block, and accesses go to the copy at the same offset from the
+//! block. They're copied byte for byte, so their initial values shouldn't point into themselves.
+//! Nothing can use them before this CPU's block is set up, the boot CPU's in the memory stage.
+//!
+//! User mode can load a GS selector of its own, which changes the GS base, so GS only points at
+//! the block while the CPU is in the kernel. In user mode the block is kept in KERNEL_GS_BASE
+//! instead, and every way into the kernel from user mode starts with a `swapgs` and every way
+//! back ends with one: the syscall entry code does it itself, and interrupt handlers do it
+//! with a [`KernelGs`] before anything else. The GS base user mode had isn't kept per thread.
+//!
+//! links:
+//! - <https://wiki.osdev.org/SWAPGS>
+//!
+
+use alloc::alloc::alloc_zeroed;
+use core::alloc::Layout;
+use core::arch::asm;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+use crate::arch::msr;
+use crate::cpu::features;
+use crate::init::{InitCall, Stage};
+
+extern "C" {
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+}
+
+/// The start of each CPU's block
+#[repr(C)]
+struct Header {
+    /// The block's own address
+    this: u64,
+    /// Where this CPU's copy of the `.percpu` section is
+    locals: u64,
+    cpu: u64,
+    apic_id: u64,
+    /// Top of the stack [`syscall`](crate::syscall)s run on
+    syscall_stack: u64,
+    /// Where the user's stack pointer is kept while a syscall runs
+    user_stack: u64,
+}
+
+/// Offsets of the header fields the syscall entry code reaches through GS
+pub const SYSCALL_STACK_OFFSET: usize = offset_of!(Header, syscall_stack);
+pub const USER_STACK_OFFSET: usize = offset_of!(Header, user_stack);
+
+/// The copy of the `.percpu` section is aligned this much, which is as much as any variable in
+/// it can be
+const LOCALS_ALIGN: usize = 64;
+
+/// Read a field of this CPU's header
+macro_rules! header_field {
+    ($field:ident) => {{
+        let value: u64;
+        unsafe {
+            asm!(
+                "mov {}, gs:[{offset}]",
+                out(reg) value,
+                offset = const offset_of!(Header, $field),
+                options(nostack, readonly, preserves_flags),
+            );
+        }
+        value
+    }};
+}
+
+/// Set once the boot CPU has its block. Before that it's the only one running, and after it
+/// every CPU has one from the start.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Whether this CPU has its block, and [`CpuLocal`]s can be used
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// This CPU's number, 0 for the boot CPU and counting up as [`smp`](crate::smp) starts the rest.
+/// It's 0 before there are blocks too, when only the boot CPU runs.
+pub fn cpu_id() -> usize {
+    if !is_ready() {
+        return 0;
+    }
+    header_field!(cpu) as usize
+}
+
+pub fn apic_id() -> u8 {
+    header_field!(apic_id) as u8
+}
+
+/// Have syscalls on this CPU run on the stack ending at `top`
+pub fn set_syscall_stack(top: VirtAddr) {
+    let header = header_field!(this) as *mut Header;
+    unsafe { (&raw mut (*header).syscall_stack).write_volatile(top.as_u64()) };
+}
+
+fn swapgs() {
+    unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+}
+
+/// Has GS on this CPU's block while an interrupt handler runs, by swapping it in if the
+/// interrupt came from user mode, and back out when it's dropped. Handlers make one before
+/// anything else, since anything could use `percpu`.
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    /// For the interrupt that pushed `frame`
+    pub fn enter(frame: &InterruptStackFrame) -> KernelGs {
+        let swapped = frame.code_segment.rpl() == PrivilegeLevel::Ring3;
+        if swapped {
+            swapgs();
+        }
+        KernelGs { swapped }
+    }
+
+    /// For an interrupt that can come in while the kernel still has the user's GS loaded, like
+    /// an NMI right on the `syscall` or a fault on the `iretq` back: goes by which base is
+    /// loaded rather than where it came from. User mode can only have a lower half one.
+    pub fn enter_paranoid() -> KernelGs {
+        let swapped = msr::gs_base().as_u64() < crate::mem::PHYS_OFFSET;
+        if swapped {
+            swapgs();
+        }
+        KernelGs { swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            swapgs();
+        }
+    }
+}
+
+/// Swap the user's GS base in, on the way out to user mode with interrupts off
+///
+/// # Safety
+///
+/// Nothing can use `percpu` until the CPU is back in the kernel.
+pub unsafe fn leave_for_user() {
+    swapgs();
+}
+
+/// A variable with a copy on every CPU, declared with [`cpu_local!`](crate::cpu_local)
+pub struct CpuLocal<T: 'static> {
+    template: &'static Template<T>,
+}
+
+/// The `.percpu` section's copy of a variable, which is never used itself
+#[doc(hidden)]
+pub struct Template<T>(T);
+
+// only the copies get used, and each of those by just one CPU
+unsafe impl<T> Sync for Template<T> {}
+
+impl<T> Template<T> {
+    pub const fn new(value: T) -> Template<T> {
+        Template(value)
+    }
+}
+
+impl<T: 'static> CpuLocal<T> {
+    #[doc(hidden)]
+    pub const fn new(template: &'static Template<T>) -> CpuLocal<T> {
+        CpuLocal { template }
+    }
+
+    /// Run `f` on this CPU's copy. Interrupts are off meanwhile, so neither an interrupt handler
+    /// nor a switch to another CPU can come in between.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let offset = &raw const self.template.0 as usize - &raw const __percpu_start as usize;
+        interrupts::without_interrupts(|| {
+            let locals = header_field!(locals) as usize;
+            f(unsafe { &*((locals + offset) as *const T) })
+        })
+    }
+}
+
+/// Declare variables each CPU has its own copy of, see [`percpu`](crate::percpu)
+#[macro_export]
+macro_rules! cpu_local {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::CpuLocal<$ty> = {
+                #[link_section = ".percpu"]
+                static TEMPLATE: $crate::percpu::Template<$ty> =
+                    $crate::percpu::Template::new($init);
+                $crate::percpu::CpuLocal::new(&TEMPLATE)
+            };
+        )*
+    };
+}
+
+/// Make a block for CPU `cpu`, which [`load`]s it. The boot CPU makes those of the others
+/// before it starts them, so they never run without one.
+pub fn new_block(cpu: usize, apic_id: u8) -> VirtAddr {
+    let start = &raw const __percpu_start;
+    let len = &raw const __percpu_end as usize - start as usize;
+    let locals_offset = size_of::<Header>().next_multiple_of(LOCALS_ALIGN);
+    let layout = Layout::from_size_align(locals_offset + len, LOCALS_ALIGN).unwrap();
+
+    // the CPU never goes away, and neither does its block
+    let block = unsafe { alloc_zeroed(layout) };
+    assert!(
+        !block.is_null(),
+        "percpu: no memory for cpu {}'s block",
+        cpu
+    );
+    unsafe {
+        let locals = block.add(locals_offset);
+        core::ptr::copy_nonoverlapping(start, locals, len);
+        block.cast::<Header>().write(Header {
+            this: block as u64,
+            locals: locals as u64,
+            cpu: cpu as u64,
+            apic_id: apic_id as u64,
+            syscall_stack: 0,
+            user_stack: 0,
+        });
+    }
+
+    VirtAddr::from_ptr(block)
+}
+
+/// Point GS at `block`. User mode starts with a GS base of 0, which is in KERNEL_GS_BASE until
+/// the first `swapgs` on the way out.
+///
+/// # Safety
+///
+/// `block` has to be from [`new_block`], for this CPU.
+pub unsafe fn load(block: VirtAddr) {
+    msr::set_gs_base(block);
+    msr::set_kernel_gs_base(VirtAddr::zero());
+}
+
+fn init() {
+    let apic_id = features::info().map_or(0, |info| info.apic_id as u8);
+    unsafe { load(new_block(0, apic_id)) };
+    READY.store(true, Ordering::Release);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "percpu",
+    stage: Stage::Memory,
+    after: &["heap"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    crate::cpu_local! {
+        static COUNTER: Cell<u64> = Cell::new(5);
+    }
+
+    #[test_case]
+    fn boot_cpu_is_zero() {
+        assert_eq!(cpu_id(), 0);
+    }
+
+    /// The copy starts out with the initial value and keeps what's written to it
+    #[test_case]
+    fn cpu_local_keeps_value() {
+        COUNTER.with(|counter| {
+            assert_eq!(counter.get(), 5);
+            counter.set(counter.get() + 1);
+        });
+        assert_eq!(COUNTER.with(Cell::get), 6);
+        // the template is left alone
+        assert_eq!(COUNTER.template.0.get(), 5);
+    }
+}
@@ -0,0 +1,215 @@
+//! 8259 programmable interrupt controllers
+//!
+//! The two chained PICs deliver the 16 legacy hardware IRQs. Out of reset they use vectors
+//! 8-15 for IRQs 0-7, which collide with CPU exceptions, so they're remapped to [`IRQ_BASE`]
+//! and up. IRQ 2 is where the secondary PIC is chained into the primary one.
+//!
+//! All IRQs start out masked. A driver registers a handler with [`set_handler`], which also
+//! unmasks the IRQ. Handlers run with interrupts off, and the end of interrupt is sent after
+//! they return.
+//!
+//! Once the [`apic`](crate::apic) module has taken over, the PICs stay masked and the same IRQs
+//! come through the I/O APIC on the same vectors. Masking and end of interrupt go to the APICs
+//! then, and nothing else changes for drivers.
+//!
+//! links:
+//! - <https://wiki.osdev.org/8259_PIC>
+//! - datasheet: <https://pdos.csail.mit.edu/6.828/2005/readings/hardware/8259A.pdf>
+//!
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use crate::arch::io::Port;
+use crate::init::{InitCall, Stage};
+use crate::{apic, rand, sched};
+
+/// Vector of IRQ 0, IRQ n is at `IRQ_BASE + n`
+pub const IRQ_BASE: u8 = 32;
+pub const IRQ_COUNT: u8 = 16;
+
+/// IRQ the secondary PIC is connected to on the primary one
+const CASCADE_IRQ: u8 = 2;
+
+const PRIMARY_COMMAND: u16 = 0x20;
+const PRIMARY_DATA: u16 = 0x21;
+const SECONDARY_COMMAND: u16 = 0xa0;
+const SECONDARY_DATA: u16 = 0xa1;
+
+/// ICW1: initialize, and an ICW4 follows
+const ICW1_INIT: u8 = 0x11;
+/// ICW4: 8086 mode
+const ICW4_8086: u8 = 0x01;
+/// OCW2: non-specific end of interrupt
+const EOI: u8 = 0x20;
+/// OCW3: the next command port read returns the in-service register
+const READ_ISR: u8 = 0x0b;
+
+/// Handlers by IRQ, as `fn()` pointers with 0 for none. Atomics rather than a lock, since
+/// they're read from interrupt context.
+static HANDLERS: [AtomicUsize; IRQ_COUNT as usize] =
+    [const { AtomicUsize::new(0) }; IRQ_COUNT as usize];
+/// Where the CPU was when the IRQ being handled came in
+static INTERRUPTED_AT: AtomicU64 = AtomicU64::new(0);
+
+fn outb(port: u16, value: u8) {
+    // the old PICs need time to react between writes
+    unsafe { Port::new(port) }.write_delayed(value);
+}
+
+fn inb(port: u16) -> u8 {
+    unsafe { Port::<u8>::new(port) }.read()
+}
+
+/// The data port and bit for `irq`'s mask
+fn mask_bit(irq: u8) -> (u16, u8) {
+    assert!(irq < IRQ_COUNT, "pic: no IRQ {}", irq);
+    if irq < 8 {
+        (PRIMARY_DATA, 1 << irq)
+    } else {
+        (SECONDARY_DATA, 1 << (irq - 8))
+    }
+}
+
+/// Stop `irq` from being delivered
+pub fn mask(irq: u8) {
+    if apic::enabled() {
+        return apic::set_masked(irq, true);
+    }
+    let (port, bit) = mask_bit(irq);
+    interrupts::without_interrupts(|| outb(port, inb(port) | bit));
+}
+
+/// Let `irq` be delivered
+pub fn unmask(irq: u8) {
+    if apic::enabled() {
+        return apic::set_masked(irq, false);
+    }
+    let (port, bit) = mask_bit(irq);
+    interrupts::without_interrupts(|| outb(port, inb(port) & !bit));
+}
+
+/// Tell the PICs `irq` has been handled, so they deliver the next one
+pub fn end_of_interrupt(irq: u8) {
+    if apic::enabled() {
+        return apic::end_of_interrupt();
+    }
+    if irq >= 8 {
+        outb(SECONDARY_COMMAND, EOI);
+    }
+    outb(PRIMARY_COMMAND, EOI);
+}
+
+/// Whether the PIC is really servicing `irq`, which is only in question for IRQs 7 and 15:
+/// those are also delivered when a request goes away before it's acknowledged.
+fn in_service(irq: u8) -> bool {
+    let command = if irq < 8 {
+        PRIMARY_COMMAND
+    } else {
+        SECONDARY_COMMAND
+    };
+    outb(command, READ_ISR);
+    inb(command) & (1 << (irq % 8)) != 0
+}
+
+/// Mask every IRQ on both PICs, for when the APICs take over
+pub fn mask_all() {
+    outb(PRIMARY_DATA, 0xff);
+    outb(SECONDARY_DATA, 0xff);
+}
+
+/// Call `handler` for every `irq` from now on, and unmask it
+pub fn set_handler(irq: u8, handler: fn()) {
+    HANDLERS[irq as usize].store(handler as usize, Ordering::Release);
+    unmask(irq);
+}
+
+/// Interrupts `irq` has delivered since boot
+pub fn count(irq: u8) -> u64 {
+    crate::interrupts::count(IRQ_BASE + irq)
+}
+
+/// The instruction the boot CPU was at when the IRQ being handled came in, for handlers that
+/// want to know what they interrupted
+pub fn interrupted_at() -> u64 {
+    INTERRUPTED_AT.load(Ordering::Relaxed)
+}
+
+/// Whether `irq` has a handler
+pub fn has_handler(irq: u8) -> bool {
+    HANDLERS[irq as usize].load(Ordering::Relaxed) != 0
+}
+
+fn dispatch(irq: u8, frame: &InterruptStackFrame) {
+    INTERRUPTED_AT.store(frame.instruction_pointer.as_u64(), Ordering::Relaxed);
+    if !apic::enabled() && irq % 8 == 7 && !in_service(irq) {
+        // spurious, the primary PIC still needs its EOI if it came through the secondary one
+        if irq == 15 {
+            outb(PRIMARY_COMMAND, EOI);
+        }
+        crate::interrupts::record_spurious();
+        return;
+    }
+
+    crate::interrupts::record(IRQ_BASE + irq);
+    rand::add_interrupt(irq);
+    let handler = HANDLERS[irq as usize].load(Ordering::Acquire);
+    if handler != 0 {
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+    end_of_interrupt(irq);
+    sched::preempt();
+}
+
+/// Interrupt handlers for each IRQ, they all go through `dispatch`
+macro_rules! irq_stubs {
+    ($($irq:literal => $stub:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $stub(frame: InterruptStackFrame) {
+                let _gs = crate::percpu::KernelGs::enter(&frame);
+                dispatch($irq, &frame);
+            }
+        )*
+
+        /// Point the IRQ vectors in `idt` at the dispatcher
+        pub fn install(idt: &mut InterruptDescriptorTable) {
+            $(idt[IRQ_BASE + $irq].set_handler_fn($stub);)*
+        }
+    };
+}
+
+irq_stubs! {
+    0 => irq0, 1 => irq1, 2 => irq2, 3 => irq3,
+    4 => irq4, 5 => irq5, 6 => irq6, 7 => irq7,
+    8 => irq8, 9 => irq9, 10 => irq10, 11 => irq11,
+    12 => irq12, 13 => irq13, 14 => irq14, 15 => irq15,
+}
+
+/// Remap both PICs, mask everything but the cascade, and turn interrupts on
+fn init() {
+    outb(PRIMARY_COMMAND, ICW1_INIT);
+    outb(SECONDARY_COMMAND, ICW1_INIT);
+    // ICW2: vector offsets
+    outb(PRIMARY_DATA, IRQ_BASE);
+    outb(SECONDARY_DATA, IRQ_BASE + 8);
+    // ICW3: which IRQ line the secondary is on, as a bit for the primary and a number for it
+    outb(PRIMARY_DATA, 1 << CASCADE_IRQ);
+    outb(SECONDARY_DATA, CASCADE_IRQ);
+    outb(PRIMARY_DATA, ICW4_8086);
+    outb(SECONDARY_DATA, ICW4_8086);
+
+    outb(PRIMARY_DATA, !(1 << CASCADE_IRQ));
+    outb(SECONDARY_DATA, 0xff);
+
+    interrupts::enable();
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "pic",
+    stage: Stage::Interrupts,
+    after: &["idt"],
+    func: init,
+};
@@ -0,0 +1,357 @@
+//! Power management
+//!
+//! `shutdown()` enters S5 with the PM1 control ports and sleep type that the
+//! [FADT](crate::acpi::Fadt) gives, switching the chipset into ACPI mode first if it isn't.
+//! Without ACPI, or if that doesn't work, it tries the PM1a ports that the common emulators
+//! hardwire, and then QEMU's [isa-debug-exit](crate::qemu) device. `reboot()` writes the FADT
+//! reset register if there is one, then goes through the keyboard controller, with a triple
+//! fault as the last resort.
+//!
+//! What a panic ends with is up to `panic=ACTION` on the command line, see [`after_panic`].
+//! `panic=reboot,SECONDS` leaves the report up that long before restarting.
+//!
+//! With ACPI, [`INIT`] also switches the chipset into ACPI mode and sets PWRBTN_EN in the PM1
+//! event blocks, so pressing the power button raises the SCI on the IRQ the FADT names. The
+//! handler clears the status bits, and when PWRBTN_STS is one of them it wakes a thread that
+//! logs it and calls `shutdown()`, outside the interrupt where the FADT can be locked.
+//!
+//! links:
+//! - ACPI spec, PM1 control register: <https://uefi.org/specs/ACPI/6.5/04_ACPI_Hardware_Specification.html#pm1-control-registers>
+//! - ACPI spec, PM1 event registers: <https://uefi.org/specs/ACPI/6.5/04_ACPI_Hardware_Specification.html#pm1-event-grouping>
+//! - osdev shutdown notes: <https://wiki.osdev.org/Shutdown>
+//! - osdev reboot notes: <https://wiki.osdev.org/Reboot>
+//! - 8042 controller: <https://wiki.osdev.org/%228042%22_PS/2_Controller>
+//!
+
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use x86_64::instructions::tables::lidt;
+use x86_64::instructions::{hlt, interrupts};
+use x86_64::structures::port::{PortRead as _, PortWrite as _};
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use crate::init::{InitCall, Stage};
+use crate::sync::WaitQueue;
+use crate::{acpi, apic, cmdline, ilog, pic, qemu, sched, timer, wlog};
+
+/// SLP_EN, bit 13 of PM1x_CNT. Setting it enters the sleep state selected by SLP_TYPx.
+const SLP_EN: u16 = 1 << 13;
+/// Where SLP_TYPx goes in PM1x_CNT
+const SLP_TYP_SHIFT: u16 = 10;
+/// SCI_EN, bit 0 of PM1x_CNT, set once the chipset is in ACPI mode
+const SCI_EN: u16 = 1;
+/// PWRBTN_STS in PM1x_STS and PWRBTN_EN in PM1x_EN, bit 8 of both
+const PWRBTN: u16 = 1 << 8;
+
+/// Known (port, value) pairs for entering S5 through the PM1a control block
+const PM1A_SOFT_OFF: [(u16, u16); 3] = [
+    // QEMU since 2.0 (PIIX4 PM at 0x600, S5 SLP_TYP is 0)
+    (0x604, SLP_EN),
+    // Bochs and older QEMU (PM at 0xb000)
+    (0xb004, SLP_EN),
+    // VirtualBox (S5 SLP_TYP is 5)
+    (0x4004, SLP_EN | (5 << 10)),
+];
+
+/// 8042 status register (read) and command register (write) share a port
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+
+/// Status bit that's set while the controller still has a byte in its input buffer
+const KBC_INPUT_FULL: u8 = 1 << 1;
+
+/// Controller command that pulses the output line wired to CPU reset
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+/// How many times to poll the controller before giving up on it
+const KBC_SPIN: usize = 100_000;
+
+/// How long each of the waits before a reboot is, under the 54 ms the PIT can count
+const WAIT_STEP_MS: u64 = 50;
+
+/// Power off the machine
+///
+/// If none of the known soft-off paths work, this falls back to halting the CPU.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+
+    if let Some(fadt) = acpi::fadt() {
+        acpi_shutdown(&fadt);
+    }
+    for (port, value) in PM1A_SOFT_OFF {
+        unsafe {
+            u16::write_to_port(port, value);
+        }
+    }
+    qemu::try_exit(qemu::ExitCode::Success);
+
+    // still here, so we're on something that doesn't use any of the hardwired ports
+    crate::println!("shutdown failed, it's now safe to turn off your computer");
+    halt()
+}
+
+/// Enter S5 the way the FADT says to, returning if it can't or it doesn't work
+fn acpi_shutdown(fadt: &acpi::Fadt) {
+    let Some((slp_typ_a, slp_typ_b)) = fadt.s5 else {
+        return;
+    };
+    if fadt.pm1a_control == 0 {
+        return;
+    }
+    enable_acpi_mode(fadt);
+    unsafe {
+        let control = |port: u16, slp_typ: u8| {
+            let value = u16::read_from_port(port) & !(7 << SLP_TYP_SHIFT);
+            u16::write_to_port(port, value | ((slp_typ as u16) << SLP_TYP_SHIFT) | SLP_EN);
+        };
+        control(fadt.pm1a_control, slp_typ_a);
+        if fadt.pm1b_control != 0 {
+            control(fadt.pm1b_control, slp_typ_b);
+        }
+    }
+    // the machine is off by the time the write finishes, or it isn't going to turn off
+    for _ in 0..KBC_SPIN {
+        core::hint::spin_loop();
+    }
+}
+
+/// Hand the chipset's power management over from SMM to us, if it isn't already, through
+/// the SMI command port. It's done once SCI_EN reads back set.
+fn enable_acpi_mode(fadt: &acpi::Fadt) {
+    unsafe {
+        if u16::read_from_port(fadt.pm1a_control) & SCI_EN == 0 && fadt.smi_command != 0 {
+            u8::write_to_port(fadt.smi_command, fadt.acpi_enable);
+            for _ in 0..KBC_SPIN {
+                if u16::read_from_port(fadt.pm1a_control) & SCI_EN != 0 {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// The PM1a and PM1b status ports, 0 for one that isn't there. Copied out of the FADT so the
+/// SCI doesn't have to lock it.
+static PM1A_STATUS: AtomicU16 = AtomicU16::new(0);
+static PM1B_STATUS: AtomicU16 = AtomicU16::new(0);
+
+/// Set by the SCI when the power button's been pressed
+static PRESSED: AtomicBool = AtomicBool::new(false);
+static BUTTON: WaitQueue = WaitQueue::new("power button");
+
+fn status_ports() -> impl Iterator<Item = u16> {
+    [&PM1A_STATUS, &PM1B_STATUS]
+        .into_iter()
+        .map(|port| port.load(Ordering::Relaxed))
+        .filter(|&port| port != 0)
+}
+
+/// The SCI handler. The status bits are write-one-to-clear, and the SCI stays asserted until
+/// every one that's enabled is cleared.
+fn sci() {
+    let mut status = 0;
+    for port in status_ports() {
+        unsafe {
+            let bits = u16::read_from_port(port);
+            u16::write_to_port(port, bits);
+            status |= bits;
+        }
+    }
+    if status & PWRBTN != 0 {
+        PRESSED.store(true, Ordering::Relaxed);
+        BUTTON.wake_all();
+    }
+}
+
+fn button_thread() {
+    BUTTON.wait_until(|| PRESSED.load(Ordering::Relaxed));
+    ilog!("power: power button pressed, shutting down");
+    shutdown()
+}
+
+/// Restart the machine
+///
+/// Tries the FADT reset register and then the 8042 keyboard controller's reset line, and
+/// forces a triple fault if the machine is still running after that.
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    if let Some(acpi::Fadt {
+        reset_port: Some(port),
+        reset_value,
+        ..
+    }) = acpi::fadt()
+    {
+        unsafe {
+            u8::write_to_port(port, reset_value);
+        }
+        for _ in 0..KBC_SPIN {
+            core::hint::spin_loop();
+        }
+    }
+
+    unsafe {
+        // the command gets dropped if the controller is still busy with a previous byte
+        for _ in 0..KBC_SPIN {
+            if u8::read_from_port(KBC_STATUS) & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        u8::write_to_port(KBC_COMMAND, KBC_PULSE_RESET);
+    }
+
+    // give the reset a moment to land
+    for _ in 0..KBC_SPIN {
+        core::hint::spin_loop();
+    }
+
+    // with an empty IDT there's nowhere to deliver the breakpoint, so it escalates through a
+    // double fault to a triple fault, which resets the CPU
+    unsafe {
+        lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::zero(),
+        });
+    }
+    interrupts::int3();
+
+    halt()
+}
+
+/// What a panicking kernel does once it's printed everything
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    /// Stop, the default
+    Halt,
+    /// Restart after this many seconds, so the report can be read first
+    Reboot(u64),
+    Shutdown,
+}
+
+/// A `panic=` value that isn't one of the actions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseActionError;
+
+impl FromStr for PanicAction {
+    type Err = ParseActionError;
+
+    fn from_str(s: &str) -> Result<PanicAction, ParseActionError> {
+        match s.split_once(',') {
+            None if s == "halt" => Ok(PanicAction::Halt),
+            None if s == "reboot" => Ok(PanicAction::Reboot(0)),
+            None if s == "shutdown" => Ok(PanicAction::Shutdown),
+            Some(("reboot", secs)) => secs
+                .parse()
+                .map(PanicAction::Reboot)
+                .map_err(|_| ParseActionError),
+            _ => Err(ParseActionError),
+        }
+    }
+}
+
+/// The action `panic=` on the command line asks for: `halt`, `reboot`, `reboot,SECONDS`, or
+/// `shutdown`. Anything else halts.
+pub fn panic_action() -> PanicAction {
+    match cmdline::parse("panic") {
+        Ok(Some(action)) => action,
+        _ => PanicAction::Halt,
+    }
+}
+
+/// Do the [`panic_action`]
+pub fn after_panic() -> ! {
+    match panic_action() {
+        PanicAction::Halt => halt(),
+        PanicAction::Reboot(secs) => {
+            if secs > 0 {
+                crate::println!("rebooting in {} seconds", secs);
+                wait_secs(secs);
+            }
+            reboot()
+        }
+        PanicAction::Shutdown => shutdown(),
+    }
+}
+
+/// Spin for `secs` seconds. A panic can come before the scheduler or with interrupts off, so
+/// this counts on [`timer::wait_ms`], which waits on the PIT or the HPET without either.
+fn wait_secs(secs: u64) {
+    for _ in 0..secs.saturating_mul(1000) / WAIT_STEP_MS {
+        timer::wait_ms(WAIT_STEP_MS);
+    }
+}
+
+/// Stop executing for good
+pub fn halt() -> ! {
+    interrupts::disable();
+    loop {
+        hlt();
+    }
+}
+
+fn init() {
+    let Some(fadt) = acpi::fadt() else {
+        return;
+    };
+    if fadt.pm1a_event == 0 || fadt.pm1a_control == 0 || fadt.pm1_event_len < 4 {
+        wlog!("power: no PM1 event block, the power button won't do anything");
+        return;
+    }
+    if fadt.sci_interrupt >= pic::IRQ_COUNT {
+        wlog!(
+            "power: the SCI is on IRQ {}, which isn't an ISA one",
+            fadt.sci_interrupt
+        );
+        return;
+    }
+
+    enable_acpi_mode(&fadt);
+    PM1A_STATUS.store(fadt.pm1a_event, Ordering::Relaxed);
+    PM1B_STATUS.store(fadt.pm1b_event, Ordering::Relaxed);
+    // the enable registers are the second half of each block
+    let enable_offset = fadt.pm1_event_len as u16 / 2;
+    for port in status_ports() {
+        unsafe {
+            // a press from before boot doesn't count
+            u16::write_to_port(port, u16::read_from_port(port));
+            let enable = port + enable_offset;
+            u16::write_to_port(enable, u16::read_from_port(enable) | PWRBTN);
+        }
+    }
+
+    sched::spawn("power button", button_thread);
+    if apic::enabled()
+        && acpi::madt().is_some_and(|madt| madt.isa_gsi(fadt.sci_interrupt).1.is_none())
+    {
+        apic::set_level_low(fadt.sci_interrupt);
+    }
+    pic::set_handler(fadt.sci_interrupt, sci);
+    ilog!("power: power button on IRQ {}", fadt.sci_interrupt);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "power",
+    stage: Stage::Late,
+    // the SCI goes through the I/O APIC if there is one
+    after: &["acpi", "apic", "sched"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parses_panic_actions() {
+        assert_eq!("halt".parse(), Ok(PanicAction::Halt));
+        assert_eq!("reboot".parse(), Ok(PanicAction::Reboot(0)));
+        assert_eq!("reboot,10".parse(), Ok(PanicAction::Reboot(10)));
+        assert_eq!("shutdown".parse(), Ok(PanicAction::Shutdown));
+        assert_eq!("reboot,".parse::<PanicAction>(), Err(ParseActionError));
+        assert_eq!("reboot,-1".parse::<PanicAction>(), Err(ParseActionError));
+        assert_eq!("shutdown,10".parse::<PanicAction>(), Err(ParseActionError));
+        assert_eq!("".parse::<PanicAction>(), Err(ParseActionError));
+    }
+}
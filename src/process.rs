@@ -0,0 +1,344 @@
+//! Processes
+//!
+//! A process is a user program and what it has of its own: an [`AddressSpace`], which its code
+//! and stack are mapped into, the files it has open, and a [`Pid`]. It runs in one thread,
+//! started with [`sched::spawn_in`] on its address space, so the scheduler loads its page
+//! tables whenever it's switched to, and two programs linked at the same addresses each see
+//! their own pages there.
+//!
+//! [`spawn`] sets one up and starts it. It runs until it [`exit`]s, with the `exit` syscall or
+//! by being killed for a fault, which frees its address space and closes its files. It stays
+//! in the process table with its exit code until something [`wait`]s for it.
+//!
+//! A process [`spawn`]ed by the kernel is the kernel's to wait for. One that's forked is its
+//! parent's, with [`try_wait_child`]: children left in the table when the parent exits are
+//! reaped then, and ones still running are reaped as soon as they exit, since nothing can wait
+//! for them anymore.
+//!
+//! [`fork`] starts a copy of the running process that shares its pages copy-on-write, so
+//! making one only costs the page tables, and pages are copied as either one writes to them.
+//! Its stack, and the `.bss` of an ELF executable, are filled in page by page as they're
+//! touched. The faults that come from both end up in [`handle_page_fault`].
+//!
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::VirtAddr;
+
+use crate::cpu::fpu::FpuState;
+use crate::fs::vfs::File;
+use crate::ilog;
+use crate::mem::paging::{self, AddressSpace};
+use crate::sched::{self, ThreadId};
+use crate::sync::{SpinLock, WaitQueue};
+use crate::user::{self, Registers, UserError};
+
+/// Files a process can have open at once
+pub const MAX_FILES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Pid {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The PID numbered `pid`, whether or not there's a process with it
+    pub fn from_u64(pid: u64) -> Pid {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// What went wrong with a process or its files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    /// There's no process with that PID, or it's been waited for already
+    NoSuchProcess,
+    /// It has [`MAX_FILES`] files open already
+    TooManyFiles,
+    /// It has no file open with that handle
+    BadHandle,
+    /// There wasn't a free frame for its page tables
+    NoFrames,
+}
+
+/// The files a process has open, by handle
+#[derive(Clone)]
+pub struct FileTable {
+    files: Vec<Option<File>>,
+}
+
+impl FileTable {
+    const fn new() -> FileTable {
+        FileTable { files: Vec::new() }
+    }
+
+    /// Keep `file` open under the lowest handle that's free
+    pub fn insert(&mut self, file: File) -> Result<usize, ProcessError> {
+        if let Some(handle) = self.files.iter().position(Option::is_none) {
+            self.files[handle] = Some(file);
+            return Ok(handle);
+        }
+        if self.files.len() == MAX_FILES {
+            return Err(ProcessError::TooManyFiles);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn get(&mut self, handle: usize) -> Result<&mut File, ProcessError> {
+        self.files
+            .get_mut(handle)
+            .and_then(Option::as_mut)
+            .ok_or(ProcessError::BadHandle)
+    }
+
+    /// Take the file under `handle` out of the table, it's closed when it's dropped
+    pub fn remove(&mut self, handle: usize) -> Result<File, ProcessError> {
+        self.files
+            .get_mut(handle)
+            .and_then(Option::take)
+            .ok_or(ProcessError::BadHandle)
+    }
+}
+
+/// Who a process is waited for by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Parent {
+    Kernel,
+    Process(Pid),
+    /// Its parent exited first
+    Orphan,
+}
+
+struct Process {
+    name: String,
+    thread: ThreadId,
+    /// Only changed with [`PROCESSES`] held
+    parent: SpinLock<Parent>,
+    /// None once it's exited
+    space: SpinLock<Option<AddressSpace>>,
+    files: SpinLock<FileTable>,
+    /// Set when it exits
+    exit_code: SpinLock<Option<i64>>,
+    exited: WaitQueue,
+}
+
+/// Every process that hasn't been waited for
+static PROCESSES: SpinLock<BTreeMap<Pid, Arc<Process>>> =
+    SpinLock::new("processes", BTreeMap::new());
+
+/// Set up an address space, map a program into it with `load`, which returns its entry point,
+/// give it a stack, and start it running as a new process
+pub fn spawn(
+    name: &str,
+    load: impl FnOnce(&mut AddressSpace) -> Result<VirtAddr, UserError>,
+) -> Result<Pid, UserError> {
+    // dropping it frees whatever was mapped before a failure
+    let mut space = AddressSpace::new()?;
+    let entry = load(&mut space)?;
+    user::map_stack(&mut space);
+    let run = move || unsafe { user::enter(entry, VirtAddr::new(user::STACK_TOP)) };
+    let files = FileTable::new();
+    Ok(start(
+        name,
+        Parent::Kernel,
+        space,
+        files,
+        FpuState::new(),
+        run,
+    ))
+}
+
+/// Start a copy of the running process, which shares its pages copy-on-write and has its files
+/// open too, each at its own position. Its thread starts with `registers`, and the floating
+/// point registers as they are now.
+pub fn fork(registers: Registers) -> Result<Pid, ProcessError> {
+    let (pid, parent) = current_process().ok_or(ProcessError::NoSuchProcess)?;
+    let space = match parent.space.lock().as_mut() {
+        Some(space) => space.fork().map_err(|_| ProcessError::NoFrames)?,
+        None => return Err(ProcessError::NoSuchProcess),
+    };
+    let files = parent.files.lock().clone();
+    let run = move || unsafe { user::resume(registers) };
+    let fpu = FpuState::current();
+    Ok(start(
+        &parent.name,
+        Parent::Process(pid),
+        space,
+        files,
+        fpu,
+        run,
+    ))
+}
+
+/// Put a process into the table and start its thread on `space` running `run`
+fn start(
+    name: &str,
+    parent: Parent,
+    space: AddressSpace,
+    files: FileTable,
+    fpu: FpuState,
+    run: impl FnOnce() + Send + 'static,
+) -> Pid {
+    let pid = Pid::new();
+    let level_4 = space.level_4();
+    // held until the process is in the table, so its thread can't run and not find itself
+    let mut processes = PROCESSES.lock();
+    let thread = sched::spawn_in("user", level_4, fpu, run);
+    processes.insert(
+        pid,
+        Arc::new(Process {
+            name: String::from(name),
+            thread,
+            parent: SpinLock::new("process parent", parent),
+            space: SpinLock::new("process space", Some(space)),
+            files: SpinLock::new("process files", files),
+            exit_code: SpinLock::new("process exit", None),
+            exited: WaitQueue::new("process exit"),
+        }),
+    );
+    pid
+}
+
+/// The process the running thread belongs to
+fn current_process() -> Option<(Pid, Arc<Process>)> {
+    let thread = sched::current()?;
+    let processes = PROCESSES.lock();
+    processes
+        .iter()
+        .find(|(_, process)| process.thread == thread)
+        .map(|(&pid, process)| (pid, process.clone()))
+}
+
+/// The PID of the running process, None in a kernel thread
+pub fn current() -> Option<Pid> {
+    current_process().map(|(pid, _)| pid)
+}
+
+/// Run `f` on the running process's files, None in a kernel thread
+pub fn with_files<R>(f: impl FnOnce(&mut FileTable) -> R) -> Option<R> {
+    let (_, process) = current_process()?;
+    let mut files = process.files.lock();
+    Some(f(&mut files))
+}
+
+/// Resolve a page fault at `virt` in the running process's address space, a write if `write`
+/// is set, see [`AddressSpace::resolve_fault`]. False if it's a real fault, or not in a
+/// process.
+pub fn handle_page_fault(virt: VirtAddr, write: bool) -> bool {
+    let Some((_, process)) = current_process() else {
+        return false;
+    };
+    let mut space = process.space.lock();
+    space
+        .as_mut()
+        .is_some_and(|space| space.resolve_fault(virt, write))
+}
+
+/// End the running process with `code`, from a syscall or an exception it caused
+pub fn exit(code: i64) -> ! {
+    if let Some((pid, process)) = current_process() {
+        ilog!("process: {} ({}) exited with {}", pid, process.name, code);
+        // off its tables before they're freed
+        unsafe { sched::set_level_4(paging::kernel_level_4()) };
+        drop(process.space.lock().take());
+        *process.files.lock() = FileTable::new();
+
+        let mut processes = PROCESSES.lock();
+        *process.exit_code.lock() = Some(code);
+        if *process.parent.lock() == Parent::Orphan {
+            processes.remove(&pid);
+        }
+        processes.retain(|_, child| {
+            let mut parent = child.parent.lock();
+            if *parent != Parent::Process(pid) {
+                return true;
+            }
+            *parent = Parent::Orphan;
+            child.exit_code.lock().is_none()
+        });
+        drop(processes);
+        process.exited.wake_all();
+    }
+    sched::exit()
+}
+
+/// The exit code of `pid` if it's exited, in which case it's gone from the table, or None if
+/// it's still running
+pub fn try_wait(pid: Pid) -> Result<Option<i64>, ProcessError> {
+    let mut processes = PROCESSES.lock();
+    let process = processes.get(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    let code = *process.exit_code.lock();
+    if code.is_some() {
+        processes.remove(&pid);
+    }
+    Ok(code)
+}
+
+/// Reap a child of the running process that's exited, `pid` or any of them if it's None, and
+/// return its PID and exit code, or None if they're all still running
+pub fn try_wait_child(pid: Option<Pid>) -> Result<Option<(Pid, i64)>, ProcessError> {
+    let (parent, _) = current_process().ok_or(ProcessError::NoSuchProcess)?;
+    let mut processes = PROCESSES.lock();
+    let mut children = processes
+        .iter()
+        .filter(|(&child, process)| {
+            pid.is_none_or(|pid| pid == child) && *process.parent.lock() == Parent::Process(parent)
+        })
+        .peekable();
+    if children.peek().is_none() {
+        return Err(ProcessError::NoSuchProcess);
+    }
+    let exited = children.find_map(|(&child, process)| Some((child, (*process.exit_code.lock())?)));
+    if let Some((child, _)) = exited {
+        processes.remove(&child);
+    }
+    Ok(exited)
+}
+
+/// Block until `pid` exits, and return its exit code
+pub fn wait(pid: Pid) -> Result<i64, ProcessError> {
+    let process = PROCESSES
+        .lock()
+        .get(&pid)
+        .cloned()
+        .ok_or(ProcessError::NoSuchProcess)?;
+    process
+        .exited
+        .wait_until(|| process.exit_code.lock().is_some());
+    try_wait(pid)?.ok_or(ProcessError::NoSuchProcess)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Both are linked at the same addresses, and each gets through to its own exit
+    #[test_case]
+    fn two_programs_at_once() {
+        let first = user::run("hello").unwrap();
+        let second = user::run("hello").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(wait(second), Ok(0));
+        assert_eq!(wait(first), Ok(0));
+        assert_eq!(wait(first), Err(ProcessError::NoSuchProcess));
+    }
+}
@@ -0,0 +1,36 @@
+//! QEMU's isa-debug-exit device
+//!
+//! Writing to the device's port makes QEMU exit with `(value << 1) | 1`, which is how test
+//! kernels report whether they passed. `test-success-exit-code` in Cargo.toml tells bootimage
+//! which status means success.
+//!
+//! links:
+//! - <https://os.phil-opp.com/testing/#exiting-qemu>
+//!
+
+use x86_64::structures::port::PortWrite as _;
+
+use crate::power;
+
+/// What to exit QEMU with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// I/O port the device is set up on, see `test-args` in Cargo.toml
+const EXIT_PORT: u16 = 0xf4;
+
+pub fn exit(code: ExitCode) -> ! {
+    try_exit(code);
+    power::halt()
+}
+
+/// Exit QEMU if the device is there, which it's only under `cargo test`, and return if it isn't
+pub fn try_exit(code: ExitCode) {
+    unsafe {
+        u32::write_to_port(EXIT_PORT, code as u32);
+    }
+}
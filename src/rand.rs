@@ -0,0 +1,173 @@
+//! Random numbers
+//!
+//! [`u64`] and [`fill`] take their bits from RDRAND when the CPU has it, and RDSEED for
+//! [`seed`], which is slower but straight from the hardware's entropy source. Either can
+//! run dry for a moment, so they're retried a few times before giving up.
+//!
+//! Without them, numbers come from a pool of four words that gets the TSC's jitter mixed in:
+//! [`INIT`] times a few hundred short busy loops, and every IRQ adds when it came in. Each
+//! number out of the pool is the pool, a counter, and the TSC run through a 64-bit mixer, so
+//! two calls never give the same thing, but it's only as unpredictable as the timings that
+//! went in. It's meant for stack canaries and address randomization, not for keys.
+//!
+//! links:
+//! - Intel DRNG guide: <https://www.intel.com/content/www/us/en/developer/articles/guide/intel-digital-random-number-generator-drng-software-implementation-guide.html>
+//! - the mixer is splitmix64's: <https://prng.di.unimi.it/splitmix64.c>
+//!
+
+use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::cpu::{self, Feature};
+use crate::ilog;
+use crate::init::{InitCall, Stage};
+
+/// Tries before RDRAND or RDSEED counts as out of entropy
+const RETRIES: usize = 10;
+/// Busy loops [`INIT`] times to seed the pool
+const JITTER_SAMPLES: usize = 256;
+
+const POOL_WORDS: usize = 4;
+/// Mixed into with every IRQ and every number taken out, all without locks so interrupt
+/// handlers can add to it
+static POOL: [AtomicU64; POOL_WORDS] = [const { AtomicU64::new(0) }; POOL_WORDS];
+/// Which word the next interrupt goes into
+static NEXT_WORD: AtomicUsize = AtomicUsize::new(0);
+/// Bumped for every number taken out of the pool
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// splitmix64's output function, every input bit affects every output bit
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn rdrand() -> Option<u64> {
+    if !cpu::has(Feature::Rdrand) {
+        return None;
+    }
+    for _ in 0..RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack))
+        };
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn rdseed() -> Option<u64> {
+    if !cpu::has(Feature::Rdseed) {
+        return None;
+    }
+    for _ in 0..RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack))
+        };
+        if ok != 0 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+/// Stir `value` into the pool, for anything that happens at an unpredictable time
+pub fn add_entropy(value: u64) {
+    let word = NEXT_WORD.fetch_add(1, Ordering::Relaxed) % POOL_WORDS;
+    POOL[word].fetch_add(mix(value ^ rdtsc()), Ordering::Relaxed);
+}
+
+/// Called for every IRQ, see [`pic`](crate::pic)
+pub fn add_interrupt(irq: u8) {
+    add_entropy((irq as u64) << 56);
+}
+
+/// A number out of the pool, mixing it up as it goes
+fn from_pool() -> u64 {
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut x = mix(count ^ rdtsc());
+    for word in &POOL {
+        x = mix(x ^ word.load(Ordering::Relaxed));
+    }
+    // so the next number out doesn't start from the same pool
+    POOL[count as usize % POOL_WORDS].fetch_xor(x, Ordering::Relaxed);
+    x
+}
+
+/// A random number
+pub fn u64() -> u64 {
+    rdrand().unwrap_or_else(from_pool)
+}
+
+/// A random number for seeding other generators, from RDSEED if the CPU has it
+pub fn seed() -> u64 {
+    rdseed().or_else(rdrand).unwrap_or_else(from_pool)
+}
+
+/// Fill `bytes` with random bytes
+pub fn fill(bytes: &mut [u8]) {
+    for chunk in bytes.chunks_mut(8) {
+        chunk.copy_from_slice(&u64().to_le_bytes()[..chunk.len()]);
+    }
+}
+
+fn init() {
+    // how long the same loop takes varies with caches, pipelines, and whatever the
+    // hypervisor is up to, so the low bits of each time are noise
+    for i in 0..JITTER_SAMPLES {
+        let start = rdtsc();
+        for _ in 0..(i % 7 + 1) * 16 {
+            core::hint::spin_loop();
+        }
+        add_entropy(rdtsc() - start);
+    }
+    if let Some(seed) = rdseed().or_else(rdrand) {
+        add_entropy(seed);
+    }
+
+    let source = if cpu::has(Feature::Rdrand) {
+        "rdrand"
+    } else {
+        "tsc jitter"
+    };
+    ilog!("rand: using {}", source);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "rand",
+    stage: Stage::Early,
+    after: &["cpu"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn pool_numbers_differ() {
+        let a = from_pool();
+        let b = from_pool();
+        assert_ne!(a, b);
+    }
+
+    #[test_case]
+    fn fill_covers_odd_lengths() {
+        let mut bytes = [0u8; 13];
+        fill(&mut bytes);
+        // all 13 zero bytes from random input would be a 1 in 2^104 event
+        assert!(bytes.iter().any(|&b| b != 0));
+    }
+}
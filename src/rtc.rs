@@ -0,0 +1,240 @@
+//! CMOS real-time clock
+//!
+//! The RTC keeps the date and time in the CMOS, read through an index port and a data port.
+//! It updates its registers once a second, and reading while it does gives torn values, so
+//! [`now`] waits for the update-in-progress flag to clear and reads until it gets the same
+//! time twice. Depending on status register B the values are BCD or binary, and the hour is
+//! 12 or 24 hour.
+//!
+//! The clock is whatever the firmware was set to, usually UTC in a VM. [`INIT`] reads it once
+//! so [`wall_clock_secs`] can give the time of day from the uptime without going back to the
+//! CMOS, which is what `logtime` on the command line prefixes [log](crate::log) lines with.
+//!
+//! links:
+//! - <https://wiki.osdev.org/CMOS>
+//!
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::arch::io::Port;
+use crate::init::{InitCall, Stage};
+use crate::sync::SpinLock;
+use crate::{ilog, timer};
+
+/// Selects the register, bit 7 also masks NMIs, which is left clear
+const INDEX: Port<u8> = unsafe { Port::new(0x70) };
+const DATA: Port<u8> = unsafe { Port::new(0x71) };
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+/// Not standard, but where QEMU and most PCs keep it, see the FADT's century field
+const CENTURY: u8 = 0x32;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const FORMAT_24_HOUR: u8 = 1 << 1;
+const FORMAT_BINARY: u8 = 1 << 2;
+/// Set in the hours register for PM in 12 hour mode
+const HOUR_PM: u8 = 1 << 7;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The index and data ports have to be used in pairs
+static CMOS: SpinLock<()> = SpinLock::new("cmos", ());
+
+/// The time of day in seconds when the uptime was 0, or `u64::MAX` before [`INIT`]
+static BOOT_SECS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// A calendar date and time, as the RTC keeps it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since midnight
+    pub fn secs_of_day(&self) -> u64 {
+        (self.hour as u64 * 60 + self.minute as u64) * 60 + self.second as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// The registers as read, before decoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Raw {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_register(register: u8) -> u8 {
+    INDEX.write(register);
+    DATA.read()
+}
+
+fn read_raw() -> Raw {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    Raw {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: read_register(CENTURY),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+/// Turn what was read into a date, given status register B
+fn decode(raw: Raw, status_b: u8) -> DateTime {
+    let binary = |value| {
+        if status_b & FORMAT_BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = binary(raw.hour & !HOUR_PM);
+    if status_b & FORMAT_24_HOUR == 0 {
+        // 12 am is midnight, 12 pm is noon
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+
+    // a CMOS without the century register reads it as 0 or garbage
+    let century = match binary(raw.century) {
+        century @ 19..=99 => century as u16,
+        _ => 20,
+    };
+    DateTime {
+        year: century * 100 + binary(raw.year) as u16,
+        month: binary(raw.month),
+        day: binary(raw.day),
+        hour,
+        minute: binary(raw.minute),
+        second: binary(raw.second),
+    }
+}
+
+/// Read the date and time from the RTC. Takes up to the length of an update, a few ms at most.
+pub fn now() -> DateTime {
+    interrupts::without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        // the same values twice in a row means no update happened in between
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        decode(raw, read_register(STATUS_B))
+    })
+}
+
+/// Seconds since midnight by the RTC, worked out from the uptime. None before [`INIT`].
+pub fn wall_clock_secs() -> Option<u64> {
+    let boot = BOOT_SECS.load(Ordering::Relaxed);
+    if boot == u64::MAX {
+        return None;
+    }
+    Some((boot + timer::uptime_ms() / 1000) % SECS_PER_DAY)
+}
+
+fn init() {
+    let now = now();
+    let boot = now.secs_of_day() + SECS_PER_DAY - timer::uptime_ms() / 1000 % SECS_PER_DAY;
+    BOOT_SECS.store(boot % SECS_PER_DAY, Ordering::Relaxed);
+    ilog!("rtc: {}", now);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "rtc",
+    stage: Stage::Drivers,
+    // the uptime it's kept from
+    after: &["timer"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn decodes_bcd_12_hour() {
+        let raw = Raw {
+            second: 0x59,
+            minute: 0x30,
+            hour: HOUR_PM | 0x12,
+            day: 0x14,
+            month: 0x10,
+            year: 0x26,
+            century: 0x20,
+        };
+        let date = decode(raw, 0);
+        assert_eq!(
+            date,
+            DateTime {
+                year: 2026,
+                month: 10,
+                day: 14,
+                hour: 12,
+                minute: 30,
+                second: 59,
+            }
+        );
+        // 12 am
+        let midnight = decode(Raw { hour: 0x12, ..raw }, 0);
+        assert_eq!(midnight.hour, 0);
+    }
+
+    #[test_case]
+    fn decodes_binary_24_hour() {
+        let raw = Raw {
+            second: 5,
+            minute: 4,
+            hour: 23,
+            day: 1,
+            month: 2,
+            year: 99,
+            century: 0,
+        };
+        let date = decode(raw, FORMAT_BINARY | FORMAT_24_HOUR);
+        assert_eq!(date.year, 2099);
+        assert_eq!(date.hour, 23);
+    }
+}
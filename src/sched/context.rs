@@ -0,0 +1,94 @@
+//! Switching between thread stacks
+//!
+//! A thread that isn't running is just its saved stack pointer. Everything else it needs is on
+//! its stack: [`switch`] pushes the callee-saved registers and the flags there before moving
+//! to the next thread's stack and popping that thread's, and the caller-saved ones were saved
+//! by whoever called [`switch`], or by the interrupt that led to it.
+//!
+
+use core::arch::global_asm;
+
+// switch(from: *mut u64, to: u64)
+global_asm!(
+    ".global sched_switch",
+    "sched_switch:",
+    "    pushq %rbp",
+    "    pushq %rbx",
+    "    pushq %r12",
+    "    pushq %r13",
+    "    pushq %r14",
+    "    pushq %r15",
+    "    pushfq",
+    "    movq %rsp, (%rdi)",
+    "    movq %rsi, %rsp",
+    "    popfq",
+    "    popq %r15",
+    "    popq %r14",
+    "    popq %r13",
+    "    popq %r12",
+    "    popq %rbx",
+    "    popq %rbp",
+    "    retq",
+    // where a new thread's first switch returns to, with its argument in r12
+    ".global sched_thread_start",
+    "sched_thread_start:",
+    "    movq %r12, %rdi",
+    "    callq *%r13",
+    "    ud2",
+    options(att_syntax)
+);
+
+extern "C" {
+    fn sched_switch(from: *mut u64, to: u64);
+    static sched_thread_start: u8;
+}
+
+/// What [`switch`] pops, from the stack pointer up
+#[repr(C)]
+struct Frame {
+    rflags: u64,
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    ret: u64,
+}
+
+/// Interrupts off, the only flag a new thread cares about, and bit 1, which is always set
+const INITIAL_RFLAGS: u64 = 0x2;
+
+/// Set up a new stack ending at `top` so switching to it calls `entry(arg)` with interrupts
+/// off, and return the stack pointer to switch to. `entry` must not return.
+///
+/// # Safety
+///
+/// The stack below `top` has to be big enough and not be used for anything else.
+pub unsafe fn init_stack(top: u64, entry: extern "C" fn(u64) -> !, arg: u64) -> u64 {
+    // leaves the stack 16-byte aligned for the call in `sched_thread_start`
+    let top = (top & !0xf) - 16;
+    let rsp = top - size_of::<Frame>() as u64;
+    (rsp as *mut Frame).write(Frame {
+        rflags: INITIAL_RFLAGS,
+        r15: 0,
+        r14: 0,
+        r13: entry as *const () as u64,
+        r12: arg,
+        rbx: 0,
+        rbp: 0,
+        ret: &raw const sched_thread_start as u64,
+    });
+    rsp
+}
+
+/// Save the running thread's stack pointer in `from` and carry on from the one in `to`.
+/// Returns when something switches back to `from`.
+///
+/// # Safety
+///
+/// Interrupts have to be off, and `to` has to be a stack pointer saved by [`switch`] or from
+/// [`init_stack`].
+pub unsafe fn switch(from: *mut u64, to: u64) {
+    sched_switch(from, to);
+}
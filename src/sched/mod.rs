@@ -0,0 +1,553 @@
+//! Kernel threads
+//!
+//! Every thread has a stack of its own, and [`spawn`] starts one running a closure. They share
+//! the boot CPU round-robin: the one that's running keeps it until it calls [`yield_now`] or
+//! [`exit`], or until its [`config::time_slice_ms`] runs out, at which point the timer interrupt
+//! switches to the next ready thread on its way out. The code `kernel_main` was running when
+//! [`INIT`] ran becomes the `main` thread, which is where the shell runs.
+//!
+//! A thread that has to wait calls [`block`], and is left out until something calls [`wake`]
+//! for it, from an interrupt handler if need be. A wake that comes first isn't lost, it makes
+//! the next [`block`] return straight away. When every thread is blocked the `idle` thread
+//! halts the CPU until one is woken.
+//!
+//! A thread isn't preempted while it holds a [`SpinLock`](crate::sync::SpinLock), since the
+//! thread switched to could spin on the same lock for its whole slice. Taking a lock bumps the
+//! count [`preempt_disable`] does, and dropping the guard undoes it. There's one count for the
+//! whole machine, so a lock held on another CPU holds off preemption here too, for as long as
+//! it's held.
+//!
+//! Each thread runs on a level 4 page table, the kernel's unless it was started with
+//! [`spawn_in`] for a [`process`](crate::process), and switching to it loads that into CR3 if
+//! it isn't already. The kernel's half is the same in all of them. Those threads also get an
+//! [`FpuState`], which holds their floating point registers while they aren't running.
+//!
+//! Threads only run on the boot CPU, the others are left idling.
+//!
+//! links:
+//! - <https://wiki.osdev.org/Scheduling_Algorithms>
+//! - <https://wiki.osdev.org/Context_Switching>
+//!
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use x86_64::instructions::{self, interrupts};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+use crate::cpu::fpu::FpuState;
+use crate::init::{InitCall, Stage};
+use crate::mem::paging;
+use crate::mem::stack::{self, Stack, StackUsage};
+use crate::sync::SpinLock;
+use crate::{config, gdt, percpu};
+
+mod context;
+
+/// Stack size for each thread, `main` keeps the boot stack
+pub const STACK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> ThreadId {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// Waiting for its turn
+    Ready,
+    /// Waiting for a [`wake`]
+    Blocked,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Running => "running",
+            State::Ready => "ready",
+            State::Blocked => "blocked",
+        }
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    /// Where it left off, only meaningful while it isn't running
+    rsp: u64,
+    /// Kept until the thread is freed, None for `main`, which runs on the boot stack
+    stack: Option<Stack>,
+    /// The end of `stack`, where interrupts from user mode start
+    stack_top: Option<VirtAddr>,
+    /// The level 4 page table it runs on
+    level_4: PhysFrame,
+    /// Its floating point registers, saved and restored on every switch. None for kernel
+    /// threads, which don't use them.
+    fpu: Option<FpuState>,
+    /// Timer ticks spent running
+    ticks: u64,
+    /// Woken while it wasn't blocked, so the next [`block`] doesn't
+    wake_pending: bool,
+}
+
+/// What [`for_each`] says about a thread
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: State,
+    pub ticks: u64,
+    /// None for `main`, whose boot stack isn't tracked
+    pub stack: Option<StackUsage>,
+}
+
+struct Scheduler {
+    current: Option<Box<Thread>>,
+    /// Room is kept for every thread here and in `blocked`, so switching and waking never
+    /// allocate
+    ready: VecDeque<Box<Thread>>,
+    #[allow(clippy::vec_box)]
+    blocked: Vec<Box<Thread>>,
+    /// Runs when nothing else can, and is never on the other lists
+    idle: Option<Box<Thread>>,
+    idle_id: Option<ThreadId>,
+    /// Threads that haven't exited, not counting `idle`
+    threads: usize,
+    /// Threads that exited, their stacks are freed by whoever runs next outside an interrupt.
+    /// Boxed like the rest, the switch away saves the stack pointer after it's been put here.
+    #[allow(clippy::vec_box)]
+    dead: Vec<Box<Thread>>,
+}
+
+impl Scheduler {
+    /// Make sure any thread can be put on either list without allocating
+    fn make_room(&mut self) {
+        let threads = self.threads;
+        self.ready.reserve(threads.saturating_sub(self.ready.len()));
+        self.blocked
+            .reserve(threads.saturating_sub(self.blocked.len()));
+    }
+
+    fn is_idle(&self, thread: &Thread) -> bool {
+        self.idle_id == Some(thread.id)
+    }
+}
+
+/// Only locked with interrupts off, the timer interrupt switches threads
+static SCHEDULER: SpinLock<Scheduler> = SpinLock::new(
+    "scheduler",
+    Scheduler {
+        current: None,
+        ready: VecDeque::new(),
+        blocked: Vec::new(),
+        idle: None,
+        idle_id: None,
+        threads: 0,
+        dead: Vec::new(),
+    },
+);
+
+/// Set once there's a `main` thread to switch from
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Preemption is off while this isn't 0
+static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// Set by the timer when the running thread's slice is up
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+/// What's left of the running thread's slice
+static SLICE_LEFT_NS: AtomicU64 = AtomicU64::new(0);
+/// Ticks the running thread has had since it was switched to
+static RUN_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Don't let the running thread be preempted until the matching [`preempt_enable`]. Nests.
+pub fn preempt_disable() {
+    PREEMPT_COUNT.fetch_add(1, Ordering::Acquire);
+}
+
+pub fn preempt_enable() {
+    PREEMPT_COUNT.fetch_sub(1, Ordering::Release);
+}
+
+/// The running thread, None before [`INIT`] or off the boot CPU
+pub fn current() -> Option<ThreadId> {
+    if !ENABLED.load(Ordering::Acquire) || percpu::cpu_id() != 0 {
+        return None;
+    }
+    interrupts::without_interrupts(|| SCHEDULER.lock().current.as_ref().map(|thread| thread.id))
+}
+
+fn new_thread(
+    name: &'static str,
+    level_4: PhysFrame,
+    fpu: Option<FpuState>,
+    f: impl FnOnce() + Send + 'static,
+) -> Box<Thread> {
+    // a thin pointer to hand to the new thread in a register
+    let entry: *mut Box<dyn FnOnce() + Send> = Box::into_raw(Box::new(Box::new(f)));
+    let stack = stack::allocate(name, STACK_SIZE).expect("sched: no stack for a new thread");
+    let top = stack.top();
+    let rsp = unsafe { context::init_stack(top.as_u64(), thread_main, entry as u64) };
+
+    Box::new(Thread {
+        id: ThreadId::new(),
+        name,
+        rsp,
+        stack: Some(stack),
+        stack_top: Some(top),
+        level_4,
+        fpu,
+        ticks: 0,
+        wake_pending: false,
+    })
+}
+
+/// Start a thread running `f`, it gets the CPU when its turn comes. The thread exits when `f`
+/// returns.
+pub fn spawn(name: &'static str, f: impl FnOnce() + Send + 'static) -> ThreadId {
+    start(new_thread(name, paging::kernel_level_4(), None, f))
+}
+
+/// Start a thread running `f` like [`spawn`], on the level 4 page table in `level_4` and with
+/// `fpu` in its floating point registers, for a user program
+pub fn spawn_in(
+    name: &'static str,
+    level_4: PhysFrame,
+    fpu: FpuState,
+    f: impl FnOnce() + Send + 'static,
+) -> ThreadId {
+    start(new_thread(name, level_4, Some(fpu), f))
+}
+
+fn start(thread: Box<Thread>) -> ThreadId {
+    assert!(ENABLED.load(Ordering::Acquire), "sched: spawn before init");
+    reap();
+
+    let id = thread.id;
+    // allocating with the scheduler locked is fine outside an interrupt, the heap lock is never
+    // held by a preempted thread
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.threads += 1;
+        scheduler.make_room();
+        scheduler.ready.push_back(thread);
+    });
+    id
+}
+
+extern "C" fn thread_main(entry: u64) -> ! {
+    interrupts::enable();
+    let entry = unsafe { Box::from_raw(entry as *mut Box<dyn FnOnce() + Send>) };
+    entry();
+    exit()
+}
+
+/// Free the stacks of threads that have exited
+fn reap() {
+    let dead = interrupts::without_interrupts(|| mem::take(&mut SCHEDULER.lock().dead));
+    drop(dead);
+}
+
+/// What to do with the running thread when switching away from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Switch {
+    /// Back to the end of the ready queue
+    Yield,
+    /// Leave it out until it's woken
+    Block,
+    /// Away for good
+    Exit,
+}
+
+/// Switch to the next ready thread, or to `idle` if there's none and the running thread can't
+/// carry on. Returns false if it didn't switch: there was nothing else to yield to, or a wake
+/// came before the block.
+///
+/// # Safety
+///
+/// Interrupts have to be off.
+unsafe fn switch_next(how: Switch) -> bool {
+    let (from, to) = {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current.as_mut().unwrap();
+        if how == Switch::Block && mem::take(&mut current.wake_pending) {
+            return false;
+        }
+
+        let next = match scheduler.ready.pop_front() {
+            Some(next) => next,
+            None if how == Switch::Yield => return false,
+            None => scheduler
+                .idle
+                .take()
+                .expect("sched: idle can't block or exit"),
+        };
+        let to = next.rsp;
+        if let Some(top) = next.stack_top {
+            gdt::set_kernel_stack(top);
+        }
+        paging::switch_to(next.level_4);
+        let mut previous = scheduler.current.replace(next).unwrap();
+        if let Some(fpu) = previous.fpu.as_mut() {
+            fpu.save();
+        }
+        if let Some(fpu) = scheduler
+            .current
+            .as_ref()
+            .and_then(|next| next.fpu.as_ref())
+        {
+            fpu.restore();
+        }
+        previous.ticks += RUN_TICKS.swap(0, Ordering::Relaxed);
+        let from = &raw mut previous.rsp;
+        if scheduler.is_idle(&previous) {
+            scheduler.idle = Some(previous);
+        } else {
+            match how {
+                Switch::Yield => scheduler.ready.push_back(previous),
+                Switch::Block => scheduler.blocked.push(previous),
+                Switch::Exit => {
+                    scheduler.threads -= 1;
+                    scheduler.dead.push(previous);
+                }
+            }
+        }
+        (from, to)
+    };
+
+    new_slice();
+    context::switch(from, to);
+    true
+}
+
+/// Move the running thread to the level 4 page table in `level_4`, and load it
+///
+/// # Safety
+///
+/// The same as for [`paging::switch_to`], for as long as the thread runs on it.
+pub unsafe fn set_level_4(level_4: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(current) = scheduler.current.as_mut() {
+            current.level_4 = level_4;
+        }
+        paging::switch_to(level_4);
+    });
+}
+
+/// Let the other ready threads run before carrying on. Not to be called with a lock held.
+pub fn yield_now() {
+    if current().is_none() {
+        return;
+    }
+    interrupts::without_interrupts(|| unsafe {
+        switch_next(Switch::Yield);
+    });
+    reap();
+}
+
+/// Stop running until [`wake`] is called for this thread, or return straight away if it was
+/// since the last time. Other wakers than the one waited for can come along, so it's meant to
+/// be called in a loop that checks what it's waiting for. Not to be called with a lock held.
+pub fn block() {
+    assert!(current().is_some(), "sched: block outside a thread");
+    interrupts::without_interrupts(|| unsafe {
+        switch_next(Switch::Block);
+    });
+    reap();
+}
+
+/// Let thread `id` run again if it's blocked, or have its next [`block`] return if it isn't.
+/// Doesn't allocate, so it can be called from an interrupt handler.
+pub fn wake(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(i) = scheduler.blocked.iter().position(|thread| thread.id == id) {
+            let thread = scheduler.blocked.swap_remove(i);
+            scheduler.ready.push_back(thread);
+            // no need to wait for the slice to run out when there's nothing else to do
+            if let Some(current) = &scheduler.current {
+                if scheduler.is_idle(current) {
+                    NEED_RESCHED.store(true, Ordering::Relaxed);
+                }
+            }
+            return;
+        }
+
+        let scheduler = &mut *scheduler;
+        let thread = scheduler
+            .current
+            .iter_mut()
+            .chain(scheduler.ready.iter_mut())
+            .find(|thread| thread.id == id);
+        if let Some(thread) = thread {
+            thread.wake_pending = true;
+        }
+    });
+}
+
+/// End the running thread
+pub fn exit() -> ! {
+    assert!(current().is_some(), "sched: exit outside a thread");
+    interrupts::disable();
+    // this one's stack is freed by whoever comes next
+    reap();
+    unsafe { switch_next(Switch::Exit) };
+    unreachable!("sched: switched back to a thread that exited");
+}
+
+fn new_slice() {
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+    SLICE_LEFT_NS.store(config::time_slice_ms() * 1_000_000, Ordering::Relaxed);
+}
+
+/// Count `tick_ns` off the running thread's slice, called by the timer on every tick
+pub fn tick(tick_ns: u64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    RUN_TICKS.fetch_add(1, Ordering::Relaxed);
+    // only this interrupt changes it while a thread is running
+    let left = SLICE_LEFT_NS
+        .load(Ordering::Relaxed)
+        .saturating_sub(tick_ns);
+    SLICE_LEFT_NS.store(left, Ordering::Relaxed);
+    if left == 0 {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Switch threads if the running one's slice is up. Called at the end of every IRQ, after its
+/// end of interrupt, so the next thread still gets interrupts.
+pub fn preempt() {
+    if !NEED_RESCHED.load(Ordering::Relaxed)
+        || PREEMPT_COUNT.load(Ordering::Relaxed) != 0
+        || percpu::cpu_id() != 0
+    {
+        return;
+    }
+    unsafe {
+        if !switch_next(Switch::Yield) {
+            // nothing else wants the CPU, so this one gets another slice
+            new_slice();
+        }
+    }
+}
+
+/// Run `f` on every thread, the running one first
+pub fn for_each(mut f: impl FnMut(ThreadInfo)) {
+    let mut threads = Vec::new();
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let running = scheduler.current.iter().map(|thread| {
+            let ticks = thread.ticks + RUN_TICKS.load(Ordering::Relaxed);
+            (thread, State::Running, ticks)
+        });
+        let ready = scheduler
+            .ready
+            .iter()
+            .chain(scheduler.idle.iter())
+            .map(|thread| (thread, State::Ready, thread.ticks));
+        let blocked = scheduler
+            .blocked
+            .iter()
+            .map(|thread| (thread, State::Blocked, thread.ticks));
+        threads.extend(
+            running
+                .chain(ready)
+                .chain(blocked)
+                .map(|(thread, state, ticks)| ThreadInfo {
+                    id: thread.id,
+                    name: thread.name,
+                    state,
+                    ticks,
+                    stack: thread.stack.as_ref().map(Stack::usage),
+                }),
+        );
+    });
+    threads.into_iter().for_each(&mut f);
+}
+
+fn init() {
+    new_slice();
+    let main = Box::new(Thread {
+        id: ThreadId::new(),
+        name: "main",
+        rsp: 0,
+        stack: None,
+        stack_top: None,
+        level_4: paging::kernel_level_4(),
+        fpu: None,
+        ticks: 0,
+        wake_pending: false,
+    });
+    let idle = new_thread("idle", paging::kernel_level_4(), None, || loop {
+        instructions::hlt();
+    });
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.threads = 1;
+        scheduler.make_room();
+        scheduler.current = Some(main);
+        scheduler.idle_id = Some(idle.id);
+        scheduler.idle = Some(idle);
+    });
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "sched",
+    stage: Stage::Late,
+    after: &["heap", "timer"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use super::*;
+
+    #[test_case]
+    fn main_is_running() {
+        let mut running = Vec::new();
+        for_each(|thread| {
+            if thread.state == State::Running {
+                running.push(thread.name);
+            }
+        });
+        assert_eq!(running, ["main"]);
+    }
+
+    /// A spawned thread gets to run when main yields, and exits
+    #[test_case]
+    fn spawned_thread_runs() {
+        let ran = Arc::new(AtomicUsize::new(0));
+        let counter = ran.clone();
+        let id = spawn("test", move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        for _ in 0..10 {
+            yield_now();
+        }
+        assert_eq!(ran.load(Ordering::Relaxed), 1);
+        let mut alive = false;
+        for_each(|thread| alive |= thread.id == id);
+        assert!(!alive);
+    }
+}
@@ -0,0 +1,185 @@
+//! Serial port
+//!
+//! Drives the first 16550 UART (COM1), which QEMU can hook up to the terminal it runs in with
+//! `-serial stdio`. That's how test results get out of the VM.
+//!
+//! Once [`INIT`] has run, what comes in interrupts on IRQ 4 and goes into the
+//! [console input](crate::console::input) with the keyboard's, so with `console=serial` the
+//! shell works over the serial port alone. Code that has to work with interrupts off, like the
+//! [kernel debugger](crate::debug::kdb), can still poll with [`SerialPort::receive`], as long
+//! as it keeps them off meanwhile.
+//!
+//! links:
+//! - registers: <https://wiki.osdev.org/Serial_Ports>
+//!
+
+use core::fmt;
+
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use x86_64::structures::port::{PortRead as _, PortWrite as _};
+
+use crate::console::input;
+use crate::init::{InitCall, Stage};
+use crate::pic;
+use crate::sync::SpinLock;
+
+const COM1: u16 = 0x3f8;
+const COM1_IRQ: u8 = 4;
+
+// register offsets from the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+/// Interrupt enable bit for when a byte has been received
+const RECEIVED_DATA: u8 = 0x01;
+
+/// Line control bit that turns the data and interrupt enable registers into the divisor latch
+const DLAB: u8 = 0x80;
+/// Line status bit saying a received byte is waiting in the data register
+const DATA_READY: u8 = 0x01;
+/// Line status bit saying the transmit register can take another byte
+const TRANSMIT_EMPTY: u8 = 0x20;
+
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// A UART at I/O port `base`, which has to be [`init`](SerialPort::init)ed before use
+    pub const fn new(base: u16) -> SerialPort {
+        SerialPort { base }
+    }
+
+    fn write_reg(&mut self, reg: u16, value: u8) {
+        unsafe {
+            u8::write_to_port(self.base + reg, value);
+        }
+    }
+
+    fn read_reg(&mut self, reg: u16) -> u8 {
+        unsafe { u8::read_from_port(self.base + reg) }
+    }
+
+    /// Set the port up for 38400 baud, 8 data bits, no parity, one stop bit, no interrupts
+    pub fn init(&mut self) {
+        self.write_reg(INTERRUPT_ENABLE, 0);
+
+        // the divisor is from the 115200 baud base clock
+        self.write_reg(LINE_CONTROL, DLAB);
+        self.write_reg(DATA, 3);
+        self.write_reg(INTERRUPT_ENABLE, 0);
+
+        // 8n1, which also clears DLAB
+        self.write_reg(LINE_CONTROL, 0x03);
+        // enable and clear the FIFOs, with a 14 byte threshold
+        self.write_reg(FIFO_CONTROL, 0xc7);
+        // DTR, RTS, and OUT2
+        self.write_reg(MODEM_CONTROL, 0x0b);
+    }
+
+    /// Interrupt whenever a byte comes in. The modem control register's OUT2, which
+    /// [`init`](SerialPort::init) sets, connects the interrupt line.
+    pub fn enable_receive_interrupt(&mut self) {
+        self.write_reg(INTERRUPT_ENABLE, RECEIVED_DATA);
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        while self.read_reg(LINE_STATUS) & TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write_reg(DATA, byte);
+    }
+
+    /// The next received byte, if one has come in
+    pub fn try_receive(&mut self) -> Option<u8> {
+        (self.read_reg(LINE_STATUS) & DATA_READY != 0).then(|| self.read_reg(DATA))
+    }
+
+    /// Wait for the next received byte
+    pub fn receive(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_receive() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref SERIAL1: SpinLock<SerialPort> = {
+        let mut port = SerialPort::new(COM1);
+        port.init();
+        SpinLock::new("serial", port)
+    };
+}
+
+/// Write text to the serial port
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+/// Write a line of text to the serial port
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    // an interrupt handler printing while we hold the lock would wait on it forever
+    interrupts::without_interrupts(|| {
+        SERIAL1.lock().write_fmt(args).unwrap();
+    });
+}
+
+/// Write to COM1 without going through [`SERIAL1`]'s lock
+///
+/// For reporting problems with locks themselves. The output can get mixed up with anything
+/// else being printed at the same time.
+pub fn write_unlocked(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = SerialPort::new(COM1).write_fmt(args);
+}
+
+fn interrupt() {
+    // everything the FIFO has, which also clears the interrupt
+    let mut port = SERIAL1.lock();
+    while let Some(byte) = port.try_receive() {
+        input::push(&[byte]);
+    }
+}
+
+fn init() {
+    interrupts::without_interrupts(|| {
+        let mut port = SERIAL1.lock();
+        // anything that came in before now would keep the interrupt from ever firing
+        while port.try_receive().is_some() {}
+        port.enable_receive_interrupt();
+    });
+    pic::set_handler(COM1_IRQ, interrupt);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "serial",
+    stage: Stage::Drivers,
+    after: &["pic"],
+    func: init,
+};
@@ -0,0 +1,208 @@
+//! File commands, through the VFS
+//!
+//! Paths that don't start with `/` are taken from the current directory, which `cd` changes.
+//!
+
+use alloc::format;
+use alloc::string::{String, ToString};
+
+use super::memory::parse_number;
+use crate::console::input;
+use crate::fmt::Hexdump;
+use crate::fs::fat;
+use crate::fs::vfs::{self, File, Kind, VfsError};
+use crate::sync::SpinLock;
+use crate::{block, print, println};
+
+/// Bytes `cat` and `hexdump` read and print at a time
+const CHUNK: usize = 512;
+/// Bytes `hexdump` shows without a length
+const HEXDUMP_LEN: u64 = 256;
+
+/// Normalized, empty until the first `cd`, which is `/`
+static CWD: SpinLock<String> = SpinLock::new("shell cwd", String::new());
+
+/// The directory relative paths start from
+pub fn cwd() -> String {
+    let cwd = CWD.lock();
+    if cwd.is_empty() {
+        "/".to_string()
+    } else {
+        cwd.clone()
+    }
+}
+
+/// `path` as an absolute path
+fn absolute(path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("{}/{}", cwd(), path)
+    }
+}
+
+pub fn cd(args: &[&str]) {
+    let path = absolute(args.get(1).copied().unwrap_or("/"));
+    let result = vfs::normalize(&path).and_then(|path| match vfs::metadata(&path)?.kind {
+        Kind::Directory => Ok(path),
+        _ => Err(VfsError::NotADirectory),
+    });
+    match result {
+        Ok(path) => *CWD.lock() = path,
+        Err(error) => println!("cd: {}: {:?}", path, error),
+    }
+}
+
+pub fn pwd(_args: &[&str]) {
+    println!("{}", cwd());
+}
+
+pub fn ls(args: &[&str]) {
+    let path = args.get(1).map_or_else(cwd, |path| absolute(path));
+    match vfs::read_dir(&path) {
+        Ok(entries) => {
+            for entry in entries {
+                let suffix = if entry.kind == Kind::Directory {
+                    "/"
+                } else {
+                    ""
+                };
+                println!("{}{}", entry.name, suffix);
+            }
+        }
+        Err(error) => println!("ls: {}: {:?}", path, error),
+    }
+}
+
+pub fn cat(args: &[&str]) {
+    for path in &args[1..] {
+        let path = absolute(path);
+        if let Err(error) = vfs::open(&path).and_then(|mut file| print_file(&mut file)) {
+            println!("cat: {}: {:?}", path, error);
+        }
+    }
+}
+
+/// Print `file` a chunk at a time until it ends, or until a key is pressed, since some files,
+/// like `/dev/zero`, never do
+fn print_file(file: &mut File) -> Result<(), VfsError> {
+    let mut buf = [0; CHUNK];
+    // the start of a character the last chunk cut off
+    let mut carried = 0;
+    loop {
+        let len = file.read(&mut buf[carried..])?;
+        let end = carried + len;
+        let whole = match core::str::from_utf8(&buf[..end]) {
+            Err(error) if len != 0 && error.error_len().is_none() => error.valid_up_to(),
+            _ => end,
+        };
+        print!("{}", String::from_utf8_lossy(&buf[..whole]));
+        if len == 0 {
+            return Ok(());
+        }
+        buf.copy_within(whole..end, 0);
+        carried = end - whole;
+
+        if input::try_read().is_some() {
+            println!();
+            return Ok(());
+        }
+    }
+}
+
+pub fn hexdump(args: &[&str]) {
+    let Some(path) = args.get(1) else {
+        println!("usage: hexdump PATH [LEN [OFFSET]]");
+        return;
+    };
+    let number = |i: usize, default| match args.get(i) {
+        Some(arg) => parse_number(arg).ok_or(*arg),
+        None => Ok(default),
+    };
+    let (len, offset) = match (number(2, HEXDUMP_LEN), number(3, 0)) {
+        (Ok(len), Ok(offset)) => (len, offset),
+        (Err(arg), _) | (_, Err(arg)) => {
+            println!("hexdump: not a number: {}", arg);
+            return;
+        }
+    };
+
+    let path = absolute(path);
+    let result = vfs::open(&path).and_then(|mut file| {
+        file.seek(offset);
+        dump_file(&mut file, len)
+    });
+    if let Err(error) = result {
+        println!("hexdump: {}: {:?}", path, error);
+    }
+}
+
+/// Dump up to `len` bytes of `file` from its position, labelled with their offsets
+fn dump_file(file: &mut File, len: u64) -> Result<(), VfsError> {
+    let mut buf = [0; CHUNK];
+    let mut left = len;
+    while left > 0 {
+        let offset = file.position();
+        let want = left.min(CHUNK as u64) as usize;
+        let got = file.read(&mut buf[..want])?;
+        if got == 0 {
+            break;
+        }
+        print!("{}", Hexdump::new(&buf[..got]).at(offset));
+        left -= got as u64;
+
+        if input::try_read().is_some() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// List what's mounted, or mount the FAT32 volume on a block device
+pub fn mount(args: &[&str]) {
+    let [_, device, path] = args else {
+        if args.len() == 1 {
+            vfs::for_each_mount(|path, fs| println!("{} on {}", fs.name(), path));
+        } else {
+            println!("usage: mount [DEVICE PATH]");
+        }
+        return;
+    };
+    let Some(device) = block::get(device) else {
+        println!("mount: no block device {}", device);
+        return;
+    };
+    if let Err(error) = fat::mount_at(device, &absolute(path)) {
+        println!("mount: {}: {:?}", device.name(), error);
+    }
+}
+
+pub fn umount(args: &[&str]) {
+    for path in &args[1..] {
+        if let Err(error) = vfs::unmount(&absolute(path)) {
+            println!("umount: {}: {:?}", path, error);
+        }
+    }
+}
+
+/// Complete `word` as a path, from the entries of the directory it's in
+pub fn complete_path(word: &str, candidate: &mut dyn FnMut(&str)) {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(slash) => (&word[..=slash], &word[slash + 1..]),
+        None => ("", word),
+    };
+    let Ok(entries) = vfs::read_dir(&absolute(dir)) else {
+        return;
+    };
+    for entry in entries {
+        if !entry.name.starts_with(prefix) {
+            continue;
+        }
+        let suffix = if entry.kind == Kind::Directory {
+            "/"
+        } else {
+            ""
+        };
+        candidate(&format!("{}{}{}", dir, entry.name, suffix));
+    }
+}
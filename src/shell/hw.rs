@@ -0,0 +1,159 @@
+//! Hardware inspection commands
+//!
+
+use crate::console::input;
+use crate::cpu::features;
+use crate::fmt::Hexdump;
+use crate::net::capture;
+use crate::{block, console, interrupts, mouse, net, pci, print, println, task, time, vga};
+
+/// Mouse counts to a character cell. The defaults are 4 counts a millimeter.
+const COUNTS_PER_CELL: i32 = 8;
+
+pub fn lspci(_args: &[&str]) {
+    for device in pci::devices() {
+        println!("{}", device);
+        for (n, bar) in device.bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                println!("    bar {}: {}", n, bar);
+            }
+        }
+    }
+}
+
+pub fn lsblk(_args: &[&str]) {
+    println!("name        sectors  sector       size");
+    block::for_each(|device| {
+        println!(
+            "{:<8} {:>10}  {:>6} {:>6} KiB",
+            device.name(),
+            device.sector_count(),
+            device.sector_size(),
+            device.size() / 1024
+        );
+    });
+}
+
+pub fn lsnet(_args: &[&str]) {
+    println!("name  mac");
+    net::for_each(|device| println!("{:<5} {}", device.name(), device.mac()));
+    match net::address() {
+        Some(address) => println!("address {}", address),
+        None => println!("no address, set one with ip= on the command line"),
+    }
+    net::arp::for_each(|address, mac| println!("arp   {} is at {}", address, mac));
+}
+
+pub fn capture(args: &[&str]) {
+    match args[1..] {
+        [] => {
+            net::for_each(|device| {
+                let state = if capture::is_tapped(device) {
+                    "on"
+                } else {
+                    "off"
+                };
+                println!("{:<5} {}", device.name(), state);
+            });
+            println!("{} frames captured", capture::captured().len());
+        }
+        [state @ ("on" | "off"), name] => match net::get(name) {
+            Some(device) => capture::set_tapped(device, state == "on"),
+            None => println!("capture: no network device {}", name),
+        },
+        ["show"] => {
+            for frame in capture::captured() {
+                let ms = frame.time_ns / 1_000_000;
+                println!(
+                    "{}.{:03} {} {} {} bytes",
+                    ms / 1000,
+                    ms % 1000,
+                    frame.device,
+                    frame.direction.name(),
+                    frame.len
+                );
+                print!("{}", Hexdump::new(frame.data()));
+            }
+        }
+        ["pcap"] => {
+            // `xxd -r -p` takes the lines between the markers back to bytes
+            println!("-- pcap --");
+            print_hex(&capture::pcap_header());
+            for frame in capture::captured() {
+                print_hex(&capture::pcap_record(&frame));
+                print_hex(frame.data());
+            }
+            println!("-- end --");
+        }
+        ["clear"] => capture::clear(),
+        _ => println!("usage: capture [on|off DEVICE | show | pcap | clear]"),
+    }
+}
+
+/// `bytes` as lines of plain hex, 32 bytes to a line
+fn print_hex(bytes: &[u8]) {
+    for line in bytes.chunks(32) {
+        for byte in line {
+            print!("{:02x}", byte);
+        }
+        println!();
+    }
+}
+
+pub fn lsirq(_args: &[&str]) {
+    print!("{}", interrupts::stats());
+}
+
+pub fn cpuinfo(_args: &[&str]) {
+    let Some(info) = features::info() else {
+        println!("cpuinfo: the CPU hasn't been identified yet");
+        return;
+    };
+    println!("vendor:   {}", info.vendor());
+    if let Some(brand) = info.brand() {
+        println!("model:    {}", brand);
+    }
+    println!(
+        "family {:#x}, model {:#x}, stepping {}",
+        info.family, info.model, info.stepping
+    );
+    println!("apic id:  {}", info.apic_id);
+    println!("tsc:      {} MHz", time::tsc_hz() / 1_000_000);
+    println!("flags:    {}", info.features);
+}
+
+pub fn mouse(_args: &[&str]) {
+    let (columns, rows) = console::with_console(|console| console.size());
+    let (columns, rows) = (
+        columns as i32 * COUNTS_PER_CELL,
+        rows as i32 * COUNTS_PER_CELL,
+    );
+    // in mouse counts, starting in the middle
+    let (mut x, mut y) = (columns / 2, rows / 2);
+    let mut buttons = (false, false, false);
+
+    loop {
+        while let Some(event) = mouse::try_read() {
+            x = (x + event.dx as i32).clamp(0, columns - 1);
+            y = (y + event.dy as i32).clamp(0, rows - 1);
+            let (col, row) = (
+                (x / COUNTS_PER_CELL) as usize,
+                (y / COUNTS_PER_CELL) as usize,
+            );
+            vga::set_pointer(Some((row, col)));
+
+            if (event.left, event.right, event.middle) != buttons {
+                buttons = (event.left, event.right, event.middle);
+                println!(
+                    "{}, {}: left {}, right {}, middle {}",
+                    col, row, event.left, event.right, event.middle
+                );
+            }
+        }
+        if input::try_read().is_some() {
+            break;
+        }
+        task::idle();
+    }
+    vga::set_pointer(None);
+}
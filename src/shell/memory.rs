@@ -0,0 +1,66 @@
+//! Memory inspection commands
+//!
+
+use x86_64::VirtAddr;
+
+#[cfg(debug_assertions)]
+use crate::debug::symbols;
+use crate::mem::{self, paging};
+use crate::println;
+
+/// A decimal number, or hex with 0x
+pub(super) fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Sites listed at most
+#[cfg(debug_assertions)]
+const MAX_SITES: usize = 20;
+
+/// Outstanding heap allocations by where they were made
+#[cfg(debug_assertions)]
+pub fn leaks(_args: &[&str]) {
+    let (sites, untracked) = mem::track::by_site();
+    println!("{:>8} {:>10}  site", "count", "bytes");
+    for site in sites.iter().take(MAX_SITES) {
+        println!(
+            "{:>8} {:>10}  {}",
+            site.count,
+            site.bytes,
+            symbols::resolve(site.addr)
+        );
+    }
+    if sites.len() > MAX_SITES {
+        println!("and {} more sites", sites.len() - MAX_SITES);
+    }
+    if untracked > 0 {
+        println!(
+            "{} allocations weren't tracked, the table was full",
+            untracked
+        );
+    }
+}
+
+#[cfg(not(debug_assertions))]
+pub fn leaks(_args: &[&str]) {
+    println!("leaks: allocations are only tracked in debug builds");
+}
+
+pub fn memmap(_args: &[&str]) {
+    mem::dump_memory_map();
+}
+
+pub fn pt(args: &[&str]) {
+    let addr = match args {
+        [_, addr] => parse_number(addr),
+        _ => None,
+    };
+    match addr.map(VirtAddr::try_new) {
+        Some(Ok(virt)) => paging::dump_mapping(virt),
+        Some(Err(_)) => println!("pt: the address isn't canonical"),
+        None => println!("usage: pt <addr>"),
+    }
+}
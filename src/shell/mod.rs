@@ -0,0 +1,594 @@
+//! Interactive kernel shell
+//!
+//! A prompt for poking at the running kernel. Output goes wherever `print!` goes, which
+//! includes the serial port unless that's been turned off, and input comes from both the
+//! keyboard and the serial port. It needs interrupts to work, unlike
+//! [`kdb`](crate::debug::kdb), which is still the place to go when something is broken. The
+//! shell is for when things work.
+//!
+//! Lines are read with an [`Editor`], which has cursor keys, history, and tab completion of
+//! command names, and of paths after them. File commands take paths from the current
+//! directory unless they start with `/`, see `cd`.
+//!
+//! Commands are looked up in [`COMMANDS`]. A line is split on whitespace into arguments, and
+//! double quotes group words with spaces into one argument.
+//!
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::console::readline::Editor;
+use crate::console::{self, sink};
+use crate::debug::{fault, profile};
+use crate::log::{self, LogLevel};
+use crate::mem::stack::StackUsage;
+use crate::sched::ThreadInfo;
+use crate::{
+    debug, klog, mem, power, print, println, process, rtc, sched, serial_print, task, timer, user,
+    watchdog,
+};
+
+/// Arguments passed to a command at most, including its name
+const MAX_ARGS: usize = 16;
+
+const PROMPT: &str = "zenix> ";
+
+pub struct Command {
+    pub name: &'static str,
+    /// One line for `help`
+    pub help: &'static str,
+    /// Called with the arguments, `args[0]` is the command's name
+    pub run: fn(args: &[&str]),
+}
+
+pub static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list commands",
+        run: help,
+    },
+    Command {
+        name: "echo",
+        help: "print the arguments",
+        run: echo,
+    },
+    Command {
+        name: "clear",
+        help: "clear the screen",
+        run: clear,
+    },
+    Command {
+        name: "uptime",
+        help: "time since boot",
+        run: uptime,
+    },
+    Command {
+        name: "ticks",
+        help: "timer interrupts since boot",
+        run: |_| {
+            let tick_ns = timer::tick_ns();
+            println!(
+                "{} ticks of {}.{:03} ms",
+                timer::ticks(),
+                tick_ns / 1_000_000,
+                tick_ns / 1000 % 1000
+            );
+        },
+    },
+    Command {
+        name: "date",
+        help: "the date and time from the RTC",
+        run: |_| println!("{}", rtc::now()),
+    },
+    Command {
+        name: "loglevel",
+        help: "show or set the log level",
+        run: loglevel,
+    },
+    Command {
+        name: "sink",
+        help: "list output sinks, or turn one on or off",
+        run: sink,
+    },
+    Command {
+        name: "dmesg",
+        help: "print the kernel message buffer",
+        run: |_| klog::for_each_line(|line| print!("{}", line)),
+    },
+    Command {
+        name: "free",
+        help: "physical memory usage",
+        run: free,
+    },
+    Command {
+        name: "mem",
+        help: "the same as free",
+        run: free,
+    },
+    Command {
+        name: "memmap",
+        help: "the memory map from the bootloader",
+        run: memory::memmap,
+    },
+    Command {
+        name: "leaks",
+        help: "heap allocations not freed yet, by where they were made",
+        run: memory::leaks,
+    },
+    Command {
+        name: "pt",
+        help: "walk the page tables for an address",
+        run: memory::pt,
+    },
+    Command {
+        name: "ps",
+        help: "list threads and tasks",
+        run: ps,
+    },
+    Command {
+        name: "top",
+        help: "show threads by CPU time, every second until a key is pressed",
+        run: top,
+    },
+    Command {
+        name: "run",
+        help: "list user programs, or run some at once",
+        run: run_program,
+    },
+    Command {
+        name: "cd",
+        help: "change the current directory, to / without one",
+        run: files::cd,
+    },
+    Command {
+        name: "pwd",
+        help: "print the current directory",
+        run: files::pwd,
+    },
+    Command {
+        name: "ls",
+        help: "list a directory",
+        run: files::ls,
+    },
+    Command {
+        name: "cat",
+        help: "print files, a key stops one that doesn't end",
+        run: files::cat,
+    },
+    Command {
+        name: "hexdump",
+        help: "dump a file in hex, LEN bytes from OFFSET",
+        run: files::hexdump,
+    },
+    Command {
+        name: "mount",
+        help: "list mounted filesystems, or mount a FAT32 disk: mount DEVICE PATH",
+        run: files::mount,
+    },
+    Command {
+        name: "umount",
+        help: "unmount filesystems",
+        run: files::umount,
+    },
+    Command {
+        name: "lspci",
+        help: "list PCI devices",
+        run: hw::lspci,
+    },
+    Command {
+        name: "lsblk",
+        help: "list block devices",
+        run: hw::lsblk,
+    },
+    Command {
+        name: "lsnet",
+        help: "list network cards",
+        run: hw::lsnet,
+    },
+    Command {
+        name: "capture",
+        help: "capture frames on a network device, and show them",
+        run: hw::capture,
+    },
+    Command {
+        name: "lsirq",
+        help: "interrupt counts, by cpu and vector",
+        run: hw::lsirq,
+    },
+    Command {
+        name: "mouse",
+        help: "move a pointer around with the mouse, until a key is pressed",
+        run: hw::mouse,
+    },
+    Command {
+        name: "cpuinfo",
+        help: "what CPUID says about this CPU",
+        run: hw::cpuinfo,
+    },
+    Command {
+        name: "bench",
+        help: "time kernel primitives, or the ones matching a name",
+        run: |args| debug::bench::run_kernel(args.get(1).copied()),
+    },
+    Command {
+        name: "fault",
+        help: "list fault injection points, or make one fire every N passes",
+        run: fault,
+    },
+    Command {
+        name: "profile",
+        help: "sample where the CPU is on every tick: start, stop, or show",
+        run: profile,
+    },
+    Command {
+        name: "reboot",
+        help: "restart the machine",
+        run: |_| power::reboot(),
+    },
+    Command {
+        name: "shutdown",
+        help: "power off",
+        run: |_| power::shutdown(),
+    },
+];
+
+mod files;
+mod hw;
+mod memory;
+
+fn help(_args: &[&str]) {
+    for command in COMMANDS {
+        println!("  {:<10} {}", command.name, command.help);
+    }
+}
+
+fn echo(args: &[&str]) {
+    let mut words = args[1..].iter();
+    if let Some(first) = words.next() {
+        print!("{}", first);
+    }
+    for word in words {
+        print!(" {}", word);
+    }
+    println!();
+}
+
+fn clear(_args: &[&str]) {
+    console::with_console(|console| console.clear());
+    serial_print!("\x1b[2J\x1b[H");
+}
+
+fn uptime(_args: &[&str]) {
+    let ms = timer::uptime_ms();
+    let secs = ms / 1000;
+    println!(
+        "up {}:{:02}:{:02}.{:03}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        ms % 1000
+    );
+}
+
+fn loglevel(args: &[&str]) {
+    match args.get(1) {
+        None => println!("{}", log::level().name()),
+        Some(name) => match LogLevel::from_name(name) {
+            Some(level) => log::set_level(level),
+            None => println!("levels are debug, info, warn, and error"),
+        },
+    }
+}
+
+fn sink(args: &[&str]) {
+    match args {
+        [_] => sink::for_each(|name, enabled| {
+            println!("  {:<10} {}", name, if enabled { "on" } else { "off" });
+        }),
+        [_, name, state @ ("on" | "off")] => {
+            if !sink::set_enabled(name, *state == "on") {
+                println!("sink: no sink called {}", name);
+            }
+        }
+        _ => println!("usage: sink [<name> on|off]"),
+    }
+}
+
+fn free(_args: &[&str]) {
+    let Some(stats) = mem::stats() else {
+        println!("no memory map");
+        return;
+    };
+
+    println!(
+        "{:>12} {:>12} {:>12} {:>12}",
+        "total", "usable", "kernel", "reserved"
+    );
+    println!(
+        "{:>10}Ki {:>10}Ki {:>10}Ki {:>10}Ki",
+        stats.total() / 1024,
+        stats.usable / 1024,
+        stats.kernel / 1024,
+        stats.reserved / 1024
+    );
+
+    if let Some(frames) = mem::frame::stats() {
+        println!();
+        println!("{:>12} {:>12} {:>12}", "frames", "used", "free");
+        println!(
+            "{:>10}Ki {:>10}Ki {:>10}Ki",
+            frames.total * mem::frame::FRAME_SIZE / 1024,
+            frames.used * mem::frame::FRAME_SIZE / 1024,
+            frames.free() * mem::frame::FRAME_SIZE / 1024
+        );
+    }
+
+    let heap = mem::heap::stats();
+    println!();
+    println!("{:>12} {:>12} {:>12}", "heap", "used", "allocations");
+    println!(
+        "{:>10}Ki {:>10}Ki {:>12}",
+        heap.size / 1024,
+        heap.used / 1024,
+        heap.allocations
+    );
+    for (i, count) in heap.by_size.iter().enumerate() {
+        match mem::heap::BLOCK_SIZES.get(i) {
+            Some(size) => print!(" {}:{}", size, count),
+            None => println!(" large:{}", count),
+        }
+    }
+}
+
+/// A thread's deepest stack use and its stack size, in KiB
+fn stack_column(stack: Option<StackUsage>) -> String {
+    match stack {
+        Some(stack) => format!("{}/{}Ki", stack.peak.div_ceil(1024), stack.size / 1024),
+        None => String::from("-"),
+    }
+}
+
+fn ps(_args: &[&str]) {
+    println!(
+        "  {:>4} {:<16} {:<8} {:>8} {:>10}",
+        "id", "name", "state", "ticks", "stack"
+    );
+    sched::for_each(|thread| {
+        println!(
+            "  {:>4} {:<16} {:<8} {:>8} {:>10}",
+            thread.id,
+            thread.name,
+            thread.state.name(),
+            thread.ticks,
+            stack_column(thread.stack)
+        );
+    });
+    println!("{} async tasks", task::executor::count());
+}
+
+/// How often `top` redraws
+const TOP_INTERVAL_MS: u64 = 1000;
+
+fn top(_args: &[&str]) {
+    let mut last = Vec::new();
+    sched::for_each(|thread| last.push(thread));
+    let mut last_ticks = timer::ticks();
+    loop {
+        watchdog::MAIN.touch();
+        timer::sleep_ms(TOP_INTERVAL_MS);
+        let mut threads = Vec::new();
+        sched::for_each(|thread| threads.push(thread));
+        let ticks = timer::ticks();
+        let elapsed = (ticks - last_ticks).max(1);
+
+        // ticks each thread ran since the last time, a new one's are all since
+        let mut rows: Vec<_> = threads
+            .iter()
+            .map(|thread| {
+                let before = last
+                    .iter()
+                    .find(|old: &&ThreadInfo| old.id == thread.id)
+                    .map_or(0, |old| old.ticks);
+                (thread, thread.ticks - before)
+            })
+            .collect();
+        rows.sort_by_key(|&(_, ran)| core::cmp::Reverse(ran));
+
+        clear(&[]);
+        println!(
+            "up {}s, {} threads, {} async tasks",
+            timer::uptime_ms() / 1000,
+            threads.len(),
+            task::executor::count()
+        );
+        println!(
+            "  {:>4} {:<16} {:<8} {:>5} {:>8} {:>10}",
+            "id", "name", "state", "cpu%", "ticks", "stack"
+        );
+        for (thread, ran) in rows {
+            println!(
+                "  {:>4} {:<16} {:<8} {:>5} {:>8} {:>10}",
+                thread.id,
+                thread.name,
+                thread.state.name(),
+                ran * 100 / elapsed,
+                thread.ticks,
+                stack_column(thread.stack)
+            );
+        }
+        println!("press a key to stop");
+
+        last = threads;
+        last_ticks = ticks;
+        if console::input::try_read().is_some() {
+            return;
+        }
+    }
+}
+
+fn fault(args: &[&str]) {
+    match args[1..] {
+        [] => {
+            println!("{:<12} {:>8} {:>8}", "point", "every", "fired");
+            for point in fault::POINTS {
+                println!(
+                    "{:<12} {:>8} {:>8}",
+                    point.name,
+                    point.every(),
+                    point.fired()
+                );
+            }
+        }
+        [name, every] => match (fault::get(name), every.parse()) {
+            (Some(point), Ok(every)) => point.set_every(every),
+            (None, _) => println!("fault: no point called {}", name),
+            (_, Err(_)) => println!("fault: not a number: {}", every),
+        },
+        _ => println!("usage: fault [POINT N], 0 turns it off"),
+    }
+}
+
+/// Functions `profile show` lists at most
+const MAX_PROFILED: usize = 25;
+
+fn profile(args: &[&str]) {
+    match args.get(1).copied() {
+        Some("start") => profile::start(),
+        Some("stop") => profile::stop(),
+        Some("show") => {
+            let (entries, samples) = profile::profile();
+            if samples == 0 {
+                println!("profile: no samples, start it first");
+                return;
+            }
+            println!("{} samples", samples);
+            println!("{:>6} {:>6}  function", "own%", "total%");
+            for entry in entries.iter().take(MAX_PROFILED) {
+                let function = if entry.addr == 0 {
+                    String::from("(user)")
+                } else {
+                    format!("{}", debug::symbols::resolve(entry.addr))
+                };
+                println!(
+                    "{:>6} {:>6}  {}",
+                    entry.own * 100 / samples,
+                    entry.total * 100 / samples,
+                    function
+                );
+            }
+        }
+        _ => {
+            let state = if profile::is_running() {
+                "running"
+            } else {
+                "stopped"
+            };
+            println!("profile is {}", state);
+            println!("usage: profile start|stop|show");
+        }
+    }
+}
+
+fn run_program(args: &[&str]) {
+    if args.len() < 2 {
+        for program in user::PROGRAMS {
+            println!("  {}", program.name);
+        }
+        return;
+    }
+    let mut pids = Vec::new();
+    for name in &args[1..] {
+        match user::run(name) {
+            Ok(pid) => pids.push(pid),
+            Err(error) => println!("run: {}: {:?}", name, error),
+        }
+    }
+    // wait for them, they have the console until they're done
+    for pid in pids {
+        while let Ok(None) = process::try_wait(pid) {
+            watchdog::MAIN.touch();
+            timer::sleep_ms(10);
+        }
+    }
+}
+
+/// Split `line` into arguments, double quotes keep spaces in one. Anything past `MAX_ARGS`
+/// arguments is dropped.
+fn split_args<'a>(line: &'a str, args: &mut [&'a str; MAX_ARGS]) -> usize {
+    let mut count = 0;
+    let mut rest = line.trim_start();
+
+    while !rest.is_empty() && count < MAX_ARGS {
+        let (arg, after) = match rest.strip_prefix('"') {
+            // an unterminated quote runs to the end of the line
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+        };
+        args[count] = arg;
+        count += 1;
+        rest = after.trim_start();
+    }
+
+    count
+}
+
+/// Complete command names for the first word, and paths after it
+fn complete(line: &str, word: &str, candidate: &mut dyn FnMut(&str)) {
+    if word.len() != line.trim_start().len() {
+        files::complete_path(word, candidate);
+        return;
+    }
+    for command in COMMANDS {
+        if command.name.starts_with(word) {
+            candidate(command.name);
+        }
+    }
+}
+
+/// Run one line of input
+pub fn execute(line: &str) {
+    let mut args = [""; MAX_ARGS];
+    let count = split_args(line, &mut args);
+    if count == 0 {
+        return;
+    }
+
+    let args = &args[..count];
+    match COMMANDS.iter().find(|command| command.name == args[0]) {
+        Some(command) => (command.run)(args),
+        None => println!("{}: unknown command, try help", args[0]),
+    }
+}
+
+/// Take commands forever
+pub fn run() -> ! {
+    println!("type help for commands");
+
+    let mut editor = Editor::new(complete);
+    loop {
+        if let Some(line) = editor.read_line(PROMPT) {
+            execute(line);
+            watchdog::MAIN.touch();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn split_on_whitespace() {
+        let mut args = [""; MAX_ARGS];
+        let count = split_args("  echo a   b\tc ", &mut args);
+        assert_eq!(&args[..count], &["echo", "a", "b", "c"]);
+    }
+
+    #[test_case]
+    fn split_quoted() {
+        let mut args = [""; MAX_ARGS];
+        let count = split_args(r#"echo "a b" "" "c"#, &mut args);
+        assert_eq!(&args[..count], &["echo", "a b", "", "c"]);
+    }
+}
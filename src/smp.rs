@@ -0,0 +1,314 @@
+//! Starting the other CPUs
+//!
+//! Only the boot CPU runs when the kernel starts. [`INIT`] wakes the application processors
+//! the MADT lists, one at a time: each gets an INIT and a startup IPI pointing at a trampoline
+//! page below 1 MiB, since that's where they start, in real mode. The trampoline goes straight
+//! to long mode on the kernel's page tables and calls `ap_main` on the stack it was left, which
+//! sets the CPU up, says hello, and idles.
+//!
+//! A CPU that doesn't make it in time could still be on its way, reading the trampoline and
+//! the stacks it was given, so it's the last one started, and the trampoline stays mapped.
+//!
+//! Nothing runs on the application processors yet, and no interrupts are routed to them.
+//!
+//! links:
+//! - <https://wiki.osdev.org/Symmetric_Multiprocessing>
+//! - <https://wiki.osdev.org/Entering_Long_Mode_Directly>
+//! - Intel SDM vol. 3A, 8.4.4 "MP Initialization Example"
+//!
+
+use core::arch::global_asm;
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use x86_64::instructions::{self, interrupts};
+use x86_64::registers::control::{Cr3, Cr4};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::init::{InitCall, Stage};
+use crate::mem::frame::{self, FRAME_SIZE};
+use crate::mem::{paging, phys_to_virt, stack};
+use crate::{acpi, apic, gdt, ilog, percpu, println, syscall, timer, wlog};
+
+/// Stack size for each application processor
+const STACK_SIZE: usize = 16 * 1024;
+
+/// How long to wait for a CPU to get to `ap_main`
+const STARTUP_TIMEOUT_MS: u64 = 100;
+
+/// Where [`Args`] goes in the trampoline page, the code has to fit in front of it
+const ARGS_OFFSET: usize = 0xf00;
+
+/// What the trampoline needs to know, filled in for each CPU
+#[repr(C)]
+struct Args {
+    /// Far pointer to `ap_long_mode`, for the jump into long mode
+    long_mode: u32,
+    long_mode_selector: u16,
+    /// GDT pointer for `lgdt`, to `gdt`
+    gdt_limit: u16,
+    gdt_base: u32,
+    _pad: u32,
+    cr3: u64,
+    cr4: u64,
+    stack: u64,
+    entry: u64,
+    /// The CPU's [`percpu`] block, which it loads before anything takes a lock
+    percpu: u64,
+    /// Null, 64-bit code, and data descriptors
+    gdt: [u64; 3],
+}
+
+const CODE_SELECTOR: u16 = 0x08;
+const CODE_DESCRIPTOR: u64 = 0x0020_9a00_0000_0000;
+const DATA_DESCRIPTOR: u64 = 0x0000_9200_0000_0000;
+
+// Starts at CS:0 in real mode with CS set to the trampoline's page, so everything in the 16-bit
+// part is addressed from the start of the page. CR4 and CR3 get the boot CPU's values,
+// EFER gets long mode and no-execute, and then paging and protection go on together.
+global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".code16",
+    ".global ap_trampoline",
+    "ap_trampoline:",
+    "    cli",
+    "    cld",
+    "    movw %cs, %ax",
+    "    movw %ax, %ds",
+    "    movl {args}+{cr4}, %eax",
+    "    movl %eax, %cr4",
+    "    movl {args}+{cr3}, %eax",
+    "    movl %eax, %cr3",
+    "    movl $0xc0000080, %ecx",
+    "    rdmsr",
+    "    orl $0x900, %eax",
+    "    wrmsr",
+    "    lgdtl {args}+{gdtr}",
+    "    movl $0x80010001, %eax",
+    "    movl %eax, %cr0",
+    "    ljmpl *{args}",
+    ".code64",
+    ".global ap_long_mode",
+    "ap_long_mode:",
+    "    movw $0x10, %ax",
+    "    movw %ax, %ds",
+    "    movw %ax, %es",
+    "    movw %ax, %ss",
+    "    leaq ap_trampoline(%rip), %rbx",
+    "    movq {args}+{stack}(%rbx), %rsp",
+    "    movq {args}+{percpu}(%rbx), %rdi",
+    "    movq {args}+{entry}(%rbx), %rax",
+    "    callq *%rax",
+    "    ud2",
+    ".global ap_trampoline_end",
+    "ap_trampoline_end:",
+    ".popsection",
+    args = const ARGS_OFFSET,
+    cr3 = const offset_of!(Args, cr3),
+    cr4 = const offset_of!(Args, cr4),
+    gdtr = const offset_of!(Args, gdt_limit),
+    stack = const offset_of!(Args, stack),
+    percpu = const offset_of!(Args, percpu),
+    entry = const offset_of!(Args, entry),
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline: u8;
+    static ap_long_mode: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// The trampoline page, 0 if there isn't one
+static TRAMPOLINE: AtomicU64 = AtomicU64::new(0);
+
+/// CPUs that have made it to `ap_main`, and the boot CPU
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+/// Set by each CPU once it's done with what the trampoline left it
+static STARTED: AtomicBool = AtomicBool::new(false);
+/// Top of the next CPU's double fault stack
+static DOUBLE_FAULT_STACK: AtomicU64 = AtomicU64::new(0);
+
+/// How many CPUs are running
+pub fn online() -> usize {
+    ONLINE.load(Ordering::Relaxed)
+}
+
+extern "C" fn ap_main(percpu: u64) -> ! {
+    unsafe { percpu::load(VirtAddr::new(percpu)) };
+    gdt::init_ap(VirtAddr::new(DOUBLE_FAULT_STACK.load(Ordering::Relaxed)));
+    crate::interrupts::load();
+    syscall::init_cpu();
+    apic::enable_ap();
+    STARTED.store(true, Ordering::Release);
+
+    ONLINE.fetch_add(1, Ordering::Relaxed);
+    println!(
+        "smp: cpu {} is up, local APIC {}",
+        percpu::cpu_id(),
+        percpu::apic_id()
+    );
+    interrupts::enable();
+    loop {
+        instructions::hlt();
+    }
+}
+
+/// Copy the trampoline into `page` and point it at the kernel's page tables
+fn load_trampoline(page: PhysAddr) -> &'static mut Args {
+    let start = &raw const ap_trampoline;
+    let len = &raw const ap_trampoline_end as usize - start as usize;
+    assert!(len <= ARGS_OFFSET, "smp: the trampoline is too big");
+
+    let base = phys_to_virt(page);
+    let args = unsafe {
+        core::ptr::copy_nonoverlapping(start, base.as_mut_ptr::<u8>(), len);
+        &mut *(base + ARGS_OFFSET as u64).as_mut_ptr::<Args>()
+    };
+
+    let long_mode = &raw const ap_long_mode as u64 - start as u64;
+    let gdt = page + ARGS_OFFSET as u64 + offset_of!(Args, gdt) as u64;
+    *args = Args {
+        long_mode: (page.as_u64() + long_mode) as u32,
+        long_mode_selector: CODE_SELECTOR,
+        gdt_limit: (size_of::<[u64; 3]>() - 1) as u16,
+        gdt_base: gdt.as_u64() as u32,
+        _pad: 0,
+        cr3: Cr3::read().0.start_address().as_u64(),
+        cr4: Cr4::read_raw(),
+        stack: 0,
+        entry: ap_main as *const () as u64,
+        percpu: 0,
+        gdt: [0, CODE_DESCRIPTOR, DATA_DESCRIPTOR],
+    };
+    args
+}
+
+#[derive(Debug)]
+enum StartError {
+    /// Nothing was sent, so the CPU never ran
+    NoStacks,
+    /// The CPU was woken but didn't get to `ap_main` in time
+    TimedOut,
+}
+
+/// Give CPU `cpu` its stacks and wake it
+fn start(args: &mut Args, page: PhysAddr, cpu: usize, apic_id: u8) -> Result<(), StartError> {
+    // the CPUs never stop, so neither do their stacks
+    let stacks = stack::allocate("cpu", STACK_SIZE).and_then(|stack| {
+        let double_fault_stack = stack::allocate("double fault", gdt::IST_STACK_SIZE)?;
+        Ok((stack.leak(), double_fault_stack.leak()))
+    });
+    let (stack, double_fault_stack) = match stacks {
+        Ok(stacks) => stacks,
+        Err(error) => {
+            wlog!("smp: no stacks for cpu {}: {:?}", cpu, error);
+            return Err(StartError::NoStacks);
+        }
+    };
+    args.stack = stack.as_u64();
+    args.percpu = percpu::new_block(cpu, apic_id).as_u64();
+    DOUBLE_FAULT_STACK.store(double_fault_stack.as_u64(), Ordering::Relaxed);
+    STARTED.store(false, Ordering::Release);
+
+    let page = (page.as_u64() / FRAME_SIZE) as u8;
+    apic::send_init(apic_id);
+    timer::pit_wait_ms(10);
+    // a second startup IPI is only for CPUs that missed the first
+    for _ in 0..2 {
+        apic::send_startup(apic_id, page);
+        timer::pit_wait_ms(1);
+        if STARTED.load(Ordering::Acquire) {
+            return Ok(());
+        }
+    }
+
+    for _ in 0..STARTUP_TIMEOUT_MS {
+        if STARTED.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        timer::pit_wait_ms(1);
+    }
+    Err(StartError::TimedOut)
+}
+
+/// Take the trampoline page before the low memory is used up
+fn reserve() {
+    let limit = PhysAddr::new(0x10_0000);
+    match frame::FRAMES
+        .lock()
+        .as_mut()
+        .and_then(|frames| frames.allocate_below(limit))
+    {
+        Some(page) => TRAMPOLINE.store(page.start_address().as_u64(), Ordering::Relaxed),
+        None => wlog!("smp: no memory below 1 MiB, only the boot CPU can run"),
+    }
+}
+
+pub const RESERVE_INIT: InitCall = InitCall {
+    name: "smp trampoline",
+    stage: Stage::Memory,
+    after: &["frame"],
+    func: reserve,
+};
+
+fn init() {
+    let Some(madt) = acpi::madt() else {
+        return;
+    };
+    let page = PhysAddr::new(TRAMPOLINE.load(Ordering::Relaxed));
+    if page.is_null() || !apic::enabled() {
+        return;
+    }
+    let (level_4, _) = Cr3::read();
+    if level_4.start_address().as_u64() >= 1 << 32 {
+        wlog!("smp: the page tables are out of reach of the trampoline");
+        return;
+    }
+
+    // the trampoline keeps running where it is when paging goes on
+    let identity = VirtAddr::new(page.as_u64());
+    if let Err(error) = unsafe { paging::map_to(identity, page, PageTableFlags::PRESENT) } {
+        wlog!("smp: couldn't map the trampoline: {:?}", error);
+        return;
+    }
+    let args = load_trampoline(page);
+
+    let boot_cpu = apic::id();
+    let mut cpu = 1;
+    let mut timed_out = false;
+    for local_apic in madt.local_apics() {
+        if !local_apic.enabled || local_apic.apic_id == boot_cpu {
+            continue;
+        }
+        match interrupts::without_interrupts(|| start(args, page, cpu, local_apic.apic_id)) {
+            Ok(()) => cpu += 1,
+            Err(error) => {
+                // a late CPU might still be reading the arguments the next would get, and
+                // without stacks for this one there won't be any for the next
+                wlog!(
+                    "smp: the CPU with local APIC {} didn't start: {:?}, not starting the rest",
+                    local_apic.apic_id,
+                    error
+                );
+                timed_out = matches!(error, StartError::TimedOut);
+                break;
+            }
+        }
+    }
+
+    if timed_out {
+        wlog!("smp: keeping the trampoline mapped for the CPU that's late");
+    } else if let Err(error) = unsafe { paging::unmap(identity) } {
+        wlog!("smp: couldn't unmap the trampoline: {:?}", error);
+    }
+    ilog!("smp: {} cpus online", cpu);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "smp",
+    stage: Stage::Late,
+    after: &["apic", "heap"],
+    func: init,
+};
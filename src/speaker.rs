@@ -0,0 +1,172 @@
+//! PC speaker
+//!
+//! The speaker is driven by channel 2 of the PIT, gated through the keyboard controller's port
+//! B. [`beep`] starts a tone and returns straight away, the timer interrupt stops it once it's
+//! played for long enough. That way it works from anywhere text gets printed, which is where
+//! [`bell`] is played from for `\x07`. [`play_tones`] plays a sequence of tones the same way,
+//! one after another.
+//!
+//! With interrupts off there's no timer interrupt to stop anything, so [`play_blocking`]
+//! spins through its tones instead, timing them with the TSC since channel 2 is busy playing
+//! them. That's how a panic sounds its [`alert`].
+//!
+//! links:
+//! - <https://wiki.osdev.org/PC_Speaker>
+//!
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::structures::port::{PortRead as _, PortWrite as _};
+
+use crate::sync::SpinLock;
+use crate::time;
+use crate::timer::{self, PIT_HZ};
+
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 2, low then high byte, mode 3 (square wave)
+const CHANNEL2_SQUARE: u8 = 0b1011_0110;
+/// Channel 2's gate and the speaker enable, in port B
+const PORT_B: u16 = 0x61;
+const GATE_AND_SPEAKER: u8 = 0b11;
+
+const BELL_HZ: u32 = 880;
+const BELL_MS: u64 = 100;
+
+/// A panic's alert, high-low three times
+const ALERT: [Tone; 6] = [
+    Tone::new(1760, 150),
+    Tone::new(880, 150),
+    Tone::new(1760, 150),
+    Tone::new(880, 150),
+    Tone::new(1760, 150),
+    Tone::new(880, 300),
+];
+
+/// A note in a sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tone {
+    /// 0 for silence
+    pub hz: u32,
+    pub ms: u64,
+}
+
+impl Tone {
+    pub const fn new(hz: u32, ms: u64) -> Tone {
+        Tone { hz, ms }
+    }
+}
+
+/// Uptime the tone stops at, 0 when it's off
+static STOP_AT: AtomicU64 = AtomicU64::new(0);
+/// What [`play_tones`] has left to play after the current tone. Only locked with interrupts
+/// off, the timer interrupt goes through it.
+static SEQUENCE: SpinLock<&'static [Tone]> = SpinLock::new("speaker sequence", &[]);
+
+/// Play a tone at `hz` until it's stopped
+pub fn play(hz: u32) {
+    let divisor = (PIT_HZ / hz.max(1) as u64).clamp(1, u16::MAX as u64) as u16;
+    interrupts::without_interrupts(|| unsafe {
+        u8::write_to_port(PIT_COMMAND, CHANNEL2_SQUARE);
+        u8::write_to_port(PIT_CHANNEL2, divisor as u8);
+        u8::write_to_port(PIT_CHANNEL2, (divisor >> 8) as u8);
+        let port_b = u8::read_from_port(PORT_B);
+        u8::write_to_port(PORT_B, port_b | GATE_AND_SPEAKER);
+    });
+}
+
+/// Stop the tone, and whatever was left of a sequence
+pub fn stop() {
+    play_tones(&[]);
+}
+
+fn silence() {
+    STOP_AT.store(0, Ordering::Relaxed);
+    interrupts::without_interrupts(|| unsafe {
+        let port_b = u8::read_from_port(PORT_B);
+        u8::write_to_port(PORT_B, port_b & !GATE_AND_SPEAKER);
+    });
+}
+
+/// Start `tone`, to be stopped by the timer interrupt
+fn start(tone: Tone) {
+    if tone.hz == 0 {
+        silence();
+    } else {
+        play(tone.hz);
+    }
+    // a silent tone still has to end, so it can't be 0
+    STOP_AT.store(timer::uptime_ms() + tone.ms.max(1), Ordering::Relaxed);
+}
+
+/// Play a tone at `hz` for `ms` milliseconds, in the background
+pub fn beep(hz: u32, ms: u64) {
+    interrupts::without_interrupts(|| *SEQUENCE.lock() = &[]);
+    start(Tone::new(hz, ms));
+}
+
+/// Beep briefly, for `\x07`
+pub fn bell() {
+    beep(BELL_HZ, BELL_MS);
+}
+
+/// Play `tones` one after another, in the background, instead of whatever's playing
+pub fn play_tones(tones: &'static [Tone]) {
+    let first = interrupts::without_interrupts(|| {
+        let (first, rest) = tones
+            .split_first()
+            .map_or((None, &[][..]), |(first, rest)| (Some(*first), rest));
+        *SEQUENCE.lock() = rest;
+        first
+    });
+    match first {
+        Some(tone) => start(tone),
+        None => silence(),
+    }
+}
+
+/// Play `tones` one after another, returning once they're done. Works with interrupts off.
+pub fn play_blocking(tones: &[Tone]) {
+    // measuring the TSC uses channel 2, so that has to be done before it's playing anything
+    time::tsc_hz();
+    for &tone in tones {
+        STOP_AT.store(0, Ordering::Relaxed);
+        if tone.hz == 0 {
+            silence();
+        } else {
+            play(tone.hz);
+        }
+        let end = time::nanos() + tone.ms * 1_000_000;
+        while time::nanos() < end {
+            core::hint::spin_loop();
+        }
+    }
+    silence();
+}
+
+/// Sound the alert for a panic
+pub fn alert() {
+    play_blocking(&ALERT);
+}
+
+/// Called on every timer tick, moves on to the next tone when its time is up
+pub fn tick(uptime_ms: u64) {
+    let stop_at = STOP_AT.load(Ordering::Relaxed);
+    if stop_at == 0 || uptime_ms < stop_at {
+        return;
+    }
+    // interrupts are already off in the handler
+    let next = {
+        let mut sequence = SEQUENCE.lock();
+        let tones: &'static [Tone] = *sequence;
+        tones.split_first().map(|(next, rest)| {
+            *sequence = rest;
+            *next
+        })
+    };
+    match next {
+        Some(tone) => start(tone),
+        None => silence(),
+    }
+}
@@ -0,0 +1,101 @@
+//! Status line at the bottom of the VGA text screen
+//!
+//! In text mode the bottom row is taken out of the scrolling text and shows the uptime, the
+//! log level, and how much physical memory is free. It's repainted by a [`timer::every`]
+//! callback every [`INTERVAL_MS`], from the timer interrupt, which means it can't wait for locks: if the screen or the frame
+//! allocator is busy, that repaint is skipped or leaves the number out.
+//!
+//! `nostatus` on the command line keeps the whole screen for text, and `statusrows=` keeps more
+//! rows free above the status line, see [`config::status_rows`].
+//!
+
+use core::fmt::{self, Write as _};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::console::Color;
+use crate::init::{InitCall, Stage};
+use crate::mem::frame::{self, FRAME_SIZE};
+use crate::{config, gfx, log, timer, tty, wlog};
+
+pub const INTERVAL_MS: u64 = 1000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the status line is shown
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Formats into a line on the stack, the heap's lock can't be taken from an interrupt either
+struct Line {
+    buf: [u8; 80],
+    len: usize,
+}
+
+impl fmt::Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn draw(uptime_ms: u64) {
+    let mut line = Line {
+        buf: [0; 80],
+        len: 0,
+    };
+    let seconds = uptime_ms / 1000;
+    let _ = write!(
+        line,
+        " zenix | up {}:{:02}:{:02} | log {}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        log::level().name()
+    );
+    let stats = frame::FRAMES
+        .try_lock()
+        .and_then(|frames| frames.as_ref().map(|frames| frames.stats()));
+    if let Some(stats) = stats {
+        let _ = write!(line, " | free {} KiB", stats.free() * FRAME_SIZE / 1024);
+    }
+
+    // only printable ASCII went in
+    let text = core::str::from_utf8(&line.buf[..line.len]).unwrap_or("");
+    if let Some(mut writer) = tty::terminal(tty::active()).try_lock() {
+        writer.draw_status(text, Color::Black, Color::LightGray);
+    }
+}
+
+fn repaint() {
+    draw(timer::uptime_ms());
+}
+
+fn init() {
+    // the framebuffer console doesn't have one
+    if gfx::framebuffer_info().is_some() {
+        return;
+    }
+    let rows = config::status_rows();
+    if rows == 0 {
+        return;
+    }
+
+    for n in 0..tty::COUNT {
+        tty::terminal(n).lock().reserve_rows(rows);
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+    repaint();
+    if let Err(error) = timer::every(INTERVAL_MS, repaint) {
+        wlog!("statusbar: no timer for repaints: {:?}", error);
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "statusbar",
+    stage: Stage::Drivers,
+    after: &["timer", "vga", "config"],
+    func: init,
+};
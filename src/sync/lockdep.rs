@@ -0,0 +1,142 @@
+//! Lock order checking for debug builds
+//!
+//! Locks are grouped by name. Whenever a lock is taken while others are held, the order
+//! "held before new" is recorded. If the opposite order was seen before, two paths take the
+//! same pair of locks in different orders, and running them at the same time could deadlock.
+//! That's reported on the serial port, once per pair, even if the deadlock never happened.
+//!
+//! Each CPU has its own set of held locks, since a lock one CPU holds says nothing about the
+//! order another takes them in. The orders seen are shared. Before [`percpu`] is ready only the
+//! boot CPU runs, and it uses a set of its own.
+//!
+
+use core::cell::RefCell;
+use core::panic::Location;
+
+use crate::{percpu, serial};
+
+/// Most locks held at once that are tracked
+const MAX_HELD: usize = 16;
+/// Most lock orders remembered
+const MAX_ORDERS: usize = 128;
+
+struct Order {
+    before: &'static str,
+    after: &'static str,
+    /// Where `after` was first taken with `before` held
+    location: &'static Location<'static>,
+    reported: bool,
+}
+
+/// The locks one CPU holds, innermost last
+#[derive(Clone, Copy)]
+struct Held {
+    names: [&'static str; MAX_HELD],
+    depth: usize,
+}
+
+impl Held {
+    const fn new() -> Held {
+        Held {
+            names: [""; MAX_HELD],
+            depth: 0,
+        }
+    }
+}
+
+struct State {
+    orders: [Option<Order>; MAX_ORDERS],
+}
+
+crate::cpu_local! {
+    static HELD: RefCell<Held> = RefCell::new(Held::new());
+}
+
+// plain spin locks, tracking these would recurse
+static BOOT_HELD: spin::Mutex<Held> = spin::Mutex::new(Held::new());
+static STATE: spin::Mutex<State> = spin::Mutex::new(State {
+    orders: [const { None }; MAX_ORDERS],
+});
+
+/// Run `f` on this CPU's held locks. An NMI that comes in while they're being changed isn't
+/// tracked.
+fn with_held<R>(f: impl FnOnce(&mut Held) -> R) -> Option<R> {
+    if !percpu::is_ready() {
+        return Some(f(&mut BOOT_HELD.lock()));
+    }
+    HELD.with(|held| held.try_borrow_mut().ok().map(|mut held| f(&mut held)))
+}
+
+impl State {
+    fn find(&mut self, before: &str, after: &str) -> Option<&mut Order> {
+        self.orders
+            .iter_mut()
+            .flatten()
+            .find(|order| order.before == before && order.after == after)
+    }
+
+    fn record(&mut self, before: &'static str, after: &'static str, location: &'static Location) {
+        if self.find(before, after).is_some() {
+            return;
+        }
+        if let Some(slot) = self.orders.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(Order {
+                before,
+                after,
+                location,
+                reported: false,
+            });
+        }
+    }
+}
+
+/// Note that lock `name` was just taken at `location`
+pub fn acquired(name: &'static str, location: &'static Location<'static>) {
+    let Some(held) = with_held(|held| *held) else {
+        return;
+    };
+    let mut state = STATE.lock();
+
+    for &held in &held.names[..held.depth] {
+        // several locks with the same name can be nested, e.g. walking a list of them
+        if held == name {
+            continue;
+        }
+
+        if let Some(order) = state.find(name, held) {
+            if !order.reported {
+                order.reported = true;
+                serial::write_unlocked(format_args!(
+                    "lock order inversion: {} taken with {} held at {}\n",
+                    name, held, location
+                ));
+                serial::write_unlocked(format_args!(
+                    "  but {} was taken with {} held at {}\n",
+                    held, name, order.location
+                ));
+            }
+        } else {
+            state.record(held, name, location);
+        }
+    }
+    drop(state);
+
+    with_held(|held| {
+        if held.depth < MAX_HELD {
+            held.names[held.depth] = name;
+            held.depth += 1;
+        }
+    });
+}
+
+/// Note that lock `name` was just released
+pub fn released(name: &'static str) {
+    // usually the innermost lock, but locks don't have to be released in order
+    with_held(|held| {
+        let depth = held.depth;
+        if let Some(i) = held.names[..depth].iter().rposition(|&held| held == name) {
+            held.names.copy_within(i + 1..depth, i);
+            held.depth -= 1;
+        }
+    });
+}
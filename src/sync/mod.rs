@@ -0,0 +1,18 @@
+//! Synchronization primitives
+//!
+//! [`SpinLock`] is for data interrupt handlers touch, or that's only held briefly. The others
+//! block the thread that has to wait, see [`wait`]: [`Mutex`] for data that's held for longer,
+//! and [`Semaphore`] for counting things that come and go.
+//!
+
+#[cfg(debug_assertions)]
+pub mod lockdep;
+pub mod mutex;
+pub mod semaphore;
+pub mod spinlock;
+pub mod wait;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use spinlock::{SpinLock, SpinLockGuard};
+pub use wait::WaitQueue;
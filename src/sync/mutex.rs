@@ -0,0 +1,102 @@
+//! A lock that blocks instead of spinning
+//!
+//! [`Mutex`] puts a thread that finds it locked on a [`WaitQueue`] until the holder unlocks
+//! it, so the CPU goes to other threads meanwhile, and it can be held across a
+//! [`sleep_ms`](crate::timer::sleep_ms) or anything else that blocks. That also means it can't
+//! be taken from an interrupt handler, use a [`SpinLock`](super::SpinLock) for data those touch.
+//!
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::WaitQueue;
+
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+// the data is only reached through a guard, and there's only ever one
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(name: &'static str, value: T) -> Mutex<T> {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(name),
+            data: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Block until the lock is free, then take it
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let mut guard = None;
+        self.waiters.wait_until(|| {
+            guard = self.try_lock();
+            guard.is_some()
+        });
+        guard.unwrap()
+    }
+
+    /// Take the lock if it's free
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(MutexGuard { mutex: self })
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use super::*;
+    use crate::{sched, timer};
+
+    /// A thread that finds it locked blocks until it's unlocked, and then gets it
+    #[test_case]
+    fn waiter_gets_lock() {
+        let mutex = Arc::new(Mutex::new("test mutex", 0));
+        let guard = mutex.lock();
+
+        let other = mutex.clone();
+        sched::spawn("mutex test", move || *other.lock() += 1);
+        timer::sleep_ms(5);
+        assert_eq!(*guard, 0);
+
+        drop(guard);
+        timer::sleep_ms(5);
+        assert_eq!(*mutex.lock(), 1);
+    }
+}
@@ -0,0 +1,49 @@
+//! Counting semaphores
+//!
+//! A [`Semaphore`] hands out a number of permits. [`acquire`](Semaphore::acquire) blocks the
+//! thread until there's one to take, and [`release`](Semaphore::release) gives one back and
+//! wakes a waiter. Releasing never blocks, so an interrupt handler can do it, e.g. to say
+//! there's data for a thread to pick up.
+//!
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::WaitQueue;
+
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(name: &'static str, permits: usize) -> Semaphore {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(name),
+        }
+    }
+
+    /// Block until there's a permit, then take it
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Take a permit if there's one
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Permits there are to take right now
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,191 @@
+//! Spinlocks with deadlock detection in debug builds
+//!
+//! [`SpinLock`] is a `spin::Mutex` with a name, which holds off [`sched`](crate::sched)
+//! preemption while it's held. In release builds that's all it is. In debug builds it also
+//! remembers which CPU holds it and where it was locked from, and:
+//!
+//! - panics if a CPU tries to take a lock it already holds, which can never succeed
+//! - panics if it spins for more than [`SPIN_LIMIT`] cycles, naming where the lock was taken
+//! - reports locks taken in an order that contradicts an earlier one, see [`lockdep`]
+//!
+//! Problems are reported directly on the serial port, since the console's own locks might be
+//! the ones in trouble.
+//!
+//! [`lockdep`]: super::lockdep
+//!
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+#[cfg(debug_assertions)]
+use core::{
+    arch::x86_64::_rdtsc,
+    panic::Location,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering},
+};
+
+#[cfg(debug_assertions)]
+use super::lockdep;
+use crate::sched;
+#[cfg(debug_assertions)]
+use crate::serial;
+
+/// How long to spin before deciding a lock isn't coming back, about a few seconds
+#[cfg(debug_assertions)]
+pub const SPIN_LIMIT: u64 = 10_000_000_000;
+
+/// Marks a lock as not held by anyone, CPUs are stored as their APIC ID + 1
+#[cfg(debug_assertions)]
+const NO_OWNER: u32 = 0;
+
+/// Set once a lock problem has been reported, so the panic that follows doesn't report the
+/// same problem again if it runs into the same lock
+#[cfg(debug_assertions)]
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+pub struct SpinLock<T: ?Sized> {
+    name: &'static str,
+    #[cfg(debug_assertions)]
+    owner: AtomicU32,
+    #[cfg(debug_assertions)]
+    locked_at: AtomicPtr<Location<'static>>,
+    inner: spin::Mutex<T>,
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized + 'a> {
+    #[cfg(debug_assertions)]
+    lock: &'a SpinLock<T>,
+    /// Dropped by hand, so preemption only comes back once it's unlocked
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+}
+
+/// This CPU's ID for lock ownership
+#[cfg(debug_assertions)]
+fn current_cpu() -> u32 {
+    // 0 is for no owner
+    crate::percpu::cpu_id() as u32 + 1
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(name: &'static str, value: T) -> SpinLock<T> {
+        SpinLock {
+            name,
+            #[cfg(debug_assertions)]
+            owner: AtomicU32::new(NO_OWNER),
+            #[cfg(debug_assertions)]
+            locked_at: AtomicPtr::new(ptr::null_mut()),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Spin until the lock is free, then take it
+    #[cfg(not(debug_assertions))]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let guard = ManuallyDrop::new(self.inner.lock());
+        sched::preempt_disable();
+        SpinLockGuard { guard }
+    }
+
+    /// Take the lock if it's free
+    #[cfg(not(debug_assertions))]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let guard = ManuallyDrop::new(self.inner.try_lock()?);
+        sched::preempt_disable();
+        Some(SpinLockGuard { guard })
+    }
+
+    /// Spin until the lock is free, then take it
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let cpu = current_cpu();
+        let start = unsafe { _rdtsc() };
+
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+
+            if self.owner.load(Ordering::Relaxed) == cpu {
+                self.report("deadlock: this CPU already holds");
+            }
+            if unsafe { _rdtsc() } - start > SPIN_LIMIT {
+                self.report("spun too long on");
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Take the lock if it's free
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let guard = ManuallyDrop::new(self.inner.try_lock()?);
+        sched::preempt_disable();
+
+        let location = Location::caller();
+        self.owner.store(current_cpu(), Ordering::Relaxed);
+        self.locked_at
+            .store(location as *const _ as *mut _, Ordering::Relaxed);
+        lockdep::acquired(self.name, location);
+
+        Some(SpinLockGuard { lock: self, guard })
+    }
+
+    /// Describe a problem with this lock and panic
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    fn report(&self, problem: &str) {
+        if REPORTED.swap(true, Ordering::Relaxed) {
+            // already reported, and the panic that followed ended up back here
+            loop {
+                core::hint::spin_loop();
+            }
+        }
+
+        let here = Location::caller();
+        let held = self.locked_at.load(Ordering::Relaxed);
+        serial::write_unlocked(format_args!("{} lock {}\n", problem, self.name));
+        serial::write_unlocked(format_args!("  wanted at {}\n", here));
+        if let Some(held) = unsafe { held.as_ref() } {
+            serial::write_unlocked(format_args!("  held since {}\n", held));
+        }
+        panic!("{} lock {}", problem, self.name);
+    }
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            self.lock.owner.store(NO_OWNER, Ordering::Relaxed);
+            self.lock
+                .locked_at
+                .store(ptr::null_mut(), Ordering::Relaxed);
+            lockdep::released(self.lock.name);
+        }
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        sched::preempt_enable();
+    }
+}
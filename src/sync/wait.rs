@@ -0,0 +1,78 @@
+//! Wait queues
+//!
+//! A [`WaitQueue`] is a list of threads waiting for something to change. A thread checks the
+//! condition, puts itself on the queue and [`block`](sched::block)s if it doesn't hold yet,
+//! and whoever changes it calls [`wake_one`](WaitQueue::wake_one) or
+//! [`wake_all`](WaitQueue::wake_all). A wake that comes between the check and the block isn't
+//! lost, the scheduler remembers it.
+//!
+//! Waking doesn't allocate and can be done from an interrupt handler. Waiting can't, and
+//! without a thread to block, before the [`sched`] is up, on the other CPUs, or with
+//! interrupts off, it spins instead.
+//!
+
+use alloc::collections::VecDeque;
+
+use x86_64::instructions::interrupts;
+
+use super::SpinLock;
+use crate::sched::{self, ThreadId};
+
+pub struct WaitQueue {
+    /// Only locked with interrupts off, wakes can come from interrupt handlers
+    waiters: SpinLock<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new(name: &'static str) -> WaitQueue {
+        WaitQueue {
+            waiters: SpinLock::new(name, VecDeque::new()),
+        }
+    }
+
+    /// Block until `condition` returns true. It's checked again every time the thread is
+    /// woken, and anything it does happens with no lock held.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        while !condition() {
+            let thread = match sched::current() {
+                Some(thread) if interrupts::are_enabled() => thread,
+                _ => {
+                    core::hint::spin_loop();
+                    continue;
+                }
+            };
+
+            // room is made here, so the wake side never has to
+            interrupts::without_interrupts(|| self.waiters.lock().push_back(thread));
+            if !condition() {
+                sched::block();
+            } else {
+                self.remove(thread);
+                return;
+            }
+            // still on the queue if something else woke it
+            self.remove(thread);
+        }
+    }
+
+    fn remove(&self, thread: ThreadId) {
+        interrupts::without_interrupts(|| self.waiters.lock().retain(|&id| id != thread));
+    }
+
+    /// Wake the thread that's been waiting longest, false if none was
+    pub fn wake_one(&self) -> bool {
+        let thread = interrupts::without_interrupts(|| self.waiters.lock().pop_front());
+        match thread {
+            Some(thread) => {
+                sched::wake(thread);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake every waiting thread
+    pub fn wake_all(&self) {
+        while self.wake_one() {}
+    }
+}
@@ -0,0 +1,443 @@
+//! System calls
+//!
+//! User code calls into the kernel with `syscall`, which jumps to `syscall_entry` with CPL 0
+//! and the kernel's segments from the STAR MSR, but still on the user's stack. The entry code
+//! `swapgs`es to find this CPU's [`percpu`] block through GS, moves to the syscall stack kept
+//! there, saves the user's registers, and calls [`dispatch`], which looks the call up in
+//! [`SYSCALLS`]. `sysret` takes it back, after a `swapgs` to put the user's GS base back.
+//!
+//! On Intel, `sysret` to an address that isn't canonical faults in ring 0, with the user's
+//! stack already loaded. User code can't be mapped high enough for a `syscall` to return
+//! there, see [`USER_END`], but the entry code doesn't count on it: it goes back through
+//! `iretq` instead, which faults too, and the general protection fault handler takes a fault
+//! there as the program's, see [`is_return_to_user`].
+//!
+//! The calling convention is the one Linux uses: the number in `rax`, up to six arguments in
+//! `rdi`, `rsi`, `rdx`, `r10`, `r8`, and `r9`, and the result in `rax`, negative for a
+//! [`SyscallError`]. `rcx` and `r11` are lost, the CPU keeps the return address and flags in
+//! them.
+//!
+//! Interrupts stay off while a syscall runs, since every CPU has only the one stack for them.
+//!
+//! | number | call                  | does                                           |
+//! |--------|-----------------------|------------------------------------------------|
+//! | 0      | `write(buf, len)`     | prints to the console, returns `len`           |
+//! | 1      | `exit(code)`          | ends the calling process                       |
+//! | 2      | `open(path, len)`     | opens a file in the [`vfs`], returns a handle  |
+//! | 3      | `read(fd, buf, len)`  | reads from an open file, returns bytes read    |
+//! | 4      | `close(fd)`           | closes an open file                            |
+//! | 5      | `getpid()`            | returns the calling process's [`Pid`]          |
+//! | 6      | `fork()`              | copies the calling process, see below          |
+//! | 7      | `wait(pid, code)`     | reaps an exited child, see below               |
+//!
+//! Files are kept in the calling [`process`]'s own table, and its handles mean nothing to
+//! any other process.
+//!
+//! `fork` returns the new process's PID in the caller and 0 in the new process, which carries
+//! on from the same place with the same registers and a copy-on-write copy of its memory.
+//!
+//! `wait` takes the PID of one of the caller's children, or 0 for any of them, stores the exit
+//! code of one that's exited at `code`, and returns its PID, after which it's gone. It doesn't
+//! block, since a syscall can't: while the children are all still running it fails with
+//! [`WouldBlock`](SyscallError::WouldBlock), and the caller tries again.
+//!
+//! User memory a syscall is given may not have a frame yet, or may be shared copy-on-write, so
+//! it's faulted in like the program itself touching it would before the kernel goes near it.
+//!
+//! [`vfs`]: crate::fs::vfs
+//! [`Pid`]: crate::process::Pid
+//!
+//! links:
+//! - <https://wiki.osdev.org/SYSENTER#AMD:_SYSCALL.2FSYSRET>
+//! - Intel SDM vol. 2B, "SYSCALL" and "SYSRET"
+//!
+
+use alloc::string::String;
+use alloc::vec;
+use core::arch::global_asm;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::registers::model_specific::EferFlags;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::arch::msr;
+use crate::cpu::protection;
+use crate::fs::vfs::{self, VfsError};
+use crate::init::{InitCall, Stage};
+use crate::mem::paging::{self, PAGE_SIZE};
+use crate::mem::stack;
+use crate::process::{self, Pid, ProcessError};
+use crate::user::{Registers, USER_END};
+use crate::{gdt, percpu, print};
+
+const STACK_SIZE: usize = 16 * 1024;
+
+/// Why a syscall failed, returned to the caller negated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    /// There's no syscall with that number
+    NoSuchCall = 1,
+    /// A pointer argument isn't to memory the caller can reach
+    BadAddress = 2,
+    /// An argument is out of range
+    Invalid = 3,
+    /// There's no file at that path
+    NotFound = 4,
+    /// The handle isn't one of the caller's open files
+    BadHandle = 5,
+    /// The caller can't open any more files
+    TooManyFiles = 6,
+    /// The file couldn't be read
+    Io = 7,
+    /// There wasn't the memory for it
+    NoMemory = 8,
+    /// It can't be done yet, and would have to wait
+    WouldBlock = 9,
+}
+
+impl From<VfsError> for SyscallError {
+    fn from(error: VfsError) -> SyscallError {
+        match error {
+            VfsError::NotFound | VfsError::NotADirectory => SyscallError::NotFound,
+            VfsError::RelativePath => SyscallError::Invalid,
+            _ => SyscallError::Io,
+        }
+    }
+}
+
+impl From<ProcessError> for SyscallError {
+    fn from(error: ProcessError) -> SyscallError {
+        match error {
+            ProcessError::NoSuchProcess => SyscallError::Invalid,
+            ProcessError::TooManyFiles => SyscallError::TooManyFiles,
+            ProcessError::BadHandle => SyscallError::BadHandle,
+            ProcessError::NoFrames => SyscallError::NoMemory,
+        }
+    }
+}
+
+/// The registers the entry code saves, from the stack pointer up
+#[repr(C)]
+pub struct SyscallFrame {
+    pub number: u64,
+    /// Not touched by the call, but saved so `fork` can give the child all of them
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// The arguments, in order
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "    swapgs",
+    "    movq %rsp, %gs:{user_stack}",
+    "    movq %gs:{syscall_stack}, %rsp",
+    "    pushq %gs:{user_stack}",
+    "    pushq %rcx",
+    "    pushq %r11",
+    "    pushq %rdi",
+    "    pushq %rsi",
+    "    pushq %rdx",
+    "    pushq %r10",
+    "    pushq %r8",
+    "    pushq %r9",
+    "    pushq %rbx",
+    "    pushq %rbp",
+    "    pushq %r12",
+    "    pushq %r13",
+    "    pushq %r14",
+    "    pushq %r15",
+    "    pushq %rax",
+    "    movq %rsp, %rdi",
+    "    callq {dispatch}",
+    // any bit above the lower half set, and sysretq would fault
+    "    movq {rip}(%rsp), %rcx",
+    "    shrq $47, %rcx",
+    "    jnz 2f",
+    "    addq $8, %rsp",
+    "    popq %r15",
+    "    popq %r14",
+    "    popq %r13",
+    "    popq %r12",
+    "    popq %rbp",
+    "    popq %rbx",
+    "    popq %r9",
+    "    popq %r8",
+    "    popq %r10",
+    "    popq %rdx",
+    "    popq %rsi",
+    "    popq %rdi",
+    "    popq %r11",
+    "    popq %rcx",
+    "    popq %rsp",
+    "    swapgs",
+    "    sysretq",
+    // the same, but building an iretq frame from rflags, rip, and rsp as they're popped
+    "2:",
+    "    addq $8, %rsp",
+    "    popq %r15",
+    "    popq %r14",
+    "    popq %r13",
+    "    popq %r12",
+    "    popq %rbp",
+    "    popq %rbx",
+    "    popq %r9",
+    "    popq %r8",
+    "    popq %r10",
+    "    popq %rdx",
+    "    popq %rsi",
+    "    popq %rdi",
+    "    pushq {user_ss}(%rip)",
+    "    pushq 24(%rsp)",
+    "    pushq 16(%rsp)",
+    "    pushq {user_cs}(%rip)",
+    "    pushq 40(%rsp)",
+    "    movq 16(%rsp), %r11",
+    "    movq (%rsp), %rcx",
+    "    swapgs",
+    ".global syscall_iretq",
+    "syscall_iretq:",
+    "    iretq",
+    user_stack = const percpu::USER_STACK_OFFSET,
+    syscall_stack = const percpu::SYSCALL_STACK_OFFSET,
+    rip = const offset_of!(SyscallFrame, rip),
+    user_cs = sym USER_CS,
+    user_ss = sym USER_SS,
+    dispatch = sym dispatch,
+    options(att_syntax)
+);
+
+// the frame is 16 registers, which keeps the stack 16-byte aligned for the call
+const _: () = assert!(size_of::<SyscallFrame>().is_multiple_of(16));
+const _: () = assert!(offset_of!(SyscallFrame, rsp) == 15 * 8);
+
+/// The user selectors, for the entry code's `iretq`
+static USER_CS: AtomicU64 = AtomicU64::new(0);
+static USER_SS: AtomicU64 = AtomicU64::new(0);
+
+extern "C" {
+    fn syscall_entry();
+    static syscall_iretq: u8;
+}
+
+/// Whether the exception that pushed `frame` is from the `iretq` the entry code returns
+/// through, which only faults for a user address that isn't canonical
+pub fn is_return_to_user(frame: &InterruptStackFrame) -> bool {
+    frame.instruction_pointer.as_u64() == &raw const syscall_iretq as u64
+}
+
+/// A syscall, given the frame so it can read its arguments
+pub type Handler = fn(frame: &mut SyscallFrame) -> Result<u64, SyscallError>;
+
+/// Syscalls by number
+pub static SYSCALLS: &[Handler] = &[write, exit, open, read, close, getpid, fork, wait];
+
+extern "C" fn dispatch(frame: &mut SyscallFrame) -> u64 {
+    let result = match SYSCALLS.get(frame.number as usize) {
+        Some(handler) => handler(frame),
+        None => Err(SyscallError::NoSuchCall),
+    };
+    match result {
+        Ok(value) => value,
+        Err(error) => (error as u64).wrapping_neg(),
+    }
+}
+
+/// Check that the `len` bytes at `addr` are mapped for user code, and get them
+fn user_bytes(addr: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    let start = check_user(addr, len, PageTableFlags::USER_ACCESSIBLE)?;
+    Ok(unsafe { core::slice::from_raw_parts(start.as_ptr(), len as usize) })
+}
+
+/// Check that the `len` bytes at `addr` are mapped writable for user code, and get them
+fn user_bytes_mut(addr: u64, len: u64) -> Result<&'static mut [u8], SyscallError> {
+    let flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+    let start = check_user(addr, len, flags)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr(), len as usize) })
+}
+
+/// Check that every page of the `len` bytes at `addr` is mapped with `flags`
+fn check_user(addr: u64, len: u64, flags: PageTableFlags) -> Result<VirtAddr, SyscallError> {
+    let start = VirtAddr::try_new(addr).map_err(|_| SyscallError::BadAddress)?;
+    let end = addr.checked_add(len).ok_or(SyscallError::BadAddress)?;
+    // the upper half is the kernel's
+    if end > USER_END {
+        return Err(SyscallError::BadAddress);
+    }
+
+    let mut page = start.align_down(PAGE_SIZE);
+    while page.as_u64() < end {
+        let allowed =
+            |page| paging::translate(page).is_some_and(|(_, mapped)| mapped.contains(flags));
+        // the fault the access would have caused
+        let write = flags.contains(PageTableFlags::WRITABLE);
+        if !allowed(page) && !(process::handle_page_fault(page, write) && allowed(page)) {
+            return Err(SyscallError::BadAddress);
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(start)
+}
+
+/// Most bytes one write prints
+const MAX_WRITE: u64 = 4096;
+
+fn write(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let [addr, len, ..] = frame.args();
+    if len > MAX_WRITE {
+        return Err(SyscallError::Invalid);
+    }
+    let bytes = user_bytes(addr, len)?;
+    let text = protection::with_user_access(|| String::from_utf8_lossy(bytes).into_owned());
+    print!("{}", text);
+    Ok(len)
+}
+
+fn exit(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let [code, ..] = frame.args();
+    process::exit(code as i64)
+}
+
+/// Longest path `open` takes
+const MAX_PATH: u64 = 256;
+
+fn open(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let [addr, len, ..] = frame.args();
+    if len > MAX_PATH {
+        return Err(SyscallError::Invalid);
+    }
+    let bytes = user_bytes(addr, len)?;
+    let path = protection::with_user_access(|| String::from_utf8(bytes.into()));
+    let path = path.map_err(|_| SyscallError::Invalid)?;
+    let file = vfs::open(&path)?;
+    let handle = process::with_files(|files| files.insert(file)).ok_or(SyscallError::Invalid)??;
+    Ok(handle as u64)
+}
+
+/// Most bytes one read reads
+const MAX_READ: u64 = 4096;
+
+fn read(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let [handle, addr, len, ..] = frame.args();
+    let len = len.min(MAX_READ);
+    let buf = user_bytes_mut(addr, len)?;
+    // read into the kernel's memory first, the filesystem doesn't know about user access
+    let mut bytes = vec![0; len as usize];
+    let read = process::with_files(|files| -> Result<usize, SyscallError> {
+        Ok(files.get(handle as usize)?.read(&mut bytes)?)
+    });
+    let read = read.ok_or(SyscallError::Invalid)??;
+    protection::with_user_access(|| buf[..read].copy_from_slice(&bytes[..read]));
+    Ok(read as u64)
+}
+
+fn close(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let [handle, ..] = frame.args();
+    process::with_files(|files| files.remove(handle as usize)).ok_or(SyscallError::Invalid)??;
+    Ok(0)
+}
+
+fn getpid(_frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    process::current()
+        .map(|pid| pid.as_u64())
+        .ok_or(SyscallError::Invalid)
+}
+
+fn fork(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let registers = Registers {
+        // what fork returns in the child
+        rax: 0,
+        rbx: frame.rbx,
+        rcx: frame.rip,
+        rdx: frame.rdx,
+        rsi: frame.rsi,
+        rdi: frame.rdi,
+        rbp: frame.rbp,
+        r8: frame.r8,
+        r9: frame.r9,
+        r10: frame.r10,
+        r11: frame.rflags,
+        r12: frame.r12,
+        r13: frame.r13,
+        r14: frame.r14,
+        r15: frame.r15,
+        rip: frame.rip,
+        rsp: frame.rsp,
+        rflags: frame.rflags,
+    };
+    Ok(process::fork(registers)?.as_u64())
+}
+
+fn wait(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let [pid, addr, ..] = frame.args();
+    let code = user_bytes_mut(addr, size_of::<i64>() as u64)?;
+    let pid = (pid != 0).then(|| Pid::from_u64(pid));
+    let (child, exit_code) = process::try_wait_child(pid)?.ok_or(SyscallError::WouldBlock)?;
+    protection::with_user_access(|| code.copy_from_slice(&exit_code.to_ne_bytes()));
+    Ok(child.as_u64())
+}
+
+/// Point this CPU's `syscall` at the entry code and give it a stack to run on.
+/// [`percpu`] has to be set up first.
+pub fn init_cpu() {
+    let selectors = gdt::selectors();
+    unsafe {
+        msr::set_star(
+            selectors.user_code,
+            selectors.user_data,
+            selectors.code,
+            selectors.data,
+        )
+        .expect("syscall: the GDT isn't laid out for sysret");
+        msr::set_lstar(VirtAddr::new(syscall_entry as *const () as u64));
+    }
+    USER_CS.store(selectors.user_code.0 as u64, Ordering::Relaxed);
+    USER_SS.store(selectors.user_data.0 as u64, Ordering::Relaxed);
+    // the entry code runs with interrupts off, and with the flags the kernel expects. AC would
+    // let the kernel reach user pages with SMAP on, and NT would make an iretq a task switch.
+    unsafe {
+        msr::set_sfmask(
+            RFlags::INTERRUPT_FLAG
+                | RFlags::DIRECTION_FLAG
+                | RFlags::TRAP_FLAG
+                | RFlags::ALIGNMENT_CHECK
+                | RFlags::NESTED_TASK,
+        );
+        msr::update_efer(|efer| efer.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+
+    // the CPU never goes away, and neither does its stack
+    let stack = stack::allocate("syscall", STACK_SIZE).expect("syscall: no stack");
+    percpu::set_syscall_stack(stack.leak());
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "syscall",
+    stage: Stage::Interrupts,
+    after: &[],
+    func: init_cpu,
+};
@@ -0,0 +1,175 @@
+//! Polling tasks when they're woken
+//!
+//! Every task has a waker that puts its ID on the ready queue. The queue has room for every
+//! task and a task is never on it twice, so waking never allocates and can be done from an
+//! interrupt handler. [`run_ready`] polls the tasks that were ready when it was called, so a
+//! task that keeps waking itself can't keep the caller in there.
+//!
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use x86_64::instructions::interrupts;
+
+use super::{Task, TaskId};
+use crate::sync::SpinLock;
+use crate::{percpu, watchdog};
+
+/// Tasks that can be alive at once
+pub const MAX_TASKS: usize = 256;
+
+/// IDs of the tasks to poll, oldest first. Only locked with interrupts off, since wakers
+/// called from interrupt handlers lock it too.
+struct ReadyQueue {
+    ids: [TaskId; MAX_TASKS],
+    head: usize,
+    len: usize,
+}
+
+impl ReadyQueue {
+    fn push(&mut self, id: TaskId) {
+        // can't happen while no task is queued twice, but losing a wake beats a panic in an
+        // interrupt handler
+        if self.len == MAX_TASKS {
+            return;
+        }
+        self.ids[(self.head + self.len) % MAX_TASKS] = id;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<TaskId> {
+        if self.len == 0 {
+            return None;
+        }
+        let id = self.ids[self.head];
+        self.head = (self.head + 1) % MAX_TASKS;
+        self.len -= 1;
+        Some(id)
+    }
+}
+
+static READY: SpinLock<ReadyQueue> = SpinLock::new(
+    "ready tasks",
+    ReadyQueue {
+        ids: [TaskId(0); MAX_TASKS],
+        head: 0,
+        len: 0,
+    },
+);
+
+struct TaskWaker {
+    id: TaskId,
+    /// Already on the ready queue, or done and never to be queued again
+    queued: AtomicBool,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            interrupts::without_interrupts(|| READY.lock().push(self.id));
+        }
+    }
+}
+
+struct Entry {
+    /// None while it's being polled
+    task: Option<Task>,
+    waker: Arc<TaskWaker>,
+}
+
+static TASKS: SpinLock<BTreeMap<TaskId, Entry>> = SpinLock::new("tasks", BTreeMap::new());
+
+/// Set while [`run_ready`] is polling, so a task calling it doesn't poll itself
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Start running `future` as a task, it's first polled by the next [`run_ready`]
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+    let task = Task::new(future);
+    let id = task.id();
+    let waker = Arc::new(TaskWaker {
+        id,
+        queued: AtomicBool::new(false),
+    });
+
+    {
+        let mut tasks = TASKS.lock();
+        assert!(tasks.len() < MAX_TASKS, "task: too many tasks");
+        tasks.insert(
+            id,
+            Entry {
+                task: Some(task),
+                waker: waker.clone(),
+            },
+        );
+    }
+    waker.wake_by_ref();
+    id
+}
+
+/// How many tasks haven't finished
+pub fn count() -> usize {
+    TASKS.lock().len()
+}
+
+/// Poll the tasks that are ready now, once each. Does nothing on the application processors.
+pub fn run_ready() {
+    if percpu::cpu_id() != 0 || RUNNING.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    let ready = interrupts::without_interrupts(|| READY.lock().len);
+    for _ in 0..ready {
+        let Some(id) = interrupts::without_interrupts(|| READY.lock().pop()) else {
+            break;
+        };
+        let Some((mut task, waker)) = TASKS
+            .lock()
+            .get_mut(&id)
+            .and_then(|entry| Some((entry.task.take()?, entry.waker.clone())))
+        else {
+            continue;
+        };
+
+        // wakes from here on poll it again
+        waker.queued.store(false, Ordering::Release);
+        let task_waker = Waker::from(waker.clone());
+        let mut context = Context::from_waker(&task_waker);
+        match task.poll(&mut context) {
+            Poll::Ready(()) => {
+                waker.queued.store(true, Ordering::Release);
+                TASKS.lock().remove(&id);
+            }
+            Poll::Pending => {
+                if let Some(entry) = TASKS.lock().get_mut(&id) {
+                    entry.task = Some(task);
+                }
+            }
+        }
+    }
+
+    RUNNING.store(false, Ordering::Release);
+}
+
+/// Run the ready tasks, then sleep until the next interrupt if none are left. Needs interrupts
+/// on, for something to wake it.
+pub fn idle() {
+    // the main loop has nothing to do, so it isn't stuck
+    watchdog::MAIN.touch();
+    run_ready();
+
+    // a wake between the check and the hlt would otherwise have to wait for the next interrupt
+    interrupts::disable();
+    if READY.lock().len == 0 {
+        interrupts::enable_and_hlt();
+    } else {
+        interrupts::enable();
+    }
+}
@@ -0,0 +1,164 @@
+//! Cooperative tasks
+//!
+//! A [`Task`] is a future that runs until it's done, and the [`executor`] polls it whenever
+//! its waker says it can get further. That lets things that wait on something, like the next
+//! key, be written as `async fn`s instead of loops around `hlt`:
+//!
+//! ```ignore
+//! task::spawn(async {
+//!     loop {
+//!         let byte = input::next_byte().await;
+//!         // ...
+//!     }
+//! });
+//! ```
+//!
+//! Tasks only run when something calls [`idle`] or [`run_ready`], which the shell does while
+//! it waits for input, and they're only ever polled on the boot CPU. Nothing preempts them, so
+//! a task that doesn't `.await` holds up everything else.
+//!
+//! links:
+//! - <https://os.phil-opp.com/async-await/>
+//!
+
+use alloc::boxed::Box;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use x86_64::instructions::interrupts;
+
+use crate::sync::SpinLock;
+
+pub mod executor;
+
+pub use executor::{idle, run_ready, spawn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> TaskId {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// A future that's been spawned
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+/// Where a future that's waiting on an interrupt leaves its waker
+///
+/// [`register`](WakerSlot::register) takes the lock with interrupts off, so the handler
+/// calling [`wake`](WakerSlot::wake) can never find it held on the same CPU. The waker stays in
+/// the slot after a wake, so the handler never drops the last reference to one and frees
+/// memory; the next registration replaces it.
+pub struct WakerSlot {
+    waker: SpinLock<Option<Waker>>,
+}
+
+impl WakerSlot {
+    pub const fn new(name: &'static str) -> WakerSlot {
+        WakerSlot {
+            waker: SpinLock::new(name, None),
+        }
+    }
+
+    /// Have the next [`wake`](WakerSlot::wake) wake `waker`
+    pub fn register(&self, waker: &Waker) {
+        interrupts::without_interrupts(|| {
+            let mut slot = self.waker.lock();
+            match &mut *slot {
+                Some(old) if old.will_wake(waker) => {}
+                _ => *slot = Some(waker.clone()),
+            }
+        });
+    }
+
+    /// Wake whoever registered last, safe to call from an interrupt handler
+    pub fn wake(&self) {
+        if let Some(slot) = self.waker.try_lock() {
+            if let Some(waker) = &*slot {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// Let the other ready tasks run before carrying on
+pub fn yield_now() -> impl Future<Output = ()> {
+    let mut yielded = false;
+    core::future::poll_fn(move |context| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    #[test_case]
+    fn spawned_task_runs() {
+        let ran = Arc::new(AtomicUsize::new(0));
+        let counter = ran.clone();
+        spawn(async move {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        run_ready();
+        assert_eq!(ran.load(Ordering::Relaxed), 1);
+    }
+
+    /// A task that yields is polled again, and finishes
+    #[test_case]
+    fn yielding_task_finishes() {
+        let steps = Arc::new(AtomicUsize::new(0));
+        let counter = steps.clone();
+        spawn(async move {
+            for _ in 0..3 {
+                counter.fetch_add(1, Ordering::Relaxed);
+                yield_now().await;
+            }
+        });
+        for _ in 0..4 {
+            run_ready();
+        }
+        assert_eq!(steps.load(Ordering::Relaxed), 3);
+        assert_eq!(executor::count(), 0);
+    }
+}
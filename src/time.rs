@@ -0,0 +1,179 @@
+//! High-resolution time
+//!
+//! The [`timer`](crate::timer)'s uptime only moves once a tick. For anything finer this reads
+//! the TSC, which counts CPU cycles, and converts it with the TSC's frequency. [`INIT`]
+//! measures that by counting cycles over [`timer::wait_ms`], on PIT channel 2 or the
+//! [`hpet`](crate::hpet), a few times, keeping the shortest. It also checks CPUID for an invariant TSC: without one the TSC may change speed
+//! with the CPU's clock or stop in deep sleep states, and [`nanos`] drifts with it. In a VM
+//! it's usually reported missing, though the host keeps it steady anyway.
+//!
+//! [`nanos`] counts from when [`INIT`] ran, and the [`Instant`]s from [`Instant::now`] give
+//! [`Duration`]s for timing code paths:
+//!
+//! ```ignore
+//! let start = Instant::now();
+//! do_the_thing();
+//! ilog!("took {:?}", start.elapsed());
+//! ```
+//!
+//! links:
+//! - <https://wiki.osdev.org/TSC>
+//! - Intel SDM vol. 3B, 18.17 "Time-Stamp Counter"
+//!
+
+use core::arch::x86_64::_rdtsc;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+pub use core::time::Duration;
+
+use crate::cpu::{self, Feature};
+use crate::init::{InitCall, Stage};
+use crate::{ilog, timer, wlog};
+
+/// How long each calibration run counts cycles for, in ms
+const CALIBRATE_MS: u64 = 10;
+const CALIBRATE_RUNS: usize = 3;
+
+/// TSC frequency in Hz, 0 until it's been measured
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// The TSC when [`nanos`] was 0
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Count TSC cycles over a wait that doesn't need interrupts. Anything that gets in the way
+/// only makes a run longer, so the shortest is the closest.
+fn calibrate() -> u64 {
+    let cycles = (0..CALIBRATE_RUNS)
+        .map(|_| {
+            let start = unsafe { _rdtsc() };
+            timer::wait_ms(CALIBRATE_MS);
+            let end = unsafe { _rdtsc() };
+            end - start
+        })
+        .min()
+        .unwrap_or(0);
+    cycles * 1000 / CALIBRATE_MS
+}
+
+/// The TSC's frequency in Hz, measured the first time it's needed if [`INIT`] hasn't run
+pub fn tsc_hz() -> u64 {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => {
+            let hz = calibrate().max(1);
+            TSC_HZ.store(hz, Ordering::Relaxed);
+            hz
+        }
+        hz => hz,
+    }
+}
+
+/// Convert a TSC cycle count to nanoseconds
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    (cycles as u128 * 1_000_000_000 / tsc_hz() as u128) as u64
+}
+
+/// Nanoseconds since [`INIT`]
+pub fn nanos() -> u64 {
+    let cycles = unsafe { _rdtsc() }.saturating_sub(BOOT_TSC.load(Ordering::Relaxed));
+    cycles_to_ns(cycles)
+}
+
+/// A point in time, from [`nanos`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(nanos())
+    }
+
+    /// How long it's been since `earlier`, zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// How long it's been since this
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Nanoseconds since [`INIT`]
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0 + duration.as_nanos() as u64)
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_sub(duration.as_nanos() as u64))
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+fn init() {
+    if !cpu::has(Feature::Tsc) {
+        wlog!("time: no TSC");
+        return;
+    }
+    let hz = calibrate().max(1);
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    BOOT_TSC.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+
+    let invariant = cpu::has(Feature::InvariantTsc);
+    ilog!(
+        "time: tsc at {}.{:03} MHz{}",
+        hz / 1_000_000,
+        hz / 1000 % 1000,
+        if invariant { ", invariant" } else { "" }
+    );
+    if !invariant {
+        wlog!("time: the TSC isn't invariant, nanos() may drift");
+    }
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "time",
+    stage: Stage::Memory,
+    // the HPET is the better clock to calibrate against, when it's used
+    after: &["cpu", "hpet"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn nanos_follow_the_pit() {
+        let start = Instant::now();
+        timer::pit_wait_ms(5);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(4), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(50), "{:?}", elapsed);
+    }
+
+    #[test_case]
+    fn instants_add_up() {
+        let now = Instant::now();
+        let later = now + Duration::from_micros(3);
+        assert_eq!(later - now, Duration::from_micros(3));
+        assert_eq!(now - later, Duration::ZERO);
+        assert_eq!(later - Duration::from_micros(3), now);
+    }
+}
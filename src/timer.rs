@@ -0,0 +1,341 @@
+//! System timer
+//!
+//! Channel 0 of the 8254 PIT fires IRQ 0 at [`config::timer_hz`], or whatever [`set_frequency`]
+//! was last given. When the [`apic`](crate::apic)s are in use, the local APIC timer fires it
+//! instead, and with `clock=hpet` on the command line the [`hpet`](crate::hpet) does if the
+//! APICs aren't. Other clocks are calibrated with [`wait_ms`], on the HPET if it's in use and
+//! PIT channel 2 otherwise. Every tick adds its length to the uptime, so the clock doesn't jump when
+//! the frequency changes.
+//!
+//! Code that wants to run later registers a callback with [`after`] or [`every`]. Pending
+//! timers are kept in a list sorted by when they're due, of at most [`MAX_TIMERS`], and each
+//! tick runs the ones whose time has come, from the interrupt handler. [`sleep_ms`] is a timer
+//! that wakes the sleeping thread, which is [`sched::block`]ed meanwhile.
+//!
+//! links:
+//! - <https://wiki.osdev.org/Programmable_Interval_Timer>
+//!
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::{self, interrupts};
+use x86_64::structures::port::{PortRead as _, PortWrite as _};
+
+use crate::debug::profile;
+use crate::init::{InitCall, Stage};
+use crate::sched::{self, ThreadId};
+use crate::sync::SpinLock;
+use crate::{apic, config, hpet, pic, speaker};
+
+/// The PIT's input clock in Hz
+pub const PIT_HZ: u64 = 1_193_182;
+
+const TIMER_IRQ: u8 = 0;
+
+const CHANNEL0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+/// Channel 0, low then high byte, mode 2 (rate generator)
+const CHANNEL0_RATE: u8 = 0b0011_0100;
+
+const CHANNEL2: u16 = 0x42;
+/// Channel 2, low then high byte, mode 0 (the output goes high when the count runs out)
+const CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+/// Channel 2's gate (bit 0) and speaker enable (bit 1) are in the keyboard controller's port B,
+/// which also reads back channel 2's output (bit 5)
+const PORT_B: u16 = 0x61;
+const GATE: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+const OUT2: u8 = 1 << 5;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static UPTIME_NS: AtomicU64 = AtomicU64::new(0);
+/// Length of a tick at the current frequency
+static TICK_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Timers that can be pending at once
+pub const MAX_TIMERS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// There are already [`MAX_TIMERS`] pending
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+#[derive(Clone, Copy)]
+enum Action {
+    Call(fn()),
+    Wake(ThreadId),
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    id: TimerId,
+    /// Uptime it's due at
+    due_ns: u64,
+    /// 0 for one that only fires once
+    period_ns: u64,
+    action: Action,
+}
+
+/// Pending timers, the first `len` sorted by when they're due
+struct Timers {
+    list: [Option<Timer>; MAX_TIMERS],
+    len: usize,
+    next_id: u64,
+}
+
+impl Timers {
+    fn insert(&mut self, timer: Timer) -> Result<(), TimerError> {
+        if self.len == MAX_TIMERS {
+            return Err(TimerError::Full);
+        }
+        // after the ones due at the same time, so they go in the order they were added
+        let at = self.list[..self.len]
+            .iter()
+            .position(|pending| pending.is_some_and(|pending| pending.due_ns > timer.due_ns))
+            .unwrap_or(self.len);
+        self.list[at..=self.len].rotate_right(1);
+        self.list[at] = Some(timer);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, at: usize) -> Timer {
+        let timer = self.list[at].take().unwrap();
+        self.list[at..self.len].rotate_left(1);
+        self.len -= 1;
+        timer
+    }
+
+    /// Take the first timer if it's due by `now_ns`, putting it back for its next time if it's
+    /// periodic
+    fn pop_due(&mut self, now_ns: u64) -> Option<Action> {
+        if self.list[0]?.due_ns > now_ns {
+            return None;
+        }
+        let mut timer = self.remove(0);
+        if timer.period_ns != 0 {
+            // ticks that were missed are skipped rather than made up
+            timer.due_ns = (timer.due_ns + timer.period_ns).max(now_ns + 1);
+            // there's room, it was just taken out
+            let _ = self.insert(timer);
+        }
+        Some(timer.action)
+    }
+}
+
+/// Only locked with interrupts off, the timer interrupt goes through it
+static TIMERS: SpinLock<Timers> = SpinLock::new(
+    "timers",
+    Timers {
+        list: [None; MAX_TIMERS],
+        len: 0,
+        next_id: 0,
+    },
+);
+
+fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    profile::sample(pic::interrupted_at());
+    let tick_ns = TICK_NS.load(Ordering::Relaxed);
+    let now_ns = UPTIME_NS.fetch_add(tick_ns, Ordering::Relaxed) + tick_ns;
+    speaker::tick(uptime_ms());
+
+    // the lock isn't held while they run, so they can add timers of their own
+    loop {
+        let due = TIMERS.lock().pop_due(now_ns);
+        let Some(action) = due else {
+            break;
+        };
+        match action {
+            Action::Call(callback) => callback(),
+            Action::Wake(thread) => sched::wake(thread),
+        }
+    }
+    sched::tick(tick_ns);
+}
+
+fn add(delay_ms: u64, period_ms: u64, action: Action) -> Result<TimerId, TimerError> {
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let id = TimerId(timers.next_id);
+        timers.next_id += 1;
+        timers.insert(Timer {
+            id,
+            due_ns: UPTIME_NS.load(Ordering::Relaxed) + delay_ms * 1_000_000,
+            period_ns: period_ms * 1_000_000,
+            action,
+        })?;
+        Ok(id)
+    })
+}
+
+/// Call `callback` from the timer interrupt once, `ms` milliseconds from now
+pub fn after(ms: u64, callback: fn()) -> Result<TimerId, TimerError> {
+    add(ms, 0, Action::Call(callback))
+}
+
+/// Call `callback` from the timer interrupt every `ms` milliseconds, starting `ms` from now
+pub fn every(ms: u64, callback: fn()) -> Result<TimerId, TimerError> {
+    add(ms, ms.max(1), Action::Call(callback))
+}
+
+/// Stop timer `id`, false if it had already fired for the last time
+pub fn cancel(id: TimerId) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let at = timers.list[..timers.len]
+            .iter()
+            .position(|timer| timer.is_some_and(|timer| timer.id == id));
+        at.map(|at| timers.remove(at)).is_some()
+    })
+}
+
+/// Fire the timer interrupt `hz` times a second, as close as the PIT can get. Anything from
+/// 19 Hz to the PIT's clock works.
+pub fn set_frequency(hz: u32) {
+    if apic::enabled() {
+        let tick_ns = interrupts::without_interrupts(|| apic::set_timer_frequency(hz));
+        TICK_NS.store(tick_ns, Ordering::Relaxed);
+        return;
+    }
+
+    if let Some(tick_ns) = interrupts::without_interrupts(|| hpet::set_frequency(hz)) {
+        TICK_NS.store(tick_ns, Ordering::Relaxed);
+        return;
+    }
+
+    let divisor = pit_divisor(hz);
+
+    interrupts::without_interrupts(|| {
+        TICK_NS.store(divisor as u64 * 1_000_000_000 / PIT_HZ, Ordering::Relaxed);
+        write_channel0(divisor);
+    });
+}
+
+/// Run PIT channel 0 at `hz` while another clock is the tick, for the
+/// [watchdog](crate::watchdog)'s NMIs. Returns how long its period is, in ns.
+pub fn start_pit(hz: u32) -> u64 {
+    let divisor = pit_divisor(hz);
+    interrupts::without_interrupts(|| write_channel0(divisor));
+    divisor as u64 * 1_000_000_000 / PIT_HZ
+}
+
+fn pit_divisor(hz: u32) -> u16 {
+    (PIT_HZ / hz.max(1) as u64).clamp(1, u16::MAX as u64) as u16
+}
+
+fn write_channel0(divisor: u16) {
+    unsafe {
+        u8::write_to_port(COMMAND, CHANNEL0_RATE);
+        u8::write_to_port(CHANNEL0, divisor as u8);
+        u8::write_to_port(CHANNEL0, (divisor >> 8) as u8);
+    }
+}
+
+/// Spin for `ms` milliseconds without interrupts or the timer, for calibrating other clocks
+/// against. Up to 54 ms unless the HPET is in use.
+pub fn wait_ms(ms: u64) {
+    if hpet::enabled() {
+        hpet::wait_ms(ms);
+    } else {
+        pit_wait_ms(ms);
+    }
+}
+
+/// Spin for `ms` milliseconds, up to 54, on PIT channel 2
+pub fn pit_wait_ms(ms: u64) {
+    let count = (PIT_HZ * ms / 1000).min(u16::MAX as u64) as u16;
+
+    unsafe {
+        // gate off and the speaker disconnected
+        let port_b = u8::read_from_port(PORT_B) & !(GATE | SPEAKER);
+        u8::write_to_port(PORT_B, port_b);
+
+        u8::write_to_port(COMMAND, CHANNEL2_ONE_SHOT);
+        u8::write_to_port(CHANNEL2, count as u8);
+        u8::write_to_port(CHANNEL2, (count >> 8) as u8);
+
+        // counting starts when the gate goes high
+        u8::write_to_port(PORT_B, port_b | GATE);
+        while u8::read_from_port(PORT_B) & OUT2 == 0 {
+            core::hint::spin_loop();
+        }
+        u8::write_to_port(PORT_B, port_b);
+    }
+}
+
+/// Timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// How long a tick is, in nanoseconds
+pub fn tick_ns() -> u64 {
+    TICK_NS.load(Ordering::Relaxed)
+}
+
+/// Time since the timer was started
+pub fn uptime_ms() -> u64 {
+    UPTIME_NS.load(Ordering::Relaxed) / 1_000_000
+}
+
+/// Wait at least `ms` milliseconds. The time only moves with interrupts on, so that's how this
+/// has to be called.
+///
+/// A thread is blocked until its time is up, so the others get the CPU meanwhile. Before
+/// there are threads, or if there's no room for another timer, this halts until it's time.
+pub fn sleep_ms(ms: u64) {
+    debug_assert!(
+        interrupts::are_enabled(),
+        "timer: sleep_ms with interrupts off"
+    );
+
+    let end_ns = UPTIME_NS.load(Ordering::Relaxed) + ms * 1_000_000;
+    let timer = sched::current().and_then(|thread| add(ms, 0, Action::Wake(thread)).ok());
+    while UPTIME_NS.load(Ordering::Relaxed) < end_ns {
+        match timer {
+            Some(_) => sched::block(),
+            None => instructions::hlt(),
+        }
+    }
+}
+
+fn init() {
+    set_frequency(config::timer_hz());
+    pic::set_handler(TIMER_IRQ, tick);
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "timer",
+    stage: Stage::Interrupts,
+    // which clock it uses depends on whether the APICs took over
+    after: &["pic", "apic", "hpet"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicBool;
+
+    use super::*;
+
+    static FIRED: AtomicBool = AtomicBool::new(false);
+
+    #[test_case]
+    fn callback_fires() {
+        after(2, || FIRED.store(true, Ordering::Relaxed)).unwrap();
+        sleep_ms(10);
+        assert!(FIRED.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn cancelled_timer_is_gone() {
+        let id = every(1000, || {}).unwrap();
+        assert!(cancel(id));
+        assert!(!cancel(id));
+    }
+}
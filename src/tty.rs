@@ -0,0 +1,160 @@
+//! Virtual terminals
+//!
+//! There are [`COUNT`] terminals, each a [`Writer`] with its own text, cursor, and scrollback,
+//! and one of them is on the screen at a time. Alt+F1 to Alt+F4 switch between them.
+//!
+//! A writer's cells are kept whether or not it's on the screen, and whatever the screen is.
+//! In VGA text mode the one on the screen draws straight to the text buffer. With a
+//! framebuffer the console is the [framebuffer console](gfx::console), which has cells of its
+//! own, and the other terminals are drawn through it: switching to one hides the console's
+//! cells and draws all of the terminal's, and from then on the rows that change are drawn
+//! again after each write. Switching back puts the console's cells back. Scrolling back
+//! through the history only works in text mode.
+//!
+//! | terminal | shows                                                 |
+//! |----------|-------------------------------------------------------|
+//! | 0        | the console: the shell and everything printed         |
+//! | 1        | every log message, whatever the log level is          |
+//! | 2, 3     | debugging output sent with [`write_fmt`]              |
+//!
+//! Switching happens in the keyboard interrupt, so it doesn't wait for locks. If a terminal is
+//! busy the switch doesn't happen, and the key has to be pressed again.
+//!
+
+use core::fmt::{self, Write as _};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::console::{Color, Console};
+use crate::gfx;
+use crate::gfx::console::FramebufferConsole;
+use crate::gfx::framebuffer::Framebuffer;
+use crate::sync::SpinLock;
+use crate::vga::{self, Writer};
+
+pub const COUNT: usize = 4;
+
+pub const CONSOLE: usize = 0;
+pub const LOG: usize = 1;
+pub const DEBUG: usize = 2;
+
+static OTHERS: [SpinLock<Writer>; COUNT - 1] = [
+    SpinLock::new("tty1", Writer::new()),
+    SpinLock::new("tty2", Writer::new()),
+    SpinLock::new("tty3", Writer::new()),
+];
+
+static ACTIVE: AtomicUsize = AtomicUsize::new(CONSOLE);
+
+/// Terminal `n`'s writer, the console's is the one in [`vga`]
+pub fn terminal(n: usize) -> &'static SpinLock<Writer> {
+    match n {
+        CONSOLE => &vga::WRITER,
+        _ => &OTHERS[n - 1],
+    }
+}
+
+/// The terminal on the screen
+pub fn active() -> usize {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Put terminal `n` on the screen. Returns false if it doesn't exist or a terminal was busy.
+pub fn switch(n: usize) -> bool {
+    if n >= COUNT {
+        return false;
+    }
+
+    interrupts::without_interrupts(|| {
+        let current = active();
+        if n == current {
+            return true;
+        }
+        let (Some(mut old), Some(mut new)) = (terminal(current).try_lock(), terminal(n).try_lock())
+        else {
+            return false;
+        };
+        let (Some(mut fbcon), Some(mut fb)) = (
+            gfx::console::CONSOLE.try_lock(),
+            gfx::framebuffer::FRAMEBUFFER.try_lock(),
+        ) else {
+            return false;
+        };
+
+        match (fbcon.as_mut(), fb.as_mut()) {
+            (Some(fbcon), Some(fb)) => {
+                fbcon.set_visible(fb, n == CONSOLE);
+                if n != CONSOLE {
+                    draw(fbcon, fb, &mut new, true);
+                }
+            }
+            _ => {
+                old.set_visible(false);
+                new.set_visible(true);
+            }
+        }
+        ACTIVE.store(n, Ordering::Relaxed);
+        true
+    })
+}
+
+/// Draw `writer`'s rows that changed through the framebuffer console, or all of them
+fn draw(fbcon: &FramebufferConsole, fb: &mut Framebuffer, writer: &mut Writer, all: bool) {
+    let dirty = writer.take_dirty();
+    let rows = if all { 0..vga::BUFFER_HEIGHT } else { dirty };
+    fbcon.draw_other(fb, all, rows, vga::BUFFER_WIDTH, |row, col| {
+        writer.cell(row, col)
+    });
+}
+
+/// After writing to terminal `n`, draw what changed if it's on a framebuffer. If the console
+/// or the framebuffer is busy it's drawn with the next write.
+fn show(n: usize, writer: &mut Writer) {
+    if n == CONSOLE || n != active() {
+        return;
+    }
+    let (Some(fbcon), Some(mut fb)) = (
+        gfx::console::CONSOLE.try_lock(),
+        gfx::framebuffer::FRAMEBUFFER.try_lock(),
+    ) else {
+        return;
+    };
+    if let (Some(fbcon), Some(fb)) = (fbcon.as_ref(), fb.as_mut()) {
+        draw(fbcon, fb, writer, false);
+    }
+}
+
+/// Scroll the active terminal's view, see [`Writer::scroll_view`]. Gives up if it's busy.
+pub fn scroll_view(lines: isize) {
+    if let Some(mut writer) = terminal(active()).try_lock() {
+        writer.scroll_view(lines);
+    }
+}
+
+/// Write to terminal `n`, nothing happens if there's no such terminal
+pub fn write_fmt(n: usize, args: fmt::Arguments) {
+    if n >= COUNT {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let mut writer = terminal(n).lock();
+        let _ = writer.write_fmt(args);
+        show(n, &mut writer);
+    });
+}
+
+/// Write a line to terminal `n` in `color`, for the log
+pub fn write_colored(n: usize, color: Color, args: fmt::Arguments) {
+    if n >= COUNT {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let mut writer = terminal(n).lock();
+        let (foreground, background) = writer.color();
+        writer.set_color(color, background);
+        let _ = writer.write_fmt(args);
+        writer.set_color(foreground, background);
+        show(n, &mut writer);
+    });
+}
@@ -0,0 +1,30 @@
+# hello: prints a line and exits with the status kept in .data
+#
+# built into hello.elf by tools/build-user.sh
+
+    .section .text
+    .global _start
+_start:
+    leaq msg(%rip), %rdi
+    movq $(msg_end - msg), %rsi
+    xorl %eax, %eax
+    syscall
+    # .bss starts out zeroed, so this adds nothing
+    movq status(%rip), %rdi
+    addq zero(%rip), %rdi
+    movl $1, %eax
+    syscall
+    ud2
+
+    .section .rodata
+msg:
+    .ascii "hello from an ELF in ring 3\n"
+msg_end:
+
+    .section .data
+status:
+    .quad 0
+
+    .section .bss
+zero:
+    .skip 8
@@ -0,0 +1,389 @@
+//! User mode programs
+//!
+//! Each program runs as a [`process`], with its pages mapped user-accessible into the lower
+//! half of its own address space, and its thread drops into ring 3 with `iretq`. From there it
+//! can only get back into the kernel with a [`syscall`](crate::syscall) or by faulting. Either
+//! way it ends in [`process::exit`], which frees its pages and ends the thread; a fault in
+//! user mode never panics the kernel, see [`fault`].
+//!
+//! User code runs with a GS base of its own, which it can change by loading a GS selector, and
+//! the kernel's is swapped back in on every way into the kernel, see [`percpu`](crate::percpu).
+//! Programs live below [`USER_END`].
+//!
+//! The programs in [`PROGRAMS`] are built into the kernel, either as flat binaries, whose
+//! bytes are copied to [`CODE_BASE`] and run from the start, or as [`elf`] executables. The
+//! ELF ones are assembled from the `.s` files next to this one by `tools/build-user.sh`.
+//! Anything else is run from `/bin` in the [`initrd`], if there is one.
+//!
+//! links:
+//! - <https://wiki.osdev.org/Getting_to_Ring_3>
+//!
+
+use alloc::format;
+use core::arch::global_asm;
+use core::mem::offset_of;
+
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::{instructions, PrivilegeLevel, VirtAddr};
+
+use crate::elf::{self, ElfError};
+use crate::fs::initrd;
+use crate::mem::paging::{AddressSpace, MapError, PAGE_SIZE};
+use crate::mem::{frame, phys_to_virt};
+use crate::process::{self, Pid};
+use crate::{gdt, percpu, wlog};
+
+/// Where a program's code is copied to and starts running
+pub const CODE_BASE: u64 = 0x40_0000;
+/// Where user mappings end, a page short of the end of the lower half like Linux's
+/// `TASK_SIZE`, so the address after a `syscall` is always canonical
+pub const USER_END: u64 = 0x0000_7fff_ffff_f000;
+/// The end of a program's stack, which grows down from here
+pub const STACK_TOP: u64 = 0x80_0000;
+/// Pages of stack a program can use, each is only given a frame when it's first touched
+const STACK_PAGES: u64 = 16;
+
+/// Why a program couldn't be started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserError {
+    /// There's no program with that name
+    NotFound,
+    /// There wasn't a free frame for one of its pages
+    NoFrames,
+    /// One of its pages couldn't be mapped
+    Map(MapError),
+    /// Its executable can't be loaded
+    Elf(ElfError),
+}
+
+impl From<MapError> for UserError {
+    fn from(error: MapError) -> UserError {
+        UserError::Map(error)
+    }
+}
+
+impl From<ElfError> for UserError {
+    fn from(error: ElfError) -> UserError {
+        UserError::Elf(error)
+    }
+}
+
+/// How a program is stored
+pub enum Image {
+    /// A flat binary, got through a function since its bytes are between two linker symbols
+    Flat(fn() -> &'static [u8]),
+    /// An ELF executable
+    Elf(&'static [u8]),
+}
+
+/// A program built into the kernel
+pub struct Program {
+    pub name: &'static str,
+    pub image: Image,
+}
+
+pub static PROGRAMS: &[Program] = &[
+    Program {
+        name: "hello",
+        image: Image::Elf(include_bytes!("hello.elf")),
+    },
+    Program {
+        name: "hello-flat",
+        image: Image::Flat(|| unsafe {
+            image(&raw const user_hello_start, &raw const user_hello_end)
+        }),
+    },
+    Program {
+        name: "fork",
+        image: Image::Flat(|| unsafe {
+            image(&raw const user_fork_start, &raw const user_fork_end)
+        }),
+    },
+    Program {
+        name: "fault",
+        image: Image::Flat(|| unsafe {
+            image(&raw const user_fault_start, &raw const user_fault_end)
+        }),
+    },
+];
+
+// write(msg, len), then exit(0)
+global_asm!(
+    ".pushsection .rodata.user_hello, \"a\"",
+    ".global user_hello_start",
+    "user_hello_start:",
+    "    leaq .Lhello_msg(%rip), %rdi",
+    "    movq $(.Lhello_end - .Lhello_msg), %rsi",
+    "    xorl %eax, %eax",
+    "    syscall",
+    "    xorl %edi, %edi",
+    "    movl $1, %eax",
+    "    syscall",
+    "    ud2",
+    ".Lhello_msg:",
+    "    .ascii \"hello from ring 3\\n\"",
+    ".Lhello_end:",
+    ".global user_hello_end",
+    "user_hello_end:",
+    ".popsection",
+    options(att_syntax)
+);
+
+// fork(), then write(msg, len) with a different message in each, then exit(0)
+global_asm!(
+    ".pushsection .rodata.user_fork, \"a\"",
+    ".global user_fork_start",
+    "user_fork_start:",
+    "    movl $6, %eax",
+    "    syscall",
+    "    testq %rax, %rax",
+    "    leaq .Lfork_parent(%rip), %rdi",
+    "    movq $(.Lfork_child - .Lfork_parent), %rsi",
+    "    jnz 1f",
+    "    leaq .Lfork_child(%rip), %rdi",
+    "    movq $(.Lfork_end - .Lfork_child), %rsi",
+    "1:",
+    "    xorl %eax, %eax",
+    "    syscall",
+    "    xorl %edi, %edi",
+    "    movl $1, %eax",
+    "    syscall",
+    "    ud2",
+    ".Lfork_parent:",
+    "    .ascii \"hello from the parent\\n\"",
+    ".Lfork_child:",
+    "    .ascii \"hello from the child\\n\"",
+    ".Lfork_end:",
+    ".global user_fork_end",
+    "user_fork_end:",
+    ".popsection",
+    options(att_syntax)
+);
+
+// reads the kernel's memory, which has to fault
+global_asm!(
+    ".pushsection .rodata.user_fault, \"a\"",
+    ".global user_fault_start",
+    "user_fault_start:",
+    "    movabsq $0xffffffff80000000, %rax",
+    "    movq (%rax), %rax",
+    "    ud2",
+    ".global user_fault_end",
+    "user_fault_end:",
+    ".popsection",
+    options(att_syntax)
+);
+
+extern "C" {
+    static user_hello_start: u8;
+    static user_hello_end: u8;
+    static user_fork_start: u8;
+    static user_fork_end: u8;
+    static user_fault_start: u8;
+    static user_fault_end: u8;
+}
+
+/// The bytes from `start` up to `end`
+unsafe fn image(start: *const u8, end: *const u8) -> &'static [u8] {
+    core::slice::from_raw_parts(start, end as usize - start as usize)
+}
+
+/// Look up `name` in [`PROGRAMS`], or as an ELF executable in the initrd's `/bin`, and start
+/// it as a new process
+pub fn run(name: &str) -> Result<Pid, UserError> {
+    let Some(program) = PROGRAMS.iter().find(|program| program.name == name) else {
+        let bytes = initrd::get(&format!("bin/{}", name)).ok_or(UserError::NotFound)?;
+        return spawn_elf(name, bytes);
+    };
+    match program.image {
+        Image::Flat(code) => spawn(name, code()),
+        Image::Elf(bytes) => spawn_elf(name, bytes),
+    }
+}
+
+/// Copy the flat binary `code` to [`CODE_BASE`] and start running it as a new process
+pub fn spawn(name: &str, code: &[u8]) -> Result<Pid, UserError> {
+    process::spawn(name, |space| {
+        load_flat(space, code)?;
+        Ok(VirtAddr::new(CODE_BASE))
+    })
+}
+
+/// Load the ELF executable in `bytes` and start running it as a new process
+pub fn spawn_elf(name: &str, bytes: &[u8]) -> Result<Pid, UserError> {
+    process::spawn(name, |space| {
+        let elf = elf::parse(bytes)?;
+        Ok(elf::load(&elf, space)?)
+    })
+}
+
+fn load_flat(space: &mut AddressSpace, code: &[u8]) -> Result<(), UserError> {
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    for (i, chunk) in code.chunks(PAGE_SIZE as usize).enumerate() {
+        let virt = VirtAddr::new(CODE_BASE + i as u64 * PAGE_SIZE);
+        let frame = map_page(space, virt, user)?;
+        // the page isn't writable through its user mapping, so fill it in through the physical
+        // memory window
+        let page = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        unsafe { page.copy_from_nonoverlapping(chunk.as_ptr(), chunk.len()) };
+    }
+    Ok(())
+}
+
+/// Give a program in `space` a stack ending at [`STACK_TOP`], which is filled in as it grows
+pub fn map_stack(space: &mut AddressSpace) {
+    let stack = PageTableFlags::PRESENT
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE;
+    let bottom = VirtAddr::new(STACK_TOP - STACK_PAGES * PAGE_SIZE);
+    space.map_lazy(bottom, VirtAddr::new(STACK_TOP), stack);
+}
+
+/// Map a zeroed frame at `virt` in `space`, which frees it with the rest
+fn map_page(
+    space: &mut AddressSpace,
+    virt: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<PhysFrame, UserError> {
+    let frame = frame::allocate_frame().ok_or(UserError::NoFrames)?;
+    unsafe {
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, PAGE_SIZE as usize);
+        if let Err(error) = space.map_to(virt, frame.start_address(), flags) {
+            frame::deallocate_frame(frame);
+            return Err(error.into());
+        }
+    }
+    Ok(frame)
+}
+
+/// Drop to ring 3 and start running at `entry` with the stack at `stack`, with interrupts on
+///
+/// # Safety
+///
+/// `entry` and `stack` have to be mapped for user mode.
+pub unsafe fn enter(entry: VirtAddr, stack: VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    instructions::interrupts::disable();
+    percpu::leave_for_user();
+    InterruptStackFrameValue::new(
+        entry,
+        selectors.user_code,
+        RFlags::INTERRUPT_FLAG,
+        stack,
+        selectors.user_data,
+    )
+    .iretq()
+}
+
+/// Whether the interrupt that pushed `frame` came in from user mode
+pub fn from_user(frame: &InterruptStackFrame) -> bool {
+    frame.code_segment.rpl() == PrivilegeLevel::Ring3
+}
+
+/// Every general purpose register of a user thread, and where it's running, for [`resume`]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+}
+
+// resume(registers: &Registers, code: u64, data: u64), builds the frame iretq pops from the
+// registers and loads the rest, rdi last since it points at them
+global_asm!(
+    ".global user_resume",
+    "user_resume:",
+    "    cli",
+    "    pushq %rdx",
+    "    pushq {rsp}(%rdi)",
+    "    pushq {rflags}(%rdi)",
+    "    pushq %rsi",
+    "    pushq {rip}(%rdi)",
+    "    movq {rax}(%rdi), %rax",
+    "    movq {rbx}(%rdi), %rbx",
+    "    movq {rcx}(%rdi), %rcx",
+    "    movq {rdx}(%rdi), %rdx",
+    "    movq {rsi}(%rdi), %rsi",
+    "    movq {rbp}(%rdi), %rbp",
+    "    movq {r8}(%rdi), %r8",
+    "    movq {r9}(%rdi), %r9",
+    "    movq {r10}(%rdi), %r10",
+    "    movq {r11}(%rdi), %r11",
+    "    movq {r12}(%rdi), %r12",
+    "    movq {r13}(%rdi), %r13",
+    "    movq {r14}(%rdi), %r14",
+    "    movq {r15}(%rdi), %r15",
+    "    movq {rdi}(%rdi), %rdi",
+    "    swapgs",
+    "    iretq",
+    rax = const offset_of!(Registers, rax),
+    rbx = const offset_of!(Registers, rbx),
+    rcx = const offset_of!(Registers, rcx),
+    rdx = const offset_of!(Registers, rdx),
+    rsi = const offset_of!(Registers, rsi),
+    rdi = const offset_of!(Registers, rdi),
+    rbp = const offset_of!(Registers, rbp),
+    r8 = const offset_of!(Registers, r8),
+    r9 = const offset_of!(Registers, r9),
+    r10 = const offset_of!(Registers, r10),
+    r11 = const offset_of!(Registers, r11),
+    r12 = const offset_of!(Registers, r12),
+    r13 = const offset_of!(Registers, r13),
+    r14 = const offset_of!(Registers, r14),
+    r15 = const offset_of!(Registers, r15),
+    rip = const offset_of!(Registers, rip),
+    rsp = const offset_of!(Registers, rsp),
+    rflags = const offset_of!(Registers, rflags),
+    options(att_syntax)
+);
+
+extern "C" {
+    fn user_resume(registers: &Registers, code: u64, data: u64) -> !;
+}
+
+/// Drop to ring 3 with every register set from `registers`, with interrupts on whatever the
+/// flags in it say
+///
+/// # Safety
+///
+/// `registers.rip` and `registers.rsp` have to be mapped for user mode.
+pub unsafe fn resume(mut registers: Registers) -> ! {
+    let selectors = gdt::selectors();
+    registers.rflags |= RFlags::INTERRUPT_FLAG.bits() | 0x2;
+    user_resume(
+        &registers,
+        selectors.user_code.0 as u64,
+        selectors.user_data.0 as u64,
+    )
+}
+
+/// End the program that caused exception `name` instead of panicking
+pub fn fault(name: &str, frame: &InterruptStackFrame) -> ! {
+    wlog!(
+        "user: killed by {} at {:#x}",
+        name,
+        frame.instruction_pointer.as_u64()
+    );
+    process::exit(-1)
+}
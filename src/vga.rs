@@ -4,7 +4,7 @@
 //!     - as you hit the bottom, the line buffer shifts
 //!         - (like how xnu or linux scrolls on verbose boots)
 //!   - colors
-//!   - dlog, ilog, wlog, elog macros
+//!   - dlog, ilog, wlog, elog macros (see `log`)
 //!     - some way to conditionally enable/disable debug logging, or change the log level
 //!
 //! links:
@@ -15,33 +15,23 @@
 //!
 
 use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
-use x86_64::structures::port::{PortRead as _, PortWrite as _};
+use x86_64::PhysAddr;
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum Color {
-    Black = 0,
-    Blue = 1,
-    Green = 2,
-    Cyan = 3,
-    Red = 4,
-    Magenta = 5,
-    Brown = 6,
-    LightGray = 7,
-    DarkGray = 8,
-    LightBlue = 9,
-    LightGreen = 10,
-    LightCyan = 11,
-    LightRed = 12,
-    Pink = 13,
-    Yellow = 14,
-    White = 15,
-}
+use crate::arch::io::Port;
+use crate::console::ansi::{self, Csi, Output, Parser};
+use crate::console::cp437;
+use crate::console::Console;
+use crate::gfx::Rect;
+use crate::init::{InitCall, Stage};
+use crate::mem::paging;
+use crate::sync::SpinLock;
+use crate::{gfx, mem, speaker, wlog};
+
+pub use crate::console::Color;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
@@ -60,53 +50,264 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+/// Physical address of the text mode buffer
+const BUFFER_ADDR: u64 = 0xb8000;
+
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+
+/// Lines kept after they scroll off the top of the screen
+pub const HISTORY_LINES: usize = 256;
+
+type Row = [ScreenChar; BUFFER_WIDTH];
+
+const BLANK_ROW: Row = [ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode(0x0f),
+}; BUFFER_WIDTH];
 
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/// Where the buffer is reachable, the physical memory window until [`map_buffer`] runs
+static BUFFER: AtomicU64 = AtomicU64::new(mem::PHYS_OFFSET + BUFFER_ADDR);
+
+/// The text buffer. Only writers that are [`visible`](Writer::set_visible) draw to it.
+fn buffer() -> &'static mut Buffer {
+    unsafe { &mut *(BUFFER.load(Ordering::Relaxed) as *mut Buffer) }
+}
+
+impl Buffer {
+    /// Copy a whole row in, 8 bytes at a time rather than a cell at a time
+    fn write_row(&mut self, row: usize, source: &Row) {
+        const WORDS: usize = core::mem::size_of::<Row>() / 8;
+        let dst = self.chars[row].as_mut_ptr() as *mut u64;
+        let src = source.as_ptr() as *const u64;
+        for i in 0..WORDS {
+            unsafe { dst.add(i).write_volatile(src.add(i).read_unaligned()) };
+        }
+    }
+}
+
+impl ColorCode {
+    fn foreground(self) -> Color {
+        Color::from_index(self.0)
+    }
+
+    fn background(self) -> Color {
+        Color::from_index(self.0 >> 4)
+    }
+}
+
+/// Writes to the text buffer. Everything is drawn into `shadow` in ordinary memory first, and
+/// the rows that changed are copied out to the buffer once per call, which is much cheaper than
+/// going through the uncached mapping for every character.
 pub struct Writer {
     current_col: usize,
     current_row: usize,
+    /// What text is written in right now, escape sequences change it
+    color_code: ColorCode,
+    /// What escape sequences reset the colors to
     default_color_code: ColorCode,
-    buffer: &'static mut Buffer,
+    parser: Parser,
+    /// Whether this writer is the one on the screen
+    visible: bool,
+    /// What the screen shows when it's not scrolled back
+    shadow: [Row; BUFFER_HEIGHT],
+    /// Rows of `shadow` that haven't been copied to the buffer, start..end
+    dirty_start: usize,
+    dirty_end: usize,
+
+    /// Ring of the lines that scrolled off, `history_len` of them starting at `history_start`
+    history: [Row; HISTORY_LINES],
+    history_start: usize,
+    history_len: usize,
+    /// How many lines back the screen shows, 0 for the live text
+    view: usize,
+    /// Whether the hardware cursor follows the text, see [`enable_cursor`]
+    cursor: bool,
+    /// Rows text goes in and scrolls through, the rest is the status line
+    text_rows: usize,
+    /// Row and column of the cell drawn with its colors swapped, see [`set_pointer`]
+    pointer: Option<(usize, usize)>,
+}
+
+impl Default for Writer {
+    fn default() -> Writer {
+        Writer::new()
+    }
 }
 
 impl Writer {
+    /// A blank writer at the top left. It's not on the screen until it's made visible.
+    pub const fn new() -> Writer {
+        Writer {
+            current_col: 0,
+            current_row: 0,
+            color_code: ColorCode(0x0f),
+            default_color_code: ColorCode(0x0f),
+            parser: Parser::new(),
+            visible: false,
+            shadow: [BLANK_ROW; BUFFER_HEIGHT],
+            dirty_start: 0,
+            dirty_end: 0,
+            history: [BLANK_ROW; HISTORY_LINES],
+            history_start: 0,
+            history_len: 0,
+            view: 0,
+            cursor: false,
+            text_rows: BUFFER_HEIGHT,
+            pointer: None,
+        }
+    }
+
+    /// Put this writer on the screen, or take it off. The screen isn't touched when it's taken
+    /// off, whatever's made visible next draws over it.
+    pub fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+        if visible {
+            let view = core::mem::replace(&mut self.view, 0);
+            self.mark_dirty(0, BUFFER_HEIGHT);
+            self.flush();
+            self.scroll_view(view as isize);
+            self.update_cursor();
+        }
+    }
+
+    /// The glyph and colors of the live text at `row`, `col`, which has to be on the screen
+    pub fn cell(&self, row: usize, col: usize) -> (u8, Color, Color) {
+        let screen_char = self.shadow[row][col];
+        let color_code = screen_char.color_code;
+        (
+            screen_char.ascii_character,
+            color_code.foreground(),
+            color_code.background(),
+        )
+    }
+
+    /// The rows that changed since this was last called, or since the writer last drew them
+    /// to the buffer, for showing a writer that isn't visible somewhere else
+    pub fn take_dirty(&mut self) -> Range<usize> {
+        let dirty = self.dirty_start..self.dirty_end;
+        self.dirty_start = 0;
+        self.dirty_end = 0;
+        dirty
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.new_line(),
-            byte => {
-                if self.current_col >= BUFFER_WIDTH {
-                    self.new_line();
-                }
+        self.put_byte(byte);
+        self.flush();
+        self.update_cursor();
+    }
+
+    fn set_cell(&mut self, row: usize, col: usize, screen_char: ScreenChar) {
+        self.shadow[row][col] = screen_char;
+        self.mark_dirty(row, row + 1);
+    }
 
-                let row = self.current_row;
-                let col = self.current_col;
+    fn mark_dirty(&mut self, start: usize, end: usize) {
+        if self.dirty_start >= self.dirty_end {
+            (self.dirty_start, self.dirty_end) = (start, end);
+        } else {
+            self.dirty_start = self.dirty_start.min(start);
+            self.dirty_end = self.dirty_end.max(end);
+        }
+    }
 
-                let color_code = self.default_color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
-                self.current_col += 1;
+    /// Copy the rows that changed out to the buffer. Text rows stay behind while the view is
+    /// scrolled back, going back to the live text redraws them all anyway.
+    fn flush(&mut self) {
+        if !self.visible {
+            return;
+        }
+        for row in self.dirty_start..self.dirty_end {
+            if self.view == 0 || row >= self.text_rows {
+                buffer().write_row(row, &self.shadow[row]);
+                self.draw_pointer(row);
             }
         }
+        self.dirty_start = 0;
+        self.dirty_end = 0;
+    }
+
+    /// If the pointer is on `row`, which was just copied to the buffer, swap the colors of its
+    /// cell there
+    fn draw_pointer(&self, row: usize) {
+        let Some((pointer_row, col)) = self.pointer else {
+            return;
+        };
+        if pointer_row != row {
+            return;
+        }
+        let cell = &mut buffer().chars[row][col];
+        let screen_char = cell.read();
+        let color_code = screen_char.color_code;
+        cell.write(ScreenChar {
+            ascii_character: screen_char.ascii_character,
+            color_code: ColorCode::new(color_code.background(), color_code.foreground()),
+        });
+    }
+
+    /// Show a pointer on the cell at `row`, `col`, clamped to the screen, or take it away for
+    /// None. It's only on the screen, the text underneath doesn't change.
+    pub fn set_pointer(&mut self, pointer: Option<(usize, usize)>) {
+        let pointer =
+            pointer.map(|(row, col)| (row.min(BUFFER_HEIGHT - 1), col.min(BUFFER_WIDTH - 1)));
+        if pointer == self.pointer {
+            return;
+        }
+        // copying the rows out again takes it off the old cell and puts it on the new one
+        for (row, _) in [self.pointer, pointer].into_iter().flatten() {
+            self.mark_dirty(row, row + 1);
+        }
+        self.pointer = pointer;
+        self.flush();
+    }
+
+    fn put_byte(&mut self, byte: u8) {
+        self.show_live();
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.current_col = 0,
+            // to the next multiple of 8, or the end of the line
+            b'\t' => self.current_col = ((self.current_col / 8 + 1) * 8).min(BUFFER_WIDTH),
+            // backspace only moves back, the shell erases by writing a space over it
+            0x08 => self.current_col = self.current_col.saturating_sub(1),
+            0x07 => speaker::bell(),
+            byte => self.put_glyph(byte),
+        }
+    }
+
+    /// Put the CP437 glyph `byte` at the cursor, even the ones in place of control characters
+    fn put_glyph(&mut self, byte: u8) {
+        if self.current_col >= BUFFER_WIDTH {
+            self.new_line();
+        }
+
+        let row = self.current_row;
+        let col = self.current_col;
+
+        let color_code = self.color_code;
+        self.set_cell(
+            row,
+            col,
+            ScreenChar {
+                ascii_character: byte,
+                color_code,
+            },
+        );
+        self.current_col += 1;
     }
 
     fn new_line(&mut self) {
         // check if we still have more screen real estate to use
-        if self.current_row == BUFFER_HEIGHT - 1 {
+        if self.current_row >= self.text_rows - 1 {
             // we ran out of space, shift all the rows up in preparation to overwrite the bottom row
-            for row in 1..BUFFER_HEIGHT {
-                for col in 0..BUFFER_WIDTH {
-                    let character = self.buffer.chars[row][col].read();
-                    self.buffer.chars[row - 1][col].write(character);
-                }
-            }
+            self.push_history(self.shadow[0]);
+            self.shadow.copy_within(1..self.text_rows, 0);
+            self.mark_dirty(0, self.text_rows);
             self.clear_row(self.current_row);
         } else {
             // we still have more rows available
@@ -116,24 +317,220 @@ impl Writer {
         self.current_col = 0;
     }
 
+    /// Write `s`, acting on the ANSI escape sequences in it (see [`handle_csi`](Self::handle_csi))
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // not part of printable ASCII range
-                _ => self.write_byte(0xfe),
+        self.show_live();
+        for c in s.chars() {
+            match self.parser.feed(c) {
+                // printable ASCII, or one of the control characters write_byte handles
+                Some(Output::Char(c @ (' '..='~' | '\n' | '\r' | '\t' | '\x08' | '\x07'))) => {
+                    self.put_byte(c as u8)
+                }
+                Some(Output::Char(c)) => {
+                    self.put_glyph(cp437::encode(c).unwrap_or(cp437::REPLACEMENT))
+                }
+                Some(Output::Csi(csi)) => self.handle_csi(&csi),
+                None => {}
             }
         }
+        self.flush();
+        self.update_cursor();
     }
 
-    fn clear_row(&mut self, row: usize) {
-        let blank = ScreenChar {
-            ascii_character: b' ',
-            color_code: self.default_color_code,
+    /// Move the hardware cursor to where the next character goes, if it's following the text
+    fn update_cursor(&self) {
+        if self.cursor && self.visible && self.view == 0 {
+            set_cursor_position(self.current_row, self.current_col);
+        }
+    }
+
+    fn push_history(&mut self, row: Row) {
+        let end = (self.history_start + self.history_len) % HISTORY_LINES;
+        self.history[end] = row;
+        if self.history_len < HISTORY_LINES {
+            self.history_len += 1;
+        } else {
+            self.history_start = (self.history_start + 1) % HISTORY_LINES;
+        }
+    }
+
+    /// Move the view `lines` back into the history, or forward towards the live text for a
+    /// negative count. It stops at either end.
+    pub fn scroll_view(&mut self, lines: isize) {
+        let view = self.view.saturating_add_signed(lines).min(self.history_len);
+        if view == self.view {
+            return;
+        }
+
+        self.view = view;
+        if !self.visible {
+            return;
+        }
+
+        // the screen shows the view's lines of history followed by the top of the live screen
+        let top = self.history_len - view;
+        for row in 0..self.text_rows {
+            let line = top + row;
+            let source = if line < self.history_len {
+                &self.history[(self.history_start + line) % HISTORY_LINES]
+            } else {
+                &self.shadow[line - self.history_len]
+            };
+            buffer().write_row(row, source);
+            self.draw_pointer(row);
+        }
+        self.update_cursor();
+    }
+
+    /// Move to `row`, `col`, where the next character goes. Both are clamped to the screen.
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.show_live();
+        self.current_row = row.min(self.text_rows - 1);
+        self.current_col = col.min(BUFFER_WIDTH - 1);
+        self.update_cursor();
+    }
+
+    /// Fill the cells in `rect`, counted in characters, with `character` in the given colors.
+    /// The part off the screen is dropped, and the cursor stays where it was.
+    pub fn fill_region(&mut self, rect: Rect, character: u8, foreground: Color, background: Color) {
+        let Some(rect) = rect.clip(BUFFER_WIDTH, BUFFER_HEIGHT) else {
+            return;
+        };
+
+        self.show_live();
+        let screen_char = ScreenChar {
+            ascii_character: character,
+            color_code: ColorCode::new(foreground, background),
         };
+        for row in rect.y..rect.y + rect.height {
+            self.shadow[row][rect.x..rect.x + rect.width].fill(screen_char);
+        }
+        self.mark_dirty(rect.y, rect.y + rect.height);
+        self.flush();
+    }
+
+    /// Keep the bottom `rows` rows out of the text, the last of them for [`draw_status`]. Text
+    /// on them moves up.
+    pub fn reserve_rows(&mut self, rows: usize) {
+        let text_rows = BUFFER_HEIGHT - rows.min(BUFFER_HEIGHT - 1);
+        if self.text_rows <= text_rows {
+            return;
+        }
+        self.show_live();
+        while self.current_row >= text_rows {
+            // scroll everything up a row, the cursor with it
+            let (row, col) = (self.current_row, self.current_col);
+            self.current_row = self.text_rows - 1;
+            self.new_line();
+            self.current_row = row - 1;
+            self.current_col = col;
+        }
+        self.text_rows = text_rows;
+        for row in text_rows..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.flush();
+        self.update_cursor();
+    }
+
+    /// Whether the bottom row is kept for the status line
+    pub fn has_status_line(&self) -> bool {
+        self.text_rows < BUFFER_HEIGHT
+    }
+
+    /// Put `text` on the status line, cut off at the screen's width
+    pub fn draw_status(&mut self, text: &str, foreground: Color, background: Color) {
+        if !self.has_status_line() {
+            return;
+        }
+
+        let color_code = ColorCode::new(foreground, background);
+        let mut chars = text.chars();
         for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+            let byte = match chars.next() {
+                Some(c) => cp437::encode(c).unwrap_or(cp437::REPLACEMENT),
+                None => b' ',
+            };
+            self.shadow[BUFFER_HEIGHT - 1][col] = ScreenChar {
+                ascii_character: byte,
+                color_code,
+            };
+        }
+        self.mark_dirty(BUFFER_HEIGHT - 1, BUFFER_HEIGHT);
+        self.flush();
+    }
+
+    /// Go back to the live text, anything written shows up there
+    fn show_live(&mut self) {
+        if self.view != 0 {
+            self.scroll_view(-(self.view as isize));
+        }
+    }
+
+    fn blank(&self) -> ScreenChar {
+        ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        }
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        self.shadow[row] = [self.blank(); BUFFER_WIDTH];
+        self.mark_dirty(row, row + 1);
+    }
+
+    /// Blank the cells from `start` up to `end`, both counted in cells from the top left
+    fn erase(&mut self, start: usize, end: usize) {
+        let blank = self.blank();
+        for i in start..end.min(BUFFER_WIDTH * self.text_rows) {
+            self.set_cell(i / BUFFER_WIDTH, i % BUFFER_WIDTH, blank);
+        }
+    }
+
+    /// Act on a control sequence: SGR colors, cursor movement and positioning, and erasing the
+    /// screen or line. Anything else is ignored.
+    fn handle_csi(&mut self, csi: &Csi) {
+        let n = csi.param(0, 1) as usize;
+        // the column is one past the end when the next character wraps
+        let col = self.current_col.min(BUFFER_WIDTH - 1);
+        let row = self.current_row;
+        let here = row * BUFFER_WIDTH + col;
+        let line_start = row * BUFFER_WIDTH;
+
+        match csi.action {
+            'm' => {
+                let (mut fg, mut bg) = (self.color_code.foreground(), self.color_code.background());
+                let default = self.default_color_code;
+                ansi::apply_sgr(
+                    csi,
+                    &mut fg,
+                    &mut bg,
+                    default.foreground(),
+                    default.background(),
+                );
+                self.color_code = ColorCode::new(fg, bg);
+            }
+            'H' | 'f' => {
+                self.current_row = (csi.param(0, 1) as usize - 1).min(self.text_rows - 1);
+                self.current_col = (csi.param(1, 1) as usize - 1).min(BUFFER_WIDTH - 1);
+            }
+            'A' => self.current_row = row.saturating_sub(n),
+            'B' => self.current_row = (row + n).min(self.text_rows - 1),
+            'C' => self.current_col = (col + n).min(BUFFER_WIDTH - 1),
+            'D' => self.current_col = col.saturating_sub(n),
+            'J' => match csi.param(0, 0) {
+                0 => self.erase(here, BUFFER_WIDTH * self.text_rows),
+                1 => self.erase(0, here + 1),
+                2 => self.erase(0, BUFFER_WIDTH * self.text_rows),
+                _ => {}
+            },
+            'K' => match csi.param(0, 0) {
+                0 => self.erase(here, line_start + BUFFER_WIDTH),
+                1 => self.erase(line_start, here + 1),
+                2 => self.erase(line_start, line_start + BUFFER_WIDTH),
+                _ => {}
+            },
+            _ => {}
         }
     }
 }
@@ -145,61 +542,262 @@ impl fmt::Write for Writer {
     }
 }
 
-lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        current_col: 0,
-        current_row: 0,
-        default_color_code: ColorCode::new(Color::White, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+impl Console for Writer {
+    fn size(&self) -> (usize, usize) {
+        (BUFFER_WIDTH, self.text_rows)
+    }
+
+    fn clear(&mut self) {
+        self.show_live();
+        for row in 0..self.text_rows {
+            self.clear_row(row);
+        }
+        self.current_row = 0;
+        self.current_col = 0;
+        self.flush();
+        self.update_cursor();
+    }
+
+    fn color(&self) -> (Color, Color) {
+        let code = self.default_color_code;
+        (code.foreground(), code.background())
+    }
+
+    fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+        self.default_color_code = self.color_code;
+    }
+}
+
+/// The writer behind the console, see [`tty`](crate::tty) for the others
+pub static WRITER: SpinLock<Writer> = SpinLock::new("vga", {
+    let mut writer = Writer::new();
+    writer.visible = true;
+    writer
+});
+
+pub const INIT: InitCall = InitCall {
+    name: "vga",
+    stage: Stage::Early,
+    after: &["gfx"],
+    func: init,
+};
+
+/// Set the colors the text buffer writes in, whether or not it's the active console. See
+/// [`console::set_color`](crate::console::set_color) for that one.
+pub fn set_color(foreground: Color, background: Color) {
+    WRITER.lock().set_color(foreground, background);
 }
 
-/// Write text to the console
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => ($crate::vga::_print(format_args!($($arg)*)));
+/// Blank the text buffer and move to the top left
+pub fn clear_screen() {
+    WRITER.lock().clear();
 }
 
-/// Write a line of text to the console
-#[macro_export]
-macro_rules! println {
-    () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+/// Move the text buffer writer to `row`, `col`
+pub fn set_position(row: usize, col: usize) {
+    WRITER.lock().set_position(row, col);
 }
 
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
+/// Fill `rect` in the text buffer, see [`Writer::fill_region`]
+pub fn fill_region(rect: Rect, character: u8, foreground: Color, background: Color) {
+    WRITER
+        .lock()
+        .fill_region(rect, character, foreground, background);
 }
 
-pub fn disable_cursor() {
-    // first, figure out the I/OAS status
-    // http://www.osdever.net/FreeVGA/vga/extreg.htm#3CCR3C2W
-    let misc_out: u8;
-    unsafe {
-        misc_out = u8::read_from_port(0x3cc);
-    }
-
-    // determine the port addresses based on the lowest bit of the above port read
-    // http://www.osdever.net/FreeVGA/vga/crtcreg.htm
-    let crtc_addr: u16;
-    let crtc_data: u16;
-    if (misc_out & 1) == 0 {
-        crtc_addr = 0x3b4;
-        crtc_data = 0x3b5;
+/// Show a pointer on a cell of the text buffer, see [`Writer::set_pointer`]
+pub fn set_pointer(pointer: Option<(usize, usize)>) {
+    WRITER.lock().set_pointer(pointer);
+}
+
+/// Move the writer off the physical memory window onto an uncached mapping of the buffer.
+/// This can only happen once there's memory management, so the writer starts out without.
+fn map_buffer() {
+    let size = core::mem::size_of::<Buffer>() as u64;
+    match unsafe { paging::map_mmio(PhysAddr::new(BUFFER_ADDR), size) } {
+        Ok(addr) => BUFFER.store(addr.as_u64(), Ordering::Relaxed),
+        Err(error) => wlog!("vga: couldn't map the text buffer: {:?}", error),
+    }
+}
+
+pub const MMIO_INIT: InitCall = InitCall {
+    name: "vga mmio",
+    stage: Stage::Memory,
+    after: &["paging"],
+    func: map_buffer,
+};
+
+fn init() {
+    // the CRTC registers only mean anything to us in text mode
+    if gfx::framebuffer_info().is_none() {
+        enable_cursor();
+    }
+}
+
+/// Miscellaneous Output Register, read side. Bit 0 is I/O address select.
+/// http://www.osdever.net/FreeVGA/vga/extreg.htm#3CCR3C2W
+const MISC_OUTPUT: Port<u8> = unsafe { Port::new(0x3cc) };
+const IO_ADDRESS_SELECT: u8 = 1 << 0;
+
+/// The CRTC's (address, data) ports, monochrome and color
+/// http://www.osdever.net/FreeVGA/vga/crtcreg.htm
+const CRTC_MONO: (Port<u8>, Port<u8>) = unsafe { (Port::new(0x3b4), Port::new(0x3b5)) };
+const CRTC_COLOR: (Port<u8>, Port<u8>) = unsafe { (Port::new(0x3d4), Port::new(0x3d5)) };
+
+/// The CRTC's ports, which move depending on the I/O address select bit
+fn crtc_ports() -> (Port<u8>, Port<u8>) {
+    if MISC_OUTPUT.read() & IO_ADDRESS_SELECT == 0 {
+        CRTC_MONO
     } else {
-        crtc_addr = 0x3d4;
-        crtc_data = 0x3d5;
+        CRTC_COLOR
+    }
+}
+
+fn write_crtc(index: u8, value: u8) {
+    let (address, data) = crtc_ports();
+    address.write(index);
+    data.write(value);
+}
+
+/// Cursor Start Register, bit 5 turns the cursor off
+/// http://www.osdever.net/FreeVGA/vga/crtcreg.htm#0A
+const CURSOR_START: u8 = 0x0a;
+const CURSOR_DISABLE: u8 = 1 << 5;
+/// http://www.osdever.net/FreeVGA/vga/crtcreg.htm#0B
+const CURSOR_END: u8 = 0x0b;
+/// Cursor Location High and Low, in characters from the top left
+/// http://www.osdever.net/FreeVGA/vga/crtcreg.htm#0E
+const CURSOR_HIGH: u8 = 0x0e;
+const CURSOR_LOW: u8 = 0x0f;
+
+/// Show the blinking cursor as an underline, and have it follow the text from now on
+pub fn enable_cursor() {
+    // scanlines 14 and 15 of the 16 in a character
+    write_crtc(CURSOR_START, 14);
+    write_crtc(CURSOR_END, 15);
+
+    let mut writer = WRITER.lock();
+    writer.cursor = true;
+    writer.update_cursor();
+}
+
+pub fn disable_cursor() {
+    WRITER.lock().cursor = false;
+    write_crtc(CURSOR_START, CURSOR_DISABLE);
+}
+
+/// Put the hardware cursor on a cell, it moves again with the next write
+pub fn set_cursor_position(row: usize, col: usize) {
+    let position = (row.min(BUFFER_HEIGHT - 1) * BUFFER_WIDTH + col.min(BUFFER_WIDTH - 1)) as u16;
+    write_crtc(CURSOR_HIGH, (position >> 8) as u8);
+    write_crtc(CURSOR_LOW, position as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text goes into the buffer at the cursor, in the writer's color
+    #[test_case]
+    fn write_string_fills_row() {
+        let s = "write_string_fills_row output";
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writer.new_line();
+            writer.write_string(s);
+
+            let row = writer.current_row;
+            for (col, byte) in s.bytes().enumerate() {
+                let screen_char = buffer().chars[row][col].read();
+                assert_eq!(screen_char.ascii_character, byte);
+                assert_eq!(screen_char.color_code, writer.color_code);
+            }
+            assert_eq!(writer.current_col, s.len());
+        });
+    }
+
+    /// Writing past the last column wraps onto the next row
+    #[test_case]
+    fn long_line_wraps() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writer.new_line();
+            for _ in 0..BUFFER_WIDTH + 1 {
+                writer.write_byte(b'x');
+            }
+
+            let row = writer.current_row;
+            assert_eq!(writer.current_col, 1);
+            assert_eq!(buffer().chars[row][0].read().ascii_character, b'x');
+            assert_eq!(
+                buffer().chars[row - 1][BUFFER_WIDTH - 1]
+                    .read()
+                    .ascii_character,
+                b'x'
+            );
+        });
+    }
+
+    /// SGR changes the color of what's written next, and a reset puts the default back
+    #[test_case]
+    fn sgr_colors() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writer.new_line();
+            writer.write_string("\x1b[31;44mx\x1b[0my");
+
+            let row = writer.current_row;
+            let red = buffer().chars[row][0].read();
+            assert_eq!(red.ascii_character, b'x');
+            assert_eq!(red.color_code, ColorCode::new(Color::Red, Color::Blue));
+            let reset = buffer().chars[row][1].read();
+            assert_eq!(reset.color_code, writer.default_color_code);
+        });
+    }
+
+    /// Lines that scroll off the top come back when the view is scrolled, and writing goes
+    /// back to the live screen
+    #[test_case]
+    fn scrollback() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            for _ in 0..writer.text_rows {
+                writer.write_string("\n");
+            }
+            writer.write_string("scrolled off");
+            for _ in 0..writer.text_rows {
+                writer.write_string("\n");
+            }
+
+            writer.scroll_view(1);
+            assert_eq!(buffer().chars[0][0].read().ascii_character, b's');
+            writer.write_string("x");
+            assert_eq!(writer.view, 0);
+            assert_eq!(buffer().chars[0][0].read().ascii_character, b' ');
+        });
     }
 
-    unsafe {
-        // set the address to the Cursor Start Register
-        // http://www.osdever.net/FreeVGA/vga/crtcreg.htm#0A
-        u8::write_to_port(crtc_addr, 0xa);
+    /// Filling a region that runs off the screen only touches the cells on it
+    #[test_case]
+    fn fill_region_clips() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            let rect = Rect {
+                x: BUFFER_WIDTH - 2,
+                y: BUFFER_HEIGHT - 1,
+                width: 10,
+                height: 10,
+            };
+            writer.fill_region(rect, b'#', Color::Yellow, Color::Blue);
 
-        // then, turn it off
-        // only bit 4 needs to be set
-        u8::write_to_port(crtc_data, 1 << 4);
+            let corner = buffer().chars[BUFFER_HEIGHT - 1][BUFFER_WIDTH - 1].read();
+            assert_eq!(corner.ascii_character, b'#');
+            assert_eq!(
+                corner.color_code,
+                ColorCode::new(Color::Yellow, Color::Blue)
+            );
+            writer.clear();
+        });
     }
 }
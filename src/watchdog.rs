@@ -0,0 +1,275 @@
+//! Lockup watchdog
+//!
+//! Code that should keep making progress [`watch`]es a [`Heartbeat`] and
+//! [`touch`](Heartbeat::touch)es it every so often. Once a second a timer callback checks
+//! every watched heartbeat, and one that hasn't been touched for `watchdog=SECONDS` (10 by
+//! default, 0 turns the watchdog off) is a panic, which says where the boot CPU was when the
+//! timer interrupt came in. Threads all run on the boot CPU, so that's usually somewhere in
+//! the loop that's stuck, and the panic's backtrace goes on from the interrupt into it.
+//!
+//! [`MAIN`] is the kernel's main loop, the shell. It's touched whenever the main thread idles,
+//! which it does while it waits for a key, and after every command, so a command that never
+//! returns or waits is what it catches. A heartbeat can be [`pause`](Heartbeat::pause)d while
+//! its owner waits for something that could take arbitrarily long.
+//!
+//! A loop with interrupts off stops the timer too, so that's caught another way: when the
+//! [`apic`]s are in use, the PIT isn't the timer anymore, and [`INIT`] has it send the boot
+//! CPU an NMI [`NMI_HZ`] times a second, which interrupts-off doesn't hold back. Each one
+//! checks that the tick count has moved, and when it hasn't for the same timeout, that's a
+//! panic from the NMI, with a backtrace of the loop it came in on. With the PICs the PIT is
+//! the timer itself, so there's no tick check. Anything that waits with interrupts off on
+//! purpose, like a debugger, does it [`without_tick_check`], and a panic [`disable`]s both.
+//!
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::debug::symbols;
+use crate::init::{InitCall, Stage};
+use crate::sync::SpinLock;
+use crate::{apic, cmdline, ilog, pic, timer, wlog};
+
+/// Heartbeats that can be watched at once
+pub const MAX_WATCHED: usize = 16;
+/// How often the tick check's NMIs come in, about as slow as the PIT goes
+pub const NMI_HZ: u32 = 19;
+
+/// The PIT's IRQ
+const PIT_IRQ: u8 = 0;
+
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+const CHECK_MS: u64 = 1000;
+
+/// How long a heartbeat or the tick can go without progress before it's a panic, 0 for never
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT_MS);
+/// Cleared for good by [`disable`]
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// The NMIs' period, 0 while there's no tick check
+static NMI_NS: AtomicU64 = AtomicU64::new(0);
+/// The tick count at the last NMI
+static LAST_TICKS: AtomicU64 = AtomicU64::new(0);
+/// How long the tick count hasn't moved for
+static STALLED_NS: AtomicU64 = AtomicU64::new(0);
+/// Callers in [`without_tick_check`]
+static HELD: AtomicUsize = AtomicUsize::new(0);
+
+/// Only locked with interrupts off, the timer interrupt goes through it
+static WATCHED: SpinLock<[Option<&'static Heartbeat>; MAX_WATCHED]> =
+    SpinLock::new("watchdog", [None; MAX_WATCHED]);
+
+/// The kernel main loop's
+pub static MAIN: Heartbeat = Heartbeat::new("main loop");
+
+/// Something the watchdog expects to be touched regularly
+pub struct Heartbeat {
+    name: &'static str,
+    /// Uptime it was last touched at, 0 while it's paused
+    touched_ms: AtomicU64,
+}
+
+impl Heartbeat {
+    /// A heartbeat that's paused until it's first touched
+    pub const fn new(name: &'static str) -> Heartbeat {
+        Heartbeat {
+            name,
+            touched_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Show there's still progress being made
+    pub fn touch(&self) {
+        self.touched_ms
+            .store(timer::uptime_ms().max(1), Ordering::Relaxed);
+    }
+
+    /// Stop checking it until it's touched again
+    pub fn pause(&self) {
+        self.touched_ms.store(0, Ordering::Relaxed);
+    }
+
+    /// How long it's gone without being touched, None while it's paused
+    fn stale_for(&self, now_ms: u64) -> Option<u64> {
+        match self.touched_ms.load(Ordering::Relaxed) {
+            0 => None,
+            touched_ms => Some(now_ms.saturating_sub(touched_ms)),
+        }
+    }
+}
+
+/// Why a heartbeat couldn't be watched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchError {
+    /// It's already being watched
+    Exists,
+    /// All [`MAX_WATCHED`] slots are taken
+    Full,
+}
+
+/// Start checking `heartbeat`
+pub fn watch(heartbeat: &'static Heartbeat) -> Result<(), WatchError> {
+    interrupts::without_interrupts(|| {
+        let mut watched = WATCHED.lock();
+        if watched
+            .iter()
+            .flatten()
+            .any(|&watched| core::ptr::eq(watched, heartbeat))
+        {
+            return Err(WatchError::Exists);
+        }
+        let slot = watched
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(WatchError::Full)?;
+        *slot = Some(heartbeat);
+        Ok(())
+    })
+}
+
+/// Stop checking `heartbeat`
+pub fn unwatch(heartbeat: &'static Heartbeat) {
+    interrupts::without_interrupts(|| {
+        for slot in WATCHED.lock().iter_mut() {
+            if slot.is_some_and(|watched| core::ptr::eq(watched, heartbeat)) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// Stop checking anything, for good. For the panic handler, nothing makes progress after.
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Run `f`, which waits with interrupts off for as long as it takes, without the tick check
+/// going off. The heartbeats are still checked once the timer's back.
+pub fn without_tick_check<R>(f: impl FnOnce() -> R) -> R {
+    HELD.fetch_add(1, Ordering::Relaxed);
+    let result = f();
+    STALLED_NS.store(0, Ordering::Relaxed);
+    HELD.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+/// Panic about the first heartbeat that's gone stale, from the timer interrupt
+fn check() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let timeout_ms = TIMEOUT_MS.load(Ordering::Relaxed);
+    let now_ms = timer::uptime_ms();
+    let watched = *WATCHED.lock();
+    for heartbeat in watched.into_iter().flatten() {
+        let Some(stale_ms) = heartbeat.stale_for(now_ms) else {
+            continue;
+        };
+        if stale_ms < timeout_ms {
+            continue;
+        }
+        disable();
+        panic!(
+            "watchdog: {} stuck for {} s, in {}",
+            heartbeat.name,
+            stale_ms / 1000,
+            symbols::resolve(pic::interrupted_at())
+        );
+    }
+}
+
+/// An NMI came in on the boot CPU. Every one is the tick check's while it's on, and this
+/// returns false otherwise, for the exception handler to deal with. Nothing it does takes a
+/// lock, the NMI can come in anywhere.
+pub fn nmi() -> bool {
+    let period_ns = NMI_NS.load(Ordering::Relaxed);
+    if period_ns == 0 {
+        return false;
+    }
+    let ticks = timer::ticks();
+    if LAST_TICKS.swap(ticks, Ordering::Relaxed) != ticks
+        || HELD.load(Ordering::Relaxed) != 0
+        || !ENABLED.load(Ordering::Relaxed)
+    {
+        STALLED_NS.store(0, Ordering::Relaxed);
+        return true;
+    }
+    let stalled_ns = STALLED_NS.fetch_add(period_ns, Ordering::Relaxed) + period_ns;
+    if stalled_ns >= TIMEOUT_MS.load(Ordering::Relaxed) * 1_000_000 {
+        disable();
+        panic!(
+            "watchdog: no timer tick for {} s, stuck with interrupts off",
+            stalled_ns / 1_000_000_000
+        );
+    }
+    true
+}
+
+/// Have the PIT send NMIs for the tick check, if it's free and there's an I/O APIC for them
+fn start_tick_check() {
+    if !apic::enabled() {
+        ilog!("watchdog: the PIT is the timer, no check for interrupts being off");
+        return;
+    }
+    LAST_TICKS.store(timer::ticks(), Ordering::Relaxed);
+    let period_ns = timer::start_pit(NMI_HZ);
+    NMI_NS.store(period_ns, Ordering::Relaxed);
+    if !apic::route_nmi(PIT_IRQ) {
+        NMI_NS.store(0, Ordering::Relaxed);
+        wlog!("watchdog: the PIT can't send NMIs, no check for interrupts being off");
+    }
+}
+
+fn init() {
+    match cmdline::parse::<u64>("watchdog") {
+        Ok(Some(seconds)) => TIMEOUT_MS.store(seconds * 1000, Ordering::Relaxed),
+        Ok(None) => {}
+        Err(error) => wlog!("watchdog: {}", error),
+    }
+    let timeout_ms = TIMEOUT_MS.load(Ordering::Relaxed);
+    if timeout_ms == 0 {
+        return;
+    }
+
+    MAIN.touch();
+    let _ = watch(&MAIN);
+    match timer::every(CHECK_MS, check) {
+        Ok(_) => ilog!(
+            "watchdog: panic after {} s without progress",
+            timeout_ms / 1000
+        ),
+        Err(error) => wlog!("watchdog: couldn't add the timer: {:?}", error),
+    }
+    start_tick_check();
+}
+
+pub const INIT: InitCall = InitCall {
+    name: "watchdog",
+    stage: Stage::Late,
+    after: &["timer"],
+    func: init,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn heartbeats_go_stale() {
+        static HEARTBEAT: Heartbeat = Heartbeat::new("test");
+        assert_eq!(HEARTBEAT.stale_for(timer::uptime_ms()), None);
+        HEARTBEAT.touch();
+        let touched_ms = HEARTBEAT.touched_ms.load(Ordering::Relaxed);
+        assert_eq!(HEARTBEAT.stale_for(touched_ms + 5000), Some(5000));
+        HEARTBEAT.pause();
+        assert_eq!(HEARTBEAT.stale_for(touched_ms + 5000), None);
+
+        assert_eq!(watch(&HEARTBEAT), Ok(()));
+        assert_eq!(watch(&HEARTBEAT), Err(WatchError::Exists));
+        unwatch(&HEARTBEAT);
+    }
+}
@@ -0,0 +1,36 @@
+//! Nothing but the bare kernel, to check printing works before anything else is set up
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zenix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use zenix::println;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    test_main();
+    zenix::power::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zenix::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_println() {
+    println!("test_println output");
+}
+
+#[test_case]
+fn test_println_many() {
+    // enough to scroll the screen a few times
+    for i in 0..200 {
+        println!("test_println_many output {}", i);
+    }
+}
@@ -0,0 +1,65 @@
+//! Microbenchmarks of kernel primitives, see `zenix::debug::bench`
+//!
+//! Run with `cargo test --test bench`, the results are the `bench` lines in the output.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zenix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use zenix::debug::bench::Bench;
+use zenix::gfx::framebuffer::FRAMEBUFFER;
+use zenix::gfx::{Rect, Rgb};
+use zenix::sync::SpinLock;
+use zenix::{bootinfo, init, mem, println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    bootinfo::init(boot_info).unwrap();
+    init::run();
+    test_main();
+    zenix::power::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zenix::test_panic_handler(info)
+}
+
+#[test_case]
+static SPINLOCK: Bench = Bench::new("spinlock", || {
+    static LOCK: SpinLock<u64> = SpinLock::new("bench", 0);
+    *LOCK.lock() += 1;
+});
+
+/// A whole line, which scrolls once the screen has filled up during warmup
+#[test_case]
+static CONSOLE_SCROLL: Bench = Bench::new("console_scroll", || {
+    println!("console_scroll");
+});
+
+#[test_case]
+static FILL_SCREEN: Bench = Bench::new("fill_screen", || {
+    if let Some(fb) = FRAMEBUFFER.lock().as_mut() {
+        let screen = Rect {
+            x: 0,
+            y: 0,
+            width: fb.width(),
+            height: fb.height(),
+        };
+        fb.fill_rect(screen, Rgb::new(0, 0, 0x80));
+        fb.flush();
+    }
+});
+
+/// Translating an address through all four levels of the page tables
+#[test_case]
+static PAGE_WALK: Bench = Bench::new("page_walk", || {
+    let addr = VirtAddr::new(main as *const () as u64);
+    assert!(mem::virt_to_phys(addr).is_some());
+});
@@ -0,0 +1,62 @@
+//! The kernel heap, which needs the memory map to be set up
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zenix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use zenix::{bootinfo, config, init};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    bootinfo::init(boot_info).unwrap();
+    init::run();
+    test_main();
+    zenix::power::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zenix::test_panic_handler(info)
+}
+
+#[test_case]
+fn simple_allocation() {
+    let a = Box::new(41);
+    let b = Box::new(13);
+    assert_eq!(*a, 41);
+    assert_eq!(*b, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let vec: Vec<u64> = (0..n).collect();
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+/// Freed memory has to be reused, or this runs out of heap
+#[test_case]
+fn many_boxes() {
+    for i in 0..config::heap_size() {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+/// Too big for a block, so these come from the region list and have to go back to it
+#[test_case]
+fn many_large() {
+    for i in 0..config::heap_size() / 1024 {
+        let vec: Vec<u64> = (0..i).take(1024).collect();
+        assert_eq!(vec.len() as u64, i.min(1024));
+    }
+}
@@ -0,0 +1,57 @@
+//! Boot-time initialization, run the same way the kernel does it
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zenix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+extern crate alloc;
+
+use alloc::vec::Vec;
+use zenix::{bootinfo, console, gfx, init, mem, println, statusbar};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    assert_eq!(boot_info.physical_memory_offset, mem::PHYS_OFFSET);
+    bootinfo::init(boot_info).unwrap();
+    init::run();
+    test_main();
+    zenix::power::halt()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zenix::test_panic_handler(info)
+}
+
+/// The frame allocator got the memory map, and the heap was mapped from it
+#[test_case]
+fn heap_is_usable() {
+    let frames = mem::frame::stats().expect("no frame allocator");
+    assert!(frames.free() > 0);
+    assert!(mem::heap::stats().size > 0);
+    let vec: Vec<u64> = (0..1000).collect();
+    assert_eq!(vec.iter().sum::<u64>(), 999 * 1000 / 2);
+}
+
+#[test_case]
+fn console_matches_display() {
+    let (cols, rows) = console::with_console(|console| console.size());
+    match gfx::framebuffer_info() {
+        Some(fb) => {
+            assert_eq!(cols, fb.width / 8);
+            assert_eq!(rows, fb.height / 16);
+        }
+        // the status line takes the bottom row
+        None => assert_eq!((cols, rows), (80, 25 - statusbar::enabled() as usize)),
+    }
+}
+
+#[test_case]
+fn println_after_init() {
+    println!("println_after_init output");
+}
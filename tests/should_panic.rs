@@ -0,0 +1,30 @@
+//! A test that only passes by panicking
+//!
+//! Built without the test harness (see Cargo.toml), since the run ends at the first panic
+//! anyway. The panic handler is what reports success.
+
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use zenix::{qemu, serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    should_fail();
+    serial_println!("[test did not panic]");
+    qemu::exit(qemu::ExitCode::Failed);
+}
+
+fn should_fail() {
+    serial_print!("should_panic::should_fail...\t");
+    assert_eq!(0, 1);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    qemu::exit(qemu::ExitCode::Success);
+}
@@ -0,0 +1,57 @@
+//! A kernel stack overflow ends up in the double fault handler, on its own stack
+//!
+//! Built without the test harness like `should_panic`, the double fault handler is what
+//! reports success.
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use zenix::{bootinfo, gdt, init, qemu, serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_overflow::stack_overflow...\t");
+
+    bootinfo::init(boot_info).unwrap();
+    init::run();
+    TEST_IDT.load();
+
+    stack_overflow();
+
+    panic!("execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    // keeps the call from being turned into a loop
+    volatile::Volatile::new(0).read();
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(_frame: InterruptStackFrame, _code: u64) -> ! {
+    serial_println!("[ok]");
+    qemu::exit(qemu::ExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zenix::test_panic_handler(info)
+}
@@ -0,0 +1,12 @@
+#!/bin/sh
+# assemble and link the user programs in src/user into the ELF files the kernel embeds
+set -e
+cd "$(dirname "$0")/../src/user"
+for source in *.s; do
+    name="${source%.s}"
+    as --64 -o "/tmp/$name.o" "$source"
+    # linked where user code goes, see CODE_BASE in src/user/mod.rs
+    ld -static -nostdlib --build-id=none -z max-page-size=0x1000 -z noexecstack -s \
+        -Ttext-segment=0x400000 -o "$name.elf" "/tmp/$name.o"
+    rm "/tmp/$name.o"
+done
@@ -0,0 +1,121 @@
+#!/usr/bin/env python3
+"""Fill in a kernel's embedded symbol table after it's linked.
+
+The kernel reserves a zeroed `.ksyms` section (see src/debug/symbols.rs). This lists the
+function symbols in the linked ELF with llvm-nm, packs them into the format the kernel reads,
+and writes that over the section in place, so nothing moves and no relink is needed.
+
+Format, all little-endian:
+    b"KSYM", u32 count
+    count * (u64 address, u32 size, u32 name offset), sorted by address
+    NUL-terminated names, offsets are from the start of the names
+
+usage: ksyms.py <kernel elf>
+"""
+
+import os
+import re
+import shutil
+import struct
+import subprocess
+import sys
+
+SECTION = b".ksyms"
+MAGIC = b"KSYM"
+# legacy mangling leaves a hash on the end of every path
+HASH_SUFFIX = re.compile(r"::h[0-9a-f]{16}$")
+# address, size, type, name. symbols without a size leave that column out, and demangled names
+# can have spaces in them
+NM_LINE = re.compile(r"^([0-9a-f]+) (?:([0-9a-f]+) )?(\S) (.+)$")
+
+
+def find_llvm_nm():
+    found = shutil.which("llvm-nm")
+    if found:
+        return found
+
+    # the llvm-tools component, which bootimage needs anyway
+    sysroot = subprocess.check_output(["rustc", "--print", "sysroot"], text=True).strip()
+    rustlib = os.path.join(sysroot, "lib", "rustlib")
+    for host in os.listdir(rustlib):
+        candidate = os.path.join(rustlib, host, "bin", "llvm-nm")
+        if os.path.exists(candidate):
+            return candidate
+    sys.exit("ksyms: can't find llvm-nm, install it with `rustup component add llvm-tools`")
+
+
+def find_section(elf):
+    """(file offset, size) of the .ksyms section"""
+    if elf[:4] != b"\x7fELF" or elf[4] != 2:
+        sys.exit("ksyms: not a 64-bit ELF file")
+
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3a)
+
+    def header(i):
+        # name, type, flags, addr, offset, size
+        return struct.unpack_from("<IIQQQQ", elf, shoff + i * shentsize)
+
+    strtab_offset = header(shstrndx)[4]
+    for i in range(shnum):
+        name, _, _, _, offset, size = header(i)
+        start = strtab_offset + name
+        if elf[start:elf.index(b"\0", start)] == SECTION:
+            return offset, size
+    sys.exit("ksyms: no .ksyms section, is this a zenix kernel?")
+
+
+def read_symbols(path):
+    output = subprocess.check_output(
+        [find_llvm_nm(), "--demangle", "--defined-only", "--print-size", "--numeric-sort", path],
+        text=True,
+    )
+
+    symbols = {}
+    for line in output.splitlines():
+        match = NM_LINE.match(line)
+        if not match or match[3] not in "tT":
+            continue
+
+        address = int(match[1], 16)
+        size = int(match[2] or "0", 16)
+        name = match[4]
+        # for aliases, a function with a size beats a bare label like __text_start
+        if address not in symbols or symbols[address][0] == 0:
+            symbols[address] = (size, HASH_SUFFIX.sub("", name))
+    return sorted((address, size, name) for address, (size, name) in symbols.items())
+
+
+def pack(symbols):
+    entries = bytearray()
+    names = bytearray()
+    for address, size, name in symbols:
+        entries += struct.pack("<QII", address, min(size, 0xffffffff), len(names))
+        names += name.encode() + b"\0"
+    return MAGIC + struct.pack("<I", len(symbols)) + entries + names
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(__doc__.strip().splitlines()[-1])
+    path = sys.argv[1]
+
+    with open(path, "rb") as f:
+        elf = bytearray(f.read())
+    offset, size = find_section(elf)
+
+    symbols = read_symbols(path)
+    table = pack(symbols)
+    if len(table) > size:
+        # an empty table just means raw addresses in backtraces, which beats failing the build
+        print(f"ksyms: {len(symbols)} symbols need {len(table)} bytes, only {size} reserved",
+              file=sys.stderr)
+        table = b""
+
+    elf[offset:offset + size] = table.ljust(size, b"\0")
+    with open(path, "wb") as f:
+        f.write(elf)
+
+
+if __name__ == "__main__":
+    main()
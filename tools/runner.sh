@@ -0,0 +1,5 @@
+#!/bin/sh
+# cargo runner: embed the kernel's symbol table, then boot it with bootimage
+set -e
+python3 "$(dirname "$0")/ksyms.py" "$1"
+exec bootimage runner "$@"